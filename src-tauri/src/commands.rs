@@ -0,0 +1,369 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use tauri::State;
+use torrent::{
+    announcer::TrackerStatus,
+    bandwidth::{self, BandwidthReport, BandwidthTestOptions},
+    client_identity::ClientIdentity,
+    dht::DhtStatus,
+    disk::{CacheMode, DeleteMode},
+    health::TorrentHealth,
+    metainfo::{FileEntry, MetaInfo},
+    peer_activity::{ConnectedPeer, PeerActivity, PeerFailure},
+    peer_list,
+    profile::Profile,
+    sanity::{self, TorrentWarning},
+    statistics::StatisticsSnapshot,
+    swarm_history::SwarmSample,
+    torrent_settings::TorrentSettings,
+    tracker::TrackerScrape,
+};
+
+use crate::{error::CommandError, guard::decode_info_hash, state::AppState};
+
+#[tauri::command]
+pub fn statistics(state: State<'_, AppState>) -> StatisticsSnapshot {
+    state.statistics.lock().unwrap().snapshot()
+}
+
+// Parse the torrent and report the things user may want to know before adding it.
+#[tauri::command]
+pub fn analyze_torrent(bytes: Vec<u8>) -> Result<Vec<TorrentWarning>, CommandError> {
+    let metainfo = MetaInfo::from_bytes(&bytes)?;
+    Ok(sanity::check(&metainfo))
+}
+
+// The files of the torrent with their attributes, the padding files are left out.
+#[tauri::command]
+pub fn torrent_files(bytes: Vec<u8>) -> Result<Vec<FileEntry>, CommandError> {
+    let metainfo = MetaInfo::from_bytes(&bytes)?;
+    Ok(metainfo.file_entries())
+}
+
+// Add the torrent file, returns the hex info hash the other commands of the torrent take.
+#[tauri::command]
+pub async fn add_torrent(
+    state: State<'_, AppState>,
+    bytes: Vec<u8>,
+) -> Result<String, CommandError> {
+    state.add_torrent_file(bytes).await
+}
+
+// Add the torrent file on top of the files the user already has in `source_dir`, e.g.
+// downloaded by another client. Returns the hex info hash like `add_torrent`.
+#[tauri::command]
+pub async fn add_torrent_with_existing_data(
+    state: State<'_, AppState>,
+    bytes: Vec<u8>,
+    source_dir: PathBuf,
+) -> Result<String, CommandError> {
+    state
+        .add_torrent_with_existing_data(bytes, source_dir)
+        .await
+}
+
+// Seed the torrents of the .torrent files in `torrents_dir` which the data in `data_dir` is
+// complete for, e.g. the same release on other trackers. Returns their hex info hashes.
+#[tauri::command]
+pub async fn cross_seed(
+    state: State<'_, AppState>,
+    torrents_dir: PathBuf,
+    data_dir: PathBuf,
+) -> Result<Vec<String>, CommandError> {
+    state.cross_seed(torrents_dir, data_dir).await
+}
+
+// Download the torrent for a while and report the speed and what limits it.
+#[tauri::command]
+pub async fn bandwidth_test(
+    bytes: Vec<u8>,
+    seconds: Option<u64>,
+) -> Result<BandwidthReport, CommandError> {
+    let metainfo = MetaInfo::from_bytes(&bytes)?;
+    let mut options = BandwidthTestOptions::default();
+    if let Some(seconds) = seconds {
+        options.duration = Duration::from_secs(seconds);
+    }
+    Ok(bandwidth::run(metainfo, options).await?)
+}
+
+// Write the connected peers of the torrent to a text file, one ip:port per line.
+#[tauri::command]
+pub async fn export_peer_list(
+    state: State<'_, AppState>,
+    info_hash: String,
+    path: PathBuf,
+) -> Result<(), CommandError> {
+    let torrent = state.torrent(&info_hash)?;
+    let activity = torrent.lock().await.peer_activity();
+    let peers: Vec<SocketAddr> = activity.connected().into_iter().map(|it| it.addr).collect();
+    Ok(peer_list::export(&peers, &path)?)
+}
+
+// Read the peers from a text file written by `export_peer_list` or by hand, they are dialed
+// for the torrent as manually added ones. Returns the imported peers.
+#[tauri::command]
+pub fn import_peer_list(
+    state: State<'_, AppState>,
+    info_hash: String,
+    path: PathBuf,
+) -> Result<Vec<SocketAddr>, CommandError> {
+    state.torrent(&info_hash)?;
+    let candidates = peer_list::import(&path)?;
+    let peers = candidates.iter().map(|it| it.addr).collect();
+    state
+        .engine
+        .add_peers(&decode_info_hash(&info_hash)?, candidates)?;
+    Ok(peers)
+}
+
+// Validate the user agent and peer id prefix from the settings, returns whether they report
+// another client than BitDrift, which the settings warn about.
+#[tauri::command]
+pub fn check_client_identity(
+    user_agent: String,
+    peer_id_prefix: String,
+) -> Result<bool, CommandError> {
+    Ok(ClientIdentity::new(&user_agent, &peer_id_prefix)?.is_spoofed())
+}
+
+#[derive(Serialize)]
+pub struct ProfilesSnapshot {
+    active: String,
+    profiles: Vec<Profile>,
+}
+
+#[tauri::command]
+pub fn profiles(state: State<'_, AppState>) -> ProfilesSnapshot {
+    let profiles = state.profiles.lock().unwrap();
+    ProfilesSnapshot {
+        active: profiles.active().name,
+        profiles: profiles.list().to_vec(),
+    }
+}
+
+#[derive(Serialize)]
+pub struct CacheModeOption {
+    mode: CacheMode,
+    description: &'static str,
+}
+
+// The choices of the disk cache setting, with what each of them risks on a crash.
+#[tauri::command]
+pub fn disk_cache_modes() -> Vec<CacheModeOption> {
+    [CacheMode::WriteThrough, CacheMode::write_back()]
+        .into_iter()
+        .map(|mode| CacheModeOption {
+            mode,
+            description: mode.description(),
+        })
+        .collect()
+}
+
+// Add or edit a profile, the edit of the active profile is applied right away. Returns true
+// if some of the edit only applies after a restart, see `Profile::needs_restart`.
+#[tauri::command]
+pub fn save_profile(state: State<'_, AppState>, profile: Profile) -> bool {
+    let mut profiles = state.profiles.lock().unwrap();
+    let previous = profiles.active();
+    profiles.save_profile(profile);
+    let needs_restart = profiles.active().needs_restart(&previous);
+    drop(profiles);
+    state.save_profiles();
+    needs_restart
+}
+
+#[tauri::command]
+pub fn remove_profile(state: State<'_, AppState>, name: String) -> Result<(), CommandError> {
+    state.profiles.lock().unwrap().remove(&name)?;
+    state.save_profiles();
+    Ok(())
+}
+
+// Switch to the profile. Returns true if some of its settings only apply after a restart,
+// the rest the engine applies right away.
+#[tauri::command]
+pub fn switch_profile(state: State<'_, AppState>, name: String) -> Result<bool, CommandError> {
+    let mut profiles = state.profiles.lock().unwrap();
+    let previous = profiles.active();
+    profiles.switch(&name)?;
+    let needs_restart = profiles.active().needs_restart(&previous);
+    drop(profiles);
+    state.save_profiles();
+    Ok(needs_restart)
+}
+
+#[tauri::command]
+pub async fn get_torrent_options(
+    state: State<'_, AppState>,
+    info_hash: String,
+) -> Result<TorrentSettings, CommandError> {
+    let torrent = state.torrent(&info_hash)?;
+    let settings = torrent.lock().await.settings();
+    Ok(settings)
+}
+
+// All the options are applied together, or none of them if any field is invalid.
+#[tauri::command]
+pub async fn set_torrent_options(
+    state: State<'_, AppState>,
+    info_hash: String,
+    options: TorrentSettings,
+) -> Result<(), CommandError> {
+    let guard = state.guard.lock(&info_hash).await?;
+    let torrent = state.torrent(&guard.info_hash)?;
+    let result = torrent.lock().await.apply_settings(options).await;
+    result.map_err(|errors| CommandError::InvalidTorrentOptions { errors })
+}
+
+// The commands of the torrent still waiting for their turn fail with not found after. The
+// downloaded files are deleted too with `delete_data`, the torrent is kept if they can't be.
+#[tauri::command]
+pub async fn remove_torrent(
+    state: State<'_, AppState>,
+    info_hash: String,
+    delete_data: Option<DeleteMode>,
+) -> Result<(), CommandError> {
+    let guard = state.guard.lock(&info_hash).await?;
+    state.torrent(&guard.info_hash)?;
+    let decoded = decode_info_hash(&guard.info_hash)?;
+    match delete_data {
+        Some(mode) => {
+            state
+                .engine
+                .remove_torrent_with_data(&decoded, mode)
+                .await?;
+        }
+        None => {
+            state.engine.remove_torrent(&decoded).await;
+        }
+    }
+    state.guard.mark_removing(&guard);
+    state.forget_torrent_file(&guard.info_hash);
+    state.guard.forget(guard);
+    Ok(())
+}
+
+// The history graph of the torrent since it was added.
+#[tauri::command]
+pub async fn swarm_history(
+    state: State<'_, AppState>,
+    info_hash: String,
+) -> Result<Vec<SwarmSample>, CommandError> {
+    let torrent = state.torrent(&info_hash)?;
+    let samples = torrent.lock().await.swarm_history().samples();
+    Ok(samples)
+}
+
+// The red/yellow/green dot of the torrent list.
+#[tauri::command]
+pub async fn torrent_health(
+    state: State<'_, AppState>,
+    info_hash: String,
+) -> Result<TorrentHealth, CommandError> {
+    let torrent = state.torrent(&info_hash)?;
+    // Paused, no tracker status
+    let trackers = state
+        .engine
+        .tracker_status(&decode_info_hash(&info_hash)?)
+        .unwrap_or_default();
+    let health = torrent.lock().await.health(&trackers).await;
+    Ok(health)
+}
+
+// The tracker tab, with how each tracker did since the client started.
+#[tauri::command]
+pub fn tracker_status(
+    state: State<'_, AppState>,
+    info_hash: String,
+) -> Result<Vec<TrackerStatus>, CommandError> {
+    let status = state.engine.tracker_status(&decode_info_hash(&info_hash)?);
+    status.ok_or_else(|| CommandError::TorrentNotFound {
+        info_hash: info_hash.to_lowercase(),
+    })
+}
+
+// The swarm of the torrent as the trackers see it, without announcing.
+#[tauri::command]
+pub async fn scrape_trackers(
+    state: State<'_, AppState>,
+    info_hash: String,
+) -> Result<Vec<TrackerScrape>, CommandError> {
+    let scrapes = state.engine.scrape(&decode_info_hash(&info_hash)?).await?;
+    Ok(scrapes)
+}
+
+#[derive(Serialize)]
+pub struct RecheckReport {
+    have: usize,
+    pieces: usize,
+}
+
+// Hash the files of the torrent again, the corrupt pieces are downloaded again.
+#[tauri::command]
+pub async fn recheck_torrent(
+    state: State<'_, AppState>,
+    info_hash: String,
+) -> Result<RecheckReport, CommandError> {
+    let guard = state.guard.lock(&info_hash).await?;
+    let have = state
+        .engine
+        .recheck_torrent(&decode_info_hash(&guard.info_hash)?)
+        .await?;
+    Ok(RecheckReport {
+        have: have.count_ones(),
+        pieces: have.len(),
+    })
+}
+
+// The recent activity of a peer for the peer detail pane, the oldest first.
+#[tauri::command]
+pub async fn peer_activity(
+    state: State<'_, AppState>,
+    info_hash: String,
+    addr: SocketAddr,
+) -> Result<Vec<PeerActivity>, CommandError> {
+    let torrent = state.torrent(&info_hash)?;
+    let activity = torrent.lock().await.peer_activity();
+    Ok(activity.recent(&addr))
+}
+
+// The peer list, with the round trip of each peer's requests.
+#[tauri::command]
+pub async fn connected_peers(
+    state: State<'_, AppState>,
+    info_hash: String,
+) -> Result<Vec<ConnectedPeer>, CommandError> {
+    let torrent = state.torrent(&info_hash)?;
+    let activity = torrent.lock().await.peer_activity();
+    Ok(activity.connected())
+}
+
+// The peers dropped before the session started and why, e.g. they failed certificate pinning.
+#[tauri::command]
+pub async fn peer_failures(
+    state: State<'_, AppState>,
+    info_hash: String,
+) -> Result<Vec<PeerFailure>, CommandError> {
+    let torrent = state.torrent(&info_hash)?;
+    let activity = torrent.lock().await.peer_activity();
+    Ok(activity.failures())
+}
+
+// For the DHT indicator, None if the DHT is off or failed to start.
+#[tauri::command]
+pub fn dht_status(state: State<'_, AppState>) -> Option<DhtStatus> {
+    state.dht.as_ref().map(|dht| dht.status())
+}
+
+// Our address as the trackers and the peers see it, None until any of them told us.
+#[tauri::command]
+pub fn external_ip(state: State<'_, AppState>) -> Option<IpAddr> {
+    state.external_ip.ip()
+}
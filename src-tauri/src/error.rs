@@ -0,0 +1,102 @@
+use serde::Serialize;
+use torrent::{
+    bandwidth::BandwidthError, client_identity::ClientIdentityError, engine::EngineError,
+    metainfo::MetaInfoError, peer_list::PeerListError, profile::ProfileError,
+    torrent_settings::FieldError,
+};
+
+// What the commands return when they fail. The frontend shows the message of the code in
+// the language of the user, the English text of the error only goes to the log.
+#[derive(Debug, Serialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum CommandError {
+    InvalidTorrent,
+    FileAccess,
+    InvalidPeer { line: usize, entry: String },
+    InvalidUserAgent,
+    InvalidPeerIdPrefix,
+    ProfileNotFound { name: String },
+    RemoveActiveProfile,
+    ProfileStorage,
+    TorrentNotFound { info_hash: String },
+    TorrentExists,
+    // The trackers of the torrent can't be set up, e.g. their TLS settings are invalid.
+    InvalidTrackers,
+    // Not the hex of an info hash.
+    InvalidTorrentId { info_hash: String },
+    // The torrent can't take the command now, e.g. it's being removed.
+    InvalidState { state: &'static str },
+    // The earlier commands of the torrent are still running, try again later.
+    EngineBusy,
+    InvalidTorrentOptions { errors: Vec<FieldError> },
+    // No port is free to listen for the peers on.
+    ListenFailed,
+    // The torrent has neither trackers nor web seeds, the bandwidth test finds nobody.
+    NoPeerSources,
+}
+
+impl From<MetaInfoError> for CommandError {
+    fn from(e: MetaInfoError) -> Self {
+        log::warn!("Invalid torrent: {:?}", e);
+        CommandError::InvalidTorrent
+    }
+}
+
+impl From<PeerListError> for CommandError {
+    fn from(e: PeerListError) -> Self {
+        log::warn!("Failed to read or write peer list: {:?}", e);
+        match e {
+            PeerListError::Io(_) => CommandError::FileAccess,
+            PeerListError::InvalidEntry { line, entry } => {
+                CommandError::InvalidPeer { line, entry }
+            }
+        }
+    }
+}
+
+impl From<ClientIdentityError> for CommandError {
+    fn from(e: ClientIdentityError) -> Self {
+        log::warn!("Invalid client identity: {:?}", e);
+        match e {
+            ClientIdentityError::InvalidUserAgent => CommandError::InvalidUserAgent,
+            ClientIdentityError::InvalidPeerIdPrefix => CommandError::InvalidPeerIdPrefix,
+        }
+    }
+}
+
+impl From<ProfileError> for CommandError {
+    fn from(e: ProfileError) -> Self {
+        log::warn!("Profile error: {:?}", e);
+        match e {
+            ProfileError::NotFound(name) => CommandError::ProfileNotFound { name },
+            ProfileError::RemoveActive => CommandError::RemoveActiveProfile,
+            ProfileError::Io(_) | ProfileError::Bencode(_) => CommandError::ProfileStorage,
+        }
+    }
+}
+
+impl From<EngineError> for CommandError {
+    fn from(e: EngineError) -> Self {
+        log::warn!("Engine failed to run torrent: {:?}", e);
+        match e {
+            EngineError::AlreadyAdded => CommandError::TorrentExists,
+            EngineError::NotFound(info_hash) => CommandError::TorrentNotFound {
+                info_hash: info_hash.iter().map(|it| format!("{:02x}", it)).collect(),
+            },
+            EngineError::Tracker(_) => CommandError::InvalidTrackers,
+            EngineError::ExistingData(_) => CommandError::FileAccess,
+            EngineError::DeleteFiles(_) => CommandError::FileAccess,
+        }
+    }
+}
+
+impl From<BandwidthError> for CommandError {
+    fn from(e: BandwidthError) -> Self {
+        log::warn!("Failed to run the bandwidth test: {:?}", e);
+        match e {
+            BandwidthError::Listen(_) => CommandError::ListenFailed,
+            BandwidthError::Tracker(_) => CommandError::InvalidTrackers,
+            BandwidthError::NoSources => CommandError::NoPeerSources,
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::OwnedMutexGuard;
+
+use crate::error::CommandError;
+
+// The UI fires the actions as fast as the user clicks, e.g. pause and remove right after.
+// The commands changing a torrent take its guard, so they run one after the other instead of
+// racing each other, and a command waiting too long gives up instead of piling up.
+
+// Longer than any mutating command takes, the engine is stuck otherwise.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+pub struct CommandGuard {
+    torrents: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    // The torrents being removed, the commands arriving meanwhile are turned away.
+    removing: Mutex<HashSet<String>>,
+}
+
+// Held for the whole command, the next command of the torrent waits for it.
+pub struct TorrentGuard {
+    pub info_hash: String,
+    _permit: OwnedMutexGuard<()>,
+}
+
+impl CommandGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn lock(&self, info_hash: &str) -> Result<TorrentGuard, CommandError> {
+        let info_hash = validate_info_hash(info_hash)?;
+        if self.removing.lock().unwrap().contains(&info_hash) {
+            return Err(CommandError::InvalidState { state: "removing" });
+        }
+        let lock = self
+            .torrents
+            .lock()
+            .unwrap()
+            .entry(info_hash.clone())
+            .or_default()
+            .clone();
+        let permit = tokio::time::timeout(BUSY_TIMEOUT, lock.lock_owned())
+            .await
+            .map_err(|_| CommandError::EngineBusy)?;
+        Ok(TorrentGuard {
+            info_hash,
+            _permit: permit,
+        })
+    }
+
+    // Turn away the commands of the torrent until `forget` once it's removed.
+    pub fn mark_removing(&self, guard: &TorrentGuard) {
+        self.removing
+            .lock()
+            .unwrap()
+            .insert(guard.info_hash.clone());
+    }
+
+    // The torrent is gone, its commands fail with not found from now on.
+    pub fn forget(&self, guard: TorrentGuard) {
+        self.removing.lock().unwrap().remove(&guard.info_hash);
+        self.torrents.lock().unwrap().remove(&guard.info_hash);
+    }
+}
+
+// The hex of the v1 info hash or the truncated v2 info hash, which the torrents are known by.
+pub fn validate_info_hash(info_hash: &str) -> Result<String, CommandError> {
+    if info_hash.len() != 40 || !info_hash.chars().all(|it| it.is_ascii_hexdigit()) {
+        return Err(CommandError::InvalidTorrentId {
+            info_hash: info_hash.to_string(),
+        });
+    }
+    Ok(info_hash.to_lowercase())
+}
+
+// The info hash the engine knows the torrent by.
+pub fn decode_info_hash(info_hash: &str) -> Result<[u8; 20], CommandError> {
+    let hex = validate_info_hash(info_hash)?;
+    let mut decoded = [0; 20];
+    for (i, byte) in decoded.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| {
+            CommandError::InvalidTorrentId {
+                info_hash: info_hash.to_string(),
+            }
+        })?;
+    }
+    Ok(decoded)
+}
@@ -1,3 +1,10 @@
+use tauri::Manager;
+
+mod commands;
+mod error;
+mod guard;
+mod state;
+
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
 fn greet(name: &str) -> String {
@@ -8,7 +15,57 @@ fn greet(name: &str) -> String {
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        .invoke_handler(tauri::generate_handler![greet])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .setup(|app| {
+            let data_dir = app.path().app_data_dir()?;
+            let state = tauri::async_runtime::block_on(state::AppState::start(data_dir))?;
+            tauri::async_runtime::spawn(state::forward_engine_events(
+                app.handle().clone(),
+                state.engine.subscribe(),
+            ));
+            app.manage(state);
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![
+            greet,
+            commands::statistics,
+            commands::analyze_torrent,
+            commands::torrent_files,
+            commands::add_torrent,
+            commands::add_torrent_with_existing_data,
+            commands::cross_seed,
+            commands::bandwidth_test,
+            commands::export_peer_list,
+            commands::import_peer_list,
+            commands::check_client_identity,
+            commands::profiles,
+            commands::save_profile,
+            commands::disk_cache_modes,
+            commands::remove_profile,
+            commands::switch_profile,
+            commands::get_torrent_options,
+            commands::set_torrent_options,
+            commands::remove_torrent,
+            commands::swarm_history,
+            commands::torrent_health,
+            commands::tracker_status,
+            commands::scrape_trackers,
+            commands::recheck_torrent,
+            commands::peer_activity,
+            commands::connected_peers,
+            commands::peer_failures,
+            commands::dht_status,
+            commands::external_ip
+        ])
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event {
+                let state = app.state::<state::AppState>();
+                tauri::async_runtime::block_on(async {
+                    state.record_statistics().await;
+                    state.stop_announcers().await;
+                });
+                state.save();
+            }
+        });
 }
@@ -1,7 +1,7 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-#[tokio::main]
-async fn main() {
+// Tauri runs its own async runtime, the setup and the exit block on it.
+fn main() {
     bitdrift_lib::run()
 }
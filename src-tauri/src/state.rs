@@ -0,0 +1,469 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tauri::{AppHandle, Emitter};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, watch},
+    time::interval,
+};
+use torrent::{
+    client_identity::ClientIdentity,
+    dedupe::DedupeIndex,
+    dht::{Dht, BOOTSTRAP_NODES},
+    disk::DiskOptions,
+    engine::{Engine, EngineEvent},
+    existing_data::PlaceMode,
+    external_ip::ExternalIp,
+    listener::PeerListener,
+    metainfo::MetaInfo,
+    profile::{Profile, Profiles},
+    qbittorrent::{self, AddOptions},
+    startup::StartupOptions,
+    statistics::Statistics,
+    torrent::Torrent,
+};
+
+use crate::{
+    error::CommandError,
+    guard::{decode_info_hash, CommandGuard},
+};
+
+// The transfers of the torrents are added to the lifetime statistics this often, and saved
+// every few records so a crash loses little of them.
+const RECORD_STATISTICS_INTERVAL: Duration = Duration::from_secs(5);
+const SAVE_STATISTICS_EVERY: u32 = 12;
+
+pub struct AppState {
+    pub statistics: Arc<Mutex<Statistics>>,
+    statistics_path: PathBuf,
+    // The engine subscribes to the profiles, switching one applies what it can without a
+    // restart, see `Profile::needs_restart`.
+    pub profiles: Mutex<Profiles>,
+    profiles_path: PathBuf,
+    // The .torrent files of the added torrents, by their hex info hash, started again with
+    // the app.
+    torrents_dir: PathBuf,
+    // The files linked across the torrents, the references of each torrent are saved in
+    // `<hex>.resume` next to its torrent file.
+    dedupe: Arc<Mutex<DedupeIndex>>,
+    // None while the DHT is off, or its port couldn't be bound. Started once with the app, the
+    // listener and the engine share it.
+    pub dht: Option<Arc<Dht>>,
+    // Handed to the announcers and the listener, they learn our address from the others.
+    pub external_ip: Arc<ExternalIp>,
+    // Serves, announces and dials the peers of the torrents, shared with the WebUI API.
+    pub engine: Arc<Engine>,
+    // Taken by the commands changing a torrent, see `CommandGuard`.
+    pub guard: CommandGuard,
+}
+
+impl AppState {
+    // The listener is bound with the settings of the active profile.
+    pub async fn start(data_dir: PathBuf) -> std::io::Result<Self> {
+        let statistics_path = data_dir.join("statistics.dat");
+        let statistics = Statistics::load(&statistics_path).unwrap_or_else(|e| {
+            log::warn!("Failed to load statistics, start from zero: {:?}", e);
+            Statistics::new()
+        });
+        let statistics = Arc::new(Mutex::new(statistics));
+        let profiles_path = data_dir.join("profiles.dat");
+        let profiles = Profiles::load(&profiles_path).unwrap_or_else(|e| {
+            log::warn!("Failed to load profiles, start with the default: {:?}", e);
+            Profiles::new()
+        });
+        let profile = profiles.active();
+        let identity = ClientIdentity::default();
+        let external_ip = Arc::new(ExternalIp::new());
+        let dht = match profile.dht_addr() {
+            Some(addr) => start_dht(addr, &external_ip).await,
+            None => None,
+        };
+        let listener = PeerListener::bind_local(
+            &profile.bind_settings(),
+            profile.pick_listen_port(),
+            identity.generate_peer_id(),
+        )
+        .await?
+        .with_encryption(profile.encryption)
+        .with_external_ip(external_ip.clone());
+        let listener = match &dht {
+            Some(dht) => listener.with_dht(dht.clone()),
+            None => listener,
+        };
+        let torrents_dir = data_dir.join("torrents");
+        let dedupe = Arc::new(Mutex::new(load_dedupe(&torrents_dir)));
+        let disk_options = DiskOptions {
+            dedupe: Some(dedupe.clone()),
+            ..profile.disk_options()
+        };
+        let engine = Arc::new(
+            Engine::start(listener, identity)
+                .with_external_ip(external_ip.clone())
+                .with_disk_options(disk_options)
+                .with_hash_check_options(profile.hash_check_options())
+                .with_stopped_grace(profile.stopped_announce_grace()),
+        );
+        engine.watch_disks();
+        tokio::spawn(apply_profiles(engine.clone(), profiles.subscribe()));
+        let library = load_library(&torrents_dir);
+        let starting = engine.clone();
+        tokio::spawn(async move {
+            starting
+                .start_torrents(library, StartupOptions::default())
+                .await;
+        });
+        tokio::spawn(record_statistics(
+            engine.clone(),
+            statistics.clone(),
+            statistics_path.clone(),
+        ));
+        if let Some(addr) = profile.web_api_addr() {
+            let listener = TcpListener::bind(addr).await?;
+            let serve = qbittorrent::serve(
+                listener,
+                engine.clone(),
+                profile.web_api_credentials.clone(),
+            );
+            tokio::spawn(async move {
+                if let Err(e) = serve.await {
+                    log::error!("WebUI API stopped: {:?}", e);
+                }
+            });
+        }
+        Ok(Self {
+            statistics,
+            statistics_path,
+            profiles: Mutex::new(profiles),
+            profiles_path,
+            torrents_dir,
+            dedupe,
+            dht,
+            external_ip,
+            engine,
+            guard: CommandGuard::new(),
+        })
+    }
+
+    pub fn torrent(
+        &self,
+        info_hash: &str,
+    ) -> Result<Arc<tokio::sync::Mutex<Torrent>>, CommandError> {
+        let torrent = self.engine.torrent(&decode_info_hash(info_hash)?);
+        torrent.ok_or_else(|| CommandError::TorrentNotFound {
+            info_hash: info_hash.to_lowercase(),
+        })
+    }
+
+    // Serve and announce the torrent, it's known by the returned hex info hash from now on.
+    pub async fn add_torrent(&self, torrent: Torrent) -> Result<String, CommandError> {
+        let info_hash: String = torrent
+            .info_hash()
+            .iter()
+            .map(|it| format!("{:02x}", it))
+            .collect();
+        let _guard = self.guard.lock(&info_hash).await?;
+        self.engine.add_torrent(torrent).await?;
+        Ok(info_hash)
+    }
+
+    // Same as `add_torrent`, the file is kept to start the torrent again with the app.
+    pub async fn add_torrent_file(&self, bytes: Vec<u8>) -> Result<String, CommandError> {
+        let metainfo = MetaInfo::from_bytes(&bytes)?;
+        let info_hash = self.add_torrent(Torrent::from_metainfo(metainfo)).await?;
+        self.keep_torrent_file(&info_hash, &bytes);
+        Ok(info_hash)
+    }
+
+    // Same as `add_torrent_file`, the files the user has in `source_dir` are placed in the
+    // save path and rechecked first, only the pieces they miss are downloaded.
+    pub async fn add_torrent_with_existing_data(
+        &self,
+        bytes: Vec<u8>,
+        source_dir: PathBuf,
+    ) -> Result<String, CommandError> {
+        let metainfo = MetaInfo::from_bytes(&bytes)?;
+        let info_hash: String = metainfo
+            .info_hash
+            .iter()
+            .map(|it| format!("{:02x}", it))
+            .collect();
+        let _guard = self.guard.lock(&info_hash).await?;
+        self.engine
+            .add_with_existing_data(
+                metainfo,
+                source_dir,
+                PlaceMode::default(),
+                AddOptions::default(),
+            )
+            .await?;
+        self.keep_torrent_file(&info_hash, &bytes);
+        self.save_resume_data(&info_hash);
+        Ok(info_hash)
+    }
+
+    // Seed the torrents of the .torrent files in `torrents_dir` whose data the user has in
+    // `data_dir`, returns their hex info hashes. The files are kept like `add_torrent_file`.
+    pub async fn cross_seed(
+        &self,
+        torrents_dir: PathBuf,
+        data_dir: PathBuf,
+    ) -> Result<Vec<String>, CommandError> {
+        let added = self
+            .engine
+            .cross_seed(torrents_dir, data_dir, AddOptions::default())
+            .await?;
+        let mut info_hashes = Vec::with_capacity(added.len());
+        for (path, info_hash) in added {
+            let info_hash: String = info_hash.iter().map(|it| format!("{:02x}", it)).collect();
+            match std::fs::read(&path) {
+                Ok(bytes) => self.keep_torrent_file(&info_hash, &bytes),
+                Err(e) => log::warn!("Failed to read torrent {}: {:?}", path.display(), e),
+            }
+            self.save_resume_data(&info_hash);
+            info_hashes.push(info_hash);
+        }
+        Ok(info_hashes)
+    }
+
+    fn keep_torrent_file(&self, info_hash: &str, bytes: &[u8]) {
+        let path = self.torrents_dir.join(format!("{}.torrent", info_hash));
+        let saved =
+            std::fs::create_dir_all(&self.torrents_dir).and_then(|_| std::fs::write(path, bytes));
+        if let Err(e) = saved {
+            log::warn!(
+                "Failed to save torrent {}, it won't start with the app: {:?}",
+                info_hash,
+                e
+            );
+        }
+    }
+
+    // The removed torrent doesn't start with the app anymore.
+    pub fn forget_torrent_file(&self, info_hash: &str) {
+        for extension in ["torrent", "resume"] {
+            let path = self
+                .torrents_dir
+                .join(format!("{}.{}", info_hash, extension));
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    log::warn!("Failed to delete torrent {}: {:?}", info_hash, e);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // The files the torrent shares with the others, to link them again after a restart.
+    fn save_resume_data(&self, info_hash: &str) {
+        let Ok(hash) = decode_info_hash(info_hash) else {
+            return;
+        };
+        let path = self.torrents_dir.join(format!("{}.resume", info_hash));
+        let resume_data = self.dedupe.lock().unwrap().resume_data(hash);
+        let saved = match resume_data {
+            Ok(resume_data) => std::fs::write(path, resume_data),
+            Err(e) => {
+                log::warn!("Failed to encode resume data of {}: {:?}", info_hash, e);
+                return;
+            }
+        };
+        if let Err(e) = saved {
+            log::warn!("Failed to save resume data of {}: {:?}", info_hash, e);
+        }
+    }
+
+    // Quitting, the stopped announces go out within the grace of the active profile.
+    pub async fn stop_announcers(&self) {
+        let grace = self
+            .profiles
+            .lock()
+            .unwrap()
+            .active()
+            .stopped_announce_grace();
+        if !self.engine.stop(grace).await {
+            log::warn!("Quit before all the trackers took the stopped announce");
+        }
+    }
+
+    // Quitting, what the torrents transferred since the last record.
+    pub async fn record_statistics(&self) {
+        let snapshots = self.engine.transfer_snapshots().await;
+        self.statistics.lock().unwrap().record_torrents(&snapshots);
+    }
+
+    pub fn save(&self) {
+        let statistics = self.statistics.lock().unwrap();
+        if let Err(e) = statistics.save(&self.statistics_path) {
+            log::warn!("Failed to save statistics: {:?}", e);
+        }
+        drop(statistics);
+        self.save_profiles();
+        // A torrent shares fewer files once it wrote into them
+        for info_hash in library_hashes(&self.torrents_dir) {
+            self.save_resume_data(&info_hash);
+        }
+    }
+
+    // Saved on every change, the profiles are what the user edited by hand.
+    pub fn save_profiles(&self) {
+        let profiles = self.profiles.lock().unwrap();
+        if let Err(e) = profiles.save(&self.profiles_path) {
+            log::warn!("Failed to save profiles: {:?}", e);
+        }
+    }
+}
+
+// Runs with the app, the statistics are saved once more on quitting.
+async fn record_statistics(engine: Arc<Engine>, statistics: Arc<Mutex<Statistics>>, path: PathBuf) {
+    let mut ticker = interval(RECORD_STATISTICS_INTERVAL);
+    let mut records = 0;
+    loop {
+        ticker.tick().await;
+        let snapshots = engine.transfer_snapshots().await;
+        let mut lifetime = statistics.lock().unwrap();
+        lifetime.record_torrents(&snapshots);
+        records += 1;
+        if records % SAVE_STATISTICS_EVERY == 0 {
+            if let Err(e) = lifetime.save(&path) {
+                log::warn!("Failed to save statistics: {:?}", e);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+struct DiskFailed {
+    info_hash: String,
+    piece_index: usize,
+    error: String,
+}
+
+#[derive(Clone, Serialize)]
+struct FilesTrashed {
+    info_hash: String,
+    paths: Vec<PathBuf>,
+}
+
+// Runs with the app, the frontend shows the user what went wrong.
+pub async fn forward_engine_events(app: AppHandle, mut events: broadcast::Receiver<EngineEvent>) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let emitted = match event {
+            EngineEvent::DiskFailed {
+                info_hash,
+                piece_index,
+                error,
+            } => {
+                let error = match std::error::Error::source(&*error) {
+                    Some(source) => format!("{}: {}", error, source),
+                    None => error.to_string(),
+                };
+                let payload = DiskFailed {
+                    info_hash: info_hash.iter().map(|it| format!("{:02x}", it)).collect(),
+                    piece_index,
+                    error,
+                };
+                app.emit("disk-failed", payload)
+            }
+            // The frontend offers to restore them
+            EngineEvent::FilesTrashed { info_hash, paths } => {
+                let payload = FilesTrashed {
+                    info_hash: info_hash.iter().map(|it| format!("{:02x}", it)).collect(),
+                    paths,
+                };
+                app.emit("files-trashed", payload)
+            }
+        };
+        if let Err(e) = emitted {
+            log::warn!("Failed to tell the frontend: {:?}", e);
+        }
+    }
+}
+
+// The DHT is left off if its port can't be bound, the trackers still find the peers. The
+// bootstrap runs in the background, the torrents announce once it knows some nodes.
+async fn start_dht(addr: SocketAddr, external_ip: &ExternalIp) -> Option<Arc<Dht>> {
+    let dht = match Dht::bind_with_external_ip(addr, external_ip.ip()).await {
+        Ok(dht) => Arc::new(dht),
+        Err(e) => {
+            log::warn!("Failed to start DHT: {:?}", e);
+            return None;
+        }
+    };
+    let bootstrapping = dht.clone();
+    tokio::spawn(async move {
+        if bootstrapping.bootstrap(BOOTSTRAP_NODES).await == 0 {
+            log::warn!("DHT bootstrap found no nodes, only the peers may add some");
+        }
+    });
+    Some(dht)
+}
+
+// Runs with the app, the engine follows the switched or edited active profile.
+async fn apply_profiles(engine: Arc<Engine>, mut profiles: watch::Receiver<Profile>) {
+    let mut previous = profiles.borrow_and_update().clone();
+    while profiles.changed().await.is_ok() {
+        let profile = profiles.borrow_and_update().clone();
+        engine.apply_profile(&profile, &previous).await;
+        previous = profile;
+    }
+}
+
+// The hex info hashes of the torrents in the library.
+fn library_hashes(torrents_dir: &Path) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(torrents_dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|it| it.ok())
+        .map(|it| it.path())
+        .filter(|it| it.extension().is_some_and(|it| it == "torrent"))
+        .filter_map(|it| Some(it.file_stem()?.to_string_lossy().into_owned()))
+        .collect()
+}
+
+// The references of the torrents which share files, from their resume data.
+fn load_dedupe(torrents_dir: &Path) -> DedupeIndex {
+    let mut index = DedupeIndex::new();
+    for info_hash in library_hashes(torrents_dir) {
+        let path = torrents_dir.join(format!("{}.resume", info_hash));
+        let Ok(resume_data) = std::fs::read(&path) else {
+            continue;
+        };
+        if let Err(e) = index.restore(&resume_data) {
+            log::warn!("Failed to load resume data {}: {:?}", path.display(), e);
+        }
+    }
+    index
+}
+
+// The torrents added before, the files which can't be read are skipped.
+fn load_library(torrents_dir: &Path) -> Vec<Torrent> {
+    let Ok(entries) = std::fs::read_dir(torrents_dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|it| it.ok())
+        .map(|it| it.path())
+        .filter(|it| it.extension().is_some_and(|it| it == "torrent"))
+        .filter_map(|path| {
+            let metainfo = std::fs::read(&path)
+                .ok()
+                .and_then(|bytes| MetaInfo::from_bytes(&bytes).ok());
+            if metainfo.is_none() {
+                log::warn!("Failed to load torrent {}", path.display());
+            }
+            metainfo.map(Torrent::from_metainfo)
+        })
+        .collect()
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use url::Url;
+
+use crate::types::Sha1Hash;
+
+// The peers of an announce are worth dialing for a while after, most of them are still in
+// the swarm. Keep the last peer list of each tracker, so a torrent whose trackers are all
+// down can redial the peers it saw recently instead of sitting idle until one is back.
+pub const DEFAULT_MAX_PEER_AGE: Duration = Duration::from_secs(30 * 60);
+
+pub struct AnnounceCache {
+    // The cached peers older than this are not dialed anymore.
+    max_age: Duration,
+    entries: Mutex<HashMap<(Sha1Hash, Url), Entry>>,
+}
+
+struct Entry {
+    // From the last successful announce, None if it never succeeded.
+    peers: Option<(Instant, Vec<SocketAddr>)>,
+    // The last announce failed.
+    failing: bool,
+}
+
+impl AnnounceCache {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record_success(&self, info_hash: Sha1Hash, tracker: &Url, peers: &[SocketAddr]) {
+        self.entries.lock().unwrap().insert(
+            (info_hash, tracker.clone()),
+            Entry {
+                peers: Some((Instant::now(), peers.to_vec())),
+                failing: false,
+            },
+        );
+    }
+
+    // The peers of the last successful announce are kept.
+    pub fn record_failure(&self, info_hash: Sha1Hash, tracker: &Url) {
+        self.entries
+            .lock()
+            .unwrap()
+            .entry((info_hash, tracker.clone()))
+            .or_insert(Entry {
+                peers: None,
+                failing: true,
+            })
+            .failing = true;
+    }
+
+    // The recently seen peers of the torrent, only when every tracker it announced to is
+    // failing, otherwise the working trackers hand out fresher peers.
+    pub fn fallback_peers(&self, info_hash: Sha1Hash) -> Vec<SocketAddr> {
+        let entries = self.entries.lock().unwrap();
+        let mut trackers = entries
+            .iter()
+            .filter(|((it, _), _)| *it == info_hash)
+            .map(|(_, entry)| entry)
+            .peekable();
+        if trackers.peek().is_none() {
+            return Vec::new();
+        }
+        let mut seen = HashSet::new();
+        let mut peers = Vec::new();
+        for entry in trackers {
+            if !entry.failing {
+                return Vec::new();
+            }
+            let Some((received_at, cached)) = &entry.peers else {
+                continue;
+            };
+            if received_at.elapsed() > self.max_age {
+                continue;
+            }
+            peers.extend(cached.iter().filter(|it| seen.insert(**it)));
+        }
+        peers
+    }
+
+    // The torrent is removed.
+    pub fn forget(&self, info_hash: Sha1Hash) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|(it, _), _| *it != info_hash);
+    }
+}
+
+impl Default for AnnounceCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_PEER_AGE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fallback_peers() {
+        let cache = AnnounceCache::default();
+        let info_hash = [1u8; 20];
+        let a = Url::parse("http://a.com/announce").unwrap();
+        let b = Url::parse("http://b.com/announce").unwrap();
+        let peer1: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let peer2: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+
+        cache.record_success(info_hash, &a, &[peer1, peer2]);
+        cache.record_success(info_hash, &b, &[peer2]);
+        // The trackers are working
+        assert!(cache.fallback_peers(info_hash).is_empty());
+
+        cache.record_failure(info_hash, &a);
+        assert!(cache.fallback_peers(info_hash).is_empty());
+
+        cache.record_failure(info_hash, &b);
+        let mut peers = cache.fallback_peers(info_hash);
+        peers.sort();
+        assert_eq!(peers, vec![peer1, peer2]);
+        assert!(cache.fallback_peers([2u8; 20]).is_empty());
+
+        // Back to work
+        cache.record_success(info_hash, &b, &[]);
+        assert!(cache.fallback_peers(info_hash).is_empty());
+
+        cache.forget(info_hash);
+        cache.record_failure(info_hash, &a);
+        assert!(cache.fallback_peers(info_hash).is_empty());
+    }
+
+    #[test]
+    fn test_expired_peers() {
+        let cache = AnnounceCache::new(Duration::ZERO);
+        let info_hash = [1u8; 20];
+        let a = Url::parse("http://a.com/announce").unwrap();
+        cache.record_success(info_hash, &a, &["10.0.0.1:6881".parse().unwrap()]);
+        cache.record_failure(info_hash, &a);
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(cache.fallback_peers(info_hash).is_empty());
+    }
+}
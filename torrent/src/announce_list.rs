@@ -0,0 +1,298 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use rand::seq::SliceRandom;
+use url::Url;
+
+use crate::tracker;
+
+// The trackers of a torrent grouped in tiers, announced in the order of BEP 12:
+// the tiers are tried in order, the trackers in a tier are shuffled once,
+// and the tracker which responds is moved to the front of its tier.
+// https://www.bittorrent.org/beps/bep_0012.html
+// A failing tracker is skipped for this long, doubled on every failure in a row.
+const MIN_BACKOFF: Duration = Duration::from_secs(15);
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone)]
+pub struct AnnounceList {
+    tiers: Vec<Vec<Url>>,
+    // The trackers which failed the last time, skipped until they can be retried.
+    backoff: HashMap<Url, Backoff>,
+    // The failures since the last `take_failures`.
+    failures: Vec<TrackerFailure>,
+}
+
+#[derive(Debug, Clone)]
+struct Backoff {
+    failures: u32,
+    retry_at: Instant,
+}
+
+// A tracker failed to answer an announce.
+#[derive(Debug, Clone)]
+pub struct TrackerFailure {
+    pub url: Url,
+    pub error: String,
+    // How many announces in a row it failed.
+    pub failures: u32,
+    // It's skipped until then.
+    pub retry_in: Duration,
+}
+
+impl AnnounceList {
+    pub fn new(mut tiers: Vec<Vec<Url>>) -> Self {
+        let mut rng = rand::rng();
+        for tier in tiers.iter_mut() {
+            tier.shuffle(&mut rng);
+        }
+        Self {
+            tiers,
+            backoff: HashMap::new(),
+            failures: Vec::new(),
+        }
+    }
+
+    pub fn tiers(&self) -> &[Vec<Url>] {
+        &self.tiers
+    }
+
+    // The tracker worked, try it first next time.
+    pub fn promote(&mut self, url: &Url) {
+        for tier in self.tiers.iter_mut() {
+            if let Some(position) = tier.iter().position(|it| it == url) {
+                let url = tier.remove(position);
+                tier.insert(0, url);
+                return;
+            }
+        }
+    }
+
+    // Announce to the trackers in order until one responds, returns the tracker and its response.
+    // The trackers backing off from their failures are skipped, the next one is tried instead.
+    pub async fn announce<F, Fut>(&mut self, mut announce: F) -> Option<(Url, tracker::Response)>
+    where
+        F: FnMut(Url) -> Fut,
+        Fut: Future<Output = tracker::Result<tracker::Response>>,
+    {
+        for url in self.ready_urls() {
+            match announce(url.clone()).await {
+                Ok(response) => {
+                    self.backoff.remove(&url);
+                    self.promote(&url);
+                    return Some((url, response));
+                }
+                Err(e) => {
+                    log::warn!("Failed to announce to {}: {:?}", url, e);
+                    self.record_failure(url, e.to_string());
+                }
+            }
+        }
+        None
+    }
+
+    // Announce to all the trackers of all the tiers at once, as most clients do instead of
+    // the order of BEP 12. Returns the trackers which responded with their responses.
+    pub async fn announce_all<F, Fut>(&mut self, mut announce: F) -> Vec<(Url, tracker::Response)>
+    where
+        F: FnMut(Url) -> Fut,
+        Fut: Future<Output = tracker::Result<tracker::Response>>,
+    {
+        let results = join_all(self.ready_urls().into_iter().map(|url| {
+            let result = announce(url.clone());
+            async move { (url, result.await) }
+        }))
+        .await;
+        let mut responded = Vec::new();
+        for (url, result) in results {
+            match result {
+                Ok(response) => {
+                    self.backoff.remove(&url);
+                    responded.push((url, response));
+                }
+                Err(e) => {
+                    log::warn!("Failed to announce to {}: {:?}", url, e);
+                    self.record_failure(url, e.to_string());
+                }
+            }
+        }
+        responded
+    }
+
+    // The trackers in order, without the ones backing off.
+    fn ready_urls(&self) -> Vec<Url> {
+        let now = Instant::now();
+        self.tiers
+            .iter()
+            .flatten()
+            .filter(|url| self.backoff.get(*url).is_none_or(|it| it.retry_at <= now))
+            .cloned()
+            .collect()
+    }
+
+    fn record_failure(&mut self, url: Url, error: String) {
+        let failures = self.backoff.get(&url).map_or(0, |it| it.failures) + 1;
+        let retry_in = MIN_BACKOFF
+            .saturating_mul(1 << (failures - 1).min(16))
+            .min(MAX_BACKOFF);
+        self.backoff.insert(
+            url.clone(),
+            Backoff {
+                failures,
+                retry_at: Instant::now() + retry_in,
+            },
+        );
+        self.failures.push(TrackerFailure {
+            url,
+            error,
+            failures,
+            retry_in,
+        });
+    }
+
+    // The trackers which failed since the last call.
+    pub fn take_failures(&mut self) -> Vec<TrackerFailure> {
+        std::mem::take(&mut self.failures)
+    }
+
+    // When the first of the failing trackers can be tried again.
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.backoff.values().map(|it| it.retry_at).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::{Response, TrackerError};
+
+    fn url(host: &str) -> Url {
+        Url::parse(&format!("http://{}/announce", host)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_announce_promotes_responding_tracker() {
+        let mut list = AnnounceList::new(vec![
+            vec![url("a1"), url("a2"), url("a3")],
+            vec![url("b1"), url("b2")],
+        ]);
+        // Tiers are kept in order, only shuffled within
+        assert_eq!(list.tiers()[0].len(), 3);
+        assert!(list.tiers()[1].contains(&url("b1")));
+
+        let mut attempts = Vec::new();
+        let (responded, _) = list
+            .announce(|url| {
+                attempts.push(url.clone());
+                async move {
+                    if url.host_str() == Some("b2") {
+                        Ok(Response {
+                            interval: 1800,
+                            min_interval: None,
+                            warning: None,
+                            peers: Vec::new(),
+                            seeders: None,
+                            leechers: None,
+                            tracker_id: None,
+                            external_ip: None,
+                        })
+                    } else {
+                        Err(TrackerError::QueryPeers("down".to_string()))
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(responded, url("b2"));
+        // All of the first tier is tried before the second tier
+        assert!(attempts[..3].iter().all(|it| list.tiers()[0].contains(it)));
+        assert_eq!(list.tiers()[1][0], url("b2"));
+    }
+
+    #[tokio::test]
+    async fn test_announce_all_tiers() {
+        let mut list = AnnounceList::new(vec![vec![url("a1"), url("a2")], vec![url("b")]]);
+        let mut responded = list
+            .announce_all(|url| async move {
+                if url.host_str() == Some("a2") {
+                    Err(TrackerError::QueryPeers("down".to_string()))
+                } else {
+                    Ok(Response {
+                        interval: 1800,
+                        min_interval: None,
+                        warning: None,
+                        peers: Vec::new(),
+                        seeders: None,
+                        leechers: None,
+                        tracker_id: None,
+                        external_ip: None,
+                    })
+                }
+            })
+            .await
+            .into_iter()
+            .map(|(url, _)| url)
+            .collect::<Vec<_>>();
+        responded.sort();
+        // The second tier is announced to even though the first responded
+        assert_eq!(responded, [url("a1"), url("b")]);
+        assert_eq!(list.take_failures()[0].url, url("a2"));
+    }
+
+    #[test]
+    fn test_failing_tracker_backs_off() {
+        let mut list = AnnounceList::new(vec![vec![url("a")], vec![url("b")]]);
+        let announce = |list: &mut AnnounceList, up: &'static [&'static str]| {
+            let mut attempts = Vec::new();
+            let result = futures::executor::block_on(list.announce(|url| {
+                attempts.push(url.host_str().unwrap().to_string());
+                async move {
+                    if up.contains(&url.host_str().unwrap()) {
+                        Ok(Response {
+                            interval: 1800,
+                            min_interval: None,
+                            warning: None,
+                            peers: Vec::new(),
+                            seeders: None,
+                            leechers: None,
+                            tracker_id: None,
+                            external_ip: None,
+                        })
+                    } else {
+                        Err(TrackerError::QueryPeers("down".to_string()))
+                    }
+                }
+            }));
+            (result.map(|(url, _)| url), attempts)
+        };
+
+        // Fail over to the next tier
+        let (responded, attempts) = announce(&mut list, &["b"]);
+        assert_eq!(responded, Some(url("b")));
+        assert_eq!(attempts, ["a", "b"]);
+        let failures = list.take_failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(
+            (failures[0].failures, failures[0].retry_in),
+            (1, MIN_BACKOFF)
+        );
+        assert!(list.take_failures().is_empty());
+
+        // The failing tracker is skipped while backing off
+        let (_, attempts) = announce(&mut list, &[]);
+        assert_eq!(attempts, ["b"]);
+        assert_eq!(list.take_failures()[0].url, url("b"));
+        assert!(list.next_retry().unwrap() > Instant::now());
+
+        list.backoff.get_mut(&url("a")).unwrap().retry_at = Instant::now();
+        let (responded, attempts) = announce(&mut list, &[]);
+        assert_eq!(responded, None);
+        assert_eq!(attempts, ["a"]);
+        let failures = list.take_failures();
+        assert_eq!(failures[0].retry_in, MIN_BACKOFF * 2);
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{Mutex as AsyncMutex, OwnedSemaphorePermit, Semaphore},
+    time::{Instant, sleep_until},
+};
+use url::Url;
+
+// Limit how hard we hit a single tracker host when many torrents announce to it,
+// some trackers ban the clients which send too many requests at once.
+// The announces are served in the order they arrive, so every torrent gets its turn.
+pub struct AnnounceThrottle {
+    max_concurrent_per_host: usize,
+    // Minimum gap between two announces start to the same host.
+    min_interval_per_host: Duration,
+    hosts: Mutex<HashMap<String, Arc<HostLimiter>>>,
+}
+
+struct HostLimiter {
+    // tokio semaphore is fair, the waiting announces acquire it in FIFO order.
+    semaphore: Arc<Semaphore>,
+    next_slot: AsyncMutex<Instant>,
+}
+
+// Hold it until the announce is finished.
+pub struct AnnouncePermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl AnnounceThrottle {
+    pub fn new(max_concurrent_per_host: usize, min_interval_per_host: Duration) -> Self {
+        Self {
+            max_concurrent_per_host: max_concurrent_per_host.max(1),
+            min_interval_per_host,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Wait until the announce to the tracker is allowed.
+    pub async fn acquire(&self, url: &Url) -> AnnouncePermit {
+        let limiter = self.host_limiter(url);
+        let permit = limiter
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Announce semaphore is never closed");
+
+        let mut next_slot = limiter.next_slot.lock().await;
+        sleep_until(*next_slot).await;
+        *next_slot = Instant::now() + self.min_interval_per_host;
+
+        AnnouncePermit { _permit: permit }
+    }
+
+    fn host_limiter(&self, url: &Url) -> Arc<HostLimiter> {
+        let host = format!(
+            "{}:{}",
+            url.host_str().unwrap_or_default(),
+            url.port_or_known_default().unwrap_or_default()
+        );
+        self.hosts
+            .lock()
+            .unwrap()
+            .entry(host)
+            .or_insert_with(|| {
+                Arc::new(HostLimiter {
+                    semaphore: Arc::new(Semaphore::new(self.max_concurrent_per_host)),
+                    next_slot: AsyncMutex::new(Instant::now()),
+                })
+            })
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_acquire_limits_concurrency_per_host() {
+        let throttle = Arc::new(AnnounceThrottle::new(1, Duration::ZERO));
+        let url = Url::parse("http://tracker.example.com/announce").unwrap();
+        let other_url = Url::parse("http://other.example.com/announce").unwrap();
+
+        let permit = throttle.acquire(&url).await;
+
+        // Other hosts are not affected
+        let other_permit = throttle.acquire(&other_url).await;
+        drop(other_permit);
+
+        let waiting = {
+            let throttle = throttle.clone();
+            let url = url.clone();
+            tokio::spawn(async move {
+                throttle.acquire(&url).await;
+            })
+        };
+        sleep_until(Instant::now() + Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        drop(permit);
+        waiting.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_acquire_keeps_interval_per_host() {
+        let throttle = AnnounceThrottle::new(2, Duration::from_millis(100));
+        let url = Url::parse("http://tracker.example.com/announce").unwrap();
+
+        let started_at = Instant::now();
+        let _first = throttle.acquire(&url).await;
+        let _second = throttle.acquire(&url).await;
+        assert!(started_at.elapsed() >= Duration::from_millis(100));
+    }
+}
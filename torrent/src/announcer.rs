@@ -0,0 +1,574 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{self, Arc},
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+use tokio::{
+    sync::{Mutex, broadcast, mpsc, oneshot},
+    task::JoinHandle,
+    time::{Instant, interval, sleep_until, timeout},
+};
+use url::Url;
+
+use crate::{
+    announce_list::{AnnounceList, TrackerFailure},
+    client_identity::ClientIdentity,
+    dialer::{DialCandidate, PeerSource},
+    external_ip::ExternalIp,
+    listener::ListenPort,
+    torrent::{Torrent, TorrentPhase},
+    tracker::{self, RequestParams, Tracker, TrackerEvent},
+    transfer::TransferSnapshot,
+    types::{PeerId, Sha1Hash},
+};
+
+// Announce again this soon when none of the trackers responded and none is backing off.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+// A tracker which takes longer is counted as failed, the next one is tried.
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(30);
+// How often to check if the download finished, to send the completed event.
+const COMPLETION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// Don't hold the removal or the shutdown for long on a tracker which doesn't respond.
+pub const DEFAULT_STOPPED_GRACE: Duration = Duration::from_secs(5);
+// Whatever the user sets, the client must quit.
+const MAX_STOPPED_GRACE: Duration = Duration::from_secs(30);
+// Peers asked for in each announce, the trackers default to 50 which is too few to choose from.
+const NUMWANT: u32 = 200;
+
+// Drive the announces of a torrent over its lifetime: started when it's added, again every
+// interval the tracker asks for, completed when the download finishes and stopped at the end.
+// The peers from the trackers go to the peer manager.
+pub struct Announcer {
+    torrent: Arc<Mutex<Torrent>>,
+    info_hash: Sha1Hash,
+    trackers: AnnounceList,
+    clients: HashMap<Url, Tracker>,
+    peer_id: PeerId,
+    // Read on every announce, the listener may be bound again on another port.
+    port: ListenPort,
+    // Random for each announcer, the same in all of its announces.
+    key: u32,
+    peers: mpsc::UnboundedSender<DialCandidate>,
+    events: broadcast::Sender<AnnounceEvent>,
+    status: Arc<sync::Mutex<Vec<TrackerStatus>>>,
+    // Told the address the trackers see us at, None to ignore it.
+    external_ip: Option<Arc<ExternalIp>>,
+    // How long the stopped announce may take.
+    stopped_grace: Duration,
+    // `TorrentOptions::announce_to_all_tiers` as of the last announce.
+    all_tiers: bool,
+}
+
+// What we know of each tracker, for the tracker tab of the UI.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TrackerStatus {
+    pub url: String,
+    pub tier: usize,
+    // None until it's announced to, only one tracker is announced to while it works.
+    pub last_announce: Option<SystemTime>,
+    pub next_announce: Option<SystemTime>,
+    // Cleared when it responds again.
+    pub last_error: Option<String>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    // How many peers the last response had.
+    pub peers: usize,
+    pub stats: TrackerStats,
+}
+
+// How the tracker did since the client started, the evidence to remove a dead tracker.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TrackerStats {
+    pub announces: u32,
+    pub failures: u32,
+    // None before the first announce.
+    pub success_rate: Option<f64>,
+    // Of the announces it responded to.
+    pub last_latency_ms: Option<u64>,
+    pub average_latency_ms: Option<u64>,
+    // Unlike `TrackerStatus::last_error`, kept after it responds again.
+    pub last_failure: Option<String>,
+    #[serde(skip)]
+    total_latency_ms: u64,
+}
+
+impl TrackerStats {
+    fn record_success(&mut self, latency: Duration) {
+        let latency = latency.as_millis() as u64;
+        self.announces += 1;
+        self.total_latency_ms += latency;
+        self.last_latency_ms = Some(latency);
+        self.average_latency_ms =
+            Some(self.total_latency_ms / (self.announces - self.failures) as u64);
+        self.update_success_rate();
+    }
+
+    fn record_failure(&mut self, error: &str) {
+        self.announces += 1;
+        self.failures += 1;
+        self.last_failure = Some(error.to_string());
+        self.update_success_rate();
+    }
+
+    fn update_success_rate(&mut self) {
+        self.success_rate = Some((self.announces - self.failures) as f64 / self.announces as f64);
+    }
+}
+
+// What happened to the announces, for the UI to show the tracker status.
+#[derive(Debug, Clone)]
+pub enum AnnounceEvent {
+    Announced { url: Url, peers: usize },
+    // The tracker accepted the announce with a warning for the user.
+    Warning { url: Url, message: String },
+    // The tracker failed, the next one of the tiers is tried.
+    TrackerFailed(TrackerFailure),
+    // None of the trackers responded, announce again then.
+    AllFailed { retry_in: Duration },
+}
+
+// Stop the announcer of a removed torrent, or when the client quits.
+pub struct AnnouncerHandle {
+    // With the totals to report, None takes them when the stop arrives.
+    stop: oneshot::Sender<Option<TransferSnapshot>>,
+    task: JoinHandle<()>,
+    status: Arc<sync::Mutex<Vec<TrackerStatus>>>,
+}
+
+impl AnnouncerHandle {
+    pub fn tracker_status(&self) -> Vec<TrackerStatus> {
+        self.status.lock().unwrap().clone()
+    }
+
+    // Send the stopped event and wait for it.
+    pub async fn stop(self) {
+        let _ = self.stop.send(None);
+        let _ = self.task.await;
+    }
+
+    // For the shutdown, with the totals taken before the sessions closed. The torrent may be
+    // busy tearing them down by the time the stopped announce goes out.
+    pub async fn stop_with(self, snapshot: TransferSnapshot) {
+        let _ = self.stop.send(Some(snapshot));
+        let _ = self.task.await;
+    }
+}
+
+impl Announcer {
+    pub async fn new(
+        torrent: Arc<Mutex<Torrent>>,
+        peer_id: PeerId,
+        port: ListenPort,
+        identity: &ClientIdentity,
+        peers: mpsc::UnboundedSender<DialCandidate>,
+    ) -> tracker::Result<Self> {
+        let (info_hash, tiers, local_addr, tls, all_tiers) = {
+            let torrent = torrent.lock().await;
+            let options = torrent.options();
+            (
+                torrent.info_hash(),
+                torrent.tracker_tiers(),
+                options.bind_addr,
+                options.tracker_tls.clone(),
+                options.announce_to_all_tiers,
+            )
+        };
+        // The status lists the trackers in the shuffled order they are announced to.
+        let trackers = AnnounceList::new(tiers);
+        let mut clients = HashMap::new();
+        let mut status = Vec::new();
+        for (tier, urls) in trackers.tiers().iter().enumerate() {
+            for url in urls {
+                let client = Tracker::with_tls(url.clone(), local_addr, identity, &tls)?;
+                clients.insert(url.clone(), client);
+                status.push(TrackerStatus {
+                    url: url.to_string(),
+                    tier,
+                    last_announce: None,
+                    next_announce: None,
+                    last_error: None,
+                    seeders: None,
+                    leechers: None,
+                    peers: 0,
+                    stats: TrackerStats::default(),
+                });
+            }
+        }
+        Ok(Self {
+            torrent,
+            info_hash,
+            trackers,
+            clients,
+            peer_id,
+            port,
+            key: rand::random(),
+            peers,
+            events: broadcast::channel(64).0,
+            status: Arc::new(sync::Mutex::new(status)),
+            external_ip: None,
+            stopped_grace: DEFAULT_STOPPED_GRACE,
+            all_tiers,
+        })
+    }
+
+    pub fn with_external_ip(mut self, external_ip: Arc<ExternalIp>) -> Self {
+        self.external_ip = Some(external_ip);
+        self
+    }
+
+    // Capped, a tracker which doesn't respond mustn't keep the client from quitting.
+    pub fn with_stopped_grace(mut self, grace: Duration) -> Self {
+        self.stopped_grace = grace.min(MAX_STOPPED_GRACE);
+        self
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AnnounceEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn tracker_status(&self) -> Vec<TrackerStatus> {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn spawn(self) -> AnnouncerHandle {
+        let (stop, stopped) = oneshot::channel();
+        let status = self.status.clone();
+        let task = tokio::spawn(self.run(stopped));
+        AnnouncerHandle { stop, task, status }
+    }
+
+    fn update_status(&self, url: &Url, update: impl FnOnce(&mut TrackerStatus)) {
+        let mut status = self.status.lock().unwrap();
+        if let Some(status) = status.iter_mut().find(|it| it.url == url.as_str()) {
+            update(status);
+        }
+    }
+
+    async fn run(mut self, mut stop: oneshot::Receiver<Option<TransferSnapshot>>) {
+        let mut next_announce = self.announce(Some(TrackerEvent::Started), None).await;
+        // Only a torrent which finished while we run is completed, not one added as a seed
+        let mut is_completed = self.is_seeding().await;
+        let mut completion_check = interval(COMPLETION_CHECK_INTERVAL);
+        let snapshot = loop {
+            tokio::select! {
+                // The handle dropped stops too
+                snapshot = &mut stop => break snapshot.ok().flatten(),
+                _ = sleep_until(next_announce) => {
+                    next_announce = self.announce(None, None).await;
+                }
+                _ = completion_check.tick() => {
+                    if !is_completed && self.is_seeding().await {
+                        is_completed = true;
+                        next_announce = self.announce(Some(TrackerEvent::Completed), None).await;
+                    }
+                }
+            }
+        };
+        let grace = self.stopped_grace;
+        let stopped = self.announce(Some(TrackerEvent::Stopped), snapshot);
+        if timeout(grace, stopped).await.is_err() {
+            log::warn!("Stopped announce timed out");
+        }
+    }
+
+    async fn is_seeding(&self) -> bool {
+        self.torrent.lock().await.phase().await == TorrentPhase::Seeding
+    }
+
+    fn request_params(
+        &self,
+        event: Option<TrackerEvent>,
+        snapshot: TransferSnapshot,
+    ) -> RequestParams {
+        RequestParams::new(self.info_hash, self.peer_id, self.port.get(), snapshot.left)
+            .with_event(event)
+            .with_transfer(snapshot.uploaded, snapshot.downloaded)
+            .with_key(self.key)
+            // Don't ask for peers we'll never connect to
+            .with_numwant(if event == Some(TrackerEvent::Stopped) {
+                0
+            } else {
+                NUMWANT
+            })
+    }
+
+    // Announce to the first tracker which responds, or to all of them if the torrent is set to,
+    // returns when to announce next. The totals are taken now unless given.
+    async fn announce(
+        &mut self,
+        event: Option<TrackerEvent>,
+        snapshot: Option<TransferSnapshot>,
+    ) -> Instant {
+        let (params, all_tiers) = match snapshot {
+            // Not waiting on the torrent, it may be busy closing the sessions
+            Some(snapshot) => (self.request_params(event, snapshot), self.all_tiers),
+            None => {
+                let torrent = self.torrent.lock().await;
+                let params = self.request_params(event, torrent.transfer_snapshot().await);
+                let params = if event.is_none() && torrent.is_partial_seed().await {
+                    params.as_partial_seed()
+                } else {
+                    params
+                };
+                self.all_tiers = torrent.options().announce_to_all_tiers;
+                (params, self.all_tiers)
+            }
+        };
+        let clients = &self.clients;
+        let params = &params;
+        // How long each tracker took to respond
+        let latencies = &sync::Mutex::new(HashMap::new());
+        let announce = |url: Url| async move {
+            match clients.get(&url) {
+                Some(client) => {
+                    let start = Instant::now();
+                    let result = timeout(ANNOUNCE_TIMEOUT, client.fetch_peers(params.clone()))
+                        .await
+                        .unwrap_or(Err(tracker::TrackerError::Timeout));
+                    latencies.lock().unwrap().insert(url, start.elapsed());
+                    result
+                }
+                None => Err(tracker::TrackerError::QueryPeers(format!(
+                    "Unknown tracker {}",
+                    url
+                ))),
+            }
+        };
+        let responses = if all_tiers {
+            self.trackers.announce_all(announce).await
+        } else {
+            self.trackers.announce(announce).await.into_iter().collect()
+        };
+        for failure in self.trackers.take_failures() {
+            let now = SystemTime::now();
+            self.update_status(&failure.url, |status| {
+                status.last_announce = Some(now);
+                status.next_announce = Some(now + failure.retry_in);
+                status.last_error = Some(failure.error.clone());
+                status.stats.record_failure(&failure.error);
+            });
+            let _ = self.events.send(AnnounceEvent::TrackerFailed(failure));
+        }
+        if responses.is_empty() {
+            let retry_in = self.trackers.next_retry().map_or(RETRY_INTERVAL, |it| {
+                it.saturating_duration_since(std::time::Instant::now())
+            });
+            let _ = self.events.send(AnnounceEvent::AllFailed { retry_in });
+            return Instant::now() + retry_in;
+        }
+        let latencies = latencies.lock().unwrap().clone();
+        for (url, _) in &responses {
+            let latency = latencies.get(url).copied().unwrap_or_default();
+            self.update_status(url, |status| status.stats.record_success(latency));
+        }
+        for (url, response) in &responses {
+            let _ = self.events.send(AnnounceEvent::Announced {
+                url: url.clone(),
+                peers: response.peers.len(),
+            });
+            log::info!(
+                "Announced {:?} to {}, got {} peers",
+                event,
+                url,
+                response.peers.len()
+            );
+            if let (Some(external_ip), Some(ip)) = (&self.external_ip, response.external_ip) {
+                external_ip.vote_from_tracker(url, ip);
+            }
+            if let Some(message) = &response.warning {
+                log::warn!("Tracker {} warns: {}", url, message);
+                let _ = self.events.send(AnnounceEvent::Warning {
+                    url: url.clone(),
+                    message: message.clone(),
+                });
+            }
+        }
+        if event == Some(TrackerEvent::Stopped) {
+            return Instant::now();
+        }
+
+        // The trackers return many of the same peers
+        let mut seen = HashSet::new();
+        let peers: Vec<SocketAddr> = responses
+            .iter()
+            .flat_map(|(_, response)| response.peers.iter().copied())
+            .filter(|addr| seen.insert(*addr))
+            .collect();
+        let seeders = responses.iter().filter_map(|(_, it)| it.seeders).max();
+        // As often as the most eager tracker asks, but no more often than any of them allows,
+        // they may ban the clients announcing more often
+        let interval = responses
+            .iter()
+            .map(|(_, it)| it.interval)
+            .min()
+            .unwrap_or(0);
+        let min_interval = responses
+            .iter()
+            .filter_map(|(_, it)| it.min_interval)
+            .max()
+            .unwrap_or(0);
+        let interval = {
+            let mut torrent = self.torrent.lock().await;
+            torrent.record_swarm(peers.len(), seeders);
+            torrent.announce_interval(Duration::from_secs(interval))
+        };
+        let interval = interval.max(Duration::from_secs(min_interval));
+        let now = SystemTime::now();
+        for (url, response) in &responses {
+            self.update_status(url, |status| {
+                status.last_announce = Some(now);
+                status.next_announce = Some(now + interval);
+                status.last_error = None;
+                status.seeders = response.seeders;
+                status.leechers = response.leechers;
+                status.peers = response.peers.len();
+            });
+        }
+        for addr in peers {
+            let _ = self
+                .peers
+                .send(DialCandidate::new(addr, PeerSource::Tracker, None));
+        }
+        Instant::now() + interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+
+    use super::*;
+    use crate::magnet::MagnetLink;
+
+    #[test]
+    fn test_tracker_stats() {
+        let mut stats = TrackerStats::default();
+        assert_eq!(stats.success_rate, None);
+        stats.record_success(Duration::from_millis(100));
+        stats.record_failure("Timed out");
+        stats.record_success(Duration::from_millis(300));
+        stats.record_failure("Connection refused");
+        assert_eq!(stats.announces, 4);
+        assert_eq!(stats.failures, 2);
+        assert_eq!(stats.success_rate, Some(0.5));
+        assert_eq!(stats.last_latency_ms, Some(300));
+        assert_eq!(stats.average_latency_ms, Some(200));
+        assert_eq!(stats.last_failure.as_deref(), Some("Connection refused"));
+    }
+
+    #[tokio::test]
+    async fn test_announce_lifecycle() {
+        let mut server = mockito::Server::new_async().await;
+        let started = server
+            .mock("GET", "/announce")
+            .match_query(Matcher::UrlEncoded("event".into(), "started".into()))
+            .with_body(b"d8:intervali1800e5:peers6:\x0a\x00\x00\x02\x1a\xe1e")
+            .expect(1)
+            .create_async()
+            .await;
+        let stopped = server
+            .mock("GET", "/announce")
+            .match_query(Matcher::UrlEncoded("event".into(), "stopped".into()))
+            .with_body(b"d8:intervali1800e5:peers0:e")
+            .expect(1)
+            .create_async()
+            .await;
+
+        let magnet = MagnetLink {
+            info_hash: [1; 20],
+            display_name: None,
+            trackers: vec![Url::parse(&format!("{}/announce", server.url())).unwrap()],
+            select_only: None,
+            peers: Vec::new(),
+        };
+        let torrent = Arc::new(Mutex::new(Torrent::from_magnet(magnet)));
+        let (peers, mut discovered) = mpsc::unbounded_channel();
+        let announcer = Announcer::new(
+            torrent,
+            [2; 20],
+            ListenPort::new(6881),
+            &ClientIdentity::default(),
+            peers,
+        )
+        .await
+        .unwrap();
+        let handle = announcer.spawn();
+
+        let candidate = discovered.recv().await.unwrap();
+        assert_eq!(candidate.addr, "10.0.0.2:6881".parse().unwrap());
+        assert_eq!(candidate.source, PeerSource::Tracker);
+        let status = handle.tracker_status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].peers, 1);
+        assert_eq!(status[0].last_error, None);
+        assert!(status[0].next_announce > status[0].last_announce);
+        assert_eq!(status[0].stats.announces, 1);
+        assert_eq!(status[0].stats.success_rate, Some(1.0));
+        assert!(status[0].stats.average_latency_ms.is_some());
+        handle.stop().await;
+        started.assert_async().await;
+        stopped.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_stopped_with_snapshot() {
+        let mut server = mockito::Server::new_async().await;
+        let _started = server
+            .mock("GET", "/announce")
+            .match_query(Matcher::UrlEncoded("event".into(), "started".into()))
+            .with_body(b"d8:intervali1800e5:peers0:e")
+            .create_async()
+            .await;
+        let stopped = server
+            .mock("GET", "/announce")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("event".into(), "stopped".into()),
+                Matcher::UrlEncoded("uploaded".into(), "4096".into()),
+                Matcher::UrlEncoded("downloaded".into(), "1024".into()),
+                Matcher::UrlEncoded("left".into(), "7".into()),
+            ]))
+            .with_body(b"d8:intervali1800e5:peers0:e")
+            .expect(1)
+            .create_async()
+            .await;
+
+        let magnet = MagnetLink {
+            info_hash: [1; 20],
+            display_name: None,
+            trackers: vec![Url::parse(&format!("{}/announce", server.url())).unwrap()],
+            select_only: None,
+            peers: Vec::new(),
+        };
+        let torrent = Arc::new(Mutex::new(Torrent::from_magnet(magnet)));
+        let (peers, _discovered) = mpsc::unbounded_channel();
+        let announcer = Announcer::new(
+            torrent.clone(),
+            [2; 20],
+            ListenPort::new(6881),
+            &ClientIdentity::default(),
+            peers,
+        )
+        .await
+        .unwrap()
+        .with_stopped_grace(Duration::from_secs(2));
+        let mut events = announcer.subscribe();
+        let handle = announcer.spawn();
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            AnnounceEvent::Announced { .. }
+        ));
+
+        // The torrent is busy closing the sessions while the stopped announce goes out
+        let _closing = torrent.lock().await;
+        let snapshot = TransferSnapshot {
+            uploaded: 4096,
+            downloaded: 1024,
+            left: 7,
+        };
+        handle.stop_with(snapshot).await;
+        stopped.assert_async().await;
+    }
+}
@@ -0,0 +1,448 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fs,
+    io::{self, Write},
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use thiserror::Error;
+use tokio::{
+    sync::{Mutex, mpsc},
+    task::JoinSet,
+    time::interval,
+};
+
+use crate::{
+    announcer::Announcer,
+    client_identity::ClientIdentity,
+    disk::DiskOptions,
+    listener::{DialEvent, PeerListener},
+    metainfo::MetaInfo,
+    peer_activity::PeerEvent,
+    torrent::{Torrent, TorrentOptions},
+    tracker::TrackerError,
+    transport::TransportPolicy,
+    types::PeerId,
+    webseed::WebSeed,
+};
+
+// Download a torrent for a while to measure what the connection can do, and tell whether
+// the disk, the network or the swarm holds it back, so the user knows which setting to tune.
+// The torrent is served like any other, by the peer sessions and the web seeds, only it's
+// dropped when the test ends and the downloaded data is thrown away.
+
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(30);
+// With fewer peers willing to send, the swarm is the limit before the network is.
+const MIN_UNCHOKED_PEERS: usize = 3;
+const DISK_PROBE_SIZE: usize = 16 * 1024 * 1024;
+// The disk is the limit if it writes at most this much faster than the download.
+const DISK_HEADROOM: f64 = 1.2;
+// How often the peers which unchoked us are looked up, and the torrent checked for done.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Under the save path, whatever the test writes is removed with it.
+const SCRATCH_DIR: &str = ".bitdrift_bandwidth_test";
+
+#[derive(Debug, Clone)]
+pub struct BandwidthTestOptions {
+    pub duration: Duration,
+    // Where the disk write speed is measured, use the folder the torrents are saved to.
+    pub save_path: PathBuf,
+    pub max_connections: usize,
+    pub transport: TransportPolicy,
+    pub peer_id: PeerId,
+    // The User-Agent of the announces and web seed requests.
+    pub identity: ClientIdentity,
+}
+
+impl Default for BandwidthTestOptions {
+    fn default() -> Self {
+        Self {
+            duration: DEFAULT_DURATION,
+            save_path: std::env::temp_dir(),
+            max_connections: 30,
+            transport: TransportPolicy::default(),
+            peer_id: ClientIdentity::default().generate_peer_id(),
+            identity: ClientIdentity::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitingFactor {
+    // Too few peers or web seeds are sending, more bandwidth won't help.
+    PeerAvailability,
+    // The disk can't write faster than the download.
+    Disk,
+    // The peers have more to give, the link or the rate limits are the cap.
+    Network,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BandwidthReport {
+    pub elapsed: Duration,
+    pub downloaded_bytes: u64,
+    // Bytes per second.
+    pub download_rate: f64,
+    // Bytes per second, None if the disk couldn't be measured.
+    pub disk_write_rate: Option<f64>,
+    pub peers_found: usize,
+    pub connection_attempts: usize,
+    pub connections: usize,
+    // The peers which unchoked us.
+    pub unchoked_peers: usize,
+    pub web_seeds: usize,
+    pub limit: LimitingFactor,
+}
+
+#[derive(Debug, Error)]
+pub enum BandwidthError {
+    #[error("Failed to listen for the peers")]
+    Listen(#[from] io::Error),
+
+    // Only when there is no web seed to test with either.
+    #[error("Failed to set up the trackers of the torrent")]
+    Tracker(#[from] TrackerError),
+
+    #[error("Torrent has neither trackers nor web seeds to test with")]
+    NoSources,
+}
+
+// Listen for the peers so the trackers are told a port we answer on, the listener is
+// stopped with the test.
+pub async fn run(
+    metainfo: MetaInfo,
+    options: BandwidthTestOptions,
+) -> Result<BandwidthReport, BandwidthError> {
+    let web_seeds = WebSeed::from_metainfo(&metainfo);
+    if web_seeds.is_empty() && metainfo.tracker_tiers().is_empty() {
+        return Err(BandwidthError::NoSources);
+    }
+
+    let disk_write_rate = match measure_disk(options.save_path.clone()).await {
+        Ok(rate) => Some(rate),
+        Err(e) => {
+            log::warn!("Failed to measure the disk write speed: {:?}", e);
+            None
+        }
+    };
+
+    let listener = PeerListener::bind((Ipv4Addr::UNSPECIFIED, 0), options.peer_id).await?;
+    let registry = listener.registry();
+    let port = listener.listen_port();
+
+    let info_hash = metainfo.info_hash;
+    let piece_count = metainfo.piece_count();
+    let mut torrent = Torrent::from_metainfo(metainfo.clone());
+    torrent
+        .set_options(TorrentOptions {
+            transport: options.transport,
+            ..TorrentOptions::default()
+        })
+        .await;
+    let torrent = Arc::new(Mutex::new(torrent));
+    let scratch = options.save_path.join(SCRATCH_DIR);
+    // The peers the tracker and the sessions find
+    let (peers_tx, mut peers_rx) = mpsc::unbounded_channel();
+    let announcer = match Announcer::new(
+        torrent.clone(),
+        options.peer_id,
+        port,
+        &options.identity,
+        peers_tx.clone(),
+    )
+    .await
+    {
+        Ok(announcer) => Some(announcer),
+        Err(e) if web_seeds.is_empty() => return Err(e.into()),
+        Err(e) => {
+            log::warn!(
+                "Failed to set up the trackers of the bandwidth test: {:?}",
+                e
+            );
+            None
+        }
+    };
+    let listening = tokio::spawn(async move {
+        if let Err(e) = listener.run().await {
+            log::warn!("Bandwidth test listener stopped: {:?}", e);
+        }
+    });
+    registry
+        .add_shared_torrent(
+            torrent.clone(),
+            peers_tx.clone(),
+            DiskOptions {
+                save_path: scratch.clone(),
+                ..DiskOptions::default()
+            },
+        )
+        .await;
+
+    let started_at = Instant::now();
+    let mut tasks = JoinSet::new();
+    let web_seed_count = web_seeds.len();
+    for web_seed in web_seeds {
+        tasks.spawn(
+            web_seed
+                .with_identity(&options.identity)
+                .run(torrent.clone()),
+        );
+    }
+    let announcer = announcer.map(Announcer::spawn);
+
+    let activity = torrent.lock().await.peer_activity();
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let mut found = HashSet::new();
+    let mut queued = VecDeque::new();
+    let mut active = 0;
+    let mut connection_attempts = 0;
+    let mut connections = 0;
+    let mut unchoked = HashSet::new();
+    let mut ticker = interval(POLL_INTERVAL);
+    let deadline = started_at + options.duration;
+    loop {
+        tokio::select! {
+            Some(candidate) = peers_rx.recv() => {
+                if found.insert(candidate.addr) {
+                    queued.push_back(candidate);
+                }
+            }
+            Some(event) = events.recv() => match event {
+                DialEvent::Connected { .. } => connections += 1,
+                DialEvent::Failed { .. } | DialEvent::Disconnected { .. } => active -= 1,
+            },
+            _ = ticker.tick() => {
+                // The activity of a peer is gone once it disconnects, look while it's here
+                for peer in activity.connected() {
+                    let recent = activity.recent(&peer.addr);
+                    if recent.iter().any(|it| it.event == PeerEvent::UnchokedUs) {
+                        unchoked.insert(peer.addr);
+                    }
+                }
+                if torrent.lock().await.left_bytes().await == 0 {
+                    break;
+                }
+            }
+            _ = tokio::time::sleep_until(deadline.into()) => break,
+        }
+        while active < options.max_connections {
+            let Some(candidate) = queued.pop_front() else {
+                break;
+            };
+            let Some(session) = registry.dial_session(&info_hash, candidate, events_tx.clone())
+            else {
+                break;
+            };
+            tasks.spawn(session);
+            active += 1;
+            connection_attempts += 1;
+        }
+    }
+    let elapsed = started_at.elapsed();
+    tasks.abort_all();
+    listening.abort();
+    registry.remove_torrent(&info_hash);
+    // The trackers drop us from the swarm right away
+    if let Some(announcer) = announcer {
+        announcer.stop().await;
+    }
+    if let Err(e) = fs::remove_dir_all(&scratch)
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        log::warn!("Failed to remove {:?}: {:?}", scratch, e);
+    }
+
+    let torrent = torrent.lock().await;
+    let downloaded_bytes = torrent.transfer_totals().downloaded();
+    let download_rate = downloaded_bytes as f64 / elapsed.as_secs_f64().max(0.001);
+    let web_seed_bytes = web_seed_bytes(&torrent, &metainfo, piece_count).await;
+    Ok(BandwidthReport {
+        elapsed,
+        downloaded_bytes,
+        download_rate,
+        disk_write_rate,
+        peers_found: found.len(),
+        connection_attempts,
+        connections,
+        unchoked_peers: unchoked.len(),
+        web_seeds: web_seed_count,
+        limit: limiting_factor(
+            download_rate,
+            disk_write_rate,
+            unchoked.len(),
+            web_seed_bytes > 0,
+        ),
+    })
+}
+
+// The blocks of the web seeds have no peer, what the peers didn't send of the verified
+// pieces came from the web seeds. A piece they didn't finish isn't counted.
+async fn web_seed_bytes(torrent: &Torrent, metainfo: &MetaInfo, piece_count: usize) -> u64 {
+    let piece_length = metainfo.info.piece_length as u64;
+    let total_bytes = metainfo.total_bytes() as u64;
+    let mut bytes = 0;
+    for piece_index in 0..piece_count as u32 {
+        if !torrent.has_piece(piece_index).await {
+            continue;
+        }
+        let offset = piece_index as u64 * piece_length;
+        let from_peers: u64 = torrent
+            .piece_sources(piece_index)
+            .iter()
+            .map(|(_, it)| it)
+            .sum();
+        bytes += piece_length
+            .min(total_bytes - offset)
+            .saturating_sub(from_peers);
+    }
+    bytes
+}
+
+fn limiting_factor(
+    download_rate: f64,
+    disk_write_rate: Option<f64>,
+    unchoked_peers: usize,
+    web_seeds_serving: bool,
+) -> LimitingFactor {
+    if unchoked_peers == 0 && !web_seeds_serving {
+        return LimitingFactor::PeerAvailability;
+    }
+    if disk_write_rate.is_some_and(|it| it <= download_rate * DISK_HEADROOM) {
+        return LimitingFactor::Disk;
+    }
+    // A web seed is a server, it's usually faster than a handful of peers.
+    if unchoked_peers < MIN_UNCHOKED_PEERS && !web_seeds_serving {
+        return LimitingFactor::PeerAvailability;
+    }
+    LimitingFactor::Network
+}
+
+// Write a scratch file and flush it to the disk, returns bytes per second.
+async fn measure_disk(dir: PathBuf) -> io::Result<f64> {
+    tokio::task::spawn_blocking(move || {
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(".bitdrift_disk_probe");
+        let result = write_probe(&path);
+        let _ = fs::remove_file(&path);
+        result
+    })
+    .await?
+}
+
+fn write_probe(path: &Path) -> io::Result<f64> {
+    let chunk = vec![0xa5u8; 1024 * 1024];
+    let started_at = Instant::now();
+    let mut file = fs::File::create(path)?;
+    for _ in 0..DISK_PROBE_SIZE / chunk.len() {
+        file.write_all(&chunk)?;
+    }
+    file.sync_all()?;
+    Ok(DISK_PROBE_SIZE as f64 / started_at.elapsed().as_secs_f64().max(0.001))
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+    use url::Url;
+
+    use super::*;
+    use crate::metainfo::TestMetaInfo;
+
+    #[tokio::test]
+    async fn test_run() {
+        let mut server = mockito::Server::new_async().await;
+        // Told the port of the listener, and that we left once the test is over
+        let started = server
+            .mock("GET", "/announce")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("event".into(), "started".into()),
+                Matcher::Regex("port=[1-9]".into()),
+            ]))
+            .with_body(b"d8:intervali1800e5:peers0:e")
+            .expect(1)
+            .create_async()
+            .await;
+        let stopped = server
+            .mock("GET", "/announce")
+            .match_query(Matcher::UrlEncoded("event".into(), "stopped".into()))
+            .with_body(b"d8:intervali1800e5:peers0:e")
+            .expect(1)
+            .create_async()
+            .await;
+        let web_seed = server
+            .mock("GET", "/data.bin")
+            .match_header("range", "bytes=0-3")
+            .with_status(206)
+            .with_body("abcd")
+            .create_async()
+            .await;
+        let mut metainfo = TestMetaInfo::new("data.bin", 4).single(b"abcd").build();
+        metainfo.announce = Some(Url::parse(&format!("{}/announce", server.url())).unwrap());
+        metainfo.web_seeds = vec![Url::parse(&format!("{}/data.bin", server.url())).unwrap()];
+
+        let save_path = std::env::temp_dir().join("bitdrift_test_bandwidth_run");
+        let options = BandwidthTestOptions {
+            duration: Duration::from_secs(10),
+            save_path: save_path.clone(),
+            ..BandwidthTestOptions::default()
+        };
+        let report = run(metainfo, options).await.unwrap();
+        // Done before the duration, with the torrent complete
+        assert!(report.elapsed < Duration::from_secs(10));
+        assert_eq!(report.downloaded_bytes, 4);
+        assert_eq!(report.web_seeds, 1);
+        assert_eq!(report.limit, LimitingFactor::Network);
+        assert!(!save_path.join(SCRATCH_DIR).exists());
+        started.assert_async().await;
+        stopped.assert_async().await;
+        web_seed.assert_async().await;
+        let _ = fs::remove_dir_all(&save_path);
+    }
+
+    #[tokio::test]
+    async fn test_run_without_sources() {
+        let mut metainfo = TestMetaInfo::new("data.bin", 4).single(b"abcd").build();
+        metainfo.announce = None;
+        let options = BandwidthTestOptions {
+            save_path: std::env::temp_dir().join("bitdrift_test_bandwidth_no_sources"),
+            ..BandwidthTestOptions::default()
+        };
+        assert!(matches!(
+            run(metainfo, options).await,
+            Err(BandwidthError::NoSources)
+        ));
+    }
+
+    #[test]
+    fn test_limiting_factor() {
+        let mb = 1024.0 * 1024.0;
+        // Nobody sends
+        assert_eq!(
+            limiting_factor(0.0, Some(100.0 * mb), 0, false),
+            LimitingFactor::PeerAvailability
+        );
+        // The disk barely keeps up
+        assert_eq!(
+            limiting_factor(10.0 * mb, Some(11.0 * mb), 10, false),
+            LimitingFactor::Disk
+        );
+        // Two peers only
+        assert_eq!(
+            limiting_factor(1.0 * mb, Some(100.0 * mb), 2, false),
+            LimitingFactor::PeerAvailability
+        );
+        assert_eq!(
+            limiting_factor(1.0 * mb, Some(100.0 * mb), 0, true),
+            LimitingFactor::Network
+        );
+        assert_eq!(
+            limiting_factor(10.0 * mb, None, 10, false),
+            LimitingFactor::Network
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    io::{Cursor, Read},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use flate2::read::MultiGzDecoder;
+use reqwest::{
+    Client, StatusCode,
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use url::Url;
+
+use crate::{
+    client_identity::ClientIdentity,
+    ip_filter::{IpFilter, SharedIpFilter},
+};
+
+// Keep the IP filter up to date with the blocklists the user subscribed to. The lists are
+// downloaded again on a schedule, only when they changed, and the new filter replaces the old
+// one at once while the peers it still allows stay connected.
+
+pub const DEFAULT_UPDATE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+// The lists are tens of MB, give them time.
+const TIMEOUT: Duration = Duration::from_secs(120);
+// Decompressed, far above the largest lists, so a tiny archive can't fill the memory.
+const MAX_LIST_SIZE: u64 = 256 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum BlocklistError {
+    #[error("Failed to download the blocklist: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("The blocklist responded with {0}")]
+    Status(StatusCode),
+    #[error("Failed to decompress the blocklist: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to unzip the blocklist: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("The blocklist is larger than {MAX_LIST_SIZE} bytes")]
+    TooLarge,
+}
+
+pub(crate) type Result<T> = std::result::Result<T, BlocklistError>;
+
+#[derive(Debug, Clone)]
+pub struct BlocklistSource {
+    pub url: Url,
+    // From the last download, so the server can answer 304 if the list didn't change.
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    // The last list downloaded, kept while the downloads fail.
+    filter: IpFilter,
+}
+
+impl BlocklistSource {
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            etag: None,
+            last_modified: None,
+            filter: IpFilter::new(),
+        }
+    }
+}
+
+pub struct BlocklistUpdater {
+    sources: Vec<BlocklistSource>,
+    filter: SharedIpFilter,
+    client: Client,
+    interval: Duration,
+}
+
+impl BlocklistUpdater {
+    pub fn new(
+        sources: Vec<BlocklistSource>,
+        filter: SharedIpFilter,
+        identity: &ClientIdentity,
+    ) -> Result<Self> {
+        let client = Client::builder()
+            .user_agent(identity.user_agent())
+            .timeout(TIMEOUT)
+            .build()?;
+        Ok(Self {
+            sources,
+            filter,
+            client,
+            interval: DEFAULT_UPDATE_INTERVAL,
+        })
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn sources(&self) -> &[BlocklistSource] {
+        &self.sources
+    }
+
+    // Download the lists which changed and replace the filter if any did. Returns the
+    // connected peers the new filter blocks, for the caller to close.
+    pub async fn update(&mut self, connected: &[SocketAddr]) -> Vec<SocketAddr> {
+        let mut changed = false;
+        for source in &mut self.sources {
+            match download(&self.client, source).await {
+                Ok(updated) => changed |= updated,
+                // The last list of the source stays in the filter
+                Err(e) => log::warn!("Failed to update the blocklist {}: {}", source.url, e),
+            }
+        }
+        if !changed {
+            return vec![];
+        }
+        let filter = IpFilter::combine(self.sources.iter().map(|it| &it.filter));
+        log::info!("Updated the IP filter, {} ranges blocked", filter.len());
+        self.filter.replace(filter, connected)
+    }
+
+    // Update now and then on the interval, the blocked peers are sent to be closed.
+    pub async fn run(
+        mut self,
+        connected: impl Fn() -> Vec<SocketAddr>,
+        blocked_tx: mpsc::UnboundedSender<SocketAddr>,
+    ) {
+        loop {
+            for addr in self.update(&connected()).await {
+                if blocked_tx.send(addr).is_err() {
+                    return;
+                }
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
+
+// Returns false if the list didn't change since the last download.
+async fn download(client: &Client, source: &mut BlocklistSource) -> Result<bool> {
+    let mut request = client.get(source.url.clone());
+    if let Some(etag) = &source.etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &source.last_modified {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+    let response = request.send().await?;
+    match response.status() {
+        StatusCode::NOT_MODIFIED => return Ok(false),
+        status if !status.is_success() => return Err(BlocklistError::Status(status)),
+        _ => {}
+    }
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|it| it.to_str().ok())
+            .map(str::to_string)
+    };
+    let (etag, last_modified) = (header(ETAG), header(LAST_MODIFIED));
+    let body = response.bytes().await?;
+    let text = decompress(&body)?;
+    source.filter = IpFilter::parse(&text);
+    source.etag = etag;
+    source.last_modified = last_modified;
+    Ok(true)
+}
+
+// The lists are served gzipped, zipped or plain, told apart by their magic bytes rather than
+// the URL, which often doesn't say.
+fn decompress(body: &[u8]) -> Result<String> {
+    decompress_with_limit(body, MAX_LIST_SIZE)
+}
+
+fn decompress_with_limit(body: &[u8], limit: u64) -> Result<String> {
+    let mut bytes = Vec::new();
+    if body.starts_with(&[0x1f, 0x8b]) {
+        read_limited(MultiGzDecoder::new(body), &mut bytes, limit)?;
+    } else if body.starts_with(b"PK\x03\x04") {
+        // Every file of the archive, some lists are split in a few
+        let mut archive = zip::ZipArchive::new(Cursor::new(body))?;
+        for i in 0..archive.len() {
+            let file = archive.by_index(i)?;
+            if file.is_file() {
+                read_limited(file, &mut bytes, limit)?;
+                bytes.push(b'\n');
+            }
+        }
+    } else {
+        read_limited(body, &mut bytes, limit)?;
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// Appends to the bytes, fails once all of them together are over the limit.
+fn read_limited(reader: impl Read, bytes: &mut Vec<u8>, limit: u64) -> Result<()> {
+    let left = limit.saturating_sub(bytes.len() as u64);
+    reader.take(left + 1).read_to_end(bytes)?;
+    if bytes.len() as u64 > limit {
+        return Err(BlocklistError::TooLarge);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{Compression, write::GzEncoder};
+
+    use super::*;
+
+    const LIST: &str = "Some Org:1.2.3.0-1.2.3.255\n";
+
+    #[test]
+    fn test_decompress() {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(LIST.as_bytes()).unwrap();
+        let gz = gz.finish().unwrap();
+        assert_eq!(decompress(&gz).unwrap(), LIST);
+        assert!(matches!(
+            decompress_with_limit(&gz, LIST.len() as u64 - 1),
+            Err(BlocklistError::TooLarge)
+        ));
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("list.p2p", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(LIST.as_bytes()).unwrap();
+        let zip = zip.finish().unwrap().into_inner();
+        assert_eq!(decompress(&zip).unwrap().trim(), LIST.trim());
+        assert!(matches!(
+            decompress_with_limit(&zip, 10),
+            Err(BlocklistError::TooLarge)
+        ));
+
+        assert_eq!(decompress(LIST.as_bytes()).unwrap(), LIST);
+    }
+
+    #[tokio::test]
+    async fn test_update_when_changed() {
+        let mut server = mockito::Server::new_async().await;
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(LIST.as_bytes()).unwrap();
+        let list = server
+            .mock("GET", "/list.gz")
+            .match_header("if-none-match", mockito::Matcher::Missing)
+            .with_header("etag", "\"v1\"")
+            .with_body(gz.finish().unwrap())
+            .expect(1)
+            .create_async()
+            .await;
+        let not_modified = server
+            .mock("GET", "/list.gz")
+            .match_header("if-none-match", "\"v1\"")
+            .with_status(304)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let url = Url::parse(&format!("{}/list.gz", server.url())).unwrap();
+        let filter = SharedIpFilter::default();
+        let mut updater = BlocklistUpdater::new(
+            vec![BlocklistSource::new(url)],
+            filter.clone(),
+            &ClientIdentity::default(),
+        )
+        .unwrap();
+        let connected: Vec<SocketAddr> = vec![
+            "1.2.3.4:6881".parse().unwrap(),
+            "5.6.7.8:6881".parse().unwrap(),
+        ];
+
+        assert_eq!(updater.update(&connected).await, vec![connected[0]]);
+        assert!(filter.is_blocked("1.2.3.4".parse().unwrap()));
+        assert_eq!(updater.sources()[0].etag.as_deref(), Some("\"v1\""));
+
+        // Unchanged, the filter stays as it is
+        let current = filter.current();
+        assert!(updater.update(&connected).await.is_empty());
+        assert!(std::sync::Arc::ptr_eq(&current, &filter.current()));
+
+        list.assert_async().await;
+        not_modified.assert_async().await;
+    }
+}
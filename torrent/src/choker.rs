@@ -1,28 +1,214 @@
-use std::cmp::{Ordering, min};
+use std::{
+    cmp::{Ordering, min},
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::time::Instant;
 
 use crate::peer_connection::PeerConnection;
 
+/// Decides which peers get the upload slots.
+pub(crate) trait ChokeStrategy: Send {
+    /// The peer to unchoke first is the less one.
+    fn compare(&self, a: &PeerConnection, b: &PeerConnection) -> Ordering;
+
+    /// Slots given round robin to the rest of the peers,
+    /// so the peers we never uploaded to get a chance to show their rate.
+    fn optimistic_slots(&self) -> usize {
+        0
+    }
+}
+
+/// The choke strategies which can be chosen in the settings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChokeStrategyKind {
+    /// Tit-for-tat while downloading, fastest upload while seeding.
+    #[default]
+    Auto,
+    RoundRobin,
+    TitForTat,
+    FastestUpload,
+    AntiLeech,
+}
+
+impl ChokeStrategyKind {
+    pub(crate) fn build(self, is_seeding: bool) -> Box<dyn ChokeStrategy> {
+        match self {
+            ChokeStrategyKind::Auto if is_seeding => Box::new(FastestUpload),
+            ChokeStrategyKind::Auto => Box::new(TitForTat),
+            ChokeStrategyKind::RoundRobin => Box::new(RoundRobin),
+            ChokeStrategyKind::TitForTat => Box::new(TitForTat),
+            ChokeStrategyKind::FastestUpload => Box::new(FastestUpload),
+            ChokeStrategyKind::AntiLeech => Box::new(AntiLeech),
+        }
+    }
+}
+
+/// Every interested peer gets its turn, regardless of what it gives back.
+pub(crate) struct RoundRobin;
+
+impl ChokeStrategy for RoundRobin {
+    fn compare(&self, a: &PeerConnection, b: &PeerConnection) -> Ordering {
+        Choker::unchoke_compare_round_robin(a, b)
+    }
+}
+
+/// Upload to the peers which upload to us the fastest, for downloading.
+pub(crate) struct TitForTat;
+
+impl ChokeStrategy for TitForTat {
+    fn compare(&self, a: &PeerConnection, b: &PeerConnection) -> Ordering {
+        interested_first(a, b).then_with(|| b.download_rate.total_cmp(&a.download_rate))
+    }
+
+    fn optimistic_slots(&self) -> usize {
+        1
+    }
+}
+
+/// Upload to the peers which take our upload the fastest, for seeding,
+/// nothing comes back from the peers so the upload capacity is what's left to make use of.
+pub(crate) struct FastestUpload;
+
+impl ChokeStrategy for FastestUpload {
+    fn compare(&self, a: &PeerConnection, b: &PeerConnection) -> Ordering {
+        interested_first(a, b).then_with(|| b.upload_rate.total_cmp(&a.upload_rate))
+    }
+
+    fn optimistic_slots(&self) -> usize {
+        1
+    }
+}
+
+/// Prefer the peers which just started or are about to finish. The peers stuck in the middle
+/// for long are likely leechers which never share, the new peers need the first pieces to
+/// start sharing, and the almost done ones become seeders soon.
+pub(crate) struct AntiLeech;
+
+impl AntiLeech {
+    fn score(peer: &PeerConnection) -> f64 {
+        (peer.progress() - 0.5).abs()
+    }
+}
+
+impl ChokeStrategy for AntiLeech {
+    fn compare(&self, a: &PeerConnection, b: &PeerConnection) -> Ordering {
+        interested_first(a, b)
+            .then_with(|| AntiLeech::score(b).total_cmp(&AntiLeech::score(a)))
+            .then_with(|| Choker::unchoke_compare_round_robin(a, b))
+    }
+}
+
+fn interested_first(a: &PeerConnection, b: &PeerConnection) -> Ordering {
+    b.is_peer_interesting.cmp(&a.is_peer_interesting)
+}
+
+/// When to disconnect the peers which transfer nothing, so new peers can take their place.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeadWeightPolicy {
+    pub enabled: bool,
+    /// Nothing transferred either way for this long makes a peer dead weight.
+    pub idle_after: Duration,
+    /// At most this many peers are disconnected each round.
+    pub max_disconnects: usize,
+}
+
+impl Default for DeadWeightPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            idle_after: Duration::from_secs(5 * 60),
+            max_disconnects: 2,
+        }
+    }
+}
+
 struct Choker {
     /// A quota of peers that can be uploaded at same time.
     upload_slot: usize,
+    strategy: Box<dyn ChokeStrategy>,
 }
 
 impl Choker {
     pub fn new(upload_slot: usize) -> Self {
-        Self { upload_slot }
+        Self {
+            upload_slot,
+            strategy: Box::new(RoundRobin),
+        }
     }
 
     pub fn set_upload_slot(&mut self, upload_slot: usize) {
         self.upload_slot = upload_slot;
     }
 
-    pub fn sort_by_unchoke(&self, peers: &mut Vec<PeerConnection>) -> usize {
-        let upload_slot = min(self.upload_slot, peers.len());
-        peers.select_nth_unstable_by(upload_slot - 1, |a, b| {
-            Choker::unchoke_compare_round_robin(a, b)
+    pub fn set_strategy(&mut self, strategy: Box<dyn ChokeStrategy>) {
+        self.strategy = strategy;
+    }
+
+    /// The regular and the optimistic slots for this many peers.
+    fn slots(&self, peer_count: usize) -> (usize, usize) {
+        let upload_slot = min(self.upload_slot, peer_count);
+        if upload_slot == 0 {
+            return (0, 0);
+        }
+        let optimistic_slots = min(self.strategy.optimistic_slots(), upload_slot - 1);
+        (upload_slot - optimistic_slots, optimistic_slots)
+    }
+
+    /// The indexes of the peers to unchoke, the ones of the regular slots first.
+    pub fn unchoked_peers(&self, peers: &[PeerConnection]) -> Vec<usize> {
+        let (regular_slots, optimistic_slots) = self.slots(peers.len());
+        let upload_slot = regular_slots + optimistic_slots;
+        if upload_slot == 0 {
+            return Vec::new();
+        }
+        let mut order: Vec<usize> = (0..peers.len()).collect();
+        order.select_nth_unstable_by(regular_slots - 1, |a, b| {
+            self.strategy.compare(&peers[*a], &peers[*b])
         });
+        if optimistic_slots > 0 {
+            order[regular_slots..].select_nth_unstable_by(optimistic_slots - 1, |a, b| {
+                Choker::unchoke_compare_round_robin(&peers[*a], &peers[*b])
+            });
+        }
+        order.truncate(upload_slot);
+        order
+    }
 
-        upload_slot
+    /// The indexes of the peers to disconnect, the longest idle first. Only applied when all
+    /// the `max_connections` are taken, and never to the peers in the optimistic slots,
+    /// they haven't had their chance to transfer yet.
+    pub fn dead_weight(
+        &self,
+        peers: &[PeerConnection],
+        max_connections: usize,
+        policy: &DeadWeightPolicy,
+        now: Instant,
+    ) -> Vec<usize> {
+        if !policy.enabled || peers.len() < max_connections {
+            return Vec::new();
+        }
+        let (regular_slots, _) = self.slots(peers.len());
+        let optimistic = &self.unchoked_peers(peers)[regular_slots..];
+        let mut idle: Vec<usize> = (0..peers.len())
+            .filter(|i| !optimistic.contains(i) && peers[*i].idle_for(now) >= policy.idle_after)
+            .collect();
+        idle.sort_by_key(|i| std::cmp::Reverse(peers[*i].idle_for(now)));
+        idle.truncate(policy.max_disconnects);
+        idle
+    }
+
+    /// Move the peers to unchoke to the front, returns how many of them.
+    pub fn sort_by_unchoke(&self, peers: &mut Vec<PeerConnection>) -> usize {
+        let unchoked = self.unchoked_peers(peers);
+        let mut rest: Vec<Option<PeerConnection>> = peers.drain(..).map(Some).collect();
+        peers.extend(unchoked.iter().filter_map(|i| rest[*i].take()));
+        peers.extend(rest.into_iter().flatten());
+        unchoked.len()
     }
 
     /// Use to prioritizes peer to determine which peer should unchoke
@@ -36,17 +222,136 @@ impl Choker {
             _ => {}
         }
 
-        match a.last_unchoked_at.cmp(&b.last_unchoked_at) {
-            Ordering::Less => return Ordering::Less,
-            Ordering::Greater => return Ordering::Greater,
-            Ordering::Equal => return Ordering::Equal,
+        a.last_unchoked_at.cmp(&b.last_unchoked_at)
+    }
+}
+
+/// How often the upload slots are given out again.
+pub(crate) const UNCHOKE_INTERVAL: Duration = Duration::from_secs(10);
+pub(crate) const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+/// What a choke round is run with, from the options of the torrent.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChokeRoundOptions {
+    pub strategy: ChokeStrategyKind,
+    pub is_seeding: bool,
+    pub max_connections: usize,
+    pub dead_weight: DeadWeightPolicy,
+}
+
+/// What the last round decided for a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ChokeDecision {
+    pub unchoke: bool,
+    /// Disconnect the peer, its connection slot goes to a new peer.
+    pub dead_weight: bool,
+}
+
+/// The choke rounds of a torrent. The sessions report their peer each tick, and the first
+/// one to see a round is due runs it for all of them, each session then chokes or unchokes
+/// its own peer.
+pub(crate) struct ChokeRounds {
+    choker: Choker,
+    peers: HashMap<SocketAddr, PeerConnection>,
+    /// Kept here instead of by the sessions, a peer which keeps its slot is unchoked again
+    /// each round, so round robin moves on to the others.
+    last_unchoked: HashMap<SocketAddr, Instant>,
+    unchoked: HashSet<SocketAddr>,
+    dead_weight: HashSet<SocketAddr>,
+    /// None runs a round on the next tick.
+    last_round: Option<Instant>,
+}
+
+impl ChokeRounds {
+    pub fn new(upload_slot: usize) -> Self {
+        Self {
+            choker: Choker::new(upload_slot),
+            peers: HashMap::new(),
+            last_unchoked: HashMap::new(),
+            unchoked: HashSet::new(),
+            dead_weight: HashSet::new(),
+            last_round: None,
+        }
+    }
+
+    pub fn update(&mut self, addr: SocketAddr, peer: PeerConnection) {
+        self.peers.insert(addr, peer);
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+        self.last_unchoked.remove(addr);
+        if self.unchoked.remove(addr) {
+            // Its slot is free for the others
+            self.last_round = None;
+        }
+        self.dead_weight.remove(addr);
+    }
+
+    /// Run the next round early if the change of interest frees or may take a slot,
+    /// instead of making the peer wait for the next round.
+    pub fn on_interest_changed(&mut self, addr: &SocketAddr, interested: bool) {
+        let has_free_slot = self.unchoked.len() < self.choker.upload_slot;
+        if interested && has_free_slot || !interested && self.unchoked.contains(addr) {
+            self.last_round = None;
+        }
+    }
+
+    /// Run the round if it's due, then return what it decided for the peer.
+    pub fn decide(
+        &mut self,
+        addr: &SocketAddr,
+        options: &ChokeRoundOptions,
+        now: Instant,
+    ) -> ChokeDecision {
+        let is_due = self
+            .last_round
+            .is_none_or(|it| now.saturating_duration_since(it) >= UNCHOKE_INTERVAL);
+        if is_due {
+            self.run(options, now);
+        }
+        ChokeDecision {
+            unchoke: self.unchoked.contains(addr),
+            dead_weight: self.dead_weight.remove(addr),
+        }
+    }
+
+    fn run(&mut self, options: &ChokeRoundOptions, now: Instant) {
+        self.choker
+            .set_strategy(options.strategy.build(options.is_seeding));
+        let (addrs, peers): (Vec<SocketAddr>, Vec<PeerConnection>) = self
+            .peers
+            .iter()
+            .map(|(addr, peer)| {
+                let mut peer = peer.clone();
+                peer.last_unchoked_at = self.last_unchoked.get(addr).copied();
+                (*addr, peer)
+            })
+            .unzip();
+        // A peer which doesn't want anything from us wastes the slot
+        self.unchoked = self
+            .choker
+            .unchoked_peers(&peers)
+            .into_iter()
+            .filter(|i| peers[*i].is_peer_interesting)
+            .map(|i| addrs[i])
+            .collect();
+        for addr in &self.unchoked {
+            self.last_unchoked.insert(*addr, now);
         }
+        self.dead_weight = self
+            .choker
+            .dead_weight(&peers, options.max_connections, &options.dead_weight, now)
+            .into_iter()
+            .map(|i| addrs[i])
+            .collect();
+        self.last_round = Some(now);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::peer_connection::PeerConnection;
+    use crate::{peer_connection::PeerConnection, types::BitField};
     use std::time::Duration;
     use tokio::time::Instant;
 
@@ -169,4 +474,224 @@ mod tests {
         let f = make_peer(true, None);
         assert_eq!(Choker::unchoke_compare_round_robin(&e, &f), Ordering::Equal);
     }
+
+    #[test]
+    fn test_dead_weight() {
+        let now = Instant::now();
+        let idle_since = |secs| Some(now - Duration::from_secs(secs));
+        let mut peers: Vec<PeerConnection> = (0..5).map(|_| make_peer(true, None)).collect();
+        peers[0].last_transfer_at = idle_since(600);
+        peers[1].last_transfer_at = idle_since(10);
+        peers[2].last_transfer_at = idle_since(900);
+        peers[3].last_transfer_at = idle_since(400);
+        peers[4].last_transfer_at = idle_since(1000);
+        // The optimistic slot goes to the peer unchoked the longest ago
+        for (i, peer) in peers.iter_mut().enumerate() {
+            peer.last_unchoked_at = Some(now - Duration::from_secs(10 - i as u64));
+        }
+        let mut choker = Choker::new(2);
+        choker.set_strategy(Box::new(TitForTat));
+        let policy = DeadWeightPolicy::default();
+
+        // There is still room for new peers
+        assert!(choker.dead_weight(&peers, 6, &policy, now).is_empty());
+
+        let optimistic = choker.unchoked_peers(&peers)[1];
+        let dead = choker.dead_weight(&peers, 5, &policy, now);
+        assert_eq!(dead.len(), 2);
+        assert!(!dead.contains(&optimistic));
+        assert!(!dead.contains(&1));
+        assert!(peers[dead[0]].idle_for(now) >= peers[dead[1]].idle_for(now));
+
+        let disabled = DeadWeightPolicy {
+            enabled: false,
+            ..policy
+        };
+        assert!(choker.dead_weight(&peers, 5, &disabled, now).is_empty());
+    }
+
+    fn round_options(strategy: ChokeStrategyKind) -> ChokeRoundOptions {
+        ChokeRoundOptions {
+            strategy,
+            is_seeding: false,
+            max_connections: 50,
+            dead_weight: DeadWeightPolicy::default(),
+        }
+    }
+
+    #[test]
+    fn test_choke_rounds() {
+        let now = Instant::now();
+        let addr = |port| SocketAddr::from(([10, 0, 0, 1], port));
+        let mut rounds = ChokeRounds::new(2);
+        for port in 1..=3 {
+            rounds.update(addr(port), make_peer(port != 3, None));
+        }
+        let options = round_options(ChokeStrategyKind::RoundRobin);
+
+        // The uninterested peer is never unchoked, even with a free slot
+        let unchoked = |rounds: &mut ChokeRounds, now| -> Vec<bool> {
+            (1..=3)
+                .map(|port| rounds.decide(&addr(port), &options, now).unchoke)
+                .collect()
+        };
+        assert_eq!(unchoked(&mut rounds, now), [true, true, false]);
+
+        // Another interested peer waits for the next round
+        rounds.update(addr(4), make_peer(true, None));
+        let later = now + Duration::from_secs(1);
+        assert!(!rounds.decide(&addr(4), &options, later).unchoke);
+        let later = now + UNCHOKE_INTERVAL;
+        assert!(rounds.decide(&addr(4), &options, later).unchoke);
+        // One of the two unchoked before made room
+        let count = unchoked(&mut rounds, later)
+            .iter()
+            .filter(|it| **it)
+            .count();
+        assert_eq!(count, 1);
+
+        // Leaving frees the slot right away
+        rounds.remove(&addr(4));
+        let later = later + Duration::from_secs(1);
+        assert_eq!(unchoked(&mut rounds, later), [true, true, false]);
+
+        // The third peer only gets in early while a slot is free
+        rounds.update(addr(3), make_peer(true, None));
+        rounds.on_interest_changed(&addr(3), true);
+        assert_eq!(unchoked(&mut rounds, later), [true, true, false]);
+        rounds.update(addr(2), make_peer(false, None));
+        rounds.on_interest_changed(&addr(2), false);
+        assert_eq!(unchoked(&mut rounds, later), [true, false, true]);
+    }
+
+    #[test]
+    fn test_choke_rounds_dead_weight() {
+        let now = Instant::now();
+        let mut rounds = ChokeRounds::new(1);
+        let idle = SocketAddr::from(([10, 0, 0, 1], 1));
+        let busy = SocketAddr::from(([10, 0, 0, 2], 1));
+        let mut peer = make_peer(false, None);
+        peer.connected_at = now - Duration::from_secs(600);
+        rounds.update(idle, peer);
+        let mut peer = make_peer(true, None);
+        peer.last_transfer_at = Some(now);
+        rounds.update(busy, peer);
+        let options = ChokeRoundOptions {
+            max_connections: 2,
+            ..round_options(ChokeStrategyKind::TitForTat)
+        };
+        assert!(rounds.decide(&idle, &options, now).dead_weight);
+        // Told once, the session closes itself
+        assert!(!rounds.decide(&idle, &options, now).dead_weight);
+        assert_eq!(
+            rounds.decide(&busy, &options, now),
+            ChokeDecision {
+                unchoke: true,
+                dead_weight: false,
+            }
+        );
+    }
+
+    // A peer of the simulated swarm.
+    struct SimPeer {
+        // Bytes per second the peer uploads to us, tit-for-tat peers only do it while unchoked.
+        gives: f64,
+        // Bytes per second the peer can download from us.
+        takes: f64,
+        progress: f64,
+    }
+
+    fn sim_peer(gives: f64, takes: f64, progress: f64) -> SimPeer {
+        SimPeer {
+            gives,
+            takes,
+            progress,
+        }
+    }
+
+    // Run the choker for some rounds, returns how many rounds each peer was unchoked
+    // and the bytes uploaded to the swarm.
+    fn simulate(kind: ChokeStrategyKind, is_seeding: bool, swarm: &[SimPeer]) -> (Vec<u32>, f64) {
+        let mut choker = Choker::new(3);
+        choker.set_strategy(kind.build(is_seeding));
+        let mut peers: Vec<PeerConnection> = swarm
+            .iter()
+            .map(|sim| {
+                let mut peer = make_peer(true, None);
+                peer.peer_bitfield = BitField::repeat(false, 100);
+                for piece in 0..(sim.progress * 100.0) as usize {
+                    peer.peer_bitfield.set(piece, true);
+                }
+                peer
+            })
+            .collect();
+        let mut unchoked_rounds = vec![0; swarm.len()];
+        let mut uploaded = 0.0;
+        let start = Instant::now();
+        for round in 0..60 {
+            let unchoked = choker.unchoked_peers(&peers);
+            let now = start + Duration::from_secs(10 * round);
+            for (i, (peer, sim)) in peers.iter_mut().zip(swarm).enumerate() {
+                if unchoked.contains(&i) {
+                    unchoked_rounds[i] += 1;
+                    peer.last_unchoked_at = Some(now);
+                    peer.upload_rate = sim.takes;
+                    peer.download_rate = sim.gives;
+                    uploaded += sim.takes;
+                } else {
+                    peer.upload_rate = 0.0;
+                    peer.download_rate = 0.0;
+                }
+            }
+        }
+        (unchoked_rounds, uploaded)
+    }
+
+    #[test]
+    fn test_simulate_free_riders() {
+        // The first three give back, the others never do
+        let swarm: Vec<SimPeer> = [100.0, 100.0, 100.0, 0.0, 0.0, 0.0]
+            .into_iter()
+            .map(|gives| sim_peer(gives, 50.0, 0.3))
+            .collect();
+        let free_rider_rounds = |rounds: &[u32]| -> u32 { rounds[3..].iter().sum() };
+
+        let (round_robin, _) = simulate(ChokeStrategyKind::RoundRobin, false, &swarm);
+        let (tit_for_tat, _) = simulate(ChokeStrategyKind::TitForTat, false, &swarm);
+        let (auto, _) = simulate(ChokeStrategyKind::Auto, false, &swarm);
+        // Round robin doesn't care, tit-for-tat only lets them in through the optimistic slot
+        assert_eq!(free_rider_rounds(&round_robin), 90);
+        assert!(free_rider_rounds(&tit_for_tat) <= 45);
+        // The givers found first keep their slots
+        assert_eq!(tit_for_tat.iter().filter(|it| **it == 60).count(), 2);
+        assert_eq!(auto, tit_for_tat);
+    }
+
+    #[test]
+    fn test_simulate_seeding() {
+        let swarm: Vec<SimPeer> = [10.0, 10.0, 1000.0, 10.0, 1000.0, 10.0]
+            .into_iter()
+            .map(|takes| sim_peer(0.0, takes, 0.3))
+            .collect();
+        let (_, round_robin) = simulate(ChokeStrategyKind::RoundRobin, true, &swarm);
+        let (_, fastest_upload) = simulate(ChokeStrategyKind::FastestUpload, true, &swarm);
+        let (_, auto) = simulate(ChokeStrategyKind::Auto, true, &swarm);
+        // Once found, the fast peers keep their slots
+        assert!(fastest_upload > 1.5 * round_robin);
+        assert_eq!(auto, fastest_upload);
+    }
+
+    #[test]
+    fn test_simulate_anti_leech() {
+        let swarm: Vec<SimPeer> = [0.5, 0.0, 0.45, 0.95, 0.55, 0.05]
+            .into_iter()
+            .map(|progress| sim_peer(0.0, 50.0, progress))
+            .collect();
+        let (round_robin, _) = simulate(ChokeStrategyKind::RoundRobin, true, &swarm);
+        let (anti_leech, _) = simulate(ChokeStrategyKind::AntiLeech, true, &swarm);
+        let stuck_rounds = |rounds: &[u32]| rounds[0] + rounds[2] + rounds[4];
+        assert_eq!(stuck_rounds(&round_robin), 90);
+        assert_eq!(stuck_rounds(&anti_leech), 0);
+        assert_eq!(anti_leech[1] + anti_leech[3] + anti_leech[5], 180);
+    }
 }
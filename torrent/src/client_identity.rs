@@ -0,0 +1,141 @@
+use std::net::IpAddr;
+
+use rand::distr::{Alphanumeric, SampleString};
+use reqwest::{Certificate, Client, Proxy};
+use thiserror::Error;
+use url::Url;
+
+use crate::{tracker::TrackerTls, types::PeerId};
+
+// How we introduce ourselves, the User-Agent to the trackers and web seeds and the peer id
+// prefix to the peers. Some private trackers only allow a list of clients, so the user can
+// report another client, at the risk of being banned if the tracker notices.
+
+// Azureus style, the client code and the version between dashes.
+pub const DEFAULT_PEER_ID_PREFIX: &str = "-BD0001-";
+pub const DEFAULT_USER_AGENT: &str = concat!("BitDrift/", env!("CARGO_PKG_VERSION"));
+
+// Leave at least this many random bytes in the peer id, so the ids don't collide.
+const MIN_RANDOM_LENGTH: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum ClientIdentityError {
+    #[error("Peer id prefix must be printable ASCII of at most {} bytes", 20 - MIN_RANDOM_LENGTH)]
+    InvalidPeerIdPrefix,
+    #[error("User agent must be non-empty printable ASCII")]
+    InvalidUserAgent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    user_agent: String,
+    peer_id_prefix: String,
+}
+
+impl Default for ClientIdentity {
+    fn default() -> Self {
+        Self {
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            peer_id_prefix: DEFAULT_PEER_ID_PREFIX.to_string(),
+        }
+    }
+}
+
+impl ClientIdentity {
+    pub fn new(user_agent: &str, peer_id_prefix: &str) -> Result<Self, ClientIdentityError> {
+        let is_printable = |it: &str| it.bytes().all(|byte| (0x20..0x7f).contains(&byte));
+        if user_agent.is_empty() || !is_printable(user_agent) {
+            return Err(ClientIdentityError::InvalidUserAgent);
+        }
+        if peer_id_prefix.len() > 20 - MIN_RANDOM_LENGTH || !is_printable(peer_id_prefix) {
+            return Err(ClientIdentityError::InvalidPeerIdPrefix);
+        }
+        Ok(Self {
+            user_agent: user_agent.to_string(),
+            peer_id_prefix: peer_id_prefix.to_string(),
+        })
+    }
+
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+
+    pub fn peer_id_prefix(&self) -> &str {
+        &self.peer_id_prefix
+    }
+
+    // We report another client, the settings warn the user about it.
+    pub fn is_spoofed(&self) -> bool {
+        *self != Self::default()
+    }
+
+    // A new peer id, the prefix followed by random characters.
+    pub fn generate_peer_id(&self) -> PeerId {
+        let random = Alphanumeric.sample_string(&mut rand::rng(), 20 - self.peer_id_prefix.len());
+        let mut peer_id = [0; 20];
+        peer_id.copy_from_slice(format!("{}{}", self.peer_id_prefix, random).as_bytes());
+        peer_id
+    }
+
+    // The HTTP client for the trackers and web seeds, sending from the local address if given.
+    pub fn http_client(&self, local_addr: Option<IpAddr>) -> reqwest::Result<Client> {
+        self.http_client_via(local_addr, None)
+    }
+
+    // Same as `http_client`, but the requests go through the proxy, e.g. socks5://host:1080.
+    pub fn http_client_via(
+        &self,
+        local_addr: Option<IpAddr>,
+        proxy: Option<&Url>,
+    ) -> reqwest::Result<Client> {
+        self.http_client_with_tls(local_addr, proxy, None)
+    }
+
+    // Same as `http_client_via`, with the TLS settings of the tracker host if it has any.
+    pub fn http_client_with_tls(
+        &self,
+        local_addr: Option<IpAddr>,
+        proxy: Option<&Url>,
+        tls: Option<&TrackerTls>,
+    ) -> reqwest::Result<Client> {
+        let mut builder = Client::builder()
+            .user_agent(&self.user_agent)
+            .local_address(local_addr);
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(Proxy::all(proxy.as_str())?);
+        }
+        if let Some(tls) = tls {
+            for pem in &tls.root_certificates {
+                builder = builder.add_root_certificate(Certificate::from_pem(pem.as_bytes())?);
+            }
+            builder = builder.danger_accept_invalid_certs(tls.accept_invalid_certs);
+        }
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_peer_id() {
+        let identity = ClientIdentity::default();
+        assert!(!identity.is_spoofed());
+        let peer_id = identity.generate_peer_id();
+        assert!(peer_id.starts_with(b"-BD0001-"));
+        assert_ne!(peer_id, identity.generate_peer_id());
+
+        let identity = ClientIdentity::new("qBittorrent/4.6.0", "-qB4600-").unwrap();
+        assert!(identity.is_spoofed());
+        assert!(identity.generate_peer_id().starts_with(b"-qB4600-"));
+    }
+
+    #[test]
+    fn test_invalid_identity() {
+        assert!(ClientIdentity::new("", "-BD0001-").is_err());
+        assert!(ClientIdentity::new("BitDrift\n", "-BD0001-").is_err());
+        assert!(ClientIdentity::new("BitDrift", "-TooLongPrefix-").is_err());
+        assert!(ClientIdentity::new("BitDrift", "-BD✓-").is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// The compact peer format shared by the trackers, PEX and DHT.
+// https://www.bittorrent.org/beps/bep_0023.html
+
+// An address without the port, e.g. the "external ip" of the trackers and the "yourip" of the
+// extension handshake.
+pub fn encode_ip(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+pub fn decode_ip(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?))),
+        16 => Some(IpAddr::V6(Ipv6Addr::from(
+            <[u8; 16]>::try_from(bytes).ok()?,
+        ))),
+        _ => None,
+    }
+}
+
+// Encode to the compact IPv4 and IPv6 peer lists,
+// 4 or 16 bytes for the address followed by 2 bytes for the port.
+pub fn encode_peers(peers: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for peer in peers {
+        match peer.ip() {
+            IpAddr::V4(ip) => {
+                v4.extend_from_slice(&ip.octets());
+                v4.extend_from_slice(&peer.port().to_be_bytes());
+            }
+            IpAddr::V6(ip) => {
+                v6.extend_from_slice(&ip.octets());
+                v6.extend_from_slice(&peer.port().to_be_bytes());
+            }
+        }
+    }
+    (v4, v6)
+}
+
+pub fn decode_peers(bytes: &[u8], ip_length: usize) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(ip_length + 2)
+        .map(|chunk| {
+            let (ip, port) = chunk.split_at(ip_length);
+            // The chunk size is exact, so the conversions can't fail
+            let ip = if ip_length == 4 {
+                IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap()))
+            } else {
+                IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap()))
+            };
+            SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+        })
+        .collect()
+}
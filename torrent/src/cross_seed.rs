@@ -0,0 +1,135 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    existing_data::{self, PlaceMode},
+    metainfo::MetaInfo,
+    torrent::Torrent,
+};
+
+// Find the other torrents of the same content, e.g. the same release on another tracker,
+// and seed them from the data we already have instead of downloading it again.
+
+// The .torrent files in the folder, the files which fail to parse are skipped.
+pub fn scan_torrent_files(dir: &Path) -> io::Result<Vec<(PathBuf, MetaInfo)>> {
+    let mut torrents = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|it| it != "torrent") {
+            continue;
+        }
+        match MetaInfo::from_bytes(&fs::read(&path)?) {
+            Ok(metainfo) => torrents.push((path, metainfo)),
+            Err(e) => log::warn!("Skip invalid torrent {}: {:?}", path.display(), e),
+        }
+    }
+    torrents.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(torrents)
+}
+
+// Whether every file of the torrent has a file of the same size and name in the data.
+// It's only a guess, the data is verified by hash when it's set up.
+pub fn is_candidate(metainfo: &MetaInfo, data_dir: &Path) -> io::Result<bool> {
+    let files = metainfo.files();
+    let non_empty = files.iter().filter(|it| it.length > 0).count();
+    if non_empty == 0 {
+        return Ok(false);
+    }
+    let matches = existing_data::match_files(metainfo, data_dir, data_dir)?;
+    Ok(matches.len() == non_empty)
+}
+
+// The torrents of `scan_torrent_files` which can be seeded from the data.
+pub fn find_candidates(
+    torrents: Vec<(PathBuf, MetaInfo)>,
+    data_dir: &Path,
+) -> io::Result<Vec<(PathBuf, MetaInfo)>> {
+    let mut candidates = Vec::new();
+    for (path, metainfo) in torrents {
+        if is_candidate(&metainfo, data_dir)? {
+            candidates.push((path, metainfo));
+        }
+    }
+    Ok(candidates)
+}
+
+// Link the data into the save path of the torrent and recheck it,
+// returns the torrent if every piece is verified, so it can start seeding right away.
+pub async fn set_up(
+    metainfo: MetaInfo,
+    data_dir: PathBuf,
+    save_path: PathBuf,
+) -> io::Result<Option<Torrent>> {
+    let name = metainfo.info.name.clone();
+    let existing =
+        existing_data::add_with_existing_data(metainfo, data_dir, save_path, PlaceMode::Link)
+            .await?;
+    if existing.have.all() {
+        log::info!("Cross-seeding {}", name);
+        Ok(Some(existing.torrent))
+    } else {
+        log::info!(
+            "Not cross-seeding {}, {} of {} pieces don't match",
+            name,
+            existing.have.count_zeros(),
+            existing.have.len()
+        );
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::TestMetaInfo;
+
+    #[tokio::test]
+    async fn test_cross_seed() {
+        let root = std::env::temp_dir().join("bitdrift_test_cross_seed");
+        let _ = fs::remove_dir_all(&root);
+        let data_dir = root.join("data");
+        fs::create_dir_all(data_dir.join("show/s01")).unwrap();
+        fs::write(data_dir.join("show/s01/e01.mkv"), b"abcd").unwrap();
+        fs::write(data_dir.join("show/s01/e02.mkv"), b"efgh").unwrap();
+
+        let same_content = TestMetaInfo::new("Show.S01", 4)
+            .file("Show.S01/s01/e01.mkv", b"abcd")
+            .file("Show.S01/s01/e02.mkv", b"efgh")
+            .build();
+        // Same names and sizes, but different bytes
+        let different_content = TestMetaInfo::new("show", 4)
+            .file("show/s01/e01.mkv", b"abcd")
+            .file("show/s01/e02.mkv", b"xxxx")
+            .build();
+        let missing_file = TestMetaInfo::new("show", 4)
+            .file("show/s01/e01.mkv", b"abcd")
+            .file("show/s01/e03.mkv", b"ijklmn")
+            .build();
+        let torrents = vec![same_content, different_content, missing_file]
+            .into_iter()
+            .enumerate()
+            .map(|(index, it)| (PathBuf::from(format!("{}.torrent", index)), it))
+            .collect();
+        let candidates = find_candidates(torrents, &data_dir).unwrap();
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[1].0, PathBuf::from("1.torrent"));
+
+        let mut names = Vec::new();
+        for (index, (_, candidate)) in candidates.into_iter().enumerate() {
+            let name = candidate.info.name.clone();
+            let save_path = root.join(format!("save{}", index));
+            if let Some(torrent) = set_up(candidate, data_dir.clone(), save_path)
+                .await
+                .unwrap()
+            {
+                assert!(torrent.has_piece(1).await);
+                names.push(name);
+            }
+        }
+        assert_eq!(names, vec!["Show.S01"]);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
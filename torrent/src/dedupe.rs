@@ -0,0 +1,400 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::{
+    hash::IncrementalHash,
+    metainfo::MetaInfo,
+    types::{BitField, Sha1Hash},
+};
+
+pub(crate) type Result<T> = std::result::Result<T, DedupeError>;
+
+#[derive(Error, Debug)]
+pub enum DedupeError {
+    #[error("Failed to access file")]
+    Io(#[from] io::Error),
+
+    #[error("Failed to parse dedupe index")]
+    Bencode(#[from] serde_bencode::Error),
+}
+
+// Keep one copy of the files which are byte-identical across torrents, the other torrents
+// get a hard link to it. The index remembers which torrents reference each shared file,
+// each torrent saves its references with its resume data so the links survive restarts.
+//
+// A hard link shares the bytes, so a torrent must call `unshare` before writing into
+// a file, otherwise the other torrents would see the write too.
+#[derive(Debug, Default)]
+pub struct DedupeIndex {
+    files: Vec<raw::SharedFile>,
+}
+
+impl DedupeIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Load the index from the file, start empty if the file is not exists yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => {
+                let index: raw::Index = serde_bencode::from_bytes(&bytes)?;
+                Ok(Self { files: index.files })
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(e) => Err(DedupeError::Io(e)),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let bytes = serde_bencode::to_bytes(&raw::Index {
+            files: self.files.clone(),
+        })?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    // The file of the torrent is complete and verified. If the same bytes are already stored
+    // for another torrent, the file is replaced by a hard link to them.
+    // Returns true if the file is linked.
+    pub fn register(&mut self, info_hash: Sha1Hash, path: &Path) -> Result<bool> {
+        let length = fs::metadata(path)?.len();
+        let hash = hash_file(path)?;
+        let reference = raw::Reference {
+            info_hash: info_hash.to_vec(),
+            path: path.to_string_lossy().into_owned(),
+        };
+
+        let existing = self
+            .files
+            .iter_mut()
+            .find(|it| it.length == length && it.hash == hash);
+        let Some(shared) = existing else {
+            self.files.push(raw::SharedFile {
+                hash: hash.to_vec(),
+                length,
+                references: vec![reference],
+            });
+            return Ok(false);
+        };
+        if shared.references.contains(&reference) {
+            return Ok(false);
+        }
+        // The user may have deleted some of the copies, they share nothing anymore
+        shared
+            .references
+            .retain(|it| Path::new(&it.path) != path && Path::new(&it.path).exists());
+        let Some(original) = shared.references.first().map(|it| PathBuf::from(&it.path)) else {
+            // This one is the only copy left
+            shared.references.push(reference);
+            return Ok(false);
+        };
+
+        // Link next to the file then rename over it, so the file is never missing.
+        let tmp_path = path.with_extension("dedupe");
+        let _ = fs::remove_file(&tmp_path);
+        if let Err(e) = fs::hard_link(&original, &tmp_path) {
+            // e.g. on another filesystem, keep the copy
+            log::warn!(
+                "Failed to link {} to {}: {:?}",
+                path.display(),
+                original.display(),
+                e
+            );
+            return Ok(false);
+        }
+        fs::rename(&tmp_path, path)?;
+        shared.references.push(reference);
+        Ok(true)
+    }
+
+    // Register the files of the torrent whose pieces are all in `have`, see `register`.
+    // Returns how many of them are linked.
+    pub fn register_torrent(
+        &mut self,
+        metainfo: &MetaInfo,
+        save_path: &Path,
+        have: &BitField,
+    ) -> Result<usize> {
+        let piece_length = metainfo.info.piece_length as u64;
+        let mut offset = 0;
+        let mut linked = 0;
+        for file in metainfo.files() {
+            let first_piece = offset / piece_length;
+            offset += file.length;
+            if file.length == 0 || file.attributes().padding {
+                continue;
+            }
+            let last_piece = (offset - 1) / piece_length;
+            let complete =
+                (first_piece..=last_piece).all(|it| have.get(it as usize).is_some_and(|it| *it));
+            // The same path the disk writes to, so `unshare` finds it
+            let path = save_path.join(file.path.join("/"));
+            if complete && self.register(metainfo.info_hash, &path)? {
+                linked += 1;
+            }
+        }
+        Ok(linked)
+    }
+
+    // The torrent is about to write into the file, give it its own copy if the bytes are shared.
+    pub fn unshare(&mut self, path: &Path) -> Result<()> {
+        let path_str = path.to_string_lossy();
+        let Some(shared) = self
+            .files
+            .iter_mut()
+            .find(|it| it.references.iter().any(|it| it.path == path_str))
+        else {
+            return Ok(());
+        };
+        shared.references.retain(|it| it.path != path_str);
+        if !shared.references.is_empty() {
+            let tmp_path = path.with_extension("dedupe");
+            fs::copy(path, &tmp_path)?;
+            fs::rename(&tmp_path, path)?;
+        }
+        self.files.retain(|it| !it.references.is_empty());
+        Ok(())
+    }
+
+    // The references of the torrent, to save with its resume data.
+    pub fn resume_data(&self, info_hash: Sha1Hash) -> Result<Vec<u8>> {
+        let files = self
+            .files
+            .iter()
+            .filter_map(|it| {
+                let references: Vec<_> = it
+                    .references
+                    .iter()
+                    .filter(|it| it.info_hash == info_hash)
+                    .cloned()
+                    .collect();
+                (!references.is_empty()).then(|| raw::SharedFile {
+                    references,
+                    ..it.clone()
+                })
+            })
+            .collect();
+        Ok(serde_bencode::to_bytes(&raw::Index { files })?)
+    }
+
+    // Take back the references of a torrent from its resume data.
+    pub fn restore(&mut self, resume_data: &[u8]) -> Result<()> {
+        let index: raw::Index = serde_bencode::from_bytes(resume_data)?;
+        for file in index.files {
+            let existing = self
+                .files
+                .iter_mut()
+                .find(|it| it.length == file.length && it.hash == file.hash);
+            let Some(shared) = existing else {
+                self.files.push(file);
+                continue;
+            };
+            for reference in file.references {
+                if !shared.references.contains(&reference) {
+                    shared.references.push(reference);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The torrent is removed, drop its references.
+    pub fn release(&mut self, info_hash: Sha1Hash) {
+        for shared in self.files.iter_mut() {
+            shared.references.retain(|it| it.info_hash != info_hash);
+        }
+        self.files.retain(|it| !it.references.is_empty());
+    }
+
+    // How many torrents share the bytes of the file, 0 if the file is not in the index.
+    pub fn references(&self, path: &Path) -> usize {
+        let path = path.to_string_lossy();
+        self.files
+            .iter()
+            .find(|it| it.references.iter().any(|it| it.path == path))
+            .map_or(0, |it| it.references.len())
+    }
+
+    // The bytes saved by the links.
+    pub fn saved_bytes(&self) -> u64 {
+        self.files
+            .iter()
+            .map(|it| it.length * (it.references.len() as u64).saturating_sub(1))
+            .sum()
+    }
+}
+
+fn hash_file(path: &Path) -> io::Result<Sha1Hash> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = IncrementalHash::default();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher.finalize())
+}
+
+mod raw {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    pub struct Index {
+        pub files: Vec<SharedFile>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct SharedFile {
+        // SHA-1 of the whole file
+        #[serde(with = "serde_bytes")]
+        pub hash: Vec<u8>,
+        pub length: u64,
+        pub references: Vec<Reference>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct Reference {
+        #[serde(rename = "info hash", with = "serde_bytes")]
+        pub info_hash: Vec<u8>,
+        pub path: String,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedupe_identical_files() {
+        let root = std::env::temp_dir().join("bitdrift_test_dedupe");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let a = root.join("a.mkv");
+        let b = root.join("b.mkv");
+        let c = root.join("c.mkv");
+        fs::write(&a, b"same bytes").unwrap();
+        fs::write(&b, b"same bytes").unwrap();
+        fs::write(&c, b"other byte").unwrap();
+
+        let mut index = DedupeIndex::new();
+        assert!(!index.register([1; 20], &a).unwrap());
+        assert!(index.register([2; 20], &b).unwrap());
+        assert!(!index.register([2; 20], &c).unwrap());
+        assert_eq!(index.references(&a), 2);
+        assert_eq!(index.references(&c), 1);
+        assert_eq!(index.saved_bytes(), 10);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(
+                fs::metadata(&a).unwrap().ino(),
+                fs::metadata(&b).unwrap().ino()
+            );
+        }
+
+        let index_path = root.join("dedupe.dat");
+        index.save(&index_path).unwrap();
+        let mut index = DedupeIndex::load(&index_path).unwrap();
+        assert_eq!(index.references(&b), 2);
+
+        // Writing into b must not change a
+        index.unshare(&b).unwrap();
+        fs::write(&b, b"new bytes!").unwrap();
+        assert_eq!(fs::read(&a).unwrap(), b"same bytes");
+        assert_eq!(index.references(&a), 1);
+
+        index.release([1; 20]);
+        assert_eq!(index.references(&a), 0);
+        assert_eq!(index.saved_bytes(), 0);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_deleted_original() {
+        let root = std::env::temp_dir().join("bitdrift_test_dedupe_deleted");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let a = root.join("a.mkv");
+        let b = root.join("b.mkv");
+        fs::write(&a, b"same bytes").unwrap();
+        fs::write(&b, b"same bytes").unwrap();
+
+        let mut index = DedupeIndex::new();
+        index.register([1; 20], &a).unwrap();
+        fs::remove_file(&a).unwrap();
+        // Nothing to link to, b is the only copy and saves nothing
+        assert!(!index.register([2; 20], &b).unwrap());
+        assert_eq!(index.references(&b), 1);
+        assert_eq!(index.saved_bytes(), 0);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_resume_data() {
+        let root = std::env::temp_dir().join("bitdrift_test_dedupe_resume");
+        let _ = fs::remove_dir_all(&root);
+        let metainfo = crate::metainfo::TestMetaInfo::new("show", 4)
+            .file("show/e01.mkv", b"abcd")
+            .file("show/e02.mkv", b"efgh")
+            .build();
+        let mut other = metainfo.clone();
+        other.info_hash = [2; 20];
+        for dir in ["first", "second"] {
+            fs::create_dir_all(root.join(dir).join("show")).unwrap();
+            fs::write(root.join(dir).join("show/e01.mkv"), b"abcd").unwrap();
+            fs::write(root.join(dir).join("show/e02.mkv"), b"efgh").unwrap();
+        }
+
+        let mut index = DedupeIndex::new();
+        let have_all = BitField::repeat(true, 2);
+        let have_first = [true, false].into_iter().collect();
+        let first = root.join("first");
+        let second = root.join("second");
+        assert_eq!(
+            index
+                .register_torrent(&metainfo, &first, &have_all)
+                .unwrap(),
+            0
+        );
+        // The second file isn't verified yet, it's left alone
+        assert_eq!(
+            index
+                .register_torrent(&other, &second, &have_first)
+                .unwrap(),
+            1
+        );
+        assert_eq!(index.saved_bytes(), 4);
+
+        let mut restored = DedupeIndex::new();
+        restored
+            .restore(&index.resume_data(metainfo.info_hash).unwrap())
+            .unwrap();
+        restored
+            .restore(&index.resume_data(other.info_hash).unwrap())
+            .unwrap();
+        restored
+            .restore(&index.resume_data([3; 20]).unwrap())
+            .unwrap();
+        assert_eq!(restored.references(&second.join("show/e01.mkv")), 2);
+        assert_eq!(restored.references(&first.join("show/e02.mkv")), 1);
+        assert_eq!(restored.saved_bytes(), 4);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
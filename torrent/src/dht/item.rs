@@ -0,0 +1,172 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use thiserror::Error;
+
+use super::node_id::NodeId;
+use crate::hash::calculate_sha1_hash;
+
+// Arbitrary data stored in the DHT, immutable items are addressed by the hash of the value,
+// mutable items by the public key which signs each version of them.
+// https://www.bittorrent.org/beps/bep_0044.html
+
+pub const MAX_VALUE_SIZE: usize = 1000;
+pub const MAX_SALT_SIZE: usize = 64;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ItemError {
+    #[error("The value is not bencoded")]
+    InvalidValue,
+    #[error("The value is larger than 1000 bytes")]
+    ValueTooBig,
+    #[error("The salt is larger than 64 bytes")]
+    SaltTooBig,
+    #[error("Invalid signature")]
+    InvalidSignature,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    // The bencoded value.
+    Immutable(Vec<u8>),
+    Mutable(MutableItem),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MutableItem {
+    // The ed25519 public key.
+    pub key: [u8; 32],
+    // Lets one key own several items.
+    pub salt: Vec<u8>,
+    // Newer versions have higher seq, the nodes keep only the latest.
+    pub seq: i64,
+    // The bencoded value.
+    pub value: Vec<u8>,
+    pub signature: [u8; 64],
+}
+
+impl Item {
+    pub fn target(&self) -> NodeId {
+        match self {
+            Item::Immutable(value) => immutable_target(value),
+            Item::Mutable(item) => mutable_target(&item.key, &item.salt),
+        }
+    }
+
+    pub fn value(&self) -> &[u8] {
+        match self {
+            Item::Immutable(value) => value,
+            Item::Mutable(item) => &item.value,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ItemError> {
+        validate_value(self.value())?;
+        match self {
+            Item::Immutable(_) => Ok(()),
+            Item::Mutable(item) => item.verify(),
+        }
+    }
+}
+
+impl MutableItem {
+    // Sign a new version of the item, `secret_key` is the ed25519 seed.
+    pub fn sign(
+        secret_key: &[u8; 32],
+        salt: &[u8],
+        seq: i64,
+        value: Vec<u8>,
+    ) -> Result<Self, ItemError> {
+        validate_value(&value)?;
+        if salt.len() > MAX_SALT_SIZE {
+            return Err(ItemError::SaltTooBig);
+        }
+        let signing_key = SigningKey::from_bytes(secret_key);
+        let signature = signing_key.sign(&signed_data(salt, seq, &value));
+        Ok(Self {
+            key: signing_key.verifying_key().to_bytes(),
+            salt: salt.to_vec(),
+            seq,
+            value,
+            signature: signature.to_bytes(),
+        })
+    }
+
+    pub fn verify(&self) -> Result<(), ItemError> {
+        if self.salt.len() > MAX_SALT_SIZE {
+            return Err(ItemError::SaltTooBig);
+        }
+        let key = VerifyingKey::from_bytes(&self.key).map_err(|_| ItemError::InvalidSignature)?;
+        let signature = Signature::from_bytes(&self.signature);
+        key.verify_strict(&signed_data(&self.salt, self.seq, &self.value), &signature)
+            .map_err(|_| ItemError::InvalidSignature)
+    }
+}
+
+pub fn immutable_target(value: &[u8]) -> NodeId {
+    NodeId(calculate_sha1_hash(value.to_vec()))
+}
+
+pub fn mutable_target(key: &[u8; 32], salt: &[u8]) -> NodeId {
+    NodeId(calculate_sha1_hash([&key[..], salt].concat()))
+}
+
+fn validate_value(value: &[u8]) -> Result<(), ItemError> {
+    if value.len() > MAX_VALUE_SIZE {
+        return Err(ItemError::ValueTooBig);
+    }
+    serde_bencode::from_bytes::<serde_bencode::value::Value>(value)
+        .map(|_| ())
+        .map_err(|_| ItemError::InvalidValue)
+}
+
+// What is signed is the bencoded dict of the salt, seq and v, without the "d" and "e".
+fn signed_data(salt: &[u8], seq: i64, value: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    if !salt.is_empty() {
+        data.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+        data.extend_from_slice(salt);
+    }
+    data.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    data.extend_from_slice(value);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_immutable_target() {
+        // Example from BEP 44
+        let item = Item::Immutable(b"12:Hello World!".to_vec());
+        assert_eq!(
+            format!("{:?}", item.target()),
+            "e5f96f6f38320f0f33959cb4d3d656452117aadb"
+        );
+        assert_eq!(item.validate(), Ok(()));
+        assert_eq!(
+            Item::Immutable(b"Hello World!".to_vec()).validate(),
+            Err(ItemError::InvalidValue)
+        );
+    }
+
+    #[test]
+    fn test_sign_mutable_item() {
+        assert_eq!(
+            signed_data(b"foobar", 1, b"12:Hello World!"),
+            b"4:salt6:foobar3:seqi1e1:v12:Hello World!"
+        );
+        let item = MutableItem::sign(&[1; 32], b"foobar", 1, b"12:Hello World!".to_vec()).unwrap();
+        assert_eq!(item.verify(), Ok(()));
+        assert_eq!(
+            Item::Mutable(item.clone()).target(),
+            mutable_target(&item.key, b"foobar")
+        );
+
+        let mut tampered = item.clone();
+        tampered.seq = 2;
+        assert_eq!(tampered.verify(), Err(ItemError::InvalidSignature));
+        let mut tampered = item;
+        tampered.salt = b"other".to_vec();
+        assert_eq!(tampered.verify(), Err(ItemError::InvalidSignature));
+    }
+}
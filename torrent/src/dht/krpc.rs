@@ -0,0 +1,480 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{
+    item::{Item, MutableItem},
+    node_id::NodeId,
+};
+use crate::{
+    compact::{decode_peers, encode_peers},
+    types::Sha1Hash,
+};
+
+// The KRPC protocol, bencoded dicts sent over UDP.
+// https://www.bittorrent.org/beps/bep_0005.html#krpc-protocol
+
+pub(crate) type Result<T> = std::result::Result<T, KrpcError>;
+
+#[derive(Debug, Error)]
+pub enum KrpcError {
+    #[error("Failed to parse KRPC message")]
+    Bencode(#[from] serde_bencode::Error),
+    #[error("Invalid KRPC message")]
+    InvalidMessage,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: Sha1Hash,
+    },
+    AnnouncePeer {
+        info_hash: Sha1Hash,
+        port: u16,
+        token: Vec<u8>,
+        // Use the source port of the UDP packet instead of the port argument.
+        implied_port: bool,
+    },
+    // BEP 44, the seq of the mutable item we already have, so the node can skip the value.
+    Get {
+        target: NodeId,
+        seq: Option<i64>,
+    },
+    Put {
+        token: Vec<u8>,
+        item: Item,
+        // Compare and swap, only store if the current seq is this one.
+        cas: Option<i64>,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Response {
+    pub nodes: Vec<(NodeId, SocketAddr)>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+    // The item of a get query, verified by who asked since the salt isn't sent back.
+    pub value: Option<Vec<u8>>,
+    pub key: Option<[u8; 32]>,
+    pub signature: Option<[u8; 64]>,
+    pub seq: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Body {
+    Query(Query),
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KrpcMessage {
+    pub transaction_id: Vec<u8>,
+    // The id of the sender, None for errors.
+    pub id: Option<NodeId>,
+    pub body: Body,
+}
+
+mod raw {
+    use serde_bencode::value::Value;
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    pub struct Message {
+        #[serde(with = "serde_bytes")]
+        pub t: Vec<u8>,
+        pub y: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub q: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub a: Option<Arguments>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub r: Option<Values>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub e: Option<(i64, String)>,
+    }
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    pub struct Arguments {
+        pub id: ByteBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub target: Option<ByteBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub info_hash: Option<ByteBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub port: Option<u16>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub token: Option<ByteBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub implied_port: Option<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub v: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub k: Option<ByteBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub sig: Option<ByteBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub seq: Option<i64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub salt: Option<ByteBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub cas: Option<i64>,
+    }
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    pub struct Values {
+        pub id: ByteBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub nodes: Option<ByteBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub values: Option<Vec<ByteBuf>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub token: Option<ByteBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub v: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub k: Option<ByteBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub sig: Option<ByteBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub seq: Option<i64>,
+    }
+}
+
+impl KrpcMessage {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let id = serde_bytes::ByteBuf::from(self.id.map(|id| id.0.to_vec()).unwrap_or_default());
+        let mut message = raw::Message {
+            t: self.transaction_id.clone(),
+            ..Default::default()
+        };
+        match &self.body {
+            Body::Query(query) => {
+                message.y = "q".to_string();
+                let mut arguments = raw::Arguments {
+                    id,
+                    ..Default::default()
+                };
+                let name = match query {
+                    Query::Ping => "ping",
+                    Query::FindNode { target } => {
+                        arguments.target = Some(target.0.to_vec().into());
+                        "find_node"
+                    }
+                    Query::GetPeers { info_hash } => {
+                        arguments.info_hash = Some(info_hash.to_vec().into());
+                        "get_peers"
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        token,
+                        implied_port,
+                    } => {
+                        arguments.info_hash = Some(info_hash.to_vec().into());
+                        arguments.port = Some(*port);
+                        arguments.token = Some(token.clone().into());
+                        arguments.implied_port = implied_port.then_some(1);
+                        "announce_peer"
+                    }
+                    Query::Get { target, seq } => {
+                        arguments.target = Some(target.0.to_vec().into());
+                        arguments.seq = *seq;
+                        "get"
+                    }
+                    Query::Put { token, item, cas } => {
+                        arguments.token = Some(token.clone().into());
+                        arguments.v = Some(to_value(item.value())?);
+                        if let Item::Mutable(item) = item {
+                            arguments.k = Some(item.key.to_vec().into());
+                            arguments.sig = Some(item.signature.to_vec().into());
+                            arguments.seq = Some(item.seq);
+                            arguments.salt =
+                                (!item.salt.is_empty()).then(|| item.salt.clone().into());
+                            arguments.cas = *cas;
+                        }
+                        "put"
+                    }
+                };
+                message.q = Some(name.to_string());
+                message.a = Some(arguments);
+            }
+            Body::Response(response) => {
+                message.y = "r".to_string();
+                message.r = Some(raw::Values {
+                    id,
+                    nodes: (!response.nodes.is_empty())
+                        .then(|| encode_nodes(&response.nodes).into()),
+                    values: (!response.values.is_empty()).then(|| {
+                        response
+                            .values
+                            .iter()
+                            .map(|peer| encode_peers(&[*peer]).0.into())
+                            .collect()
+                    }),
+                    token: response.token.clone().map(Into::into),
+                    v: response.value.as_deref().map(to_value).transpose()?,
+                    k: response.key.map(|it| it.to_vec().into()),
+                    sig: response.signature.map(|it| it.to_vec().into()),
+                    seq: response.seq,
+                });
+            }
+            Body::Error {
+                code,
+                message: text,
+            } => {
+                message.y = "e".to_string();
+                message.e = Some((*code, text.clone()));
+            }
+        }
+        Ok(serde_bencode::to_bytes(&message)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let message: raw::Message = serde_bencode::from_bytes(bytes)?;
+        let (id, body) = match message.y.as_str() {
+            "q" => {
+                let arguments = message.a.ok_or(KrpcError::InvalidMessage)?;
+                let info_hash = || {
+                    arguments
+                        .info_hash
+                        .as_ref()
+                        .and_then(|it| Sha1Hash::try_from(it.as_slice()).ok())
+                        .ok_or(KrpcError::InvalidMessage)
+                };
+                let query = match message.q.as_deref() {
+                    Some("ping") => Query::Ping,
+                    Some("find_node") => Query::FindNode {
+                        target: arguments
+                            .target
+                            .as_ref()
+                            .and_then(|it| NodeId::from_slice(it))
+                            .ok_or(KrpcError::InvalidMessage)?,
+                    },
+                    Some("get_peers") => Query::GetPeers {
+                        info_hash: info_hash()?,
+                    },
+                    Some("announce_peer") => Query::AnnouncePeer {
+                        info_hash: info_hash()?,
+                        port: arguments.port.ok_or(KrpcError::InvalidMessage)?,
+                        token: arguments
+                            .token
+                            .clone()
+                            .ok_or(KrpcError::InvalidMessage)?
+                            .into_vec(),
+                        implied_port: arguments.implied_port.unwrap_or(0) != 0,
+                    },
+                    Some("get") => Query::Get {
+                        target: arguments
+                            .target
+                            .as_ref()
+                            .and_then(|it| NodeId::from_slice(it))
+                            .ok_or(KrpcError::InvalidMessage)?,
+                        seq: arguments.seq,
+                    },
+                    Some("put") => {
+                        let value = serde_bencode::to_bytes(
+                            arguments.v.as_ref().ok_or(KrpcError::InvalidMessage)?,
+                        )?;
+                        let item = match &arguments.k {
+                            Some(key) => Item::Mutable(MutableItem {
+                                key: fixed(key)?,
+                                salt: arguments
+                                    .salt
+                                    .clone()
+                                    .map(|it| it.into_vec())
+                                    .unwrap_or_default(),
+                                seq: arguments.seq.ok_or(KrpcError::InvalidMessage)?,
+                                value,
+                                signature: fixed(
+                                    arguments.sig.as_ref().ok_or(KrpcError::InvalidMessage)?,
+                                )?,
+                            }),
+                            None => Item::Immutable(value),
+                        };
+                        Query::Put {
+                            token: arguments
+                                .token
+                                .clone()
+                                .ok_or(KrpcError::InvalidMessage)?
+                                .into_vec(),
+                            item,
+                            cas: arguments.cas,
+                        }
+                    }
+                    _ => return Err(KrpcError::InvalidMessage),
+                };
+                (NodeId::from_slice(&arguments.id), Body::Query(query))
+            }
+            "r" => {
+                let values = message.r.ok_or(KrpcError::InvalidMessage)?;
+                let response = Response {
+                    nodes: values.nodes.map(|it| decode_nodes(&it)).unwrap_or_default(),
+                    values: values
+                        .values
+                        .unwrap_or_default()
+                        .iter()
+                        .flat_map(|it| decode_peers(it, 4))
+                        .collect(),
+                    token: values.token.map(|it| it.into_vec()),
+                    value: values
+                        .v
+                        .map(|it| serde_bencode::to_bytes(&it))
+                        .transpose()?,
+                    key: values.k.map(|it| fixed(&it)).transpose()?,
+                    signature: values.sig.map(|it| fixed(&it)).transpose()?,
+                    seq: values.seq,
+                };
+                (NodeId::from_slice(&values.id), Body::Response(response))
+            }
+            "e" => {
+                let (code, text) = message.e.ok_or(KrpcError::InvalidMessage)?;
+                return Ok(Self {
+                    transaction_id: message.t,
+                    id: None,
+                    body: Body::Error {
+                        code,
+                        message: text,
+                    },
+                });
+            }
+            _ => return Err(KrpcError::InvalidMessage),
+        };
+        Ok(Self {
+            transaction_id: message.t,
+            id: Some(id.ok_or(KrpcError::InvalidMessage)?),
+            body,
+        })
+    }
+}
+
+fn to_value(bytes: &[u8]) -> Result<serde_bencode::value::Value> {
+    Ok(serde_bencode::from_bytes(bytes)?)
+}
+
+fn fixed<const N: usize>(bytes: &[u8]) -> Result<[u8; N]> {
+    bytes.try_into().map_err(|_| KrpcError::InvalidMessage)
+}
+
+// Compact node info, 20 bytes node id followed by the compact IPv4 peer.
+fn encode_nodes(nodes: &[(NodeId, SocketAddr)]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(nodes.len() * 26);
+    for (id, addr) in nodes {
+        // Only IPv4 nodes are in the nodes list, IPv6 nodes use nodes6.
+        if let IpAddr::V4(ip) = addr.ip() {
+            bytes.extend_from_slice(&id.0);
+            bytes.extend_from_slice(&ip.octets());
+            bytes.extend_from_slice(&addr.port().to_be_bytes());
+        }
+    }
+    bytes
+}
+
+fn decode_nodes(bytes: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    bytes
+        .chunks_exact(26)
+        .filter_map(|chunk| {
+            let id = NodeId::from_slice(&chunk[..20])?;
+            let ip = Ipv4Addr::new(chunk[20], chunk[21], chunk[22], chunk[23]);
+            let port = u16::from_be_bytes([chunk[24], chunk[25]]);
+            Some((id, SocketAddr::new(IpAddr::V4(ip), port)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_ping() {
+        // Example from BEP 5
+        let message = KrpcMessage {
+            transaction_id: b"aa".to_vec(),
+            id: Some(NodeId(*b"abcdefghij0123456789")),
+            body: Body::Query(Query::Ping),
+        };
+        let bytes = message.to_bytes().unwrap();
+        assert_eq!(
+            bytes,
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        );
+        assert_eq!(KrpcMessage::from_bytes(&bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn test_message_roundtrip() {
+        let messages = vec![
+            KrpcMessage {
+                transaction_id: b"ab".to_vec(),
+                id: Some(NodeId([1; 20])),
+                body: Body::Query(Query::AnnouncePeer {
+                    info_hash: [2; 20],
+                    port: 6881,
+                    token: b"token".to_vec(),
+                    implied_port: true,
+                }),
+            },
+            KrpcMessage {
+                transaction_id: b"ab".to_vec(),
+                id: Some(NodeId([1; 20])),
+                body: Body::Response(Response {
+                    nodes: vec![(NodeId([3; 20]), "10.0.0.1:6881".parse().unwrap())],
+                    values: vec![
+                        "10.0.0.2:6881".parse().unwrap(),
+                        "10.0.0.3:6881".parse().unwrap(),
+                    ],
+                    token: Some(b"token".to_vec()),
+                    ..Default::default()
+                }),
+            },
+            KrpcMessage {
+                transaction_id: b"ab".to_vec(),
+                id: Some(NodeId([1; 20])),
+                body: Body::Query(Query::Put {
+                    token: b"token".to_vec(),
+                    item: Item::Mutable(MutableItem {
+                        key: [4; 32],
+                        salt: b"salt".to_vec(),
+                        seq: 2,
+                        value: b"d1:ai1ee".to_vec(),
+                        signature: [5; 64],
+                    }),
+                    cas: Some(1),
+                }),
+            },
+            KrpcMessage {
+                transaction_id: b"ab".to_vec(),
+                id: Some(NodeId([1; 20])),
+                body: Body::Response(Response {
+                    token: Some(b"token".to_vec()),
+                    value: Some(b"12:Hello World!".to_vec()),
+                    key: Some([4; 32]),
+                    signature: Some([5; 64]),
+                    seq: Some(2),
+                    ..Default::default()
+                }),
+            },
+            KrpcMessage {
+                transaction_id: b"ab".to_vec(),
+                id: None,
+                body: Body::Error {
+                    code: 201,
+                    message: "A Generic Error Ocurred".to_string(),
+                },
+            },
+        ];
+        for message in messages {
+            let bytes = message.to_bytes().unwrap();
+            assert_eq!(KrpcMessage::from_bytes(&bytes).unwrap(), message);
+        }
+    }
+}
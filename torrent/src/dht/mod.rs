@@ -0,0 +1,772 @@
+mod item;
+mod krpc;
+mod node_id;
+mod routing_table;
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use serde::Serialize;
+use tokio::{
+    net::{UdpSocket, lookup_host},
+    sync::oneshot,
+    task::JoinHandle,
+    time::timeout,
+};
+
+pub use self::{
+    item::{Item, ItemError, MutableItem},
+    node_id::NodeId,
+    routing_table::BucketStatus,
+};
+use self::{
+    item::{immutable_target, mutable_target},
+    krpc::{Body, KrpcMessage, Query, Response},
+    routing_table::{K, Node, RoutingTable},
+};
+use crate::{hash::calculate_sha1_hash, tracker::local_ip_addresses, types::Sha1Hash};
+
+// Mainline DHT, find the peers of a torrent without any tracker.
+// https://www.bittorrent.org/beps/bep_0005.html
+
+pub const BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+// How many nodes are queried at the same time during a lookup.
+const ALPHA: usize = 3;
+// The announce tokens are accepted for 10 minutes, by keeping the previous secret.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+// Peers announced to us, keep only the latest ones for each torrent.
+const MAX_PEERS_PER_TORRENT: usize = 100;
+// Keep the get_peers response in a single UDP packet.
+const MAX_VALUES_PER_RESPONSE: usize = 50;
+const MAX_PACKET_SIZE: usize = 1500;
+// The items put to us are dropped unless they are put again, BEP 44 suggests 2 hours.
+const ITEM_LIFETIME: Duration = Duration::from_secs(2 * 60 * 60);
+const MAX_ITEMS: usize = 1000;
+// The success rate of the status is over this many of the last queries.
+const RECENT_QUERIES: usize = 100;
+// Without any query from the other nodes by then, they can't reach us.
+const FIREWALLED_AFTER: Duration = Duration::from_secs(5 * 60);
+
+// Handle to the DHT node, the engine queries it for the peers of each torrent.
+pub struct Dht {
+    inner: Arc<Inner>,
+    receiver: JoinHandle<()>,
+}
+
+struct Inner {
+    id: NodeId,
+    socket: UdpSocket,
+    state: Mutex<State>,
+    bound_at: Instant,
+}
+
+struct State {
+    table: RoutingTable,
+    // Queries waiting for the response, by transaction id.
+    pending: HashMap<Vec<u8>, oneshot::Sender<KrpcMessage>>,
+    next_transaction_id: u16,
+    peers: HashMap<Sha1Hash, Vec<SocketAddr>>,
+    // The BEP 44 items by target, with when they were put.
+    items: HashMap<NodeId, (Item, Instant)>,
+    secret: [u8; 20],
+    previous_secret: [u8; 20],
+    secret_rotated_at: Instant,
+    // Whether each of our last queries got a response, oldest first.
+    recent_queries: VecDeque<bool>,
+    // Queries the other nodes sent us, they can only if we are reachable.
+    incoming_queries: u64,
+}
+
+// What the DHT is doing, to tell whether a magnet link stuck at fetching the metadata
+// is a DHT problem, e.g. no nodes or a firewall.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DhtStatus {
+    pub node_count: usize,
+    pub buckets: Vec<BucketStatus>,
+    pub nat: NatStatus,
+    // Of the last queries, how many got a response, None before the first query.
+    pub query_success_rate: Option<f64>,
+    pub incoming_queries: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NatStatus {
+    // Other nodes queried us, so they can reach us.
+    Reachable,
+    // We have nodes but none of them queried us for a while, incoming packets are blocked.
+    Firewalled,
+    // Too early to tell.
+    Unknown,
+}
+
+// Result of an iterative lookup.
+#[derive(Default)]
+struct Lookup {
+    peers: HashSet<SocketAddr>,
+    // The closest nodes which responded, with the token to announce to them.
+    closest: Vec<(SocketAddr, Option<Vec<u8>>)>,
+    // The responses to the get queries which carried an item.
+    items: Vec<Response>,
+}
+
+impl Dht {
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Self::bind_with_external_ip(addr, None).await
+    }
+
+    // The node id is derived from the external IP, e.g. `ExternalIp::ip` once the trackers or
+    // the peers told it, so the other nodes behind the NAT don't drop us for a wrong id.
+    pub async fn bind_with_external_ip(
+        addr: SocketAddr,
+        external_ip: Option<IpAddr>,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        // Without a public address we are behind NAT, the external IP is unknown
+        let id = match (external_ip, local_ip_addresses()) {
+            (Some(ip), _) => NodeId::secure(ip),
+            (None, (Some(ip), _)) => NodeId::secure(IpAddr::V4(ip)),
+            _ => NodeId::random(),
+        };
+        let secret = rand::random();
+        let inner = Arc::new(Inner {
+            id,
+            socket,
+            state: Mutex::new(State {
+                table: RoutingTable::new(id),
+                pending: HashMap::new(),
+                next_transaction_id: 0,
+                peers: HashMap::new(),
+                items: HashMap::new(),
+                secret,
+                previous_secret: secret,
+                secret_rotated_at: Instant::now(),
+                recent_queries: VecDeque::new(),
+                incoming_queries: 0,
+            }),
+            bound_at: Instant::now(),
+        });
+        let receiver = tokio::spawn(inner.clone().receive());
+        Ok(Self { inner, receiver })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.socket.local_addr()
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.inner.state.lock().unwrap().table.len()
+    }
+
+    pub fn status(&self) -> DhtStatus {
+        let state = self.inner.state.lock().unwrap();
+        let node_count = state.table.len();
+        let nat = if state.incoming_queries > 0 {
+            NatStatus::Reachable
+        } else if !state.table.is_empty() && self.inner.bound_at.elapsed() >= FIREWALLED_AFTER {
+            NatStatus::Firewalled
+        } else {
+            NatStatus::Unknown
+        };
+        let responded = state.recent_queries.iter().filter(|it| **it).count();
+        DhtStatus {
+            node_count,
+            buckets: state.table.buckets(),
+            nat,
+            query_success_rate: (!state.recent_queries.is_empty())
+                .then(|| responded as f64 / state.recent_queries.len() as f64),
+            incoming_queries: state.incoming_queries,
+        }
+    }
+
+    // Join the network through the well known nodes, returns how many nodes we know after it.
+    pub async fn bootstrap(&self, hosts: &[&str]) -> usize {
+        let mut addrs = Vec::new();
+        for host in hosts {
+            match lookup_host(host).await {
+                Ok(resolved) => addrs.extend(resolved.filter(|it| it.is_ipv4())),
+                Err(e) => log::warn!("Failed to resolve DHT bootstrap node {}: {:?}", host, e),
+            }
+        }
+        let target = self.inner.id;
+        join_all(
+            addrs
+                .into_iter()
+                .map(|addr| self.inner.query(addr, Query::FindNode { target })),
+        )
+        .await;
+        // Find the nodes close to us, to fill the buckets near our id.
+        self.inner.lookup(target, Query::FindNode { target }).await;
+        self.node_count()
+    }
+
+    // Add a node we learned outside of the DHT, e.g. from the PORT message of a peer.
+    pub async fn add_node(&self, addr: SocketAddr) -> bool {
+        self.inner.query(addr, Query::Ping).await.is_some()
+    }
+
+    pub async fn get_peers(&self, info_hash: Sha1Hash) -> Vec<SocketAddr> {
+        let lookup = self
+            .inner
+            .lookup(NodeId(info_hash), Query::GetPeers { info_hash })
+            .await;
+        lookup.peers.into_iter().collect()
+    }
+
+    // Tell the closest nodes we are downloading the torrent, the port None means the peers
+    // should use the source port of our DHT packets, for the clients behind NAT.
+    // The peers found on the way are returned as well.
+    pub async fn announce(&self, info_hash: Sha1Hash, port: Option<u16>) -> Vec<SocketAddr> {
+        let lookup = self
+            .inner
+            .lookup(NodeId(info_hash), Query::GetPeers { info_hash })
+            .await;
+        join_all(lookup.closest.into_iter().filter_map(|(addr, token)| {
+            let query = Query::AnnouncePeer {
+                info_hash,
+                port: port.unwrap_or(0),
+                token: token?,
+                implied_port: port.is_none(),
+            };
+            Some(self.inner.query(addr, query))
+        }))
+        .await;
+        lookup.peers.into_iter().collect()
+    }
+
+    // The bencoded value which hashes to the target.
+    pub async fn get_immutable(&self, target: Sha1Hash) -> Option<Vec<u8>> {
+        let target = NodeId(target);
+        let lookup = self
+            .inner
+            .lookup(target, Query::Get { target, seq: None })
+            .await;
+        lookup
+            .items
+            .into_iter()
+            .filter_map(|response| response.value)
+            .find(|value| immutable_target(value) == target)
+    }
+
+    // The latest version of the mutable item, newer than `seq` when given.
+    pub async fn get_mutable(
+        &self,
+        key: &[u8; 32],
+        salt: &[u8],
+        seq: Option<i64>,
+    ) -> Option<MutableItem> {
+        let target = mutable_target(key, salt);
+        let lookup = self.inner.lookup(target, Query::Get { target, seq }).await;
+        lookup
+            .items
+            .into_iter()
+            .filter_map(|response| {
+                let item = MutableItem {
+                    key: response.key?,
+                    salt: salt.to_vec(),
+                    seq: response.seq?,
+                    value: response.value?,
+                    signature: response.signature?,
+                };
+                (item.key == *key && item.verify().is_ok()).then_some(item)
+            })
+            .filter(|item| seq.is_none_or(|seq| item.seq > seq))
+            .max_by_key(|item| item.seq)
+    }
+
+    // Store the item on the closest nodes to its target, returns how many of them stored it.
+    // `cas` is the seq of the mutable item we are replacing, the nodes which have another
+    // version reject the put.
+    pub async fn put(&self, item: Item, cas: Option<i64>) -> Result<usize, ItemError> {
+        item.validate()?;
+        let target = item.target();
+        let lookup = self
+            .inner
+            .lookup(target, Query::Get { target, seq: None })
+            .await;
+        let responses = join_all(lookup.closest.into_iter().filter_map(|(addr, token)| {
+            let query = Query::Put {
+                token: token?,
+                item: item.clone(),
+                cas,
+            };
+            Some(self.inner.query(addr, query))
+        }))
+        .await;
+        Ok(responses.into_iter().flatten().count())
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+impl Inner {
+    async fn receive(self: Arc<Self>) {
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        loop {
+            let (length, addr) = match self.socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    // e.g. ICMP port unreachable of a previous query on Windows
+                    log::debug!("Failed to receive DHT packet: {:?}", e);
+                    continue;
+                }
+            };
+            let message = match KrpcMessage::from_bytes(&buffer[..length]) {
+                Ok(message) => message,
+                Err(e) => {
+                    log::debug!("Received invalid DHT packet from {}: {:?}", addr, e);
+                    continue;
+                }
+            };
+            let reply = self.on_message(addr, message);
+            if let Some(reply) = reply {
+                self.send(addr, &reply).await;
+            }
+        }
+    }
+
+    fn on_message(&self, addr: SocketAddr, message: KrpcMessage) -> Option<KrpcMessage> {
+        let mut state = self.state.lock().unwrap();
+        match message.body {
+            Body::Query(query) => {
+                state.incoming_queries += 1;
+                let body = state.on_query(addr, query);
+                Some(KrpcMessage {
+                    transaction_id: message.transaction_id,
+                    id: Some(self.id),
+                    body,
+                })
+            }
+            Body::Response(_) | Body::Error { .. } => {
+                if let Some(id) = message.id {
+                    state.table.insert(Node::new(id, addr));
+                }
+                if let Some(pending) = state.pending.remove(&message.transaction_id) {
+                    let _ = pending.send(message);
+                }
+                None
+            }
+        }
+    }
+
+    async fn send(&self, addr: SocketAddr, message: &KrpcMessage) {
+        let bytes = match message.to_bytes() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                log::error!("Failed to encode DHT message: {:?}", e);
+                return;
+            }
+        };
+        if let Err(e) = self.socket.send_to(&bytes, addr).await {
+            log::debug!("Failed to send DHT message to {}: {:?}", addr, e);
+        }
+    }
+
+    // Returns None if the node didn't respond in time or responded with an error.
+    async fn query(&self, addr: SocketAddr, query: Query) -> Option<Response> {
+        let (sender, receiver) = oneshot::channel();
+        let transaction_id = {
+            let mut state = self.state.lock().unwrap();
+            state.next_transaction_id = state.next_transaction_id.wrapping_add(1);
+            let transaction_id = state.next_transaction_id.to_be_bytes().to_vec();
+            state.pending.insert(transaction_id.clone(), sender);
+            transaction_id
+        };
+        let message = KrpcMessage {
+            transaction_id: transaction_id.clone(),
+            id: Some(self.id),
+            body: Body::Query(query),
+        };
+        self.send(addr, &message).await;
+
+        let result = timeout(QUERY_TIMEOUT, receiver).await;
+        let mut state = self.state.lock().unwrap();
+        // An error response still means the node is there
+        state.recent_queries.push_back(matches!(result, Ok(Ok(_))));
+        if state.recent_queries.len() > RECENT_QUERIES {
+            state.recent_queries.pop_front();
+        }
+        match result {
+            Ok(Ok(KrpcMessage {
+                body: Body::Response(response),
+                ..
+            })) => Some(response),
+            Ok(_) => None,
+            Err(_) => {
+                state.pending.remove(&transaction_id);
+                None
+            }
+        }
+    }
+
+    // Iterative lookup, query the closer and closer nodes to the target until
+    // the closest nodes we know have all been queried.
+    async fn lookup(&self, target: NodeId, query: Query) -> Lookup {
+        let mut candidates: Vec<(NodeId, SocketAddr)> = {
+            let state = self.state.lock().unwrap();
+            state
+                .table
+                .closest(&target, K)
+                .into_iter()
+                .map(|node| (node.id, node.addr))
+                .collect()
+        };
+        let mut queried = HashSet::new();
+        let mut responded = Vec::new();
+        let mut lookup = Lookup::default();
+
+        loop {
+            let batch: Vec<(NodeId, SocketAddr)> = candidates
+                .iter()
+                .filter(|(_, addr)| !queried.contains(addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            queried.extend(batch.iter().map(|(_, addr)| *addr));
+
+            let responses = join_all(batch.into_iter().map(|(id, addr)| {
+                let query = query.clone();
+                async move { (id, addr, self.query(addr, query).await) }
+            }))
+            .await;
+
+            for (id, addr, response) in responses {
+                let Some(response) = response else {
+                    self.state.lock().unwrap().table.record_failure(&id);
+                    continue;
+                };
+                lookup.peers.extend(response.values.iter().copied());
+                responded.push((id, addr, response.token.clone()));
+                for node in &response.nodes {
+                    if !candidates.iter().any(|(_, addr)| *addr == node.1) {
+                        candidates.push(*node);
+                    }
+                }
+                if response.value.is_some() {
+                    lookup.items.push(response);
+                }
+            }
+            candidates.sort_by_key(|(id, _)| id.distance(&target));
+            candidates.truncate(K * 2);
+            if candidates
+                .iter()
+                .take(K)
+                .all(|(_, addr)| queried.contains(addr))
+            {
+                break;
+            }
+        }
+
+        responded.sort_by_key(|(id, _, _)| id.distance(&target));
+        lookup.closest = responded
+            .into_iter()
+            .take(K)
+            .map(|(_, addr, token)| (addr, token))
+            .collect();
+        lookup
+    }
+}
+
+impl State {
+    fn on_query(&mut self, addr: SocketAddr, query: Query) -> Body {
+        match query {
+            Query::Ping => Body::Response(Response::default()),
+            Query::FindNode { target } => Body::Response(Response {
+                nodes: self.closest_nodes(&target),
+                ..Default::default()
+            }),
+            Query::GetPeers { info_hash } => {
+                let token = Some(self.token(addr.ip()));
+                match self.peers.get(&info_hash) {
+                    Some(peers) => Body::Response(Response {
+                        values: peers
+                            .iter()
+                            .rev()
+                            .take(MAX_VALUES_PER_RESPONSE)
+                            .copied()
+                            .collect(),
+                        token,
+                        ..Default::default()
+                    }),
+                    None => Body::Response(Response {
+                        nodes: self.closest_nodes(&NodeId(info_hash)),
+                        token,
+                        ..Default::default()
+                    }),
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                token,
+                implied_port,
+            } => {
+                if !self.is_valid_token(addr.ip(), &token) {
+                    return Body::Error {
+                        code: 203,
+                        message: "Bad token".to_string(),
+                    };
+                }
+                let port = if implied_port { addr.port() } else { port };
+                let peer = SocketAddr::new(addr.ip(), port);
+                let peers = self.peers.entry(info_hash).or_default();
+                peers.retain(|it| *it != peer);
+                peers.push(peer);
+                if peers.len() > MAX_PEERS_PER_TORRENT {
+                    peers.remove(0);
+                }
+                Body::Response(Response::default())
+            }
+            Query::Get { target, seq } => {
+                let mut response = Response {
+                    nodes: self.closest_nodes(&target),
+                    token: Some(self.token(addr.ip())),
+                    ..Default::default()
+                };
+                self.expire_items();
+                match self.items.get(&target) {
+                    Some((Item::Immutable(value), _)) => response.value = Some(value.clone()),
+                    Some((Item::Mutable(item), _)) => {
+                        response.key = Some(item.key);
+                        response.seq = Some(item.seq);
+                        // They already have this version
+                        if seq.is_none_or(|seq| item.seq > seq) {
+                            response.value = Some(item.value.clone());
+                            response.signature = Some(item.signature);
+                        }
+                    }
+                    None => {}
+                }
+                Body::Response(response)
+            }
+            Query::Put { token, item, cas } => {
+                if !self.is_valid_token(addr.ip(), &token) {
+                    return Body::Error {
+                        code: 203,
+                        message: "Bad token".to_string(),
+                    };
+                }
+                if let Err(e) = item.validate() {
+                    let code = match e {
+                        ItemError::InvalidValue => 203,
+                        ItemError::ValueTooBig => 205,
+                        ItemError::InvalidSignature => 206,
+                        ItemError::SaltTooBig => 207,
+                    };
+                    return Body::Error {
+                        code,
+                        message: e.to_string(),
+                    };
+                }
+                let target = item.target();
+                if let (Item::Mutable(new), Some((Item::Mutable(current), _))) =
+                    (&item, self.items.get(&target))
+                {
+                    if cas.is_some_and(|cas| cas != current.seq) {
+                        return Body::Error {
+                            code: 301,
+                            message: "CAS mismatch".to_string(),
+                        };
+                    }
+                    if new.seq < current.seq {
+                        return Body::Error {
+                            code: 302,
+                            message: "Sequence number less than current".to_string(),
+                        };
+                    }
+                }
+                self.expire_items();
+                if self.items.len() >= MAX_ITEMS && !self.items.contains_key(&target) {
+                    let oldest = self
+                        .items
+                        .iter()
+                        .min_by_key(|(_, (_, put_at))| *put_at)
+                        .map(|(target, _)| *target);
+                    if let Some(oldest) = oldest {
+                        self.items.remove(&oldest);
+                    }
+                }
+                self.items.insert(target, (item, Instant::now()));
+                Body::Response(Response::default())
+            }
+        }
+    }
+
+    fn expire_items(&mut self) {
+        self.items
+            .retain(|_, (_, put_at)| put_at.elapsed() < ITEM_LIFETIME);
+    }
+
+    fn closest_nodes(&self, target: &NodeId) -> Vec<(NodeId, SocketAddr)> {
+        self.table
+            .closest(target, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect()
+    }
+
+    fn rotate_secret(&mut self) {
+        if self.secret_rotated_at.elapsed() >= TOKEN_ROTATION {
+            self.previous_secret = self.secret;
+            self.secret = rand::random();
+            self.secret_rotated_at = Instant::now();
+        }
+    }
+
+    // The token proves the announcing node received our get_peers response at its address.
+    fn token(&mut self, ip: IpAddr) -> Vec<u8> {
+        self.rotate_secret();
+        make_token(&self.secret, ip)
+    }
+
+    fn is_valid_token(&mut self, ip: IpAddr, token: &[u8]) -> bool {
+        self.rotate_secret();
+        token == make_token(&self.secret, ip) || token == make_token(&self.previous_secret, ip)
+    }
+}
+
+fn make_token(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+    let mut data = secret.to_vec();
+    match ip {
+        IpAddr::V4(ip) => data.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => data.extend_from_slice(&ip.octets()),
+    }
+    calculate_sha1_hash(data)[..8].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_announce_and_get_peers() {
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let router = Dht::bind(localhost).await.unwrap();
+        let router_addr = router.local_addr().unwrap().to_string();
+
+        let seeder = Dht::bind(localhost).await.unwrap();
+        assert_eq!(seeder.bootstrap(&[&router_addr]).await, 1);
+        let info_hash = [7u8; 20];
+        assert!(seeder.announce(info_hash, Some(6881)).await.is_empty());
+
+        let leecher = Dht::bind(localhost).await.unwrap();
+        leecher.bootstrap(&[&router_addr]).await;
+        assert_eq!(
+            leecher.get_peers(info_hash).await,
+            vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let node = Dht::bind(localhost).await.unwrap();
+        let client = Dht::bind(localhost).await.unwrap();
+        let status = client.status();
+        assert_eq!(status.node_count, 0);
+        assert_eq!(status.query_success_rate, None);
+        assert_eq!(status.nat, NatStatus::Unknown);
+
+        assert!(client.add_node(node.local_addr().unwrap()).await);
+        // Nobody listens there
+        let closed = UdpSocket::bind(localhost).await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        assert!(!client.add_node(closed_addr).await);
+
+        let status = client.status();
+        assert_eq!(status.node_count, 1);
+        assert_eq!(status.buckets.iter().map(|it| it.nodes).sum::<usize>(), 1);
+        assert_eq!(status.query_success_rate, Some(0.5));
+        assert_eq!(node.status().nat, NatStatus::Reachable);
+        assert_eq!(node.status().incoming_queries, 1);
+    }
+
+    #[tokio::test]
+    async fn test_announce_with_bad_token() {
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let node = Dht::bind(localhost).await.unwrap();
+        let client = Dht::bind(localhost).await.unwrap();
+        let query = Query::AnnouncePeer {
+            info_hash: [7u8; 20],
+            port: 6881,
+            token: b"forged".to_vec(),
+            implied_port: false,
+        };
+        assert!(
+            client
+                .inner
+                .query(node.local_addr().unwrap(), query)
+                .await
+                .is_none()
+        );
+        assert!(node.inner.state.lock().unwrap().peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_put_and_get_items() {
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let router = Dht::bind(localhost).await.unwrap();
+        let router_addr = router.local_addr().unwrap().to_string();
+        let publisher = Dht::bind(localhost).await.unwrap();
+        publisher.bootstrap(&[&router_addr]).await;
+        let reader = Dht::bind(localhost).await.unwrap();
+        reader.bootstrap(&[&router_addr]).await;
+
+        let immutable = Item::Immutable(b"12:Hello World!".to_vec());
+        let target = immutable.target();
+        assert_eq!(publisher.put(immutable, None).await, Ok(1));
+        assert_eq!(
+            reader.get_immutable(target.0).await,
+            Some(b"12:Hello World!".to_vec())
+        );
+
+        let secret_key = [9u8; 32];
+        let first = MutableItem::sign(&secret_key, b"salt", 1, b"i1e".to_vec()).unwrap();
+        let key = first.key;
+        assert_eq!(publisher.put(Item::Mutable(first), None).await, Ok(1));
+        let second = MutableItem::sign(&secret_key, b"salt", 2, b"i2e".to_vec()).unwrap();
+        // Replacing another version than the stored one
+        assert_eq!(
+            publisher.put(Item::Mutable(second.clone()), Some(0)).await,
+            Ok(0)
+        );
+        assert_eq!(
+            publisher.put(Item::Mutable(second.clone()), Some(1)).await,
+            Ok(1)
+        );
+        assert_eq!(reader.get_mutable(&key, b"salt", None).await, Some(second));
+        assert_eq!(reader.get_mutable(&key, b"salt", Some(2)).await, None);
+        assert_eq!(reader.get_mutable(&key, b"other", None).await, None);
+
+        let mut forged = MutableItem::sign(&secret_key, b"salt", 3, b"i3e".to_vec()).unwrap();
+        forged.value = b"i4e".to_vec();
+        assert_eq!(
+            publisher.put(Item::Mutable(forged), None).await,
+            Err(ItemError::InvalidSignature)
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use std::{fmt, net::IpAddr};
+
+// 160 bits identifier of a DHT node, in the same space as the info hashes.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    // The id derived from our external IP, so we can't pick where we land in the id space.
+    // https://www.bittorrent.org/beps/bep_0042.html
+    pub fn secure(ip: IpAddr) -> Self {
+        let r: u8 = rand::random();
+        let prefix = secure_prefix(ip, r);
+        let mut id: [u8; 20] = rand::random();
+        id[0] = prefix[0];
+        id[1] = prefix[1];
+        id[2] = (prefix[2] & 0xf8) | (id[2] & 0x07);
+        id[19] = r;
+        Self(id)
+    }
+
+    // Whether the node at the IP may use this id. The local network addresses are exempt,
+    // they have no external IP to derive from.
+    pub fn is_valid_for(&self, ip: IpAddr) -> bool {
+        if is_local(ip) {
+            return true;
+        }
+        let prefix = secure_prefix(ip, self.0[19]);
+        self.0[0] == prefix[0] && self.0[1] == prefix[1] && self.0[2] & 0xf8 == prefix[2] & 0xf8
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+
+    // Kademlia distance, smaller is closer.
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut distance = [0u8; 20];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        distance
+    }
+
+    // How many leading bits are shared with the other id, 160 if they are the same.
+    pub fn common_prefix_length(&self, other: &NodeId) -> usize {
+        let distance = self.distance(other);
+        distance
+            .iter()
+            .position(|byte| *byte != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)
+            .unwrap_or(160)
+    }
+}
+
+// The first 21 bits of the id, crc32c of the masked IP with the random number in the top bits.
+fn secure_prefix(ip: IpAddr, r: u8) -> [u8; 3] {
+    let mut bytes = match ip {
+        IpAddr::V4(ip) => {
+            let masked = u32::from_be_bytes(ip.octets()) & 0x030f3fff;
+            masked.to_be_bytes().to_vec()
+        }
+        IpAddr::V6(ip) => {
+            let high = u64::from_be_bytes(ip.octets()[..8].try_into().unwrap());
+            (high & 0x0103070f1f3f7fff).to_be_bytes().to_vec()
+        }
+    };
+    bytes[0] |= (r & 0x07) << 5;
+    let crc = crc32c(&bytes).to_be_bytes();
+    [crc[0], crc[1], crc[2]]
+}
+
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local(),
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_common_prefix_length() {
+        let a = NodeId([0; 20]);
+        let mut b = [0; 20];
+        b[1] = 0b0010_0000;
+        assert_eq!(a.common_prefix_length(&NodeId(b)), 10);
+        assert_eq!(a.common_prefix_length(&a), 160);
+    }
+
+    #[test]
+    fn test_secure_node_id() {
+        // Examples from BEP 42, the ip, the random number and the first 3 bytes of the id
+        let examples = [
+            ("124.31.75.21", 1, [0x5f, 0xbf, 0xbf]),
+            ("21.75.31.124", 86, [0x5a, 0x3c, 0xe9]),
+            ("65.23.51.170", 22, [0xa5, 0xd4, 0x32]),
+            ("84.124.73.14", 65, [0x1b, 0x03, 0x21]),
+            ("43.213.53.83", 90, [0xe5, 0x6f, 0x6c]),
+        ];
+        for (ip, r, prefix) in examples {
+            let ip: IpAddr = ip.parse().unwrap();
+            let mut id = [0u8; 20];
+            id[..3].copy_from_slice(&prefix);
+            id[19] = r;
+            assert!(NodeId(id).is_valid_for(ip), "{}", ip);
+            id[1] ^= 1;
+            assert!(!NodeId(id).is_valid_for(ip), "{}", ip);
+        }
+
+        for ip in ["124.31.75.21", "2001:db8::1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert!(NodeId::secure(ip).is_valid_for(ip));
+        }
+        assert!(NodeId([0; 20]).is_valid_for("192.168.1.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use super::node_id::NodeId;
+
+// Nodes per bucket
+pub const K: usize = 8;
+
+// A node which hasn't responded for this long may have left the network.
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+// A node which didn't respond to this many queries in a row is bad, it's dropped from the table.
+const MAX_FAILURES: u32 = 3;
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    last_seen: Instant,
+    // The queries it didn't respond to since it was last seen.
+    failures: u32,
+}
+
+impl Node {
+    pub fn new(id: NodeId, addr: SocketAddr) -> Self {
+        Self {
+            id,
+            addr,
+            last_seen: Instant::now(),
+            failures: 0,
+        }
+    }
+
+    fn is_questionable(&self) -> bool {
+        self.last_seen.elapsed() >= QUESTIONABLE_AFTER
+    }
+}
+
+// How full a bucket is, for the DHT status.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BucketStatus {
+    // How many bits the ids in the bucket share with ours.
+    pub prefix_length: usize,
+    pub nodes: usize,
+    pub questionable: usize,
+}
+
+// Kademlia routing table, the nodes are bucketed by how many bits they share with our id,
+// so we know more nodes close to us than far away.
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Add the node which we just heard from, or refresh it if it's already known.
+    // The nodes whose id doesn't match their IP are left out, see `NodeId::secure`,
+    // otherwise one host could fill the buckets around a torrent with made up ids.
+    pub fn insert(&mut self, node: Node) {
+        if node.id == self.own_id || !node.id.is_valid_for(node.addr.ip()) {
+            return;
+        }
+        let index = self.own_id.common_prefix_length(&node.id).min(159);
+        let bucket = &mut self.buckets[index];
+        if let Some(position) = bucket.iter().position(|it| it.id == node.id) {
+            // Move to the end, the bucket is ordered by last seen
+            bucket.remove(position);
+            bucket.push(node);
+            return;
+        }
+        if bucket.len() < K {
+            bucket.push(node);
+            return;
+        }
+        // The good nodes are kept over the new ones, they are more likely to stay online.
+        if let Some(position) = bucket.iter().position(|it| it.is_questionable()) {
+            bucket.remove(position);
+            bucket.push(node);
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        let index = self.own_id.common_prefix_length(id).min(159);
+        self.buckets[index].retain(|it| it.id != *id);
+    }
+
+    // The node didn't respond to a query, it's removed once it failed too many in a row.
+    // Hearing from it again with `insert` starts the count over.
+    pub fn record_failure(&mut self, id: &NodeId) {
+        let index = self.own_id.common_prefix_length(id).min(159);
+        let Some(node) = self.buckets[index].iter_mut().find(|it| it.id == *id) else {
+            return;
+        };
+        node.failures += 1;
+        if node.failures >= MAX_FAILURES {
+            self.remove(id);
+        }
+    }
+
+    // The buckets which have any node.
+    pub fn buckets(&self) -> Vec<BucketStatus> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| !bucket.is_empty())
+            .map(|(prefix_length, bucket)| BucketStatus {
+                prefix_length,
+                nodes: bucket.len(),
+                questionable: bucket.iter().filter(|it| it.is_questionable()).count(),
+            })
+            .collect()
+    }
+
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets.iter().flatten().cloned().collect();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_id(first_byte: u8, last_byte: u8) -> NodeId {
+        let mut id = [0u8; 20];
+        id[0] = first_byte;
+        id[19] = last_byte;
+        NodeId(id)
+    }
+
+    #[test]
+    fn test_insert_keeps_bucket_size() {
+        let mut table = RoutingTable::new(node_id(0, 0));
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        // All in the same bucket, the first bit differs from our id
+        for i in 0..K as u8 + 2 {
+            table.insert(Node::new(node_id(0x80, i), addr));
+        }
+        assert_eq!(table.len(), K);
+        // Refresh doesn't duplicate
+        table.insert(Node::new(node_id(0x80, 0), addr));
+        assert_eq!(table.len(), K);
+        // Our own id is never added
+        table.insert(Node::new(node_id(0, 0), addr));
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn test_insert_rejects_insecure_id() {
+        let mut table = RoutingTable::new(node_id(0, 0));
+        let addr: SocketAddr = "124.31.75.21:6881".parse().unwrap();
+        table.insert(Node::new(node_id(0x80, 1), addr));
+        assert!(table.is_empty());
+        table.insert(Node::new(NodeId::secure(addr.ip()), addr));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn test_remove_failed_node() {
+        let mut table = RoutingTable::new(node_id(0, 0));
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        table.insert(Node::new(node_id(0x80, 1), addr));
+        table.insert(Node::new(node_id(0x80, 2), addr));
+        for _ in 0..MAX_FAILURES - 1 {
+            table.record_failure(&node_id(0x80, 1));
+        }
+        // Heard from it again, the count starts over
+        table.insert(Node::new(node_id(0x80, 1), addr));
+        table.record_failure(&node_id(0x80, 1));
+        assert_eq!(table.len(), 2);
+
+        for _ in 0..MAX_FAILURES {
+            table.record_failure(&node_id(0x80, 2));
+        }
+        let closest = table.closest(&node_id(0x80, 0), K);
+        assert_eq!(closest.len(), 1);
+        assert_eq!(closest[0].id, node_id(0x80, 1));
+        // Unknown nodes are ignored
+        table.record_failure(&node_id(0x40, 1));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn test_closest() {
+        let mut table = RoutingTable::new(node_id(0, 0));
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        for first_byte in [0x80, 0x40, 0x20, 0x10] {
+            table.insert(Node::new(node_id(first_byte, 1), addr));
+        }
+        let closest = table.closest(&node_id(0x41, 0), 2);
+        assert_eq!(closest[0].id, node_id(0x40, 1));
+        assert_eq!(closest[1].id, node_id(0x10, 1));
+    }
+}
@@ -0,0 +1,543 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::{Mutex, Notify, mpsc},
+    task::JoinHandle,
+    time::{sleep_until, timeout},
+};
+
+use crate::transport::{BindSettings, PeerStream, TransportPolicy};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Where we learned the peer address from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerSource {
+    Manual,
+    Tracker,
+    Dht,
+    Pex,
+    // A relay asked us to dial the peer, see `holepunch`.
+    Holepunch,
+}
+
+impl PeerSource {
+    // The private torrents only take the peers from their trackers and the user.
+    pub fn is_allowed_for_private(&self) -> bool {
+        matches!(self, PeerSource::Manual | PeerSource::Tracker)
+    }
+
+    // Higher rank is dialed first, the manual added peers are what user asked explicitly.
+    // The holepunch peer is dialing us at the same time, the NAT only opens if we dial now.
+    fn rank(&self) -> u8 {
+        match self {
+            PeerSource::Holepunch => 4,
+            PeerSource::Manual => 3,
+            PeerSource::Tracker => 2,
+            PeerSource::Dht => 1,
+            PeerSource::Pex => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DialCandidate {
+    pub addr: SocketAddr,
+    pub source: PeerSource,
+    // Canonical peer priority, see `canonical_priority`.
+    pub priority: u32,
+    // How many times dialing this address failed before.
+    pub failures: u32,
+}
+
+impl DialCandidate {
+    pub fn new(addr: SocketAddr, source: PeerSource, own_ip: Option<IpAddr>) -> Self {
+        let priority = own_ip
+            .map(|own_ip| canonical_priority(own_ip, addr.ip()))
+            .unwrap_or(0);
+        Self {
+            addr,
+            source,
+            priority,
+            failures: 0,
+        }
+    }
+}
+
+impl PartialEq for DialCandidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DialCandidate {}
+
+impl PartialOrd for DialCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// The greater candidate is dialed first:
+// - the peer from the more trusted source
+// - if same source, the peer which failed fewer times
+// - if same failures, the peer with higher canonical priority
+impl Ord for DialCandidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.source
+            .rank()
+            .cmp(&other.source.rank())
+            .then_with(|| other.failures.cmp(&self.failures))
+            .then_with(|| self.priority.cmp(&other.priority))
+    }
+}
+
+// Pending candidates ordered by priority, each address is queued at most once.
+#[derive(Default)]
+pub struct DialQueue {
+    heap: BinaryHeap<DialCandidate>,
+    queued: HashSet<SocketAddr>,
+}
+
+impl DialQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns false if the address is already queued.
+    pub fn push(&mut self, candidate: DialCandidate) -> bool {
+        if !self.queued.insert(candidate.addr) {
+            return false;
+        }
+        self.heap.push(candidate);
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<DialCandidate> {
+        let candidate = self.heap.pop()?;
+        self.queued.remove(&candidate.addr);
+        Some(candidate)
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectSchedule {
+    // The connects started each second, spread evenly over the second.
+    pub connects_per_second: u32,
+    // Before the same address is dialed again, doubled for each time it failed.
+    pub cooldown: Duration,
+}
+
+impl Default for ConnectSchedule {
+    fn default() -> Self {
+        Self {
+            connects_per_second: 20,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+// The cooldown stops growing after this many failures.
+const MAX_COOLDOWN_DOUBLINGS: u32 = 4;
+
+// When the next connect may start, so the connects of a large peer list trickle out instead
+// of flooding the router's NAT table at once.
+struct ConnectScheduler {
+    schedule: ConnectSchedule,
+    next_connect: Option<Instant>,
+    last_attempt: HashMap<SocketAddr, Instant>,
+}
+
+impl ConnectScheduler {
+    fn new(schedule: ConnectSchedule) -> Self {
+        Self {
+            schedule,
+            next_connect: None,
+            last_attempt: HashMap::new(),
+        }
+    }
+
+    fn cooldown(&self, failures: u32) -> Duration {
+        self.schedule.cooldown * 2u32.pow(failures.min(MAX_COOLDOWN_DOUBLINGS))
+    }
+
+    // Some if the address was dialed too recently, with when it may be dialed again.
+    fn cooldown_until(&self, candidate: &DialCandidate, now: Instant) -> Option<Instant> {
+        let last_attempt = self.last_attempt.get(&candidate.addr)?;
+        let until = *last_attempt + self.cooldown(candidate.failures);
+        (until > now).then_some(until)
+    }
+
+    // Take the next free slot for dialing the address, returns when to start.
+    fn reserve(&mut self, addr: SocketAddr, now: Instant) -> Instant {
+        let interval = Duration::from_secs(1) / self.schedule.connects_per_second.max(1);
+        let start = self.next_connect.map_or(now, |it| it.max(now));
+        self.next_connect = Some(start + interval);
+
+        // The addresses out of their longest cooldown are as good as never dialed
+        let longest = self.cooldown(MAX_COOLDOWN_DOUBLINGS);
+        self.last_attempt
+            .retain(|_, at| now.saturating_duration_since(*at) < longest);
+        self.last_attempt.insert(addr, start);
+        start
+    }
+}
+
+pub struct DialOutcome {
+    pub candidate: DialCandidate,
+    pub result: std::io::Result<PeerStream>,
+}
+
+// Consume the dial queue with a bounded number of dialer tasks,
+// so a large peer list doesn't open hundreds of sockets at the same time.
+// The number of tasks is the half-open limit, the connects in progress at any time.
+pub struct Dialer {
+    queue: Arc<Mutex<DialQueue>>,
+    notify: Arc<Notify>,
+    scheduler: Arc<std::sync::Mutex<ConnectScheduler>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Dialer {
+    pub fn new(
+        max_concurrent: usize,
+        bind: BindSettings,
+        transport: TransportPolicy,
+        outcome_tx: mpsc::UnboundedSender<DialOutcome>,
+    ) -> Self {
+        let queue = Arc::new(Mutex::new(DialQueue::new()));
+        let notify = Arc::new(Notify::new());
+        let scheduler = Arc::new(std::sync::Mutex::new(ConnectScheduler::new(
+            ConnectSchedule::default(),
+        )));
+        let workers = (0..max_concurrent.max(1))
+            .map(|_| {
+                let queue = queue.clone();
+                let notify = notify.clone();
+                let scheduler = scheduler.clone();
+                let bind = bind.clone();
+                let outcome_tx = outcome_tx.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let candidate = queue.lock().await.pop();
+                        let Some(candidate) = candidate else {
+                            notify.notified().await;
+                            continue;
+                        };
+                        let now = Instant::now();
+                        let start = {
+                            let mut scheduler = scheduler.lock().unwrap();
+                            match scheduler.cooldown_until(&candidate, now) {
+                                Some(until) => Err(until),
+                                None => Ok(scheduler.reserve(candidate.addr, now)),
+                            }
+                        };
+                        let start = match start {
+                            Ok(start) => start,
+                            // Queued again once the cooldown is over, the task moves on
+                            Err(until) => {
+                                let queue = queue.clone();
+                                let notify = notify.clone();
+                                tokio::spawn(async move {
+                                    sleep_until(until.into()).await;
+                                    if queue.lock().await.push(candidate) {
+                                        notify.notify_one();
+                                    }
+                                });
+                                continue;
+                            }
+                        };
+                        sleep_until(start.into()).await;
+                        let result = match bind.local_addr(candidate.addr.is_ipv6()) {
+                            Ok(local_addr) => {
+                                let connect = transport.connect(candidate.addr, local_addr);
+                                match timeout(CONNECT_TIMEOUT, connect).await {
+                                    Ok(result) => result,
+                                    Err(_) => Err(std::io::Error::new(
+                                        std::io::ErrorKind::TimedOut,
+                                        "Connect to peer timed out",
+                                    )),
+                                }
+                            }
+                            // Not dialed at all, it would go out of another interface
+                            Err(e) => Err(e),
+                        };
+                        if outcome_tx.send(DialOutcome { candidate, result }).is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            queue,
+            notify,
+            scheduler,
+            workers,
+        }
+    }
+
+    pub fn with_schedule(self, schedule: ConnectSchedule) -> Self {
+        self.scheduler.lock().unwrap().schedule = schedule;
+        self
+    }
+
+    pub async fn push(&self, candidate: DialCandidate) {
+        if self.queue.lock().await.push(candidate) {
+            self.notify.notify_one();
+        }
+    }
+
+    // Dial the peers named by host:port, e.g. the x.pe peers of a magnet link.
+    // They are added by the user, so they are allowed for the private torrents too.
+    pub async fn push_hints(&self, hints: &[String], own_ip: Option<IpAddr>) {
+        for addr in resolve_hints(hints).await {
+            self.push(DialCandidate::new(addr, PeerSource::Manual, own_ip))
+                .await;
+        }
+    }
+
+    pub async fn pending(&self) -> usize {
+        self.queue.lock().await.len()
+    }
+
+    pub async fn shutdown(self) {
+        for worker in self.workers {
+            worker.abort();
+            let _ = worker.await;
+        }
+    }
+}
+
+// Resolve the host:port peers, the hostnames which fail to resolve are skipped.
+async fn resolve_hints(hints: &[String]) -> Vec<SocketAddr> {
+    let mut addrs = Vec::new();
+    for hint in hints {
+        match tokio::net::lookup_host(hint.as_str()).await {
+            // The first address is enough, it's the same peer
+            Ok(mut resolved) => addrs.extend(resolved.next()),
+            Err(e) => log::warn!("Failed to resolve peer {}: {:?}", hint, e),
+        }
+    }
+    addrs
+}
+
+// Canonical peer priority, so both side of a connection agree which peer is preferred.
+// https://www.bittorrent.org/beps/bep_0040.html
+pub fn canonical_priority(own_ip: IpAddr, peer_ip: IpAddr) -> u32 {
+    let (mut a, mut b) = match (own_ip, peer_ip) {
+        (IpAddr::V4(own), IpAddr::V4(peer)) => {
+            let (own, peer) = (own.octets(), peer.octets());
+            let mask: [u8; 4] = if own[..3] == peer[..3] {
+                [0xff; 4]
+            } else if own[..2] == peer[..2] {
+                [0xff, 0xff, 0xff, 0x55]
+            } else {
+                [0xff, 0xff, 0x55, 0x55]
+            };
+            (apply_mask(&own, &mask), apply_mask(&peer, &mask))
+        }
+        (IpAddr::V6(own), IpAddr::V6(peer)) => {
+            let mut mask = [0x55u8; 16];
+            mask[..6].copy_from_slice(&[0xff; 6]);
+            (
+                apply_mask(&own.octets(), &mask),
+                apply_mask(&peer.octets(), &mask),
+            )
+        }
+        _ => return 0,
+    };
+    if a > b {
+        std::mem::swap(&mut a, &mut b);
+    }
+    a.extend_from_slice(&b);
+    crc32c(&a)
+}
+
+fn apply_mask(ip: &[u8], mask: &[u8]) -> Vec<u8> {
+    ip.iter().zip(mask).map(|(i, m)| i & m).collect()
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn candidate(port: u16, source: PeerSource, priority: u32, failures: u32) -> DialCandidate {
+        DialCandidate {
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+            source,
+            priority,
+            failures,
+        }
+    }
+
+    #[test]
+    fn test_dial_queue_order() {
+        let mut queue = DialQueue::new();
+        queue.push(candidate(1, PeerSource::Pex, 100, 0));
+        queue.push(candidate(2, PeerSource::Tracker, 10, 2));
+        queue.push(candidate(3, PeerSource::Tracker, 5, 0));
+        queue.push(candidate(4, PeerSource::Tracker, 50, 0));
+        queue.push(candidate(5, PeerSource::Manual, 0, 5));
+
+        let ports: Vec<u16> = std::iter::from_fn(|| queue.pop())
+            .map(|it| it.addr.port())
+            .collect();
+        assert_eq!(ports, vec![5, 4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn test_dial_queue_dedup() {
+        let mut queue = DialQueue::new();
+        assert!(queue.push(candidate(1, PeerSource::Tracker, 0, 0)));
+        assert!(!queue.push(candidate(1, PeerSource::Dht, 0, 0)));
+        assert_eq!(queue.len(), 1);
+        queue.pop();
+        assert!(queue.push(candidate(1, PeerSource::Dht, 0, 0)));
+    }
+
+    #[test]
+    fn test_crc32c() {
+        // Test vector from RFC 3720
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn test_canonical_priority() {
+        // Examples from BEP 40
+        let priority = canonical_priority(
+            "123.213.32.10".parse().unwrap(),
+            "98.76.54.32".parse().unwrap(),
+        );
+        assert_eq!(priority, 0xec2d_7224);
+        let priority = canonical_priority(
+            "123.213.32.10".parse().unwrap(),
+            "123.213.32.234".parse().unwrap(),
+        );
+        assert_eq!(priority, 0x99568189);
+    }
+
+    #[tokio::test]
+    async fn test_dialer_connects_queued_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let dialer = Dialer::new(2, BindSettings::default(), TransportPolicy::default(), tx);
+
+        dialer
+            .push(DialCandidate::new(addr, PeerSource::Tracker, None))
+            .await;
+        let outcome = rx.recv().await.unwrap();
+        assert_eq!(outcome.candidate.addr, addr);
+        assert!(outcome.result.is_ok());
+
+        dialer.shutdown().await;
+    }
+
+    #[test]
+    fn test_connect_schedule() {
+        let mut scheduler = ConnectScheduler::new(ConnectSchedule {
+            connects_per_second: 10,
+            cooldown: Duration::from_secs(30),
+        });
+        let now = Instant::now();
+        let starts: Vec<_> = (0..3)
+            .map(|port| scheduler.reserve(candidate(port, PeerSource::Tracker, 0, 0).addr, now))
+            .collect();
+        assert_eq!(starts[0], now);
+        assert_eq!(starts[1], now + Duration::from_millis(100));
+        assert_eq!(starts[2], now + Duration::from_millis(200));
+
+        // The address just dialed waits out its cooldown, longer after it failed
+        let dialed = candidate(0, PeerSource::Tracker, 0, 0);
+        assert_eq!(
+            scheduler.cooldown_until(&dialed, now),
+            Some(now + Duration::from_secs(30))
+        );
+        let failed = candidate(0, PeerSource::Tracker, 0, 2);
+        assert_eq!(
+            scheduler.cooldown_until(&failed, now),
+            Some(now + Duration::from_secs(120))
+        );
+        let later = now + Duration::from_secs(30);
+        assert_eq!(scheduler.cooldown_until(&dialed, later), None);
+        assert_eq!(
+            scheduler.cooldown_until(&candidate(3, PeerSource::Tracker, 0, 0), now),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dialer_spreads_connects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let dialer = Dialer::new(4, BindSettings::default(), TransportPolicy::default(), tx)
+            .with_schedule(ConnectSchedule {
+                connects_per_second: 2,
+                cooldown: Duration::from_millis(300),
+            });
+
+        let started = Instant::now();
+        dialer
+            .push(DialCandidate::new(addr, PeerSource::Tracker, None))
+            .await;
+        rx.recv().await.unwrap();
+        // Dialed again, it waits for the cooldown
+        dialer
+            .push(DialCandidate::new(addr, PeerSource::Tracker, None))
+            .await;
+        rx.recv().await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        dialer.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_resolve_hints() {
+        let hints = [
+            "127.0.0.1:6881".to_string(),
+            "localhost:6882".to_string(),
+            "invalid.invalid:6883".to_string(),
+        ];
+        let addrs = resolve_hints(&hints).await;
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[0], "127.0.0.1:6881".parse().unwrap());
+        assert!(addrs[1].ip().is_loopback());
+        assert_eq!(addrs[1].port(), 6882);
+    }
+}
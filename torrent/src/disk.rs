@@ -1,45 +1,310 @@
-use std::io::{Seek, Write};
+use std::{
+    borrow::Cow,
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use tokio::{
-    sync::{mpsc, oneshot},
+    sync::{Semaphore, mpsc, oneshot},
     task::JoinHandle,
+    time::interval,
 };
 
 use crate::{
-    metainfo::MetaInfo,
-    piece::{self, Piece},
+    dedupe::{DedupeError, DedupeIndex},
+    metainfo::{MetaInfo, is_safe_path, raw},
+    piece::{self, Piece, PieceHash},
+    piece_picker::BlockInfo,
     types::BitField,
 };
 
 pub enum DiskCommand {
-    WritePiece(MetaInfo, Piece, Vec<u8>),
+    // The piece is boxed, it carries the hasher state which is large.
+    WritePiece(MetaInfo, Box<Piece>, Vec<u8>),
     BitField(MetaInfo, oneshot::Sender<BitField>),
+    ReadBlock(
+        MetaInfo,
+        BlockInfo,
+        oneshot::Sender<std::io::Result<Vec<u8>>>,
+    ),
+    DeleteFiles(
+        MetaInfo,
+        DeleteMode,
+        oneshot::Sender<Result<Vec<PathBuf>, DiskError>>,
+    ),
     Shutdown,
 }
 
+#[derive(Debug, Error)]
+pub enum DiskError {
+    #[error("Disk IO failed")]
+    Io(#[from] std::io::Error),
+    #[error("Written piece doesn't match its hash")]
+    VerifyFailed,
+    #[error("Failed to unshare deduplicated file")]
+    Dedupe(#[from] DedupeError),
+    #[error("Failed to move files to the trash")]
+    Trash(#[from] trash::Error),
+}
+
+impl DiskError {
+    // Worth trying again, e.g. the file is locked by an antivirus on Windows for a moment.
+    // A full or read-only disk needs the user to step in.
+    fn is_transient(&self) -> bool {
+        match self {
+            DiskError::Io(e) => !matches!(
+                e.kind(),
+                std::io::ErrorKind::StorageFull
+                    | std::io::ErrorKind::ReadOnlyFilesystem
+                    | std::io::ErrorKind::InvalidInput
+                    | std::io::ErrorKind::Unsupported
+            ),
+            DiskError::VerifyFailed | DiskError::Dedupe(_) | DiskError::Trash(_) => false,
+        }
+    }
+}
+
+pub enum DiskEvent {
+    // The piece is written (and verified if write verify is enabled), it's safe to mark it as completed.
+    PieceWritten(usize),
+    // A write failed even after the retries, the torrent should be paused until the user fixes it.
+    Error(usize, DiskError),
+    // The files are moved to the trash of the OS, the user can still restore them from there.
+    FilesTrashed(Vec<PathBuf>),
+}
+
+// How the downloaded files are removed with the torrent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeleteMode {
+    #[default]
+    Trash,
+    Permanent,
+}
+
+// How the failed piece writes are retried, the piece data is kept in memory meanwhile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteRetryPolicy {
+    // How many times a failed write is retried, 0 reports the first failure.
+    pub retries: u32,
+    // The delay before the first retry, doubled after each one up to the max delay.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for WriteRetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 5,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl WriteRetryPolicy {
+    fn delay(&self, retry: u32) -> Duration {
+        self.initial_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay)
+    }
+}
+
+pub const DEFAULT_MAX_DIRTY_BYTES: u64 = 64 * 1024 * 1024;
+pub const DEFAULT_FLUSH_INTERVAL_SECS: u64 = 30;
+
+// When the written pieces reach the disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum CacheMode {
+    // Each piece is synced to the disk before it's reported written.
+    #[default]
+    WriteThrough,
+    // The pieces are kept in memory and reported written at once, then synced to the disk
+    // together when they add up to `max_dirty_bytes` or every `flush_interval_secs`.
+    WriteBack {
+        max_dirty_bytes: u64,
+        flush_interval_secs: u64,
+    },
+}
+
+impl CacheMode {
+    pub fn write_back() -> Self {
+        CacheMode::WriteBack {
+            max_dirty_bytes: DEFAULT_MAX_DIRTY_BYTES,
+            flush_interval_secs: DEFAULT_FLUSH_INTERVAL_SECS,
+        }
+    }
+
+    // Shown next to the setting, what the mode risks when the app crashes or the power goes.
+    pub fn description(&self) -> &'static str {
+        match self {
+            CacheMode::WriteThrough => {
+                "Every piece is on the disk before it counts as downloaded. \
+                 Nothing is lost on a crash, at the cost of a sync per piece."
+            }
+            CacheMode::WriteBack { .. } => {
+                "Pieces are kept in memory and written to the disk in batches. \
+                 Fewer and larger writes, but a crash loses the pieces not written yet \
+                 and the torrent has to be rechecked."
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DiskOptions {
+    // Read the piece back and check its hash after writing,
+    // to catch silent corruption on the flaky drives at the cost of an extra read.
+    pub write_verify: bool,
+    // The directory the torrent files are saved in, empty means the working directory.
+    pub save_path: PathBuf,
+    // The files hard linked across torrents, they are copied before written to.
+    pub dedupe: Option<Arc<Mutex<DedupeIndex>>>,
+    pub write_retry: WriteRetryPolicy,
+    pub cache_mode: CacheMode,
+    // The threads reading the blocks to upload, None means one per CPU up to 8.
+    pub io_workers: Option<usize>,
+}
+
+impl DiskOptions {
+    pub fn io_workers(&self) -> usize {
+        self.io_workers.unwrap_or_else(default_io_workers).max(1)
+    }
+}
+
+pub fn default_io_workers() -> usize {
+    std::thread::available_parallelism().map_or(4, |it| it.get().min(8))
+}
+
+// The pieces reported written but not on the disk yet, with `CacheMode::WriteBack`.
+#[derive(Default)]
+struct WriteBackCache {
+    pieces: Vec<(MetaInfo, Box<Piece>, Vec<u8>)>,
+    dirty_bytes: u64,
+}
+
+impl WriteBackCache {
+    fn push(&mut self, meta_info: MetaInfo, piece: Box<Piece>, data: Vec<u8>) {
+        self.dirty_bytes += data.len() as u64;
+        self.pieces.push((meta_info, piece, data));
+    }
+
+    // The block if its piece is still in memory, the copy on the disk is missing or stale.
+    fn read(&self, meta_info: &MetaInfo, block: &BlockInfo) -> Option<Vec<u8>> {
+        let (_, _, data) = self.pieces.iter().rev().find(|(it, piece, _)| {
+            it.info_hash == meta_info.info_hash && piece.index == block.piece_index as usize
+        })?;
+        let begin = block.begin as usize;
+        data.get(begin..begin + block.length as usize)
+            .map(<[u8]>::to_vec)
+    }
+
+    // The files are going away, their pieces are no use.
+    fn discard(&mut self, meta_info: &MetaInfo) {
+        self.pieces
+            .retain(|(it, _, _)| it.info_hash != meta_info.info_hash);
+        self.dirty_bytes = self.pieces.iter().map(|it| it.2.len() as u64).sum();
+    }
+
+    // A piece which fails to be written was reported written already, the error tells the
+    // torrent to get it again.
+    async fn flush(&mut self, options: &DiskOptions, events: &mpsc::UnboundedSender<DiskEvent>) {
+        for (meta_info, piece, data) in self.pieces.drain(..) {
+            if let Err(e) = Disk::write_with_retry(&meta_info, &piece, &data, options).await {
+                log::error!("Failed to flush piece {}: {:?}", piece.index, e);
+                let _ = events.send(DiskEvent::Error(piece.index, e));
+            }
+        }
+        self.dirty_bytes = 0;
+    }
+}
+
 pub struct Disk {
     sender: mpsc::UnboundedSender<DiskCommand>,
     handle: JoinHandle<()>,
 }
 
 impl Disk {
-    pub fn new() -> Self {
+    pub fn new(options: DiskOptions) -> (Self, mpsc::UnboundedReceiver<DiskEvent>) {
         let (sender, mut receiver) = mpsc::unbounded_channel::<DiskCommand>();
+        let (event_tx, event_rx) = mpsc::unbounded_channel::<DiskEvent>();
 
         let handle = tokio::spawn(async move {
-            while let Some(command) = receiver.recv().await {
+            // The reads go on while the writes and the other commands wait their turn, a few
+            // at a time so the uploads of one slow disk don't take all the blocking threads
+            let readers = Arc::new(Semaphore::new(options.io_workers()));
+            let mut cache = WriteBackCache::default();
+            let (max_dirty_bytes, flush_interval) = match options.cache_mode {
+                CacheMode::WriteThrough => (None, Duration::MAX),
+                CacheMode::WriteBack {
+                    max_dirty_bytes,
+                    flush_interval_secs,
+                } => (
+                    Some(max_dirty_bytes),
+                    Duration::from_secs(flush_interval_secs.max(1)),
+                ),
+            };
+            let mut flush_timer = interval(flush_interval);
+            loop {
+                let command = tokio::select! {
+                    command = receiver.recv() => command,
+                    _ = flush_timer.tick(), if max_dirty_bytes.is_some() => {
+                        cache.flush(&options, &event_tx).await;
+                        continue;
+                    }
+                };
                 match command {
-                    DiskCommand::Shutdown => break,
-                    _ => Disk::handle_command(command),
+                    None | Some(DiskCommand::Shutdown) => break,
+                    Some(DiskCommand::WritePiece(meta_info, piece, data))
+                        if let Some(max_dirty_bytes) = max_dirty_bytes =>
+                    {
+                        let _ = event_tx.send(DiskEvent::PieceWritten(piece.index));
+                        cache.push(meta_info, piece, data);
+                        if cache.dirty_bytes >= max_dirty_bytes {
+                            cache.flush(&options, &event_tx).await;
+                        }
+                    }
+                    Some(DiskCommand::ReadBlock(meta_info, block, response_tx)) => {
+                        if let Some(data) = cache.read(&meta_info, &block) {
+                            let _ = response_tx.send(Ok(data));
+                            continue;
+                        }
+                        let readers = readers.clone();
+                        let save_path = options.save_path.clone();
+                        tokio::spawn(async move {
+                            let Ok(_reader) = readers.acquire_owned().await else {
+                                return;
+                            };
+                            let _ = tokio::task::spawn_blocking(move || {
+                                Disk::read_block_to(&meta_info, &save_path, &block, response_tx)
+                            })
+                            .await;
+                        });
+                    }
+                    Some(command) => {
+                        match &command {
+                            DiskCommand::DeleteFiles(meta_info, _, _) => cache.discard(meta_info),
+                            // The recheck reads the files, they must be complete
+                            DiskCommand::BitField(..) => cache.flush(&options, &event_tx).await,
+                            _ => {}
+                        }
+                        Disk::handle_command(command, &options, &event_tx).await;
+                    }
                 }
             }
+            cache.flush(&options, &event_tx).await;
         });
 
-        Self { sender, handle }
+        (Self { sender, handle }, event_rx)
     }
 
     pub fn write_piece(&self, meta_info: MetaInfo, piece: Piece, data: Vec<u8>) {
-        let command = DiskCommand::WritePiece(meta_info, piece, data);
+        let command = DiskCommand::WritePiece(meta_info, Box::new(piece), data);
         self.sender.send(command).unwrap();
     }
 
@@ -48,6 +313,14 @@ impl Disk {
         self.handle.await.unwrap();
     }
 
+    // Start over with other options, e.g. the user changed the workers, without restarting
+    // the app. The cached pieces are written first, the events go to the returned receiver.
+    pub async fn restart(&mut self, options: DiskOptions) -> mpsc::UnboundedReceiver<DiskEvent> {
+        let (disk, events) = Disk::new(options);
+        std::mem::replace(self, disk).shutdown().await;
+        events
+    }
+
     pub async fn bitfield(self, metainfo: MetaInfo) -> BitField {
         let (tx, rx) = oneshot::channel();
 
@@ -57,41 +330,331 @@ impl Disk {
         rx.await.unwrap()
     }
 
-    fn handle_command(command: DiskCommand) {
+    // Read a block to upload to the peer.
+    // Dropping the returned receiver aborts the read if the disk hasn't started it yet.
+    pub fn read_block(
+        &self,
+        metainfo: MetaInfo,
+        block: BlockInfo,
+    ) -> oneshot::Receiver<std::io::Result<Vec<u8>>> {
+        let (tx, rx) = oneshot::channel();
+        let command = DiskCommand::ReadBlock(metainfo, block, tx);
+        self.sender.send(command).unwrap();
+        rx
+    }
+
+    // Remove the files of the torrent which exist, returns their paths.
+    pub async fn delete_files(
+        &self,
+        metainfo: MetaInfo,
+        mode: DeleteMode,
+    ) -> Result<Vec<PathBuf>, DiskError> {
+        let (tx, rx) = oneshot::channel();
+        let command = DiskCommand::DeleteFiles(metainfo, mode, tx);
+        self.sender.send(command).unwrap();
+        rx.await.unwrap()
+    }
+
+    async fn handle_command(
+        command: DiskCommand,
+        options: &DiskOptions,
+        events: &mpsc::UnboundedSender<DiskEvent>,
+    ) {
         match command {
             DiskCommand::Shutdown => {}
             DiskCommand::WritePiece(meta_info, piece, data) => {
-                let filepath = Disk::filepath(&meta_info, piece.index);
-                let offset = Disk::offset_of_file(&meta_info, piece.index);
-                let full_path = filepath.join("/");
+                let event = match Disk::write_with_retry(&meta_info, &piece, &data, options).await {
+                    Ok(_) => DiskEvent::PieceWritten(piece.index),
+                    Err(e) => {
+                        log::error!("Failed to write piece {}: {:?}", piece.index, e);
+                        DiskEvent::Error(piece.index, e)
+                    }
+                };
+                let _ = events.send(event);
+            }
+            DiskCommand::BitField(meta_info, response_tx) => {
+                // Hashing all the pieces takes a while, the other commands go on meanwhile
+                let save_path = options.save_path.clone();
+                tokio::spawn(async move {
+                    let piece_count = meta_info.piece_count();
+                    let bitfield =
+                        tokio::task::spawn_blocking(move || Disk::recheck(&meta_info, &save_path))
+                            .await
+                            .unwrap_or_else(|_| BitField::repeat(false, piece_count));
+                    let _ = response_tx.send(bitfield);
+                });
+            }
+            DiskCommand::ReadBlock(meta_info, block, response_tx) => {
+                Disk::read_block_to(&meta_info, &options.save_path, &block, response_tx);
+            }
+            DiskCommand::DeleteFiles(meta_info, mode, response_tx) => {
+                let result = Disk::delete(&meta_info, &options.save_path, mode);
+                if mode == DeleteMode::Trash
+                    && let Ok(paths) = &result
+                {
+                    let _ = events.send(DiskEvent::FilesTrashed(paths.clone()));
+                }
+                let _ = response_tx.send(result);
+            }
+        }
+    }
+
+    fn read_block_to(
+        meta_info: &MetaInfo,
+        save_path: &Path,
+        block: &BlockInfo,
+        response_tx: oneshot::Sender<std::io::Result<Vec<u8>>>,
+    ) {
+        // The peer cancelled the request before we start reading.
+        if response_tx.is_closed() {
+            return;
+        }
+        let offset =
+            block.piece_index as u64 * meta_info.info.piece_length as u64 + block.begin as u64;
+        let result = Disk::read(meta_info, save_path, offset, block.length as usize);
+        let _ = response_tx.send(result);
+    }
+
+    fn delete(
+        metainfo: &MetaInfo,
+        save_path: &Path,
+        mode: DeleteMode,
+    ) -> Result<Vec<PathBuf>, DiskError> {
+        let paths: Vec<PathBuf> = metainfo
+            .files()
+            .iter()
+            .map(|file| save_path.join(file.path.join("/")))
+            .filter(|path| path.exists())
+            .collect();
+        match mode {
+            DeleteMode::Trash => trash::delete_all(&paths)?,
+            DeleteMode::Permanent => {
+                for path in &paths {
+                    std::fs::remove_file(path)?;
+                }
+            }
+        }
+        // Clean up the directories of a multi-file torrent which are empty now
+        for path in &paths {
+            let mut dir = path.parent();
+            while let Some(it) = dir.filter(|it| it.starts_with(save_path) && *it != save_path) {
+                if std::fs::remove_dir(it).is_err() {
+                    break;
+                }
+                dir = it.parent();
+            }
+        }
+        Ok(paths)
+    }
 
-                // Ensure the directory exists
-                std::fs::create_dir_all(std::path::Path::new(&full_path).parent().unwrap())
-                    .unwrap();
+    // The other commands wait while a write is retried, the disk is likely unusable meanwhile.
+    async fn write_with_retry(
+        meta_info: &MetaInfo,
+        piece: &Piece,
+        data: &[u8],
+        options: &DiskOptions,
+    ) -> Result<(), DiskError> {
+        let policy = options.write_retry;
+        let mut retry = 0;
+        loop {
+            match Disk::write(meta_info, piece, data, options) {
+                Err(e) if e.is_transient() && retry < policy.retries => {
+                    let delay = policy.delay(retry);
+                    log::warn!(
+                        "Failed to write piece {}, retry in {:?}: {:?}",
+                        piece.index,
+                        delay,
+                        e
+                    );
+                    retry += 1;
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
 
-                // Open the file and write the data
-                let mut file = std::fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open(full_path)
-                    .unwrap();
+    fn write(
+        meta_info: &MetaInfo,
+        piece: &Piece,
+        data: &[u8],
+        options: &DiskOptions,
+    ) -> Result<(), DiskError> {
+        let piece_offset = piece.index as u64 * meta_info.info.piece_length as u64;
+        let mut written = 0;
+        for (file, offset, length) in Disk::file_spans(meta_info, piece_offset, data.len()) {
+            let span = &data[written..written + length];
+            written += length;
+            // The padding is all zeros, no need to keep it on the disk
+            let attributes = file.attributes();
+            if attributes.padding {
+                continue;
+            }
+            let full_path = options.save_path.join(file.path.join("/"));
 
-                file.seek(std::io::SeekFrom::Start(offset as u64)).unwrap();
-                file.write_all(&data).unwrap();
-                file.flush().unwrap();
+            // Ensure the directory exists
+            if let Some(parent) = std::path::Path::new(&full_path).parent() {
+                std::fs::create_dir_all(parent)?;
             }
-            DiskCommand::BitField(meta_info, response_tx) => {
-                // TODO: read data from disk and get which pieces are available
-                let piece_length = meta_info.info.piece_length as usize;
-                let total_bytes = meta_info.total_bytes() as usize;
-                let piece_size = total_bytes / piece_length;
-                let mut bitfield = BitField::repeat(false, piece_size);
 
-                response_tx.send(bitfield).unwrap();
+            if let Some(dedupe) = &options.dedupe {
+                dedupe.lock().unwrap().unshare(&full_path)?;
+            }
+
+            // Open the file and write the data
+            let mut open_options = std::fs::OpenOptions::new();
+            open_options.write(true).create(true).truncate(false);
+            #[cfg(windows)]
+            if attributes.hidden {
+                use std::os::windows::fs::OpenOptionsExt;
+                // FILE_ATTRIBUTE_HIDDEN, the dot files are hidden already on the other OSes
+                open_options.attributes(0x2);
+            }
+            let mut file = open_options.open(&full_path)?;
+
+            file.seek(std::io::SeekFrom::Start(offset))?;
+            file.write_all(span)?;
+            file.flush()?;
+            // On the disk and not only in the page cache before it's reported written, also so
+            // the write verify reads back what is on the disk as much as we can.
+            file.sync_data()?;
+
+            #[cfg(unix)]
+            if attributes.executable {
+                use std::os::unix::fs::PermissionsExt;
+                let mut permissions = file.metadata()?.permissions();
+                if permissions.mode() & 0o111 != 0o111 {
+                    permissions.set_mode(permissions.mode() | 0o111);
+                    file.set_permissions(permissions)?;
+                }
             }
         }
+        Disk::create_symlinks(meta_info, &options.save_path);
+
+        if options.write_verify {
+            // Make sure we read back what is on the disk instead of the page cache as much as we can.
+            let written = Disk::read(meta_info, &options.save_path, piece_offset, data.len())?;
+            if !piece.hash.matches(&written) {
+                return Err(DiskError::VerifyFailed);
+            }
+        }
+        Ok(())
+    }
+
+    // The symlinks have no content, they are made along with the first piece written.
+    // A failed symlink is only logged, e.g. Windows needs a privilege for them.
+    fn create_symlinks(metainfo: &MetaInfo, save_path: &Path) {
+        let Some(files) = &metainfo.info.files else {
+            return;
+        };
+        for file in files.iter().filter(|it| it.attributes().symlink) {
+            let Some(target) = &file.symlink_path else {
+                continue;
+            };
+            let link = save_path.join(file.path.join("/"));
+            // The link and its target are relative to the torrent root, and must stay in it.
+            // The parsed torrents are checked already, not the ones built in code.
+            if !is_safe_path(&file.path) || !is_safe_path(target) {
+                log::warn!("Ignore symlink {:?} pointing out of the torrent", link);
+                continue;
+            }
+            if link.symlink_metadata().is_ok() {
+                continue;
+            }
+            let mut relative = PathBuf::new();
+            for _ in 1..file.path.len() {
+                relative.push("..");
+            }
+            relative.push(target.join("/"));
+            let result = link
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| symlink(&relative, &link));
+            if let Err(e) = result {
+                log::warn!("Failed to create symlink {:?}: {:?}", link, e);
+            }
+        }
+    }
+
+    // Hash every piece on the disk, the pieces missing or not matching their hash are unset.
+    fn recheck(metainfo: &MetaInfo, save_path: &Path) -> BitField {
+        piece::piece_hashes(metainfo)
+            .iter()
+            .enumerate()
+            .map(|(index, hash)| Disk::recheck_piece(metainfo, save_path, index as u32, hash))
+            .collect()
+    }
+
+    // Whether the piece on the disk matches its hash, false if it can't be read.
+    pub(crate) fn recheck_piece(
+        metainfo: &MetaInfo,
+        save_path: &Path,
+        index: u32,
+        hash: &PieceHash,
+    ) -> bool {
+        let piece_length = metainfo.info.piece_length as u64;
+        let offset = index as u64 * piece_length;
+        let length = piece_length.min((metainfo.total_bytes() as u64).saturating_sub(offset));
+        Disk::read(metainfo, save_path, offset, length as usize)
+            .is_ok_and(|data| hash.matches(&data))
     }
 
+    fn read(
+        metainfo: &MetaInfo,
+        save_path: &Path,
+        offset: u64,
+        length: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(length);
+        for (file, file_offset, span_length) in Disk::file_spans(metainfo, offset, length) {
+            if file.attributes().padding {
+                data.resize(data.len() + span_length, 0);
+                continue;
+            }
+            let mut file = std::fs::File::open(save_path.join(file.path.join("/")))?;
+            file.seek(std::io::SeekFrom::Start(file_offset))?;
+            let mut buffer = vec![0; span_length];
+            file.read_exact(&mut buffer)?;
+            data.extend_from_slice(&buffer);
+        }
+        if data.len() != length {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Range is out of the torrent",
+            ));
+        }
+        Ok(data)
+    }
+
+    // Map a range of the torrent into the (file, offset in the file, length) it covers,
+    // a range may cross multiple files in a multi-file torrent.
+    pub(crate) fn file_spans(
+        metainfo: &MetaInfo,
+        offset: u64,
+        length: usize,
+    ) -> Vec<(raw::File, u64, usize)> {
+        let files: Cow<[raw::File]> = match &metainfo.info.files {
+            Some(files) => Cow::Borrowed(files),
+            None => Cow::Owned(metainfo.files()),
+        };
+        let mut spans = Vec::new();
+        let mut file_start = 0u64;
+        let mut offset = offset;
+        let mut remaining = length as u64;
+        for file in files.iter() {
+            let file_end = file_start + file.length;
+            if remaining > 0 && offset < file_end {
+                let span_length = remaining.min(file_end - offset);
+                spans.push((file.clone(), offset - file_start, span_length as usize));
+                offset += span_length;
+                remaining -= span_length;
+            }
+            file_start = file_end;
+        }
+        spans
+    }
+
+    #[cfg(test)]
     fn filepath(metainfo: &MetaInfo, piece_index: usize) -> Vec<String> {
         if let Some(_) = metainfo.info.length {
             return vec![metainfo.info.name.clone()];
@@ -108,6 +671,7 @@ impl Disk {
         panic!("Invalid metainfo, must have length or files");
     }
 
+    #[cfg(test)]
     fn offset_of_file(metainfo: &MetaInfo, piece_index: usize) -> u32 {
         if let Some(_) = metainfo.info.length {
             return piece_index as u32 * metainfo.info.piece_length;
@@ -125,17 +689,30 @@ impl Disk {
     }
 }
 
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io::Read;
-
     use super::*;
+    use crate::{hash::calculate_sha1_hash, metainfo::TestMetaInfo};
 
     #[tokio::test]
     async fn test_write_piece_command() {
         // Mock MetaInfo and Piece
         let meta_info = MetaInfo {
-            announce: "http://example.com/announce".parse().unwrap(),
+            announce: Some("http://example.com/announce".parse().unwrap()),
+            announce_list: Vec::new(),
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
+            v2: None,
             info: crate::metainfo::raw::Info {
                 name: "test_file".to_string(),
                 piece_length: 1024,
@@ -154,11 +731,13 @@ mod tests {
 
         let data = vec![1, 2, 3, 4, 5];
 
-        Disk::handle_command(DiskCommand::WritePiece(
-            meta_info.clone(),
-            piece.clone(),
-            data.clone(),
-        ));
+        let (events, _) = mpsc::unbounded_channel();
+        Disk::handle_command(
+            DiskCommand::WritePiece(meta_info.clone(), Box::new(piece.clone()), data.clone()),
+            &DiskOptions::default(),
+            &events,
+        )
+        .await;
 
         // Verify the file was created and data was written
         let filepath = Disk::filepath(&meta_info, piece.index);
@@ -179,7 +758,11 @@ mod tests {
     async fn test_write_piece_command_multiple_files() {
         // Mock MetaInfo with multiple files
         let meta_info = MetaInfo {
-            announce: "http://example.com/announce".parse().unwrap(),
+            announce: Some("http://example.com/announce".parse().unwrap()),
+            announce_list: Vec::new(),
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
+            v2: None,
             info: crate::metainfo::raw::Info {
                 name: "test_torrent".to_string(),
                 piece_length: 1024,
@@ -188,10 +771,12 @@ mod tests {
                     crate::metainfo::raw::File {
                         length: 1024,
                         path: vec!["test/file1.txt".to_string()],
+                        ..Default::default()
                     },
                     crate::metainfo::raw::File {
                         length: 2048,
                         path: vec!["test/file2.txt".to_string()],
+                        ..Default::default()
                     },
                 ]),
                 pieces: vec![0; 40],
@@ -207,11 +792,13 @@ mod tests {
 
         let data = vec![6, 7, 8, 9, 10];
 
-        Disk::handle_command(DiskCommand::WritePiece(
-            meta_info.clone(),
-            piece.clone(),
-            data.clone(),
-        ));
+        let (events, _) = mpsc::unbounded_channel();
+        Disk::handle_command(
+            DiskCommand::WritePiece(meta_info.clone(), Box::new(piece.clone()), data.clone()),
+            &DiskOptions::default(),
+            &events,
+        )
+        .await;
 
         // Verify the file was created and data was written
         let filepath = Disk::filepath(&meta_info, piece.index);
@@ -227,4 +814,361 @@ mod tests {
         // Clean up the test files
         let _ = std::fs::remove_dir_all("test");
     }
+
+    #[tokio::test]
+    async fn test_write_back_cache() {
+        let meta_info = TestMetaInfo::new("test_write_back", 4)
+            .single(&[0; 8])
+            .build();
+        let (mut disk, mut events) = Disk::new(DiskOptions {
+            save_path: PathBuf::from("test_write_back_dir"),
+            cache_mode: CacheMode::WriteBack {
+                max_dirty_bytes: 8,
+                flush_interval_secs: 3600,
+            },
+            ..Default::default()
+        });
+        let path = Path::new("test_write_back_dir/test_write_back");
+
+        // Reported written and served from memory before it's on the disk
+        let piece = Piece::new_unverified(0, [0u8; 20], 4);
+        disk.write_piece(meta_info.clone(), piece, vec![1, 2, 3, 4]);
+        assert!(matches!(
+            events.recv().await,
+            Some(DiskEvent::PieceWritten(0))
+        ));
+        let data = disk.read_block(meta_info.clone(), BlockInfo::new(0, 1, 2));
+        assert_eq!(data.await.unwrap().unwrap(), vec![2, 3]);
+        assert!(!path.exists());
+
+        // The dirty window is full
+        let piece = Piece::new_unverified(1, [0u8; 20], 4);
+        disk.write_piece(meta_info.clone(), piece, vec![5, 6, 7, 8]);
+        assert!(matches!(
+            events.recv().await,
+            Some(DiskEvent::PieceWritten(1))
+        ));
+        let data = disk.read_block(meta_info.clone(), BlockInfo::new(0, 0, 4));
+        assert_eq!(data.await.unwrap().unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(std::fs::read(path).unwrap(), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // Restarting writes the cached pieces before the new options apply
+        let piece = Piece::new_unverified(0, [0u8; 20], 4);
+        disk.write_piece(meta_info, piece, vec![9, 9, 9, 9]);
+        assert!(matches!(
+            events.recv().await,
+            Some(DiskEvent::PieceWritten(0))
+        ));
+        let _events = disk
+            .restart(DiskOptions {
+                save_path: PathBuf::from("test_write_back_dir"),
+                ..Default::default()
+            })
+            .await;
+        assert_eq!(std::fs::read(path).unwrap(), vec![9, 9, 9, 9, 5, 6, 7, 8]);
+
+        disk.shutdown().await;
+        let _ = std::fs::remove_dir_all("test_write_back_dir");
+    }
+
+    #[tokio::test]
+    async fn test_read_block_across_files() {
+        let meta_info = MetaInfo {
+            announce: Some("http://example.com/announce".parse().unwrap()),
+            announce_list: Vec::new(),
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
+            v2: None,
+            info: crate::metainfo::raw::Info {
+                name: "test_read".to_string(),
+                piece_length: 8,
+                length: None,
+                files: Some(vec![
+                    crate::metainfo::raw::File {
+                        length: 4,
+                        path: vec!["test_read/file1.txt".to_string()],
+                        ..Default::default()
+                    },
+                    crate::metainfo::raw::File {
+                        length: 4,
+                        path: vec!["test_read/file2.txt".to_string()],
+                        ..Default::default()
+                    },
+                ]),
+                pieces: vec![0; 20],
+                extra: std::collections::BTreeMap::new(),
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info_hash: [0u8; 20],
+        };
+        std::fs::create_dir_all("test_read").unwrap();
+        std::fs::write("test_read/file1.txt", [1, 2, 3, 4]).unwrap();
+        std::fs::write("test_read/file2.txt", [5, 6, 7, 8]).unwrap();
+
+        let (tx, rx) = oneshot::channel();
+        let (events, _) = mpsc::unbounded_channel();
+        Disk::handle_command(
+            DiskCommand::ReadBlock(meta_info, BlockInfo::new(0, 2, 4), tx),
+            &DiskOptions::default(),
+            &events,
+        )
+        .await;
+        let data = rx.await.unwrap().unwrap();
+
+        assert_eq!(data, vec![3, 4, 5, 6]);
+
+        let _ = std::fs::remove_dir_all("test_read");
+    }
+
+    #[tokio::test]
+    async fn test_write_piece_with_write_verify() {
+        let meta_info = MetaInfo {
+            announce: Some("http://example.com/announce".parse().unwrap()),
+            announce_list: Vec::new(),
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
+            v2: None,
+            info: crate::metainfo::raw::Info {
+                name: "test_write_verify".to_string(),
+                piece_length: 4,
+                length: Some(8),
+                files: None,
+                pieces: vec![0; 40],
+                extra: std::collections::BTreeMap::new(),
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info_hash: [0u8; 20],
+        };
+        let options = DiskOptions {
+            write_verify: true,
+            ..Default::default()
+        };
+        let (events, mut event_rx) = mpsc::unbounded_channel();
+        let data = vec![5, 6, 7, 8];
+
+        let piece = Piece::new_unverified(1, calculate_sha1_hash(data.clone()), 4);
+        Disk::handle_command(
+            DiskCommand::WritePiece(meta_info.clone(), Box::new(piece), data.clone()),
+            &options,
+            &events,
+        )
+        .await;
+        assert!(matches!(
+            event_rx.recv().await,
+            Some(DiskEvent::PieceWritten(1))
+        ));
+
+        let piece = Piece::new_unverified(1, [0u8; 20], 4);
+        Disk::handle_command(
+            DiskCommand::WritePiece(meta_info, Box::new(piece), data),
+            &options,
+            &events,
+        )
+        .await;
+        assert!(matches!(
+            event_rx.recv().await,
+            Some(DiskEvent::Error(1, DiskError::VerifyFailed))
+        ));
+
+        let _ = std::fs::remove_file("test_write_verify");
+    }
+
+    #[tokio::test]
+    async fn test_retry_failed_write() {
+        let meta_info = MetaInfo {
+            announce: None,
+            announce_list: Vec::new(),
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
+            v2: None,
+            info: crate::metainfo::raw::Info {
+                name: "test_write_retry".to_string(),
+                piece_length: 4,
+                length: Some(4),
+                files: None,
+                pieces: vec![0; 20],
+                extra: std::collections::BTreeMap::new(),
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info_hash: [0u8; 20],
+        };
+        let write = |retries| {
+            let options = DiskOptions {
+                write_retry: WriteRetryPolicy {
+                    retries,
+                    initial_delay: Duration::from_millis(20),
+                    max_delay: Duration::from_millis(40),
+                },
+                ..Default::default()
+            };
+            let meta_info = meta_info.clone();
+            async move {
+                let (events, mut event_rx) = mpsc::unbounded_channel();
+                let piece = Piece::new_unverified(0, [0u8; 20], 4);
+                Disk::handle_command(
+                    DiskCommand::WritePiece(meta_info, Box::new(piece), vec![1, 2, 3, 4]),
+                    &options,
+                    &events,
+                )
+                .await;
+                event_rx.recv().await
+            }
+        };
+
+        // A directory in the way of the file stands in for a file locked for a moment
+        std::fs::create_dir_all("test_write_retry").unwrap();
+        assert!(matches!(
+            write(0).await,
+            Some(DiskEvent::Error(0, DiskError::Io(_)))
+        ));
+
+        tokio::spawn(async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            std::fs::remove_dir("test_write_retry").unwrap();
+        });
+        assert!(matches!(write(5).await, Some(DiskEvent::PieceWritten(0))));
+        assert_eq!(std::fs::read("test_write_retry").unwrap(), [1, 2, 3, 4]);
+
+        let _ = std::fs::remove_file("test_write_retry");
+    }
+
+    #[tokio::test]
+    async fn test_delete_files_permanently() {
+        let meta_info = MetaInfo {
+            announce: None,
+            announce_list: Vec::new(),
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
+            v2: None,
+            info: crate::metainfo::raw::Info {
+                name: "test_delete".to_string(),
+                piece_length: 4,
+                length: None,
+                files: Some(vec![
+                    crate::metainfo::raw::File {
+                        length: 4,
+                        path: vec!["dir".to_string(), "file1.txt".to_string()],
+                        ..Default::default()
+                    },
+                    crate::metainfo::raw::File {
+                        length: 4,
+                        path: vec!["file2.txt".to_string()],
+                        ..Default::default()
+                    },
+                ]),
+                pieces: vec![0; 40],
+                extra: std::collections::BTreeMap::new(),
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info_hash: [0u8; 20],
+        };
+        std::fs::create_dir_all("test_delete/dir").unwrap();
+        std::fs::write("test_delete/dir/file1.txt", [1, 2, 3, 4]).unwrap();
+        // Not a file of the torrent, kept
+        std::fs::write("test_delete/other.txt", [1]).unwrap();
+
+        let (tx, rx) = oneshot::channel();
+        let (events, mut event_rx) = mpsc::unbounded_channel();
+        let options = DiskOptions {
+            save_path: PathBuf::from("test_delete"),
+            ..Default::default()
+        };
+        Disk::handle_command(
+            DiskCommand::DeleteFiles(meta_info, DeleteMode::Permanent, tx),
+            &options,
+            &events,
+        )
+        .await;
+        let paths = rx.await.unwrap().unwrap();
+
+        assert_eq!(paths, vec![PathBuf::from("test_delete/dir/file1.txt")]);
+        assert!(!Path::new("test_delete/dir").exists());
+        assert!(Path::new("test_delete/other.txt").exists());
+        // Only trashing is undoable
+        assert!(event_rx.try_recv().is_err());
+
+        let _ = std::fs::remove_dir_all("test_delete");
+    }
+
+    #[tokio::test]
+    async fn test_write_piece_with_file_attributes() {
+        let file = |path: &[&str], length: u64, attr: &str| crate::metainfo::raw::File {
+            length,
+            path: path.iter().map(|it| it.to_string()).collect(),
+            attr: Some(attr.to_string()),
+            ..Default::default()
+        };
+        let meta_info = MetaInfo {
+            announce: None,
+            announce_list: Vec::new(),
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
+            v2: None,
+            info: crate::metainfo::raw::Info {
+                name: "test_attributes".to_string(),
+                piece_length: 8,
+                length: None,
+                files: Some(vec![
+                    file(&["bin", "run"], 3, "x"),
+                    file(&[".pad", "5"], 5, "p"),
+                    file(&["data"], 2, ""),
+                    crate::metainfo::raw::File {
+                        symlink_path: Some(vec!["data".to_string()]),
+                        ..file(&["bin", "link"], 0, "l")
+                    },
+                    // Would point to /etc/passwd
+                    crate::metainfo::raw::File {
+                        symlink_path: Some(vec!["".into(), "etc".into(), "passwd".into()]),
+                        ..file(&["bin", "escape"], 0, "l")
+                    },
+                ]),
+                pieces: vec![0; 40],
+                extra: std::collections::BTreeMap::new(),
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info_hash: [0u8; 20],
+        };
+        let options = DiskOptions {
+            save_path: PathBuf::from("test_attributes"),
+            ..Default::default()
+        };
+        let (events, _) = mpsc::unbounded_channel();
+        for (index, data) in [(0, vec![1, 2, 3, 0, 0, 0, 0, 0]), (1, vec![4, 5])] {
+            let piece = Piece::new_unverified(index, [0u8; 20], data.len() as u32);
+            Disk::handle_command(
+                DiskCommand::WritePiece(meta_info.clone(), Box::new(piece), data),
+                &options,
+                &events,
+            )
+            .await;
+        }
+
+        let save_path = Path::new("test_attributes");
+        assert_eq!(std::fs::read(save_path.join("bin/run")).unwrap(), [1, 2, 3]);
+        assert!(!save_path.join(".pad").exists());
+        assert_eq!(
+            Disk::read(&meta_info, save_path, 0, 10).unwrap(),
+            [1, 2, 3, 0, 0, 0, 0, 0, 4, 5]
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::metadata(save_path.join("bin/run")).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o111, 0o111);
+            assert_eq!(std::fs::read(save_path.join("bin/link")).unwrap(), [4, 5]);
+        }
+        assert!(save_path.join("bin/escape").symlink_metadata().is_err());
+
+        let _ = std::fs::remove_dir_all("test_attributes");
+    }
 }
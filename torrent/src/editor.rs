@@ -0,0 +1,223 @@
+use std::{io, ops::Range, path::Path};
+
+use serde::Serialize;
+use thiserror::Error;
+use url::Url;
+
+use crate::{
+    extension::bencode_value_length,
+    hash::calculate_sha1_hash,
+    metainfo::{MetaInfo, MetaInfoError},
+    types::Sha1Hash,
+};
+
+// Edit what's outside of the info dict of an existing .torrent, e.g. replace the dead
+// trackers before publishing it again. The info dict is copied byte by byte, so the info
+// hash stays the same even if the dict isn't in the canonical form we would encode.
+
+pub(crate) type Result<T> = std::result::Result<T, EditorError>;
+
+#[derive(Debug, Error)]
+pub enum EditorError {
+    #[error("Failed to read or write the torrent")]
+    Io(#[from] io::Error),
+    #[error("Invalid torrent")]
+    InvalidTorrent(#[from] MetaInfoError),
+    #[error("Failed to encode the torrent")]
+    Bencode(#[from] serde_bencode::Error),
+}
+
+pub struct TorrentEditor {
+    // The entries of the top level dict except the info, as their raw key and value.
+    fields: Vec<(Vec<u8>, Vec<u8>)>,
+    info_bytes: Vec<u8>,
+}
+
+impl TorrentEditor {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        // Fails the same way as adding the torrent would
+        MetaInfo::from_bytes(bytes)?;
+        let entries = dict_entries(bytes).ok_or(MetaInfoError::InvalidFileTree)?;
+        let mut fields = Vec::new();
+        let mut info_bytes = None;
+        for (key, value) in entries {
+            if key == b"info" {
+                info_bytes = Some(bytes[value].to_vec());
+            } else {
+                fields.push((key.to_vec(), bytes[value].to_vec()));
+            }
+        }
+        Ok(Self {
+            fields,
+            info_bytes: info_bytes.ok_or(MetaInfoError::InvalidFileTree)?,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn info_hash(&self) -> Sha1Hash {
+        calculate_sha1_hash(self.info_bytes.clone())
+    }
+
+    // The first tracker is the `announce` for the clients without BEP 12, and the tiers are
+    // only written when there is more than the one tracker.
+    pub fn set_trackers(&mut self, tiers: &[Vec<Url>]) -> Result<()> {
+        let tiers: Vec<Vec<&str>> = tiers
+            .iter()
+            .map(|tier| tier.iter().map(Url::as_str).collect::<Vec<_>>())
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        self.set_field(b"announce", tiers.first().map(|tier| tier[0]))?;
+        let tracker_count = tiers.iter().map(Vec::len).sum::<usize>();
+        self.set_field(b"announce-list", (tracker_count > 1).then_some(&tiers))
+    }
+
+    pub fn set_comment(&mut self, comment: Option<&str>) -> Result<()> {
+        self.set_field(b"comment", comment.filter(|it| !it.is_empty()))
+    }
+
+    pub fn set_web_seeds(&mut self, urls: &[Url]) -> Result<()> {
+        let urls: Vec<&str> = urls.iter().map(Url::as_str).collect();
+        self.set_field(b"url-list", (!urls.is_empty()).then_some(&urls))
+    }
+
+    // The private flag is in the info dict, unlike the rest it changes the info hash,
+    // the torrent becomes a new swarm.
+    pub fn set_private(&mut self, private: bool) {
+        let Some(entries) = dict_entries(&self.info_bytes) else {
+            return;
+        };
+        let mut info = b"d".to_vec();
+        let mut inserted = !private;
+        for (key, value) in entries {
+            if key == b"private" {
+                continue;
+            }
+            if !inserted && key > b"private".as_slice() {
+                info.extend_from_slice(b"7:privatei1e");
+                inserted = true;
+            }
+            info.extend_from_slice(format!("{}:", key.len()).as_bytes());
+            info.extend_from_slice(key);
+            info.extend_from_slice(&self.info_bytes[value]);
+        }
+        if !inserted {
+            info.extend_from_slice(b"7:privatei1e");
+        }
+        info.push(b'e');
+        self.info_bytes = info;
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut entries: Vec<(&[u8], &[u8])> = self
+            .fields
+            .iter()
+            .map(|(key, value)| (key.as_slice(), value.as_slice()))
+            .chain([(b"info".as_slice(), self.info_bytes.as_slice())])
+            .collect();
+        entries.sort_by_key(|(key, _)| *key);
+        let mut bytes = b"d".to_vec();
+        for (key, value) in entries {
+            bytes.extend_from_slice(format!("{}:", key.len()).as_bytes());
+            bytes.extend_from_slice(key);
+            bytes.extend_from_slice(value);
+        }
+        bytes.push(b'e');
+        bytes
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        Ok(std::fs::write(path, self.to_bytes())?)
+    }
+
+    // None removes the field.
+    fn set_field<T: Serialize>(&mut self, key: &[u8], value: Option<T>) -> Result<()> {
+        let value = value.map(|it| serde_bencode::to_bytes(&it)).transpose()?;
+        let index = self.fields.iter().position(|(it, _)| it == key);
+        match (index, value) {
+            (Some(index), Some(value)) => self.fields[index].1 = value,
+            (Some(index), None) => {
+                self.fields.remove(index);
+            }
+            (None, Some(value)) => self.fields.push((key.to_vec(), value)),
+            (None, None) => {}
+        }
+        Ok(())
+    }
+}
+
+// The keys and the ranges of their values in a bencoded dict.
+fn dict_entries(bytes: &[u8]) -> Option<Vec<(&[u8], Range<usize>)>> {
+    if bytes.first() != Some(&b'd') {
+        return None;
+    }
+    let mut entries = Vec::new();
+    let mut offset = 1;
+    while *bytes.get(offset)? != b'e' {
+        let key_length = bencode_value_length(&bytes[offset..])?;
+        let colon = bytes[offset..].iter().position(|it| *it == b':')?;
+        let key = &bytes[offset + colon + 1..offset + key_length];
+        offset += key_length;
+        let value_length = bencode_value_length(&bytes[offset..])?;
+        entries.push((key, offset..offset + value_length));
+        offset += value_length;
+    }
+    Some(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The info dict is not sorted, re-encoding it would change the info hash.
+    const INFO: &str = "d4:name1:a6:lengthi1e12:piece lengthi1e6:pieces20:12345678901234567890e";
+
+    #[test]
+    fn test_edit_torrent() {
+        let torrent = format!("d8:announce12:http://a.com7:comment3:old4:info{}e", INFO);
+        let mut editor = TorrentEditor::from_bytes(torrent.as_bytes()).unwrap();
+        let info_hash = calculate_sha1_hash(INFO.as_bytes().to_vec());
+        assert_eq!(editor.info_hash(), info_hash);
+
+        let url = |it: &str| Url::parse(it).unwrap();
+        editor
+            .set_trackers(&[vec![url("http://b.com/")], vec![url("udp://c.com:80")]])
+            .unwrap();
+        editor.set_comment(None).unwrap();
+        editor.set_web_seeds(&[url("http://d.com/a")]).unwrap();
+        assert_eq!(
+            String::from_utf8(editor.to_bytes()).unwrap(),
+            format!(
+                "d8:announce13:http://b.com/13:announce-listll13:http://b.com/el14:udp://c.com:80ee4:info{}8:url-listl14:http://d.com/aee",
+                INFO
+            )
+        );
+
+        editor.set_trackers(&[vec![url("http://b.com/")]]).unwrap();
+        let metainfo = MetaInfo::from_bytes(&editor.to_bytes()).unwrap();
+        assert!(metainfo.announce_list.is_empty());
+        assert_eq!(editor.info_hash(), info_hash);
+    }
+
+    #[test]
+    fn test_set_private() {
+        let torrent = format!("d4:info{}e", INFO);
+        let mut editor = TorrentEditor::from_bytes(torrent.as_bytes()).unwrap();
+        editor.set_private(true);
+        let metainfo = MetaInfo::from_bytes(&editor.to_bytes()).unwrap();
+        assert!(metainfo.is_private());
+        assert_ne!(
+            editor.info_hash(),
+            calculate_sha1_hash(INFO.as_bytes().to_vec())
+        );
+
+        editor.set_private(false);
+        assert!(
+            !MetaInfo::from_bytes(&editor.to_bytes())
+                .unwrap()
+                .is_private()
+        );
+    }
+}
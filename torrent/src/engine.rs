@@ -0,0 +1,1656 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use thiserror::Error;
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::{JoinHandle, JoinSet},
+    time::interval,
+};
+
+use crate::{
+    announcer::{AnnounceEvent, Announcer, AnnouncerHandle, DEFAULT_STOPPED_GRACE, TrackerStatus},
+    client_identity::ClientIdentity,
+    cross_seed,
+    dht::Dht,
+    dialer::{DialCandidate, PeerSource},
+    disk::{DeleteMode, Disk, DiskError, DiskEvent, DiskOptions},
+    existing_data::{self, PlaceMode},
+    external_ip::ExternalIp,
+    hash_check::{self, HashCheckJob, HashCheckOptions},
+    listener::{DialEvent, ListenPort, PeerListener, TorrentRegistry},
+    magnet::MagnetLink,
+    metainfo::MetaInfo,
+    peer_manager::{DisconnectReason, PeerManager, PeerManagerOptions},
+    profile::Profile,
+    qbittorrent::{
+        AddError, AddOptions, AddSource, Backend, ServerState, TorrentInfo, TorrentState,
+    },
+    startup::{StartupOptions, start_in_priority_order},
+    torrent::{Torrent, TorrentPhase},
+    tracker::{Tracker, TrackerError, TrackerScrape},
+    transfer::TransferSnapshot,
+    types::{BitField, Sha1Hash},
+    webseed::WebSeed,
+};
+
+// Runs the added torrents: the listener serves their incoming peers, an announcer for each
+// finds their peers, and the peer manager decides which of the peers to dial. The peers the
+// sessions learn of, e.g. through PEX, go to the peer manager too.
+
+// Dial the peers whose backoff ran out, when no new peer arrived meanwhile.
+const DIAL_INTERVAL: Duration = Duration::from_secs(1);
+// What qBittorrent reports as the eta of a torrent which isn't downloading.
+const INFINITE_ETA: i64 = 8640000;
+
+// How often the public torrents are announced to the DHT, the peers found on the way are dialed.
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Error)]
+pub enum EngineError {
+    #[error("Torrent is added already")]
+    AlreadyAdded,
+
+    #[error("Torrent is not added")]
+    NotFound(Sha1Hash),
+
+    #[error("Failed to set up the trackers of the torrent")]
+    Tracker(#[from] TrackerError),
+
+    #[error("Failed to place the existing data of the torrent")]
+    ExistingData(#[from] std::io::Error),
+
+    #[error("Failed to delete the files of the torrent")]
+    DeleteFiles(#[from] DiskError),
+}
+
+pub type Result<T> = std::result::Result<T, EngineError>;
+
+// What the engine tells its owner, e.g. to show the user, see `Engine::subscribe`.
+#[derive(Debug, Clone)]
+pub enum EngineEvent {
+    // Writing a piece failed after its retries, e.g. the disk is full or the piece doesn't
+    // match its hash once read back. The torrent is paused until the user fixes the disk and
+    // resumes it, the piece is downloaded again then.
+    DiskFailed {
+        info_hash: Sha1Hash,
+        piece_index: usize,
+        error: Arc<DiskError>,
+    },
+    // The files of the removed torrent went to the trash, the user may restore them from
+    // there.
+    FilesTrashed {
+        info_hash: Sha1Hash,
+        paths: Vec<PathBuf>,
+    },
+}
+
+pub struct Engine {
+    registry: TorrentRegistry,
+    port: ListenPort,
+    identity: ClientIdentity,
+    // Told the address the trackers see us at, None to ignore it.
+    external_ip: Option<Arc<ExternalIp>>,
+    // The one the listener is set up with, None while it's off.
+    dht: Option<Arc<Dht>>,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    candidates: mpsc::UnboundedSender<(Sha1Hash, DialCandidate)>,
+    torrents: Mutex<HashMap<Sha1Hash, AddedTorrent>>,
+    // The save path of a torrent added with one replaces the one in here.
+    disk_options: Mutex<DiskOptions>,
+    // Of the rechecks.
+    hash_check: Mutex<HashCheckOptions>,
+    // How long the stopped announce of a removed or paused torrent may take.
+    stopped_grace: Mutex<Duration>,
+    // Taken by `watch_disks`, the listener sends the disk failures of the torrents.
+    disk_events: Mutex<Option<mpsc::UnboundedReceiver<(Sha1Hash, DiskEvent)>>>,
+    events: broadcast::Sender<EngineEvent>,
+}
+
+// The torrent shared with its sessions, and what the engine keeps to run it.
+struct AddedTorrent {
+    torrent: Arc<tokio::sync::Mutex<Torrent>>,
+    // None while paused, or until the torrent is served.
+    announcer: Option<AnnouncerHandle>,
+    // The downloads from the web seeds of the torrent while it's served.
+    web_seeds: Vec<JoinHandle<()>>,
+    // The DHT announces of the torrent while it's served.
+    dht_announces: Option<JoinHandle<()>>,
+    paused: bool,
+    // The category and the save path the WebUI API clients added it with.
+    options: AddOptions,
+    added_on: SystemTime,
+    // The totals at the previous poll of the WebUI API, the rates are told from them.
+    last_poll: Option<(Instant, u64, u64)>,
+    last_rates: (u64, u64),
+}
+
+impl Engine {
+    // Run the listener, it's set up by the caller, e.g. with the encryption of the profile.
+    pub fn start(listener: PeerListener, identity: ClientIdentity) -> Self {
+        let (disk_events, received_disk_events) = mpsc::unbounded_channel();
+        let listener = listener.with_disk_events(disk_events);
+        let registry = listener.registry();
+        let dht = registry.dht();
+        let port = listener.listen_port();
+        tokio::spawn(async move {
+            if let Err(e) = listener.run().await {
+                log::error!("Peer listener stopped: {:?}", e);
+            }
+        });
+        let peer_manager = Arc::new(Mutex::new(PeerManager::new(PeerManagerOptions::default())));
+        let (candidates, received) = mpsc::unbounded_channel();
+        tokio::spawn(drive_peers(
+            registry.clone(),
+            peer_manager.clone(),
+            received,
+        ));
+        Self {
+            registry,
+            port,
+            identity,
+            external_ip: None,
+            dht,
+            peer_manager,
+            candidates,
+            torrents: Mutex::new(HashMap::new()),
+            disk_options: Mutex::new(DiskOptions::default()),
+            hash_check: Mutex::new(HashCheckOptions::default()),
+            stopped_grace: Mutex::new(DEFAULT_STOPPED_GRACE),
+            disk_events: Mutex::new(Some(received_disk_events)),
+            events: broadcast::channel(16).0,
+        }
+    }
+
+    pub fn with_external_ip(mut self, external_ip: Arc<ExternalIp>) -> Self {
+        self.external_ip = Some(external_ip);
+        self
+    }
+
+    pub fn with_disk_options(mut self, disk_options: DiskOptions) -> Self {
+        *self.disk_options.get_mut().unwrap() = disk_options;
+        self
+    }
+
+    pub fn with_hash_check_options(mut self, options: HashCheckOptions) -> Self {
+        *self.hash_check.get_mut().unwrap() = options;
+        self
+    }
+
+    // See `Announcer::with_stopped_grace`, quitting waits with `stop` on top of it.
+    pub fn with_stopped_grace(mut self, grace: Duration) -> Self {
+        *self.stopped_grace.get_mut().unwrap() = grace;
+        self
+    }
+
+    // The settings of the switched or edited profile which apply while running. The disks of
+    // the served torrents are restarted if their options changed, the rest applies to the next
+    // announcers and rechecks. The others take a restart, see `Profile::needs_restart`.
+    pub async fn apply_profile(&self, profile: &Profile, previous: &Profile) {
+        *self.stopped_grace.lock().unwrap() = profile.stopped_announce_grace();
+        *self.hash_check.lock().unwrap() = profile.hash_check_options();
+        {
+            let mut disk_options = self.disk_options.lock().unwrap();
+            let options = profile.disk_options();
+            disk_options.cache_mode = options.cache_mode;
+            disk_options.io_workers = options.io_workers;
+            disk_options.write_retry.retries = options.write_retry.retries;
+        }
+        if !profile.restarts_disk(previous) {
+            return;
+        }
+        let served: Vec<_> = self
+            .torrents
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, it)| !it.paused)
+            .map(|(info_hash, _)| *info_hash)
+            .collect();
+        for info_hash in served {
+            let options = self.disk_options(&info_hash);
+            self.registry.restart_disk(&info_hash, options).await;
+        }
+    }
+
+    // Receive what the engine tells from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<EngineEvent> {
+        self.events.subscribe()
+    }
+
+    // Pause the torrents whose disk failed and tell the subscribers. Started once by the owner
+    // of the engine, until then the failures wait.
+    pub fn watch_disks(self: &Arc<Self>) {
+        let Some(mut disk_events) = self.disk_events.lock().unwrap().take() else {
+            return;
+        };
+        let engine = Arc::downgrade(self);
+        tokio::spawn(async move {
+            while let Some((info_hash, event)) = disk_events.recv().await {
+                let Some(engine) = engine.upgrade() else {
+                    break;
+                };
+                engine.on_disk_event(info_hash, event).await;
+            }
+        });
+    }
+
+    pub fn registry(&self) -> TorrentRegistry {
+        self.registry.clone()
+    }
+
+    // Serve the torrent to the incoming peers and start announcing it. Returns the torrent
+    // shared with the sessions.
+    pub async fn add_torrent(&self, torrent: Torrent) -> Result<Arc<tokio::sync::Mutex<Torrent>>> {
+        self.add_torrent_with(torrent, AddOptions::default()).await
+    }
+
+    // Same as `add_torrent`, a paused torrent is kept without serving it until resumed.
+    pub async fn add_torrent_with(
+        &self,
+        torrent: Torrent,
+        options: AddOptions,
+    ) -> Result<Arc<tokio::sync::Mutex<Torrent>>> {
+        let (torrent, _) = self.insert(torrent, options).await?;
+        Ok(torrent)
+    }
+
+    // Add the torrent on top of the files the user has in `source_dir`, e.g. downloaded by
+    // another client, see `existing_data::add_with_existing_data`. Only the pieces which
+    // didn't pass the recheck are downloaded.
+    pub async fn add_with_existing_data(
+        &self,
+        metainfo: MetaInfo,
+        source_dir: PathBuf,
+        mode: PlaceMode,
+        mut options: AddOptions,
+    ) -> Result<Arc<tokio::sync::Mutex<Torrent>>> {
+        if self
+            .torrents
+            .lock()
+            .unwrap()
+            .contains_key(&metainfo.info_hash)
+        {
+            return Err(EngineError::AlreadyAdded);
+        }
+        let save_path = options
+            .save_path
+            .get_or_insert_with(|| self.disk_options.lock().unwrap().save_path.clone())
+            .clone();
+        let existing = existing_data::add_with_existing_data(
+            metainfo.clone(),
+            source_dir,
+            save_path.clone(),
+            mode,
+        )
+        .await?;
+        let torrent = self.add_torrent_with(existing.torrent, options).await?;
+        self.dedupe(metainfo, save_path, existing.have).await;
+        Ok(torrent)
+    }
+
+    // Seed the torrents of the .torrent files in `torrents_dir` whose data is complete in
+    // `data_dir`, e.g. the same release on other trackers, see `cross_seed`. The torrents
+    // added already are skipped. Returns the files added with their info hashes.
+    pub async fn cross_seed(
+        &self,
+        torrents_dir: PathBuf,
+        data_dir: PathBuf,
+        mut options: AddOptions,
+    ) -> Result<Vec<(PathBuf, Sha1Hash)>> {
+        let candidates = {
+            let data_dir = data_dir.clone();
+            tokio::task::spawn_blocking(move || {
+                let torrents = cross_seed::scan_torrent_files(&torrents_dir)?;
+                cross_seed::find_candidates(torrents, &data_dir)
+            })
+            .await
+            .map_err(std::io::Error::from)??
+        };
+        let save_path = options
+            .save_path
+            .get_or_insert_with(|| self.disk_options.lock().unwrap().save_path.clone())
+            .clone();
+        let mut added = Vec::new();
+        for (path, metainfo) in candidates {
+            let info_hash = metainfo.info_hash;
+            if self.torrents.lock().unwrap().contains_key(&info_hash) {
+                continue;
+            }
+            let Some(torrent) =
+                cross_seed::set_up(metainfo.clone(), data_dir.clone(), save_path.clone()).await?
+            else {
+                continue;
+            };
+            match self.add_torrent_with(torrent, options.clone()).await {
+                Ok(_) => {
+                    let have = BitField::repeat(true, metainfo.piece_count());
+                    self.dedupe(metainfo, save_path.clone(), have).await;
+                    added.push((path, info_hash));
+                }
+                Err(EngineError::AlreadyAdded) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(added)
+    }
+
+    // Add the torrents of the library when the app starts, the higher priorities announce
+    // and dial first, see `start_in_priority_order`. A torrent is started once its trackers
+    // answered the first announce, or failed it.
+    pub async fn start_torrents(self: &Arc<Self>, torrents: Vec<Torrent>, options: StartupOptions) {
+        let torrents = torrents
+            .into_iter()
+            .map(|it| {
+                let priority = it.options().priority;
+                (it, priority)
+            })
+            .collect();
+        start_in_priority_order(torrents, options, |torrent| {
+            let engine = self.clone();
+            async move {
+                let info_hash = torrent.info_hash();
+                let mut events = match engine.insert(torrent, AddOptions::default()).await {
+                    Ok((_, Some(events))) => events,
+                    Ok((_, None)) => return,
+                    Err(e) => {
+                        log::warn!("Failed to start torrent {}: {}", hex(&info_hash), e);
+                        return;
+                    }
+                };
+                let has_trackers = engine
+                    .tracker_status(&info_hash)
+                    .is_some_and(|it| !it.is_empty());
+                if !has_trackers {
+                    return;
+                }
+                let announced = async {
+                    while let Ok(event) = events.recv().await {
+                        if matches!(
+                            event,
+                            AnnounceEvent::Announced { .. } | AnnounceEvent::AllFailed { .. }
+                        ) {
+                            break;
+                        }
+                    }
+                };
+                // The tier moves on after its timeout anyway, free the slot by then too
+                let _ = tokio::time::timeout(options.tier_timeout, announced).await;
+            }
+        })
+        .await;
+    }
+
+    // Returns the announces of the torrent too, None if it's paused.
+    async fn insert(
+        &self,
+        torrent: Torrent,
+        options: AddOptions,
+    ) -> Result<(
+        Arc<tokio::sync::Mutex<Torrent>>,
+        Option<broadcast::Receiver<AnnounceEvent>>,
+    )> {
+        let info_hash = torrent.info_hash();
+        let paused = options.paused;
+        let torrent = Arc::new(tokio::sync::Mutex::new(torrent));
+        {
+            // Taken before serving, so the same torrent can't be added twice meanwhile
+            let mut torrents = self.torrents.lock().unwrap();
+            if torrents.contains_key(&info_hash) {
+                return Err(EngineError::AlreadyAdded);
+            }
+            torrents.insert(
+                info_hash,
+                AddedTorrent {
+                    torrent: torrent.clone(),
+                    announcer: None,
+                    web_seeds: Vec::new(),
+                    dht_announces: None,
+                    paused,
+                    options,
+                    added_on: SystemTime::now(),
+                    last_poll: None,
+                    last_rates: (0, 0),
+                },
+            );
+        }
+        if paused {
+            return Ok((torrent, None));
+        }
+        match self.serve(&info_hash, torrent.clone()).await {
+            Ok((announcer, events)) => {
+                self.set_announcer(&info_hash, announcer);
+                Ok((torrent, Some(events)))
+            }
+            Err(e) => {
+                self.torrents.lock().unwrap().remove(&info_hash);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn torrent(&self, info_hash: &Sha1Hash) -> Option<Arc<tokio::sync::Mutex<Torrent>>> {
+        let torrents = self.torrents.lock().unwrap();
+        torrents.get(info_hash).map(|it| it.torrent.clone())
+    }
+
+    // Empty while the torrent is paused, None if it's not added.
+    pub fn tracker_status(&self, info_hash: &Sha1Hash) -> Option<Vec<TrackerStatus>> {
+        let torrents = self.torrents.lock().unwrap();
+        let added = torrents.get(info_hash)?;
+        Some(
+            added
+                .announcer
+                .as_ref()
+                .map(AnnouncerHandle::tracker_status)
+                .unwrap_or_default(),
+        )
+    }
+
+    // No peer of the torrent is dialed or accepted from now on, and the trackers are told it
+    // stopped. Returns false if the torrent is not added.
+    pub async fn remove_torrent(&self, info_hash: &Sha1Hash) -> bool {
+        let Some(added) = self.torrents.lock().unwrap().remove(info_hash) else {
+            return false;
+        };
+        self.unserve(info_hash);
+        if let Some(dedupe) = self.disk_options.lock().unwrap().dedupe.clone() {
+            dedupe.lock().unwrap().release(*info_hash);
+        }
+        for web_seed in added.web_seeds {
+            web_seed.abort();
+        }
+        if let Some(dht_announces) = added.dht_announces {
+            dht_announces.abort();
+        }
+        if let Some(announcer) = added.announcer {
+            announcer.stop().await;
+        }
+        true
+    }
+
+    // Same as `remove_torrent`, its downloaded files are deleted first, see `DeleteMode`. The
+    // torrent is kept if they can't be. Returns the deleted paths.
+    pub async fn remove_torrent_with_data(
+        &self,
+        info_hash: &Sha1Hash,
+        mode: DeleteMode,
+    ) -> Result<Vec<PathBuf>> {
+        let torrent = self
+            .torrent(info_hash)
+            .ok_or(EngineError::NotFound(*info_hash))?;
+        // Nothing is downloaded before the metadata
+        let metainfo = torrent.lock().await.metainfo().cloned();
+        let paths = match metainfo {
+            Some(metainfo) => {
+                match self
+                    .registry
+                    .delete_files(info_hash, metainfo.clone(), mode)
+                    .await
+                {
+                    Some(result) => result?,
+                    // Paused, the files are deleted without a disk of its own
+                    None => {
+                        let (disk, mut events) = Disk::new(self.disk_options(info_hash));
+                        let result = disk.delete_files(metainfo, mode).await;
+                        disk.shutdown().await;
+                        while let Ok(event) = events.try_recv() {
+                            self.on_disk_event(*info_hash, event).await;
+                        }
+                        result?
+                    }
+                }
+            }
+            None => Vec::new(),
+        };
+        self.remove_torrent(info_hash).await;
+        Ok(paths)
+    }
+
+    // Dial the peers for the torrent, e.g. the manual ones imported from a peer list. They are
+    // dropped while the torrent is paused.
+    pub fn add_peers(&self, info_hash: &Sha1Hash, candidates: Vec<DialCandidate>) -> Result<()> {
+        if !self.torrents.lock().unwrap().contains_key(info_hash) {
+            return Err(EngineError::NotFound(*info_hash));
+        }
+        for candidate in candidates {
+            let _ = self.candidates.send((*info_hash, candidate));
+        }
+        Ok(())
+    }
+
+    // Stop serving the torrent and tell its trackers, it's kept until resumed or removed.
+    pub async fn pause_torrent(&self, info_hash: &Sha1Hash) -> Result<()> {
+        let (torrent, announcer) = {
+            let mut torrents = self.torrents.lock().unwrap();
+            let added = torrents
+                .get_mut(info_hash)
+                .ok_or(EngineError::NotFound(*info_hash))?;
+            if added.paused {
+                return Ok(());
+            }
+            added.paused = true;
+            (added.torrent.clone(), added.announcer.take())
+        };
+        self.unserve(info_hash);
+        if let Some(announcer) = announcer {
+            let snapshot = torrent.lock().await.transfer_snapshot().await;
+            announcer.stop_with(snapshot).await;
+        }
+        Ok(())
+    }
+
+    pub async fn resume_torrent(&self, info_hash: &Sha1Hash) -> Result<()> {
+        let torrent = {
+            let mut torrents = self.torrents.lock().unwrap();
+            let added = torrents
+                .get_mut(info_hash)
+                .ok_or(EngineError::NotFound(*info_hash))?;
+            if !added.paused {
+                return Ok(());
+            }
+            added.paused = false;
+            added.torrent.clone()
+        };
+        match self.serve(info_hash, torrent).await {
+            Ok((announcer, _)) => {
+                self.set_announcer(info_hash, announcer);
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(added) = self.torrents.lock().unwrap().get_mut(info_hash) {
+                    added.paused = true;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Quitting, the totals of every torrent are taken before their sessions close, then the
+    // stopped announces go out together. Returns false if they didn't all make it within
+    // the grace.
+    pub async fn stop(&self, grace: Duration) -> bool {
+        let stopping: Vec<_> = self
+            .torrents
+            .lock()
+            .unwrap()
+            .values_mut()
+            .filter_map(|it| Some((it.torrent.clone(), it.announcer.take()?)))
+            .collect();
+        let mut stopped = JoinSet::new();
+        for (torrent, announcer) in stopping {
+            let snapshot = torrent.lock().await.transfer_snapshot().await;
+            stopped.spawn(announcer.stop_with(snapshot));
+        }
+        let all_stopped = async { while stopped.join_next().await.is_some() {} };
+        tokio::time::timeout(grace, all_stopped).await.is_ok()
+    }
+
+    // Ask the trackers of the torrent of its swarm without announcing, in the tier order.
+    // The trackers which failed or can't scrape are left out.
+    pub async fn scrape(&self, info_hash: &Sha1Hash) -> Result<Vec<TrackerScrape>> {
+        let torrent = self
+            .torrent(info_hash)
+            .ok_or(EngineError::NotFound(*info_hash))?;
+        let (tiers, local_addr, tls) = {
+            let torrent = torrent.lock().await;
+            let options = torrent.options();
+            (
+                torrent.tracker_tiers(),
+                options.bind_addr,
+                options.tracker_tls.clone(),
+            )
+        };
+        let mut scrapes = Vec::new();
+        for url in tiers.into_iter().flatten() {
+            let tracker = Tracker::with_tls(url.clone(), local_addr, &self.identity, &tls)?;
+            let info_hash = *info_hash;
+            scrapes.push(async move {
+                let result = tracker.scrape(&[info_hash]).await;
+                (url, result.map(|mut it| it.remove(&info_hash)))
+            });
+        }
+        let scrapes = futures::future::join_all(scrapes).await;
+        Ok(scrapes
+            .into_iter()
+            .filter_map(|(url, result)| match result {
+                Ok(stats) => Some(TrackerScrape {
+                    url: url.to_string(),
+                    stats: stats?,
+                }),
+                Err(e) => {
+                    log::debug!("Failed to scrape {}: {}", url, e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    // Hash the files of the torrent again, e.g. the user touched them. The pieces found are
+    // seeded and the corrupt ones downloaded again. Returns the pieces we have.
+    pub async fn recheck_torrent(&self, info_hash: &Sha1Hash) -> Result<BitField> {
+        let torrent = self
+            .torrent(info_hash)
+            .ok_or(EngineError::NotFound(*info_hash))?;
+        // Nothing to check before the info dict of a magnet link is fetched
+        let Some(metainfo) = torrent.lock().await.metainfo().cloned() else {
+            return Ok(BitField::new());
+        };
+        let job = HashCheckJob {
+            metainfo,
+            save_path: self.disk_options(info_hash).save_path,
+        };
+        let (progress, _) = mpsc::unbounded_channel();
+        let options = *self.hash_check.lock().unwrap();
+        let have = hash_check::check_all(vec![job], options, progress)
+            .await
+            .pop()
+            .unwrap_or_default();
+        torrent.lock().await.apply_recheck(&have).await;
+        Ok(have)
+    }
+
+    // The totals of every added torrent, for the lifetime statistics. Unlike the WebUI API
+    // polls, the rates are left alone.
+    pub async fn transfer_snapshots(&self) -> Vec<(Sha1Hash, TransferSnapshot)> {
+        let added: Vec<_> = self
+            .torrents
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(info_hash, it)| (*info_hash, it.torrent.clone()))
+            .collect();
+        let mut snapshots = Vec::with_capacity(added.len());
+        for (info_hash, torrent) in added {
+            snapshots.push((info_hash, torrent.lock().await.transfer_snapshot().await));
+        }
+        snapshots
+    }
+
+    pub fn connection_count(&self, info_hash: &Sha1Hash) -> usize {
+        self.peer_manager
+            .lock()
+            .unwrap()
+            .torrent_connection_count(info_hash)
+    }
+
+    // Hand the torrent to the listener and the peer manager, and start announcing it.
+    async fn serve(
+        &self,
+        info_hash: &Sha1Hash,
+        torrent: Arc<tokio::sync::Mutex<Torrent>>,
+    ) -> Result<(AnnouncerHandle, broadcast::Receiver<AnnounceEvent>)> {
+        let info_hash = *info_hash;
+        let max_peers = torrent.lock().await.options().max_peers;
+        self.peer_manager
+            .lock()
+            .unwrap()
+            .add_torrent(info_hash, max_peers);
+        let (peers, mut discovered) = mpsc::unbounded_channel::<DialCandidate>();
+        let candidates = self.candidates.clone();
+        // Ends once the announcer and the sessions of the torrent are all gone
+        tokio::spawn(async move {
+            while let Some(candidate) = discovered.recv().await {
+                if candidates.send((info_hash, candidate)).is_err() {
+                    break;
+                }
+            }
+        });
+        self.registry
+            .add_shared_torrent(
+                torrent.clone(),
+                peers.clone(),
+                self.disk_options(&info_hash),
+            )
+            .await;
+        self.run_web_seeds(&info_hash, &torrent).await;
+        self.run_dht_announces(&info_hash, &torrent, peers.clone());
+        let announcer = match Announcer::new(
+            torrent,
+            self.registry.peer_id(),
+            self.port.clone(),
+            &self.identity,
+            peers,
+        )
+        .await
+        {
+            Ok(announcer) => announcer,
+            Err(e) => {
+                self.unserve(&info_hash);
+                return Err(e.into());
+            }
+        };
+        let grace = *self.stopped_grace.lock().unwrap();
+        let announcer = announcer.with_stopped_grace(grace);
+        let announcer = match &self.external_ip {
+            Some(external_ip) => announcer.with_external_ip(external_ip.clone()),
+            None => announcer,
+        };
+        // Before it runs, so the first announce isn't missed
+        let events = announcer.subscribe();
+        Ok((announcer.spawn(), events))
+    }
+
+    async fn on_disk_event(&self, info_hash: Sha1Hash, event: DiskEvent) {
+        let (piece_index, error) = match event {
+            DiskEvent::Error(piece_index, error) => (piece_index, error),
+            DiskEvent::FilesTrashed(paths) => {
+                let _ = self
+                    .events
+                    .send(EngineEvent::FilesTrashed { info_hash, paths });
+                return;
+            }
+            DiskEvent::PieceWritten(_) => return,
+        };
+        log::error!(
+            "Pause {} after writing piece {} failed: {:?}",
+            hex(&info_hash),
+            piece_index,
+            error
+        );
+        let Some(torrent) = self.torrent(&info_hash) else {
+            // Removed meanwhile
+            return;
+        };
+        torrent
+            .lock()
+            .await
+            .mark_piece_missing(piece_index as u32)
+            .await;
+        if self.pause_torrent(&info_hash).await.is_err() {
+            return;
+        }
+        let _ = self.events.send(EngineEvent::DiskFailed {
+            info_hash,
+            piece_index,
+            error: Arc::new(error),
+        });
+    }
+
+    // Link the complete files of the torrent to the identical files of the other torrents,
+    // if the disk options keep an index of them. It's only to save space, a failure is logged.
+    async fn dedupe(&self, metainfo: MetaInfo, save_path: PathBuf, have: BitField) {
+        let Some(dedupe) = self.disk_options.lock().unwrap().dedupe.clone() else {
+            return;
+        };
+        let info_hash = metainfo.info_hash;
+        let linked = tokio::task::spawn_blocking(move || {
+            dedupe
+                .lock()
+                .unwrap()
+                .register_torrent(&metainfo, &save_path, &have)
+        })
+        .await;
+        match linked {
+            Ok(Ok(0)) => {}
+            Ok(Ok(linked)) => log::info!("Linked {} files of {}", linked, hex(&info_hash)),
+            Ok(Err(e)) => log::warn!("Failed to dedupe {}: {:?}", hex(&info_hash), e),
+            Err(e) => log::warn!("Failed to dedupe {}: {:?}", hex(&info_hash), e),
+        }
+    }
+
+    // Where the files of the torrent are read and written.
+    fn disk_options(&self, info_hash: &Sha1Hash) -> DiskOptions {
+        let save_path = self
+            .torrents
+            .lock()
+            .unwrap()
+            .get(info_hash)
+            .and_then(|it| it.options.save_path.clone());
+        let mut disk_options = self.disk_options.lock().unwrap().clone();
+        if let Some(save_path) = save_path {
+            disk_options.save_path = save_path;
+        }
+        disk_options
+    }
+
+    // One download for each web seed, they take the blocks no peer is downloading. A magnet
+    // link has no web seeds until the info dict is fetched.
+    async fn run_web_seeds(
+        &self,
+        info_hash: &Sha1Hash,
+        torrent: &Arc<tokio::sync::Mutex<Torrent>>,
+    ) {
+        let web_seeds = match torrent.lock().await.metainfo() {
+            Some(metainfo) => WebSeed::from_metainfo(metainfo),
+            None => return,
+        };
+        let handles: Vec<_> = web_seeds
+            .into_iter()
+            .map(|it| tokio::spawn(it.with_identity(&self.identity).run(torrent.clone())))
+            .collect();
+        match self.torrents.lock().unwrap().get_mut(info_hash) {
+            Some(added) => added.web_seeds = handles,
+            // Removed meanwhile
+            None => handles.iter().for_each(|it| it.abort()),
+        }
+    }
+
+    // Announce the torrent to the DHT until it's unserved, the peers found go to `peers`. A
+    // private torrent is skipped, a magnet link is only known private once its info dict is
+    // fetched.
+    fn run_dht_announces(
+        &self,
+        info_hash: &Sha1Hash,
+        torrent: &Arc<tokio::sync::Mutex<Torrent>>,
+        peers: mpsc::UnboundedSender<DialCandidate>,
+    ) {
+        let Some(dht) = self.dht.clone() else {
+            return;
+        };
+        let info_hash = *info_hash;
+        let torrent = Arc::downgrade(torrent);
+        let port = self.port.clone();
+        let handle = tokio::spawn(async move {
+            let mut ticker = interval(DHT_ANNOUNCE_INTERVAL);
+            loop {
+                ticker.tick().await;
+                match torrent.upgrade() {
+                    Some(torrent) if !torrent.lock().await.is_private() => {}
+                    _ => return,
+                }
+                for addr in dht.announce(info_hash, Some(port.get())).await {
+                    if peers
+                        .send(DialCandidate::new(addr, PeerSource::Dht, None))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            }
+        });
+        match self.torrents.lock().unwrap().get_mut(&info_hash) {
+            Some(added) => added.dht_announces = Some(handle),
+            // Removed meanwhile
+            None => handle.abort(),
+        }
+    }
+
+    fn unserve(&self, info_hash: &Sha1Hash) {
+        self.registry.remove_torrent(info_hash);
+        self.peer_manager.lock().unwrap().remove_torrent(info_hash);
+        if let Some(added) = self.torrents.lock().unwrap().get_mut(info_hash) {
+            for web_seed in added.web_seeds.drain(..) {
+                web_seed.abort();
+            }
+            if let Some(dht_announces) = added.dht_announces.take() {
+                dht_announces.abort();
+            }
+        }
+    }
+
+    // Removed or paused meanwhile, the announcer is stopped right away.
+    fn set_announcer(&self, info_hash: &Sha1Hash, announcer: AnnouncerHandle) {
+        let mut torrents = self.torrents.lock().unwrap();
+        match torrents.get_mut(info_hash) {
+            Some(added) if !added.paused => added.announcer = Some(announcer),
+            _ => {
+                drop(torrents);
+                self.unserve(info_hash);
+                tokio::spawn(announcer.stop());
+            }
+        }
+    }
+
+    // Bytes per second since the previous poll, zero on the first.
+    fn poll_rates(&self, info_hash: &Sha1Hash, downloaded: u64, uploaded: u64) -> (u64, u64) {
+        let now = Instant::now();
+        let mut torrents = self.torrents.lock().unwrap();
+        let Some(added) = torrents.get_mut(info_hash) else {
+            return (0, 0);
+        };
+        let rates = match added.last_poll {
+            Some((at, last_downloaded, last_uploaded)) => {
+                let secs = now.duration_since(at).as_secs_f64();
+                if secs < 1.0 {
+                    // Polled again right away, e.g. the info and the maindata together
+                    return added.last_rates;
+                }
+                (
+                    (downloaded.saturating_sub(last_downloaded) as f64 / secs) as u64,
+                    (uploaded.saturating_sub(last_uploaded) as f64 / secs) as u64,
+                )
+            }
+            None => (0, 0),
+        };
+        added.last_poll = Some((now, downloaded, uploaded));
+        added.last_rates = rates;
+        rates
+    }
+
+    fn hashes(&self, hashes: Option<&[Sha1Hash]>) -> Vec<Sha1Hash> {
+        match hashes {
+            Some(hashes) => hashes.to_vec(),
+            None => self.torrents.lock().unwrap().keys().copied().collect(),
+        }
+    }
+}
+
+// The WebUI API serves the torrents of the engine, so Sonarr, Radarr etc. drive them.
+impl Backend for Engine {
+    async fn torrents(&self) -> Vec<TorrentInfo> {
+        let added: Vec<_> = self
+            .torrents
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(info_hash, it)| {
+                (
+                    *info_hash,
+                    it.torrent.clone(),
+                    it.paused,
+                    it.options.clone(),
+                    it.added_on,
+                )
+            })
+            .collect();
+        let mut infos = Vec::with_capacity(added.len());
+        for (info_hash, torrent, paused, options, added_on) in added {
+            let torrent = torrent.lock().await;
+            let snapshot = torrent.transfer_snapshot().await;
+            let phase = torrent.phase().await;
+            let (name, size) = match torrent.metainfo() {
+                Some(metainfo) => (metainfo.info.name.clone(), metainfo.total_bytes() as u64),
+                None => (hex(&info_hash), 0),
+            };
+            drop(torrent);
+            let (dlspeed, upspeed) =
+                self.poll_rates(&info_hash, snapshot.downloaded, snapshot.uploaded);
+            let state = match phase {
+                _ if paused && phase == TorrentPhase::Seeding => TorrentState::PausedUP,
+                _ if paused => TorrentState::PausedDL,
+                TorrentPhase::DownloadingMetadata => TorrentState::MetaDL,
+                TorrentPhase::Downloading if dlspeed > 0 => TorrentState::Downloading,
+                TorrentPhase::Downloading | TorrentPhase::Dead => TorrentState::StalledDL,
+                TorrentPhase::Seeding if upspeed > 0 => TorrentState::Uploading,
+                TorrentPhase::Seeding => TorrentState::StalledUP,
+            };
+            let save_path = options
+                .save_path
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            infos.push(TorrentInfo {
+                hash: info_hash,
+                content_path: PathBuf::from(&save_path)
+                    .join(&name)
+                    .to_string_lossy()
+                    .into_owned(),
+                name,
+                size,
+                progress: if size == 0 {
+                    0.0
+                } else {
+                    size.saturating_sub(snapshot.left) as f64 / size as f64
+                },
+                dlspeed,
+                upspeed,
+                state,
+                category: options.category.unwrap_or_default(),
+                save_path,
+                added_on: added_on
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |it| it.as_secs() as i64),
+                eta: match snapshot.left.checked_div(dlspeed) {
+                    Some(eta) if !paused => eta as i64,
+                    _ => INFINITE_ETA,
+                },
+                ratio: if snapshot.downloaded == 0 {
+                    0.0
+                } else {
+                    snapshot.uploaded as f64 / snapshot.downloaded as f64
+                },
+                amount_left: snapshot.left,
+                downloaded: snapshot.downloaded,
+                uploaded: snapshot.uploaded,
+            });
+        }
+        infos
+    }
+
+    async fn server_state(&self) -> ServerState {
+        let torrents = self.torrents().await;
+        ServerState {
+            dl_info_speed: torrents.iter().map(|it| it.dlspeed).sum(),
+            up_info_speed: torrents.iter().map(|it| it.upspeed).sum(),
+            dl_info_data: torrents.iter().map(|it| it.downloaded).sum(),
+            up_info_data: torrents.iter().map(|it| it.uploaded).sum(),
+        }
+    }
+
+    async fn add(
+        &self,
+        source: AddSource,
+        options: &AddOptions,
+    ) -> std::result::Result<(), AddError> {
+        let torrent = match source {
+            AddSource::File(bytes) => Torrent::from_metainfo(MetaInfo::from_bytes(&bytes)?),
+            AddSource::Url(url) if url.starts_with("magnet:") => {
+                Torrent::from_magnet(MagnetLink::parse(&url)?)
+            }
+            AddSource::Url(url) => {
+                let bytes = self
+                    .identity
+                    .http_client(None)?
+                    .get(url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .bytes()
+                    .await?;
+                Torrent::from_metainfo(MetaInfo::from_bytes(&bytes)?)
+            }
+        };
+        self.add_torrent_with(torrent, options.clone()).await?;
+        Ok(())
+    }
+
+    async fn pause(&self, hashes: Option<&[Sha1Hash]>) {
+        for info_hash in self.hashes(hashes) {
+            if let Err(e) = self.pause_torrent(&info_hash).await {
+                log::debug!("Failed to pause torrent {}: {}", hex(&info_hash), e);
+            }
+        }
+    }
+
+    async fn resume(&self, hashes: Option<&[Sha1Hash]>) {
+        for info_hash in self.hashes(hashes) {
+            if let Err(e) = self.resume_torrent(&info_hash).await {
+                log::warn!("Failed to resume torrent {}: {}", hex(&info_hash), e);
+            }
+        }
+    }
+}
+
+fn hex(hash: &Sha1Hash) -> String {
+    hash.iter().map(|it| format!("{:02x}", it)).collect()
+}
+
+// Hand the peers to the peer manager and dial what it picks, it hears back how each went.
+async fn drive_peers(
+    registry: TorrentRegistry,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    mut candidates: mpsc::UnboundedReceiver<(Sha1Hash, DialCandidate)>,
+) {
+    let (events_sender, mut events) = mpsc::unbounded_channel();
+    let mut ticker = interval(DIAL_INTERVAL);
+    loop {
+        tokio::select! {
+            candidate = candidates.recv() => {
+                let Some((info_hash, candidate)) = candidate else {
+                    return;
+                };
+                peer_manager
+                    .lock()
+                    .unwrap()
+                    .add_candidate(&info_hash, candidate);
+            }
+            Some(event) = events.recv() => {
+                let mut peer_manager = peer_manager.lock().unwrap();
+                match event {
+                    DialEvent::Connected { info_hash, addr } => {
+                        peer_manager.on_connected(&info_hash, addr);
+                    }
+                    DialEvent::Failed {
+                        info_hash,
+                        candidate,
+                    } => peer_manager.on_dial_failed(&info_hash, candidate),
+                    DialEvent::Disconnected {
+                        info_hash,
+                        addr,
+                        reason,
+                    } => peer_manager.on_disconnected(&info_hash, &addr, reason),
+                }
+            }
+            _ = ticker.tick() => {}
+        }
+        let dials = peer_manager.lock().unwrap().next_dials();
+        for (info_hash, candidate) in dials {
+            let addr = candidate.addr;
+            if !registry.dial(&info_hash, candidate, events_sender.clone()) {
+                // Removed meanwhile
+                peer_manager.lock().unwrap().on_disconnected(
+                    &info_hash,
+                    &addr,
+                    DisconnectReason::Closed,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use mockito::Matcher;
+    use tokio::{net::TcpListener, time::timeout};
+    use url::Url;
+
+    use super::*;
+    use crate::{
+        dedupe::DedupeIndex,
+        disk::WriteRetryPolicy,
+        metainfo::TestMetaInfo,
+        peer_list,
+        torrent::{TorrentOptions, TorrentPriority},
+        tracker::ScrapeStats,
+    };
+
+    #[tokio::test]
+    async fn test_add_torrent() {
+        // The peer the tracker hands out, the engine dials it
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let mut body = b"d8:intervali1800e5:peers6:\x7f\x00\x00\x01".to_vec();
+        body.extend_from_slice(&peer_addr.port().to_be_bytes());
+        body.push(b'e');
+        let mut server = mockito::Server::new_async().await;
+        let started = server
+            .mock("GET", "/announce")
+            .match_query(Matcher::UrlEncoded("event".into(), "started".into()))
+            .with_body(body)
+            .expect(1)
+            .create_async()
+            .await;
+        let stopped = server
+            .mock("GET", "/announce")
+            .match_query(Matcher::UrlEncoded("event".into(), "stopped".into()))
+            .with_body(b"d8:intervali1800e5:peers0:e")
+            .expect(1)
+            .create_async()
+            .await;
+
+        let listener = PeerListener::bind("127.0.0.1:0", [2; 20]).await.unwrap();
+        let engine = Engine::start(listener, ClientIdentity::default());
+        let magnet = MagnetLink {
+            info_hash: [1; 20],
+            display_name: None,
+            trackers: vec![Url::parse(&format!("{}/announce", server.url())).unwrap()],
+            select_only: None,
+            peers: Vec::new(),
+        };
+        engine
+            .add_torrent(Torrent::from_magnet(magnet.clone()))
+            .await
+            .unwrap();
+        assert!(engine.registry().contains(&[1; 20]));
+        assert!(matches!(
+            engine.add_torrent(Torrent::from_magnet(magnet)).await,
+            Err(EngineError::AlreadyAdded)
+        ));
+
+        timeout(Duration::from_secs(5), peer.accept())
+            .await
+            .expect("Engine didn't dial the peer from the tracker")
+            .unwrap();
+        started.assert_async().await;
+        assert_eq!(engine.connection_count(&[1; 20]), 1);
+
+        assert!(engine.remove_torrent(&[1; 20]).await);
+        stopped.assert_async().await;
+        assert!(!engine.registry().contains(&[1; 20]));
+        assert!(engine.torrent(&[1; 20]).is_none());
+        assert_eq!(engine.connection_count(&[1; 20]), 0);
+        assert!(!engine.remove_torrent(&[1; 20]).await);
+    }
+
+    #[tokio::test]
+    async fn test_dht_peers() {
+        // The peer some other node announced to the DHT, the engine dials it
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let router = Dht::bind(localhost).await.unwrap();
+        let router_addr = router.local_addr().unwrap().to_string();
+        let seeder = Dht::bind(localhost).await.unwrap();
+        seeder.bootstrap(&[&router_addr]).await;
+        seeder
+            .announce([1; 20], Some(peer.local_addr().unwrap().port()))
+            .await;
+
+        let dht = Arc::new(Dht::bind(localhost).await.unwrap());
+        dht.bootstrap(&[&router_addr]).await;
+        let listener = PeerListener::bind("127.0.0.1:0", [2; 20])
+            .await
+            .unwrap()
+            .with_dht(dht);
+        let engine = Engine::start(listener, ClientIdentity::default());
+        let magnet = MagnetLink {
+            info_hash: [1; 20],
+            display_name: None,
+            trackers: Vec::new(),
+            select_only: None,
+            peers: Vec::new(),
+        };
+        engine
+            .add_torrent(Torrent::from_magnet(magnet))
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(5), peer.accept())
+            .await
+            .expect("Engine didn't dial the peer from the DHT")
+            .unwrap();
+        assert!(engine.remove_torrent(&[1; 20]).await);
+    }
+
+    #[tokio::test]
+    async fn test_add_peers() {
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let candidates = peer_list::parse(&peer.local_addr().unwrap().to_string()).unwrap();
+        let listener = PeerListener::bind("127.0.0.1:0", [2; 20]).await.unwrap();
+        let engine = Engine::start(listener, ClientIdentity::default());
+        assert!(matches!(
+            engine.add_peers(&[1; 20], candidates.clone()),
+            Err(EngineError::NotFound(_))
+        ));
+        let magnet = MagnetLink {
+            info_hash: [1; 20],
+            display_name: None,
+            trackers: Vec::new(),
+            select_only: None,
+            peers: Vec::new(),
+        };
+        engine
+            .add_torrent(Torrent::from_magnet(magnet))
+            .await
+            .unwrap();
+        engine.add_peers(&[1; 20], candidates).unwrap();
+        timeout(Duration::from_secs(5), peer.accept())
+            .await
+            .expect("Engine didn't dial the imported peer")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_start_torrents_by_priority() {
+        let announced = Arc::new(Mutex::new(Vec::new()));
+        let mut server = mockito::Server::new_async().await;
+        for name in ["high", "low"] {
+            let announced = announced.clone();
+            server
+                .mock("GET", format!("/{}/announce", name).as_str())
+                .match_query(Matcher::UrlEncoded("event".into(), "started".into()))
+                .with_body_from_request(move |_| {
+                    announced.lock().unwrap().push(name);
+                    b"d8:intervali1800e5:peers0:e".to_vec()
+                })
+                .create_async()
+                .await;
+        }
+        let torrent = |id: u8, name: &str, priority: TorrentPriority| {
+            let magnet = MagnetLink {
+                info_hash: [id; 20],
+                display_name: None,
+                trackers: vec![Url::parse(&format!("{}/{}/announce", server.url(), name)).unwrap()],
+                select_only: None,
+                peers: Vec::new(),
+            };
+            async move {
+                let mut torrent = Torrent::from_magnet(magnet);
+                let options = TorrentOptions {
+                    priority,
+                    ..torrent.options().clone()
+                };
+                torrent.set_options(options).await;
+                torrent
+            }
+        };
+        let torrents = vec![
+            torrent(1, "low", TorrentPriority::Low).await,
+            torrent(2, "high", TorrentPriority::High).await,
+        ];
+
+        let listener = PeerListener::bind("127.0.0.1:0", [2; 20]).await.unwrap();
+        let engine = Arc::new(Engine::start(listener, ClientIdentity::default()));
+        let options = StartupOptions {
+            max_concurrent: 1,
+            tier_timeout: Duration::from_secs(5),
+        };
+        engine.start_torrents(torrents, options).await;
+        assert_eq!(*announced.lock().unwrap(), vec!["high", "low"]);
+        assert!(engine.torrent(&[1; 20]).is_some());
+        assert!(engine.torrent(&[2; 20]).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_scrape() {
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&[1; 20]);
+        body.extend_from_slice(b"d8:completei5e10:downloadedi3e10:incompletei2eeee");
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/scrape")
+            .match_query(Matcher::Any)
+            .with_body(body)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/announce")
+            .match_query(Matcher::Any)
+            .with_body(b"d8:intervali1800e5:peers0:e")
+            .create_async()
+            .await;
+
+        let listener = PeerListener::bind("127.0.0.1:0", [2; 20]).await.unwrap();
+        let engine = Engine::start(listener, ClientIdentity::default());
+        assert!(matches!(
+            engine.scrape(&[1; 20]).await,
+            Err(EngineError::NotFound(_))
+        ));
+        let magnet = MagnetLink {
+            info_hash: [1; 20],
+            display_name: None,
+            trackers: vec![
+                Url::parse(&format!("{}/announce", server.url())).unwrap(),
+                // No scrape URL by the convention
+                Url::parse(&format!("{}/tracker", server.url())).unwrap(),
+            ],
+            select_only: None,
+            peers: Vec::new(),
+        };
+        engine
+            .add_torrent(Torrent::from_magnet(magnet))
+            .await
+            .unwrap();
+        assert_eq!(
+            engine.scrape(&[1; 20]).await.unwrap(),
+            vec![TrackerScrape {
+                url: format!("{}/announce", server.url()),
+                stats: ScrapeStats {
+                    seeders: 5,
+                    leechers: 2,
+                    completed: 3,
+                },
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_with_existing_data() {
+        let root = std::env::temp_dir().join("bitdrift_test_engine_existing_data");
+        let _ = std::fs::remove_dir_all(&root);
+        let source_dir = root.join("source");
+        std::fs::create_dir_all(&source_dir).unwrap();
+        std::fs::write(source_dir.join("a.bin"), b"abcd").unwrap();
+        let metainfo = TestMetaInfo::new("data", 4)
+            .file("data/a.bin", b"abcd")
+            .file("data/b.bin", b"efgh")
+            .build();
+        let info_hash = metainfo.info_hash;
+
+        let listener = PeerListener::bind("127.0.0.1:0", [2; 20]).await.unwrap();
+        let engine = Engine::start(listener, ClientIdentity::default());
+        let save_path = root.join("save");
+        let options = AddOptions {
+            save_path: Some(save_path.clone()),
+            ..Default::default()
+        };
+        let torrent = engine
+            .add_with_existing_data(metainfo, source_dir, PlaceMode::Copy, options)
+            .await
+            .unwrap();
+        assert!(torrent.lock().await.has_piece(0).await);
+        assert!(!torrent.lock().await.has_piece(1).await);
+        assert!(engine.registry().contains(&info_hash));
+
+        // The first file got corrupt, the second one showed up
+        std::fs::write(save_path.join("data/a.bin"), b"xxxx").unwrap();
+        std::fs::write(save_path.join("data/b.bin"), b"efgh").unwrap();
+        let have = engine.recheck_torrent(&info_hash).await.unwrap();
+        assert_eq!(have.iter_ones().collect::<Vec<_>>(), vec![1]);
+        assert!(!torrent.lock().await.has_piece(0).await);
+        assert!(torrent.lock().await.has_piece(1).await);
+        assert!(matches!(
+            engine.recheck_torrent(&[9; 20]).await,
+            Err(EngineError::NotFound(_))
+        ));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_cross_seed() {
+        let root = std::env::temp_dir().join("bitdrift_test_engine_cross_seed");
+        let _ = std::fs::remove_dir_all(&root);
+        let data_dir = root.join("data");
+        std::fs::create_dir_all(data_dir.join("show")).unwrap();
+        std::fs::write(data_dir.join("show/e01.mkv"), b"abcd").unwrap();
+        let torrents_dir = root.join("torrents");
+        std::fs::create_dir_all(&torrents_dir).unwrap();
+        let metainfo = |name: &str, data: &[u8]| {
+            TestMetaInfo::new(name, 4)
+                .file(&format!("{}/e01.mkv", name), data)
+                .build()
+        };
+        let write = |file: &str, metainfo: &MetaInfo| {
+            let mut bytes = b"d4:info".to_vec();
+            bytes.extend(metainfo.info_bytes().unwrap());
+            bytes.push(b'e');
+            std::fs::write(torrents_dir.join(file), bytes).unwrap();
+        };
+        let same = metainfo("Show", b"abcd");
+        write("same.torrent", &same);
+        let info_hash = crate::hash::calculate_sha1_hash(same.info_bytes().unwrap());
+        write("different.torrent", &metainfo("Show", b"xxxx"));
+
+        let listener = PeerListener::bind("127.0.0.1:0", [2; 20]).await.unwrap();
+        let dedupe = Arc::new(Mutex::new(DedupeIndex::new()));
+        let engine =
+            Engine::start(listener, ClientIdentity::default()).with_disk_options(DiskOptions {
+                dedupe: Some(dedupe.clone()),
+                ..Default::default()
+            });
+        let options = AddOptions {
+            save_path: Some(root.join("save")),
+            ..Default::default()
+        };
+        let added = engine
+            .cross_seed(torrents_dir.clone(), data_dir.clone(), options.clone())
+            .await
+            .unwrap();
+        assert_eq!(added, vec![(torrents_dir.join("same.torrent"), info_hash)]);
+        let torrent = engine.torrent(&info_hash).unwrap();
+        assert_eq!(torrent.lock().await.phase().await, TorrentPhase::Seeding);
+        // Seeded already
+        let added = engine
+            .cross_seed(torrents_dir, data_dir, options)
+            .await
+            .unwrap();
+        assert!(added.is_empty());
+
+        // Indexed, so the next torrent of the same bytes links to it
+        let linked = root.join("save/Show/e01.mkv");
+        assert_eq!(dedupe.lock().unwrap().references(&linked), 1);
+        engine.remove_torrent(&info_hash).await;
+        assert_eq!(dedupe.lock().unwrap().references(&linked), 0);
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_web_seeds() {
+        let mut server = mockito::Server::new_async().await;
+        let web_seed = server
+            .mock("GET", "/data.bin")
+            .match_header("range", "bytes=0-3")
+            .with_status(206)
+            .with_body("abcd")
+            .create_async()
+            .await;
+        let mut metainfo = TestMetaInfo::new("data.bin", 4).single(b"abcd").build();
+        metainfo.web_seeds = vec![Url::parse(&format!("{}/data.bin", server.url())).unwrap()];
+
+        let save_path = std::env::temp_dir().join("bitdrift_test_engine_web_seeds");
+        let _ = std::fs::remove_dir_all(&save_path);
+
+        let listener = PeerListener::bind("127.0.0.1:0", [2; 20]).await.unwrap();
+        let engine = Engine::start(listener, ClientIdentity::default());
+        let torrent = Torrent::from_metainfo(metainfo);
+        let mut verified = torrent.subscribe_verified();
+        let options = AddOptions {
+            save_path: Some(save_path.clone()),
+            ..Default::default()
+        };
+        engine.add_torrent_with(torrent, options).await.unwrap();
+        // Told to the sessions once it's on the disk
+        let written = timeout(Duration::from_secs(5), verified.recv()).await;
+        assert_eq!(written.unwrap().unwrap(), 0);
+        assert_eq!(std::fs::read(save_path.join("data.bin")).unwrap(), b"abcd");
+        web_seed.assert_async().await;
+
+        let _ = std::fs::remove_dir_all(&save_path);
+    }
+
+    #[tokio::test]
+    async fn test_disk_failure_pauses_torrent() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/data.bin")
+            .with_status(206)
+            .with_body("abcd")
+            .create_async()
+            .await;
+        let mut metainfo = TestMetaInfo::new("data.bin", 4).single(b"abcd").build();
+        metainfo.web_seeds = vec![Url::parse(&format!("{}/data.bin", server.url())).unwrap()];
+        let info_hash = metainfo.info_hash;
+        // The save path is a file, nothing can be written under it
+        let save_path = std::env::temp_dir().join("bitdrift_test_engine_disk_failure");
+        std::fs::write(&save_path, b"").unwrap();
+
+        let listener = PeerListener::bind("127.0.0.1:0", [2; 20]).await.unwrap();
+        let engine = Arc::new(
+            Engine::start(listener, ClientIdentity::default()).with_disk_options(DiskOptions {
+                write_retry: WriteRetryPolicy {
+                    retries: 0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+        );
+        engine.watch_disks();
+        let mut events = engine.subscribe();
+        let options = AddOptions {
+            save_path: Some(save_path.clone()),
+            ..Default::default()
+        };
+        engine
+            .add_torrent_with(Torrent::from_metainfo(metainfo), options)
+            .await
+            .unwrap();
+        let event = timeout(Duration::from_secs(5), events.recv()).await;
+        let EngineEvent::DiskFailed {
+            info_hash: failed,
+            piece_index,
+            ..
+        } = event.unwrap().unwrap()
+        else {
+            panic!("expected the disk failure");
+        };
+        assert_eq!((failed, piece_index), (info_hash, 0));
+        // Paused, the piece is downloaded again once the user resumes it
+        assert!(!engine.registry().contains(&info_hash));
+        assert_eq!(engine.torrents().await[0].state, TorrentState::PausedDL);
+
+        let _ = std::fs::remove_file(&save_path);
+    }
+
+    #[tokio::test]
+    async fn test_apply_profile() {
+        let listener = PeerListener::bind("127.0.0.1:0", [2; 20]).await.unwrap();
+        let engine = Engine::start(listener, ClientIdentity::default());
+        let previous = Profile::new("Home");
+        let mut profile = previous.clone();
+        profile.write_retries = 7;
+        profile.disk_io_workers = Some(3);
+        profile.stopped_announce_grace_secs = 1;
+        engine.apply_profile(&profile, &previous).await;
+        let disk_options = engine.disk_options.lock().unwrap().clone();
+        assert_eq!(disk_options.write_retry.retries, 7);
+        assert_eq!(disk_options.io_workers, Some(3));
+        assert_eq!(
+            *engine.stopped_grace.lock().unwrap(),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remove_torrent_with_data() {
+        let metainfo = TestMetaInfo::new("data.bin", 4).single(b"abcd").build();
+        let info_hash = metainfo.info_hash;
+        let save_path = std::env::temp_dir().join("bitdrift_test_engine_remove_with_data");
+        std::fs::create_dir_all(&save_path).unwrap();
+        std::fs::write(save_path.join("data.bin"), b"abcd").unwrap();
+
+        let listener = PeerListener::bind("127.0.0.1:0", [2; 20]).await.unwrap();
+        let engine = Engine::start(listener, ClientIdentity::default());
+        let options = AddOptions {
+            save_path: Some(save_path.clone()),
+            ..Default::default()
+        };
+        engine
+            .add_torrent_with(Torrent::from_metainfo(metainfo), options)
+            .await
+            .unwrap();
+        let paths = engine
+            .remove_torrent_with_data(&info_hash, DeleteMode::Permanent)
+            .await
+            .unwrap();
+        assert_eq!(paths, vec![save_path.join("data.bin")]);
+        assert!(!save_path.join("data.bin").exists());
+        assert!(engine.torrent(&info_hash).is_none());
+        assert!(!engine.registry().contains(&info_hash));
+        assert!(matches!(
+            engine
+                .remove_torrent_with_data(&info_hash, DeleteMode::Permanent)
+                .await,
+            Err(EngineError::NotFound(_))
+        ));
+
+        let _ = std::fs::remove_dir_all(&save_path);
+    }
+
+    #[tokio::test]
+    async fn test_web_api_backend() {
+        let listener = PeerListener::bind("127.0.0.1:0", [2; 20]).await.unwrap();
+        let engine = Engine::start(listener, ClientIdentity::default());
+        let options = AddOptions {
+            save_path: Some(PathBuf::from("/downloads")),
+            category: Some("tv".to_string()),
+            paused: true,
+        };
+        let magnet = AddSource::Url(format!("magnet:?xt=urn:btih:{}", hex(&[1; 20])));
+        engine.add(magnet.clone(), &options).await.unwrap();
+        assert!(matches!(
+            engine.add(magnet, &options).await,
+            Err(AddError::Engine(EngineError::AlreadyAdded))
+        ));
+        assert!(matches!(
+            engine
+                .add(AddSource::File(b"not bencode".to_vec()), &options)
+                .await,
+            Err(AddError::MetaInfo(_))
+        ));
+
+        // Kept without serving it until resumed
+        assert!(!engine.registry().contains(&[1; 20]));
+        let torrents = engine.torrents().await;
+        assert_eq!(torrents.len(), 1);
+        assert_eq!(torrents[0].hash, [1; 20]);
+        assert_eq!(torrents[0].state, TorrentState::PausedDL);
+        assert_eq!(torrents[0].category, "tv");
+        assert_eq!(torrents[0].save_path, "/downloads");
+
+        engine.resume(None).await;
+        assert!(engine.registry().contains(&[1; 20]));
+        assert_eq!(engine.torrents().await[0].state, TorrentState::MetaDL);
+
+        engine.pause(Some(&[[1; 20]])).await;
+        assert!(!engine.registry().contains(&[1; 20]));
+        assert_eq!(engine.torrents().await[0].state, TorrentState::PausedDL);
+        assert_eq!(engine.tracker_status(&[1; 20]), Some(Vec::new()));
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    disk::{Disk, DiskOptions},
+    metainfo::MetaInfo,
+    torrent::Torrent,
+    types::BitField,
+};
+
+// Add a torrent on top of data the user already has somewhere else, e.g. to cross-seed
+// a download from another client. The files are matched by size and name, then placed
+// in the save path and rechecked, so only the pieces which don't match are downloaded.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PlaceMode {
+    // Hard link the files, falls back to copy if the save path is on another filesystem.
+    #[default]
+    Link,
+    Copy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMatch {
+    pub file_index: usize,
+    // The existing file the torrent file is taken from.
+    pub source: PathBuf,
+    // Where the disk expects the torrent file.
+    pub target: PathBuf,
+}
+
+pub struct ExistingData {
+    pub torrent: Torrent,
+    pub matches: Vec<FileMatch>,
+    // The pieces which passed the recheck.
+    pub have: BitField,
+}
+
+// Match, place and recheck the existing data in `source_dir` for the torrent to be saved in `save_path`.
+pub async fn add_with_existing_data(
+    metainfo: MetaInfo,
+    source_dir: PathBuf,
+    save_path: PathBuf,
+    mode: PlaceMode,
+) -> io::Result<ExistingData> {
+    let matches = {
+        let metainfo = metainfo.clone();
+        let save_path = save_path.clone();
+        tokio::task::spawn_blocking(move || {
+            let matches = match_files(&metainfo, &source_dir, &save_path)?;
+            place_files(&matches, mode)?;
+            Ok::<_, io::Error>(matches)
+        })
+        .await??
+    };
+    let (disk, _events) = Disk::new(DiskOptions {
+        save_path,
+        ..Default::default()
+    });
+    let have = disk.bitfield(metainfo.clone()).await;
+    log::info!(
+        "Matched {} files, {} of {} pieces are complete",
+        matches.len(),
+        have.count_ones(),
+        have.len()
+    );
+    Ok(ExistingData {
+        torrent: Torrent::from_existing_pieces(metainfo, have.clone()),
+        matches,
+        have,
+    })
+}
+
+// Find the existing file of each torrent file. A file must have the same size, and the candidate
+// sharing the most trailing path components wins, so a root folder which the user renamed still
+// matches through the rest of the path.
+pub fn match_files(
+    metainfo: &MetaInfo,
+    source_dir: &Path,
+    save_path: &Path,
+) -> io::Result<Vec<FileMatch>> {
+    let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    for (path, length) in walk(source_dir)? {
+        by_size.entry(length).or_default().push(path);
+    }
+
+    let mut used = HashSet::new();
+    let mut matches = Vec::new();
+    for (file_index, file) in metainfo.files().iter().enumerate() {
+        if file.length == 0 {
+            continue;
+        }
+        let Some(candidates) = by_size.get(&file.length) else {
+            continue;
+        };
+        let best = candidates
+            .iter()
+            .filter(|it| !used.contains(*it))
+            .map(|it| (common_suffix_length(&file.path, it), it))
+            .filter(|(score, _)| *score > 0)
+            .max_by_key(|(score, _)| *score);
+        if let Some((_, source)) = best {
+            used.insert(source.clone());
+            matches.push(FileMatch {
+                file_index,
+                source: source.clone(),
+                target: save_path.join(file.path.join("/")),
+            });
+        }
+    }
+    Ok(matches)
+}
+
+// Put the matched files where the disk expects them, the files already in place are kept.
+pub fn place_files(matches: &[FileMatch], mode: PlaceMode) -> io::Result<()> {
+    for file_match in matches {
+        if file_match.target.exists() {
+            continue;
+        }
+        if let Some(parent) = file_match.target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let linked = mode == PlaceMode::Link
+            && fs::hard_link(&file_match.source, &file_match.target).is_ok();
+        if !linked {
+            fs::copy(&file_match.source, &file_match.target)?;
+        }
+    }
+    Ok(())
+}
+
+// All the files under the directory with their sizes, sorted so the matching is deterministic.
+fn walk(dir: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_file() {
+                files.push((entry.path(), entry.metadata()?.len()));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// How many of the last path components are the same, ignoring the case.
+fn common_suffix_length(torrent_path: &[String], path: &Path) -> usize {
+    let components: Vec<String> = path
+        .components()
+        .map(|it| it.as_os_str().to_string_lossy().to_lowercase())
+        .collect();
+    torrent_path
+        .iter()
+        .rev()
+        .zip(components.iter().rev())
+        .take_while(|(a, b)| a.to_lowercase() == **b)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::TestMetaInfo;
+
+    #[tokio::test]
+    async fn test_add_with_existing_data() {
+        let root = std::env::temp_dir().join("bitdrift_test_existing_data");
+        let _ = fs::remove_dir_all(&root);
+        // The user renamed the root folder and lost one file
+        let source_dir = root.join("source");
+        fs::create_dir_all(source_dir.join("Album (2020)/cd1")).unwrap();
+        fs::write(source_dir.join("Album (2020)/cd1/01.flac"), b"abcd").unwrap();
+        fs::write(source_dir.join("Album (2020)/cover.jpg"), b"efgh").unwrap();
+        fs::write(source_dir.join("unrelated.txt"), b"ijkl").unwrap();
+
+        let metainfo = TestMetaInfo::new("album", 4)
+            .file("album/cd1/01.flac", b"abcd")
+            .file("album/cover.jpg", b"efgh")
+            .file("album/cd1/02.flac", b"mnop")
+            .build();
+        let save_path = root.join("save");
+        let existing = add_with_existing_data(
+            metainfo,
+            source_dir.clone(),
+            save_path.clone(),
+            PlaceMode::Link,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(existing.matches.len(), 2);
+        assert_eq!(
+            existing.matches[1].source,
+            source_dir.join("Album (2020)/cover.jpg")
+        );
+        assert_eq!(
+            fs::read(save_path.join("album/cd1/01.flac")).unwrap(),
+            b"abcd"
+        );
+        assert_eq!(existing.have.iter_ones().collect::<Vec<_>>(), vec![0, 1]);
+        assert!(existing.torrent.has_piece(1).await);
+        assert!(!existing.torrent.has_piece(2).await);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compact::{decode_ip, encode_ip},
+    metadata::METADATA_PIECE_SIZE,
+};
+
+// Implementation of the extension protocol
+// https://www.bittorrent.org/beps/bep_0010.html
+
+// The extended message id 0 is always the extension handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+
+// The ids we ask the peers to use when they send the extension messages to us.
+pub const UT_METADATA_ID: u8 = 1;
+pub const UT_PEX_ID: u8 = 2;
+pub const UT_HOLEPUNCH_ID: u8 = 3;
+
+pub const UT_METADATA: &str = "ut_metadata";
+pub const UT_PEX: &str = "ut_pex";
+pub const UT_HOLEPUNCH: &str = "ut_holepunch";
+
+// The largest extended message we accept is a metadata piece with its header,
+// anything bigger is dropped by the codec before it's buffered.
+pub const MAX_PAYLOAD_LENGTH: usize = METADATA_PIECE_SIZE + 1024;
+
+// The metadata header is a small dict, e.g. d8:msg_typei1e5:piecei0e10:total_sizei31235ee
+const MAX_METADATA_HEADER_LENGTH: usize = 128;
+const MAX_HANDSHAKE_LENGTH: usize = 8 * 1024;
+// 50 added and 50 dropped IPv6 peers with the flags are about 2KiB.
+const MAX_PEX_LENGTH: usize = 4 * 1024;
+// The holepunch message with an IPv6 address.
+const MAX_HOLEPUNCH_LENGTH: usize = 24;
+
+// Extended messages are control messages, a peer sending more than this is misbehaving.
+const MAX_MESSAGES_PER_SECOND: u32 = 20;
+const MAX_BYTES_PER_SECOND: usize = 4 * METADATA_PIECE_SIZE;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    // Map the extension name to the message id which the sender wants to receive,
+    // id 0 means the extension is disabled.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    // The size of the info dict in bytes, only sent if the peer has it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<u64>,
+    // Client name and version
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    // 1 if the sender is a partial seed, it won't download anything.
+    // https://www.bittorrent.org/beps/bep_0021.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_only: Option<u8>,
+    // The address of the receiver as the sender sees it, see `ExternalIp`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<serde_bytes::ByteBuf>,
+}
+
+impl ExtendedHandshake {
+    // Our handshake, announcing the extensions we support.
+    pub fn new(metadata_size: Option<u64>) -> Self {
+        let mut m = BTreeMap::new();
+        m.insert(UT_METADATA.to_string(), UT_METADATA_ID);
+        m.insert(UT_PEX.to_string(), UT_PEX_ID);
+        m.insert(UT_HOLEPUNCH.to_string(), UT_HOLEPUNCH_ID);
+        Self {
+            m,
+            metadata_size,
+            v: None,
+            upload_only: None,
+            yourip: None,
+        }
+    }
+
+    // Tell the peer which address it connected from.
+    pub fn with_your_ip(mut self, ip: IpAddr) -> Self {
+        self.yourip = Some(serde_bytes::ByteBuf::from(encode_ip(ip)));
+        self
+    }
+
+    pub fn your_ip(&self) -> Option<IpAddr> {
+        decode_ip(self.yourip.as_ref()?)
+    }
+
+    pub fn with_upload_only(mut self) -> Self {
+        self.upload_only = Some(1);
+        self
+    }
+
+    pub fn is_upload_only(&self) -> bool {
+        self.upload_only.is_some_and(|it| it != 0)
+    }
+
+    // Private torrents must not exchange peers.
+    pub fn without_pex(mut self) -> Self {
+        self.m.remove(UT_PEX);
+        self
+    }
+
+    // The id we should use when sending the extension message to the peer.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|id| *id != 0)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, serde_bencode::Error> {
+        serde_bencode::to_bytes(self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_bencode::Error> {
+        serde_bencode::from_bytes(bytes)
+    }
+}
+
+// Per peer limits of the extended messages, so a peer can't flood us with them.
+pub struct ExtensionLimiter {
+    window_start: Instant,
+    messages: u32,
+    bytes: usize,
+}
+
+impl ExtensionLimiter {
+    pub fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            messages: 0,
+            bytes: 0,
+        }
+    }
+
+    // Returns false if the message should be dropped.
+    pub fn allow(&mut self, id: u8, length: usize) -> bool {
+        let max_length = match id {
+            HANDSHAKE_ID => MAX_HANDSHAKE_LENGTH,
+            UT_METADATA_ID => METADATA_PIECE_SIZE + MAX_METADATA_HEADER_LENGTH,
+            UT_PEX_ID => MAX_PEX_LENGTH,
+            UT_HOLEPUNCH_ID => MAX_HOLEPUNCH_LENGTH,
+            _ => MAX_PAYLOAD_LENGTH,
+        };
+        if length > max_length {
+            return false;
+        }
+
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            self.window_start = Instant::now();
+            self.messages = 0;
+            self.bytes = 0;
+        }
+        if self.messages >= MAX_MESSAGES_PER_SECOND || self.bytes + length > MAX_BYTES_PER_SECOND {
+            return false;
+        }
+        self.messages += 1;
+        self.bytes += length;
+        true
+    }
+}
+
+// Return the length of the first bencoded value in the bytes,
+// some extension messages append raw data after a bencoded dict.
+pub fn bencode_value_length(bytes: &[u8]) -> Option<usize> {
+    match bytes.first()? {
+        b'i' => Some(bytes.iter().position(|it| *it == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut length = 1;
+            while *bytes.get(length)? != b'e' {
+                length += bencode_value_length(&bytes[length..])?;
+            }
+            Some(length + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = bytes.iter().position(|it| *it == b':')?;
+            let string_length: usize = std::str::from_utf8(&bytes[..colon]).ok()?.parse().ok()?;
+            let length = colon + 1 + string_length;
+            (length <= bytes.len()).then_some(length)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extended_handshake_roundtrip() {
+        let handshake = ExtendedHandshake::new(Some(31235));
+        let bytes = handshake.to_bytes().unwrap();
+        assert_eq!(
+            bytes,
+            b"d1:md12:ut_holepunchi3e11:ut_metadatai1e6:ut_pexi2ee13:metadata_sizei31235ee"
+        );
+
+        let handshake = ExtendedHandshake::from_bytes(&bytes).unwrap();
+        assert_eq!(handshake.extension_id(UT_METADATA), Some(UT_METADATA_ID));
+        assert_eq!(handshake.extension_id(UT_PEX), Some(UT_PEX_ID));
+        assert_eq!(handshake.extension_id(UT_HOLEPUNCH), Some(UT_HOLEPUNCH_ID));
+        assert_eq!(handshake.metadata_size, Some(31235));
+    }
+
+    #[test]
+    fn test_extended_handshake_disabled_extension() {
+        let handshake =
+            ExtendedHandshake::from_bytes(b"d1:md11:ut_metadatai0e6:ut_pexi2ee1:v8:uTorrente")
+                .unwrap();
+        assert_eq!(handshake.extension_id(UT_METADATA), None);
+        assert_eq!(handshake.extension_id(UT_PEX), Some(2));
+        assert_eq!(handshake.v.as_deref(), Some("uTorrent"));
+    }
+
+    #[test]
+    fn test_extended_handshake_without_pex() {
+        let handshake = ExtendedHandshake::new(None).without_pex();
+        let handshake = ExtendedHandshake::from_bytes(&handshake.to_bytes().unwrap()).unwrap();
+        assert_eq!(handshake.extension_id(UT_PEX), None);
+        assert_eq!(handshake.extension_id(UT_METADATA), Some(UT_METADATA_ID));
+    }
+
+    #[test]
+    fn test_extended_handshake_upload_only() {
+        let handshake = ExtendedHandshake::new(None).with_upload_only();
+        let bytes = handshake.to_bytes().unwrap();
+        assert!(bytes.ends_with(b"11:upload_onlyi1ee"));
+        assert!(
+            ExtendedHandshake::from_bytes(&bytes)
+                .unwrap()
+                .is_upload_only()
+        );
+        assert!(!ExtendedHandshake::new(None).is_upload_only());
+    }
+
+    #[test]
+    fn test_extended_handshake_your_ip() {
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let bytes = ExtendedHandshake::new(None)
+            .with_your_ip(ip)
+            .to_bytes()
+            .unwrap();
+        assert!(bytes.ends_with(b"6:yourip4:\x01\x02\x03\x04e"));
+        assert_eq!(
+            ExtendedHandshake::from_bytes(&bytes).unwrap().your_ip(),
+            Some(ip)
+        );
+    }
+
+    #[test]
+    fn test_extension_limiter() {
+        let mut limiter = ExtensionLimiter::new();
+        assert!(!limiter.allow(UT_METADATA_ID, METADATA_PIECE_SIZE + 1024));
+        assert!(!limiter.allow(HANDSHAKE_ID, MAX_HANDSHAKE_LENGTH + 1));
+
+        for _ in 0..4 {
+            assert!(limiter.allow(UT_METADATA_ID, METADATA_PIECE_SIZE));
+        }
+        // Over the bytes per second
+        assert!(!limiter.allow(UT_METADATA_ID, METADATA_PIECE_SIZE));
+
+        let mut limiter = ExtensionLimiter::new();
+        for _ in 0..MAX_MESSAGES_PER_SECOND {
+            assert!(limiter.allow(HANDSHAKE_ID, 10));
+        }
+        // Over the messages per second
+        assert!(!limiter.allow(HANDSHAKE_ID, 10));
+    }
+
+    #[test]
+    fn test_bencode_value_length() {
+        assert_eq!(bencode_value_length(b"i42eabc"), Some(4));
+        assert_eq!(bencode_value_length(b"4:spamabc"), Some(6));
+        assert_eq!(
+            bencode_value_length(b"d8:msg_typei1e5:piecei0eeraw data"),
+            Some(25)
+        );
+        assert_eq!(bencode_value_length(b"d8:msg_type"), None);
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Mutex,
+};
+
+use url::Url;
+
+// Our address as the others see it, from the "external ip" of the tracker responses and the
+// "yourip" of the extension handshakes. A single peer may lie or sit behind the same NAT, so
+// the address most of the recent voters agree on is taken.
+
+// Only the recent votes count, so a new address wins once the network changes.
+const MAX_VOTES: usize = 50;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Voter {
+    // By the host of the announce URL.
+    Tracker(String),
+    Peer(IpAddr),
+}
+
+impl Voter {
+    // The trackers see us from the outside and have nothing to gain from lying.
+    fn weight(&self) -> usize {
+        match self {
+            Voter::Tracker(_) => 4,
+            Voter::Peer(_) => 1,
+        }
+    }
+}
+
+struct Vote {
+    voter: Voter,
+    ip: IpAddr,
+}
+
+// Shared by the announcers and the peer sessions of all the torrents.
+#[derive(Default)]
+pub struct ExternalIp {
+    votes: Mutex<VecDeque<Vote>>,
+}
+
+impl ExternalIp {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn vote_from_tracker(&self, url: &Url, ip: IpAddr) {
+        let host = url.host_str().unwrap_or_default().to_string();
+        self.vote(Voter::Tracker(host), ip);
+    }
+
+    pub fn vote_from_peer(&self, peer: IpAddr, ip: IpAddr) {
+        self.vote(Voter::Peer(peer), ip);
+    }
+
+    // Each voter counts once, with what it told us last.
+    fn vote(&self, voter: Voter, ip: IpAddr) {
+        if !is_global(ip) {
+            return;
+        }
+        let mut votes = self.votes.lock().unwrap();
+        votes.retain(|it| it.voter != voter);
+        if votes.len() == MAX_VOTES {
+            votes.pop_front();
+        }
+        votes.push_back(Vote { voter, ip });
+    }
+
+    // The address with the most votes, None until anyone told us.
+    pub fn ip(&self) -> Option<IpAddr> {
+        let votes = self.votes.lock().unwrap();
+        let mut tally: HashMap<IpAddr, usize> = HashMap::new();
+        for vote in votes.iter() {
+            *tally.entry(vote.ip).or_default() += vote.voter.weight();
+        }
+        // The latest vote breaks the ties
+        let latest = votes.back()?.ip;
+        tally
+            .into_iter()
+            .max_by_key(|(ip, weight)| (*weight, *ip == latest))
+            .map(|(ip, _)| ip)
+    }
+}
+
+// A peer on the LAN reports our LAN address, that's no use to anyone outside.
+fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast())
+        }
+        IpAddr::V6(ip) => {
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_consensus() {
+        let external_ip = ExternalIp::new();
+        assert_eq!(external_ip.ip(), None);
+
+        external_ip.vote_from_peer(ip("1.1.1.1"), ip("5.5.5.5"));
+        // The LAN address is ignored
+        external_ip.vote_from_peer(ip("1.1.1.2"), ip("192.168.1.10"));
+        assert_eq!(external_ip.ip(), Some(ip("5.5.5.5")));
+
+        // A tracker outweighs a few lying peers
+        let tracker = Url::parse("http://tracker.example.com/announce").unwrap();
+        external_ip.vote_from_tracker(&tracker, ip("6.6.6.6"));
+        for voter in ["1.1.1.3", "1.1.1.4"] {
+            external_ip.vote_from_peer(ip(voter), ip("5.5.5.5"));
+        }
+        assert_eq!(external_ip.ip(), Some(ip("6.6.6.6")));
+
+        // A voter changing its mind is counted once
+        for _ in 0..10 {
+            external_ip.vote_from_peer(ip("1.1.1.1"), ip("7.7.7.7"));
+        }
+        assert_eq!(external_ip.ip(), Some(ip("6.6.6.6")));
+    }
+}
@@ -1,6 +1,7 @@
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 
-use crate::types::Sha1Hash;
+use crate::types::{Sha1Hash, Sha256Hash};
 
 pub fn calculate_sha1_hash(data: Vec<u8>) -> Sha1Hash {
     let digest = Sha1::digest(&data);
@@ -8,3 +9,80 @@ pub fn calculate_sha1_hash(data: Vec<u8>) -> Sha1Hash {
     hash.copy_from_slice(&digest);
     hash
 }
+
+pub fn calculate_sha256_hash(data: Vec<u8>) -> Sha256Hash {
+    let digest = Sha256::digest(&data);
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&digest);
+    hash
+}
+
+// The leaves of the v2 merkle trees are the hashes of each 16KiB block.
+pub const MERKLE_BLOCK_SIZE: usize = 16 * 1024;
+
+// The root of the merkle tree over the blocks of the data, the tree has `leaves` leaves,
+// the ones past the end of the data are zeros.
+// https://www.bittorrent.org/beps/bep_0052.html
+pub fn merkle_root(data: &[u8], leaves: usize) -> Sha256Hash {
+    merkle_root_of_leaves(leaf_hashes(data), leaves)
+}
+
+// The hash of each 16KiB block of the data.
+pub fn leaf_hashes(data: &[u8]) -> Vec<Sha256Hash> {
+    data.chunks(MERKLE_BLOCK_SIZE)
+        .map(|it| calculate_sha256_hash(it.to_vec()))
+        .collect()
+}
+
+// The root of the merkle tree over the leaf hashes, padded with zeros to `leaves` leaves.
+pub fn merkle_root_of_leaves(mut layer: Vec<Sha256Hash>, leaves: usize) -> Sha256Hash {
+    layer.resize(leaves.max(layer.len()).next_power_of_two(), [0u8; 32]);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks_exact(2)
+            .map(|pair| calculate_sha256_hash([pair[0], pair[1]].concat()))
+            .collect();
+    }
+    layer[0]
+}
+
+// SHA-1 of a piece fed with its blocks in order as they arrive,
+// so only the last block is left to hash when the piece completes.
+#[derive(Clone, Default)]
+pub struct IncrementalHash {
+    hasher: Sha1,
+    hashed_bytes: usize,
+}
+
+impl IncrementalHash {
+    // The length of the prefix of the piece which is hashed, the next block must start here.
+    pub fn hashed_bytes(&self) -> usize {
+        self.hashed_bytes
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.hashed_bytes += data.len();
+    }
+
+    pub fn finalize(self) -> Sha1Hash {
+        let mut hash = [0u8; 20];
+        hash.copy_from_slice(&self.hasher.finalize());
+        hash
+    }
+}
+
+// https://www.rfc-editor.org/rfc/rfc2104
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Sha256Hash {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&calculate_sha256_hash(key.to_vec()));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let inner_key: Vec<u8> = block.iter().map(|it| it ^ 0x36).collect();
+    let outer_key: Vec<u8> = block.iter().map(|it| it ^ 0x5c).collect();
+    let inner = calculate_sha256_hash([&inner_key[..], data].concat());
+    calculate_sha256_hash([&outer_key[..], &inner[..]].concat())
+}
@@ -0,0 +1,206 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::{Semaphore, mpsc};
+
+use crate::{disk::Disk, metainfo::MetaInfo, piece, types::BitField};
+
+// Check the existing data of a batch of added torrents at the same time instead of one after
+// the other, a few torrents at a time so the disk isn't thrashed by all of them at once.
+
+// Don't report the progress more often than this, a piece is checked in a few milliseconds.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy)]
+pub struct HashCheckOptions {
+    // How many torrents are checked at the same time.
+    pub workers: usize,
+    // Bytes per second read by all the checks together, None means as fast as the disk goes.
+    pub read_rate: Option<u64>,
+}
+
+impl Default for HashCheckOptions {
+    fn default() -> Self {
+        Self {
+            workers: default_workers(),
+            read_rate: None,
+        }
+    }
+}
+
+pub fn default_workers() -> usize {
+    std::thread::available_parallelism().map_or(2, |it| it.get().min(4))
+}
+
+pub struct HashCheckJob {
+    pub metainfo: MetaInfo,
+    // The directory the torrent files are saved in.
+    pub save_path: PathBuf,
+}
+
+// The progress of the whole batch, for one progress bar.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct HashCheckProgress {
+    pub torrents_checked: usize,
+    pub torrents: usize,
+    pub bytes_checked: u64,
+    pub bytes: u64,
+}
+
+struct Shared {
+    progress: Mutex<(HashCheckProgress, Instant)>,
+    // When the next read may start, to keep under the read rate.
+    next_read: Mutex<Instant>,
+    read_rate: Option<u64>,
+    events: mpsc::UnboundedSender<HashCheckProgress>,
+}
+
+impl Shared {
+    // Wait for the turn of a read of `bytes`, the first read goes right away.
+    fn throttle(&self, bytes: u64) {
+        let Some(rate) = self.read_rate.filter(|it| *it > 0) else {
+            return;
+        };
+        let start = {
+            let mut next_read = self.next_read.lock().unwrap();
+            let start = (*next_read).max(Instant::now());
+            *next_read = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+            start
+        };
+        std::thread::sleep(start.saturating_duration_since(Instant::now()));
+    }
+
+    fn record(&self, bytes: u64, torrent_done: bool) {
+        let mut progress = self.progress.lock().unwrap();
+        let (current, reported_at) = &mut *progress;
+        current.bytes_checked += bytes;
+        current.torrents_checked += torrent_done as usize;
+        if torrent_done || reported_at.elapsed() >= PROGRESS_INTERVAL {
+            *reported_at = Instant::now();
+            let _ = self.events.send(*current);
+        }
+    }
+}
+
+// Check the pieces of each torrent, returns the pieces we have in the order of the jobs.
+pub async fn check_all(
+    jobs: Vec<HashCheckJob>,
+    options: HashCheckOptions,
+    events: mpsc::UnboundedSender<HashCheckProgress>,
+) -> Vec<BitField> {
+    let progress = HashCheckProgress {
+        torrents: jobs.len(),
+        bytes: jobs.iter().map(|it| it.metainfo.total_bytes() as u64).sum(),
+        ..Default::default()
+    };
+    let shared = Arc::new(Shared {
+        progress: Mutex::new((progress, Instant::now())),
+        next_read: Mutex::new(Instant::now()),
+        read_rate: options.read_rate,
+        events,
+    });
+    let workers = Arc::new(Semaphore::new(options.workers.max(1)));
+    let handles: Vec<_> = jobs
+        .into_iter()
+        .map(|job| {
+            let shared = shared.clone();
+            let workers = workers.clone();
+            tokio::spawn(async move {
+                let _worker = workers
+                    .acquire_owned()
+                    .await
+                    .expect("Hash check semaphore is never closed");
+                let piece_count = job.metainfo.piece_count();
+                tokio::task::spawn_blocking(move || check(&job, &shared))
+                    .await
+                    .unwrap_or_else(|_| BitField::repeat(false, piece_count))
+            })
+        })
+        .collect();
+    let mut bitfields = Vec::with_capacity(handles.len());
+    for handle in handles {
+        bitfields.push(handle.await.unwrap_or_default());
+    }
+    bitfields
+}
+
+fn check(job: &HashCheckJob, shared: &Shared) -> BitField {
+    let metainfo = &job.metainfo;
+    let piece_length = metainfo.info.piece_length as u64;
+    let total_bytes = metainfo.total_bytes() as u64;
+    let have = piece::piece_hashes(metainfo)
+        .iter()
+        .enumerate()
+        .map(|(index, hash)| {
+            let length = piece_length.min(total_bytes.saturating_sub(index as u64 * piece_length));
+            shared.throttle(length);
+            let has = Disk::recheck_piece(metainfo, &job.save_path, index as u32, hash);
+            shared.record(length, false);
+            has
+        })
+        .collect();
+    shared.record(0, true);
+    have
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::TestMetaInfo;
+
+    #[tokio::test]
+    async fn test_check_all() {
+        let save_path = std::env::temp_dir().join("bitdrift_test_hash_check");
+        std::fs::create_dir_all(&save_path).unwrap();
+        let data: Vec<u8> = (0..16u8).collect();
+        std::fs::write(save_path.join("a.bin"), &data).unwrap();
+        let mut corrupt = data.clone();
+        corrupt[0] ^= 0xff;
+        std::fs::write(save_path.join("b.bin"), &corrupt).unwrap();
+
+        let jobs = vec![
+            HashCheckJob {
+                metainfo: TestMetaInfo::new("a.bin", 8).single(&data).build(),
+                save_path: save_path.clone(),
+            },
+            HashCheckJob {
+                metainfo: TestMetaInfo::new("b.bin", 8).single(&data).build(),
+                save_path: save_path.clone(),
+            },
+        ];
+        let options = HashCheckOptions {
+            workers: 2,
+            // 4 pieces of 8 bytes, the 3 after the first wait 100ms each
+            read_rate: Some(80),
+        };
+        let (events, mut progress) = mpsc::unbounded_channel();
+        let started = Instant::now();
+        let bitfields = check_all(jobs, options, events).await;
+        assert!(started.elapsed() >= Duration::from_millis(250));
+
+        assert_eq!(bitfields[0], BitField::repeat(true, 2));
+        assert_eq!(
+            bitfields[1].iter().by_vals().collect::<Vec<_>>(),
+            [false, true]
+        );
+        let mut last = None;
+        while let Ok(it) = progress.try_recv() {
+            last = Some(it);
+        }
+        assert_eq!(
+            last,
+            Some(HashCheckProgress {
+                torrents_checked: 2,
+                torrents: 2,
+                bytes_checked: 32,
+                bytes: 32,
+            })
+        );
+
+        let _ = std::fs::remove_dir_all(save_path);
+    }
+}
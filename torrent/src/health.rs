@@ -0,0 +1,134 @@
+use serde::Serialize;
+
+// The at a glance indicator next to each torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthLevel {
+    Red,
+    Yellow,
+    Green,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TorrentHealth {
+    // 0 to 100
+    pub score: u8,
+    pub level: HealthLevel,
+}
+
+// What the score is made of, gathered by `Torrent::health`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HealthInputs {
+    // How many of the trackers responded to their last announce.
+    pub working_trackers: usize,
+    pub trackers: usize,
+    // The most seeders a tracker reported, None if none did.
+    pub seeders: Option<u32>,
+    // The copies of the missing pieces among the connected peers, see
+    // `PiecePicker::distributed_copies`. None once all the wanted pieces are downloaded.
+    pub distributed_copies: Option<f64>,
+    // Bytes per second of the latest swarm sample, None if there is no recent one.
+    pub download_rate: Option<u64>,
+}
+
+const TRACKER_POINTS: f64 = 25.0;
+const SEEDER_POINTS: f64 = 25.0;
+const AVAILABILITY_POINTS: f64 = 30.0;
+const PROGRESS_POINTS: f64 = 20.0;
+// More seeders than this is as good as it gets.
+const ENOUGH_SEEDERS: u32 = 5;
+const GREEN_SCORE: u8 = 70;
+const YELLOW_SCORE: u8 = 40;
+
+impl TorrentHealth {
+    pub fn compute(inputs: &HealthInputs) -> Self {
+        // Trackerless torrents rely on the DHT, we can't tell how it does
+        let trackers = if inputs.trackers == 0 {
+            0.5
+        } else {
+            inputs.working_trackers as f64 / inputs.trackers as f64
+        };
+        let seeders = match inputs.seeders {
+            Some(seeders) => seeders.min(ENOUGH_SEEDERS) as f64 / ENOUGH_SEEDERS as f64,
+            None => 0.4,
+        };
+        // A complete torrent is as available and progressing as it can be
+        let availability = match inputs.distributed_copies {
+            // Two thirds for one copy of everything, the rest for the second
+            Some(copies) if copies < 1.0 => copies * 2.0 / 3.0,
+            Some(copies) => (1.0 + copies.min(2.0)) / 3.0,
+            None => 1.0,
+        };
+        let progress = match inputs.distributed_copies {
+            Some(_) if inputs.download_rate.unwrap_or(0) == 0 => 0.0,
+            _ => 1.0,
+        };
+        let score = (trackers * TRACKER_POINTS
+            + seeders * SEEDER_POINTS
+            + availability * AVAILABILITY_POINTS
+            + progress * PROGRESS_POINTS)
+            .round()
+            .clamp(0.0, 100.0) as u8;
+
+        // Some piece is nowhere to be found, the torrent can't finish until a seeder shows up
+        let stuck = inputs.distributed_copies.is_some_and(|it| it < 1.0)
+            && inputs.seeders.is_none_or(|it| it == 0);
+        let level = if stuck || score < YELLOW_SCORE {
+            HealthLevel::Red
+        } else if score < GREEN_SCORE {
+            HealthLevel::Yellow
+        } else {
+            HealthLevel::Green
+        };
+        Self { score, level }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_health() {
+        let healthy = HealthInputs {
+            working_trackers: 2,
+            trackers: 2,
+            seeders: Some(12),
+            distributed_copies: Some(3.5),
+            download_rate: Some(500_000),
+        };
+        let health = TorrentHealth::compute(&healthy);
+        assert_eq!(health.score, 100);
+        assert_eq!(health.level, HealthLevel::Green);
+
+        // Seeding with the trackers down
+        let seeding = HealthInputs {
+            working_trackers: 0,
+            trackers: 2,
+            seeders: None,
+            distributed_copies: None,
+            download_rate: None,
+        };
+        assert_eq!(TorrentHealth::compute(&seeding).level, HealthLevel::Yellow);
+
+        // A piece nobody has, the score alone would be yellow
+        let stuck = HealthInputs {
+            distributed_copies: Some(0.9),
+            seeders: Some(0),
+            ..healthy.clone()
+        };
+        let health = TorrentHealth::compute(&stuck);
+        assert!(health.score >= YELLOW_SCORE);
+        assert_eq!(health.level, HealthLevel::Red);
+
+        // Stalled, but the seeders are there
+        let stalled = HealthInputs {
+            working_trackers: 1,
+            trackers: 2,
+            seeders: Some(3),
+            distributed_copies: Some(1.0),
+            download_rate: Some(0),
+        };
+        assert_eq!(TorrentHealth::compute(&stalled).level, HealthLevel::Yellow);
+    }
+}
@@ -0,0 +1,154 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use thiserror::Error;
+
+// Implementation of the holepunch extension, two peers behind NAT connect to each other
+// through a peer both of them are connected to.
+// https://www.bittorrent.org/beps/bep_0055.html
+//
+// The initiator sends rendezvous with the target to the relay, the relay sends connect to
+// both of them, then they dial each other at the same time, which opens both NATs.
+
+pub(crate) type Result<T> = std::result::Result<T, HolepunchError>;
+
+#[derive(Debug, Error)]
+pub enum HolepunchError {
+    #[error("Invalid holepunch message")]
+    InvalidMessage,
+}
+
+// Why the relay couldn't connect the peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    // The target address is invalid.
+    NoSuchPeer,
+    // The target is not connected to the relay.
+    NotConnected,
+    // The target doesn't support the holepunch extension.
+    NoSupport,
+    // The initiator asked to connect to itself.
+    NoSelf,
+    Unknown(u32),
+}
+
+impl ErrorCode {
+    fn from_u32(code: u32) -> Self {
+        match code {
+            1 => ErrorCode::NoSuchPeer,
+            2 => ErrorCode::NotConnected,
+            3 => ErrorCode::NoSupport,
+            4 => ErrorCode::NoSelf,
+            _ => ErrorCode::Unknown(code),
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            ErrorCode::NoSuchPeer => 1,
+            ErrorCode::NotConnected => 2,
+            ErrorCode::NoSupport => 3,
+            ErrorCode::NoSelf => 4,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HolepunchMessage {
+    // Initiator to relay, connect me with the peer.
+    Rendezvous(SocketAddr),
+    // Relay to both peers, dial the peer now.
+    Connect(SocketAddr),
+    // Relay to initiator.
+    Error(SocketAddr, ErrorCode),
+}
+
+// Unlike the other extensions the message is binary:
+// <msg_type u8><addr_type u8><addr 4 or 16 bytes><port u16><err_code u32>
+// The err_code is sent by every message, 0 except for the error.
+impl HolepunchMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (msg_type, addr, code) = match self {
+            HolepunchMessage::Rendezvous(addr) => (0u8, addr, 0),
+            HolepunchMessage::Connect(addr) => (1, addr, 0),
+            HolepunchMessage::Error(addr, code) => (2, addr, code.to_u32()),
+        };
+        let mut bytes = vec![msg_type];
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                bytes.push(0);
+                bytes.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&ip.octets());
+            }
+        }
+        bytes.extend_from_slice(&addr.port().to_be_bytes());
+        bytes.extend_from_slice(&code.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (&msg_type, bytes) = bytes.split_first().ok_or(HolepunchError::InvalidMessage)?;
+        let (&addr_type, bytes) = bytes.split_first().ok_or(HolepunchError::InvalidMessage)?;
+        let ip_length = match addr_type {
+            0 => 4,
+            1 => 16,
+            _ => return Err(HolepunchError::InvalidMessage),
+        };
+        if bytes.len() != ip_length + 2 + 4 {
+            return Err(HolepunchError::InvalidMessage);
+        }
+        let (ip, rest) = bytes.split_at(ip_length);
+        let ip = if ip_length == 4 {
+            IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap()))
+        } else {
+            IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap()))
+        };
+        let addr = SocketAddr::new(ip, u16::from_be_bytes([rest[0], rest[1]]));
+        let code = u32::from_be_bytes([rest[2], rest[3], rest[4], rest[5]]);
+        match msg_type {
+            0 => Ok(HolepunchMessage::Rendezvous(addr)),
+            1 => Ok(HolepunchMessage::Connect(addr)),
+            2 => Ok(HolepunchMessage::Error(addr, ErrorCode::from_u32(code))),
+            _ => Err(HolepunchError::InvalidMessage),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_holepunch_message_roundtrip() {
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let bytes = HolepunchMessage::Rendezvous(addr).to_bytes();
+        assert_eq!(bytes, [0, 0, 10, 0, 0, 1, 0x1a, 0xe1, 0, 0, 0, 0]);
+        assert_eq!(
+            HolepunchMessage::from_bytes(&bytes).unwrap(),
+            HolepunchMessage::Rendezvous(addr)
+        );
+
+        let addr: SocketAddr = "[2001:db8::1]:51413".parse().unwrap();
+        for message in [
+            HolepunchMessage::Connect(addr),
+            HolepunchMessage::Error(addr, ErrorCode::NoSupport),
+            HolepunchMessage::Error(addr, ErrorCode::Unknown(9)),
+        ] {
+            let bytes = message.to_bytes();
+            assert_eq!(bytes.len(), 24);
+            assert_eq!(HolepunchMessage::from_bytes(&bytes).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_invalid_holepunch_message() {
+        assert!(HolepunchMessage::from_bytes(&[]).is_err());
+        assert!(HolepunchMessage::from_bytes(&[0, 2, 10, 0, 0, 1, 0, 1, 0, 0, 0, 0]).is_err());
+        assert!(HolepunchMessage::from_bytes(&[3, 0, 10, 0, 0, 1, 0, 1, 0, 0, 0, 0]).is_err());
+        // Truncated address
+        assert!(HolepunchMessage::from_bytes(&[0, 1, 10, 0, 0, 1, 0, 1, 0, 0, 0, 0]).is_err());
+    }
+}
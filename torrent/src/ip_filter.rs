@@ -0,0 +1,291 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::RangeInclusive,
+    sync::{Arc, RwLock},
+};
+
+// The address ranges we don't talk to, from the blocklists in the PeerGuardian text format:
+//   Some Organization:1.2.3.0-1.2.3.255
+// or the eMule DAT format, the ranges above access level 127 are allowed:
+//   001.002.003.000 - 001.002.003.255 , 000 , Some Organization
+// A line with a single address or a CIDR block works too.
+
+// The access levels up to this one are blocked in the DAT format.
+const MAX_BLOCKED_LEVEL: u32 = 127;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    // Sorted and merged, so an address is found with a binary search.
+    v4: Vec<RangeInclusive<u32>>,
+    v6: Vec<RangeInclusive<u128>>,
+}
+
+impl IpFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The lines which don't parse are skipped, a list of a million lines has a few typos.
+    pub fn parse(text: &str) -> Self {
+        let mut filter = Self::new();
+        let mut skipped = 0;
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_line(line) {
+                Some(Some((start, end))) => filter.push(start, end),
+                Some(None) => {}
+                None => skipped += 1,
+            }
+        }
+        if skipped > 0 {
+            log::warn!("Skipped {} invalid lines of the blocklist", skipped);
+        }
+        filter.normalize();
+        filter
+    }
+
+    // Block the addresses of any of the filters.
+    pub fn combine<'a>(filters: impl IntoIterator<Item = &'a IpFilter>) -> Self {
+        let mut combined = Self::new();
+        for filter in filters {
+            combined.v4.extend(filter.v4.iter().cloned());
+            combined.v6.extend(filter.v6.iter().cloned());
+        }
+        combined.normalize();
+        combined
+    }
+
+    // Both ends are included, the addresses must be of the same family.
+    pub fn block(&mut self, start: IpAddr, end: IpAddr) {
+        self.push(start, end);
+        self.normalize();
+    }
+
+    fn push(&mut self, start: IpAddr, end: IpAddr) {
+        match (start, end) {
+            (IpAddr::V4(start), IpAddr::V4(end)) => {
+                let (start, end) = (u32::from(start), u32::from(end));
+                self.v4.push(start.min(end)..=start.max(end));
+            }
+            (IpAddr::V6(start), IpAddr::V6(end)) => {
+                let (start, end) = (u128::from(start), u128::from(end));
+                self.v6.push(start.min(end)..=start.max(end));
+            }
+            _ => {}
+        }
+    }
+
+    fn normalize(&mut self) {
+        merge(&mut self.v4);
+        merge(&mut self.v6);
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            // The v4 peers connecting over v6 are checked against the v4 ranges
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => contains(&self.v4, u32::from(ip)),
+                None => contains(&self.v6, u128::from(ip)),
+            },
+        }
+    }
+
+    // The number of ranges after merging.
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+}
+
+// None if the line doesn't parse, Some(None) for a range which is allowed.
+fn parse_line(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    // DAT, the range goes first and the access level second
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    if fields.len() >= 2
+        && let Some((start, end)) = parse_range(fields[0])
+    {
+        let level: u32 = fields[1].parse().ok()?;
+        return Some((level <= MAX_BLOCKED_LEVEL).then_some((start, end)));
+    }
+    // P2P, the name may have colons too, the range is after the last one which parses.
+    // Not for v6, their ranges are written as CIDR blocks.
+    if let Some((_, range)) = line.rsplit_once(':')
+        && let Some(range) = parse_range(range)
+    {
+        return Some(Some(range));
+    }
+    parse_range(line).map(Some)
+}
+
+fn parse_range(range: &str) -> Option<(IpAddr, IpAddr)> {
+    let range = range.trim();
+    if let Some((start, end)) = range.split_once('-') {
+        return Some((parse_ip(start)?, parse_ip(end)?));
+    }
+    if let Some((ip, prefix)) = range.split_once('/') {
+        return cidr(parse_ip(ip)?, prefix.trim().parse().ok()?);
+    }
+    let ip = parse_ip(range)?;
+    Some((ip, ip))
+}
+
+// The lists pad the v4 octets with zeros, which the std parser refuses.
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    let ip = ip.trim();
+    if ip.contains(':') {
+        return ip.parse().ok();
+    }
+    let octets: Vec<u8> = ip
+        .split('.')
+        .map(|it| it.parse().ok())
+        .collect::<Option<_>>()?;
+    let octets: [u8; 4] = octets.try_into().ok()?;
+    Some(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+fn cidr(ip: IpAddr, prefix: u32) -> Option<(IpAddr, IpAddr)> {
+    match ip {
+        IpAddr::V4(ip) if prefix <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            let start = u32::from(ip) & mask;
+            Some((
+                IpAddr::from(start.to_be_bytes()),
+                IpAddr::from((start | !mask).to_be_bytes()),
+            ))
+        }
+        IpAddr::V6(ip) if prefix <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            let start = u128::from(ip) & mask;
+            Some((
+                IpAddr::from(start.to_be_bytes()),
+                IpAddr::from((start | !mask).to_be_bytes()),
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn merge<T: Ord + Copy>(ranges: &mut Vec<RangeInclusive<T>>) {
+    ranges.sort_by_key(|it| *it.start());
+    let mut merged: Vec<RangeInclusive<T>> = Vec::with_capacity(ranges.len());
+    for range in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(last) if range.start() <= last.end() => {
+                if range.end() > last.end() {
+                    *last = *last.start()..=*range.end();
+                }
+            }
+            _ => merged.push(range),
+        }
+    }
+    *ranges = merged;
+}
+
+fn contains<T: Ord + Copy>(ranges: &[RangeInclusive<T>], value: T) -> bool {
+    // The last range starting at or before the value
+    let index = ranges.partition_point(|it| *it.start() <= value);
+    index > 0 && ranges[index - 1].contains(&value)
+}
+
+// The filter in use, shared by the listener and the dialing. A new blocklist replaces it at
+// once, the checks in progress finish with the filter they started with.
+#[derive(Debug, Clone, Default)]
+pub struct SharedIpFilter {
+    filter: Arc<RwLock<Arc<IpFilter>>>,
+}
+
+impl SharedIpFilter {
+    pub fn new(filter: IpFilter) -> Self {
+        Self {
+            filter: Arc::new(RwLock::new(Arc::new(filter))),
+        }
+    }
+
+    pub fn current(&self) -> Arc<IpFilter> {
+        self.filter.read().unwrap().clone()
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.current().is_blocked(ip)
+    }
+
+    // Returns which of the connected peers the new filter blocks, the caller closes those and
+    // keeps the others.
+    pub fn replace(&self, filter: IpFilter, connected: &[SocketAddr]) -> Vec<SocketAddr> {
+        let blocked = connected
+            .iter()
+            .filter(|addr| filter.is_blocked(addr.ip()))
+            .copied()
+            .collect();
+        *self.filter.write().unwrap() = Arc::new(filter);
+        blocked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_blocklist() {
+        let text = "\
+# A comment
+Some Org:1.2.3.0-1.2.3.255
+Org: with colons:5.0.0.0-5.0.0.10
+010.000.000.000 - 010.000.000.255 , 000 , DAT range
+011.000.000.000 - 011.000.000.255 , 200 , Allowed DAT range
+20.0.0.0/24
+2001:db8::/32
+not an address
+";
+        let filter = IpFilter::parse(text);
+        assert_eq!(filter.len(), 5);
+        assert!(filter.is_blocked(ip("1.2.3.4")));
+        assert!(filter.is_blocked(ip("5.0.0.10")));
+        assert!(!filter.is_blocked(ip("5.0.0.11")));
+        assert!(filter.is_blocked(ip("10.0.0.255")));
+        assert!(!filter.is_blocked(ip("11.0.0.1")));
+        assert!(filter.is_blocked(ip("20.0.0.200")));
+        assert!(!filter.is_blocked(ip("20.0.1.0")));
+        assert!(filter.is_blocked(ip("2001:db8::1")));
+        assert!(!filter.is_blocked(ip("2001:db9::1")));
+        // A v4 peer over v6
+        assert!(filter.is_blocked(ip("::ffff:1.2.3.4")));
+    }
+
+    #[test]
+    fn test_combine_overlapping_ranges() {
+        let a = IpFilter::parse("a:1.0.0.0-1.0.0.100\nb:1.0.0.50-1.0.0.200");
+        assert_eq!(a.len(), 1);
+        let b = IpFilter::parse("c:1.0.0.150-1.0.1.0\nd:9.9.9.9");
+        let combined = IpFilter::combine([&a, &b]);
+        assert_eq!(combined.len(), 2);
+        assert!(combined.is_blocked(ip("1.0.0.255")));
+        assert!(combined.is_blocked(ip("9.9.9.9")));
+        assert!(!combined.is_blocked(ip("9.9.9.10")));
+    }
+
+    #[test]
+    fn test_replace_keeps_allowed_peers() {
+        let shared = SharedIpFilter::new(IpFilter::parse("a:1.0.0.1"));
+        let connected: Vec<SocketAddr> = vec![
+            "1.0.0.2:6881".parse().unwrap(),
+            "2.0.0.1:6881".parse().unwrap(),
+        ];
+        let blocked = shared.replace(IpFilter::parse("b:2.0.0.0/8"), &connected);
+        assert_eq!(blocked, vec![connected[1]]);
+        assert!(!shared.is_blocked(ip("1.0.0.1")));
+        assert!(shared.is_blocked(ip("2.3.4.5")));
+    }
+}
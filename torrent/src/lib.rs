@@ -1,14 +1,65 @@
-mod choker;
-mod disk;
+mod announce_cache;
+mod announce_list;
+mod announce_throttle;
+pub mod announcer;
+pub mod bandwidth;
+pub mod blocklist;
+pub mod choker;
+pub mod client_identity;
+mod compact;
+pub mod cross_seed;
+pub mod dedupe;
+pub mod dht;
+pub mod dialer;
+pub mod disk;
+pub mod editor;
+pub mod engine;
+pub mod existing_data;
+mod extension;
+pub mod external_ip;
 mod hash;
+pub mod hash_check;
+pub mod health;
+mod holepunch;
+pub mod ip_filter;
+pub mod listener;
+pub mod liveness;
+pub mod magnet;
 mod message;
+mod metadata;
 pub mod metainfo;
+pub mod mse;
 mod peer;
+pub mod peer_activity;
 mod peer_connection;
+pub mod peer_list;
+pub mod peer_manager;
 mod peer_stats;
+pub mod peer_tls;
+mod pex;
+pub mod pick_strategy;
 mod piece;
 mod piece_picker;
+pub mod port_mapping;
+pub mod profile;
+pub mod qbittorrent;
+pub mod removal;
+pub mod sanity;
+pub mod scrub;
 mod session;
+pub mod startup;
+mod starvation;
+pub mod statistics;
+pub mod swarm_history;
 pub mod torrent;
+pub mod torrent_settings;
 pub mod tracker;
+pub mod transfer;
+pub mod transport;
 mod types;
+mod udp_tracker;
+mod upload;
+mod utp;
+pub mod webhook;
+pub mod webseed;
+pub mod webtorrent;
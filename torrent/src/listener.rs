@@ -0,0 +1,585 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU16, Ordering},
+    },
+    time::Duration,
+};
+
+use futures::{
+    StreamExt,
+    stream::{self, BoxStream},
+};
+use socket2::{Domain, Socket, Type};
+use tokio::{
+    net::{TcpListener, ToSocketAddrs, UdpSocket},
+    sync::mpsc,
+};
+
+use crate::{
+    dht::Dht,
+    dialer::DialCandidate,
+    disk::{DeleteMode, Disk, DiskError, DiskEvent, DiskOptions},
+    external_ip::ExternalIp,
+    ip_filter::SharedIpFilter,
+    metainfo::MetaInfo,
+    mse::EncryptionPolicy,
+    peer::{TorrentContext, serve_incoming, serve_outgoing},
+    peer_activity::PeerActivityLog,
+    peer_manager::DisconnectReason,
+    torrent::{PieceWrite, Torrent},
+    transfer::TransferTotals,
+    transport::{BindSettings, PeerStream},
+    types::{PeerId, Sha1Hash},
+    utp::UtpSocket,
+};
+
+// The time an incoming peer has to send its handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Accept the incoming peers and hand them to the torrent named by the info hash in their handshake.
+// The peers connect over TCP or uTP, both on the same port, and over both v4 and v6 on a
+// dual-stack host.
+pub struct PeerListener {
+    listeners: Vec<TcpListener>,
+    utp: Vec<UtpSocket>,
+    port: ListenPort,
+    registry: TorrentRegistry,
+}
+
+// The port the peers reach us at, which the announces tell the trackers. Shared, so the
+// announcers follow the listener when it's bound again on another port.
+#[derive(Debug, Clone)]
+pub struct ListenPort(Arc<AtomicU16>);
+
+impl ListenPort {
+    pub fn new(port: u16) -> Self {
+        Self(Arc::new(AtomicU16::new(port)))
+    }
+
+    pub fn get(&self) -> u16 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, port: u16) {
+        self.0.store(port, Ordering::Relaxed);
+    }
+}
+
+// How a peer dialed through `TorrentRegistry::dial` went, for the peer manager.
+#[derive(Debug)]
+pub enum DialEvent {
+    Connected {
+        info_hash: Sha1Hash,
+        addr: SocketAddr,
+    },
+    // Connecting or the handshake failed.
+    Failed {
+        info_hash: Sha1Hash,
+        candidate: DialCandidate,
+    },
+    Disconnected {
+        info_hash: Sha1Hash,
+        addr: SocketAddr,
+        reason: DisconnectReason,
+    },
+}
+
+// The torrents the incoming peers are served, by the info hash of their handshake.
+// Shared between the listener and the engine, the torrents come and go while it runs.
+// The engine dials the peers of the torrents through it too.
+#[derive(Clone)]
+pub struct TorrentRegistry {
+    torrents: Arc<Mutex<HashMap<Sha1Hash, Arc<TorrentContext>>>>,
+    peer_id: PeerId,
+    handshake_timeout: Duration,
+    encryption: EncryptionPolicy,
+    // Told what the peers see our address as, None to ignore it.
+    external_ip: Option<Arc<ExternalIp>>,
+    // Told the disk failures and the trashed files of the torrents, None to ignore them.
+    disk_events: Option<mpsc::UnboundedSender<(Sha1Hash, DiskEvent)>>,
+    // The peers of the public torrents are told its port and add theirs, None while it's off.
+    dht: Option<Arc<Dht>>,
+    // None keeps the default `PEER_TIMEOUT`.
+    peer_timeout: Option<Duration>,
+    // None keeps the default `DEFAULT_STARVATION_TIMEOUT`.
+    starvation_timeout: Option<Duration>,
+    // Neither accepted nor dialed, replaced while the engine runs.
+    ip_filter: SharedIpFilter,
+    // The uTP sockets of the listener, shared with the sessions to dial from.
+    utp: Vec<UtpSocket>,
+}
+
+// What the sessions of a torrent share, read off the torrent before it's locked behind the Arc.
+struct PendingContext {
+    // The one the torrent is known by, see `Torrent::info_hash`.
+    info_hash: Sha1Hash,
+    info_hashes: Vec<Sha1Hash>,
+    private: bool,
+    activity: Arc<PeerActivityLog>,
+    transfer: Arc<TransferTotals>,
+    discovered_peers: mpsc::UnboundedSender<DialCandidate>,
+    disk_writes: mpsc::UnboundedReceiver<PieceWrite>,
+}
+
+impl TorrentRegistry {
+    fn new(peer_id: PeerId) -> Self {
+        Self {
+            torrents: Arc::default(),
+            peer_id,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            encryption: EncryptionPolicy::default(),
+            external_ip: None,
+            disk_events: None,
+            dht: None,
+            peer_timeout: None,
+            starvation_timeout: None,
+            ip_filter: SharedIpFilter::default(),
+            utp: Vec::new(),
+        }
+    }
+
+    pub fn add_torrent(&self, torrent: Torrent) -> Arc<tokio::sync::Mutex<Torrent>> {
+        let (discovered_peers, _) = mpsc::unbounded_channel();
+        self.add_torrent_with_peers(torrent, discovered_peers)
+    }
+
+    // Same as `add_torrent`, the peers the sessions learn of, e.g. through PEX, go to
+    // `discovered_peers`. Returns the torrent shared with the sessions.
+    pub fn add_torrent_with_peers(
+        &self,
+        mut torrent: Torrent,
+        discovered_peers: mpsc::UnboundedSender<DialCandidate>,
+    ) -> Arc<tokio::sync::Mutex<Torrent>> {
+        let context = self.context(&mut torrent, discovered_peers);
+        let torrent = Arc::new(tokio::sync::Mutex::new(torrent));
+        self.register(context, torrent.clone(), DiskOptions::default());
+        torrent
+    }
+
+    // Serve the torrent again once it was removed, e.g. resumed after a pause. Its files are
+    // read and written with `disk_options`, e.g. in the save path it was added with.
+    pub async fn add_shared_torrent(
+        &self,
+        torrent: Arc<tokio::sync::Mutex<Torrent>>,
+        discovered_peers: mpsc::UnboundedSender<DialCandidate>,
+        disk_options: DiskOptions,
+    ) {
+        let context = self.context(&mut *torrent.lock().await, discovered_peers);
+        self.register(context, torrent, disk_options);
+    }
+
+    // The verified pieces of the torrent go to the disk of the context from now on.
+    fn context(
+        &self,
+        torrent: &mut Torrent,
+        discovered_peers: mpsc::UnboundedSender<DialCandidate>,
+    ) -> PendingContext {
+        let (writes, disk_writes) = mpsc::unbounded_channel();
+        torrent.set_disk_writes(writes);
+        // The peers of a hybrid torrent may handshake with either info hash
+        let info_hashes = match torrent.metainfo() {
+            Some(metainfo) => metainfo.info_hashes(),
+            None => vec![torrent.info_hash()],
+        };
+        PendingContext {
+            info_hash: torrent.info_hash(),
+            info_hashes,
+            private: torrent.is_private(),
+            activity: torrent.peer_activity(),
+            transfer: torrent.transfer_totals(),
+            discovered_peers,
+            disk_writes,
+        }
+    }
+
+    fn register(
+        &self,
+        pending: PendingContext,
+        torrent: Arc<tokio::sync::Mutex<Torrent>>,
+        disk_options: DiskOptions,
+    ) {
+        let (disk, events) = Disk::new(disk_options);
+        let context = TorrentContext::new(
+            torrent,
+            disk,
+            pending.discovered_peers,
+            self.dht.clone(),
+            pending.private,
+            pending.activity,
+            pending.transfer,
+        );
+        let context = match &self.external_ip {
+            Some(external_ip) => context.with_external_ip(external_ip.clone()),
+            None => context,
+        };
+        let context = match &self.disk_events {
+            Some(disk_events) => context.with_disk_events(pending.info_hash, disk_events.clone()),
+            None => context,
+        };
+        let context = match self.peer_timeout {
+            Some(peer_timeout) => context.with_peer_timeout(peer_timeout),
+            None => context,
+        };
+        let context = match self.starvation_timeout {
+            Some(starvation_timeout) => context.with_starvation_timeout(starvation_timeout),
+            None => context,
+        };
+        let context = Arc::new(context.with_utp(self.utp.clone()));
+        context.watch_disk(events);
+        context.write_pieces(pending.disk_writes);
+        let mut torrents = self.torrents.lock().unwrap();
+        for info_hash in pending.info_hashes {
+            torrents.insert(info_hash, context.clone());
+        }
+    }
+
+    // The incoming peers of the torrent are turned away from now on, the ones connected
+    // already are left to the torrent to disconnect.
+    pub fn remove_torrent(&self, info_hash: &Sha1Hash) {
+        let mut torrents = self.torrents.lock().unwrap();
+        let Some(context) = torrents.remove(info_hash) else {
+            return;
+        };
+        // The other info hash of a hybrid torrent
+        torrents.retain(|_, it| !Arc::ptr_eq(it, &context));
+    }
+
+    // Start the disk of the torrent over with the options, e.g. the profile changed the cache
+    // mode. Returns false if the torrent isn't served.
+    pub async fn restart_disk(&self, info_hash: &Sha1Hash, options: DiskOptions) -> bool {
+        let Some(context) = self.torrents.lock().unwrap().get(info_hash).cloned() else {
+            return false;
+        };
+        context.restart_disk(options).await;
+        true
+    }
+
+    // Delete the files of the torrent through the disk it's served with. Returns None if the
+    // torrent isn't served.
+    pub async fn delete_files(
+        &self,
+        info_hash: &Sha1Hash,
+        metainfo: MetaInfo,
+        mode: DeleteMode,
+    ) -> Option<Result<Vec<PathBuf>, DiskError>> {
+        let context = self.torrents.lock().unwrap().get(info_hash).cloned()?;
+        Some(context.delete_files(metainfo, mode).await)
+    }
+
+    // What we handshake the peers with.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    pub fn dht(&self) -> Option<Arc<Dht>> {
+        self.dht.clone()
+    }
+
+    pub fn contains(&self, info_hash: &Sha1Hash) -> bool {
+        self.torrents.lock().unwrap().contains_key(info_hash)
+    }
+
+    // Dial the peer for the torrent and serve it in the background, how it goes is sent to
+    // `events`. Returns false if the torrent isn't served.
+    pub fn dial(
+        &self,
+        info_hash: &Sha1Hash,
+        candidate: DialCandidate,
+        events: mpsc::UnboundedSender<DialEvent>,
+    ) -> bool {
+        match self.dial_session(info_hash, candidate, events) {
+            Some(session) => {
+                tokio::spawn(session);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Same as `dial`, the caller runs the session, e.g. to abort it when it's done with the
+    // torrent. None if the torrent isn't served.
+    pub(crate) fn dial_session(
+        &self,
+        info_hash: &Sha1Hash,
+        candidate: DialCandidate,
+        events: mpsc::UnboundedSender<DialEvent>,
+    ) -> Option<impl Future<Output = ()> + Send + 'static> {
+        let context = self.torrents.lock().unwrap().get(info_hash).cloned()?;
+        let info_hash = *info_hash;
+        let registry = self.clone();
+        Some(async move {
+            if registry.ip_filter.is_blocked(candidate.addr.ip()) {
+                // Failed right away, the peer manager backs off until it gives up on the peer
+                log::debug!("Didn't dial blocked peer {}", candidate.addr);
+                let _ = events.send(DialEvent::Failed {
+                    info_hash,
+                    candidate,
+                });
+                return;
+            }
+            let addr = candidate.addr;
+            let mut connected = false;
+            let result = serve_outgoing(
+                addr,
+                registry.encryption,
+                registry.peer_id,
+                registry.handshake_timeout,
+                context.clone(),
+                || {
+                    connected = true;
+                    let _ = events.send(DialEvent::Connected { info_hash, addr });
+                },
+            )
+            .await;
+            if let Err(e) = &result {
+                log::info!("Closed outgoing peer {}: {}", addr, e);
+            }
+            let event = if connected {
+                DialEvent::Disconnected {
+                    info_hash,
+                    addr,
+                    reason: result
+                        .err()
+                        .map_or(DisconnectReason::PeerClosed, |e| e.disconnect_reason()),
+                }
+            } else {
+                context.on_dial_failed(addr).await;
+                DialEvent::Failed {
+                    info_hash,
+                    candidate,
+                }
+            };
+            let _ = events.send(event);
+        })
+    }
+}
+
+impl PeerListener {
+    pub async fn bind(addr: impl ToSocketAddrs, peer_id: PeerId) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let utp = bind_utp(UdpSocket::bind(listener.local_addr()?).await);
+        Ok(Self::new(
+            vec![listener],
+            utp.into_iter().collect(),
+            peer_id,
+        ))
+    }
+
+    // On every v4 and v6 address, port 0 lets the OS choose one free on both. A host without v6
+    // only listens on v4.
+    pub async fn bind_dual_stack(port: u16, peer_id: PeerId) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+        let addr = listener.local_addr()?;
+        let mut listeners = vec![listener];
+        let mut utp: Vec<UtpSocket> = bind_utp(UdpSocket::bind(addr).await).into_iter().collect();
+        // The v6 sockets only take v6, the v4 ones own the v4-mapped addresses on the port
+        let v6_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), addr.port());
+        match bind_v6_only(v6_addr, Type::STREAM)
+            .and_then(|socket| TcpListener::from_std(std::net::TcpListener::from(socket)))
+        {
+            Ok(listener) => {
+                listeners.push(listener);
+                let socket = bind_v6_only(v6_addr, Type::DGRAM)
+                    .and_then(|socket| UdpSocket::from_std(std::net::UdpSocket::from(socket)));
+                utp.extend(bind_utp(socket));
+            }
+            Err(e) => log::warn!("Failed to listen on v6, only on v4: {:?}", e),
+        }
+        Ok(Self::new(listeners, utp, peer_id))
+    }
+
+    fn new(listeners: Vec<TcpListener>, utp: Vec<UtpSocket>, peer_id: PeerId) -> Self {
+        let port = listeners
+            .first()
+            .and_then(|it| it.local_addr().ok())
+            .map_or(0, |it| it.port());
+        let registry = TorrentRegistry {
+            utp: utp.clone(),
+            ..TorrentRegistry::new(peer_id)
+        };
+        Self {
+            listeners,
+            utp,
+            port: ListenPort::new(port),
+            registry,
+        }
+    }
+
+    // Listen on the address or the interface of the settings, on all of them if neither is set.
+    pub async fn bind_local(bind: &BindSettings, port: u16, peer_id: PeerId) -> io::Result<Self> {
+        if *bind == BindSettings::default() {
+            return Self::bind_dual_stack(port, peer_id).await;
+        }
+        let ip = match bind.local_addr(false) {
+            Ok(ip) => ip,
+            // The interface only has a v6 address
+            Err(_) if bind.interface.is_some() => bind.local_addr(true)?,
+            Err(e) => return Err(e),
+        };
+        let ip = ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        Self::bind(SocketAddr::new(ip, port), peer_id).await
+    }
+
+    // For the incoming and the dialed peers.
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.registry.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    // With `Require` the plaintext peers are dropped, otherwise both kinds are served.
+    // The dialed peers are encrypted unless it's `Allow`.
+    pub fn with_encryption(mut self, encryption: EncryptionPolicy) -> Self {
+        self.registry.encryption = encryption;
+        self
+    }
+
+    // Only applies to the torrents added after.
+    pub fn with_external_ip(mut self, external_ip: Arc<ExternalIp>) -> Self {
+        self.registry.external_ip = Some(external_ip);
+        self
+    }
+
+    // Only applies to the torrents added after.
+    pub fn with_dht(mut self, dht: Arc<Dht>) -> Self {
+        self.registry.dht = Some(dht);
+        self
+    }
+
+    // The disk failures and the trashed files of the torrents, by their info hash. Only applies
+    // to the torrents added after.
+    pub fn with_disk_events(
+        mut self,
+        disk_events: mpsc::UnboundedSender<(Sha1Hash, DiskEvent)>,
+    ) -> Self {
+        self.registry.disk_events = Some(disk_events);
+        self
+    }
+
+    // The blocked peers are closed before their handshake and never dialed, the filter may
+    // be replaced while the listener runs.
+    pub fn with_ip_filter(mut self, ip_filter: SharedIpFilter) -> Self {
+        self.registry.ip_filter = ip_filter;
+        self
+    }
+
+    // How long the connected peers may stay silent. Only applies to the torrents added after.
+    pub fn with_peer_timeout(mut self, peer_timeout: Duration) -> Self {
+        self.registry.peer_timeout = Some(peer_timeout);
+        self
+    }
+
+    // How long a session may wait for blocks while unchoked before it's kicked. Only applies
+    // to the torrents added after.
+    pub fn with_starvation_timeout(mut self, starvation_timeout: Duration) -> Self {
+        self.registry.starvation_timeout = Some(starvation_timeout);
+        self
+    }
+
+    // The first of the addresses, the v4 one on a dual-stack host.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(TcpListener::local_addr).collect()
+    }
+
+    // For the announcers, see `ListenPort`.
+    pub fn listen_port(&self) -> ListenPort {
+        self.port.clone()
+    }
+
+    pub fn add_torrent(&self, torrent: Torrent) -> Arc<tokio::sync::Mutex<Torrent>> {
+        self.registry.add_torrent(torrent)
+    }
+
+    // To add and remove the torrents once the listener runs.
+    pub fn registry(&self) -> TorrentRegistry {
+        self.registry.clone()
+    }
+
+    pub async fn run(self) -> io::Result<()> {
+        let mut incoming: Vec<BoxStream<io::Result<(PeerStream, SocketAddr)>>> = Vec::new();
+        for listener in self.listeners {
+            incoming.push(Box::pin(stream::unfold(listener, |listener| async move {
+                let result = listener
+                    .accept()
+                    .await
+                    .map(|(stream, addr)| (PeerStream::Tcp(stream), addr));
+                Some((result, listener))
+            })));
+        }
+        for socket in self.utp {
+            incoming.push(Box::pin(stream::unfold(socket, |socket| async move {
+                let result = socket.accept().await.and_then(|stream| {
+                    let addr = stream.peer_addr()?;
+                    Ok((PeerStream::Utp(stream), addr))
+                });
+                Some((result, socket))
+            })));
+        }
+        let mut incoming = stream::select_all(incoming);
+        while let Some(result) = incoming.next().await {
+            let (stream, addr) = result?;
+            if self.registry.ip_filter.is_blocked(addr.ip()) {
+                log::debug!("Refused blocked peer {}", addr);
+                continue;
+            }
+            let torrents = self.registry.torrents.clone();
+            let peer_id = self.registry.peer_id;
+            let handshake_timeout = self.registry.handshake_timeout;
+            let encryption = self.registry.encryption;
+            tokio::spawn(async move {
+                let info_hashes: Vec<Sha1Hash> = torrents.lock().unwrap().keys().copied().collect();
+                let find_torrent =
+                    |info_hash: &Sha1Hash| torrents.lock().unwrap().get(info_hash).cloned();
+                if let Err(e) = serve_incoming(
+                    stream,
+                    peer_id,
+                    handshake_timeout,
+                    encryption,
+                    &info_hashes,
+                    find_torrent,
+                )
+                .await
+                {
+                    log::warn!("Closed incoming peer {}: {}", addr, e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+// Still take the TCP peers if the UDP port is taken.
+fn bind_utp(socket: io::Result<UdpSocket>) -> Option<UtpSocket> {
+    match socket {
+        Ok(socket) => Some(UtpSocket::from_socket(socket)),
+        Err(e) => {
+            log::warn!("Failed to listen for uTP peers: {:?}", e);
+            None
+        }
+    }
+}
+
+// Linux lets the v6 sockets take the v4 peers too by default, which takes the port of the v4
+// socket bound already.
+fn bind_v6_only(addr: SocketAddr, socket_type: Type) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV6, socket_type, None)?;
+    socket.set_only_v6(true)?;
+    socket.set_nonblocking(true)?;
+    if socket_type == Type::STREAM {
+        // As tokio sets on the v4 listener
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&addr.into())?;
+    if socket_type == Type::STREAM {
+        socket.listen(1024)?;
+    }
+    Ok(socket)
+}
@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+
+// When to give up on a torrent nobody is sharing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeadTorrentPolicy {
+    // The torrent is dead after it had no connectable peers and no seeders for this long.
+    pub dead_after: Duration,
+    // Announce the dead torrent less often, most trackers will never have a seeder for it.
+    pub backoff_announces: bool,
+    pub announce_backoff: Duration,
+}
+
+impl Default for DeadTorrentPolicy {
+    fn default() -> Self {
+        Self {
+            dead_after: Duration::from_secs(24 * 60 * 60),
+            backoff_announces: false,
+            announce_backoff: Duration::from_secs(6 * 60 * 60),
+        }
+    }
+}
+
+// Track when the swarm of a torrent was last seen alive.
+pub struct SwarmLiveness {
+    last_alive: Instant,
+}
+
+impl Default for SwarmLiveness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SwarmLiveness {
+    pub fn new() -> Self {
+        Self {
+            last_alive: Instant::now(),
+        }
+    }
+
+    // Report what we know about the swarm, e.g. after an announce or a dial round.
+    pub fn record(&mut self, connectable_peers: usize, seeders: Option<u32>) {
+        if connectable_peers > 0 || seeders.is_some_and(|it| it > 0) {
+            self.last_alive = Instant::now();
+        }
+    }
+
+    pub fn is_dead(&self, policy: &DeadTorrentPolicy) -> bool {
+        self.last_alive.elapsed() >= policy.dead_after
+    }
+
+    // The interval to wait before the next announce, given the one the tracker asked for.
+    pub fn announce_interval(&self, policy: &DeadTorrentPolicy, interval: Duration) -> Duration {
+        if policy.backoff_announces && self.is_dead(policy) {
+            interval.max(policy.announce_backoff)
+        } else {
+            interval
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dead_torrent() {
+        let policy = DeadTorrentPolicy {
+            dead_after: Duration::from_millis(50),
+            backoff_announces: true,
+            announce_backoff: Duration::from_secs(3600),
+        };
+        let interval = Duration::from_secs(1800);
+        let mut liveness = SwarmLiveness::new();
+        assert!(!liveness.is_dead(&policy));
+
+        std::thread::sleep(Duration::from_millis(60));
+        liveness.record(0, Some(0));
+        assert!(liveness.is_dead(&policy));
+        assert_eq!(
+            liveness.announce_interval(&policy, interval),
+            Duration::from_secs(3600)
+        );
+
+        // A seeder shows up again
+        liveness.record(0, Some(1));
+        assert!(!liveness.is_dead(&policy));
+        assert_eq!(liveness.announce_interval(&policy, interval), interval);
+    }
+}
@@ -0,0 +1,238 @@
+use thiserror::Error;
+use url::Url;
+
+use crate::types::Sha1Hash;
+
+const MAX_SELECT_RANGE: usize = 100_000;
+
+pub(crate) type Result<T> = std::result::Result<T, MagnetError>;
+
+#[derive(Error, Debug)]
+pub enum MagnetError {
+    #[error("Failed to parse magnet URI")]
+    InvalidUri(#[from] url::ParseError),
+
+    #[error("Not a magnet URI")]
+    InvalidScheme,
+
+    #[error("Magnet URI has no BitTorrent info hash")]
+    MissingInfoHash,
+
+    #[error("Invalid info hash in magnet URI")]
+    InvalidInfoHash,
+}
+
+// A torrent identified by its info hash only, the info dict is fetched from peers later.
+// https://www.bittorrent.org/beps/bep_0009.html#magnet-uri-format
+#[derive(Debug, Clone)]
+pub struct MagnetLink {
+    pub info_hash: Sha1Hash,
+    pub display_name: Option<String>,
+    pub trackers: Vec<Url>,
+    // Only download these files, by their index in the info dict. None selects all.
+    // https://www.bittorrent.org/beps/bep_0053.html
+    pub select_only: Option<Vec<usize>>,
+    // Peers to dial directly as host:port, e.g. the seed of a trackerless private swarm.
+    pub peers: Vec<String>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let url = Url::parse(uri)?;
+        if url.scheme() != "magnet" {
+            return Err(MagnetError::InvalidScheme);
+        }
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        let mut select_only = None;
+        let mut peers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => display_name = Some(value.to_string()),
+                "tr" => match Url::parse(&value) {
+                    Ok(tracker) => trackers.push(tracker),
+                    Err(e) => log::warn!("Ignore invalid tracker {} in magnet: {:?}", value, e),
+                },
+                "so" => match parse_file_indices(&value) {
+                    Some(indices) => select_only = Some(indices),
+                    None => log::warn!("Ignore invalid select-only {} in magnet", value),
+                },
+                "x.pe" => {
+                    if is_peer_address(&value) {
+                        peers.push(value.to_string());
+                    } else {
+                        log::warn!("Ignore invalid peer {} in magnet", value);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+            display_name,
+            select_only,
+            trackers,
+            peers,
+        })
+    }
+}
+
+// Either hostname:port, ipv4:port or [ipv6]:port.
+fn is_peer_address(value: &str) -> bool {
+    let Some((host, port)) = value.rsplit_once(':') else {
+        return false;
+    };
+    let is_valid_port = port.parse::<u16>().is_ok_and(|it| it != 0);
+    is_valid_port && url::Host::parse(host).is_ok()
+}
+
+// Comma separated indices and inclusive ranges, e.g. 0,2,4-6.
+fn parse_file_indices(value: &str) -> Option<Vec<usize>> {
+    let mut indices = Vec::new();
+    for part in value.split(',') {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (start.parse().ok()?, end.parse::<usize>().ok()?);
+                // A range covering millions of files is not a real selection
+                if start > end || end - start > MAX_SELECT_RANGE {
+                    return None;
+                }
+                indices.extend(start..=end);
+            }
+            None => indices.push(part.parse().ok()?),
+        }
+    }
+    indices.sort_unstable();
+    indices.dedup();
+    Some(indices)
+}
+
+// The info hash is either 40 chars hex or 32 chars base32 encoded.
+fn parse_info_hash(value: &str) -> Result<Sha1Hash> {
+    let bytes = match value.len() {
+        40 => decode_hex(value),
+        32 => decode_base32(value),
+        _ => None,
+    }
+    .ok_or(MagnetError::InvalidInfoHash)?;
+    bytes.try_into().map_err(|_| MagnetError::InvalidInfoHash)
+}
+
+pub(crate) fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn decode_base32(value: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut bytes = Vec::with_capacity(value.len() * 5 / 8);
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    for c in value.bytes() {
+        let index = ALPHABET
+            .iter()
+            .position(|it| *it == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | index as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: Sha1Hash = [
+        0xc9, 0xe1, 0x57, 0x63, 0xf7, 0x22, 0xf2, 0x3e, 0x98, 0xa2, 0x9d, 0xec, 0xdf, 0xae, 0x34,
+        0x1b, 0x98, 0xd5, 0x30, 0x56,
+    ];
+
+    #[test]
+    fn test_parse_hex_magnet() {
+        let magnet = MagnetLink::parse(
+            "magnet:?xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056&dn=Cosmos+Laundromat&tr=udp%3A%2F%2Fexplodie.org%3A6969&tr=wss%3A%2F%2Ftracker.btorrent.xyz",
+        )
+        .unwrap();
+        assert_eq!(magnet.info_hash, INFO_HASH);
+        assert_eq!(magnet.display_name.as_deref(), Some("Cosmos Laundromat"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                Url::parse("udp://explodie.org:6969").unwrap(),
+                Url::parse("wss://tracker.btorrent.xyz").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_base32_magnet() {
+        let magnet =
+            MagnetLink::parse("magnet:?xt=urn:btih:ZHQVOY7XELZD5GFCTXWN7LRUDOMNKMCW").unwrap();
+        assert_eq!(magnet.info_hash, INFO_HASH);
+        assert!(magnet.trackers.is_empty());
+        assert_eq!(magnet.select_only, None);
+        assert!(magnet.peers.is_empty());
+    }
+
+    #[test]
+    fn test_parse_peers() {
+        let magnet = MagnetLink::parse(
+            "magnet:?xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056&x.pe=10.0.0.1:6881&x.pe=seed.example.com:51413&x.pe=%5B::1%5D:6881&x.pe=nope&x.pe=10.0.0.1:0",
+        )
+        .unwrap();
+        assert_eq!(
+            magnet.peers,
+            vec!["10.0.0.1:6881", "seed.example.com:51413", "[::1]:6881"]
+        );
+    }
+
+    #[test]
+    fn test_parse_select_only() {
+        let magnet = MagnetLink::parse(
+            "magnet:?xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056&so=0,2,4,6-8,2",
+        )
+        .unwrap();
+        assert_eq!(magnet.select_only, Some(vec![0, 2, 4, 6, 7, 8]));
+
+        for invalid in ["8-6", "a", "1,,2", "0-999999999"] {
+            let magnet = MagnetLink::parse(&format!(
+                "magnet:?xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056&so={}",
+                invalid
+            ))
+            .unwrap();
+            assert_eq!(magnet.select_only, None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_magnet() {
+        assert!(matches!(
+            MagnetLink::parse(
+                "http://example.com/?xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056"
+            ),
+            Err(MagnetError::InvalidScheme)
+        ));
+        assert!(matches!(
+            MagnetLink::parse("magnet:?dn=foo"),
+            Err(MagnetError::MissingInfoHash)
+        ));
+        assert!(matches!(
+            MagnetLink::parse("magnet:?xt=urn:btih:1234"),
+            Err(MagnetError::InvalidInfoHash)
+        ));
+    }
+}
@@ -3,19 +3,112 @@ use std::io;
 use bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::types::{BitField, PeerId, Sha1Hash};
+use crate::{
+    extension,
+    types::{BitField, PeerId, Sha1Hash},
+};
 
 const PROTOCOL_STRING: &[u8] = b"BitTorrent protocol";
 
+// The 20th bit from the right of the reserved bytes, means the peer supports the extension protocol.
+// https://www.bittorrent.org/beps/bep_0010.html
+const EXTENSION_PROTOCOL_BIT: (usize, u8) = (5, 0x10);
+
+// The last bit of the reserved bytes, means the peer runs a DHT node and accepts the PORT message.
+// https://www.bittorrent.org/beps/bep_0005.html#bittorrent-protocol-extension
+const DHT_BIT: (usize, u8) = (7, 0x01);
+
+// The 3rd least significant bit of the last reserved byte, means the peer supports the fast
+// extension, e.g. have all and reject request.
+// https://www.bittorrent.org/beps/bep_0006.html
+const FAST_BIT: (usize, u8) = (7, 0x04);
+
+// The 4th most significant bit of the last reserved byte, means the peer supports BitTorrent v2.
+// https://www.bittorrent.org/beps/bep_0052.html#upgrade-path
+const V2_BIT: (usize, u8) = (7, 0x10);
+
+const KNOWN_BITS: [(usize, u8); 4] = [EXTENSION_PROTOCOL_BIT, DHT_BIT, FAST_BIT, V2_BIT];
+
+// The largest message we accept, a Piece of 128 KiB or the bitfield of a torrent
+// with a million pieces still fit. Anything bigger is a broken or hostile peer.
+pub const MAX_MESSAGE_LENGTH: usize = 1024 * 1024;
+
+// What the reserved bytes of the handshake say the side supports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub extension_protocol: bool,
+    pub dht: bool,
+    pub fast: bool,
+    pub v2: bool,
+    // The bits we don't know, kept so the reserved bytes go out as they came in.
+    unknown: [u8; 8],
+}
+
+impl Capabilities {
+    pub fn from_reserved(reserved: [u8; 8]) -> Self {
+        let is_set = |(index, mask): (usize, u8)| reserved[index] & mask != 0;
+        let mut unknown = reserved;
+        for (index, mask) in KNOWN_BITS {
+            unknown[index] &= !mask;
+        }
+        Self {
+            extension_protocol: is_set(EXTENSION_PROTOCOL_BIT),
+            dht: is_set(DHT_BIT),
+            fast: is_set(FAST_BIT),
+            v2: is_set(V2_BIT),
+            unknown,
+        }
+    }
+
+    pub fn to_reserved(self) -> [u8; 8] {
+        let mut reserved = self.unknown;
+        let bits = [
+            (EXTENSION_PROTOCOL_BIT, self.extension_protocol),
+            (DHT_BIT, self.dht),
+            (FAST_BIT, self.fast),
+            (V2_BIT, self.v2),
+        ];
+        for ((index, mask), set) in bits {
+            if set {
+                reserved[index] |= mask;
+            }
+        }
+        reserved
+    }
+}
+
 pub struct HandShake {
+    pub capabilities: Capabilities,
     pub info_hash: Sha1Hash,
     pub peer_id: PeerId,
 }
 
 // https://www.bittorrent.org/beps/bep_0003.html#peer-protocol
 impl HandShake {
+    // We always speak the extension protocol, the fast extension isn't implemented so its bit
+    // stays off.
     pub fn new(info_hash: Sha1Hash, peer_id: PeerId) -> Self {
-        Self { info_hash, peer_id }
+        Self {
+            capabilities: Capabilities {
+                extension_protocol: true,
+                ..Default::default()
+            },
+            info_hash,
+            peer_id,
+        }
+    }
+
+    // Only set when we run a DHT node.
+    pub fn with_dht(mut self) -> Self {
+        self.capabilities.dht = true;
+        self
+    }
+
+    // Only set for the v2 and hybrid torrents, the info hash is the truncated SHA-256 one
+    // when talking to the v2 swarm.
+    pub fn with_v2(mut self) -> Self {
+        self.capabilities.v2 = true;
+        self
     }
 }
 
@@ -28,7 +121,7 @@ impl Encoder<HandShake> for HandShakeCodec {
         dst.reserve(68);
         dst.put_u8(19u8);
         dst.extend_from_slice(PROTOCOL_STRING);
-        dst.extend_from_slice(&[0u8; 8]); // reserved bytes
+        dst.extend_from_slice(&item.capabilities.to_reserved());
         dst.extend_from_slice(&item.info_hash);
         dst.extend_from_slice(&item.peer_id);
         Ok(())
@@ -52,13 +145,19 @@ impl Decoder for HandShakeCodec {
             ));
         }
 
-        src.advance(8); // Skip reserved bytes
+        src.advance(PROTOCOL_STRING.len());
+        let mut reserved = [0u8; 8];
+        src.copy_to_slice(reserved.as_mut());
         let mut info_hash: Sha1Hash = [0; 20];
         src.copy_to_slice(info_hash.as_mut());
         let mut peer_id: PeerId = [0; 20];
         src.copy_to_slice(peer_id.as_mut());
 
-        Ok(Some(HandShake::new(info_hash, peer_id)))
+        Ok(Some(HandShake {
+            capabilities: Capabilities::from_reserved(reserved),
+            info_hash,
+            peer_id,
+        }))
     }
 }
 
@@ -83,6 +182,8 @@ pub enum MessageId {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Port = 9,
+    Extended = 20,
 }
 
 impl TryFrom<u8> for MessageId {
@@ -99,6 +200,8 @@ impl TryFrom<u8> for MessageId {
             6 => Ok(MessageId::Request),
             7 => Ok(MessageId::Piece),
             8 => Ok(MessageId::Cancel),
+            9 => Ok(MessageId::Port),
+            20 => Ok(MessageId::Extended),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "Unknown message ID",
@@ -135,6 +238,16 @@ pub enum Message {
         begin: u32,
         length: u32,
     },
+    // The UDP port of the peer's DHT node
+    // https://www.bittorrent.org/beps/bep_0005.html
+    Port {
+        port: u16,
+    },
+    // https://www.bittorrent.org/beps/bep_0010.html
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
 }
 
 impl Message {
@@ -152,6 +265,10 @@ impl Message {
             Message::Piece { piece, .. } => 9 + piece.len(),
             // 1 byte for ID + 4 bytes for piece index + 4 bytes for begin + 4 bytes for length
             Message::Cancel { .. } => 13,
+            // 1 byte for ID + 2 bytes for port
+            Message::Port { .. } => 3,
+            // 1 byte for ID + 1 byte for extended message ID + length of payload
+            Message::Extended { payload, .. } => 2 + payload.len(),
         }
     }
 
@@ -167,6 +284,8 @@ impl Message {
             Message::Request { .. } => Some(MessageId::Request),
             Message::Piece { .. } => Some(MessageId::Piece),
             Message::Cancel { .. } => Some(MessageId::Cancel),
+            Message::Port { .. } => Some(MessageId::Port),
+            Message::Extended { .. } => Some(MessageId::Extended),
         }
     }
 
@@ -216,6 +335,13 @@ impl Message {
                 buffer.extend_from_slice(&length.to_be_bytes());
                 Some(buffer)
             }
+            Message::Port { port } => Some(port.to_be_bytes().to_vec()),
+            Message::Extended { id, payload } => {
+                let mut buffer = Vec::with_capacity(1 + payload.len());
+                buffer.push(*id);
+                buffer.extend_from_slice(payload);
+                Some(buffer)
+            }
         }
     }
 }
@@ -250,6 +376,22 @@ impl Decoder for MessageCodec {
 
         // length include the message ID and payload
         let length = (&src[..4]).get_u32() as usize;
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Message is too large",
+            ));
+        }
+        // Reject the oversized extended message as soon as we know its ID,
+        // instead of buffering the whole payload.
+        if length > 2 + extension::MAX_PAYLOAD_LENGTH
+            && src.get(4) == Some(&(MessageId::Extended as u8))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Extended message is too large",
+            ));
+        }
         if src.len() < 4 + length {
             return Ok(None); // Not enough data for the full message
         }
@@ -305,6 +447,154 @@ impl Decoder for MessageCodec {
                     length,
                 }))
             }
+            MessageId::Port => {
+                // Anything but the port would be left in the buffer and misframe the next messages
+                if length != 3 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Port message has the wrong length",
+                    ));
+                }
+                let port = src.get_u16();
+                Ok(Some(Message::Port { port }))
+            }
+            MessageId::Extended => {
+                // The extended message id is missing, there is nothing to read it from
+                if length < 2 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Extended message is too short",
+                    ));
+                }
+                let id = src.get_u8();
+                let payload = src.split_to(length - 2).to_vec(); // 2 bytes for message_id and extended message id
+                Ok(Some(Message::Extended { id, payload }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake_roundtrip() {
+        let mut buffer = BytesMut::new();
+        HandShakeCodec
+            .encode(HandShake::new([1u8; 20], [2u8; 20]), &mut buffer)
+            .unwrap();
+        assert_eq!(buffer.len(), 68);
+
+        let handshake = HandShakeCodec.decode(&mut buffer).unwrap().unwrap();
+        assert!(handshake.capabilities.extension_protocol);
+        assert!(!handshake.capabilities.v2);
+        assert_eq!(handshake.info_hash, [1u8; 20]);
+        assert_eq!(handshake.peer_id, [2u8; 20]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_handshake_reserved_bits() {
+        let handshake = HandShake::new([1u8; 20], [2u8; 20]).with_dht().with_v2();
+        assert_eq!(
+            handshake.capabilities.to_reserved(),
+            [0, 0, 0, 0, 0, 0x10, 0, 0x11]
+        );
+        assert!(handshake.capabilities.dht);
+        assert!(handshake.capabilities.v2);
+        assert!(!handshake.capabilities.fast);
+    }
+
+    #[test]
+    fn test_capabilities_roundtrip() {
+        // Extension protocol, fast extension and a bit we don't know
+        let reserved = [0x80, 0, 0, 0, 0, 0x10, 0, 0x04];
+        let mut buffer = BytesMut::new();
+        buffer.put_u8(19);
+        buffer.extend_from_slice(PROTOCOL_STRING);
+        buffer.extend_from_slice(&reserved);
+        buffer.extend_from_slice(&[1u8; 40]);
+        let handshake = HandShakeCodec.decode(&mut buffer).unwrap().unwrap();
+        let capabilities = handshake.capabilities;
+        assert!(capabilities.extension_protocol && capabilities.fast);
+        assert!(!capabilities.dht && !capabilities.v2);
+        assert_eq!(capabilities.to_reserved(), reserved);
+    }
+
+    #[test]
+    fn test_extended_message_roundtrip() {
+        let mut buffer = BytesMut::new();
+        MessageCodec
+            .encode(
+                Message::Extended {
+                    id: 1,
+                    payload: b"d8:msg_typei0e5:piecei0ee".to_vec(),
+                },
+                &mut buffer,
+            )
+            .unwrap();
+
+        match MessageCodec.decode(&mut buffer).unwrap() {
+            Some(Message::Extended { id, payload }) => {
+                assert_eq!(id, 1);
+                assert_eq!(payload, b"d8:msg_typei0e5:piecei0ee");
+            }
+            _ => panic!("Expected extended message"),
+        }
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_port_message_roundtrip() {
+        let mut buffer = BytesMut::new();
+        MessageCodec
+            .encode(Message::Port { port: 6881 }, &mut buffer)
+            .unwrap();
+        assert_eq!(&buffer[..], &[0, 0, 0, 3, 9, 0x1a, 0xe1]);
+
+        match MessageCodec.decode(&mut buffer).unwrap() {
+            Some(Message::Port { port }) => assert_eq!(port, 6881),
+            _ => panic!("Expected port message"),
         }
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_port_message_with_wrong_length() {
+        for payload in [&[0x1a][..], &[0x1a, 0xe1, 0]] {
+            let mut buffer = BytesMut::new();
+            buffer.put_u32(1 + payload.len() as u32);
+            buffer.put_u8(MessageId::Port as u8);
+            buffer.extend_from_slice(payload);
+            assert_eq!(
+                MessageCodec.decode(&mut buffer).unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+    }
+
+    #[test]
+    fn test_short_extended_message() {
+        let mut buffer = BytesMut::new();
+        buffer.put_u32(1);
+        buffer.put_u8(MessageId::Extended as u8);
+        assert_eq!(
+            MessageCodec.decode(&mut buffer).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_oversized_extended_message() {
+        let mut buffer = BytesMut::new();
+        // Only the length prefix and IDs arrived, the payload is not buffered yet
+        buffer.put_u32(1024 * 1024);
+        buffer.put_u8(MessageId::Extended as u8);
+        buffer.put_u8(1);
+        assert_eq!(
+            MessageCodec.decode(&mut buffer).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
     }
 }
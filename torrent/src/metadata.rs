@@ -0,0 +1,335 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{extension::bencode_value_length, hash::calculate_sha1_hash, types::Sha1Hash};
+
+// Implementation of the ut_metadata extension, to fetch the info dict from peers.
+// https://www.bittorrent.org/beps/bep_0009.html
+
+pub(crate) type Result<T> = std::result::Result<T, MetadataError>;
+
+// The info dict is transferred in 16KiB pieces, the last piece may be smaller.
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+// Don't let a peer make us allocate for a huge info dict, see `MetaInfoLimits`.
+pub const DEFAULT_MAX_METADATA_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum MetadataError {
+    #[error("Failed to parse metadata message")]
+    Bencode(#[from] serde_bencode::Error),
+    #[error("Invalid metadata message")]
+    InvalidMessage,
+    #[error("Invalid metadata size")]
+    InvalidSize,
+    #[error("Metadata is larger than the limit")]
+    TooLarge,
+    #[error("Invalid metadata piece")]
+    InvalidPiece,
+    #[error("Metadata doesn't match the info hash")]
+    InvalidHash,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataMessage {
+    Request {
+        piece: u32,
+    },
+    Data {
+        piece: u32,
+        total_size: u64,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: u32,
+    },
+}
+
+mod raw {
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct MetadataMessage {
+        pub msg_type: u8,
+        pub piece: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub total_size: Option<u64>,
+    }
+}
+
+impl MetadataMessage {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let (msg_type, piece, total_size, data) = match self {
+            MetadataMessage::Request { piece } => (0, *piece, None, None),
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => (1, *piece, Some(*total_size), Some(data)),
+            MetadataMessage::Reject { piece } => (2, *piece, None, None),
+        };
+        let mut bytes = serde_bencode::to_bytes(&raw::MetadataMessage {
+            msg_type,
+            piece,
+            total_size,
+        })?;
+        // The piece data is appended right after the dict
+        if let Some(data) = data {
+            bytes.extend_from_slice(data);
+        }
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let dict_length = bencode_value_length(bytes).ok_or(MetadataError::InvalidMessage)?;
+        let message: raw::MetadataMessage = serde_bencode::from_bytes(&bytes[..dict_length])?;
+        match message.msg_type {
+            0 => Ok(MetadataMessage::Request {
+                piece: message.piece,
+            }),
+            1 => {
+                let data = &bytes[dict_length..];
+                if data.len() > METADATA_PIECE_SIZE {
+                    return Err(MetadataError::InvalidPiece);
+                }
+                Ok(MetadataMessage::Data {
+                    piece: message.piece,
+                    total_size: message.total_size.ok_or(MetadataError::InvalidMessage)?,
+                    data: data.to_vec(),
+                })
+            }
+            2 => Ok(MetadataMessage::Reject {
+                piece: message.piece,
+            }),
+            _ => Err(MetadataError::InvalidMessage),
+        }
+    }
+}
+
+// The piece of the info dict to answer a request with, None if it's out of range.
+pub fn metadata_piece(info_bytes: &[u8], piece: u32) -> Option<&[u8]> {
+    info_bytes.chunks(METADATA_PIECE_SIZE).nth(piece as usize)
+}
+
+#[derive(Clone, PartialEq)]
+enum PieceState {
+    Missing,
+    Requested,
+    Received(Vec<u8>),
+}
+
+// Collect the info dict pieces from peers and verify it against the info hash.
+pub struct MetadataDownloader {
+    info_hash: Sha1Hash,
+    total_size: usize,
+    max_size: usize,
+    pieces: Vec<PieceState>,
+}
+
+impl MetadataDownloader {
+    pub fn new(info_hash: Sha1Hash) -> Self {
+        Self {
+            info_hash,
+            total_size: 0,
+            max_size: DEFAULT_MAX_METADATA_SIZE,
+            pieces: Vec::new(),
+        }
+    }
+
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    pub fn info_hash(&self) -> Sha1Hash {
+        self.info_hash
+    }
+
+    // The size is only known after a peer tells us in the extension handshake.
+    pub fn is_size_known(&self) -> bool {
+        self.total_size != 0
+    }
+
+    pub fn set_total_size(&mut self, total_size: usize) -> Result<()> {
+        if total_size == 0 {
+            return Err(MetadataError::InvalidSize);
+        }
+        if total_size > self.max_size {
+            return Err(MetadataError::TooLarge);
+        }
+        if self.is_size_known() {
+            return if self.total_size == total_size {
+                Ok(())
+            } else {
+                Err(MetadataError::InvalidSize)
+            };
+        }
+        self.total_size = total_size;
+        self.pieces = vec![PieceState::Missing; total_size.div_ceil(METADATA_PIECE_SIZE)];
+        Ok(())
+    }
+
+    // Pick the next piece to request, and mark it as requested.
+    pub fn next_request(&mut self) -> Option<u32> {
+        let index = self
+            .pieces
+            .iter()
+            .position(|it| *it == PieceState::Missing)?;
+        self.pieces[index] = PieceState::Requested;
+        Some(index as u32)
+    }
+
+    // The peer rejected or disconnected before sending the piece, request it again later.
+    pub fn reset_request(&mut self, piece: u32) {
+        if let Some(state) = self.pieces.get_mut(piece as usize)
+            && *state == PieceState::Requested
+        {
+            *state = PieceState::Missing;
+        }
+    }
+
+    // Returns the whole info dict once all pieces are received and verified.
+    pub fn add_piece(
+        &mut self,
+        piece: u32,
+        total_size: u64,
+        data: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        if total_size as usize != self.total_size {
+            return Err(MetadataError::InvalidSize);
+        }
+        let index = piece as usize;
+        let expected_length = if index + 1 == self.pieces.len() {
+            self.total_size - index * METADATA_PIECE_SIZE
+        } else {
+            METADATA_PIECE_SIZE
+        };
+        if index >= self.pieces.len() || data.len() != expected_length {
+            return Err(MetadataError::InvalidPiece);
+        }
+        self.pieces[index] = PieceState::Received(data);
+
+        if !self
+            .pieces
+            .iter()
+            .all(|it| matches!(it, PieceState::Received(_)))
+        {
+            return Ok(None);
+        }
+
+        let mut info = Vec::with_capacity(self.total_size);
+        for piece in &self.pieces {
+            if let PieceState::Received(data) = piece {
+                info.extend_from_slice(data);
+            }
+        }
+        if calculate_sha1_hash(info.clone()) != self.info_hash {
+            // Start over, one of the peers sent us garbage.
+            self.pieces.fill(PieceState::Missing);
+            return Err(MetadataError::InvalidHash);
+        }
+        Ok(Some(info))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_message_roundtrip() {
+        let messages = vec![
+            MetadataMessage::Request { piece: 0 },
+            MetadataMessage::Data {
+                piece: 1,
+                total_size: 20000,
+                data: vec![1, 2, 3],
+            },
+            MetadataMessage::Reject { piece: 2 },
+        ];
+        for message in messages {
+            let bytes = message.to_bytes().unwrap();
+            assert_eq!(MetadataMessage::from_bytes(&bytes).unwrap(), message);
+        }
+        assert_eq!(
+            MetadataMessage::Request { piece: 0 }.to_bytes().unwrap(),
+            b"d8:msg_typei0e5:piecei0ee"
+        );
+    }
+
+    #[test]
+    fn test_oversized_metadata_piece() {
+        let bytes = MetadataMessage::Data {
+            piece: 0,
+            total_size: 40000,
+            data: vec![0; METADATA_PIECE_SIZE + 1],
+        }
+        .to_bytes()
+        .unwrap();
+        assert!(matches!(
+            MetadataMessage::from_bytes(&bytes),
+            Err(MetadataError::InvalidPiece)
+        ));
+    }
+
+    #[test]
+    fn test_download_metadata() {
+        let info: Vec<u8> = (0..METADATA_PIECE_SIZE + 100).map(|i| i as u8).collect();
+        let info_hash = calculate_sha1_hash(info.clone());
+        let mut downloader = MetadataDownloader::new(info_hash);
+        assert_eq!(downloader.next_request(), None);
+
+        downloader.set_total_size(info.len()).unwrap();
+        assert_eq!(downloader.next_request(), Some(0));
+        assert_eq!(downloader.next_request(), Some(1));
+        assert_eq!(downloader.next_request(), None);
+        downloader.reset_request(1);
+        assert_eq!(downloader.next_request(), Some(1));
+
+        let total_size = info.len() as u64;
+        let result = downloader
+            .add_piece(1, total_size, info[METADATA_PIECE_SIZE..].to_vec())
+            .unwrap();
+        assert!(result.is_none());
+        let result = downloader
+            .add_piece(0, total_size, info[..METADATA_PIECE_SIZE].to_vec())
+            .unwrap();
+        assert_eq!(result, Some(info));
+    }
+
+    #[test]
+    fn test_download_metadata_invalid_hash() {
+        let mut downloader = MetadataDownloader::new([0u8; 20]);
+        downloader.set_total_size(10).unwrap();
+        assert!(downloader.set_total_size(11).is_err());
+        assert!(matches!(
+            downloader.add_piece(0, 10, vec![0; 9]),
+            Err(MetadataError::InvalidPiece)
+        ));
+        assert!(matches!(
+            downloader.add_piece(0, 10, vec![0; 10]),
+            Err(MetadataError::InvalidHash)
+        ));
+        // Pieces are requested again after the hash check failed
+        assert_eq!(downloader.next_request(), Some(0));
+    }
+
+    #[test]
+    fn test_metadata_size_limit() {
+        let mut downloader = MetadataDownloader::new([0u8; 20]);
+        assert!(matches!(
+            downloader.set_total_size(DEFAULT_MAX_METADATA_SIZE + 1),
+            Err(MetadataError::TooLarge)
+        ));
+        downloader.set_max_size(DEFAULT_MAX_METADATA_SIZE * 2);
+        downloader
+            .set_total_size(DEFAULT_MAX_METADATA_SIZE + 1)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_metadata_piece() {
+        let info: Vec<u8> = (0..METADATA_PIECE_SIZE + 10).map(|it| it as u8).collect();
+        assert_eq!(metadata_piece(&info, 0), Some(&info[..METADATA_PIECE_SIZE]));
+        assert_eq!(metadata_piece(&info, 1), Some(&info[METADATA_PIECE_SIZE..]));
+        assert_eq!(metadata_piece(&info, 2), None);
+    }
+}
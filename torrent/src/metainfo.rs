@@ -1,9 +1,17 @@
 use serde::{Deserialize, Serialize};
-use std::fmt;
+use serde_bencode::value::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Component, Path},
+};
 use thiserror::Error;
 use url::Url;
 
-use crate::types::Sha1Hash;
+use crate::{
+    hash::{calculate_sha1_hash, calculate_sha256_hash},
+    types::{Sha1Hash, Sha256Hash},
+};
 
 pub(crate) type Result<T> = std::result::Result<T, MetaInfoError>;
 
@@ -14,54 +22,591 @@ pub enum MetaInfoError {
 
     #[error("Failed to parse URL")]
     InvalidAnnounce(#[from] url::ParseError),
+
+    #[error("Invalid file tree")]
+    InvalidFileTree,
+
+    // A file or a symlink target which would be placed outside of the save path.
+    #[error("Invalid file path {0:?}")]
+    InvalidPath(Vec<String>),
+
+    #[error("Invalid piece length")]
+    InvalidPieceLength,
+
+    // Not a multiple of 20 bytes, or not one hash for each piece of the length.
+    #[error("Invalid piece hashes")]
+    InvalidPieces,
+
+    #[error("The torrent has no length, files nor file tree")]
+    MissingFiles,
+
+    #[error("The torrent is larger than the limit")]
+    TooLarge,
+
+    #[error("The torrent has {0} pieces, more than the limit")]
+    TooManyPieces(usize),
+
+    #[error("The torrent has {0} files, more than the limit")]
+    TooManyFiles(usize),
+
+    // A v2 only torrent has no other hashes to verify the pieces of the file with, e.g. it's
+    // started from a magnet link and the peers only sent the info dict.
+    #[error("The piece layer of {0} is missing")]
+    MissingPieceLayer(String),
+}
+
+// Bounds of what we accept, a malicious torrent or peer could otherwise make us allocate
+// for billions of pieces from a few bytes, e.g. a huge length with a tiny piece length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetaInfoLimits {
+    // The whole .torrent file, the v2 piece layers make it much larger than the info dict.
+    pub max_torrent_size: usize,
+    // The info dict, which is also the metadata fetched from the peers by ut_metadata.
+    pub max_info_size: usize,
+    pub max_pieces: usize,
+    pub max_files: usize,
+}
+
+impl Default for MetaInfoLimits {
+    fn default() -> Self {
+        Self {
+            max_torrent_size: 64 * 1024 * 1024,
+            max_info_size: 8 * 1024 * 1024,
+            max_pieces: 0x200000,
+            max_files: 100_000,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct MetaInfo {
-    pub announce: Url,
+    // Magnet links and trackerless torrents don't have an announce url.
+    pub announce: Option<Url>,
+    // Tiers of trackers, the `announce` is ignored by the clients supporting it.
+    // https://www.bittorrent.org/beps/bep_0012.html
+    pub announce_list: Vec<Vec<Url>>,
+    // HTTP servers hosting the files of the torrent.
+    // https://www.bittorrent.org/beps/bep_0019.html
+    pub web_seeds: Vec<Url>,
+    // The older seeding scripts serving the pieces by index.
+    // https://www.bittorrent.org/beps/bep_0017.html
+    pub http_seeds: Vec<Url>,
     pub info: raw::Info,
     pub comment: Option<String>,
     pub created_by: Option<String>,
     pub creation_date: Option<f64>,
+    // Identifies the torrent to the trackers, the DHT and the peers. It's the v1 info hash,
+    // or the v2 info hash truncated to 20 bytes if the torrent is v2 only.
     pub info_hash: Sha1Hash,
+    // None if the torrent is v1 only.
+    pub v2: Option<MetaInfoV2>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaVersion {
+    V1,
+    V2,
+    // Both the v1 and v2 hashes of the same data.
+    Hybrid,
+}
+
+// The BitTorrent v2 parts of the metainfo, the pieces of each file are hashed by SHA-256
+// into a merkle tree, and each file starts at a piece boundary.
+// https://www.bittorrent.org/beps/bep_0052.html
+#[derive(Debug, Clone)]
+pub struct MetaInfoV2 {
+    // SHA-256 of the info dict.
+    pub info_hash: Sha256Hash,
+    pub files: Vec<FileV2>,
+    // The piece hashes of each file by its pieces root. The files of a single piece have none,
+    // the pieces root is the hash of the piece.
+    pub piece_layers: HashMap<Sha256Hash, Vec<Sha256Hash>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileV2 {
+    pub path: Vec<String>,
+    pub length: u64,
+    // The root of the merkle tree of the file, None for an empty file.
+    pub pieces_root: Option<Sha256Hash>,
+}
+
+impl MetaInfoV2 {
+    fn parse(
+        info: &raw::Info,
+        info_bytes: &[u8],
+        piece_layers: BTreeMap<serde_bytes::ByteBuf, serde_bytes::ByteBuf>,
+    ) -> Result<Option<Self>> {
+        if !matches!(info.extra.get("meta version"), Some(Value::Int(2))) {
+            return Ok(None);
+        }
+        let Some(Value::Dict(tree)) = info.extra.get("file tree") else {
+            return Err(MetaInfoError::InvalidFileTree);
+        };
+        let mut files = Vec::new();
+        walk_file_tree(tree, &mut Vec::new(), &mut files)?;
+
+        let mut layers: HashMap<Sha256Hash, Vec<Sha256Hash>> = HashMap::new();
+        for (root, hashes) in piece_layers {
+            let Ok(root) = Sha256Hash::try_from(root.as_slice()) else {
+                log::warn!("Ignore piece layer with invalid pieces root");
+                continue;
+            };
+            if hashes.len() % 32 != 0 {
+                log::warn!("Ignore piece layer of {:?} with invalid length", root);
+                continue;
+            }
+            let hashes = hashes
+                .chunks_exact(32)
+                .map(|it| Sha256Hash::try_from(it).unwrap())
+                .collect();
+            layers.insert(root, hashes);
+        }
+
+        // A hybrid torrent falls back to its v1 hashes
+        if info.pieces.is_empty() {
+            let piece_length = info.piece_length as u64;
+            for file in &files {
+                let piece_count = file.length.div_ceil(piece_length) as usize;
+                let has_layer = file
+                    .pieces_root
+                    .and_then(|root| layers.get(&root))
+                    .is_some_and(|layer| layer.len() == piece_count);
+                if piece_count > 1 && !has_layer {
+                    return Err(MetaInfoError::MissingPieceLayer(file.path.join("/")));
+                }
+            }
+        }
+
+        Ok(Some(Self {
+            info_hash: calculate_sha256_hash(info_bytes.to_vec()),
+            files,
+            piece_layers: layers,
+        }))
+    }
+
+    // The files with a pad file after each one which doesn't end at a piece boundary,
+    // so the pieces line up with the files when they are laid out one after another.
+    // https://www.bittorrent.org/beps/bep_0047.html
+    fn aligned_files(&self, piece_length: u64) -> Vec<raw::File> {
+        let mut files = Vec::new();
+        for (index, file) in self.files.iter().enumerate() {
+            files.push(raw::File {
+                length: file.length,
+                path: file.path.clone(),
+                ..Default::default()
+            });
+            let remainder = file.length % piece_length;
+            if remainder != 0 && index + 1 < self.files.len() {
+                let padding = piece_length - remainder;
+                files.push(raw::File {
+                    length: padding,
+                    path: vec![".pad".to_string(), padding.to_string()],
+                    attr: Some("p".to_string()),
+                    ..Default::default()
+                });
+            }
+        }
+        files
+    }
+}
+
+// Each directory is a dict of its entries, a file is a dict with its properties
+// under the empty key.
+fn walk_file_tree(
+    node: &HashMap<Vec<u8>, Value>,
+    path: &mut Vec<String>,
+    files: &mut Vec<FileV2>,
+) -> Result<()> {
+    let mut entries: Vec<_> = node.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, child) in entries {
+        let Value::Dict(child) = child else {
+            return Err(MetaInfoError::InvalidFileTree);
+        };
+        if !name.is_empty() {
+            let name =
+                String::from_utf8(name.clone()).map_err(|_| MetaInfoError::InvalidFileTree)?;
+            if !is_safe_component(&name) {
+                path.push(name);
+                return Err(MetaInfoError::InvalidPath(path.clone()));
+            }
+            path.push(name);
+            walk_file_tree(child, path, files)?;
+            path.pop();
+            continue;
+        }
+        if path.is_empty() {
+            return Err(MetaInfoError::InvalidFileTree);
+        }
+        let length = match child.get(b"length".as_slice()) {
+            Some(Value::Int(length)) if *length >= 0 => *length as u64,
+            _ => return Err(MetaInfoError::InvalidFileTree),
+        };
+        let pieces_root = match child.get(b"pieces root".as_slice()) {
+            Some(Value::Bytes(root)) => Some(
+                Sha256Hash::try_from(root.as_slice())
+                    .map_err(|_| MetaInfoError::InvalidFileTree)?,
+            ),
+            None if length == 0 => None,
+            _ => return Err(MetaInfoError::InvalidFileTree),
+        };
+        files.push(FileV2 {
+            path: path.clone(),
+            length,
+            pieces_root,
+        });
+    }
+    Ok(())
 }
 
 impl MetaInfo {
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::from_bytes_with_limits(bytes, &MetaInfoLimits::default())
+    }
+
+    pub fn from_bytes_with_limits(bytes: &[u8], limits: &MetaInfoLimits) -> Result<Self> {
+        if bytes.len() > limits.max_torrent_size {
+            return Err(MetaInfoError::TooLarge);
+        }
         let metainfo: raw::MetaInfo = serde_bencode::from_bytes(bytes)?;
-        let info_hash = metainfo.calculate_info_hash()?;
+        let info_bytes = serde_bencode::to_bytes(&metainfo.info)?;
+        if info_bytes.len() > limits.max_info_size {
+            return Err(MetaInfoError::TooLarge);
+        }
+        check_layout(&metainfo.info, limits)?;
+        let v2 = MetaInfoV2::parse(
+            &metainfo.info,
+            &info_bytes,
+            metainfo.piece_layers.clone().unwrap_or_default(),
+        )?;
+        let info_hash = match &v2 {
+            Some(v2) if metainfo.info.pieces.is_empty() => truncate_info_hash(&v2.info_hash),
+            _ => metainfo.calculate_info_hash()?,
+        };
         Ok(Self {
-            announce: Url::parse(&metainfo.announce)?,
+            announce: metainfo
+                .announce
+                .map(|announce| Url::parse(&announce))
+                .transpose()?,
+            announce_list: metainfo
+                .announce_list
+                .unwrap_or_default()
+                .into_iter()
+                .map(|tier| {
+                    tier.iter()
+                        .filter_map(|url| match Url::parse(url) {
+                            Ok(url) => Some(url),
+                            Err(e) => {
+                                log::warn!(
+                                    "Ignore invalid tracker {} in announce-list: {:?}",
+                                    url,
+                                    e
+                                );
+                                None
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .filter(|tier| !tier.is_empty())
+                .collect(),
+            web_seeds: match metainfo.url_list {
+                Some(raw::UrlList::One(url)) => vec![url],
+                Some(raw::UrlList::Many(urls)) => urls,
+                None => Vec::new(),
+            }
+            .into_iter()
+            // Some torrents have an empty url-list
+            .filter(|url| !url.is_empty())
+            .filter_map(|url| match Url::parse(&url) {
+                Ok(url) => Some(url),
+                Err(e) => {
+                    log::warn!("Ignore invalid web seed {}: {:?}", url, e);
+                    None
+                }
+            })
+            .collect(),
+            http_seeds: metainfo
+                .httpseeds
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|url| match Url::parse(&url) {
+                    Ok(url) => Some(url),
+                    Err(e) => {
+                        log::warn!("Ignore invalid HTTP seed {}: {:?}", url, e);
+                        None
+                    }
+                })
+                .collect(),
             info: metainfo.info,
             comment: metainfo.comment,
             created_by: metainfo.created_by,
             creation_date: metainfo.creation_date,
             info_hash,
+            v2,
+        })
+    }
+
+    // Build the metainfo from the info dict fetched from peers (e.g. by ut_metadata),
+    // the info hash is calculated from the exact bytes we received.
+    pub fn from_info_bytes(info_bytes: &[u8], trackers: Vec<Url>) -> Result<Self> {
+        Self::from_info_bytes_with_limits(info_bytes, trackers, &MetaInfoLimits::default())
+    }
+
+    pub fn from_info_bytes_with_limits(
+        info_bytes: &[u8],
+        trackers: Vec<Url>,
+        limits: &MetaInfoLimits,
+    ) -> Result<Self> {
+        if info_bytes.len() > limits.max_info_size {
+            return Err(MetaInfoError::TooLarge);
+        }
+        let info: raw::Info = serde_bencode::from_bytes(info_bytes)?;
+        check_layout(&info, limits)?;
+        // The piece layers are not in the info dict, a v2 only torrent with files larger than
+        // a piece is refused, its pieces couldn't be verified.
+        let v2 = MetaInfoV2::parse(&info, info_bytes, BTreeMap::new())?;
+        let info_hash = match &v2 {
+            Some(v2) if info.pieces.is_empty() => truncate_info_hash(&v2.info_hash),
+            _ => calculate_sha1_hash(info_bytes.to_vec()),
+        };
+        Ok(Self {
+            announce: trackers.first().cloned(),
+            // Each tracker of the magnet link is a tier on its own.
+            announce_list: trackers.into_iter().map(|tracker| vec![tracker]).collect(),
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
+            info,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info_hash,
+            v2,
         })
     }
 
-    pub fn total_bytes(self) -> usize {
+    // A private torrent only gets its peers from its trackers, no DHT, PEX or LSD,
+    // the private trackers ban the clients leaking their peers.
+    // https://www.bittorrent.org/beps/bep_0027.html
+    pub fn is_private(&self) -> bool {
+        matches!(
+            self.info.extra.get("private"),
+            Some(serde_bencode::value::Value::Int(1))
+        )
+    }
+
+    pub fn version(&self) -> MetaVersion {
+        match &self.v2 {
+            None => MetaVersion::V1,
+            Some(_) if self.info.pieces.is_empty() => MetaVersion::V2,
+            Some(_) => MetaVersion::Hybrid,
+        }
+    }
+
+    // The info hashes the torrent is known by. A hybrid torrent is in two swarms,
+    // the peers of the v1 one and the peers of the v2 one, announce and accept both.
+    pub fn info_hashes(&self) -> Vec<Sha1Hash> {
+        match &self.v2 {
+            Some(v2) if self.version() == MetaVersion::Hybrid => {
+                vec![self.info_hash, truncate_info_hash(&v2.info_hash)]
+            }
+            _ => vec![self.info_hash],
+        }
+    }
+
+    pub fn piece_count(&self) -> usize {
+        if !self.info.pieces.is_empty() {
+            return self.info.pieces.len() / 20;
+        }
+        (self.total_bytes() as u64).div_ceil(self.info.piece_length as u64) as usize
+    }
+
+    // The trackers to announce to, tier by tier.
+    pub fn tracker_tiers(&self) -> Vec<Vec<Url>> {
+        if !self.announce_list.is_empty() {
+            return self.announce_list.clone();
+        }
+        self.announce.iter().map(|url| vec![url.clone()]).collect()
+    }
+
+    // The bencoded info dict, which is what the info hash calculated from.
+    pub fn info_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(&self.info)?)
+    }
+
+    // The files of the torrent, a single file torrent is treated as one file named by the torrent name.
+    // A v2 only torrent gets pad files between its files, so the pieces line up the same as v1.
+    pub fn files(&self) -> Vec<raw::File> {
+        if let Some(length) = self.info.length {
+            // The attributes of a single file torrent are in the info dict
+            let attr = match self.info.extra.get("attr") {
+                Some(Value::Bytes(attr)) => Some(String::from_utf8_lossy(attr).into_owned()),
+                _ => None,
+            };
+            return vec![raw::File {
+                length,
+                path: vec![self.info.name.clone()],
+                attr,
+                ..Default::default()
+            }];
+        }
+        if let Some(files) = &self.info.files {
+            return files.clone();
+        }
+        if let Some(v2) = &self.v2 {
+            return v2.aligned_files(self.info.piece_length as u64);
+        }
+        Vec::new()
+    }
+
+    // The files to show the user, without the padding files. The index is of `files`.
+    pub fn file_entries(&self) -> Vec<FileEntry> {
+        self.files()
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, file)| {
+                let attributes = file.attributes();
+                (!attributes.padding).then_some(FileEntry {
+                    index,
+                    path: file.path,
+                    length: file.length,
+                    attributes,
+                    symlink_path: file.symlink_path,
+                })
+            })
+            .collect()
+    }
+
+    pub fn total_bytes(&self) -> usize {
         if let Some(length) = self.info.length {
             return length as usize;
         }
-        if let Some(files) = self.info.files {
-            return files.iter().fold(0, |acc, it| acc + it.length as usize);
+        if self.info.files.is_none() && self.v2.is_none() {
+            panic!("Invalid metainfo, must have length or files");
         }
-        panic!("Invalid metainfo, must have length or files");
+        self.files()
+            .iter()
+            .fold(0, |acc, it| acc + it.length as usize)
     }
 }
 
-pub mod raw {
-    use crate::hash::calculate_sha1_hash;
+// The file attributes of BEP 47.
+// https://www.bittorrent.org/beps/bep_0047.html
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct FileAttributes {
+    // Only there to align the next file to a piece boundary, it's all zeros and never
+    // written to the disk.
+    pub padding: bool,
+    pub executable: bool,
+    pub hidden: bool,
+    // The file is a symlink to the `symlink path`, it has no content.
+    pub symlink: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileEntry {
+    pub index: usize,
+    pub path: Vec<String>,
+    pub length: u64,
+    pub attributes: FileAttributes,
+    pub symlink_path: Option<Vec<String>>,
+}
+
+// Check the counts before anything is allocated by them. The v2 file tree is counted by its
+// encoded size only, it's bounded by the info size.
+fn check_layout(info: &raw::Info, limits: &MetaInfoLimits) -> Result<()> {
+    if info.piece_length == 0 {
+        return Err(MetaInfoError::InvalidPieceLength);
+    }
+    let files = info.files.as_deref().unwrap_or_default();
+    if files.len() > limits.max_files {
+        return Err(MetaInfoError::TooManyFiles(files.len()));
+    }
+    if info.length.is_some() {
+        check_path(std::slice::from_ref(&info.name))?;
+    }
+    for file in files {
+        check_path(&file.path)?;
+        if let Some(target) = &file.symlink_path {
+            check_path(target)?;
+        }
+    }
+    if !info.pieces.len().is_multiple_of(20) {
+        return Err(MetaInfoError::InvalidPieces);
+    }
+    let total_bytes = match (info.length, &info.files) {
+        (Some(length), _) => length,
+        (None, Some(files)) => files
+            .iter()
+            .try_fold(0u64, |acc, it| acc.checked_add(it.length))
+            .ok_or(MetaInfoError::TooLarge)?,
+        // v2 only, the pieces are hashed by the file tree
+        (None, None) if info.extra.contains_key("file tree") => {
+            return match info.pieces.is_empty() {
+                true => Ok(()),
+                false => Err(MetaInfoError::InvalidPieces),
+            };
+        }
+        (None, None) => return Err(MetaInfoError::MissingFiles),
+    };
+    let pieces = total_bytes.div_ceil(info.piece_length as u64);
+    if pieces > limits.max_pieces as u64 {
+        return Err(MetaInfoError::TooManyPieces(pieces as usize));
+    }
+    if pieces != (info.pieces.len() / 20) as u64 {
+        return Err(MetaInfoError::InvalidPieces);
+    }
+    Ok(())
+}
 
+// The disk joins the path to the save path, each component must name an entry in the folder
+// of the one before. An empty or absolute component, `..`, a separator or a Windows drive
+// would leave the save path.
+fn check_path(path: &[String]) -> Result<()> {
+    if !is_safe_path(path) {
+        return Err(MetaInfoError::InvalidPath(path.to_vec()));
+    }
+    Ok(())
+}
+
+pub(crate) fn is_safe_path(path: &[String]) -> bool {
+    !path.is_empty() && path.iter().all(|it| is_safe_component(it))
+}
+
+fn is_safe_component(component: &str) -> bool {
+    let mut components = Path::new(component).components();
+    let is_drive =
+        component.as_bytes().get(1) == Some(&b':') && component.as_bytes()[0].is_ascii_alphabetic();
+    matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+        && !component.contains(['/', '\\', '\0'])
+        && !is_drive
+}
+
+// The v2 info hash is truncated where only 20 bytes fit, e.g. the tracker and the handshake.
+fn truncate_info_hash(info_hash: &Sha256Hash) -> Sha1Hash {
+    let mut truncated = [0u8; 20];
+    truncated.copy_from_slice(&info_hash[..20]);
+    truncated
+}
+
+pub mod raw {
     use super::*;
-    use sha1::{Digest, Sha1};
 
     // implementation of https://bittorrent.org/beps/bep_0003.html#metainfo-files
     #[derive(Debug, Serialize, Deserialize)]
     pub struct MetaInfo {
-        pub announce: String,
+        pub announce: Option<String>,
+        #[serde(rename = "announce-list", default)]
+        pub announce_list: Option<Vec<Vec<String>>>,
+        #[serde(rename = "url-list", default)]
+        pub url_list: Option<UrlList>,
+        #[serde(default)]
+        pub httpseeds: Option<Vec<String>>,
         pub info: Info,
+        // The piece hashes of the files of a v2 torrent, by the pieces root of each file.
+        #[serde(rename = "piece layers", default)]
+        pub piece_layers: Option<BTreeMap<serde_bytes::ByteBuf, serde_bytes::ByteBuf>>,
         pub comment: Option<String>,
         #[serde(rename = "created by")]
         pub created_by: Option<String>,
@@ -69,6 +614,14 @@ pub mod raw {
         pub creation_date: Option<f64>,
     }
 
+    // The url-list is a single URL or a list of them.
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(untagged)]
+    pub enum UrlList {
+        One(String),
+        Many(Vec<String>),
+    }
+
     #[derive(Serialize, Deserialize, Clone)]
     pub struct Info {
         pub name: String,
@@ -76,24 +629,55 @@ pub mod raw {
         #[serde(rename = "piece length")]
         pub piece_length: u32,
         // The SHA1 hash of each piece, concatenated together.
-        // Used to verify the integrity of the pieces.
-        #[serde(with = "serde_bytes")]
+        // Used to verify the integrity of the pieces. Empty if the torrent is v2 only.
+        #[serde(with = "serde_bytes", default, skip_serializing_if = "Vec::is_empty")]
         pub pieces: Vec<u8>,
         // If this is a single file torrent, this is the length of the file, in bytes.
         pub length: Option<u64>,
         // If this is a multi-file torrent, this is a list of files.
         pub files: Option<Vec<File>>,
-        // We not going to use the extra fields,
-        // but we need this to capture any additional fields to get the correct info_hash.
+        // We not going to use the extra fields, but we need this to capture any additional
+        // fields to get the correct info_hash. The v2 `meta version` and `file tree` are here too.
         #[serde(flatten)]
         pub extra: std::collections::BTreeMap<String, serde_bencode::value::Value>,
     }
 
-    #[derive(Debug, Serialize, Deserialize, Clone)]
+    #[derive(Debug, Serialize, Deserialize, Clone, Default)]
     pub struct File {
         // The length of the file, in bytes.
         pub length: u64,
         pub path: Vec<String>,
+        // The BEP 47 attributes, see `FileAttributes`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub attr: Option<String>,
+        // Relative to the torrent root, for the files with the `l` attribute.
+        #[serde(
+            rename = "symlink path",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub symlink_path: Option<Vec<String>>,
+        // The SHA-1 of the whole file, some torrents with padding files have it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub sha1: Option<serde_bytes::ByteBuf>,
+    }
+
+    impl File {
+        pub fn attributes(&self) -> FileAttributes {
+            let attr = self.attr.as_deref().unwrap_or_default();
+            // The padding files made before BEP 47 are only recognizable by their names
+            let is_legacy_padding = self.path.first().is_some_and(|it| it == ".pad")
+                || self
+                    .path
+                    .last()
+                    .is_some_and(|it| it.starts_with("_____padding_file_"));
+            FileAttributes {
+                padding: attr.contains('p') || is_legacy_padding,
+                executable: attr.contains('x'),
+                hidden: attr.contains('h'),
+                symlink: attr.contains('l'),
+            }
+        }
     }
 
     impl MetaInfo {
@@ -118,6 +702,73 @@ pub mod raw {
     }
 }
 
+// The metainfo of the tests of the other modules, built from the contents of its files with
+// the pieces hashed from them, so a new field only has to be set here.
+#[cfg(test)]
+pub(crate) struct TestMetaInfo {
+    name: String,
+    piece_length: u32,
+    // None for a single file torrent.
+    files: Option<Vec<raw::File>>,
+    data: Vec<u8>,
+}
+
+#[cfg(test)]
+impl TestMetaInfo {
+    pub(crate) fn new(name: &str, piece_length: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            piece_length,
+            files: None,
+            data: Vec::new(),
+        }
+    }
+
+    // The content of a single file torrent.
+    pub(crate) fn single(mut self, data: &[u8]) -> Self {
+        self.data = data.to_vec();
+        self
+    }
+
+    // A file of a multi file torrent, the path is split at the slashes.
+    pub(crate) fn file(mut self, path: &str, data: &[u8]) -> Self {
+        self.files.get_or_insert_with(Vec::new).push(raw::File {
+            length: data.len() as u64,
+            path: path.split('/').map(String::from).collect(),
+            ..Default::default()
+        });
+        self.data.extend_from_slice(data);
+        self
+    }
+
+    pub(crate) fn build(self) -> MetaInfo {
+        let pieces = self
+            .data
+            .chunks(self.piece_length as usize)
+            .flat_map(|chunk| calculate_sha1_hash(chunk.to_vec()))
+            .collect();
+        MetaInfo {
+            announce: None,
+            announce_list: Vec::new(),
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
+            v2: None,
+            info: raw::Info {
+                name: self.name,
+                piece_length: self.piece_length,
+                length: self.files.is_none().then_some(self.data.len() as u64),
+                files: self.files,
+                pieces,
+                extra: BTreeMap::new(),
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info_hash: [0u8; 20],
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -133,4 +784,351 @@ mod tests {
             metainfo.err()
         );
     }
+
+    #[test]
+    fn test_from_info_bytes() {
+        let data = fs::read("tests/test.torrent").expect("Failed to read test.torrent");
+        let metainfo = MetaInfo::from_bytes(&data).unwrap();
+        let info_bytes = serde_bencode::to_bytes(&metainfo.info).unwrap();
+
+        let trackers = vec![Url::parse("http://example.com/announce").unwrap()];
+        let lazy_metainfo = MetaInfo::from_info_bytes(&info_bytes, trackers.clone()).unwrap();
+        assert_eq!(lazy_metainfo.info_hash, metainfo.info_hash);
+        assert_eq!(lazy_metainfo.info.name, metainfo.info.name);
+        assert_eq!(lazy_metainfo.announce, trackers.first().cloned());
+        assert_eq!(lazy_metainfo.tracker_tiers(), vec![trackers]);
+    }
+
+    #[test]
+    fn test_parse_announce_list() {
+        let metainfo: raw::MetaInfo = serde_bencode::from_bytes(
+            b"d8:announce15:http://a.com/an13:announce-listll15:http://a.com/an15:http://b.com/anel14:udp://c.com:80ee4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces0:ee",
+        )
+        .unwrap();
+        assert_eq!(
+            metainfo.announce_list,
+            Some(vec![
+                vec!["http://a.com/an".to_string(), "http://b.com/an".to_string()],
+                vec!["udp://c.com:80".to_string()],
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_url_list() {
+        let info = "4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:12345678901234567890ee";
+        let metainfo =
+            MetaInfo::from_bytes(format!("d8:url-list17:http://a.com/file{}", info).as_bytes())
+                .unwrap();
+        assert_eq!(
+            metainfo.web_seeds,
+            vec![Url::parse("http://a.com/file").unwrap()]
+        );
+
+        let metainfo = MetaInfo::from_bytes(
+            format!("d8:url-listl13:http://a.com/0:13:http://b.com/e{}", info).as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(metainfo.web_seeds.len(), 2);
+
+        let metainfo = MetaInfo::from_bytes(
+            format!("d9:httpseedsl21:http://a.com/seed.phpe{}", info).as_bytes(),
+        )
+        .unwrap();
+        assert!(metainfo.web_seeds.is_empty());
+        assert_eq!(
+            metainfo.http_seeds,
+            vec![Url::parse("http://a.com/seed.php").unwrap()]
+        );
+    }
+
+    fn v2_info(file_tree: &[u8]) -> Vec<u8> {
+        let mut info = b"d9:file treed".to_vec();
+        info.extend_from_slice(file_tree);
+        info.extend_from_slice(b"e12:meta versioni2e4:name4:test12:piece lengthi16384ee");
+        info
+    }
+
+    fn v2_file(name: &str, length: u64, root: u8) -> Vec<u8> {
+        let mut file = format!(
+            "{}:{}d0:d6:lengthi{}e11:pieces root32:",
+            name.len(),
+            name,
+            length
+        )
+        .into_bytes();
+        file.extend_from_slice(&[root; 32]);
+        file.extend_from_slice(b"ee");
+        file
+    }
+
+    #[test]
+    fn test_parse_v2_torrent() {
+        let mut file_tree = v2_file("b.txt", 5, 1);
+        file_tree.extend_from_slice(b"4:dir1d");
+        file_tree.extend_from_slice(&v2_file("a.txt", 20000, 2));
+        file_tree.extend_from_slice(b"e");
+        let info = v2_info(&file_tree);
+        let mut bytes = b"d4:info".to_vec();
+        bytes.extend_from_slice(&info);
+        bytes.extend_from_slice(b"12:piece layersd32:");
+        bytes.extend_from_slice(&[2; 32]);
+        bytes.extend_from_slice(b"64:");
+        bytes.extend_from_slice(&[3; 64]);
+        bytes.extend_from_slice(b"ee");
+
+        let metainfo = MetaInfo::from_bytes(&bytes).unwrap();
+        assert_eq!(metainfo.version(), MetaVersion::V2);
+        let v2 = metainfo.v2.as_ref().unwrap();
+        // The info dict must round trip to the same bytes
+        assert_eq!(v2.info_hash, calculate_sha256_hash(info));
+        assert_eq!(metainfo.info_hash, v2.info_hash[..20]);
+        assert_eq!(
+            v2.files,
+            vec![
+                FileV2 {
+                    path: vec!["b.txt".to_string()],
+                    length: 5,
+                    pieces_root: Some([1; 32]),
+                },
+                FileV2 {
+                    path: vec!["dir1".to_string(), "a.txt".to_string()],
+                    length: 20000,
+                    pieces_root: Some([2; 32]),
+                },
+            ]
+        );
+        assert_eq!(v2.piece_layers[&[2; 32]], vec![[3; 32], [3; 32]]);
+
+        // The second file starts at the next piece
+        let files = metainfo.files();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[1].length, 16384 - 5);
+        assert_eq!(files[1].path, vec![".pad", "16379"]);
+        assert_eq!(metainfo.total_bytes(), 16384 + 20000);
+        assert_eq!(metainfo.piece_count(), 3);
+    }
+
+    #[test]
+    fn test_missing_piece_layer() {
+        let mut file_tree = v2_file("b.txt", 5, 1);
+        file_tree.extend_from_slice(&v2_file("c.txt", 20000, 2));
+        let info = v2_info(&file_tree);
+        let mut bytes = b"d4:info".to_vec();
+        bytes.extend_from_slice(&info);
+        bytes.extend_from_slice(b"e");
+        assert!(matches!(
+            MetaInfo::from_bytes(&bytes),
+            Err(MetaInfoError::MissingPieceLayer(path)) if path == "c.txt"
+        ));
+        // Fetched by ut_metadata, the info dict never has them
+        assert!(matches!(
+            MetaInfo::from_info_bytes(&info, Vec::new()),
+            Err(MetaInfoError::MissingPieceLayer(_))
+        ));
+
+        // A file of a single piece has none
+        let info = v2_info(&v2_file("b.txt", 5, 1));
+        assert!(MetaInfo::from_info_bytes(&info, Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn test_parse_invalid_file_tree() {
+        // A file with bytes must have a pieces root
+        let info = v2_info(b"5:a.txtd0:d6:lengthi5eee");
+        let mut bytes = b"d4:info".to_vec();
+        bytes.extend_from_slice(&info);
+        bytes.extend_from_slice(b"e");
+        assert!(matches!(
+            MetaInfo::from_bytes(&bytes),
+            Err(MetaInfoError::InvalidFileTree)
+        ));
+
+        let data = fs::read("tests/test.torrent").unwrap();
+        assert_eq!(
+            MetaInfo::from_bytes(&data).unwrap().version(),
+            MetaVersion::V1
+        );
+    }
+
+    #[test]
+    fn test_hybrid_info_hashes() {
+        let mut info = b"d9:file treed".to_vec();
+        info.extend_from_slice(&v2_file("test", 5, 1));
+        info.extend_from_slice(b"e6:lengthi5e12:meta versioni2e4:name4:test");
+        info.extend_from_slice(b"12:piece lengthi16384e6:pieces20:12345678901234567890e");
+        let mut bytes = b"d4:info".to_vec();
+        bytes.extend_from_slice(&info);
+        bytes.extend_from_slice(b"e");
+
+        let metainfo = MetaInfo::from_bytes(&bytes).unwrap();
+        assert_eq!(metainfo.version(), MetaVersion::Hybrid);
+        // The v1 hash comes first, it's the one the torrent is identified by
+        assert_eq!(
+            metainfo.info_hashes(),
+            vec![
+                calculate_sha1_hash(info.clone()),
+                truncate_info_hash(&calculate_sha256_hash(info))
+            ]
+        );
+        // The v1 layout is used
+        assert_eq!(metainfo.piece_count(), 1);
+    }
+
+    #[test]
+    fn test_private() {
+        let info =
+            "d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:123456789012345678907:privatei1ee";
+        let metainfo = MetaInfo::from_bytes(format!("d4:info{}e", info).as_bytes()).unwrap();
+        assert!(metainfo.is_private());
+        // The flag is part of the info dict, so of the info hash
+        assert_eq!(
+            metainfo.info_hash,
+            calculate_sha1_hash(info.as_bytes().to_vec())
+        );
+
+        let metainfo = MetaInfo::from_bytes(
+            b"d4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:12345678901234567890ee",
+        )
+        .unwrap();
+        assert!(!metainfo.is_private());
+    }
+
+    #[test]
+    fn test_file_attributes() {
+        let info = concat!(
+            "d5:filesl",
+            "d4:attr1:x6:lengthi3e4:pathl3:runee",
+            "d4:attr1:p6:lengthi13e4:pathl4:.pad2:13ee",
+            "d4:attr1:l6:lengthi0e4:pathl4:linke12:symlink pathl3:runee",
+            "d6:lengthi1e4:pathl18:_____padding_file_ee",
+            "e4:name1:a12:piece lengthi16e6:pieces40:",
+            "1234567890123456789012345678901234567890e"
+        );
+        let metainfo = MetaInfo::from_bytes(format!("d4:info{}e", info).as_bytes()).unwrap();
+        // The attributes are kept in the info dict
+        assert_eq!(
+            metainfo.info_hash,
+            calculate_sha1_hash(info.as_bytes().to_vec())
+        );
+        let entries = metainfo.file_entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].index, 0);
+        assert!(entries[0].attributes.executable);
+        assert_eq!(entries[1].index, 2);
+        assert!(entries[1].attributes.symlink);
+        assert_eq!(entries[1].symlink_path, Some(vec!["run".to_string()]));
+    }
+
+    #[test]
+    fn test_invalid_paths() {
+        let multi_file = |path: &str, symlink: &str| {
+            format!(
+                "d4:infod5:filesld4:attr1:l6:lengthi0e4:path{}12:symlink path{}ee4:name1:a12:piece lengthi16e6:pieces0:ee",
+                path, symlink
+            )
+        };
+        let parse = |torrent: String| MetaInfo::from_bytes(torrent.as_bytes());
+        assert!(parse(multi_file("l4:linke", "l3:bin3:rune")).is_ok());
+        for path in [
+            "le",
+            "l0:e",
+            "l2:..e",
+            "l1:.e",
+            "l3:a/be",
+            "l3:a\\be",
+            "l2:C:e",
+            "l4:C:\\ae",
+        ] {
+            // As the link, and as the target of the link
+            assert!(
+                matches!(
+                    parse(multi_file(path, "l3:rune")),
+                    Err(MetaInfoError::InvalidPath(_))
+                ),
+                "{}",
+                path
+            );
+            assert!(
+                matches!(
+                    parse(multi_file("l4:linke", path)),
+                    Err(MetaInfoError::InvalidPath(_))
+                ),
+                "{}",
+                path
+            );
+        }
+        // Joins to /etc/passwd
+        assert!(matches!(
+            parse(multi_file("l4:linke", "l0:3:etc6:passwde")),
+            Err(MetaInfoError::InvalidPath(_))
+        ));
+        assert!(matches!(
+            parse(multi_file("l0:3:etc6:passwde", "l3:rune")),
+            Err(MetaInfoError::InvalidPath(_))
+        ));
+        // The name is the path of a single file
+        assert!(matches!(
+            MetaInfo::from_bytes(
+                b"d4:infod6:lengthi1e4:name2:..12:piece lengthi1e6:pieces20:12345678901234567890ee"
+            ),
+            Err(MetaInfoError::InvalidPath(_))
+        ));
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = MetaInfoLimits {
+            max_pieces: 2,
+            max_files: 1,
+            ..Default::default()
+        };
+        let single = |length: u64, pieces: usize| {
+            format!(
+                "d4:infod6:lengthi{}e4:name1:a12:piece lengthi1e6:pieces{}:{}ee",
+                length,
+                pieces,
+                "x".repeat(pieces)
+            )
+        };
+        assert!(MetaInfo::from_bytes_with_limits(single(2, 40).as_bytes(), &limits).is_ok());
+        assert!(matches!(
+            MetaInfo::from_bytes_with_limits(single(1 << 40, 0).as_bytes(), &limits),
+            Err(MetaInfoError::TooManyPieces(_))
+        ));
+        // Not whole hashes, or not a hash for each piece
+        for (length, pieces) in [(2, 39), (2, 20), (1, 40), (0, 20)] {
+            assert!(matches!(
+                MetaInfo::from_bytes_with_limits(single(length, pieces).as_bytes(), &limits),
+                Err(MetaInfoError::InvalidPieces)
+            ));
+        }
+        assert!(MetaInfo::from_bytes_with_limits(single(0, 0).as_bytes(), &limits).is_ok());
+        assert!(matches!(
+            MetaInfo::from_bytes(
+                b"d4:infod4:name1:a12:piece lengthi1e6:pieces20:12345678901234567890ee"
+            ),
+            Err(MetaInfoError::MissingFiles)
+        ));
+        assert!(matches!(
+            MetaInfo::from_bytes(
+                b"d4:infod6:lengthi1e4:name1:a12:piece lengthi0e6:pieces20:12345678901234567890ee"
+            ),
+            Err(MetaInfoError::InvalidPieceLength)
+        ));
+
+        let info = "d5:filesld6:lengthi1e4:pathl1:aeed6:lengthi1e4:pathl1:beee4:name1:a12:piece lengthi1e6:pieces0:e";
+        assert!(matches!(
+            MetaInfo::from_info_bytes_with_limits(info.as_bytes(), Vec::new(), &limits),
+            Err(MetaInfoError::TooManyFiles(2))
+        ));
+        let limits = MetaInfoLimits {
+            max_info_size: 10,
+            ..Default::default()
+        };
+        assert!(matches!(
+            MetaInfo::from_info_bytes_with_limits(info.as_bytes(), Vec::new(), &limits),
+            Err(MetaInfoError::TooLarge)
+        ));
+    }
 }
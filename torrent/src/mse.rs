@@ -0,0 +1,542 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{hash::calculate_sha1_hash, transport::PeerStream, types::Sha1Hash};
+
+// Message Stream Encryption, a Diffie-Hellman key exchange followed by RC4 over the
+// whole connection, so the BitTorrent traffic can't be told apart by its handshake.
+// It's not meant to be secure, only to get past the ISPs which shape the BitTorrent traffic.
+// https://wiki.vuze.com/w/Message_Stream_Encryption
+
+pub(crate) type Result<T> = std::result::Result<T, MseError>;
+
+#[derive(Debug, Error)]
+pub(crate) enum MseError {
+    #[error("Failed to negotiate encryption")]
+    Io(#[from] io::Error),
+    #[error("Peer doesn't encrypt the connection")]
+    PlaintextRefused,
+    #[error("Failed to find the encryption handshake of the peer")]
+    SyncNotFound,
+    #[error("Peer asked for unknown torrent")]
+    UnknownTorrent,
+    #[error("No encryption method in common with the peer")]
+    NoCommonMethod,
+    #[error("Peer violated the encryption handshake: {0}")]
+    Protocol(&'static str),
+}
+
+// Whether to encrypt the connections to the peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncryptionPolicy {
+    // Only talk to the peers which encrypt the whole connection.
+    Require,
+    // Encrypt when the peer supports it, keep the incoming plaintext peers.
+    Prefer,
+    // Dial in plaintext, still accept the peers which encrypt.
+    #[default]
+    Allow,
+}
+
+// The 768 bit prime of the key exchange, the generator is 2.
+const PRIME: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+const KEY_SIZE: usize = 96;
+const MAX_PAD: usize = 512;
+// The verification constant, it tells both sides they derived the same keys.
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+// A plaintext peer starts with the BitTorrent handshake instead of its public key.
+const PROTOCOL_HEADER: &[u8] = b"\x13BitTorrent protocol";
+
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_SIZE],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let private = BigUint::from_bytes_be(&rand::random::<[u8; 20]>());
+        let public = BigUint::from(GENERATOR).modpow(&private, &prime());
+        Self {
+            private,
+            public: to_key_bytes(&public),
+        }
+    }
+
+    fn shared_secret(&self, other_public: &[u8]) -> [u8; KEY_SIZE] {
+        let other_public = BigUint::from_bytes_be(other_public);
+        to_key_bytes(&other_public.modpow(&self.private, &prime()))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME, 16).expect("The prime is valid hex")
+}
+
+// The keys are sent as 96 bytes big endian, padded with leading zeros.
+fn to_key_bytes(n: &BigUint) -> [u8; KEY_SIZE] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0u8; KEY_SIZE];
+    key[KEY_SIZE - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn hash(parts: &[&[u8]]) -> Sha1Hash {
+    calculate_sha1_hash(parts.concat())
+}
+
+fn xor(a: Sha1Hash, b: Sha1Hash) -> Sha1Hash {
+    let mut result = a;
+    for (x, y) in result.iter_mut().zip(b) {
+        *x ^= y;
+    }
+    result
+}
+
+fn random_pad() -> Vec<u8> {
+    let len = rand::random_range(0..=MAX_PAD);
+    (0..len).map(|_| rand::random()).collect()
+}
+
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, it) in state.iter_mut().enumerate() {
+            *it = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state
+                [self.state[self.i as usize].wrapping_add(self.state[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+// The initiator encrypts with keyA and the receiver with keyB,
+// the first 1024 bytes of both streams are thrown away.
+fn cipher(name: &[u8], secret: &[u8], skey: &[u8]) -> Rc4 {
+    let mut rc4 = Rc4::new(&hash(&[name, secret, skey]));
+    rc4.apply(&mut [0u8; 1024]);
+    rc4
+}
+
+// Read until the stream ends with the pattern, which comes after a random length padding.
+async fn sync(stream: &mut PeerStream, pattern: &[u8], max_len: usize) -> Result<()> {
+    let mut received = Vec::with_capacity(max_len);
+    while received.len() < max_len {
+        received.push(stream.read_u8().await?);
+        if received.ends_with(pattern) {
+            return Ok(());
+        }
+    }
+    Err(MseError::SyncNotFound)
+}
+
+async fn read_decrypted(stream: &mut PeerStream, decrypt: &mut Rc4, len: usize) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; len];
+    stream.read_exact(&mut data).await?;
+    decrypt.apply(&mut data);
+    Ok(data)
+}
+
+// Negotiate the encryption with the peer we dialed, before the BitTorrent handshake.
+// The policy must not be `Allow`, which means not to encrypt the outgoing connections.
+pub(crate) async fn initiate(
+    mut stream: PeerStream,
+    info_hash: Sha1Hash,
+    policy: EncryptionPolicy,
+) -> Result<PeerStream> {
+    let keys = KeyPair::generate();
+    stream
+        .write_all(&[keys.public.as_slice(), &random_pad()].concat())
+        .await?;
+    let mut other_public = [0u8; KEY_SIZE];
+    stream.read_exact(&mut other_public).await?;
+    let secret = keys.shared_secret(&other_public);
+    let mut encrypt = cipher(b"keyA", &secret, &info_hash);
+    let mut decrypt = cipher(b"keyB", &secret, &info_hash);
+
+    let provide = match policy {
+        EncryptionPolicy::Require => CRYPTO_RC4,
+        EncryptionPolicy::Prefer | EncryptionPolicy::Allow => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+    };
+    // No padding and no initial payload, the BitTorrent handshake follows the negotiation.
+    let mut payload = VC.to_vec();
+    payload.extend(provide.to_be_bytes());
+    payload.extend(0u16.to_be_bytes());
+    payload.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut payload);
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(hash(&[b"req2", &info_hash]), hash(&[b"req3", &secret])));
+    message.extend(payload);
+    stream.write_all(&message).await?;
+
+    // The reply comes after the padding of the receiver, find it by the encrypted VC.
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    sync(&mut stream, &vc, MAX_PAD + VC.len()).await?;
+    let reply = read_decrypted(&mut stream, &mut decrypt, 6).await?;
+    let select = u32::from_be_bytes(reply[..4].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(reply[4..].try_into().unwrap()) as usize;
+    if pad_len > MAX_PAD {
+        return Err(MseError::Protocol("padding too long"));
+    }
+    read_decrypted(&mut stream, &mut decrypt, pad_len).await?;
+
+    match select {
+        CRYPTO_RC4 => Ok(EncryptedStream::wrap(
+            stream,
+            Some(decrypt),
+            Some(encrypt),
+            Vec::new(),
+        )),
+        CRYPTO_PLAINTEXT if provide & CRYPTO_PLAINTEXT != 0 => Ok(stream),
+        _ => Err(MseError::NoCommonMethod),
+    }
+}
+
+// Negotiate the encryption with the peer which dialed us, if it encrypts at all.
+// The torrent is only known by the hash of its info hash, so all the served ones are tried.
+pub(crate) async fn accept(
+    mut stream: PeerStream,
+    info_hashes: &[Sha1Hash],
+    policy: EncryptionPolicy,
+) -> Result<PeerStream> {
+    let mut other_public = [0u8; KEY_SIZE];
+    stream
+        .read_exact(&mut other_public[..PROTOCOL_HEADER.len()])
+        .await?;
+    if other_public.starts_with(PROTOCOL_HEADER) {
+        if policy == EncryptionPolicy::Require {
+            return Err(MseError::PlaintextRefused);
+        }
+        // Hand the bytes we read to the BitTorrent handshake.
+        return Ok(EncryptedStream::wrap(
+            stream,
+            None,
+            None,
+            PROTOCOL_HEADER.to_vec(),
+        ));
+    }
+    stream
+        .read_exact(&mut other_public[PROTOCOL_HEADER.len()..])
+        .await?;
+    let keys = KeyPair::generate();
+    stream
+        .write_all(&[keys.public.as_slice(), &random_pad()].concat())
+        .await?;
+    let secret = keys.shared_secret(&other_public);
+
+    sync(
+        &mut stream,
+        &hash(&[b"req1", &secret]),
+        MAX_PAD + Sha1Hash::default().len(),
+    )
+    .await?;
+    let mut skey_hash = Sha1Hash::default();
+    stream.read_exact(&mut skey_hash).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let info_hash = info_hashes
+        .iter()
+        .find(|it| xor(hash(&[b"req2", it.as_slice()]), req3) == skey_hash)
+        .ok_or(MseError::UnknownTorrent)?;
+    let mut decrypt = cipher(b"keyA", &secret, info_hash);
+    let mut encrypt = cipher(b"keyB", &secret, info_hash);
+
+    let header = read_decrypted(&mut stream, &mut decrypt, 14).await?;
+    if header[..8] != VC {
+        return Err(MseError::Protocol("invalid verification constant"));
+    }
+    let provide = u32::from_be_bytes(header[8..12].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(header[12..].try_into().unwrap()) as usize;
+    if pad_len > MAX_PAD {
+        return Err(MseError::Protocol("padding too long"));
+    }
+    read_decrypted(&mut stream, &mut decrypt, pad_len).await?;
+    let ia_len = read_decrypted(&mut stream, &mut decrypt, 2).await?;
+    let ia_len = u16::from_be_bytes(ia_len[..].try_into().unwrap()) as usize;
+    // The initial payload is encrypted even when the rest of the connection isn't.
+    let initial_payload = read_decrypted(&mut stream, &mut decrypt, ia_len).await?;
+
+    let select = select_method(provide, policy).ok_or(MseError::NoCommonMethod)?;
+    let mut reply = VC.to_vec();
+    reply.extend(select.to_be_bytes());
+    reply.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut reply);
+    stream.write_all(&reply).await?;
+
+    if select == CRYPTO_RC4 {
+        Ok(EncryptedStream::wrap(
+            stream,
+            Some(decrypt),
+            Some(encrypt),
+            initial_payload,
+        ))
+    } else {
+        Ok(EncryptedStream::wrap(stream, None, None, initial_payload))
+    }
+}
+
+fn select_method(provide: u32, policy: EncryptionPolicy) -> Option<u32> {
+    let rc4 = provide & CRYPTO_RC4 != 0;
+    let plaintext = provide & CRYPTO_PLAINTEXT != 0;
+    match policy {
+        EncryptionPolicy::Require => rc4.then_some(CRYPTO_RC4),
+        EncryptionPolicy::Prefer if rc4 => Some(CRYPTO_RC4),
+        EncryptionPolicy::Allow if plaintext => Some(CRYPTO_PLAINTEXT),
+        _ if rc4 => Some(CRYPTO_RC4),
+        _ if plaintext => Some(CRYPTO_PLAINTEXT),
+        _ => None,
+    }
+}
+
+// The connection after the encryption handshake. The bytes read ahead during the handshake
+// are replayed first, then everything is decrypted and encrypted if RC4 was selected.
+pub struct EncryptedStream {
+    inner: PeerStream,
+    read_ahead: Vec<u8>,
+    decrypt: Option<Rc4>,
+    encrypt: Option<Rc4>,
+    // Encrypted bytes not yet taken by the inner stream, they can't be encrypted twice.
+    pending: Vec<u8>,
+    pending_written: usize,
+}
+
+impl std::fmt::Debug for EncryptedStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedStream")
+            .field("inner", &self.inner)
+            .field("encrypted", &self.encrypt.is_some())
+            .finish()
+    }
+}
+
+impl EncryptedStream {
+    fn wrap(
+        inner: PeerStream,
+        decrypt: Option<Rc4>,
+        encrypt: Option<Rc4>,
+        read_ahead: Vec<u8>,
+    ) -> PeerStream {
+        PeerStream::Encrypted(Box::new(Self {
+            inner,
+            read_ahead,
+            decrypt,
+            encrypt,
+            pending: Vec::new(),
+            pending_written: 0,
+        }))
+    }
+
+    pub(crate) fn inner(&self) -> &PeerStream {
+        &self.inner
+    }
+
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pending_written < self.pending.len() {
+            let n = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pending_written..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_written += n;
+        }
+        self.pending.clear();
+        self.pending_written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for EncryptedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.read_ahead.is_empty() {
+            let n = this.read_ahead.len().min(buf.remaining());
+            buf.put_slice(&this.read_ahead[..n]);
+            this.read_ahead.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(decrypt) = &mut this.decrypt {
+            decrypt.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for EncryptedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.encrypt.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        ready!(this.poll_write_pending(cx))?;
+        this.pending.extend_from_slice(buf);
+        if let Some(encrypt) = &mut this.encrypt {
+            encrypt.apply(&mut this.pending);
+        }
+        // The bytes are taken once encrypted, the rest is written on the next write or flush.
+        if let Poll::Ready(Err(e)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    async fn connected_pair() -> (PeerStream, PeerStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (
+            PeerStream::Tcp(client.unwrap()),
+            PeerStream::Tcp(server.unwrap().0),
+        )
+    }
+
+    #[test]
+    fn test_rc4() {
+        let mut data = b"Plaintext".to_vec();
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, [0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3]);
+    }
+
+    #[test]
+    fn test_select_method() {
+        let both = CRYPTO_RC4 | CRYPTO_PLAINTEXT;
+        assert_eq!(
+            select_method(both, EncryptionPolicy::Require),
+            Some(CRYPTO_RC4)
+        );
+        assert_eq!(
+            select_method(both, EncryptionPolicy::Prefer),
+            Some(CRYPTO_RC4)
+        );
+        assert_eq!(
+            select_method(both, EncryptionPolicy::Allow),
+            Some(CRYPTO_PLAINTEXT)
+        );
+        assert_eq!(
+            select_method(CRYPTO_PLAINTEXT, EncryptionPolicy::Require),
+            None
+        );
+        assert_eq!(
+            select_method(CRYPTO_RC4, EncryptionPolicy::Allow),
+            Some(CRYPTO_RC4)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_negotiate() {
+        let info_hash = [7u8; 20];
+        for (initiator_policy, receiver_policy, encrypted) in [
+            (EncryptionPolicy::Require, EncryptionPolicy::Allow, true),
+            (EncryptionPolicy::Prefer, EncryptionPolicy::Prefer, true),
+            (EncryptionPolicy::Prefer, EncryptionPolicy::Allow, false),
+        ] {
+            let (client, server) = connected_pair().await;
+            let server = tokio::spawn(async move {
+                let mut stream = accept(server, &[[1u8; 20], info_hash], receiver_policy)
+                    .await
+                    .unwrap();
+                let mut received = [0u8; 5];
+                stream.read_exact(&mut received).await.unwrap();
+                stream.write_all(b"world").await.unwrap();
+                stream.flush().await.unwrap();
+                received
+            });
+            let mut stream = initiate(client, info_hash, initiator_policy).await.unwrap();
+            assert_eq!(
+                matches!(&stream, PeerStream::Encrypted(it) if it.encrypt.is_some()),
+                encrypted
+            );
+            stream.write_all(b"hello").await.unwrap();
+            stream.flush().await.unwrap();
+            let mut received = [0u8; 5];
+            stream.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"world");
+            assert_eq!(&server.await.unwrap(), b"hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_plaintext() {
+        let (mut client, server) = connected_pair().await;
+        client.write_all(PROTOCOL_HEADER).await.unwrap();
+        let mut stream = accept(server, &[], EncryptionPolicy::Prefer).await.unwrap();
+        let mut received = vec![0u8; PROTOCOL_HEADER.len()];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, PROTOCOL_HEADER);
+
+        let (mut client, server) = connected_pair().await;
+        client.write_all(PROTOCOL_HEADER).await.unwrap();
+        let result = accept(server, &[], EncryptionPolicy::Require).await;
+        assert!(matches!(result, Err(MseError::PlaintextRefused)));
+    }
+
+    #[tokio::test]
+    async fn test_accept_unknown_torrent() {
+        let (client, server) = connected_pair().await;
+        let client = tokio::spawn(initiate(client, [7u8; 20], EncryptionPolicy::Require));
+        let result = accept(server, &[[1u8; 20]], EncryptionPolicy::Prefer).await;
+        assert!(matches!(result, Err(MseError::UnknownTorrent)));
+        drop(result);
+        assert!(client.await.unwrap().is_err());
+    }
+}
@@ -1,37 +1,326 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use bytes::BytesMut;
 use futures::{SinkExt, StreamExt};
+use rand::seq::SliceRandom;
 use thiserror::Error;
-use tokio::{net::TcpStream, time::interval};
-use tokio_util::codec::Framed;
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{Mutex, RwLock, broadcast, mpsc},
+    time::{interval, timeout},
+};
+use tokio_util::codec::{Encoder, Framed};
 
 use crate::{
-    message::{HandShake, HandShakeCodec, Message, MessageCodec},
+    choker::{ChokeRoundOptions, ChokeRounds, DEFAULT_UPLOAD_SLOTS},
+    dht::Dht,
+    dialer::{DialCandidate, PeerSource},
+    disk::{DeleteMode, Disk, DiskError, DiskEvent, DiskOptions},
+    extension::{self, ExtendedHandshake, ExtensionLimiter, UT_HOLEPUNCH, UT_METADATA, UT_PEX},
+    external_ip::ExternalIp,
+    holepunch::{ErrorCode, HolepunchMessage},
+    message::{Capabilities, HandShake, HandShakeCodec, Message, MessageCodec},
+    metadata::{MetadataError, MetadataMessage, metadata_piece},
+    metainfo::{MetaInfo, MetaVersion},
+    mse::{self, EncryptionPolicy, MseError},
+    peer_activity::{PeerActivityLog, PeerEvent},
+    peer_connection::PeerConnection,
+    peer_manager::{DisconnectReason, PeerManagerOptions},
     peer_stats::PeerStats,
+    peer_tls::{self, PeerTlsError},
+    pex::{PexMessage, PexState},
+    piece::Block,
+    piece_picker::BlockInfo,
+    starvation::{DEFAULT_STARVATION_TIMEOUT, StarvationWatchdog},
+    torrent::{PieceWrite, Torrent, TorrentPhase},
+    transfer::TransferTotals,
+    transport::{PeerStream, TransportPolicy},
     types::{BitField, PeerId, Sha1Hash},
+    upload::{PACING_CHUNK_SIZE, UploadPacer, UploadQueue},
+    utp::UtpSocket,
 };
 
 pub(crate) type Result<T> = std::result::Result<T, PeerError>;
 
 #[derive(Debug, Error)]
-enum PeerError {
+pub(crate) enum PeerError {
     #[error("Failed to connect to peer")]
     Io(#[from] std::io::Error),
+    #[error("Failed to encode extension message")]
+    Bencode(#[from] serde_bencode::Error),
+    #[error("Failed to encode metadata message")]
+    Metadata(#[from] MetadataError),
+    #[error("{0}")]
+    Encryption(#[from] MseError),
+    #[error("{0}")]
+    Tls(#[from] PeerTlsError),
+    #[error("Peer violated the protocol: {0}")]
+    Protocol(&'static str),
+    #[error("Peer sent nothing for {0:?}")]
+    Inactive(Duration),
+    #[error("Peer sent no block for {0:?} while unchoked")]
+    Starved(Duration),
+    #[error("Nothing transferred with the peer for {0:?}")]
+    DeadWeight(Duration),
+    #[error("Peer is upload only and has nothing we want")]
+    NothingToExchange,
+}
+
+impl PeerError {
+    // For the peer manager, whether to back off before dialing the peer again.
+    pub(crate) fn disconnect_reason(&self) -> DisconnectReason {
+        match self {
+            PeerError::Io(_) => DisconnectReason::PeerClosed,
+            PeerError::Bencode(_)
+            | PeerError::Metadata(_)
+            | PeerError::Encryption(_)
+            | PeerError::Tls(_)
+            | PeerError::Protocol(_) => DisconnectReason::Protocol,
+            PeerError::Inactive(_) | PeerError::Starved(_) => DisconnectReason::Timeout,
+            PeerError::DeadWeight(_) | PeerError::NothingToExchange => DisconnectReason::Closed,
+        }
+    }
 }
 
 enum Session {
     Idle(IdleSession),
     Connected(ConnectedSession),
-    Active(ActiveSession),
+    Active(Box<ActiveSession>),
     Disconnected(DisconnectedSession),
 }
 
+// Requests larger than this are rejected by most clients, the peer is broken or hostile.
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+
+// With the lazy bitfield, at most this share of our pieces is left out of the bitfield.
+const LAZY_BITFIELD_HIDDEN_RATIO: usize = 8;
+const LAZY_BITFIELD_MAX_HIDDEN: usize = 64;
+// How many hidden pieces are revealed with have messages each tick.
+const LAZY_BITFIELD_REVEAL_PER_TICK: usize = 4;
+
+// We send a keep-alive after this long without writing anything, most clients drop a peer
+// silent for longer than 2 minutes.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(110);
+// A peer silent for this long is dropped, it's gone or stuck and takes a connection slot.
+const PEER_TIMEOUT: Duration = Duration::from_secs(150);
+
+// Shared state of the torrent which the peer sessions belong to.
+pub(crate) struct TorrentContext {
+    torrent: Arc<Mutex<Torrent>>,
+    // Restarted in place when the disk options change, see `TorrentRegistry::restart_disk`.
+    disk: RwLock<Disk>,
+    // Addresses of the active peers, which are shared to the other peers through PEX.
+    peers: Mutex<HashSet<SocketAddr>>,
+    // Peers learned from the connected peers, to be dialed by the peer manager.
+    discovered_peers: mpsc::UnboundedSender<DialCandidate>,
+    // None if the DHT is disabled.
+    dht: Option<Arc<Dht>>,
+    // The torrent is private, the peers only come from its trackers.
+    private: bool,
+    // The active peers which support holepunch, with the messages to send them as the relay.
+    holepunch_peers: Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<HolepunchMessage>>>,
+    // The peer which told us each PEX peer, the relay to ask if we fail to dial it.
+    pex_relays: Mutex<HashMap<SocketAddr, SocketAddr>>,
+    // The recent messages of the active peers, see `Torrent::peer_activity`.
+    activity: Arc<PeerActivityLog>,
+    // See `Torrent::transfer_totals`.
+    transfer: Arc<TransferTotals>,
+    // Told the address the peers see us at, None to ignore it.
+    external_ip: Option<Arc<ExternalIp>>,
+    // Told the disk failures and the trashed files, None to ignore them.
+    // The info hash the owner knows the torrent by, with where the disk events go.
+    disk_events: Option<(Sha1Hash, mpsc::UnboundedSender<(Sha1Hash, DiskEvent)>)>,
+    upload_pacer: UploadPacer,
+    // How long a peer may send nothing, not even a keep-alive, before it's dropped.
+    peer_timeout: Duration,
+    watchdog: std::sync::Mutex<StarvationWatchdog>,
+    choke_rounds: std::sync::Mutex<ChokeRounds>,
+    // The uTP sockets of the listener, the peers are dialed from them.
+    utp: Vec<UtpSocket>,
+}
+
+impl TorrentContext {
+    pub(crate) fn new(
+        torrent: Arc<Mutex<Torrent>>,
+        disk: Disk,
+        discovered_peers: mpsc::UnboundedSender<DialCandidate>,
+        dht: Option<Arc<Dht>>,
+        private: bool,
+        activity: Arc<PeerActivityLog>,
+        transfer: Arc<TransferTotals>,
+    ) -> Self {
+        Self {
+            torrent,
+            disk: RwLock::new(disk),
+            peers: Mutex::new(HashSet::new()),
+            discovered_peers,
+            // Announcing a private torrent to the DHT leaks its peers
+            dht: dht.filter(|_| !private),
+            private,
+            holepunch_peers: Mutex::new(HashMap::new()),
+            pex_relays: Mutex::new(HashMap::new()),
+            activity,
+            transfer,
+            external_ip: None,
+            disk_events: None,
+            upload_pacer: UploadPacer::new(),
+            peer_timeout: PEER_TIMEOUT,
+            watchdog: std::sync::Mutex::new(StarvationWatchdog::new(DEFAULT_STARVATION_TIMEOUT)),
+            choke_rounds: std::sync::Mutex::new(ChokeRounds::new(DEFAULT_UPLOAD_SLOTS)),
+            utp: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_peer_timeout(mut self, peer_timeout: Duration) -> Self {
+        self.peer_timeout = peer_timeout;
+        self
+    }
+
+    // How long a session may wait for blocks while unchoked before it's kicked.
+    pub(crate) fn with_starvation_timeout(self, starvation_timeout: Duration) -> Self {
+        *self.watchdog.lock().unwrap() = StarvationWatchdog::new(starvation_timeout);
+        self
+    }
+
+    pub(crate) fn with_external_ip(mut self, external_ip: Arc<ExternalIp>) -> Self {
+        self.external_ip = Some(external_ip);
+        self
+    }
+
+    pub(crate) fn with_disk_events(
+        mut self,
+        info_hash: Sha1Hash,
+        disk_events: mpsc::UnboundedSender<(Sha1Hash, DiskEvent)>,
+    ) -> Self {
+        self.disk_events = Some((info_hash, disk_events));
+        self
+    }
+
+    pub(crate) fn with_utp(mut self, utp: Vec<UtpSocket>) -> Self {
+        self.utp = utp;
+        self
+    }
+
+    // Ask the relay to connect us with the target, e.g. a peer the relay told us through PEX
+    // which we failed to dial. Returns false if the relay doesn't support holepunch.
+    pub(crate) async fn rendezvous(&self, relay: SocketAddr, target: SocketAddr) -> bool {
+        match self.holepunch_peers.lock().await.get(&relay) {
+            Some(sender) => sender.send(HolepunchMessage::Rendezvous(target)).is_ok(),
+            None => false,
+        }
+    }
+
+    // The peer is likely behind a NAT, the peer which told us of it may connect us. Only
+    // asked once, the relay dials us when it sends connect, so that dial isn't retried.
+    pub(crate) async fn on_dial_failed(&self, target: SocketAddr) {
+        let Some(relay) = self.pex_relays.lock().await.remove(&target) else {
+            return;
+        };
+        if self.rendezvous(relay, target).await {
+            log::debug!("Asked {} to connect us with {}", relay, target);
+        }
+    }
+
+    // The reads in flight finish on the old disk, the next ones wait for the cached pieces to
+    // be written first.
+    pub(crate) async fn restart_disk(&self, options: DiskOptions) {
+        let events = self.disk.write().await.restart(options).await;
+        self.watch_disk(events);
+    }
+
+    // Delete the files of the torrent through its disk, so the cached pieces aren't written
+    // after.
+    pub(crate) async fn delete_files(
+        &self,
+        metainfo: MetaInfo,
+        mode: DeleteMode,
+    ) -> std::result::Result<Vec<PathBuf>, DiskError> {
+        self.disk.read().await.delete_files(metainfo, mode).await
+    }
+
+    // Write the verified pieces of the torrent, until it's served again or gone.
+    pub(crate) fn write_pieces(self: &Arc<Self>, mut writes: mpsc::UnboundedReceiver<PieceWrite>) {
+        let context = Arc::downgrade(self);
+        tokio::spawn(async move {
+            while let Some((metainfo, piece, data)) = writes.recv().await {
+                let Some(context) = context.upgrade() else {
+                    break;
+                };
+                context.disk.read().await.write_piece(metainfo, piece, data);
+            }
+        });
+    }
+
+    // Tell the sessions of each piece once it's on the disk, until the disk is restarted or
+    // gone.
+    pub(crate) fn watch_disk(&self, mut events: mpsc::UnboundedReceiver<DiskEvent>) {
+        let torrent = Arc::downgrade(&self.torrent);
+        let disk_events = self.disk_events.clone();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                match event {
+                    DiskEvent::PieceWritten(piece_index) => {
+                        let Some(torrent) = torrent.upgrade() else {
+                            break;
+                        };
+                        torrent.lock().await.piece_written(piece_index as u32);
+                    }
+                    // Still told once the torrent is gone, e.g. the files trashed with it
+                    event => {
+                        if let Some((info_hash, disk_events)) = &disk_events {
+                            let _ = disk_events.send((*info_hash, event));
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    // The handshake tells the peers we speak v2, the torrent may not know yet if started from
+    // a magnet link.
+    async fn supports_v2(&self) -> bool {
+        self.torrent
+            .lock()
+            .await
+            .metainfo()
+            .is_some_and(|it| it.version() != MetaVersion::V1)
+    }
+
+    // Hand a peer to the peer manager, returns false if nobody takes the peers anymore.
+    // The peers from other sources than the trackers are dropped for a private torrent.
+    fn discover_peer(&self, candidate: DialCandidate) -> bool {
+        if self.private && !candidate.source.is_allowed_for_private() {
+            log::debug!(
+                "Ignore {:?} peer {} of private torrent",
+                candidate.source,
+                candidate.addr
+            );
+            return true;
+        }
+        self.discovered_peers.send(candidate).is_ok()
+    }
+}
+
+#[derive(Clone)]
 struct IdleSession {
     addr: SocketAddr,
+    // The local address to bind before dialing, None means let the OS choose.
+    local_addr: Option<IpAddr>,
+    transport: TransportPolicy,
+    encryption: EncryptionPolicy,
+    utp: Vec<UtpSocket>,
 }
 
 struct ConnectedSession {
-    socket: Framed<TcpStream, HandShakeCodec>,
+    socket: Framed<PeerStream, HandShakeCodec>,
+    // Whether to negotiate the encryption before the handshake with the peer we dialed.
+    encryption: EncryptionPolicy,
 }
 
 struct SessionContext {
@@ -42,36 +331,105 @@ struct SessionContext {
 }
 
 struct ActiveSession {
-    socket: Framed<TcpStream, MessageCodec>,
+    addr: SocketAddr,
+    // From the handshake of the peer, the blocks we request from it are reserved under it.
+    peer_id: PeerId,
+    socket: Framed<PeerStream, MessageCodec>,
     is_bitfield_exchanged: bool,
+    // The bitfield is only allowed before any other piece related message.
+    can_receive_bitfield: bool,
     ctx: SessionContext,
     bitfield: Option<BitField>,
     stats: PeerStats,
+    // The peer let our requests go overdue, it only gets one at a time until it sends a block.
+    snubbed: bool,
+    torrent: Arc<TorrentContext>,
+    uploads: UploadQueue,
+    // What the peer set in its handshake, we always set the extension protocol bit, and only
+    // exchange the DHT ports with the PORT message if it runs a DHT node.
+    capabilities: Capabilities,
+    peer_extensions: Option<ExtendedHandshake>,
+    extension_limiter: ExtensionLimiter,
+    pex: PexState,
+    // We told the peer we are a partial seed.
+    upload_only: bool,
+    // Of the torrent, updated with each request of the peer.
+    upload_limit: Option<u64>,
+    // The pieces we have but left out of the bitfield, still to be sent as have messages.
+    unrevealed: Vec<u32>,
+    // The holepunch messages other sessions asked us to send to the peer.
+    holepunch_sender: mpsc::UnboundedSender<HolepunchMessage>,
+    holepunch_receiver: mpsc::UnboundedReceiver<HolepunchMessage>,
+    last_read: Instant,
+    last_write: Instant,
+    connected_at: Instant,
+    // A block went either way, for the dead weight of the choke rounds.
+    last_transfer: Option<Instant>,
 }
 
 struct DisconnectedSession;
 
 impl IdleSession {
-    fn new(addr: SocketAddr) -> Self {
-        Self { addr }
+    fn new(
+        addr: SocketAddr,
+        local_addr: Option<IpAddr>,
+        transport: TransportPolicy,
+        encryption: EncryptionPolicy,
+        utp: Vec<UtpSocket>,
+    ) -> Self {
+        Self {
+            addr,
+            local_addr,
+            transport,
+            encryption,
+            utp,
+        }
     }
 
     async fn connect(self) -> Result<Session> {
-        let socket = TcpStream::connect(self.addr).await?;
+        let socket = self
+            .transport
+            .connect_from(self.addr, self.local_addr, &self.utp)
+            .await?;
         let socket = Framed::new(socket, HandShakeCodec);
-        Ok(Session::Connected(ConnectedSession::new(socket)))
+        Ok(Session::Connected(ConnectedSession::new(
+            socket,
+            self.encryption,
+        )))
     }
 }
 
 impl ConnectedSession {
-    fn new(socket: Framed<TcpStream, HandShakeCodec>) -> Self {
-        Self { socket }
+    fn new(socket: Framed<PeerStream, HandShakeCodec>, encryption: EncryptionPolicy) -> Self {
+        Self { socket, encryption }
     }
 
-    async fn handshake(self, info_hash: Sha1Hash, peer_id: PeerId) -> Result<Session> {
+    // The info hash of a hybrid torrent is the one of the swarm we found the peer in, either
+    // the v1 hash or the truncated v2 hash, see `MetaInfo::info_hashes`.
+    async fn handshake(
+        self,
+        info_hash: Sha1Hash,
+        peer_id: PeerId,
+        torrent: Arc<TorrentContext>,
+    ) -> Result<Session> {
         let mut socket = self.socket;
+        let peer_tls = torrent.torrent.lock().await.options().peer_tls.clone();
+        if let Some(peer_tls) = peer_tls {
+            // TLS takes the place of the MSE encryption
+            let stream = peer_tls::connect(socket.into_inner(), &info_hash, &peer_tls).await?;
+            socket = Framed::new(stream, HandShakeCodec);
+        } else if self.encryption != EncryptionPolicy::Allow {
+            let stream = mse::initiate(socket.into_inner(), info_hash, self.encryption).await?;
+            socket = Framed::new(stream, HandShakeCodec);
+        }
         log::info!("Waiting for handshake with peer");
-        let handshake = HandShake::new(info_hash, peer_id);
+        let mut handshake = HandShake::new(info_hash, peer_id);
+        if torrent.dht.is_some() {
+            handshake = handshake.with_dht();
+        }
+        if torrent.supports_v2().await {
+            handshake = handshake.with_v2();
+        }
         socket.send(handshake).await?;
         if let Some(handshake) = socket.next().await {
             match handshake {
@@ -86,8 +444,15 @@ impl ConnectedSession {
                         socket.close().await?;
                         Ok(Session::Disconnected(DisconnectedSession {}))
                     } else {
+                        let addr = socket.get_ref().peer_addr()?;
                         let socket = Framed::new(socket.into_inner(), MessageCodec);
-                        Ok(Session::Active(ActiveSession::new(socket)))
+                        Ok(Session::Active(Box::new(ActiveSession::new(
+                            addr,
+                            handshake.peer_id,
+                            socket,
+                            torrent,
+                            handshake.capabilities,
+                        ))))
                     }
                 }
                 Err(e) => {
@@ -105,11 +470,151 @@ impl ConnectedSession {
             Ok(Session::Disconnected(DisconnectedSession {}))
         }
     }
+
+    // The peer dialed us, it sends the handshake first and we only reply if we serve the torrent.
+    async fn accept(
+        self,
+        peer_id: PeerId,
+        find_torrent: impl FnOnce(&Sha1Hash) -> Option<Arc<TorrentContext>>,
+    ) -> Result<Session> {
+        let mut socket = self.socket;
+        let handshake = match socket.next().await {
+            Some(Ok(handshake)) => handshake,
+            Some(Err(e)) => {
+                log::warn!("Failed to decode handshake from incoming peer: {:?}", e);
+                return Err(PeerError::Protocol("invalid handshake"));
+            }
+            None => return Ok(Session::Disconnected(DisconnectedSession {})),
+        };
+        let Some(torrent) = find_torrent(&handshake.info_hash) else {
+            log::warn!(
+                "Incoming peer asked for unknown torrent {:?}",
+                handshake.info_hash
+            );
+            socket.close().await?;
+            return Ok(Session::Disconnected(DisconnectedSession {}));
+        };
+
+        // Answered with the hash the peer asked for, a hybrid torrent is in both swarms
+        let mut reply = HandShake::new(handshake.info_hash, peer_id);
+        if torrent.dht.is_some() {
+            reply = reply.with_dht();
+        }
+        if torrent.supports_v2().await {
+            reply = reply.with_v2();
+        }
+        socket.send(reply).await?;
+        let addr = socket.get_ref().peer_addr()?;
+        let socket = Framed::new(socket.into_inner(), MessageCodec);
+        Ok(Session::Active(Box::new(ActiveSession::new(
+            addr,
+            handshake.peer_id,
+            socket,
+            torrent,
+            handshake.capabilities,
+        ))))
+    }
+}
+
+// Serve a peer which connected to us, until either side closes the connection.
+// The handshake must arrive within the timeout, so slow peers can't hold the connection.
+// An encrypting peer names its torrent by a hash, so `info_hashes` are all the served torrents.
+pub(crate) async fn serve_incoming(
+    stream: PeerStream,
+    peer_id: PeerId,
+    handshake_timeout: Duration,
+    encryption: EncryptionPolicy,
+    info_hashes: &[Sha1Hash],
+    find_torrent: impl FnOnce(&Sha1Hash) -> Option<Arc<TorrentContext>>,
+) -> Result<()> {
+    let accept = async {
+        let stream = mse::accept(stream, info_hashes, encryption).await?;
+        let session = ConnectedSession::new(Framed::new(stream, HandShakeCodec), encryption);
+        session.accept(peer_id, find_torrent).await
+    };
+    let session = timeout(handshake_timeout, accept)
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Handshake timed out"))??;
+    if let Session::Active(session) = session {
+        session.run().await?;
+    }
+    Ok(())
+}
+
+// Dial the peer and serve it until either side closes the connection, `on_connected` is
+// called once the handshake is done. With `Prefer`, a peer which fails the encryption handshake
+// is dialed again in plaintext, most of them just don't support it.
+pub(crate) async fn serve_outgoing(
+    addr: SocketAddr,
+    encryption: EncryptionPolicy,
+    peer_id: PeerId,
+    handshake_timeout: Duration,
+    torrent: Arc<TorrentContext>,
+    on_connected: impl FnOnce(),
+) -> Result<()> {
+    let (info_hash, local_addr, transport) = {
+        let torrent = torrent.torrent.lock().await;
+        let options = torrent.options();
+        (torrent.info_hash(), options.bind_addr, options.transport)
+    };
+    let session = IdleSession::new(addr, local_addr, transport, encryption, torrent.utp.clone());
+    let plaintext = (encryption == EncryptionPolicy::Prefer).then(|| IdleSession {
+        encryption: EncryptionPolicy::Allow,
+        ..session.clone()
+    });
+    let mut result = dial(session, info_hash, peer_id, handshake_timeout, &torrent).await;
+    if let (Err(PeerError::Encryption(e)), Some(plaintext)) = (&result, plaintext) {
+        log::info!("Dial {} again in plaintext: {}", addr, e);
+        result = dial(plaintext, info_hash, peer_id, handshake_timeout, &torrent).await;
+    }
+    // Shown in the peer list, e.g. the peer failed the certificate pinning of an SSL torrent
+    let session = result.inspect_err(|e| torrent.activity.record_failure(addr, e.to_string()))?;
+    if let Session::Active(session) = session {
+        on_connected();
+        session.run().await?;
+    }
+    Ok(())
+}
+
+// Connect and handshake, the peer has `handshake_timeout` for both.
+async fn dial(
+    session: IdleSession,
+    info_hash: Sha1Hash,
+    peer_id: PeerId,
+    handshake_timeout: Duration,
+    torrent: &Arc<TorrentContext>,
+) -> Result<Session> {
+    let dial = async {
+        let mut session = Session::Idle(session);
+        loop {
+            session = match session {
+                Session::Idle(session) => session.connect().await?,
+                Session::Connected(session) => {
+                    session
+                        .handshake(info_hash, peer_id, torrent.clone())
+                        .await?
+                }
+                session => return Ok(session),
+            };
+        }
+    };
+    timeout(handshake_timeout, dial)
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Handshake timed out"))?
 }
 
 impl ActiveSession {
-    fn new(socket: Framed<TcpStream, MessageCodec>) -> Self {
+    fn new(
+        addr: SocketAddr,
+        peer_id: PeerId,
+        socket: Framed<PeerStream, MessageCodec>,
+        torrent: Arc<TorrentContext>,
+        capabilities: Capabilities,
+    ) -> Self {
+        let (holepunch_sender, holepunch_receiver) = mpsc::unbounded_channel();
         Self {
+            addr,
+            peer_id,
             socket,
             ctx: SessionContext {
                 is_choked: true,
@@ -118,48 +623,448 @@ impl ActiveSession {
                 is_peer_interested: false,
             },
             is_bitfield_exchanged: false,
+            can_receive_bitfield: true,
             bitfield: None,
             stats: PeerStats::new(20),
+            snubbed: false,
+            torrent,
+            uploads: UploadQueue::new(),
+            capabilities,
+            peer_extensions: None,
+            extension_limiter: ExtensionLimiter::new(),
+            pex: PexState::new(),
+            upload_only: false,
+            upload_limit: None,
+            unrevealed: Vec::new(),
+            holepunch_sender,
+            holepunch_receiver,
+            last_read: Instant::now(),
+            last_write: Instant::now(),
+            connected_at: Instant::now(),
+            last_transfer: None,
         }
     }
 
     async fn on_tick(&mut self) -> Result<()> {
-        // Check if we need to send keep-alive message or any other message should be sent.
+        let silent = self.last_read.elapsed();
+        if silent > self.torrent.peer_timeout {
+            log::info!("Peer {} sent nothing for {:?}", self.addr, silent);
+            return Err(PeerError::Inactive(silent));
+        }
+        if self.stats.is_snubbed(Instant::now()) {
+            // Its requests are overdue, the blocks go to the other peers
+            if !self.snubbed {
+                log::info!("Peer {} snubbed us", self.addr);
+            }
+            self.snubbed = true;
+            self.release_requests().await;
+        }
+        self.fill_requests().await?;
+        self.check_starvation().await?;
+        self.update_choke().await?;
+        if self.last_write.elapsed() >= KEEP_ALIVE_INTERVAL {
+            self.send(Message::KeepAlive).await?;
+        }
+        if self.pex.is_due() {
+            self.send_pex().await?;
+        }
+        for _ in 0..LAZY_BITFIELD_REVEAL_PER_TICK {
+            let Some(piece_index) = self.unrevealed.pop() else {
+                break;
+            };
+            self.send(Message::Have { piece_index }).await?;
+        }
+        // The handshake can be sent again to update it, e.g. when we become a partial seed
+        if self.capabilities.extension_protocol
+            && self.upload_only != self.torrent.torrent.lock().await.is_partial_seed().await
+        {
+            self.send_extended_handshake().await?;
+        }
+        Ok(())
+    }
+
+    // Keep enough requests in flight that the peer always has a block to send us, see
+    // `PeerStats::pipeline_length`. The picker reserves the blocks for the peer.
+    async fn fill_requests(&mut self) -> Result<()> {
+        if !self.ctx.is_interested || self.ctx.is_peer_choked {
+            return Ok(());
+        }
+        let Some(bitfield) = &self.bitfield else {
+            return Ok(());
+        };
+        let pipeline_length = if self.snubbed {
+            1
+        } else {
+            self.stats.pipeline_length()
+        };
+        let count = pipeline_length.saturating_sub(self.stats.requests_in_flight());
+        let mut blocks = Vec::with_capacity(count);
+        if count > 0 {
+            let torrent = self.torrent.torrent.lock().await;
+            while blocks.len() < count
+                && let Some(block) = torrent.request_block(self.peer_id, bitfield).await
+            {
+                blocks.push(block);
+            }
+        }
+        for block in blocks {
+            self.send(Message::Request {
+                piece_index: block.piece_index,
+                begin: block.begin,
+                length: block.length,
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    // Our requests are dropped or overdue, let the other sessions request the blocks.
+    async fn release_requests(&mut self) {
+        self.stats.clear_requests();
+        self.torrent
+            .torrent
+            .lock()
+            .await
+            .release_requests(self.peer_id)
+            .await;
+    }
+
+    // The first session to notice the torrent is starved logs what it looks like, each
+    // starved session closes itself, the peer is dialed again through the peer manager.
+    async fn check_starvation(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let unchoked = self.ctx.is_interested && !self.ctx.is_peer_choked;
+        let requests = self.stats.requests_in_flight();
+        let (report, starved) = {
+            let mut watchdog = self.torrent.watchdog.lock().unwrap();
+            watchdog.update(self.addr, unchoked, requests, now);
+            let starved = watchdog
+                .is_starved(&self.addr, now)
+                .then(|| watchdog.timeout());
+            (watchdog.check(now), starved)
+        };
+        if let Some(report) = report {
+            let (left, in_flight) = {
+                let torrent = self.torrent.torrent.lock().await;
+                (
+                    torrent.left_bytes().await,
+                    torrent.requests_in_flight().await,
+                )
+            };
+            log::warn!(
+                "Torrent starved, no block for {:?}, {} of {} sessions waiting, {} requests in flight, {} bytes left, starved: {:?}",
+                report.since_last_block,
+                report.waiting,
+                report.sessions,
+                in_flight,
+                left,
+                report.starved
+            );
+        }
+        if let Some(timeout) = starved {
+            self.torrent.watchdog.lock().unwrap().remove(&self.addr);
+            log::info!("Kick peer {} which sends no blocks", self.addr);
+            return Err(PeerError::Starved(timeout));
+        }
+        Ok(())
+    }
+
+    // Report the peer to the choke rounds, then choke or unchoke it as the last round decided.
+    async fn update_choke(&mut self) -> Result<()> {
+        let options = {
+            let torrent = self.torrent.torrent.lock().await;
+            let options = torrent.options();
+            ChokeRoundOptions {
+                strategy: options.choke_strategy,
+                is_seeding: torrent.phase().await == TorrentPhase::Seeding,
+                max_connections: options
+                    .max_peers
+                    .unwrap_or(PeerManagerOptions::default().max_connections_per_torrent),
+                dead_weight: options.dead_weight,
+            }
+        };
+        let decision = {
+            let mut rounds = self.torrent.choke_rounds.lock().unwrap();
+            rounds.update(self.addr, self.choke_snapshot());
+            rounds.decide(&self.addr, &options, tokio::time::Instant::now())
+        };
+        if decision.dead_weight {
+            let idle = self.last_transfer.unwrap_or(self.connected_at).elapsed();
+            log::info!("Drop peer {} which transferred nothing", self.addr);
+            return Err(PeerError::DeadWeight(idle));
+        }
+        if decision.unchoke == self.ctx.is_choked {
+            self.set_choked(!decision.unchoke).await?;
+        }
+        Ok(())
+    }
+
+    fn choke_snapshot(&self) -> PeerConnection {
+        let mut peer = PeerConnection::new(0);
+        peer.peer_bitfield = self.bitfield.clone().unwrap_or_default();
+        peer.is_choked = self.ctx.is_choked;
+        peer.is_interesting = self.ctx.is_interested;
+        peer.is_peer_choked = self.ctx.is_peer_choked;
+        // It told us it won't download anything, whatever it says about its interest
+        peer.is_peer_interesting = self.ctx.is_peer_interested && !self.is_peer_upload_only();
+        peer.download_rate = self.stats.download_rate();
+        peer.upload_rate = self.stats.upload_rate();
+        peer.connected_at = tokio::time::Instant::from_std(self.connected_at);
+        peer.last_transfer_at = self.last_transfer.map(tokio::time::Instant::from_std);
+        peer
+    }
+
+    async fn set_choked(&mut self, choked: bool) -> Result<()> {
+        self.ctx.is_choked = choked;
+        // The peer drops its requests when we choke it, it asks again once unchoked.
+        if choked && !self.uploads.is_empty() {
+            log::debug!("Dropping the queued uploads to choked peer {}", self.addr);
+            self.uploads.clear();
+        }
+        let message = if choked {
+            Message::Choke
+        } else {
+            Message::Unchoke
+        };
+        self.send(message).await
+    }
+
+    // Tell the peer whether it has anything we want, after its bitfield and haves, and after
+    // each piece we verify, which may have been the last one it had for us.
+    async fn update_interest(&mut self) -> Result<()> {
+        let interested = match &self.bitfield {
+            Some(bitfield) => {
+                self.torrent
+                    .torrent
+                    .lock()
+                    .await
+                    .is_interesting(bitfield)
+                    .await
+            }
+            None => false,
+        };
+        if interested != self.ctx.is_interested {
+            self.set_interested(interested).await?;
+        }
+        // A partial seed won't take anything from us either, the slot is better used by another.
+        // Until we have the info dict it may still send it.
+        if !interested
+            && self.is_peer_upload_only()
+            && self.torrent.torrent.lock().await.metainfo().is_some()
+        {
+            log::info!("Drop upload only peer {} with nothing we want", self.addr);
+            return Err(PeerError::NothingToExchange);
+        }
+        Ok(())
+    }
+
+    fn is_peer_upload_only(&self) -> bool {
+        self.peer_extensions
+            .as_ref()
+            .is_some_and(ExtendedHandshake::is_upload_only)
+    }
+
+    async fn set_interested(&mut self, interested: bool) -> Result<()> {
+        self.ctx.is_interested = interested;
+        let message = if interested {
+            Message::Interested
+        } else {
+            Message::NotInterested
+        };
+        self.send(message).await
+    }
+
+    async fn send_pex(&mut self) -> Result<()> {
+        if self.torrent.private {
+            return Ok(());
+        }
+        let Some(id) = self
+            .peer_extensions
+            .as_ref()
+            .and_then(|it| it.extension_id(UT_PEX))
+        else {
+            return Ok(());
+        };
+        let mut peers = self.torrent.peers.lock().await.clone();
+        peers.remove(&self.addr);
+        if let Some(message) = self.pex.next_message(&peers) {
+            self.send(Message::Extended {
+                id,
+                payload: message.to_bytes()?,
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn on_pex_message(&mut self, message: PexMessage) {
+        log::debug!(
+            "Received {} peers through PEX from {}",
+            message.added.len(),
+            self.addr
+        );
+        for addr in message.added {
+            if addr == self.addr {
+                continue;
+            }
+            self.torrent.pex_relays.lock().await.insert(addr, self.addr);
+            let candidate = DialCandidate::new(addr, PeerSource::Pex, None);
+            if !self.torrent.discover_peer(candidate) {
+                break;
+            }
+        }
+    }
+
+    // As the relay, connect the peer with the target if we are connected to both.
+    async fn on_rendezvous(&mut self, target: SocketAddr) -> Result<()> {
+        let error = if target == self.addr {
+            Some(ErrorCode::NoSelf)
+        } else if target.ip().is_unspecified() || target.port() == 0 {
+            Some(ErrorCode::NoSuchPeer)
+        } else if let Some(sender) = self.torrent.holepunch_peers.lock().await.get(&target) {
+            // The target session is gone if the send fails
+            match sender.send(HolepunchMessage::Connect(self.addr)) {
+                Ok(_) => None,
+                Err(_) => Some(ErrorCode::NotConnected),
+            }
+        } else if self.torrent.peers.lock().await.contains(&target) {
+            Some(ErrorCode::NoSupport)
+        } else {
+            Some(ErrorCode::NotConnected)
+        };
+        let message = match error {
+            Some(code) => HolepunchMessage::Error(target, code),
+            None => HolepunchMessage::Connect(target),
+        };
+        self.send_holepunch(message).await
+    }
+
+    async fn on_holepunch_message(&mut self, message: HolepunchMessage) -> Result<()> {
+        match message {
+            HolepunchMessage::Rendezvous(target) => return self.on_rendezvous(target).await,
+            HolepunchMessage::Connect(addr) => {
+                log::debug!("Relay {} asked to connect to {}", self.addr, addr);
+                let candidate = DialCandidate::new(addr, PeerSource::Holepunch, None);
+                self.torrent.discover_peer(candidate);
+            }
+            HolepunchMessage::Error(addr, code) => {
+                log::info!(
+                    "Relay {} failed to connect us to {}: {:?}",
+                    self.addr,
+                    addr,
+                    code
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_holepunch(&mut self, message: HolepunchMessage) -> Result<()> {
+        let Some(id) = self
+            .peer_extensions
+            .as_ref()
+            .and_then(|it| it.extension_id(UT_HOLEPUNCH))
+        else {
+            return Ok(());
+        };
+        self.send(Message::Extended {
+            id,
+            payload: message.to_bytes(),
+        })
+        .await?;
         Ok(())
     }
 
     async fn on_message(&mut self, message: Message) -> Result<()> {
         let message_id = message.message_id();
         log::info!("Received message: {:?}", message_id);
+        if let Some(event) = PeerEvent::from_message(&message) {
+            self.torrent.activity.record(self.addr, event);
+        }
+        let can_receive_bitfield = self.can_receive_bitfield;
+        if matches!(
+            message,
+            Message::Have { .. }
+                | Message::Bitfield { .. }
+                | Message::Request { .. }
+                | Message::Piece { .. }
+                | Message::Cancel { .. }
+        ) {
+            self.can_receive_bitfield = false;
+        }
         match message {
             Message::KeepAlive => Ok(()),
             Message::Choke => {
                 self.ctx.is_peer_choked = true;
+                self.release_requests().await;
                 Ok(())
             }
             Message::Unchoke => {
                 self.ctx.is_peer_choked = false;
-                Ok(())
+                self.fill_requests().await
             }
             Message::Interested => {
                 self.ctx.is_peer_interested = true;
-                // TODO: send unchoke message base on strategy
+                self.torrent
+                    .choke_rounds
+                    .lock()
+                    .unwrap()
+                    .on_interest_changed(&self.addr, true);
                 Ok(())
             }
             Message::NotInterested => {
                 self.ctx.is_peer_interested = false;
-                // TODO: send choke message base on strategy
+                self.torrent
+                    .choke_rounds
+                    .lock()
+                    .unwrap()
+                    .on_interest_changed(&self.addr, false);
                 Ok(())
             }
             Message::Have { piece_index } => {
-                // TODO: update bitfield and check if we need to send interested message or request
-                Ok(())
+                // A peer with no pieces may skip the bitfield
+                if self.bitfield.is_none() {
+                    let torrent = self.torrent.torrent.lock().await;
+                    self.bitfield = torrent
+                        .metainfo()
+                        .map(|it| BitField::repeat(false, it.piece_count()));
+                }
+                let is_new = match &mut self.bitfield {
+                    Some(bitfield) => match bitfield.get_mut(piece_index as usize) {
+                        Some(mut has) => !has.replace(true),
+                        None => return Err(PeerError::Protocol("have of invalid piece")),
+                    },
+                    None => false,
+                };
+                if is_new {
+                    let wanted = {
+                        let torrent = self.torrent.torrent.lock().await;
+                        torrent.add_peer_have(piece_index).await;
+                        torrent.wants_piece(piece_index).await
+                    };
+                    // Only this piece is new, no need to look at the whole bitfield
+                    if wanted && !self.ctx.is_interested {
+                        self.set_interested(true).await?;
+                    }
+                }
+                self.fill_requests().await
             }
             Message::Bitfield { bitfield } => {
+                if !self.is_bitfield_exchanged && !can_receive_bitfield {
+                    return Err(PeerError::Protocol("bitfield after other piece messages"));
+                }
                 if !self.is_bitfield_exchanged {
                     self.is_bitfield_exchanged = true;
+                    self.torrent
+                        .torrent
+                        .lock()
+                        .await
+                        .add_peer_bitfield(&bitfield)
+                        .await;
                     self.bitfield = Some(bitfield);
                     log::info!("Received bitfield message from peer");
+                    self.update_interest().await?;
+                    self.fill_requests().await?;
                 } else {
                     log::warn!("Received bitfield message again, ignoring");
                 }
@@ -170,7 +1075,36 @@ impl ActiveSession {
                 begin,
                 length,
             } => {
-                // TODO: if I have the piece and unchoked, try to send the piece base on strategy
+                if length > MAX_REQUEST_LENGTH {
+                    return Err(PeerError::Protocol("request is too large"));
+                }
+                if self.ctx.is_choked {
+                    log::warn!("Received request from choked peer, ignoring");
+                    return Ok(());
+                }
+                let (metainfo, is_rare) = {
+                    let torrent = self.torrent.torrent.lock().await;
+                    if !torrent.has_piece(piece_index).await {
+                        log::warn!("Received request for piece {} we don't have", piece_index);
+                        return Ok(());
+                    }
+                    self.upload_limit = torrent.options().upload_limit;
+                    (
+                        torrent.metainfo().cloned(),
+                        torrent.is_rare_piece(piece_index).await,
+                    )
+                };
+                let Some(metainfo) = metainfo else {
+                    return Ok(());
+                };
+                let block = BlockInfo::new(piece_index, begin, length);
+                let read = self
+                    .torrent
+                    .disk
+                    .read()
+                    .await
+                    .read_block(metainfo, BlockInfo::new(piece_index, begin, length));
+                self.uploads.push_read(block, is_rare, read);
                 Ok(())
             }
             Message::Piece {
@@ -178,40 +1112,408 @@ impl ActiveSession {
                 begin,
                 piece,
             } => {
-                // TODO: verify piece
-                // if verified, write to disk and send have message to other peers
-                // also update the own bitfield
                 self.stats.record_download(piece.len());
-                // TODO: save piece information and wait the piece is fully completed, verify it
-                Ok(())
+                self.torrent.transfer.record_download(piece.len() as u64);
+                let now = Instant::now();
+                self.last_transfer = Some(now);
+                if self.stats.record_block(piece_index, begin, now).is_some() {
+                    self.snubbed = false;
+                    if let Some(latency) = self.stats.latency() {
+                        self.torrent.activity.record_latency(self.addr, latency);
+                    }
+                }
+                self.torrent
+                    .watchdog
+                    .lock()
+                    .unwrap()
+                    .on_block(self.addr, now);
+                let block = Block {
+                    piece_index,
+                    begin,
+                    data: piece,
+                    peer: Some(self.addr),
+                };
+                // The torrent verifies the piece once all blocks are received, and remembers
+                // which peers sent it. The sessions send have once it's written.
+                if let Err(e) = self.torrent.torrent.lock().await.add_block(block).await {
+                    log::warn!("Failed to add block of piece {}: {:?}", piece_index, e);
+                }
+                self.fill_requests().await
             }
             Message::Cancel {
                 piece_index,
                 begin,
                 length,
             } => {
-                // TODO: if piece not send yet, cancel the request
+                // Drop the block if it's not written to the socket yet,
+                // also abort the disk read if it's not started.
+                self.uploads
+                    .cancel(&BlockInfo::new(piece_index, begin, length));
+                Ok(())
+            }
+            Message::Port { port } => {
+                if let Some(dht) = &self.torrent.dht {
+                    let dht = dht.clone();
+                    let addr = SocketAddr::new(self.addr.ip(), port);
+                    // Ping it in background, the node is added once it responds.
+                    tokio::spawn(async move {
+                        dht.add_node(addr).await;
+                    });
+                }
+                Ok(())
+            }
+            Message::Extended { id, payload } => self.on_extended_message(id, payload).await,
+        }
+    }
+
+    async fn send_extended_handshake(&mut self) -> Result<()> {
+        let (metadata_size, upload_only) = {
+            let torrent = self.torrent.torrent.lock().await;
+            let metadata_size = torrent.info_bytes().map(|info| info.len() as u64);
+            (metadata_size, torrent.is_partial_seed().await)
+        };
+        let mut handshake = ExtendedHandshake::new(metadata_size).with_your_ip(self.addr.ip());
+        if self.torrent.private {
+            handshake = handshake.without_pex();
+        }
+        if upload_only {
+            handshake = handshake.with_upload_only();
+        }
+        self.upload_only = upload_only;
+        let payload = handshake.to_bytes()?;
+        self.send(Message::Extended {
+            id: extension::HANDSHAKE_ID,
+            payload,
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn on_extended_message(&mut self, id: u8, payload: Vec<u8>) -> Result<()> {
+        if !self.extension_limiter.allow(id, payload.len()) {
+            log::warn!(
+                "Dropped extended message {} of {} bytes over the limit",
+                id,
+                payload.len()
+            );
+            return Ok(());
+        }
+        match id {
+            extension::HANDSHAKE_ID => {
+                let handshake = match ExtendedHandshake::from_bytes(&payload) {
+                    Ok(handshake) => handshake,
+                    Err(e) => {
+                        log::warn!("Failed to parse extension handshake: {:?}", e);
+                        return Ok(());
+                    }
+                };
+                if let Some(metadata_size) = handshake.metadata_size {
+                    let mut torrent = self.torrent.torrent.lock().await;
+                    if let Some(downloader) = torrent.metadata_downloader()
+                        && let Err(e) = downloader.set_total_size(metadata_size as usize)
+                    {
+                        log::warn!("Peer sent invalid metadata size: {:?}", e);
+                    }
+                }
+                if let (Some(external_ip), Some(ip)) =
+                    (&self.torrent.external_ip, handshake.your_ip())
+                {
+                    external_ip.vote_from_peer(self.addr.ip(), ip);
+                }
+                if handshake.extension_id(UT_HOLEPUNCH).is_some() {
+                    self.torrent
+                        .holepunch_peers
+                        .lock()
+                        .await
+                        .insert(self.addr, self.holepunch_sender.clone());
+                }
+                self.peer_extensions = Some(handshake);
+                // The handshake may come again to tell it became upload only
+                if self.bitfield.is_some() {
+                    self.update_interest().await?;
+                }
+                self.request_metadata().await
+            }
+            extension::UT_METADATA_ID => {
+                let message = match MetadataMessage::from_bytes(&payload) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        log::warn!("Failed to parse metadata message: {:?}", e);
+                        return Ok(());
+                    }
+                };
+                self.on_metadata_message(message).await
+            }
+            extension::UT_PEX_ID => {
+                match PexMessage::from_bytes(&payload) {
+                    Ok(message) => self.on_pex_message(message).await,
+                    Err(e) => log::warn!("Failed to parse PEX message: {:?}", e),
+                }
+                Ok(())
+            }
+            extension::UT_HOLEPUNCH_ID => match HolepunchMessage::from_bytes(&payload) {
+                Ok(message) => self.on_holepunch_message(message).await,
+                Err(e) => {
+                    log::warn!("Failed to parse holepunch message: {:?}", e);
+                    Ok(())
+                }
+            },
+            _ => {
+                log::warn!("Received unknown extended message {}, ignoring", id);
+                Ok(())
+            }
+        }
+    }
+
+    async fn on_metadata_message(&mut self, message: MetadataMessage) -> Result<()> {
+        match message {
+            MetadataMessage::Request { piece } => {
+                let info_bytes = self.torrent.torrent.lock().await.info_bytes();
+                let data = info_bytes
+                    .as_ref()
+                    .and_then(|info| Some((info.len(), metadata_piece(info, piece)?)));
+                let message = match data {
+                    Some((total_size, data)) => MetadataMessage::Data {
+                        piece,
+                        total_size: total_size as u64,
+                        data: data.to_vec(),
+                    },
+                    // We are still fetching it too, or the piece is out of range
+                    None => MetadataMessage::Reject { piece },
+                };
+                self.send_metadata_message(message).await
+            }
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                let mut torrent = self.torrent.torrent.lock().await;
+                let Some(downloader) = torrent.metadata_downloader() else {
+                    return Ok(());
+                };
+                match downloader.add_piece(piece, total_size, data) {
+                    Ok(Some(info_bytes)) => {
+                        log::info!("Received all metadata pieces");
+                        if let Err(e) = torrent.set_info_bytes(&info_bytes).await {
+                            log::error!("Failed to build metainfo from metadata: {:?}", e);
+                        }
+                        Ok(())
+                    }
+                    Ok(None) => {
+                        drop(torrent);
+                        self.request_metadata().await
+                    }
+                    Err(e) => {
+                        log::warn!("Received invalid metadata piece {}: {:?}", piece, e);
+                        Ok(())
+                    }
+                }
+            }
+            MetadataMessage::Reject { piece } => {
+                let mut torrent = self.torrent.torrent.lock().await;
+                if let Some(downloader) = torrent.metadata_downloader() {
+                    downloader.reset_request(piece);
+                }
                 Ok(())
             }
         }
     }
 
+    // Request the next missing metadata piece if the torrent is still fetching metadata.
+    async fn request_metadata(&mut self) -> Result<()> {
+        let supports_metadata = self
+            .peer_extensions
+            .as_ref()
+            .is_some_and(|it| it.extension_id(UT_METADATA).is_some());
+        if !supports_metadata {
+            return Ok(());
+        }
+        let piece = {
+            let mut torrent = self.torrent.torrent.lock().await;
+            torrent
+                .metadata_downloader()
+                .and_then(|downloader| downloader.next_request())
+        };
+        match piece {
+            Some(piece) => {
+                self.send_metadata_message(MetadataMessage::Request { piece })
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn send_metadata_message(&mut self, message: MetadataMessage) -> Result<()> {
+        let Some(id) = self
+            .peer_extensions
+            .as_ref()
+            .and_then(|it| it.extension_id(UT_METADATA))
+        else {
+            return Ok(());
+        };
+        self.send(Message::Extended {
+            id,
+            payload: message.to_bytes()?,
+        })
+        .await?;
+        Ok(())
+    }
+
     async fn run(mut self) -> Result<Session> {
         log::info!("Handling messages with peer");
 
+        self.torrent.peers.lock().await.insert(self.addr);
+        let result = self.process_messages().await;
+        self.torrent.peers.lock().await.remove(&self.addr);
+        self.torrent.holepunch_peers.lock().await.remove(&self.addr);
+        self.torrent
+            .pex_relays
+            .lock()
+            .await
+            .retain(|_, relay| *relay != self.addr);
+        self.torrent.activity.remove(&self.addr);
+        self.torrent.watchdog.lock().unwrap().remove(&self.addr);
+        self.torrent.choke_rounds.lock().unwrap().remove(&self.addr);
+        self.release_requests().await;
+        if let Some(bitfield) = &self.bitfield {
+            self.torrent
+                .torrent
+                .lock()
+                .await
+                .remove_peer_bitfield(bitfield)
+                .await;
+        }
+        result?;
+
+        self.socket.close().await?;
+        Ok(Session::Disconnected(DisconnectedSession {}))
+    }
+
+    async fn send(&mut self, message: Message) -> Result<()> {
+        // The round trips are timed from when the request goes out
+        let now = Instant::now();
+        match &message {
+            Message::Request {
+                piece_index, begin, ..
+            } => self.stats.record_request(*piece_index, *begin, now),
+            Message::Cancel {
+                piece_index, begin, ..
+            } => self.stats.cancel_request(*piece_index, *begin),
+            _ => {}
+        }
+        self.socket.send(message).await?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+
+    // Write the piece message a chunk at a time under the upload limit, see `UploadPacer`.
+    // The whole message is written before anything else, the peer can't tell the difference.
+    async fn send_paced(&mut self, message: Message) -> Result<()> {
+        if self.upload_limit.is_none() {
+            return self.send(message).await;
+        }
+        let mut bytes = BytesMut::new();
+        MessageCodec.encode(message, &mut bytes)?;
+        // The framed socket flushes on every send, nothing of it is left to write before ours
+        for chunk in bytes.chunks(PACING_CHUNK_SIZE) {
+            let delay = self
+                .torrent
+                .upload_pacer
+                .reserve(chunk.len(), self.upload_limit);
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            self.socket.get_mut().write_all(chunk).await?;
+        }
+        self.socket.get_mut().flush().await?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+
+    async fn send_bitfield(&mut self) -> Result<()> {
+        let (bitfield, lazy) = {
+            let torrent = self.torrent.torrent.lock().await;
+            (
+                torrent.own_bitfield().await,
+                torrent.options().lazy_bitfield,
+            )
+        };
+        // The bitfield is optional if we have no pieces yet
+        if bitfield.not_any() {
+            return Ok(());
+        }
+        let (bitfield, unrevealed) = if lazy {
+            hide_pieces(bitfield)
+        } else {
+            (bitfield, Vec::new())
+        };
+        self.unrevealed = unrevealed;
+        self.send(Message::Bitfield { bitfield }).await?;
+        Ok(())
+    }
+
+    async fn process_messages(&mut self) -> Result<()> {
+        self.send_bitfield().await?;
+        if self.capabilities.extension_protocol {
+            self.send_extended_handshake().await?;
+        }
+        if self.capabilities.dht
+            && let Some(dht) = &self.torrent.dht
+        {
+            let port = dht.local_addr()?.port();
+            self.send(Message::Port { port }).await?;
+        }
+
         let mut ticker = interval(Duration::from_secs(1));
+        let mut verified = self.torrent.torrent.lock().await.subscribe_verified();
 
         loop {
             tokio::select! {
                 _now = ticker.tick() => {
                     self.on_tick().await?;
                 }
-                Some(message) = self.socket.next() => {
+                Some(message) = self.holepunch_receiver.recv() => {
+                    self.send_holepunch(message).await?;
+                }
+                result = verified.recv() => {
+                    match result {
+                        Ok(piece_index) => self.send(Message::Have { piece_index }).await?,
+                        // Missed some of them is fine, the peer learns of those from the
+                        // next session
+                        Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        // The torrent outlives its sessions
+                        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    }
+                    self.update_interest().await?;
+                }
+                result = self.uploads.wait_read() => {
+                    if let Err(e) = result {
+                        log::error!("Failed to read block from disk: {:?}", e);
+                    }
+                }
+                _ = std::future::ready(()), if self.uploads.has_outbound() => {
+                    // Write one block at a time, so the Cancel received in between can still
+                    // remove the blocks left in the queue.
+                    if let Some(message) = self.uploads.pop_outbound() {
+                        let length = message.message_length();
+                        self.send_paced(message).await?;
+                        self.stats.record_upload(length);
+                        self.torrent.transfer.record_upload(length as u64);
+                        self.last_transfer = Some(Instant::now());
+                    }
+                }
+                message = self.socket.next() => {
                     match message {
-                        Ok(message) => {
+                        Some(Ok(message)) => {
+                            self.last_read = Instant::now();
                             self.on_message(message).await?;
                         }
-                        Err(e) => {
+                        None => {
+                            log::info!("Peer {} closed the connection", self.addr);
+                            return Ok(());
+                        }
+                        Some(Err(e)) => {
                             log::error!("Failed to decode message: {:?}", e);
                             return Err(PeerError::Io(std::io::Error::new(
                                 std::io::ErrorKind::InvalidData,
@@ -222,8 +1524,41 @@ impl ActiveSession {
                 }
             }
         }
+    }
+}
+
+// Leave some random pieces out of the bitfield, returns the bitfield to send and the hidden
+// pieces in the order to reveal them.
+fn hide_pieces(mut bitfield: BitField) -> (BitField, Vec<u32>) {
+    let mut pieces: Vec<u32> = bitfield.iter_ones().map(|it| it as u32).collect();
+    let count = (pieces.len() / LAZY_BITFIELD_HIDDEN_RATIO).clamp(1, LAZY_BITFIELD_MAX_HIDDEN);
+    let (hidden, _) = pieces.partial_shuffle(&mut rand::rng(), count);
+    let hidden = hidden.to_vec();
+    for piece_index in &hidden {
+        bitfield.set(*piece_index as usize, false);
+    }
+    (bitfield, hidden)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hide_pieces() {
+        let bitfield = BitField::repeat(true, 100);
+        let (sent, hidden) = hide_pieces(bitfield.clone());
+        assert_eq!(hidden.len(), 100 / LAZY_BITFIELD_HIDDEN_RATIO);
+        assert_eq!(sent.count_ones() + hidden.len(), 100);
+        for piece_index in &hidden {
+            assert!(!sent[*piece_index as usize]);
+        }
 
-        self.socket.close().await?;
-        Ok(Session::Disconnected(DisconnectedSession {}))
+        // At least one piece is hidden, and only the pieces we have
+        let mut bitfield = BitField::repeat(false, 10);
+        bitfield.set(3, true);
+        let (sent, hidden) = hide_pieces(bitfield);
+        assert!(sent.not_any());
+        assert_eq!(hidden, vec![3]);
     }
 }
@@ -0,0 +1,278 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{message::Message, peer_stats::PeerLatency};
+
+// The last few messages each peer sent us, for the recent activity of the peer detail pane.
+// Cheap enough to always keep, unlike the full wire logging.
+
+const MAX_EVENTS_PER_PEER: usize = 64;
+const MAX_FAILURES: usize = 32;
+
+// What the peer told us, the payloads are left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PeerEvent {
+    ChokedUs,
+    UnchokedUs,
+    Interested,
+    NotInterested,
+    Have {
+        piece_index: u32,
+    },
+    Bitfield {
+        pieces: usize,
+    },
+    Requested {
+        piece_index: u32,
+        begin: u32,
+        length: u32,
+    },
+    SentBlock {
+        piece_index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Canceled {
+        piece_index: u32,
+        begin: u32,
+        length: u32,
+    },
+    DhtPort {
+        port: u16,
+    },
+    Extended {
+        id: u8,
+    },
+}
+
+impl PeerEvent {
+    // None for the keep-alives, they would push everything else out.
+    pub(crate) fn from_message(message: &Message) -> Option<Self> {
+        let event = match message {
+            Message::KeepAlive => return None,
+            Message::Choke => PeerEvent::ChokedUs,
+            Message::Unchoke => PeerEvent::UnchokedUs,
+            Message::Interested => PeerEvent::Interested,
+            Message::NotInterested => PeerEvent::NotInterested,
+            Message::Have { piece_index } => PeerEvent::Have {
+                piece_index: *piece_index,
+            },
+            Message::Bitfield { bitfield } => PeerEvent::Bitfield {
+                pieces: bitfield.count_ones(),
+            },
+            Message::Request {
+                piece_index,
+                begin,
+                length,
+            } => PeerEvent::Requested {
+                piece_index: *piece_index,
+                begin: *begin,
+                length: *length,
+            },
+            Message::Piece {
+                piece_index,
+                begin,
+                piece,
+            } => PeerEvent::SentBlock {
+                piece_index: *piece_index,
+                begin: *begin,
+                length: piece.len() as u32,
+            },
+            Message::Cancel {
+                piece_index,
+                begin,
+                length,
+            } => PeerEvent::Canceled {
+                piece_index: *piece_index,
+                begin: *begin,
+                length: *length,
+            },
+            Message::Port { port } => PeerEvent::DhtPort { port: *port },
+            Message::Extended { id, .. } => PeerEvent::Extended { id: *id },
+        };
+        Some(event)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PeerActivity {
+    // Milliseconds since the unix epoch.
+    pub at: u64,
+    pub event: PeerEvent,
+}
+
+// Why we dropped a peer before talking to it, e.g. it failed the certificate pinning.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PeerFailure {
+    pub at: u64,
+    pub addr: SocketAddr,
+    pub reason: String,
+}
+
+// A row of the peer list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ConnectedPeer {
+    pub addr: SocketAddr,
+    // None until the peer answered one of our requests.
+    pub latency: Option<PeerLatency>,
+}
+
+// The activity of the connected peers of a torrent, a peer is forgotten once it disconnects.
+// The failures are kept after, they are what the user looks for when a peer never shows up.
+#[derive(Debug, Default)]
+pub struct PeerActivityLog {
+    peers: Mutex<HashMap<SocketAddr, VecDeque<PeerActivity>>>,
+    failures: Mutex<VecDeque<PeerFailure>>,
+    latencies: Mutex<HashMap<SocketAddr, PeerLatency>>,
+}
+
+impl PeerActivityLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record(&self, addr: SocketAddr, event: PeerEvent) {
+        let at = now_millis();
+        let mut peers = self.peers.lock().unwrap();
+        let events = peers.entry(addr).or_default();
+        if events.len() == MAX_EVENTS_PER_PEER {
+            events.pop_front();
+        }
+        events.push_back(PeerActivity { at, event });
+    }
+
+    pub(crate) fn remove(&self, addr: &SocketAddr) {
+        self.peers.lock().unwrap().remove(addr);
+        self.latencies.lock().unwrap().remove(addr);
+    }
+
+    pub(crate) fn record_latency(&self, addr: SocketAddr, latency: PeerLatency) {
+        self.latencies.lock().unwrap().insert(addr, latency);
+    }
+
+    pub(crate) fn record_failure(&self, addr: SocketAddr, reason: String) {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() == MAX_FAILURES {
+            failures.pop_front();
+        }
+        failures.push_back(PeerFailure {
+            at: now_millis(),
+            addr,
+            reason,
+        });
+    }
+
+    // The oldest first, of all the peers.
+    pub fn failures(&self) -> Vec<PeerFailure> {
+        self.failures.lock().unwrap().iter().cloned().collect()
+    }
+
+    // The peers which sent anything, by address.
+    pub fn connected(&self) -> Vec<ConnectedPeer> {
+        let latencies = self.latencies.lock().unwrap();
+        let mut peers: Vec<ConnectedPeer> = self
+            .peers
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|addr| ConnectedPeer {
+                addr: *addr,
+                latency: latencies.get(addr).copied(),
+            })
+            .collect();
+        peers.sort_by_key(|it| it.addr);
+        peers
+    }
+
+    // The oldest first, empty if the peer isn't connected.
+    pub fn recent(&self, addr: &SocketAddr) -> Vec<PeerActivity> {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(addr)
+            .map(|events| events.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::BitField;
+
+    use super::*;
+
+    #[test]
+    fn test_keep_last_events() {
+        let log = PeerActivityLog::new();
+        let addr: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let mut bitfield = BitField::repeat(false, 8);
+        bitfield.set(2, true);
+        let messages = [
+            Message::KeepAlive,
+            Message::Bitfield { bitfield },
+            Message::Unchoke,
+        ];
+        for message in &messages {
+            if let Some(event) = PeerEvent::from_message(message) {
+                log.record(addr, event);
+            }
+        }
+        let events: Vec<_> = log.recent(&addr).iter().map(|it| it.event).collect();
+        assert_eq!(
+            events,
+            [PeerEvent::Bitfield { pieces: 1 }, PeerEvent::UnchokedUs]
+        );
+
+        for piece_index in 0..MAX_EVENTS_PER_PEER as u32 {
+            log.record(addr, PeerEvent::Have { piece_index });
+        }
+        let events = log.recent(&addr);
+        assert_eq!(events.len(), MAX_EVENTS_PER_PEER);
+        assert_eq!(events[0].event, PeerEvent::Have { piece_index: 0 });
+
+        assert_eq!(
+            log.connected(),
+            [ConnectedPeer {
+                addr,
+                latency: None
+            }]
+        );
+        let latency = PeerLatency {
+            rtt_ms: 120,
+            rtt_variance_ms: 30,
+            samples: 4,
+        };
+        log.record_latency(addr, latency);
+        assert_eq!(log.connected()[0].latency, Some(latency));
+
+        log.remove(&addr);
+        assert!(log.recent(&addr).is_empty());
+        assert!(log.connected().is_empty());
+    }
+
+    #[test]
+    fn test_keep_last_failures() {
+        let log = PeerActivityLog::new();
+        for port in 0..MAX_FAILURES as u16 + 1 {
+            let addr = SocketAddr::from(([1, 2, 3, 4], port));
+            log.record_failure(addr, "Peer sent no certificate".to_string());
+        }
+        let failures = log.failures();
+        assert_eq!(failures.len(), MAX_FAILURES);
+        assert_eq!(failures[0].addr.port(), 1);
+    }
+}
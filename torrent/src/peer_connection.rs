@@ -1,8 +1,10 @@
+use std::time::Duration;
+
 use tokio::{sync::broadcast, time::Instant};
 
 use crate::types::BitField;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PeerConnection {
     pub peer_bitfield: BitField,
 
@@ -17,6 +19,15 @@ pub struct PeerConnection {
 
     // Last time I'm unchoke the peer
     pub last_unchoked_at: Option<Instant>,
+
+    // Bytes per second the peer sends me
+    pub download_rate: f64,
+    // Bytes per second I send the peer
+    pub upload_rate: f64,
+
+    pub connected_at: Instant,
+    // Last time any bytes went either way, None if nothing ever did
+    pub last_transfer_at: Option<Instant>,
 }
 
 impl PeerConnection {
@@ -28,6 +39,23 @@ impl PeerConnection {
             is_peer_choked: true,
             is_peer_interesting: false,
             last_unchoked_at: None,
+            download_rate: 0.0,
+            upload_rate: 0.0,
+            connected_at: Instant::now(),
+            last_transfer_at: None,
+        }
+    }
+
+    // How long nothing was transferred with the peer.
+    pub fn idle_for(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_transfer_at.unwrap_or(self.connected_at))
+    }
+
+    // How much of the torrent the peer has, between 0 and 1.
+    pub fn progress(&self) -> f64 {
+        if self.peer_bitfield.is_empty() {
+            return 0.0;
         }
+        self.peer_bitfield.count_ones() as f64 / self.peer_bitfield.len() as f64
     }
 }
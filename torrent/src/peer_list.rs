@@ -0,0 +1,110 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+use thiserror::Error;
+
+use crate::dialer::{DialCandidate, PeerSource};
+
+pub(crate) type Result<T> = std::result::Result<T, PeerListError>;
+
+#[derive(Error, Debug)]
+pub enum PeerListError {
+    #[error("Failed to access peer list file")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid peer {entry:?} at line {line}")]
+    InvalidEntry { line: usize, entry: String },
+}
+
+// A plain text list of peers, one `ip:port` per line, the IPv6 addresses in brackets.
+// Used to look into a swarm, or to bootstrap a private swarm without a tracker.
+
+pub fn format(peers: &[SocketAddr]) -> String {
+    peers.iter().map(|peer| format!("{}\n", peer)).collect()
+}
+
+// The blank lines and the lines starting with `#` are skipped, the duplicated peers are
+// taken once. The peers are what the user gave us, so they are tagged as manual.
+pub fn parse(text: &str) -> Result<Vec<DialCandidate>> {
+    let mut seen = HashSet::new();
+    let mut candidates = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let entry = line.trim();
+        if entry.is_empty() || entry.starts_with('#') {
+            continue;
+        }
+        let addr = entry
+            .parse::<SocketAddr>()
+            .ok()
+            .filter(is_dialable)
+            .ok_or_else(|| PeerListError::InvalidEntry {
+                line: index + 1,
+                entry: entry.to_string(),
+            })?;
+        if seen.insert(addr) {
+            candidates.push(DialCandidate::new(addr, PeerSource::Manual, None));
+        }
+    }
+    Ok(candidates)
+}
+
+pub fn export(peers: &[SocketAddr], path: &Path) -> Result<()> {
+    std::fs::write(path, format(peers))?;
+    Ok(())
+}
+
+pub fn import(path: &Path) -> Result<Vec<DialCandidate>> {
+    parse(&std::fs::read_to_string(path)?)
+}
+
+fn is_dialable(addr: &SocketAddr) -> bool {
+    let ip_ok = match addr.ip() {
+        IpAddr::V4(ip) => !ip.is_unspecified() && !ip.is_multicast() && !ip.is_broadcast(),
+        IpAddr::V6(ip) => !ip.is_unspecified() && !ip.is_multicast(),
+    };
+    ip_ok && addr.port() != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let peers: Vec<SocketAddr> = vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "[2001:db8::1]:51413".parse().unwrap(),
+        ];
+        let text = format(&peers);
+        assert_eq!(text, "10.0.0.1:6881\n[2001:db8::1]:51413\n");
+
+        let candidates = parse(&format!("# exported\n\n{} \n10.0.0.1:6881\n", text)).unwrap();
+        let addrs: Vec<SocketAddr> = candidates.iter().map(|it| it.addr).collect();
+        assert_eq!(addrs, peers);
+        assert!(candidates.iter().all(|it| it.source == PeerSource::Manual));
+    }
+
+    #[test]
+    fn test_invalid_entries() {
+        for entry in [
+            "10.0.0.1",
+            "example.com:6881",
+            "10.0.0.1:0",
+            "0.0.0.0:6881",
+            "224.0.0.1:6881",
+            "[::]:6881",
+        ] {
+            let text = format!("10.0.0.1:6881\n{}\n", entry);
+            match parse(&text) {
+                Err(PeerListError::InvalidEntry { line, entry: got }) => {
+                    assert_eq!(line, 2);
+                    assert_eq!(got, entry);
+                }
+                other => panic!("{} is accepted: {:?}", entry, other),
+            }
+        }
+    }
+}
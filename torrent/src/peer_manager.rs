@@ -0,0 +1,452 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    dialer::{DialCandidate, DialQueue},
+    types::Sha1Hash,
+};
+
+// Decide which peers of which torrents to dial, so the sessions of all the torrents together
+// stay under the connection limits. The peers from the trackers, the DHT and PEX are pushed
+// here, the engine dials what `next_dials` returns and reports back how it went.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerManagerOptions {
+    // Of all the torrents together, the sockets run out otherwise.
+    pub max_connections: usize,
+    // For the torrents which don't set their own `TorrentOptions::max_peers`.
+    pub max_connections_per_torrent: usize,
+    // Before a failed peer is dialed again, doubled for each failure in a row.
+    pub reconnect_backoff: Duration,
+    pub max_reconnect_backoff: Duration,
+    // A peer failing this many times in a row is given up on, until it connects to us.
+    pub max_failures: u32,
+}
+
+impl Default for PeerManagerOptions {
+    fn default() -> Self {
+        Self {
+            max_connections: 200,
+            max_connections_per_torrent: 50,
+            reconnect_backoff: Duration::from_secs(30),
+            max_reconnect_backoff: Duration::from_secs(30 * 60),
+            max_failures: 5,
+        }
+    }
+}
+
+// Why a connected peer went away, reported by the engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    // We closed it, e.g. the torrent stopped or the peer was choked away, the peer did nothing
+    // wrong.
+    Closed,
+    // The peer closed it, or the connection dropped.
+    PeerClosed,
+    // The handshake or a message was invalid.
+    Protocol,
+    // The peer sent nothing for too long.
+    Timeout,
+}
+
+// What went wrong with an address, the peers which never failed have none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PeerHistory {
+    // In a row, reset when the peer connects to us or we close it cleanly.
+    pub failures: u32,
+    // Since the peer was first seen.
+    pub connect_failures: u32,
+    pub disconnects: u32,
+    pub last_disconnect: Option<DisconnectReason>,
+    // Not dialed before this.
+    pub retry_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct TorrentPeers {
+    candidates: DialQueue,
+    // The failed peers waiting out their backoff, queued again at `PeerHistory::retry_at`.
+    backing_off: HashMap<SocketAddr, DialCandidate>,
+    dialing: HashSet<SocketAddr>,
+    connected: HashSet<SocketAddr>,
+    history: HashMap<SocketAddr, PeerHistory>,
+    max_connections: Option<usize>,
+}
+
+impl TorrentPeers {
+    fn connections(&self) -> usize {
+        self.dialing.len() + self.connected.len()
+    }
+
+    fn is_given_up(&self, addr: &SocketAddr, max_failures: u32) -> bool {
+        self.history
+            .get(addr)
+            .is_some_and(|it| it.failures >= max_failures)
+    }
+
+    // Returns false if the peer is given up on, otherwise it's not dialed until its backoff
+    // is over.
+    fn record_failure(
+        &mut self,
+        addr: SocketAddr,
+        options: &PeerManagerOptions,
+        now: Instant,
+    ) -> bool {
+        let history = self.history.entry(addr).or_default();
+        history.failures += 1;
+        if history.failures >= options.max_failures {
+            history.retry_at = None;
+            self.backing_off.remove(&addr);
+            return false;
+        }
+        let backoff = options
+            .reconnect_backoff
+            .saturating_mul(2u32.saturating_pow(history.failures - 1))
+            .min(options.max_reconnect_backoff);
+        history.retry_at = Some(now + backoff);
+        true
+    }
+
+    // Queue the peers whose backoff is over.
+    fn requeue_due(&mut self, now: Instant) {
+        let due: Vec<SocketAddr> = self
+            .backing_off
+            .keys()
+            .filter(|addr| {
+                self.history
+                    .get(addr)
+                    .and_then(|it| it.retry_at)
+                    .is_none_or(|it| it <= now)
+            })
+            .copied()
+            .collect();
+        for addr in due {
+            if let Some(candidate) = self.backing_off.remove(&addr) {
+                self.candidates.push(candidate);
+            }
+        }
+    }
+}
+
+pub struct PeerManager {
+    options: PeerManagerOptions,
+    torrents: HashMap<Sha1Hash, TorrentPeers>,
+    // The torrent to dial for first in the next round, so each torrent gets its turn.
+    next_torrent: usize,
+}
+
+impl PeerManager {
+    pub fn new(options: PeerManagerOptions) -> Self {
+        Self {
+            options,
+            torrents: HashMap::new(),
+            next_torrent: 0,
+        }
+    }
+
+    // None uses `max_connections_per_torrent`.
+    pub fn add_torrent(&mut self, info_hash: Sha1Hash, max_connections: Option<usize>) {
+        self.torrents.entry(info_hash).or_default().max_connections = max_connections;
+    }
+
+    pub fn set_max_connections(&mut self, info_hash: &Sha1Hash, max_connections: Option<usize>) {
+        if let Some(peers) = self.torrents.get_mut(info_hash) {
+            peers.max_connections = max_connections;
+        }
+    }
+
+    // The sessions of the torrent are closed by the caller.
+    pub fn remove_torrent(&mut self, info_hash: &Sha1Hash) {
+        self.torrents.remove(info_hash);
+    }
+
+    // Returns false if the peer is known already, whichever source it came from first.
+    // A peer which failed before waits out its backoff, every announce brings it up again.
+    pub fn add_candidate(&mut self, info_hash: &Sha1Hash, mut candidate: DialCandidate) -> bool {
+        let Some(peers) = self.torrents.get_mut(info_hash) else {
+            return false;
+        };
+        let addr = candidate.addr;
+        if peers.dialing.contains(&addr)
+            || peers.connected.contains(&addr)
+            || peers.backing_off.contains_key(&addr)
+            || peers.is_given_up(&addr, self.options.max_failures)
+        {
+            return false;
+        }
+        if let Some(history) = peers.history.get(&addr) {
+            candidate.failures = history.failures;
+            if history.retry_at.is_some_and(|it| it > Instant::now()) {
+                peers.backing_off.insert(addr, candidate);
+                return true;
+            }
+        }
+        peers.candidates.push(candidate)
+    }
+
+    // Take the candidates to dial now, as many as the limits leave room for.
+    // They count as connections until `on_dial_failed` or `on_disconnected`.
+    pub fn next_dials(&mut self) -> Vec<(Sha1Hash, DialCandidate)> {
+        self.next_dials_at(Instant::now())
+    }
+
+    fn next_dials_at(&mut self, now: Instant) -> Vec<(Sha1Hash, DialCandidate)> {
+        for peers in self.torrents.values_mut() {
+            peers.requeue_due(now);
+        }
+        let mut dials = Vec::new();
+        let mut info_hashes: Vec<Sha1Hash> = self.torrents.keys().copied().collect();
+        info_hashes.sort();
+        if info_hashes.is_empty() {
+            return dials;
+        }
+        let start = self.next_torrent % info_hashes.len();
+        info_hashes.rotate_left(start);
+        self.next_torrent = start + 1;
+
+        // One peer for each torrent at a time, so the first torrent doesn't take all the room
+        let mut total = self.connection_count();
+        loop {
+            let mut dialed = false;
+            for info_hash in &info_hashes {
+                if total >= self.options.max_connections {
+                    return dials;
+                }
+                let peers = self.torrents.get_mut(info_hash).unwrap();
+                let limit = peers
+                    .max_connections
+                    .unwrap_or(self.options.max_connections_per_torrent);
+                if peers.connections() >= limit {
+                    continue;
+                }
+                if let Some(candidate) = peers.candidates.pop() {
+                    peers.dialing.insert(candidate.addr);
+                    dials.push((*info_hash, candidate));
+                    total += 1;
+                    dialed = true;
+                }
+            }
+            if !dialed {
+                return dials;
+            }
+        }
+    }
+
+    // The peer is queued again after its backoff, behind the ones which never failed, or
+    // given up on.
+    pub fn on_dial_failed(&mut self, info_hash: &Sha1Hash, mut candidate: DialCandidate) {
+        let Some(peers) = self.torrents.get_mut(info_hash) else {
+            return;
+        };
+        let addr = candidate.addr;
+        peers.dialing.remove(&addr);
+        peers.history.entry(addr).or_default().connect_failures += 1;
+        if peers.record_failure(addr, &self.options, Instant::now()) {
+            candidate.failures = peers.history[&addr].failures;
+            peers.backing_off.insert(addr, candidate);
+        }
+    }
+
+    // A dialed peer connected, or a peer connected to us. Returns false if the limits are
+    // reached and the connection should be closed.
+    pub fn on_connected(&mut self, info_hash: &Sha1Hash, addr: SocketAddr) -> bool {
+        let total = self.connection_count();
+        let Some(peers) = self.torrents.get_mut(info_hash) else {
+            return false;
+        };
+        // The dialed peers were counted already when they were taken
+        if peers.dialing.remove(&addr) {
+            return peers.connected.insert(addr);
+        }
+        let limit = peers
+            .max_connections
+            .unwrap_or(self.options.max_connections_per_torrent);
+        if total >= self.options.max_connections || peers.connections() >= limit {
+            return false;
+        }
+        // It's up again, whatever happened before
+        if let Some(history) = peers.history.get_mut(&addr) {
+            history.failures = 0;
+            history.retry_at = None;
+        }
+        peers.backing_off.remove(&addr);
+        peers.connected.insert(addr)
+    }
+
+    // The peer is forgotten until it's added again, if it failed the backoff applies then.
+    pub fn on_disconnected(
+        &mut self,
+        info_hash: &Sha1Hash,
+        addr: &SocketAddr,
+        reason: DisconnectReason,
+    ) {
+        let Some(peers) = self.torrents.get_mut(info_hash) else {
+            return;
+        };
+        peers.connected.remove(addr);
+        peers.dialing.remove(addr);
+        let history = peers.history.entry(*addr).or_default();
+        history.disconnects += 1;
+        history.last_disconnect = Some(reason);
+        if reason == DisconnectReason::Closed {
+            history.failures = 0;
+            history.retry_at = None;
+        } else {
+            peers.record_failure(*addr, &self.options, Instant::now());
+        }
+    }
+
+    pub fn peer_history(&self, info_hash: &Sha1Hash, addr: &SocketAddr) -> Option<PeerHistory> {
+        self.torrents.get(info_hash)?.history.get(addr).copied()
+    }
+
+    // The connected and the dialing peers of all the torrents.
+    pub fn connection_count(&self) -> usize {
+        self.torrents.values().map(TorrentPeers::connections).sum()
+    }
+
+    pub fn torrent_connection_count(&self, info_hash: &Sha1Hash) -> usize {
+        self.torrents
+            .get(info_hash)
+            .map_or(0, TorrentPeers::connections)
+    }
+
+    pub fn candidate_count(&self, info_hash: &Sha1Hash) -> usize {
+        self.torrents
+            .get(info_hash)
+            .map_or(0, |it| it.candidates.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dialer::PeerSource;
+
+    use super::*;
+
+    fn candidate(port: u16, source: PeerSource) -> DialCandidate {
+        DialCandidate::new(SocketAddr::from(([10, 0, 0, 1], port)), source, None)
+    }
+
+    #[test]
+    fn test_deduplicate_candidates() {
+        let mut manager = PeerManager::new(PeerManagerOptions::default());
+        let info_hash = [1u8; 20];
+        manager.add_torrent(info_hash, None);
+        assert!(manager.add_candidate(&info_hash, candidate(1, PeerSource::Tracker)));
+        // The same peer from the DHT and PEX
+        assert!(!manager.add_candidate(&info_hash, candidate(1, PeerSource::Dht)));
+        assert!(!manager.add_candidate(&info_hash, candidate(1, PeerSource::Pex)));
+        assert!(!manager.add_candidate(&[2u8; 20], candidate(2, PeerSource::Tracker)));
+
+        let dials = manager.next_dials();
+        assert_eq!(dials.len(), 1);
+        // Not dialed twice while connected
+        assert!(manager.on_connected(&info_hash, dials[0].1.addr));
+        assert!(!manager.add_candidate(&info_hash, candidate(1, PeerSource::Pex)));
+        manager.on_disconnected(&info_hash, &dials[0].1.addr, DisconnectReason::Closed);
+        assert!(manager.add_candidate(&info_hash, candidate(1, PeerSource::Pex)));
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let mut manager = PeerManager::new(PeerManagerOptions {
+            max_connections: 5,
+            max_connections_per_torrent: 3,
+            ..Default::default()
+        });
+        let (a, b) = ([1u8; 20], [2u8; 20]);
+        manager.add_torrent(a, None);
+        manager.add_torrent(b, Some(1));
+        for port in 0..10 {
+            manager.add_candidate(&a, candidate(port, PeerSource::Tracker));
+            manager.add_candidate(&b, candidate(100 + port, PeerSource::Tracker));
+        }
+
+        let dials = manager.next_dials();
+        assert_eq!(dials.iter().filter(|(it, _)| *it == a).count(), 3);
+        assert_eq!(dials.iter().filter(|(it, _)| *it == b).count(), 1);
+        assert!(manager.next_dials().is_empty());
+
+        // An incoming peer of a full torrent is turned away
+        let incoming = SocketAddr::from(([10, 0, 0, 2], 6881));
+        assert!(!manager.on_connected(&b, incoming));
+
+        // A failed dial frees the room for the next candidate
+        let (info_hash, failed) = dials.into_iter().find(|(it, _)| *it == a).unwrap();
+        manager.on_dial_failed(&info_hash, failed);
+        assert_eq!(manager.next_dials().len(), 1);
+        assert_eq!(manager.torrent_connection_count(&a), 3);
+        assert_eq!(manager.connection_count(), 4);
+    }
+
+    #[test]
+    fn test_give_up_failing_peer() {
+        let mut manager = PeerManager::new(PeerManagerOptions::default());
+        let info_hash = [1u8; 20];
+        manager.add_torrent(info_hash, None);
+        manager.add_candidate(&info_hash, candidate(1, PeerSource::Tracker));
+        // Past any backoff
+        let later = Instant::now() + Duration::from_secs(24 * 60 * 60);
+        for _ in 0..PeerManagerOptions::default().max_failures {
+            let (_, candidate) = manager.next_dials_at(later).pop().unwrap();
+            manager.on_dial_failed(&info_hash, candidate);
+        }
+        assert!(manager.next_dials_at(later).is_empty());
+        assert!(!manager.add_candidate(&info_hash, candidate(1, PeerSource::Tracker)));
+        assert_eq!(manager.candidate_count(&info_hash), 0);
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let mut manager = PeerManager::new(PeerManagerOptions::default());
+        let info_hash = [1u8; 20];
+        manager.add_torrent(info_hash, None);
+        let addr = SocketAddr::from(([10, 0, 0, 1], 1));
+        manager.add_candidate(&info_hash, candidate(1, PeerSource::Tracker));
+        let (_, dial) = manager.next_dials().pop().unwrap();
+        let failed_at = Instant::now();
+        manager.on_dial_failed(&info_hash, dial);
+
+        // Not dialed again before the backoff, even if an announce brings it up again
+        assert!(manager.next_dials().is_empty());
+        assert!(!manager.add_candidate(&info_hash, candidate(1, PeerSource::Tracker)));
+        assert!(
+            manager
+                .next_dials_at(failed_at + Duration::from_secs(20))
+                .is_empty()
+        );
+        let (_, dial) = manager
+            .next_dials_at(failed_at + Duration::from_secs(31))
+            .pop()
+            .unwrap();
+        assert_eq!(dial.failures, 1);
+
+        // Doubled for the second failure in a row
+        let failed_at = Instant::now();
+        manager.on_dial_failed(&info_hash, dial);
+        let history = manager.peer_history(&info_hash, &addr).unwrap();
+        assert_eq!(history.failures, 2);
+        assert_eq!(history.connect_failures, 2);
+        assert!(history.retry_at.unwrap() >= failed_at + Duration::from_secs(60));
+
+        // It connected to us, a clean start
+        assert!(manager.on_connected(&info_hash, addr));
+        assert!(
+            manager
+                .next_dials_at(failed_at + Duration::from_secs(3600))
+                .is_empty()
+        );
+        manager.on_disconnected(&info_hash, &addr, DisconnectReason::Timeout);
+        let history = manager.peer_history(&info_hash, &addr).unwrap();
+        assert_eq!(history.failures, 1);
+        assert_eq!(history.disconnects, 1);
+        assert_eq!(history.last_disconnect, Some(DisconnectReason::Timeout));
+        // Known again, but waits out the backoff of the timeout
+        assert!(manager.add_candidate(&info_hash, candidate(1, PeerSource::Pex)));
+        assert!(manager.next_dials().is_empty());
+    }
+}
@@ -1,10 +1,59 @@
-use std::collections::VecDeque;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 
+use serde::Serialize;
+
+use crate::piece_picker::BLOCK_SIZE;
+
+// The requests in flight to a peer, from the first sample on the bandwidth-delay product.
+const MIN_PIPELINE_LENGTH: usize = 4;
+const MAX_PIPELINE_LENGTH: usize = 250;
+// A peer which doesn't answer the oldest request within this, from its usual round trip, is
+// snubbing us.
+const MIN_SNUB_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
 pub struct PeerStats {
     upload: ThroughputRate,
     download: ThroughputRate,
-    window: Duration,
+    // When each request in flight was sent, by the piece index and the begin of the block.
+    requests: HashMap<(u32, u32), Instant>,
+    round_trip: Option<RoundTrip>,
+}
+
+// From a Request to its Piece, smoothed like the TCP retransmission timer of RFC 6298.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RoundTrip {
+    smoothed: Duration,
+    variance: Duration,
+    samples: u64,
+}
+
+impl RoundTrip {
+    fn new(sample: Duration) -> Self {
+        Self {
+            smoothed: sample,
+            variance: sample / 2,
+            samples: 1,
+        }
+    }
+
+    fn add(&mut self, sample: Duration) {
+        let deviation = self.smoothed.abs_diff(sample);
+        self.variance = (self.variance * 3 + deviation) / 4;
+        self.smoothed = (self.smoothed * 7 + sample) / 8;
+        self.samples += 1;
+    }
+}
+
+// For the peer list, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PeerLatency {
+    pub rtt_ms: u64,
+    pub rtt_variance_ms: u64,
+    pub samples: u64,
 }
 
 impl PeerStats {
@@ -12,7 +61,8 @@ impl PeerStats {
         Self {
             upload: ThroughputRate::new(window_secs),
             download: ThroughputRate::new(window_secs),
-            window: Duration::from_secs(window_secs),
+            requests: HashMap::new(),
+            round_trip: None,
         }
     }
 
@@ -31,6 +81,69 @@ impl PeerStats {
     pub fn download_rate(&self) -> f64 {
         self.download.rate()
     }
+
+    pub fn record_request(&mut self, piece_index: u32, begin: u32, now: Instant) {
+        self.requests.insert((piece_index, begin), now);
+    }
+
+    pub fn cancel_request(&mut self, piece_index: u32, begin: u32) {
+        self.requests.remove(&(piece_index, begin));
+    }
+
+    // The peer drops our requests when it chokes us.
+    pub fn clear_requests(&mut self) {
+        self.requests.clear();
+    }
+
+    pub fn requests_in_flight(&self) -> usize {
+        self.requests.len()
+    }
+
+    // Returns the round trip of the block, None if we didn't request it.
+    pub fn record_block(&mut self, piece_index: u32, begin: u32, now: Instant) -> Option<Duration> {
+        let sent = self.requests.remove(&(piece_index, begin))?;
+        let sample = now.saturating_duration_since(sent);
+        match &mut self.round_trip {
+            Some(round_trip) => round_trip.add(sample),
+            None => self.round_trip = Some(RoundTrip::new(sample)),
+        }
+        Some(sample)
+    }
+
+    // None until the peer answered a request.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.round_trip.map(|it| it.smoothed)
+    }
+
+    pub fn latency(&self) -> Option<PeerLatency> {
+        self.round_trip.map(|it| PeerLatency {
+            rtt_ms: it.smoothed.as_millis() as u64,
+            rtt_variance_ms: it.variance.as_millis() as u64,
+            samples: it.samples,
+        })
+    }
+
+    // How many requests to keep in flight so the peer never waits on us, the blocks it sends
+    // within a round trip at its current rate.
+    pub fn pipeline_length(&self) -> usize {
+        let Some(rtt) = self.rtt() else {
+            return MIN_PIPELINE_LENGTH;
+        };
+        let blocks = self.download_rate() * rtt.as_secs_f64() / BLOCK_SIZE as f64;
+        (blocks.ceil() as usize + 1).clamp(MIN_PIPELINE_LENGTH, MAX_PIPELINE_LENGTH)
+    }
+
+    // The oldest request is overdue for the round trips we have seen from the peer.
+    pub fn is_snubbed(&self, now: Instant) -> bool {
+        let Some(oldest) = self.requests.values().min() else {
+            return false;
+        };
+        let timeout = self
+            .round_trip
+            .map_or(MAX_SNUB_TIMEOUT, |it| it.smoothed + it.variance * 4)
+            .clamp(MIN_SNUB_TIMEOUT, MAX_SNUB_TIMEOUT);
+        now.saturating_duration_since(*oldest) > timeout
+    }
 }
 
 struct ThroughputRate {
@@ -52,7 +165,14 @@ impl ThroughputRate {
     }
 
     fn rate(&self) -> f64 {
-        let total: usize = self.log.iter().map(|&(_, b)| b).sum();
+        // The log is only cleaned up when recording, skip the expired records here.
+        let now = Instant::now();
+        let total: usize = self
+            .log
+            .iter()
+            .filter(|&&(t, _)| now.duration_since(t) <= self.window)
+            .map(|&(_, b)| b)
+            .sum();
         let secs = self.window.as_secs_f64();
         total as f64 / secs
     }
@@ -68,6 +188,60 @@ impl ThroughputRate {
         }
     }
 }
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeerContribution {
+    pub addr: SocketAddr,
+    pub bytes: u64,
+    // Share of the verified bytes of the torrent, from 0 to 100.
+    pub percentage: f64,
+}
+
+// Which peers supplied the blocks of the verified pieces.
+#[derive(Default)]
+pub struct PieceAttribution {
+    pieces: HashMap<u32, Vec<(SocketAddr, u64)>>,
+    contributions: HashMap<SocketAddr, u64>,
+    total_bytes: u64,
+}
+
+impl PieceAttribution {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The sources are the bytes each peer sent for the piece.
+    pub fn record_piece(&mut self, piece_index: u32, sources: Vec<(SocketAddr, u64)>) {
+        for (addr, bytes) in &sources {
+            *self.contributions.entry(*addr).or_default() += bytes;
+            self.total_bytes += bytes;
+        }
+        self.pieces.insert(piece_index, sources);
+    }
+
+    pub fn piece_sources(&self, piece_index: u32) -> &[(SocketAddr, u64)] {
+        self.pieces
+            .get(&piece_index)
+            .map(|it| it.as_slice())
+            .unwrap_or_default()
+    }
+
+    // Sorted by the bytes, the most helpful peer comes first.
+    pub fn contributions(&self) -> Vec<PeerContribution> {
+        let mut contributions: Vec<PeerContribution> = self
+            .contributions
+            .iter()
+            .map(|(addr, bytes)| PeerContribution {
+                addr: *addr,
+                bytes: *bytes,
+                percentage: *bytes as f64 * 100.0 / self.total_bytes as f64,
+            })
+            .collect();
+        contributions.sort_by_key(|it| Reverse(it.bytes));
+        contributions
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -118,4 +292,69 @@ mod tests {
         let rate = transfer_rate.rate();
         assert_eq!(rate, 0.0);
     }
+
+    #[test]
+    fn test_round_trip() {
+        let start = Instant::now();
+        let mut stats = PeerStats::new(20);
+        assert_eq!(stats.rtt(), None);
+        assert_eq!(stats.pipeline_length(), MIN_PIPELINE_LENGTH);
+
+        stats.record_request(0, 0, start);
+        stats.record_request(0, BLOCK_SIZE, start);
+        assert_eq!(
+            stats.record_block(0, 0, start + Duration::from_millis(200)),
+            Some(Duration::from_millis(200))
+        );
+        assert_eq!(stats.rtt(), Some(Duration::from_millis(200)));
+        // Not requested, or answered already
+        assert_eq!(stats.record_block(0, 0, start), None);
+        assert_eq!(stats.record_block(1, 0, start), None);
+
+        // Smoothed towards the new sample
+        stats.record_block(0, BLOCK_SIZE, start + Duration::from_millis(600));
+        assert_eq!(stats.rtt(), Some(Duration::from_millis(250)));
+        let latency = stats.latency().unwrap();
+        assert_eq!(latency.rtt_ms, 250);
+        assert_eq!(latency.samples, 2);
+
+        // A request answered by nobody for far longer than the usual round trip
+        stats.record_request(2, 0, start);
+        assert!(!stats.is_snubbed(start + Duration::from_secs(5)));
+        assert!(stats.is_snubbed(start + Duration::from_secs(11)));
+        stats.clear_requests();
+        assert!(!stats.is_snubbed(start + Duration::from_secs(11)));
+
+        // 1MiB/s with a 250ms round trip keeps 16 blocks in flight
+        stats.record_download(20 * 1024 * 1024);
+        assert_eq!(stats.pipeline_length(), 17);
+    }
+
+    #[test]
+    fn test_piece_attribution() {
+        let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let mut attribution = PieceAttribution::new();
+        attribution.record_piece(0, vec![(b, 300)]);
+        attribution.record_piece(1, vec![(a, 500), (b, 0)]);
+        attribution.record_piece(2, vec![(a, 200)]);
+
+        assert_eq!(attribution.piece_sources(1), &[(a, 500), (b, 0)]);
+        assert!(attribution.piece_sources(3).is_empty());
+        assert_eq!(
+            attribution.contributions(),
+            vec![
+                PeerContribution {
+                    addr: a,
+                    bytes: 700,
+                    percentage: 70.0
+                },
+                PeerContribution {
+                    addr: b,
+                    bytes: 300,
+                    percentage: 30.0
+                },
+            ]
+        );
+    }
 }
@@ -0,0 +1,202 @@
+use native_tls::{Certificate, TlsConnector};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio_native_tls::TlsStream;
+
+use crate::{hash::calculate_sha256_hash, transport::PeerStream, types::Sha1Hash};
+
+// The peers of an SSL torrent talk over TLS, and only the peers holding a certificate of the
+// swarm are let in. The certificates are issued by the CA of the swarm, or the user pins the
+// exact certificates of the peers they expect, e.g. the few seedboxes of a private group.
+
+#[derive(Error, Debug)]
+pub enum PeerTlsError {
+    #[error("Failed the TLS handshake: {0}")]
+    Tls(#[from] native_tls::Error),
+    #[error("Peer sent no certificate")]
+    NoCertificate,
+    #[error("Peer certificate {0} is not pinned")]
+    NotPinned(String),
+}
+
+pub(crate) type Result<T> = std::result::Result<T, PeerTlsError>;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerTls {
+    // PEM encoded, the peer certificates must be issued by it. None trusts any certificate,
+    // for the pinned ones.
+    pub ca_certificate: Option<String>,
+    // The SHA-256 of the DER certificates, empty lets in any certificate the CA issued.
+    pub pinned_certificates: Vec<[u8; 32]>,
+}
+
+impl PeerTls {
+    pub fn is_pinned(&self, der: &[u8]) -> bool {
+        self.pinned_certificates.is_empty()
+            || self
+                .pinned_certificates
+                .contains(&certificate_fingerprint(der))
+    }
+}
+
+// The SHA-256 of the DER certificate, as the users compare them.
+pub fn certificate_fingerprint(der: &[u8]) -> [u8; 32] {
+    calculate_sha256_hash(der.to_vec())
+}
+
+// Hex with or without colons, e.g. as openssl prints them.
+pub fn parse_fingerprint(fingerprint: &str) -> Option<[u8; 32]> {
+    let hex: String = fingerprint.chars().filter(|it| *it != ':').collect();
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn format_fingerprint(fingerprint: &[u8; 32]) -> String {
+    fingerprint
+        .iter()
+        .map(|it| format!("{:02X}", it))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// Start TLS with the peer we dialed. The peers have no hostname, the server name is the hex
+// info hash, so a peer serving a few SSL torrents knows which certificate to answer with.
+pub(crate) async fn connect(
+    stream: PeerStream,
+    info_hash: &Sha1Hash,
+    tls: &PeerTls,
+) -> Result<PeerStream> {
+    let mut builder = TlsConnector::builder();
+    builder.danger_accept_invalid_hostnames(true);
+    match &tls.ca_certificate {
+        Some(pem) => {
+            builder
+                .disable_built_in_roots(true)
+                .add_root_certificate(Certificate::from_pem(pem.as_bytes())?);
+        }
+        // Checked against the pins below instead
+        None => {
+            builder.danger_accept_invalid_certs(true);
+        }
+    }
+    let connector = tokio_native_tls::TlsConnector::from(builder.build()?);
+    let server_name: String = info_hash.iter().map(|it| format!("{:02x}", it)).collect();
+    let stream: TlsStream<PeerStream> = connector.connect(&server_name, stream).await?;
+
+    let certificate = stream
+        .get_ref()
+        .peer_certificate()?
+        .ok_or(PeerTlsError::NoCertificate)?;
+    let der = certificate.to_der()?;
+    if !tls.is_pinned(&der) {
+        let fingerprint = certificate_fingerprint(&der);
+        return Err(PeerTlsError::NotPinned(format_fingerprint(&fingerprint)));
+    }
+    Ok(PeerStream::Tls(Box::new(stream)))
+}
+
+#[cfg(test)]
+mod tests {
+    use native_tls::Identity;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    const CA: &str = include_str!("../tests/certs/ca.pem");
+    const PEER_CERT: &[u8] = include_bytes!("../tests/certs/peer_cert.pem");
+    const PEER_KEY: &[u8] = include_bytes!("../tests/certs/peer_key.pem");
+
+    // A peer serving its certificate, which answers a byte once the handshake is done.
+    async fn start_peer() -> std::net::SocketAddr {
+        let identity = Identity::from_pkcs8(PEER_CERT, PEER_KEY).unwrap();
+        let acceptor =
+            tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(mut stream) = acceptor.accept(stream).await {
+                        let _ = stream.write_all(b"!").await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    async fn dial(addr: std::net::SocketAddr, tls: &PeerTls) -> Result<PeerStream> {
+        let stream = PeerStream::Tcp(TcpStream::connect(addr).await.unwrap());
+        connect(stream, &[1; 20], tls).await
+    }
+
+    fn peer_fingerprint() -> [u8; 32] {
+        let certificate = Certificate::from_pem(PEER_CERT).unwrap();
+        certificate_fingerprint(&certificate.to_der().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_pinned_certificate() {
+        let addr = start_peer().await;
+        let tls = PeerTls {
+            ca_certificate: None,
+            pinned_certificates: vec![peer_fingerprint()],
+        };
+        let mut stream = dial(addr, &tls).await.unwrap();
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).await.unwrap();
+        assert_eq!(&byte, b"!");
+
+        let tls = PeerTls {
+            ca_certificate: None,
+            pinned_certificates: vec![[0xab; 32]],
+        };
+        let error = dial(addr, &tls).await.unwrap_err();
+        assert!(matches!(error, PeerTlsError::NotPinned(_)));
+        assert!(
+            error
+                .to_string()
+                .contains(&format_fingerprint(&peer_fingerprint()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_swarm_ca() {
+        let addr = start_peer().await;
+        let tls = PeerTls {
+            ca_certificate: Some(CA.to_string()),
+            pinned_certificates: Vec::new(),
+        };
+        assert!(dial(addr, &tls).await.is_ok());
+
+        // The certificate of another swarm
+        let tls = PeerTls {
+            ca_certificate: Some(String::from_utf8(PEER_CERT.to_vec()).unwrap()),
+            pinned_certificates: Vec::new(),
+        };
+        assert!(matches!(
+            dial(addr, &tls).await.unwrap_err(),
+            PeerTlsError::Tls(_)
+        ));
+    }
+
+    #[test]
+    fn test_parse_fingerprint() {
+        let fingerprint = peer_fingerprint();
+        assert_eq!(
+            parse_fingerprint(&format_fingerprint(&fingerprint)),
+            Some(fingerprint)
+        );
+        assert_eq!(parse_fingerprint("AB:CD"), None);
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::compact::{decode_peers, encode_peers};
+
+// Implementation of the peer exchange extension, peers tell each other who else is in the swarm.
+// https://www.bittorrent.org/beps/bep_0011.html
+
+// Peers disconnect the clients which send PEX messages more often than once a minute.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+// Maximum added and dropped peers in a single message.
+const MAX_PEERS_PER_MESSAGE: usize = 50;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PexMessage {
+    pub added: Vec<SocketAddr>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+mod raw {
+    use super::*;
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    pub struct PexMessage {
+        #[serde(default, with = "serde_bytes")]
+        pub added: Vec<u8>,
+        // One byte of flags per added peer, we don't send any flag yet.
+        #[serde(rename = "added.f", default, with = "serde_bytes")]
+        pub added_flags: Vec<u8>,
+        #[serde(default, with = "serde_bytes")]
+        pub dropped: Vec<u8>,
+        #[serde(default, with = "serde_bytes")]
+        pub added6: Vec<u8>,
+        #[serde(rename = "added6.f", default, with = "serde_bytes")]
+        pub added6_flags: Vec<u8>,
+        #[serde(default, with = "serde_bytes")]
+        pub dropped6: Vec<u8>,
+    }
+}
+
+impl PexMessage {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, serde_bencode::Error> {
+        let (added, added6) = encode_peers(&self.added);
+        let (dropped, dropped6) = encode_peers(&self.dropped);
+        serde_bencode::to_bytes(&raw::PexMessage {
+            added_flags: vec![0; added.len() / 6],
+            added6_flags: vec![0; added6.len() / 18],
+            added,
+            dropped,
+            added6,
+            dropped6,
+        })
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_bencode::Error> {
+        let message: raw::PexMessage = serde_bencode::from_bytes(bytes)?;
+        let mut added = decode_peers(&message.added, 4);
+        added.extend(decode_peers(&message.added6, 16));
+        let mut dropped = decode_peers(&message.dropped, 4);
+        dropped.extend(decode_peers(&message.dropped6, 16));
+        Ok(Self { added, dropped })
+    }
+}
+
+// The peers we have told a connected peer about, to send only the changes next time.
+pub struct PexState {
+    sent: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+}
+
+impl PexState {
+    pub fn new() -> Self {
+        Self {
+            sent: HashSet::new(),
+            last_sent: None,
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.last_sent
+            .is_none_or(|last_sent| last_sent.elapsed() >= PEX_INTERVAL)
+    }
+
+    // Build the message with the difference between the connected peers and what we sent before,
+    // the peers over the limit are sent in the next message.
+    pub fn next_message(&mut self, connected: &HashSet<SocketAddr>) -> Option<PexMessage> {
+        let added: Vec<SocketAddr> = connected
+            .difference(&self.sent)
+            .take(MAX_PEERS_PER_MESSAGE)
+            .copied()
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .difference(connected)
+            .take(MAX_PEERS_PER_MESSAGE)
+            .copied()
+            .collect();
+        self.last_sent = Some(Instant::now());
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        self.sent.extend(&added);
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        Some(PexMessage { added, dropped })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    #[test]
+    fn test_pex_message_roundtrip() {
+        let message = PexMessage {
+            added: vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[2001:db8::1]:51413".parse().unwrap(),
+            ],
+            dropped: vec!["10.0.0.2:6882".parse().unwrap()],
+        };
+        let bytes = message.to_bytes().unwrap();
+        assert_eq!(PexMessage::from_bytes(&bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn test_pex_state_sends_changes() {
+        let mut state = PexState::new();
+        assert!(state.is_due());
+
+        let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let message = state.next_message(&HashSet::from([a])).unwrap();
+        assert_eq!(message.added, vec![a]);
+        assert!(message.dropped.is_empty());
+        assert!(!state.is_due());
+
+        assert!(state.next_message(&HashSet::from([a])).is_none());
+
+        let message = state.next_message(&HashSet::from([b])).unwrap();
+        assert_eq!(message.added, vec![b]);
+        assert_eq!(message.dropped, vec![a]);
+    }
+
+    #[test]
+    fn test_pex_state_limits_message_size() {
+        let mut state = PexState::new();
+        let connected: HashSet<SocketAddr> = (0..60)
+            .map(|i| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)), 6881))
+            .collect();
+        let message = state.next_message(&connected).unwrap();
+        assert_eq!(message.added.len(), MAX_PEERS_PER_MESSAGE);
+        let message = state.next_message(&connected).unwrap();
+        assert_eq!(message.added.len(), 10);
+    }
+}
@@ -0,0 +1,136 @@
+use std::time::Instant;
+
+use rand::seq::IndexedRandom;
+use serde::Serialize;
+
+// Which piece to download next. The picker keeps the state of the blocks and the swarm,
+// the strategy only orders the pieces, so a new one can be tried without touching the picker.
+
+// A piece which the peer has and which still has blocks nobody requested.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceCandidate {
+    pub piece_index: u32,
+    // How many connected peers have the piece.
+    pub availability: u32,
+    // Some blocks of the piece are requested or received already.
+    pub in_progress: bool,
+    // When the piece is needed, e.g. by the player streaming the file.
+    pub deadline: Option<Instant>,
+}
+
+pub trait PickStrategy: Send {
+    // Returns one of the candidates, which are ordered by the piece index.
+    fn pick_piece(&mut self, candidates: &[PieceCandidate]) -> Option<u32>;
+}
+
+// The strategies which can be chosen per torrent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PickStrategyKind {
+    #[default]
+    RarestFirst,
+    Sequential,
+    Random,
+    Deadline,
+}
+
+impl PickStrategyKind {
+    pub fn build(self) -> Box<dyn PickStrategy> {
+        match self {
+            PickStrategyKind::RarestFirst => Box::new(RarestFirst),
+            PickStrategyKind::Sequential => Box::new(Sequential),
+            PickStrategyKind::Random => Box::new(Random),
+            PickStrategyKind::Deadline => Box::new(Deadline),
+        }
+    }
+}
+
+// Finish the started pieces first, then take the piece the fewest peers have,
+// so the rare pieces spread before their only sources leave.
+pub struct RarestFirst;
+
+impl PickStrategy for RarestFirst {
+    fn pick_piece(&mut self, candidates: &[PieceCandidate]) -> Option<u32> {
+        candidates
+            .iter()
+            .min_by_key(|it| (!it.in_progress, it.availability, it.piece_index))
+            .map(|it| it.piece_index)
+    }
+}
+
+// In order of the pieces, e.g. to preview a file while it downloads.
+pub struct Sequential;
+
+impl PickStrategy for Sequential {
+    fn pick_piece(&mut self, candidates: &[PieceCandidate]) -> Option<u32> {
+        candidates.first().map(|it| it.piece_index)
+    }
+}
+
+// Any piece, finishing the started ones first. Used until the first pieces are complete,
+// when there is nothing to share yet and the rarest pieces are the slowest to get.
+pub struct Random;
+
+impl PickStrategy for Random {
+    fn pick_piece(&mut self, candidates: &[PieceCandidate]) -> Option<u32> {
+        let in_progress: Vec<&PieceCandidate> =
+            candidates.iter().filter(|it| it.in_progress).collect();
+        let picked = if in_progress.is_empty() {
+            candidates.choose(&mut rand::rng())
+        } else {
+            in_progress.choose(&mut rand::rng()).copied()
+        };
+        picked.map(|it| it.piece_index)
+    }
+}
+
+// The piece needed the soonest, the pieces without a deadline are picked rarest first.
+pub struct Deadline;
+
+impl PickStrategy for Deadline {
+    fn pick_piece(&mut self, candidates: &[PieceCandidate]) -> Option<u32> {
+        candidates
+            .iter()
+            .filter_map(|it| it.deadline.map(|deadline| (deadline, it.piece_index)))
+            .min()
+            .map(|(_, piece_index)| piece_index)
+            .or_else(|| RarestFirst.pick_piece(candidates))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn candidate(piece_index: u32, availability: u32, in_progress: bool) -> PieceCandidate {
+        PieceCandidate {
+            piece_index,
+            availability,
+            in_progress,
+            deadline: None,
+        }
+    }
+
+    #[test]
+    fn test_strategies() {
+        let mut candidates = vec![
+            candidate(0, 5, false),
+            candidate(1, 1, false),
+            candidate(2, 3, true),
+            candidate(3, 1, false),
+        ];
+        assert_eq!(Sequential.pick_piece(&candidates), Some(0));
+        assert_eq!(RarestFirst.pick_piece(&candidates), Some(2));
+        assert_eq!(Random.pick_piece(&candidates), Some(2));
+        candidates[2].in_progress = false;
+        assert_eq!(RarestFirst.pick_piece(&candidates), Some(1));
+        assert_eq!(Deadline.pick_piece(&candidates), Some(1));
+
+        let now = Instant::now();
+        candidates[3].deadline = Some(now + Duration::from_secs(5));
+        candidates[0].deadline = Some(now + Duration::from_secs(1));
+        assert_eq!(Deadline.pick_piece(&candidates), Some(0));
+    }
+}
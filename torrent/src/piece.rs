@@ -1,7 +1,15 @@
-use bytes::BytesMut;
+use std::{collections::HashMap, net::SocketAddr};
+
 use thiserror::Error;
 
-use crate::{hash::calculate_sha1_hash, types::Sha1Hash};
+use crate::{
+    hash::{
+        IncrementalHash, MERKLE_BLOCK_SIZE, calculate_sha1_hash, leaf_hashes, merkle_root,
+        merkle_root_of_leaves,
+    },
+    metainfo::{MetaInfo, MetaVersion},
+    types::{Sha1Hash, Sha256Hash},
+};
 
 pub(crate) type Result<T> = std::result::Result<T, PieceError>;
 
@@ -15,6 +23,109 @@ pub enum PieceError {
     InvalidBlock,
 }
 
+// What a piece is verified against. A hybrid torrent uses the v1 hashes, they cover the same
+// bytes as the v2 ones since its files are padded to the piece boundaries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PieceHash {
+    V1(Sha1Hash),
+    // The root of the merkle tree over the blocks of the piece, which has `leaves` leaves.
+    // Only the first `length` bytes are the file's, the rest is the pad up to the next file.
+    V2 {
+        root: Sha256Hash,
+        leaves: usize,
+        length: usize,
+    },
+}
+
+impl From<Sha1Hash> for PieceHash {
+    fn from(hash: Sha1Hash) -> Self {
+        PieceHash::V1(hash)
+    }
+}
+
+impl PieceHash {
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match self {
+            PieceHash::V1(hash) => calculate_sha1_hash(data.to_vec()) == *hash,
+            PieceHash::V2 {
+                root,
+                leaves,
+                length,
+            } => data
+                .get(..*length)
+                .is_some_and(|it| merkle_root(it, *leaves) == *root),
+        }
+    }
+}
+
+// The hash of each piece of the torrent, from the hash set the torrent is verified with.
+pub(crate) fn piece_hashes(metainfo: &MetaInfo) -> Vec<PieceHash> {
+    match metainfo.version() {
+        // The piece layers of a v2 only torrent are all there, it's refused otherwise.
+        MetaVersion::V2 => merkle_hashes(metainfo)
+            .unwrap_or_default()
+            .into_iter()
+            .flatten()
+            .collect(),
+        _ => metainfo
+            .info
+            .pieces
+            .chunks_exact(20)
+            .map(|it| PieceHash::V1(it.try_into().unwrap()))
+            .collect(),
+    }
+}
+
+// The merkle root of each piece of a v2 or hybrid torrent, None for a v1 torrent.
+// The files of a hybrid torrent are padded to the piece boundaries, so its v1 and v2
+// pieces are the same. A piece of a file without a piece layer has no merkle root, only
+// a hybrid torrent gets that far and it's verified by the v1 hash.
+pub(crate) fn merkle_hashes(metainfo: &MetaInfo) -> Option<Vec<Option<PieceHash>>> {
+    let v2 = metainfo.v2.as_ref()?;
+
+    // Each file starts at a new piece in v2, the empty files have no piece.
+    let piece_length = metainfo.info.piece_length as u64;
+    let piece_leaves = piece_length as usize / MERKLE_BLOCK_SIZE;
+    let mut hashes = Vec::new();
+    for file in v2.files.iter().filter(|it| it.length > 0) {
+        let root = file.pieces_root.unwrap_or_default();
+        let piece_count = file.length.div_ceil(piece_length) as usize;
+        // The last piece of the file is cut short, the pad after it isn't hashed.
+        let last_length = (file.length - (piece_count as u64 - 1) * piece_length) as usize;
+        if piece_count == 1 {
+            // The file is a single piece, the pieces root is the hash of it.
+            let leaves = last_length.div_ceil(MERKLE_BLOCK_SIZE);
+            hashes.push(Some(PieceHash::V2 {
+                root,
+                leaves,
+                length: last_length,
+            }));
+            continue;
+        }
+        match v2.piece_layers.get(&root) {
+            Some(layer) if layer.len() == piece_count => {
+                hashes.extend(layer.iter().enumerate().map(|(index, it)| {
+                    let length = if index + 1 == piece_count {
+                        last_length
+                    } else {
+                        piece_length as usize
+                    };
+                    Some(PieceHash::V2 {
+                        root: *it,
+                        leaves: piece_leaves,
+                        length,
+                    })
+                }));
+            }
+            _ => {
+                log::warn!("Missing piece layer of {}", file.path.join("/"));
+                hashes.extend((0..piece_count).map(|_| None));
+            }
+        }
+    }
+    Some(hashes)
+}
+
 #[derive(Clone)]
 enum PieceStatus {
     Verified(Vec<u8>),
@@ -24,9 +135,15 @@ enum PieceStatus {
 #[derive(Clone)]
 pub struct Piece {
     pub index: usize,
-    pub hash: Sha1Hash,
+    pub hash: PieceHash,
     pub status: PieceStatus,
     pub length: u32,
+    // The blocks received in order so far, hashed as they arrive.
+    hasher: IncrementalHash,
+    // The merkle root of the piece if the torrent is v2 or hybrid, and the hashes of its
+    // 16KiB leaves once we have them, to tell which blocks of a failed piece are corrupt.
+    merkle_root: Option<PieceHash>,
+    leaf_hashes: Option<Vec<Sha256Hash>>,
 }
 
 #[derive(Clone)]
@@ -34,34 +151,127 @@ pub struct Block {
     pub piece_index: u32,
     pub begin: u32,
     pub data: Vec<u8>,
+    // The peer which sent the block, None if it's not from a peer.
+    pub peer: Option<SocketAddr>,
 }
 
 impl Piece {
-    pub fn new_unverified(index: usize, hash: Sha1Hash, length: u32) -> Self {
+    pub fn new_unverified(index: usize, hash: impl Into<PieceHash>, length: u32) -> Self {
         Self {
             index,
-            hash,
+            hash: hash.into(),
             length,
             status: PieceStatus::UnVerified(Vec::new()),
+            hasher: IncrementalHash::default(),
+            merkle_root: None,
+            leaf_hashes: None,
         }
     }
 
-    pub fn new_verified(index: usize, hash: Sha1Hash, length: u32, data: Vec<u8>) -> Self {
+    pub fn new_verified(
+        index: usize,
+        hash: impl Into<PieceHash>,
+        length: u32,
+        data: Vec<u8>,
+    ) -> Self {
         Self {
             index,
-            hash,
+            hash: hash.into(),
             length,
             status: PieceStatus::Verified(data),
+            hasher: IncrementalHash::default(),
+            merkle_root: None,
+            leaf_hashes: None,
+        }
+    }
+
+    pub fn with_merkle_root(mut self, root: PieceHash) -> Self {
+        if matches!(root, PieceHash::V2 { .. }) {
+            self.merkle_root = Some(root);
+        }
+        self
+    }
+
+    // The leaf hashes from the merkle data, e.g. a hash request answered by a peer.
+    // They are only kept if they add up to the merkle root of the piece.
+    pub fn set_leaf_hashes(&mut self, hashes: Vec<Sha256Hash>) -> Result<()> {
+        let Some(PieceHash::V2 {
+            root,
+            leaves,
+            length,
+        }) = self.merkle_root
+        else {
+            return Err(PieceError::InvalidHash);
+        };
+        if hashes.len() != length.div_ceil(MERKLE_BLOCK_SIZE)
+            || merkle_root_of_leaves(hashes.clone(), leaves) != root
+        {
+            return Err(PieceError::InvalidHash);
+        }
+        self.leaf_hashes = Some(hashes);
+        Ok(())
+    }
+
+    // The begin of each 16KiB block which doesn't match its leaf hash, after the piece failed
+    // to verify. None if we don't have the leaf hashes, the whole piece is suspect then.
+    pub fn corrupt_blocks(&self) -> Option<Vec<u32>> {
+        let expected = self.leaf_hashes.as_ref()?;
+        let PieceStatus::UnVerified(blocks) = &self.status else {
+            return Some(Vec::new());
+        };
+        let mut data = vec![0u8; self.length as usize];
+        for block in blocks {
+            let begin = block.begin as usize;
+            if let Some(target) = data.get_mut(begin..begin + block.data.len()) {
+                target.copy_from_slice(&block.data);
+            }
         }
+        let corrupt = leaf_hashes(&data)
+            .iter()
+            .zip(expected)
+            .enumerate()
+            .filter(|(_, (actual, expected))| actual != expected)
+            .map(|(index, _)| (index * MERKLE_BLOCK_SIZE) as u32)
+            .collect();
+        Some(corrupt)
+    }
+
+    // The piece turned out corrupt after it was verified, e.g. on disk, download it again.
+    pub fn reset(&mut self) {
+        self.status = PieceStatus::UnVerified(Vec::new());
+        self.hasher = IncrementalHash::default();
+    }
+
+    // Drop the received blocks overlapping the 16KiB blocks at `begins`, to download them again.
+    pub fn discard_blocks(&mut self, begins: &[u32]) {
+        let PieceStatus::UnVerified(blocks) = &mut self.status else {
+            return;
+        };
+        blocks.retain(|block| {
+            let end = block.begin + block.data.len() as u32;
+            !begins
+                .iter()
+                .any(|it| block.begin < it + MERKLE_BLOCK_SIZE as u32 && *it < end)
+        });
+        // Hashed again from the start, the blocks before the gap are hashed on verify
+        self.hasher = IncrementalHash::default();
     }
 
     pub fn add_block(&mut self, block: Block) -> Result<()> {
-        match &self.status {
+        match &mut self.status {
             PieceStatus::Verified(_) => Err(PieceError::InvalidBlock),
             PieceStatus::UnVerified(blocks) => {
-                let mut new_blocks = blocks.clone();
-                new_blocks.push(block);
-                self.status = PieceStatus::UnVerified(new_blocks);
+                blocks.push(block);
+                if !matches!(self.hash, PieceHash::V1(_)) {
+                    return Ok(());
+                }
+                // The block may fill the gap before the blocks which arrived early,
+                // hash as far as the blocks are contiguous.
+                while let Some(block) = blocks.iter().find(|it| {
+                    it.begin as usize == self.hasher.hashed_bytes() && !it.data.is_empty()
+                }) {
+                    self.hasher.update(&block.data);
+                }
                 Ok(())
             }
         }
@@ -75,15 +285,24 @@ impl Piece {
                     return Err(PieceError::IncompleteBlocks);
                 }
                 let received_pieces_length = blocks.iter().map(|it| it.data.len()).sum();
-                let mut data = BytesMut::with_capacity(received_pieces_length);
+                let mut data = vec![0u8; received_pieces_length];
                 for block in blocks {
                     let begin = block.begin as usize;
-                    data[begin..begin + block.data.len()].copy_from_slice(&block.data);
+                    let Some(target) = data.get_mut(begin..begin + block.data.len()) else {
+                        return Err(PieceError::InvalidBlock);
+                    };
+                    target.copy_from_slice(&block.data);
                 }
-                let hash = calculate_sha1_hash(data.to_vec());
-                if self.hash == hash {
-                    self.status = PieceStatus::Verified(data.to_vec());
-                    Ok(data.to_vec())
+                // Only hash the whole piece if the blocks didn't line up, e.g. a duplicate block.
+                let is_valid = match self.hash {
+                    PieceHash::V1(hash) if self.hasher.hashed_bytes() == received_pieces_length => {
+                        self.hasher.clone().finalize() == hash
+                    }
+                    hash => hash.matches(&data),
+                };
+                if is_valid {
+                    self.status = PieceStatus::Verified(data.clone());
+                    Ok(data)
                 } else {
                     Err(PieceError::InvalidHash)
                 }
@@ -91,6 +310,26 @@ impl Piece {
         }
     }
 
+    // How many bytes each peer sent for this piece, must be called before it's verified.
+    pub fn sources(&self) -> Vec<(SocketAddr, u64)> {
+        let PieceStatus::UnVerified(blocks) = &self.status else {
+            return Vec::new();
+        };
+        let mut sources: Vec<(SocketAddr, u64)> = Vec::new();
+        let mut indexes = HashMap::new();
+        for block in blocks {
+            let Some(peer) = block.peer else {
+                continue;
+            };
+            let index = *indexes.entry(peer).or_insert_with(|| {
+                sources.push((peer, 0));
+                sources.len() - 1
+            });
+            sources[index].1 += block.data.len() as u64;
+        }
+        sources
+    }
+
     pub fn is_all_blocks_received(&self) -> bool {
         match &self.status {
             PieceStatus::Verified(_) => true,
@@ -109,3 +348,142 @@ impl Piece {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(begin: u32, data: &[u8]) -> Block {
+        Block {
+            piece_index: 0,
+            begin,
+            data: data.to_vec(),
+            peer: None,
+        }
+    }
+
+    #[test]
+    fn test_hash_blocks_incrementally() {
+        let data = b"abcdefghijkl".to_vec();
+        let mut piece = Piece::new_unverified(0, calculate_sha1_hash(data.clone()), 12);
+
+        piece.add_block(block(4, b"efgh")).unwrap();
+        // Can't hash past the missing first block
+        assert_eq!(piece.hasher.hashed_bytes(), 0);
+        piece.add_block(block(0, b"abcd")).unwrap();
+        assert_eq!(piece.hasher.hashed_bytes(), 8);
+        piece.add_block(block(8, b"ijkl")).unwrap();
+        assert_eq!(piece.hasher.hashed_bytes(), 12);
+
+        assert!(piece.is_all_blocks_received());
+        assert_eq!(piece.verify().unwrap(), data);
+    }
+
+    #[test]
+    fn test_verify_invalid_hash() {
+        let mut piece = Piece::new_unverified(0, calculate_sha1_hash(b"abcd".to_vec()), 4);
+        piece.add_block(block(0, b"abce")).unwrap();
+        assert!(matches!(piece.verify(), Err(PieceError::InvalidHash)));
+    }
+
+    #[test]
+    fn test_verify_v2_piece() {
+        let data: Vec<u8> = (0..20000u32).map(|it| it as u8).collect();
+        let sha256 = |data: &[u8]| crate::hash::calculate_sha256_hash(data.to_vec());
+        // A piece of 4 blocks, only 2 have data
+        let left = sha256(&[sha256(&data[..16384]), sha256(&data[16384..])].concat());
+        let right = sha256(&[[0u8; 32], [0u8; 32]].concat());
+        let root = sha256(&[left, right].concat());
+
+        let hash = PieceHash::V2 {
+            root,
+            leaves: 4,
+            length: 20000,
+        };
+        let mut piece = Piece::new_unverified(0, hash, 20000);
+        piece.add_block(block(0, &data[..16384])).unwrap();
+        piece.add_block(block(16384, &data[16384..])).unwrap();
+        assert_eq!(piece.verify().unwrap(), data);
+
+        assert!(!hash.matches(&data[..19999]));
+    }
+
+    #[test]
+    fn test_verify_padded_v2_piece() {
+        use crate::metainfo::{FileV2, MetaInfoV2, TestMetaInfo};
+
+        let piece_length = 2 * MERKLE_BLOCK_SIZE;
+        let a: Vec<u8> = (0..40000u32).map(|it| it as u8).collect();
+        let b = b"the next file";
+        let mut metainfo = TestMetaInfo::new("test", piece_length as u32).build();
+        metainfo.info.pieces.clear();
+        metainfo.v2 = Some(MetaInfoV2 {
+            info_hash: [0u8; 32],
+            files: vec![
+                FileV2 {
+                    path: vec!["a".to_string()],
+                    length: a.len() as u64,
+                    pieces_root: Some([1u8; 32]),
+                },
+                FileV2 {
+                    path: vec!["b".to_string()],
+                    length: b.len() as u64,
+                    pieces_root: Some(merkle_root(b, 1)),
+                },
+            ],
+            piece_layers: HashMap::from([(
+                [1u8; 32],
+                vec![
+                    merkle_root(&a[..piece_length], 2),
+                    merkle_root(&a[piece_length..], 2),
+                ],
+            )]),
+        });
+
+        let hashes = piece_hashes(&metainfo);
+        assert_eq!(hashes.len(), 3);
+        assert!(hashes[0].matches(&a[..piece_length]));
+        // The last piece of the first file is followed by the pad up to the second file
+        let mut padded = a[piece_length..].to_vec();
+        padded.resize(piece_length, 0);
+        assert!(hashes[1].matches(&padded));
+        assert!(!hashes[1].matches(&a[piece_length..a.len() - 1]));
+        assert!(hashes[2].matches(b));
+    }
+
+    #[test]
+    fn test_isolate_corrupt_blocks() {
+        let data: Vec<u8> = (0..3 * MERKLE_BLOCK_SIZE as u32)
+            .map(|it| it as u8)
+            .collect();
+        let root = PieceHash::V2 {
+            root: merkle_root(&data, 4),
+            leaves: 4,
+            length: data.len(),
+        };
+        let mut piece = Piece::new_unverified(0, root, data.len() as u32).with_merkle_root(root);
+        assert!(piece.set_leaf_hashes(vec![[0u8; 32]; 3]).is_err());
+        piece.set_leaf_hashes(leaf_hashes(&data)).unwrap();
+
+        let mut corrupt = data.clone();
+        corrupt[MERKLE_BLOCK_SIZE + 10] ^= 0xff;
+        for begin in (0..data.len()).step_by(MERKLE_BLOCK_SIZE) {
+            let end = begin + MERKLE_BLOCK_SIZE;
+            piece
+                .add_block(block(begin as u32, &corrupt[begin..end]))
+                .unwrap();
+        }
+        assert!(matches!(piece.verify(), Err(PieceError::InvalidHash)));
+        let begins = piece.corrupt_blocks().unwrap();
+        assert_eq!(begins, [MERKLE_BLOCK_SIZE as u32]);
+
+        // Only the corrupt block is downloaded again
+        piece.discard_blocks(&begins);
+        assert!(!piece.is_all_blocks_received());
+        let begin = MERKLE_BLOCK_SIZE;
+        piece
+            .add_block(block(begin as u32, &data[begin..begin * 2]))
+            .unwrap();
+        assert_eq!(piece.verify().unwrap(), data);
+    }
+}
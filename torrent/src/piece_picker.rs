@@ -1,18 +1,48 @@
-use crate::{piece::Block, types::BitField};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
 
-// Used to track the state of each block
+use crate::{
+    pick_strategy::{PickStrategy, PickStrategyKind, PieceCandidate},
+    piece::Block,
+    types::{BitField, PeerId},
+};
+
+// Used to track the state of each block, the strategy decides which piece to download next.
 pub struct PiecePicker {
     own_bitfield: BitField,
+    // The pieces to download, the pieces of the skipped files are not wanted.
+    wanted: BitField,
     // the total file length of metainfo
     total_length: u32,
     piece_length: u32,
     missing_blocks: Vec<BlockInfo>,
+    // How many connected peers have each piece.
+    availability: Vec<u32>,
+    // When the pieces are needed, for the deadline strategy.
+    deadlines: HashMap<u32, Instant>,
+    strategy: Box<dyn PickStrategy>,
+    // Who requested each block in flight, keyed by the piece index and the begin of the block.
+    // There is no endgame yet, so a block is never requested from two peers at once.
+    reservations: HashMap<(u32, u32), PeerId>,
+    duplicates: DuplicateStats,
+}
+
+// The requests which should never happen, to validate each block is only requested once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DuplicateStats {
+    // A block was handed out while another peer still had it in flight.
+    pub requests: u64,
+    // A block arrived after we already received it, the bytes are wasted.
+    pub blocks: u64,
 }
 
 // Block size 16KB is recommend by document
 // https://www.bittorrent.org/beps/bep_0003.html#peer-messages
-const BLOCK_SIZE: u32 = 16 * 1024;
+pub(crate) const BLOCK_SIZE: u32 = 16 * 1024;
 
+#[derive(Clone)]
 pub struct BlockInfo {
     pub piece_index: u32,
     pub begin: u32,
@@ -57,64 +87,300 @@ pub enum BlockState {
 
 impl PiecePicker {
     pub fn new(own_bitfield: BitField, total_length: u32, piece_length: u32) -> Self {
-        let num_of_missing_blocks = own_bitfield.iter().fold(0, |acc, it| {
-            if it == false {
-                acc + piece_length / BLOCK_SIZE
+        let wanted = BitField::repeat(true, own_bitfield.len());
+        let mut picker = Self {
+            availability: vec![0; own_bitfield.len()],
+            own_bitfield,
+            wanted,
+            missing_blocks: Vec::new(),
+            total_length,
+            piece_length,
+            deadlines: HashMap::new(),
+            strategy: PickStrategyKind::default().build(),
+            reservations: HashMap::new(),
+            duplicates: DuplicateStats::default(),
+        };
+        picker.missing_blocks = (0..picker.own_bitfield.len() as u32)
+            .filter(|piece_index| !picker.has_piece(*piece_index))
+            .flat_map(|piece_index| picker.blocks_of_piece(piece_index))
+            .collect();
+        picker
+    }
+
+    // Swap the strategy, the blocks in flight are kept.
+    pub fn set_strategy(&mut self, strategy: Box<dyn PickStrategy>) {
+        self.strategy = strategy;
+    }
+
+    pub fn set_deadline(&mut self, piece_index: u32, deadline: Instant) {
+        if !self.has_piece(piece_index) {
+            self.deadlines.insert(piece_index, deadline);
+        }
+    }
+
+    // A peer connected or sent its bitfield.
+    pub fn add_peer_bitfield(&mut self, bitfield: &BitField) {
+        for piece_index in bitfield.iter_ones() {
+            if let Some(count) = self.availability.get_mut(piece_index) {
+                *count += 1;
+            }
+        }
+    }
+
+    // A peer disconnected, `bitfield` is the last one we know of it.
+    pub fn remove_peer_bitfield(&mut self, bitfield: &BitField) {
+        for piece_index in bitfield.iter_ones() {
+            if let Some(count) = self.availability.get_mut(piece_index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    pub fn add_peer_have(&mut self, piece_index: u32) {
+        if let Some(count) = self.availability.get_mut(piece_index as usize) {
+            *count += 1;
+        }
+    }
+
+    // The piece is wanted and we don't have it yet.
+    pub fn wants_piece(&self, piece_index: u32) -> bool {
+        let piece_index = piece_index as usize;
+        self.wanted.get(piece_index).is_some_and(|it| *it)
+            && !self.own_bitfield.get(piece_index).is_some_and(|it| *it)
+    }
+
+    // The peer has some piece we want, so we tell it we are interested.
+    pub fn is_interesting(&self, peer_bitfield: &BitField) -> bool {
+        peer_bitfield
+            .iter_ones()
+            .any(|piece_index| self.wants_piece(piece_index as u32))
+    }
+
+    // None of the connected peers has the piece.
+    pub fn is_rare(&self, piece_index: u32) -> bool {
+        self.availability.get(piece_index as usize) == Some(&0)
+    }
+
+    // The last piece and its last block may be shorter than the others.
+    fn blocks_of_piece(&self, piece_index: u32) -> Vec<BlockInfo> {
+        let piece_size = self.piece_size(piece_index);
+        (0..piece_size.div_ceil(BLOCK_SIZE))
+            .map(|i| {
+                let begin = i * BLOCK_SIZE;
+                BlockInfo::new(piece_index, begin, BLOCK_SIZE.min(piece_size - begin))
+            })
+            .collect()
+    }
+
+    // Change which pieces to download, e.g. the user skipped a file or wants it again.
+    // The blocks of the pieces which are no longer wanted are dropped even if in progress.
+    pub fn set_wanted(&mut self, wanted: BitField) {
+        self.missing_blocks
+            .retain(|it| wanted.get(it.piece_index as usize).is_some_and(|it| *it));
+        self.reservations
+            .retain(|(piece_index, _), _| wanted.get(*piece_index as usize).is_some_and(|it| *it));
+        for piece_index in wanted.iter_ones() {
+            let piece_index = piece_index as u32;
+            if !self.wanted[piece_index as usize] && !self.has_piece(piece_index) {
+                let blocks = self.blocks_of_piece(piece_index);
+                self.missing_blocks.extend(blocks);
+            }
+        }
+        self.wanted = wanted;
+    }
+
+    // How many copies of the missing pieces the connected peers have, like the distributed
+    // copies of the other clients: the whole part is the copies of the rarest piece, the fraction
+    // how many of the pieces have more. Below 1 some piece can't be downloaded from them.
+    // None if no wanted piece is missing.
+    pub fn distributed_copies(&self) -> Option<f64> {
+        let counts: Vec<u32> = self
+            .wanted
+            .iter_ones()
+            .filter(|piece_index| !self.own_bitfield[*piece_index])
+            .map(|piece_index| self.availability.get(piece_index).copied().unwrap_or(0))
+            .collect();
+        let rarest = *counts.iter().min()?;
+        let more = counts.iter().filter(|it| **it > rarest).count();
+        Some(rarest as f64 + more as f64 / counts.len() as f64)
+    }
+
+    // All the wanted pieces are downloaded, the torrent is seeding.
+    pub fn is_complete(&self) -> bool {
+        self.wanted
+            .iter_ones()
+            .all(|piece_index| self.own_bitfield[piece_index])
+    }
+
+    // Bytes of the wanted pieces we don't have yet, the `left` we announce to the trackers.
+    pub fn left_bytes(&self) -> u64 {
+        self.wanted
+            .iter_ones()
+            .filter(|piece_index| !self.own_bitfield[*piece_index])
+            .map(|piece_index| self.piece_size(piece_index as u32) as u64)
+            .sum()
+    }
+
+    fn piece_size(&self, piece_index: u32) -> u32 {
+        let begin = piece_index * self.piece_length;
+        self.piece_length.min(self.total_length - begin)
+    }
+
+    // Pick a block the peer has and reserve it for the peer, so no one else requests it.
+    pub fn request_block(&mut self, peer: PeerId, peer_bitfield: &BitField) -> Option<BlockInfo> {
+        let index = self.pick(peer_bitfield)?;
+        let block = &mut self.missing_blocks[index];
+        block.state = BlockState::Requested;
+        if let Some(other) = self
+            .reservations
+            .insert((block.piece_index, block.begin), peer)
+            && other != peer
+        {
+            log::warn!(
+                "Block {}:{} is requested again while in flight",
+                block.piece_index,
+                block.begin
+            );
+            self.duplicates.requests += 1;
+        }
+        Some(block.clone())
+    }
+
+    // The index in `missing_blocks` of the next block to request from the peer.
+    fn pick(&mut self, peer_bitfield: &BitField) -> Option<usize> {
+        let candidates = self.candidates(peer_bitfield);
+        if candidates.is_empty() {
+            return None;
+        }
+        let piece_index = self.strategy.pick_piece(&candidates)?;
+        self.missing_blocks
+            .iter()
+            .position(|it| it.piece_index == piece_index && it.state == BlockState::NotRequested)
+    }
+
+    // The pieces the peer has, which still have blocks to request.
+    fn candidates(&self, peer_bitfield: &BitField) -> Vec<PieceCandidate> {
+        let mut candidates: BTreeMap<u32, (PieceCandidate, bool)> = BTreeMap::new();
+        for block in &self.missing_blocks {
+            let piece_index = block.piece_index;
+            if !peer_bitfield
+                .get(piece_index as usize)
+                .is_some_and(|it| *it)
+            {
+                continue;
+            }
+            let (candidate, requestable) = candidates.entry(piece_index).or_insert_with(|| {
+                let candidate = PieceCandidate {
+                    piece_index,
+                    availability: self.availability[piece_index as usize],
+                    in_progress: false,
+                    deadline: self.deadlines.get(&piece_index).copied(),
+                };
+                (candidate, false)
+            });
+            if block.state == BlockState::NotRequested {
+                *requestable = true;
             } else {
-                acc
+                candidate.in_progress = true;
             }
-        });
-
-        let mut missing_blocks = Vec::with_capacity(num_of_missing_blocks as usize);
-
-        for piece_index in 0..own_bitfield.len() {
-            if own_bitfield[piece_index] == false {
-                let num_of_blocks = piece_length / BLOCK_SIZE;
-                for i in 0..num_of_blocks {
-                    let info = BlockInfo::new(
-                        piece_index as u32,
-                        i * BLOCK_SIZE,
-                        PiecePicker::block_size(
-                            &own_bitfield,
-                            piece_length,
-                            total_length,
-                            piece_index as u32,
-                            i,
-                        ),
-                    );
-                    missing_blocks.push(info);
-                }
+        }
+        candidates
+            .into_values()
+            .filter(|(_, requestable)| *requestable)
+            .map(|(candidate, _)| candidate)
+            .collect()
+    }
+
+    // The request of the peer failed, let the block be requested again.
+    // Only the peer which reserved the block can release it.
+    pub fn cancel_request(&mut self, peer: PeerId, block: &BlockInfo) {
+        let key = (block.piece_index, block.begin);
+        if self.reservations.get(&key) != Some(&peer) {
+            return;
+        }
+        self.reservations.remove(&key);
+        if let Some(block) = self
+            .missing_blocks
+            .iter_mut()
+            .find(|it| it.is_same_block_as_info(block) && it.state == BlockState::Requested)
+        {
+            block.state = BlockState::NotRequested;
+        }
+    }
+
+    // The peer is gone, release all the blocks it had in flight.
+    pub fn release_requests(&mut self, peer: PeerId) {
+        let blocks: Vec<(u32, u32)> = self
+            .reservations
+            .iter()
+            .filter(|(_, it)| **it == peer)
+            .map(|(key, _)| *key)
+            .collect();
+        for (piece_index, begin) in blocks {
+            self.reservations.remove(&(piece_index, begin));
+            if let Some(block) = self.missing_blocks.iter_mut().find(|it| {
+                it.piece_index == piece_index
+                    && it.begin == begin
+                    && it.state == BlockState::Requested
+            }) {
+                block.state = BlockState::NotRequested;
             }
         }
+    }
 
-        Self {
-            own_bitfield,
-            missing_blocks,
-            total_length,
-            piece_length,
+    // The blocks requested from the peers and not received yet.
+    pub fn in_flight(&self) -> usize {
+        self.reservations.len()
+    }
+
+    pub fn duplicates(&self) -> DuplicateStats {
+        self.duplicates
+    }
+
+    pub fn own_bitfield(&self) -> &BitField {
+        &self.own_bitfield
+    }
+
+    pub fn has_piece(&self, piece_index: u32) -> bool {
+        self.own_bitfield
+            .get(piece_index as usize)
+            .is_some_and(|it| *it)
+    }
+
+    // We lost a piece we had, e.g. it's corrupt on disk, download all of it again.
+    pub fn mark_missing(&mut self, piece_index: u32) {
+        self.missing_blocks
+            .retain(|it| it.piece_index != piece_index);
+        self.reservations
+            .retain(|(index, _), _| *index != piece_index);
+        let blocks = self.blocks_of_piece(piece_index);
+        self.missing_blocks.extend(blocks);
+        if let Some(mut has) = self.own_bitfield.get_mut(piece_index as usize) {
+            *has = false;
         }
     }
 
-    pub fn pick_block(&mut self, peer_bitfield: &BitField) -> Option<&BlockInfo> {
-        self.missing_blocks.iter().find(|it| {
-            peer_bitfield[it.piece_index as usize] == true && it.state == BlockState::NotRequested
-        })
+    // The piece turned out to be on the disk already, e.g. found by a recheck.
+    pub fn mark_have(&mut self, piece_index: u32) {
+        self.missing_blocks
+            .retain(|it| it.piece_index != piece_index);
+        self.reservations
+            .retain(|(index, _), _| *index != piece_index);
+        self.deadlines.remove(&piece_index);
+        if let Some(mut has) = self.own_bitfield.get_mut(piece_index as usize) {
+            *has = true;
+        }
     }
 
-    fn block_size(
-        own_bitfield: &BitField,
-        piece_length: u32,
-        total_length: u32,
-        piece_index: u32,
-        block_index: u32,
-    ) -> u32 {
-        let is_last_piece = own_bitfield.len() as u32 == piece_index + 1;
-        let is_last_block = block_index * BLOCK_SIZE + BLOCK_SIZE >= piece_length;
-        if is_last_piece && is_last_block {
-            let last_block_size = total_length % BLOCK_SIZE;
-            last_block_size
-        } else {
-            BLOCK_SIZE
+    // The blocks of the piece at `begins` failed to verify, request them again.
+    pub fn reset_blocks(&mut self, piece_index: u32, begins: &[u32]) {
+        for block in self.missing_blocks.iter_mut() {
+            if block.piece_index == piece_index && begins.contains(&block.begin) {
+                block.state = BlockState::NotRequested;
+            }
+        }
+        if let Some(mut has) = self.own_bitfield.get_mut(piece_index as usize) {
+            *has = false;
         }
     }
 
@@ -124,7 +390,12 @@ impl PiecePicker {
             .iter_mut()
             .find(|it| it.is_same_block_as_block(block));
         if let Some(mut_block) = mut_block {
+            if mut_block.state == BlockState::Received {
+                self.duplicates.blocks += 1;
+                return;
+            }
             mut_block.state = BlockState::Received;
+            self.reservations.remove(&(block.piece_index, block.begin));
             let is_all_blocks_received = self
                 .missing_blocks
                 .iter()
@@ -132,7 +403,170 @@ impl PiecePicker {
                 .all(|it| it.state == BlockState::Received);
             if is_all_blocks_received {
                 self.own_bitfield.set(block.piece_index as usize, true);
+                self.deadlines.remove(&block.piece_index);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: PeerId = [1; 20];
+
+    #[test]
+    fn test_set_wanted() {
+        let piece_length = 2 * BLOCK_SIZE;
+        let total_length = 3 * piece_length;
+        let mut own_bitfield = BitField::repeat(true, 3);
+        own_bitfield.set(2, false);
+        let mut picker = PiecePicker::new(own_bitfield, total_length, piece_length);
+        assert!(!picker.is_complete());
+        assert_eq!(picker.left_bytes(), piece_length as u64);
+        let mut partial = BitField::repeat(true, 3);
+        partial.set(2, false);
+        assert!(picker.is_interesting(&BitField::repeat(true, 3)));
+        assert!(!picker.is_interesting(&partial));
+
+        // Skip the last piece, nothing left to download
+        let mut wanted = BitField::repeat(true, 3);
+        wanted.set(2, false);
+        picker.set_wanted(wanted);
+        assert!(picker.is_complete());
+        assert_eq!(picker.left_bytes(), 0);
+        assert!(!picker.is_interesting(&BitField::repeat(true, 3)));
+        assert!(
+            picker
+                .request_block(PEER, &BitField::repeat(true, 3))
+                .is_none()
+        );
+
+        // Want it again after it's seeding
+        picker.set_wanted(BitField::repeat(true, 3));
+        assert!(!picker.is_complete());
+        assert_eq!(picker.left_bytes(), piece_length as u64);
+        let block = picker
+            .request_block(PEER, &BitField::repeat(true, 3))
+            .unwrap();
+        assert_eq!(block.piece_index, 2);
+        assert_eq!(picker.missing_blocks.len(), 2);
+    }
+
+    #[test]
+    fn test_distributed_copies() {
+        let piece_length = BLOCK_SIZE;
+        let mut own_bitfield = BitField::repeat(false, 4);
+        own_bitfield.set(0, true);
+        let mut picker = PiecePicker::new(own_bitfield, 4 * piece_length, piece_length);
+        assert_eq!(picker.distributed_copies(), Some(0.0));
+
+        // A seeder and a peer with one of the three missing pieces
+        picker.add_peer_bitfield(&BitField::repeat(true, 4));
+        let mut partial = BitField::repeat(false, 4);
+        partial.set(1, true);
+        picker.add_peer_bitfield(&partial);
+        assert_eq!(picker.distributed_copies(), Some(1.0 + 1.0 / 3.0));
+
+        // The seeder left, pieces 2 and 3 are nowhere
+        picker.remove_peer_bitfield(&BitField::repeat(true, 4));
+        assert_eq!(picker.distributed_copies(), Some(1.0 / 3.0));
+
+        // Only want the piece we have
+        let mut wanted = BitField::repeat(false, 4);
+        wanted.set(0, true);
+        picker.set_wanted(wanted);
+        assert_eq!(picker.distributed_copies(), None);
+    }
+
+    #[test]
+    fn test_request_block() {
+        let piece_length = 2 * BLOCK_SIZE;
+        // The last piece is a single short block
+        let total_length = piece_length + 100;
+        let mut picker = PiecePicker::new(BitField::repeat(false, 2), total_length, piece_length);
+        assert_eq!(picker.missing_blocks.len(), 3);
+        assert_eq!(picker.missing_blocks[2].length, 100);
+
+        let mut peer_bitfield = BitField::repeat(false, 2);
+        peer_bitfield.set(1, true);
+        let block = picker.request_block(PEER, &peer_bitfield).unwrap();
+        assert_eq!((block.piece_index, block.begin), (1, 0));
+        // Requested blocks are not handed out twice
+        assert!(picker.request_block(PEER, &peer_bitfield).is_none());
+
+        picker.cancel_request(PEER, &block);
+        assert!(picker.request_block(PEER, &peer_bitfield).is_some());
+    }
+
+    #[test]
+    fn test_swap_strategy() {
+        let piece_length = 2 * BLOCK_SIZE;
+        let mut picker =
+            PiecePicker::new(BitField::repeat(false, 3), 3 * piece_length, piece_length);
+        let all = BitField::repeat(true, 3);
+        let mut rare = BitField::repeat(true, 3);
+        rare.set(1, false);
+        picker.add_peer_bitfield(&all);
+        picker.add_peer_bitfield(&rare);
+
+        // Rarest first by default
+        let block = picker.request_block(PEER, &all).unwrap();
+        assert_eq!((block.piece_index, block.begin), (1, 0));
+
+        // The started piece stays in flight after the swap
+        picker.set_strategy(PickStrategyKind::Sequential.build());
+        let block = picker.request_block(PEER, &all).unwrap();
+        assert_eq!((block.piece_index, block.begin), (0, 0));
+
+        picker.set_strategy(PickStrategyKind::Deadline.build());
+        picker.set_deadline(2, Instant::now());
+        let block = picker.request_block(PEER, &all).unwrap();
+        assert_eq!((block.piece_index, block.begin), (2, 0));
+        assert_eq!(
+            picker
+                .missing_blocks
+                .iter()
+                .filter(|it| it.state == BlockState::Requested)
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_reservations() {
+        let piece_length = 2 * BLOCK_SIZE;
+        let mut picker = PiecePicker::new(BitField::repeat(false, 1), piece_length, piece_length);
+        let all = BitField::repeat(true, 1);
+        let other = [2; 20];
+        let first = picker.request_block(PEER, &all).unwrap();
+        let second = picker.request_block(other, &all).unwrap();
+        assert_ne!(first.begin, second.begin);
+        assert!(picker.request_block(other, &all).is_none());
+
+        // Another peer can't release the reservation
+        picker.cancel_request(other, &first);
+        assert!(picker.request_block(other, &all).is_none());
+
+        // The blocks of a disconnected peer are requested by the others
+        picker.release_requests(PEER);
+        let block = picker.request_block(other, &all).unwrap();
+        assert_eq!(block.begin, first.begin);
+
+        let received = Block {
+            piece_index: 0,
+            begin: block.begin,
+            data: vec![0; BLOCK_SIZE as usize],
+            peer: None,
+        };
+        picker.mark_received(&received);
+        picker.mark_received(&received);
+        assert_eq!(
+            picker.duplicates(),
+            DuplicateStats {
+                requests: 0,
+                blocks: 1
+            }
+        );
+    }
+}
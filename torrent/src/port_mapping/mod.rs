@@ -0,0 +1,484 @@
+mod natpmp;
+mod upnp;
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use reqwest::Client;
+use thiserror::Error;
+use tokio::{
+    sync::{broadcast, oneshot},
+    task::JoinHandle,
+    time::{Instant, sleep_until, timeout},
+};
+
+use crate::client_identity::ClientIdentity;
+
+use self::upnp::UpnpGateway;
+
+// Forward the listen port on the gateway, so the peers can dial us through the NAT. Both the
+// TCP and the UDP port are mapped, the uTP peers and the DHT come in over UDP. The gateway is
+// asked with PCP, then NAT-PMP, then UPnP IGD, and whichever worked is kept for the renewals.
+// The mappings are leases, renewed halfway through and removed on stop.
+
+// Asked for, the gateways may give less.
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(60 * 60);
+// When none of the methods worked, the gateway may come up later.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// Don't hold the shutdown on a gateway which went away.
+const UNMAP_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Error, Debug)]
+pub enum PortMappingError {
+    #[error("Failed to talk to the gateway: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("The gateway didn't respond")]
+    Timeout,
+    #[error("The gateway doesn't speak this version of the protocol")]
+    UnsupportedVersion,
+    #[error("The gateway refused the mapping with result {0}")]
+    Refused(u16),
+    #[error("Malformed response: {0}")]
+    Malformed(&'static str),
+    #[error("No gateway found")]
+    NoGateway,
+    #[error("No UPnP gateway found")]
+    NoUpnpGateway,
+    #[error("Failed the UPnP request: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("The UPnP gateway failed with {code}: {description}")]
+    Upnp { code: u16, description: String },
+}
+
+pub(crate) type Result<T> = std::result::Result<T, PortMappingError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "TCP"),
+            Protocol::Udp => write!(f, "UDP"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingMethod {
+    Pcp,
+    NatPmp,
+    Upnp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Mapping {
+    external_port: u16,
+    // None if the gateway didn't tell.
+    external_ip: Option<IpAddr>,
+    // Zero for a permanent mapping.
+    lifetime: Duration,
+}
+
+// How the mappings are going, for the UI to show if we're reachable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortMappingEvent {
+    Mapped {
+        protocol: Protocol,
+        method: MappingMethod,
+        external_port: u16,
+        external_ip: Option<IpAddr>,
+        // Zero for a permanent mapping.
+        lifetime: Duration,
+    },
+    // None of the methods worked, tried again later.
+    Failed {
+        protocol: Protocol,
+        error: String,
+    },
+    Unmapped {
+        protocol: Protocol,
+    },
+}
+
+pub struct PortMapper {
+    port: u16,
+    // Where to send the NAT-PMP and the PCP requests, None finds the default gateway.
+    gateway: Option<SocketAddr>,
+    natpmp: bool,
+    upnp: bool,
+    lease: Duration,
+    // Shown in the port mapping list of the router.
+    description: String,
+    client: Client,
+    events: broadcast::Sender<PortMappingEvent>,
+    // Whichever worked last, tried first.
+    method: Option<MappingMethod>,
+    upnp_gateway: Option<UpnpGateway>,
+    // Identifies our PCP mappings, the same for all of their renewals.
+    nonce: [u8; 12],
+    mapped: Vec<(Protocol, MappingMethod)>,
+}
+
+// Stop the mapping when the client quits or the listen port changes.
+pub struct PortMapperHandle {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl PortMapperHandle {
+    // Remove the mappings from the gateway and wait for it.
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+    }
+}
+
+impl PortMapper {
+    pub fn new(port: u16, identity: &ClientIdentity) -> Result<Self> {
+        Ok(Self {
+            port,
+            gateway: None,
+            natpmp: true,
+            upnp: true,
+            lease: DEFAULT_LEASE,
+            description: identity.user_agent().to_string(),
+            client: identity.http_client(None)?,
+            events: broadcast::channel(16).0,
+            method: None,
+            upnp_gateway: None,
+            nonce: rand::random(),
+            mapped: Vec::new(),
+        })
+    }
+
+    // The NAT-PMP and PCP gateway, when it's not the default gateway of the OS.
+    pub fn with_gateway(mut self, gateway: SocketAddr) -> Self {
+        self.gateway = Some(gateway);
+        self
+    }
+
+    // The user may turn off either, e.g. a router which maps UPnP badly.
+    pub fn with_methods(mut self, natpmp: bool, upnp: bool) -> Self {
+        self.natpmp = natpmp;
+        self.upnp = upnp;
+        self
+    }
+
+    pub fn with_lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PortMappingEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn spawn(self) -> PortMapperHandle {
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(self.run(stopped));
+        PortMapperHandle { stop, task }
+    }
+
+    async fn run(mut self, mut stop: oneshot::Receiver<()>) {
+        loop {
+            let next_renewal = self.map_all().await;
+            tokio::select! {
+                _ = &mut stop => break,
+                _ = sleep_until(next_renewal) => {}
+            }
+        }
+        if timeout(UNMAP_TIMEOUT, self.unmap_all()).await.is_err() {
+            log::warn!("Gateway didn't respond to the removal of the port mappings");
+        }
+    }
+
+    // Map or renew both protocols, returns when to renew.
+    async fn map_all(&mut self) -> Instant {
+        let mut renew_in = Duration::MAX;
+        for protocol in [Protocol::Tcp, Protocol::Udp] {
+            let event = match self.map(protocol).await {
+                Ok((method, mapping)) => {
+                    if !self.mapped.contains(&(protocol, method)) {
+                        self.mapped.push((protocol, method));
+                    }
+                    // A permanent mapping is renewed too, the gateway may have rebooted
+                    let lifetime = if mapping.lifetime.is_zero() {
+                        self.lease
+                    } else {
+                        mapping.lifetime
+                    };
+                    renew_in = renew_in.min(lifetime / 2);
+                    log::info!(
+                        "Mapped {} port {} to {} with {:?}",
+                        protocol,
+                        self.port,
+                        mapping.external_port,
+                        method
+                    );
+                    PortMappingEvent::Mapped {
+                        protocol,
+                        method,
+                        external_port: mapping.external_port,
+                        external_ip: mapping.external_ip,
+                        lifetime: mapping.lifetime,
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Failed to map {} port {}: {}", protocol, self.port, e);
+                    renew_in = renew_in.min(RETRY_INTERVAL);
+                    PortMappingEvent::Failed {
+                        protocol,
+                        error: e.to_string(),
+                    }
+                }
+            };
+            let _ = self.events.send(event);
+        }
+        Instant::now() + renew_in
+    }
+
+    // Try the methods until one maps the port, the one which worked last time first.
+    async fn map(&mut self, protocol: Protocol) -> Result<(MappingMethod, Mapping)> {
+        let mut methods = Vec::new();
+        if self.natpmp {
+            methods.extend([MappingMethod::Pcp, MappingMethod::NatPmp]);
+        }
+        if self.upnp {
+            methods.push(MappingMethod::Upnp);
+        }
+        if let Some(method) = self.method
+            && let Some(position) = methods.iter().position(|it| *it == method)
+        {
+            methods[..=position].rotate_right(1);
+        }
+        let mut error = PortMappingError::NoGateway;
+        for method in methods {
+            match self.map_with(method, protocol, self.lease).await {
+                Ok(mapping) => {
+                    self.method = Some(method);
+                    return Ok((method, mapping));
+                }
+                Err(e) => {
+                    log::debug!("Failed to map {} with {:?}: {}", protocol, method, e);
+                    error = e;
+                }
+            }
+        }
+        Err(error)
+    }
+
+    // A zero lifetime removes the mapping.
+    async fn map_with(
+        &mut self,
+        method: MappingMethod,
+        protocol: Protocol,
+        lifetime: Duration,
+    ) -> Result<Mapping> {
+        match method {
+            MappingMethod::Pcp => {
+                let gateway = self.natpmp_gateway()?;
+                let local_ip = local_ip_towards(gateway)?;
+                natpmp::map_pcp(gateway, local_ip, protocol, self.port, lifetime, self.nonce).await
+            }
+            MappingMethod::NatPmp => {
+                let gateway = self.natpmp_gateway()?;
+                let local_ip = local_ip_towards(gateway)?;
+                let mut mapping =
+                    natpmp::map_natpmp(gateway, local_ip, protocol, self.port, lifetime).await?;
+                if !lifetime.is_zero() {
+                    mapping.external_ip = natpmp::external_ip(gateway, local_ip).await.ok();
+                }
+                Ok(mapping)
+            }
+            MappingMethod::Upnp => {
+                let gateway = self.upnp_gateway().await?.clone();
+                if lifetime.is_zero() {
+                    gateway.delete_port_mapping(protocol, self.port).await?;
+                    return Ok(Mapping {
+                        external_port: self.port,
+                        external_ip: None,
+                        lifetime,
+                    });
+                }
+                let local_ip = local_ip_towards(SocketAddr::new(
+                    gateway.host().ok_or(PortMappingError::NoGateway)?,
+                    0,
+                ))?;
+                let lifetime = gateway
+                    .add_port_mapping(protocol, self.port, local_ip, lifetime, &self.description)
+                    .await?;
+                Ok(Mapping {
+                    external_port: self.port,
+                    external_ip: gateway.external_ip().await.ok(),
+                    lifetime,
+                })
+            }
+        }
+    }
+
+    async fn unmap_all(&mut self) {
+        for (protocol, method) in std::mem::take(&mut self.mapped) {
+            match self.map_with(method, protocol, Duration::ZERO).await {
+                Ok(_) => {
+                    let _ = self.events.send(PortMappingEvent::Unmapped { protocol });
+                }
+                Err(e) => log::warn!("Failed to remove the {} port mapping: {}", protocol, e),
+            }
+        }
+    }
+
+    fn natpmp_gateway(&self) -> Result<SocketAddr> {
+        self.gateway
+            .or_else(|| default_gateway().map(|ip| SocketAddr::new(ip, natpmp::PORT)))
+            // The UPnP gateway may speak it too
+            .or_else(|| {
+                let ip = self.upnp_gateway.as_ref()?.host()?;
+                Some(SocketAddr::new(ip, natpmp::PORT))
+            })
+            .ok_or(PortMappingError::NoGateway)
+    }
+
+    // Searched once, the gateway doesn't move.
+    async fn upnp_gateway(&mut self) -> Result<&UpnpGateway> {
+        if self.upnp_gateway.is_none() {
+            let local_ip = local_ip_towards(upnp::SSDP_ADDR)?;
+            let location = upnp::search(local_ip).await?;
+            let gateway = UpnpGateway::from_location(self.client.clone(), location).await?;
+            self.upnp_gateway = Some(gateway);
+        }
+        Ok(self.upnp_gateway.as_ref().unwrap())
+    }
+}
+
+// The address of the interface the OS routes to the address, the one the gateway sees us at.
+// Connecting a UDP socket sends nothing.
+fn local_ip_towards(addr: SocketAddr) -> Result<IpAddr> {
+    let bind: SocketAddr = match addr {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = std::net::UdpSocket::bind(bind)?;
+    // The port doesn't matter, zero isn't allowed though
+    socket.connect(SocketAddr::new(addr.ip(), addr.port().max(1)))?;
+    Ok(socket.local_addr()?.ip())
+}
+
+// From the routing table on Linux, the other systems rely on the UPnP gateway or the gateway
+// set by the user.
+fn default_gateway() -> Option<IpAddr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    parse_default_route(&routes)
+}
+
+fn parse_default_route(routes: &str) -> Option<IpAddr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            return None;
+        }
+        // In the byte order of the host, which is little endian on about everything
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        (gateway != 0).then(|| IpAddr::from(gateway.to_le_bytes()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{net::UdpSocket, sync::mpsc};
+
+    use super::*;
+
+    // A NAT-PMP gateway which doesn't speak PCP, sends the requests it got to the test.
+    async fn start_natpmp_gateway() -> (SocketAddr, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (requests_tx, requests) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let request = buf[..len].to_vec();
+                let mut response = vec![0, 0x80 | request[1], 0, 0, 0, 0, 0, 1];
+                match (request[0], request[1]) {
+                    // Unsupported version
+                    (2, _) => response[3] = 1,
+                    (0, 0) => response.extend([203, 0, 113, 5]),
+                    // The port asked for one higher, with the lifetime asked for
+                    (0, _) => {
+                        response.extend(&request[4..6]);
+                        let external = u16::from_be_bytes([request[6], request[7]]);
+                        response.extend((external + 1).to_be_bytes());
+                        response.extend(&request[8..12]);
+                    }
+                    _ => continue,
+                }
+                let _ = requests_tx.send(request);
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+        (addr, requests)
+    }
+
+    #[tokio::test]
+    async fn test_map_with_natpmp() {
+        let (gateway, mut requests) = start_natpmp_gateway().await;
+        let mapper = PortMapper::new(6881, &ClientIdentity::default())
+            .unwrap()
+            .with_gateway(gateway)
+            .with_methods(true, false)
+            .with_lease(Duration::from_secs(7200));
+        let mut events = mapper.subscribe();
+        let handle = mapper.spawn();
+
+        for protocol in [Protocol::Tcp, Protocol::Udp] {
+            assert_eq!(
+                events.recv().await.unwrap(),
+                PortMappingEvent::Mapped {
+                    protocol,
+                    method: MappingMethod::NatPmp,
+                    external_port: 6882,
+                    external_ip: Some("203.0.113.5".parse().unwrap()),
+                    lifetime: Duration::from_secs(7200),
+                }
+            );
+        }
+        handle.stop().await;
+        assert_eq!(
+            events.recv().await.unwrap(),
+            PortMappingEvent::Unmapped {
+                protocol: Protocol::Tcp
+            }
+        );
+
+        // PCP first, then NAT-PMP and the external address for each protocol, PCP isn't
+        // tried again once NAT-PMP worked
+        let mut opcodes = Vec::new();
+        while let Ok(request) = requests.try_recv() {
+            opcodes.push((request[0], request[1]));
+        }
+        assert_eq!(
+            opcodes,
+            vec![(2, 1), (0, 2), (0, 0), (0, 1), (0, 0), (0, 2), (0, 1)]
+        );
+    }
+
+    #[test]
+    fn test_parse_default_route() {
+        let routes = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0
+";
+        assert_eq!(
+            parse_default_route(routes),
+            Some("192.168.1.1".parse().unwrap())
+        );
+        assert_eq!(parse_default_route("Iface\tDestination\tGateway\n"), None);
+    }
+}
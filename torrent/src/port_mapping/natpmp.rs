@@ -0,0 +1,250 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::net::UdpSocket;
+
+use super::{Mapping, PortMappingError, Protocol, Result};
+
+// NAT-PMP and PCP, its successor, both over UDP to port 5351 of the gateway. PCP goes first,
+// a gateway which only speaks NAT-PMP answers it with the unsupported version result.
+// https://www.rfc-editor.org/rfc/rfc6886
+// https://www.rfc-editor.org/rfc/rfc6887
+
+pub(super) const PORT: u16 = 5351;
+
+const NATPMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+const PCP_OPCODE_MAP: u8 = 1;
+// Set on the opcode of the responses.
+const RESPONSE: u8 = 0x80;
+// The same result code in both.
+const UNSUPPORTED_VERSION: u16 = 1;
+const PCP_RESPONSE_LENGTH: usize = 60;
+const NATPMP_RESPONSE_LENGTH: usize = 16;
+
+// The RFCs retry for a minute doubling from 250ms, but a gateway which doesn't answer in a few
+// seconds doesn't speak it, and the UPnP is waiting.
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const MAX_ATTEMPTS: u32 = 4;
+
+impl Protocol {
+    fn natpmp_opcode(self) -> u8 {
+        match self {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+        }
+    }
+
+    fn ip_protocol(self) -> u8 {
+        match self {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+        }
+    }
+}
+
+// The nonce tells our mappings from the others, the renewals and the removal use the same.
+pub(super) async fn map_pcp(
+    gateway: SocketAddr,
+    local_ip: IpAddr,
+    protocol: Protocol,
+    port: u16,
+    lifetime: Duration,
+    nonce: [u8; 12],
+) -> Result<Mapping> {
+    let request = pcp_map_request(local_ip, protocol, port, lifetime, nonce);
+    request_response(gateway, local_ip, &request, |response| {
+        parse_pcp_map_response(response, &nonce)
+    })
+    .await
+}
+
+// Returns the mapping without the external address, see `external_ip`.
+pub(super) async fn map_natpmp(
+    gateway: SocketAddr,
+    local_ip: IpAddr,
+    protocol: Protocol,
+    port: u16,
+    lifetime: Duration,
+) -> Result<Mapping> {
+    let opcode = protocol.natpmp_opcode();
+    let mut request = vec![NATPMP_VERSION, opcode, 0, 0];
+    request.extend(port.to_be_bytes());
+    // The removal asks for no external port
+    let external_port = if lifetime.is_zero() { 0 } else { port };
+    request.extend(external_port.to_be_bytes());
+    request.extend((lifetime.as_secs() as u32).to_be_bytes());
+    request_response(gateway, local_ip, &request, |response| {
+        let response = parse_natpmp_response(response, opcode, NATPMP_RESPONSE_LENGTH)?;
+        Some(response.map(|it| Mapping {
+            external_port: u16::from_be_bytes([it[10], it[11]]),
+            external_ip: None,
+            lifetime: Duration::from_secs(
+                u32::from_be_bytes([it[12], it[13], it[14], it[15]]) as u64
+            ),
+        }))
+    })
+    .await
+}
+
+pub(super) async fn external_ip(gateway: SocketAddr, local_ip: IpAddr) -> Result<IpAddr> {
+    request_response(gateway, local_ip, &[NATPMP_VERSION, 0], |response| {
+        let response = parse_natpmp_response(response, 0, 12)?;
+        Some(response.map(|it| IpAddr::V4(Ipv4Addr::new(it[8], it[9], it[10], it[11]))))
+    })
+    .await
+}
+
+// Send the request until the gateway answers it, the datagrams which aren't the answer are
+// ignored, e.g. the address change announcements of the gateway.
+async fn request_response<T>(
+    gateway: SocketAddr,
+    local_ip: IpAddr,
+    request: &[u8],
+    parse: impl Fn(&[u8]) -> Option<Result<T>>,
+) -> Result<T> {
+    let socket = UdpSocket::bind(SocketAddr::new(local_ip, 0)).await?;
+    socket.connect(gateway).await?;
+    let mut buf = [0u8; 1100];
+    let mut wait = INITIAL_TIMEOUT;
+    for _ in 0..MAX_ATTEMPTS {
+        socket.send(request).await?;
+        let deadline = tokio::time::Instant::now() + wait;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            if let Some(result) = parse(&buf[..received?]) {
+                return result;
+            }
+        }
+        wait *= 2;
+    }
+    Err(PortMappingError::Timeout)
+}
+
+fn pcp_map_request(
+    local_ip: IpAddr,
+    protocol: Protocol,
+    port: u16,
+    lifetime: Duration,
+    nonce: [u8; 12],
+) -> Vec<u8> {
+    let mut request = Vec::with_capacity(PCP_RESPONSE_LENGTH);
+    request.extend([PCP_VERSION, PCP_OPCODE_MAP, 0, 0]);
+    request.extend((lifetime.as_secs() as u32).to_be_bytes());
+    request.extend(to_pcp_address(local_ip));
+    request.extend(nonce);
+    request.push(protocol.ip_protocol());
+    request.extend([0; 3]);
+    request.extend(port.to_be_bytes());
+    request.extend(port.to_be_bytes());
+    // Any external address of the same family
+    let unspecified = match local_ip {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    request.extend(to_pcp_address(unspecified));
+    request
+}
+
+// None if it's not the response to our request.
+fn parse_pcp_map_response(response: &[u8], nonce: &[u8; 12]) -> Option<Result<Mapping>> {
+    // A NAT-PMP gateway answers in its own version
+    if response.len() >= 4 && response[0] == NATPMP_VERSION {
+        let result = u16::from_be_bytes([response[2], response[3]]);
+        return Some(Err(if result == UNSUPPORTED_VERSION {
+            PortMappingError::UnsupportedVersion
+        } else {
+            PortMappingError::Refused(result)
+        }));
+    }
+    if response.len() < 4 || response[0] != PCP_VERSION || response[1] != RESPONSE | PCP_OPCODE_MAP
+    {
+        return None;
+    }
+    let result = response[3] as u16;
+    if result == UNSUPPORTED_VERSION {
+        return Some(Err(PortMappingError::UnsupportedVersion));
+    }
+    if response.len() < PCP_RESPONSE_LENGTH {
+        return Some(Err(PortMappingError::Malformed("short PCP response")));
+    }
+    if &response[24..36] != nonce {
+        return None;
+    }
+    if result != 0 {
+        return Some(Err(PortMappingError::Refused(result)));
+    }
+    let lifetime = u32::from_be_bytes([response[4], response[5], response[6], response[7]]);
+    let external_ip: [u8; 16] = response[44..60].try_into().unwrap();
+    let external_ip = Ipv6Addr::from(external_ip);
+    Some(Ok(Mapping {
+        external_port: u16::from_be_bytes([response[42], response[43]]),
+        external_ip: Some(match external_ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(external_ip),
+        }),
+        lifetime: Duration::from_secs(lifetime as u64),
+    }))
+}
+
+// None if it's not the response to the opcode.
+fn parse_natpmp_response(response: &[u8], opcode: u8, length: usize) -> Option<Result<&[u8]>> {
+    if response.len() < 4 || response[0] != NATPMP_VERSION || response[1] != RESPONSE | opcode {
+        return None;
+    }
+    match u16::from_be_bytes([response[2], response[3]]) {
+        0 if response.len() >= length => Some(Ok(response)),
+        0 => Some(Err(PortMappingError::Malformed("short NAT-PMP response"))),
+        UNSUPPORTED_VERSION => Some(Err(PortMappingError::UnsupportedVersion)),
+        result => Some(Err(PortMappingError::Refused(result))),
+    }
+}
+
+// PCP writes the v4 addresses v4-mapped.
+fn to_pcp_address(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pcp_map_response() {
+        let nonce = [7; 12];
+        let local_ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+        let request = pcp_map_request(
+            local_ip,
+            Protocol::Tcp,
+            6881,
+            Duration::from_secs(3600),
+            nonce,
+        );
+        assert_eq!(request.len(), PCP_RESPONSE_LENGTH);
+        assert_eq!(&request[8..24], &to_pcp_address(local_ip));
+        assert_eq!(request[36], 6);
+
+        // The gateway echoes the request with the assigned port and address
+        let mut response = request.clone();
+        response[1] |= RESPONSE;
+        response[4..8].copy_from_slice(&1800u32.to_be_bytes());
+        response[42..44].copy_from_slice(&6882u16.to_be_bytes());
+        response[44..60].copy_from_slice(&to_pcp_address("203.0.113.5".parse().unwrap()));
+        let mapping = parse_pcp_map_response(&response, &nonce).unwrap().unwrap();
+        assert_eq!(mapping.external_port, 6882);
+        assert_eq!(mapping.external_ip, Some("203.0.113.5".parse().unwrap()));
+        assert_eq!(mapping.lifetime, Duration::from_secs(1800));
+
+        // Someone else's mapping
+        assert!(parse_pcp_map_response(&response, &[8; 12]).is_none());
+        // A NAT-PMP gateway
+        assert!(matches!(
+            parse_pcp_map_response(&[0, 0x81, 0, 1, 0, 0, 0, 0], &nonce),
+            Some(Err(PortMappingError::UnsupportedVersion))
+        ));
+    }
+}
@@ -0,0 +1,338 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use reqwest::{Client, StatusCode};
+use tokio::net::UdpSocket;
+use url::Url;
+
+use super::{PortMappingError, Protocol, Result};
+
+// UPnP IGD: the gateway answers an SSDP search with where its description is, which tells
+// where to send the SOAP requests of its WAN connection service.
+// http://upnp.org/specs/gw/UPnP-gw-WANIPConnection-v2-Service.pdf
+
+pub(super) const SSDP_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+// The services which map ports, the first the gateway has is used.
+const SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+// How long the gateways may take to answer the search, the MX of the request.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(2);
+// The gateway only takes mappings without a lease.
+const ONLY_PERMANENT_LEASES: u16 = 725;
+
+// Search the gateway from the local address, returns where its description is.
+pub(super) async fn search(local_ip: IpAddr) -> Result<Url> {
+    let socket = UdpSocket::bind(SocketAddr::new(local_ip, 0)).await?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\n\r\n",
+        SSDP_ADDR,
+        SEARCH_TARGET,
+        SEARCH_TIMEOUT.as_secs()
+    );
+    socket.send_to(request.as_bytes(), SSDP_ADDR).await?;
+    let mut buf = [0u8; 2048];
+    let deadline = tokio::time::Instant::now() + SEARCH_TIMEOUT;
+    // The other devices on the network may answer too
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+        let response = String::from_utf8_lossy(&buf[..received?]);
+        if let Some(location) = parse_location(&response) {
+            return Ok(location);
+        }
+    }
+    Err(PortMappingError::NoUpnpGateway)
+}
+
+fn parse_location(response: &str) -> Option<Url> {
+    response.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if !name.trim().eq_ignore_ascii_case("location") {
+            return None;
+        }
+        Url::parse(value.trim()).ok()
+    })
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct UpnpGateway {
+    control_url: Url,
+    service_type: String,
+    client: Client,
+}
+
+impl UpnpGateway {
+    pub(super) async fn from_location(client: Client, location: Url) -> Result<Self> {
+        let response = client.get(location.clone()).send().await?;
+        if !response.status().is_success() {
+            return Err(PortMappingError::Upnp {
+                code: response.status().as_u16(),
+                description: "Failed to fetch the description".to_string(),
+            });
+        }
+        let description = response.text().await?;
+        let (service_type, control_url) =
+            find_service(&description).ok_or(PortMappingError::NoUpnpGateway)?;
+        // Relative to the base URL if the description has one
+        let base = xml_value(&description, "URLBase")
+            .and_then(|it| Url::parse(it.trim()).ok())
+            .unwrap_or(location);
+        let control_url = base
+            .join(&control_url)
+            .map_err(|_| PortMappingError::Malformed("invalid control URL"))?;
+        Ok(Self {
+            control_url,
+            service_type,
+            client,
+        })
+    }
+
+    // The address of the gateway, which speaks NAT-PMP too if we're lucky.
+    pub(super) fn host(&self) -> Option<IpAddr> {
+        match self.control_url.host()? {
+            url::Host::Ipv4(ip) => Some(IpAddr::V4(ip)),
+            url::Host::Ipv6(ip) => Some(IpAddr::V6(ip)),
+            url::Host::Domain(_) => None,
+        }
+    }
+
+    // Returns the lease the gateway took, zero if it only keeps permanent mappings.
+    pub(super) async fn add_port_mapping(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        local_ip: IpAddr,
+        lease: Duration,
+        description: &str,
+    ) -> Result<Duration> {
+        let mapping = |lease: Duration| {
+            vec![
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", port.to_string()),
+                ("NewProtocol", protocol.to_string()),
+                ("NewInternalPort", port.to_string()),
+                ("NewInternalClient", local_ip.to_string()),
+                ("NewEnabled", "1".to_string()),
+                ("NewPortMappingDescription", description.to_string()),
+                ("NewLeaseDuration", lease.as_secs().to_string()),
+            ]
+        };
+        match self.soap("AddPortMapping", &mapping(lease)).await {
+            Ok(_) => Ok(lease),
+            Err(PortMappingError::Upnp {
+                code: ONLY_PERMANENT_LEASES,
+                ..
+            }) => {
+                self.soap("AddPortMapping", &mapping(Duration::ZERO))
+                    .await?;
+                Ok(Duration::ZERO)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub(super) async fn delete_port_mapping(&self, protocol: Protocol, port: u16) -> Result<()> {
+        self.soap(
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", port.to_string()),
+                ("NewProtocol", protocol.to_string()),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub(super) async fn external_ip(&self) -> Result<IpAddr> {
+        let response = self.soap("GetExternalIPAddress", &[]).await?;
+        xml_value(&response, "NewExternalIPAddress")
+            .and_then(|it| it.trim().parse().ok())
+            .ok_or(PortMappingError::Malformed("no external address"))
+    }
+
+    // Returns the body of the response, the faults are turned into errors.
+    async fn soap(&self, action: &str, arguments: &[(&str, String)]) -> Result<String> {
+        let arguments: String = arguments
+            .iter()
+            .map(|(name, value)| format!("<{name}>{}</{name}>", escape(value)))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{}\">{arguments}</u:{action}></s:Body></s:Envelope>",
+            self.service_type
+        );
+        let response = self
+            .client
+            .post(self.control_url.clone())
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header(
+                "SOAPAction",
+                format!("\"{}#{}\"", self.service_type, action),
+            )
+            .body(body)
+            .send()
+            .await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if status == StatusCode::OK {
+            return Ok(text);
+        }
+        Err(PortMappingError::Upnp {
+            code: xml_value(&text, "errorCode")
+                .and_then(|it| it.trim().parse().ok())
+                .unwrap_or(status.as_u16()),
+            description: xml_value(&text, "errorDescription")
+                .unwrap_or_default()
+                .to_string(),
+        })
+    }
+}
+
+// The type and the control URL of the first service which maps ports.
+fn find_service(description: &str) -> Option<(String, String)> {
+    let services: Vec<(&str, &str)> = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|service| {
+            let service = service.split("</service>").next()?;
+            Some((
+                xml_value(service, "serviceType")?.trim(),
+                xml_value(service, "controlURL")?.trim(),
+            ))
+        })
+        .collect();
+    SERVICE_TYPES.iter().find_map(|wanted| {
+        services
+            .iter()
+            .find(|(service_type, _)| service_type == wanted)
+            .map(|(service_type, control_url)| (service_type.to_string(), control_url.to_string()))
+    })
+}
+
+// The text of the first element of the name, the documents are small and flat enough to not
+// need an XML parser.
+fn xml_value<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", name))?;
+    Some(&xml[start..start + end])
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+
+    use super::*;
+
+    const DESCRIPTION: &str = "<?xml version=\"1.0\"?>
+<root xmlns=\"urn:schemas-upnp-org:device-1-0\">
+<device><deviceList><device><deviceList><device>
+<serviceList>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1</serviceType>
+<controlURL>/ctl/CmnIfCfg</controlURL>
+</service>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+<controlURL>/ctl/IPConn</controlURL>
+</service>
+</serviceList>
+</device></deviceList></device></deviceList></device>
+</root>";
+
+    #[test]
+    fn test_parse_search_response() {
+        let response = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+            Location: http://192.168.1.1:5000/rootDesc.xml\r\nST: {}\r\n\r\n";
+        assert_eq!(
+            parse_location(response).unwrap().as_str(),
+            "http://192.168.1.1:5000/rootDesc.xml"
+        );
+        assert_eq!(
+            find_service(DESCRIPTION),
+            Some((SERVICE_TYPES[1].to_string(), "/ctl/IPConn".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_port_mapping() {
+        let mut server = mockito::Server::new_async().await;
+        let _description = server
+            .mock("GET", "/rootDesc.xml")
+            .with_body(DESCRIPTION)
+            .create_async()
+            .await;
+        let leased = server
+            .mock("POST", "/ctl/IPConn")
+            .match_header("soapaction", Matcher::Regex("#AddPortMapping".into()))
+            .match_body(Matcher::Regex("<NewLeaseDuration>3600<".into()))
+            .with_status(500)
+            .with_body(
+                "<s:Envelope><s:Body><s:Fault><detail><UPnPError>\
+                 <errorCode>725</errorCode>\
+                 <errorDescription>OnlyPermanentLeasesSupported</errorDescription>\
+                 </UPnPError></detail></s:Fault></s:Body></s:Envelope>",
+            )
+            .expect(1)
+            .create_async()
+            .await;
+        let permanent = server
+            .mock("POST", "/ctl/IPConn")
+            .match_header("soapaction", Matcher::Regex("#AddPortMapping".into()))
+            .match_body(Matcher::AllOf(vec![
+                Matcher::Regex("<NewLeaseDuration>0<".into()),
+                Matcher::Regex("<NewInternalClient>192.168.1.10<".into()),
+                Matcher::Regex("<NewProtocol>TCP<".into()),
+            ]))
+            .with_body("<s:Envelope><s:Body><u:AddPortMappingResponse/></s:Body></s:Envelope>")
+            .expect(1)
+            .create_async()
+            .await;
+        let _external_ip = server
+            .mock("POST", "/ctl/IPConn")
+            .match_header("soapaction", Matcher::Regex("#GetExternalIPAddress".into()))
+            .with_body(
+                "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+                 <NewExternalIPAddress>203.0.113.5</NewExternalIPAddress>\
+                 </u:GetExternalIPAddressResponse></s:Body></s:Envelope>",
+            )
+            .create_async()
+            .await;
+
+        let location = Url::parse(&format!("{}/rootDesc.xml", server.url())).unwrap();
+        let gateway = UpnpGateway::from_location(Client::new(), location)
+            .await
+            .unwrap();
+        let lease = gateway
+            .add_port_mapping(
+                Protocol::Tcp,
+                6881,
+                "192.168.1.10".parse().unwrap(),
+                Duration::from_secs(3600),
+                "test",
+            )
+            .await
+            .unwrap();
+        assert_eq!(lease, Duration::ZERO);
+        assert_eq!(
+            gateway.external_ip().await.unwrap(),
+            "203.0.113.5".parse::<IpAddr>().unwrap()
+        );
+        leased.assert_async().await;
+        permanent.assert_async().await;
+    }
+}
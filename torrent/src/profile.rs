@@ -0,0 +1,658 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::RangeInclusive,
+    path::Path,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::watch;
+
+use crate::{
+    announcer::DEFAULT_STOPPED_GRACE,
+    disk::{CacheMode, DiskOptions, WriteRetryPolicy},
+    hash_check::{self, HashCheckOptions},
+    mse::EncryptionPolicy,
+    qbittorrent::Credentials,
+    tracker::TrackerTls,
+    transport::BindSettings,
+};
+
+// Named sets of the session settings, e.g. "Home" without limits, "VPN" bound to the tunnel
+// interface, "Metered" with low rate limits. Switching the profile hands the new settings to
+// whoever subscribed. The engine applies the disk, hash check and announce settings right away,
+// the listener, the peer connections and the WebUI API only pick up theirs on the next start.
+
+pub(crate) type Result<T> = std::result::Result<T, ProfileError>;
+
+pub const DEFAULT_PROFILE: &str = "Default";
+pub const DEFAULT_LISTEN_PORT: u16 = 6881;
+// The dynamic ports, away from the well known BitTorrent ports some ISPs throttle.
+const RANDOM_LISTEN_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error("Failed to access profiles file")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse profiles file")]
+    Bencode(#[from] serde_bencode::Error),
+    #[error("No profile named {0:?}")]
+    NotFound(String),
+    #[error("The active profile can't be removed")]
+    RemoveActive,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    // Bytes per second, None means unlimited.
+    pub download_limit: Option<u64>,
+    pub upload_limit: Option<u64>,
+    // The local address of the interface to use, None means let the OS choose.
+    pub bind_addr: Option<IpAddr>,
+    // The interface to use by name, e.g. the VPN tunnel, when its address changes.
+    pub bind_interface: Option<String>,
+    // Of the TCP and uTP peers, on v4 and v6.
+    pub listen_port: u16,
+    // Pick another port each start, so the port can't be used to follow us across sessions.
+    pub randomize_listen_port: bool,
+    // e.g. socks5://127.0.0.1:1080
+    pub proxy: Option<String>,
+    pub dht: bool,
+    pub pex: bool,
+    pub utp: bool,
+    pub encryption: EncryptionPolicy,
+    // By tracker host, e.g. to trust the self-signed certificate of a private tracker.
+    pub tracker_tls: Vec<TrackerTls>,
+    // Write-through unless the user takes the risk, see `CacheMode::description`.
+    pub disk_cache: CacheMode,
+    // How many times a failed piece write is retried before the torrent is paused, e.g. the
+    // antivirus locked the file for a moment.
+    pub write_retries: u32,
+    // How long quitting waits for the trackers to take the stopped announce, capped at 30s.
+    pub stopped_announce_grace_secs: u64,
+    // The threads checking the pieces and reading the blocks to upload, None picks from the
+    // CPU count.
+    pub hashing_threads: Option<usize>,
+    pub disk_io_workers: Option<usize>,
+    // Serves the qBittorrent WebUI API on the port for Sonarr, Radarr etc., None turns it off.
+    pub web_api_port: Option<u16>,
+    // Without them the API is only bound to localhost.
+    pub web_api_credentials: Option<Credentials>,
+}
+
+impl Profile {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            download_limit: None,
+            upload_limit: None,
+            bind_addr: None,
+            bind_interface: None,
+            listen_port: DEFAULT_LISTEN_PORT,
+            randomize_listen_port: false,
+            proxy: None,
+            dht: true,
+            pex: true,
+            utp: true,
+            encryption: EncryptionPolicy::default(),
+            tracker_tls: Vec::new(),
+            disk_cache: CacheMode::default(),
+            write_retries: WriteRetryPolicy::default().retries,
+            stopped_announce_grace_secs: DEFAULT_STOPPED_GRACE.as_secs(),
+            hashing_threads: None,
+            disk_io_workers: None,
+            web_api_port: None,
+            web_api_credentials: None,
+        }
+    }
+
+    // Both the listener and the peer connections bind with these.
+    pub fn bind_settings(&self) -> BindSettings {
+        BindSettings {
+            address: self.bind_addr,
+            interface: self.bind_interface.clone(),
+        }
+    }
+
+    // Once for each start of the listener, see `PeerListener::bind_local`.
+    pub fn pick_listen_port(&self) -> u16 {
+        if self.randomize_listen_port {
+            rand::random_range(RANDOM_LISTEN_PORTS)
+        } else {
+            self.listen_port
+        }
+    }
+
+    // Where to serve the WebUI API, see `qbittorrent::serve`.
+    pub fn web_api_addr(&self) -> Option<SocketAddr> {
+        let ip = match self.web_api_credentials {
+            Some(_) => Ipv4Addr::UNSPECIFIED,
+            None => Ipv4Addr::LOCALHOST,
+        };
+        Some(SocketAddr::new(ip.into(), self.web_api_port?))
+    }
+
+    // None while the DHT is off. The node takes a port of its own, the uTP of the listener
+    // has the listen port, and only joins over v4 like the bootstrap nodes.
+    pub fn dht_addr(&self) -> Option<SocketAddr> {
+        let ip = self
+            .bind_addr
+            .filter(IpAddr::is_ipv4)
+            .unwrap_or(Ipv4Addr::UNSPECIFIED.into());
+        self.dht.then(|| SocketAddr::new(ip, 0))
+    }
+
+    // See `Announcer::with_stopped_grace`.
+    pub fn stopped_announce_grace(&self) -> Duration {
+        Duration::from_secs(self.stopped_announce_grace_secs)
+    }
+
+    // For the next batch of checks, the running ones keep their threads.
+    pub fn hash_check_options(&self) -> HashCheckOptions {
+        HashCheckOptions {
+            workers: self
+                .hashing_threads
+                .unwrap_or_else(hash_check::default_workers),
+            ..Default::default()
+        }
+    }
+
+    pub fn disk_options(&self) -> DiskOptions {
+        DiskOptions {
+            cache_mode: self.disk_cache,
+            io_workers: self.disk_io_workers,
+            write_retry: WriteRetryPolicy {
+                retries: self.write_retries,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    // The disk is restarted with `Disk::restart` to apply the profile, the rest of the engine
+    // keeps running.
+    pub fn restarts_disk(&self, previous: &Profile) -> bool {
+        self.disk_cache != previous.disk_cache
+            || self.disk_io_workers != previous.disk_io_workers
+            || self.write_retries != previous.write_retries
+    }
+
+    // The settings read once when the app starts, e.g. the rate limits, the proxy, DHT, PEX,
+    // uTP and the bind address. The rest is applied while running, see `Engine::apply_profile`.
+    pub fn needs_restart(&self, previous: &Profile) -> bool {
+        let read_on_start = |profile: &Profile| Profile {
+            name: String::new(),
+            disk_cache: CacheMode::default(),
+            write_retries: 0,
+            disk_io_workers: None,
+            hashing_threads: None,
+            stopped_announce_grace_secs: 0,
+            ..profile.clone()
+        };
+        read_on_start(self) != read_on_start(previous)
+    }
+}
+
+pub struct Profiles {
+    profiles: Vec<Profile>,
+    active: watch::Sender<Profile>,
+}
+
+impl Profiles {
+    pub fn new() -> Self {
+        let profile = Profile::new(DEFAULT_PROFILE);
+        Self {
+            profiles: vec![profile.clone()],
+            active: watch::Sender::new(profile),
+        }
+    }
+
+    // Load the profiles from the file, start with the default profile if the file is not exists yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(ProfileError::Io(e)),
+        };
+        let raw: raw::Profiles = serde_bencode::from_bytes(&bytes)?;
+        let profiles: Vec<Profile> = raw.profiles.into_iter().map(Profile::from).collect();
+        let Some(active) = profiles
+            .iter()
+            .find(|it| it.name == raw.active)
+            .or(profiles.first())
+            .cloned()
+        else {
+            return Ok(Self::new());
+        };
+        Ok(Self {
+            profiles,
+            active: watch::Sender::new(active),
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let raw = raw::Profiles {
+            active: self.active.borrow().name.clone(),
+            profiles: self.profiles.iter().map(raw::Profile::from).collect(),
+        };
+        let bytes = serde_bencode::to_bytes(&raw)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, bytes)?;
+        std::fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn list(&self) -> &[Profile] {
+        &self.profiles
+    }
+
+    pub fn active(&self) -> Profile {
+        self.active.borrow().clone()
+    }
+
+    // Receive the active profile, and the next one whenever it is switched or edited.
+    pub fn subscribe(&self) -> watch::Receiver<Profile> {
+        self.active.subscribe()
+    }
+
+    // Add the profile, or replace the one with the same name. Editing the active profile
+    // applies the change right away.
+    pub fn save_profile(&mut self, profile: Profile) {
+        if self.active.borrow().name == profile.name {
+            self.active.send_replace(profile.clone());
+        }
+        match self.profiles.iter_mut().find(|it| it.name == profile.name) {
+            Some(existing) => *existing = profile,
+            None => self.profiles.push(profile),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        if self.active.borrow().name == name {
+            return Err(ProfileError::RemoveActive);
+        }
+        let count = self.profiles.len();
+        self.profiles.retain(|it| it.name != name);
+        if self.profiles.len() == count {
+            return Err(ProfileError::NotFound(name.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn switch(&mut self, name: &str) -> Result<()> {
+        let profile = self
+            .profiles
+            .iter()
+            .find(|it| it.name == name)
+            .ok_or_else(|| ProfileError::NotFound(name.to_string()))?;
+        self.active.send_replace(profile.clone());
+        Ok(())
+    }
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+mod raw {
+    use serde::{Deserialize, Serialize};
+
+    use super::{
+        CacheMode, Credentials, DEFAULT_LISTEN_PORT, DEFAULT_STOPPED_GRACE, EncryptionPolicy,
+        TrackerTls, WriteRetryPolicy,
+    };
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Profiles {
+        pub active: String,
+        pub profiles: Vec<Profile>,
+    }
+
+    // Bencode has no booleans nor null, the flags are integers and the unset values are left out.
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Profile {
+        pub name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub download_limit: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub upload_limit: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub bind_addr: Option<String>,
+        #[serde(
+            default,
+            rename = "bind interface",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub bind_interface: Option<String>,
+        #[serde(default, rename = "listen port")]
+        pub listen_port: Option<u16>,
+        #[serde(default, rename = "randomize listen port")]
+        pub randomize_listen_port: u8,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub proxy: Option<String>,
+        pub dht: u8,
+        pub pex: u8,
+        pub utp: u8,
+        pub encryption: String,
+        #[serde(default, rename = "tracker tls")]
+        pub tracker_tls: Vec<Tls>,
+        // Left out for write-through.
+        #[serde(
+            default,
+            rename = "write back",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub write_back: Option<WriteBack>,
+        #[serde(
+            default,
+            rename = "write retries",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub write_retries: Option<u32>,
+        #[serde(
+            default,
+            rename = "stopped announce grace",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub stopped_announce_grace: Option<u64>,
+        #[serde(
+            default,
+            rename = "hashing threads",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub hashing_threads: Option<u64>,
+        #[serde(
+            default,
+            rename = "disk io workers",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub disk_io_workers: Option<u64>,
+        #[serde(
+            default,
+            rename = "web api port",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub web_api_port: Option<u16>,
+        #[serde(
+            default,
+            rename = "web api credentials",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub web_api_credentials: Option<WebApiCredentials>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct WebApiCredentials {
+        pub username: String,
+        pub password: String,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct WriteBack {
+        #[serde(rename = "max dirty bytes")]
+        pub max_dirty_bytes: u64,
+        #[serde(rename = "flush interval")]
+        pub flush_interval_secs: u64,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Tls {
+        pub host: String,
+        #[serde(rename = "root certificates")]
+        pub root_certificates: Vec<String>,
+        #[serde(rename = "accept invalid certs")]
+        pub accept_invalid_certs: u8,
+    }
+
+    impl From<&super::Profile> for Profile {
+        fn from(profile: &super::Profile) -> Self {
+            let encryption = match profile.encryption {
+                EncryptionPolicy::Require => "require",
+                EncryptionPolicy::Prefer => "prefer",
+                EncryptionPolicy::Allow => "allow",
+            };
+            Self {
+                name: profile.name.clone(),
+                download_limit: profile.download_limit,
+                upload_limit: profile.upload_limit,
+                bind_addr: profile.bind_addr.map(|it| it.to_string()),
+                bind_interface: profile.bind_interface.clone(),
+                listen_port: Some(profile.listen_port),
+                randomize_listen_port: profile.randomize_listen_port as u8,
+                proxy: profile.proxy.clone(),
+                dht: profile.dht as u8,
+                pex: profile.pex as u8,
+                utp: profile.utp as u8,
+                encryption: encryption.to_string(),
+                tracker_tls: profile
+                    .tracker_tls
+                    .iter()
+                    .map(|it| Tls {
+                        host: it.host.clone(),
+                        root_certificates: it.root_certificates.clone(),
+                        accept_invalid_certs: it.accept_invalid_certs as u8,
+                    })
+                    .collect(),
+                write_back: match profile.disk_cache {
+                    CacheMode::WriteThrough => None,
+                    CacheMode::WriteBack {
+                        max_dirty_bytes,
+                        flush_interval_secs,
+                    } => Some(WriteBack {
+                        max_dirty_bytes,
+                        flush_interval_secs,
+                    }),
+                },
+                write_retries: Some(profile.write_retries),
+                stopped_announce_grace: Some(profile.stopped_announce_grace_secs),
+                hashing_threads: profile.hashing_threads.map(|it| it as u64),
+                disk_io_workers: profile.disk_io_workers.map(|it| it as u64),
+                web_api_port: profile.web_api_port,
+                web_api_credentials: profile.web_api_credentials.as_ref().map(|it| {
+                    WebApiCredentials {
+                        username: it.username.clone(),
+                        password: it.password.clone(),
+                    }
+                }),
+            }
+        }
+    }
+
+    // The values we don't understand fall back to the defaults.
+    impl From<Profile> for super::Profile {
+        fn from(profile: Profile) -> Self {
+            let encryption = match profile.encryption.as_str() {
+                "require" => EncryptionPolicy::Require,
+                "prefer" => EncryptionPolicy::Prefer,
+                _ => EncryptionPolicy::Allow,
+            };
+            Self {
+                name: profile.name,
+                download_limit: profile.download_limit,
+                upload_limit: profile.upload_limit,
+                bind_addr: profile.bind_addr.and_then(|it| it.parse().ok()),
+                bind_interface: profile.bind_interface,
+                listen_port: profile.listen_port.unwrap_or(DEFAULT_LISTEN_PORT),
+                randomize_listen_port: profile.randomize_listen_port != 0,
+                proxy: profile.proxy,
+                dht: profile.dht != 0,
+                pex: profile.pex != 0,
+                utp: profile.utp != 0,
+                encryption,
+                tracker_tls: profile
+                    .tracker_tls
+                    .into_iter()
+                    .map(|it| TrackerTls {
+                        host: it.host,
+                        root_certificates: it.root_certificates,
+                        accept_invalid_certs: it.accept_invalid_certs != 0,
+                    })
+                    .collect(),
+                disk_cache: profile.write_back.map_or(CacheMode::WriteThrough, |it| {
+                    CacheMode::WriteBack {
+                        max_dirty_bytes: it.max_dirty_bytes,
+                        flush_interval_secs: it.flush_interval_secs,
+                    }
+                }),
+                write_retries: profile
+                    .write_retries
+                    .unwrap_or(WriteRetryPolicy::default().retries),
+                stopped_announce_grace_secs: profile
+                    .stopped_announce_grace
+                    .unwrap_or(DEFAULT_STOPPED_GRACE.as_secs()),
+                // Zero threads would never check anything
+                hashing_threads: profile
+                    .hashing_threads
+                    .filter(|it| *it > 0)
+                    .map(|it| it as usize),
+                disk_io_workers: profile
+                    .disk_io_workers
+                    .filter(|it| *it > 0)
+                    .map(|it| it as usize),
+                web_api_port: profile.web_api_port,
+                web_api_credentials: profile.web_api_credentials.map(|it| Credentials {
+                    username: it.username,
+                    password: it.password,
+                }),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metered() -> Profile {
+        Profile {
+            download_limit: Some(100 * 1024),
+            upload_limit: Some(10 * 1024),
+            dht: false,
+            ..Profile::new("Metered")
+        }
+    }
+
+    #[test]
+    fn test_switch_profile() {
+        let mut profiles = Profiles::new();
+        let mut subscriber = profiles.subscribe();
+        profiles.save_profile(metered());
+        assert!(!subscriber.has_changed().unwrap());
+
+        profiles.switch("Metered").unwrap();
+        assert!(subscriber.has_changed().unwrap());
+        assert_eq!(*subscriber.borrow_and_update(), metered());
+
+        // Editing the active profile applies it
+        let mut profile = metered();
+        profile.upload_limit = None;
+        profiles.save_profile(profile.clone());
+        assert_eq!(*subscriber.borrow_and_update(), profile);
+
+        // Unlike the disk settings, the limits are only read on the next start
+        assert!(metered().needs_restart(&Profile::new(DEFAULT_PROFILE)));
+        let write_back = Profile {
+            disk_cache: CacheMode::write_back(),
+            stopped_announce_grace_secs: 10,
+            ..metered()
+        };
+        assert!(!write_back.needs_restart(&metered()));
+
+        assert!(matches!(
+            profiles.switch("VPN"),
+            Err(ProfileError::NotFound(_))
+        ));
+        assert!(matches!(
+            profiles.remove("Metered"),
+            Err(ProfileError::RemoveActive)
+        ));
+        profiles.remove(DEFAULT_PROFILE).unwrap();
+        assert_eq!(profiles.list().len(), 1);
+    }
+
+    #[test]
+    fn test_save_and_load_profiles() {
+        let path = Path::new("test_profiles/profiles.dat");
+        let mut profiles = Profiles::new();
+        let vpn = Profile {
+            bind_addr: Some("10.8.0.2".parse().unwrap()),
+            bind_interface: Some("tun0".to_string()),
+            stopped_announce_grace_secs: 10,
+            listen_port: 51413,
+            hashing_threads: Some(2),
+            proxy: Some("socks5://127.0.0.1:1080".to_string()),
+            encryption: EncryptionPolicy::Require,
+            disk_cache: CacheMode::write_back(),
+            write_retries: 0,
+            web_api_port: Some(8080),
+            web_api_credentials: Some(Credentials {
+                username: "admin".to_string(),
+                password: "secret".to_string(),
+            }),
+            tracker_tls: vec![TrackerTls {
+                host: "private.example".to_string(),
+                root_certificates: Vec::new(),
+                accept_invalid_certs: true,
+            }],
+            ..Profile::new("VPN")
+        };
+        profiles.save_profile(vpn.clone());
+        profiles.save_profile(metered());
+        profiles.switch("VPN").unwrap();
+        profiles.save(path).unwrap();
+
+        let loaded = Profiles::load(path).unwrap();
+        assert_eq!(loaded.list(), profiles.list());
+        assert_eq!(loaded.active(), vpn);
+        // Reachable from the other hosts only behind the login
+        assert_eq!(vpn.web_api_addr(), Some("0.0.0.0:8080".parse().unwrap()));
+        assert_eq!(metered().web_api_addr(), None);
+        assert_eq!(vpn.dht_addr(), Some("10.8.0.2:0".parse().unwrap()));
+        assert_eq!(metered().dht_addr(), None);
+
+        let _ = std::fs::remove_dir_all("test_profiles");
+    }
+
+    #[test]
+    fn test_thread_pool_sizes() {
+        let default = Profile::new("Default");
+        assert!(default.hash_check_options().workers >= 1);
+        assert!(default.disk_options().io_workers() >= 1);
+
+        let profile = Profile {
+            hashing_threads: Some(1),
+            disk_io_workers: Some(16),
+            ..default.clone()
+        };
+        assert_eq!(profile.hash_check_options().workers, 1);
+        assert_eq!(profile.disk_options().io_workers(), 16);
+        assert!(profile.restarts_disk(&default));
+        let profile = Profile {
+            write_retries: 0,
+            ..default.clone()
+        };
+        assert_eq!(profile.disk_options().write_retry.retries, 0);
+        assert!(profile.restarts_disk(&default));
+        // The hash checks pick it up on their own
+        let profile = Profile {
+            hashing_threads: Some(1),
+            ..default.clone()
+        };
+        assert!(!profile.restarts_disk(&default));
+    }
+
+    #[test]
+    fn test_pick_listen_port() {
+        let mut profile = Profile::new("Random");
+        assert_eq!(profile.pick_listen_port(), DEFAULT_LISTEN_PORT);
+        profile.randomize_listen_port = true;
+        assert!(RANDOM_LISTEN_PORTS.contains(&profile.pick_listen_port()));
+    }
+}
@@ -0,0 +1,741 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Json, Router,
+    extract::{Form, Multipart, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use thiserror::Error;
+use tokio::net::TcpListener;
+
+use crate::{
+    engine::EngineError,
+    magnet::{MagnetError, decode_hex},
+    metainfo::MetaInfoError,
+    types::Sha1Hash,
+};
+
+// The subset of the qBittorrent WebUI API which Sonarr, Radarr etc. use for their download
+// clients, so they can drive us as if we were qBittorrent.
+// https://github.com/qbittorrent/qBittorrent/wiki/WebUI-API-(qBittorrent-4.1)
+
+pub const WEB_API_VERSION: &str = "2.8.3";
+// The snapshots kept for the incremental sync/maindata, older rids get a full update.
+const MAX_SNAPSHOTS: usize = 8;
+// A session not used for this long has to log in again, the default of qBittorrent.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Error)]
+pub enum AddError {
+    #[error("Failed to parse the torrent file")]
+    MetaInfo(#[from] MetaInfoError),
+
+    #[error("Failed to parse the magnet link")]
+    Magnet(#[from] MagnetError),
+
+    #[error("Failed to download the torrent file")]
+    Download(#[from] reqwest::Error),
+
+    #[error("Failed to add the torrent")]
+    Engine(#[from] EngineError),
+}
+
+// The states as qBittorrent names them, the clients map them to their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TorrentState {
+    Error,
+    MissingFiles,
+    Uploading,
+    PausedUP,
+    QueuedUP,
+    StalledUP,
+    CheckingUP,
+    Downloading,
+    MetaDL,
+    PausedDL,
+    QueuedDL,
+    StalledDL,
+    CheckingDL,
+    Moving,
+}
+
+impl TorrentState {
+    fn is_paused(&self) -> bool {
+        matches!(self, TorrentState::PausedUP | TorrentState::PausedDL)
+    }
+
+    fn is_complete(&self) -> bool {
+        matches!(
+            self,
+            TorrentState::Uploading
+                | TorrentState::PausedUP
+                | TorrentState::QueuedUP
+                | TorrentState::StalledUP
+                | TorrentState::CheckingUP
+        )
+    }
+}
+
+// The fields of torrents/info, named like qBittorrent does. The ones we don't track, e.g. the
+// completion time and the connected seeds, are left out rather than made up.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TorrentInfo {
+    #[serde(serialize_with = "serialize_hash")]
+    pub hash: Sha1Hash,
+    pub name: String,
+    pub size: u64,
+    // 0 to 1.
+    pub progress: f64,
+    // Bytes per second.
+    pub dlspeed: u64,
+    pub upspeed: u64,
+    pub state: TorrentState,
+    pub category: String,
+    pub save_path: String,
+    pub content_path: String,
+    pub added_on: i64,
+    // Seconds, 8640000 is infinity in qBittorrent.
+    pub eta: i64,
+    pub ratio: f64,
+    pub amount_left: u64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ServerState {
+    pub dl_info_speed: u64,
+    pub up_info_speed: u64,
+    pub dl_info_data: u64,
+    pub up_info_data: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AddSource {
+    // A magnet link or an URL of the .torrent file.
+    Url(String),
+    // The content of the .torrent file.
+    File(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AddOptions {
+    pub save_path: Option<PathBuf>,
+    pub category: Option<String>,
+    pub paused: bool,
+}
+
+// What the API is served from, i.e. the engine. The hashes None means all the torrents.
+pub trait Backend: Send + Sync + 'static {
+    fn torrents(&self) -> impl Future<Output = Vec<TorrentInfo>> + Send;
+    fn server_state(&self) -> impl Future<Output = ServerState> + Send;
+    fn add(
+        &self,
+        source: AddSource,
+        options: &AddOptions,
+    ) -> impl Future<Output = Result<(), AddError>> + Send;
+    fn pause(&self, hashes: Option<&[Sha1Hash]>) -> impl Future<Output = ()> + Send;
+    fn resume(&self, hashes: Option<&[Sha1Hash]>) -> impl Future<Output = ()> + Send;
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+struct ApiState<B> {
+    backend: Arc<B>,
+    // None means no login needed, e.g. the API is only bound to localhost.
+    credentials: Option<Credentials>,
+    // When each SID was last used.
+    sessions: Mutex<HashMap<String, Instant>>,
+    maindata: Mutex<MainData>,
+}
+
+// The torrents as last sent by each rid, to send only what changed since then.
+#[derive(Default)]
+struct MainData {
+    rid: u64,
+    snapshots: VecDeque<(u64, Map<String, Value>)>,
+}
+
+pub fn router<B: Backend>(backend: Arc<B>, credentials: Option<Credentials>) -> Router {
+    let state = Arc::new(ApiState {
+        backend,
+        credentials,
+        sessions: Mutex::new(HashMap::new()),
+        maindata: Mutex::new(MainData::default()),
+    });
+    Router::new()
+        .route("/api/v2/auth/login", post(login))
+        .route("/api/v2/app/webapiVersion", get(web_api_version))
+        .route("/api/v2/torrents/info", get(torrents_info))
+        .route("/api/v2/torrents/add", post(torrents_add))
+        // qBittorrent 5 renamed them to stop and start
+        .route("/api/v2/torrents/pause", post(torrents_pause))
+        .route("/api/v2/torrents/stop", post(torrents_pause))
+        .route("/api/v2/torrents/resume", post(torrents_resume))
+        .route("/api/v2/torrents/start", post(torrents_resume))
+        .route("/api/v2/sync/maindata", get(sync_maindata))
+        .with_state(state)
+}
+
+pub async fn serve<B: Backend>(
+    listener: TcpListener,
+    backend: Arc<B>,
+    credentials: Option<Credentials>,
+) -> io::Result<()> {
+    axum::serve(listener, router(backend, credentials)).await
+}
+
+type Params = HashMap<String, String>;
+
+impl<B: Backend> ApiState<B> {
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), StatusCode> {
+        if self.credentials.is_none() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        expire_sessions(&mut sessions, now);
+        let sid = headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|it| it.to_str().ok())
+            .flat_map(|it| it.split(';'))
+            .filter_map(|it| it.trim().strip_prefix("SID="))
+            .find(|sid| sessions.contains_key(*sid))
+            .ok_or(StatusCode::FORBIDDEN)?;
+        sessions.insert(sid.to_string(), now);
+        Ok(())
+    }
+}
+
+fn expire_sessions(sessions: &mut HashMap<String, Instant>, now: Instant) {
+    sessions.retain(|_, last_used| now.saturating_duration_since(*last_used) < SESSION_TIMEOUT);
+}
+
+// Looks at every byte whatever the first mismatch, so the response time doesn't tell how
+// much of the password was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+async fn login<B: Backend>(
+    State(state): State<Arc<ApiState<B>>>,
+    Form(params): Form<Params>,
+) -> Response {
+    if let Some(credentials) = &state.credentials {
+        let field = |name: &str| params.get(name).map_or(&[][..], |it| it.as_bytes());
+        // Not short circuited either
+        let matches = constant_time_eq(field("username"), credentials.username.as_bytes())
+            & constant_time_eq(field("password"), credentials.password.as_bytes());
+        if !matches {
+            return "Fails.".into_response();
+        }
+    }
+    let sid: String = (0..32)
+        .map(|_| format!("{:x}", rand::random::<u8>() & 0xf))
+        .collect();
+    let now = Instant::now();
+    let mut sessions = state.sessions.lock().unwrap();
+    expire_sessions(&mut sessions, now);
+    sessions.insert(sid.clone(), now);
+    drop(sessions);
+    (
+        [(header::SET_COOKIE, format!("SID={}; HttpOnly; path=/", sid))],
+        "Ok.",
+    )
+        .into_response()
+}
+
+async fn web_api_version() -> &'static str {
+    WEB_API_VERSION
+}
+
+async fn torrents_info<B: Backend>(
+    State(state): State<Arc<ApiState<B>>>,
+    headers: HeaderMap,
+    Query(params): Query<Params>,
+) -> Result<Json<Vec<TorrentInfo>>, StatusCode> {
+    state.authorize(&headers)?;
+    let hashes = params
+        .get("hashes")
+        .map(|it| parse_hashes(it))
+        .transpose()?
+        .flatten();
+    let torrents = state
+        .backend
+        .torrents()
+        .await
+        .into_iter()
+        .filter(|it| {
+            hashes
+                .as_ref()
+                .is_none_or(|hashes| hashes.contains(&it.hash))
+        })
+        .filter(|it| params.get("category").is_none_or(|c| it.category == *c))
+        .filter(|it| matches_filter(it, params.get("filter").map_or("all", |it| it.as_str())))
+        .collect();
+    Ok(Json(torrents))
+}
+
+async fn torrents_add<B: Backend>(
+    State(state): State<Arc<ApiState<B>>>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<&'static str, StatusCode> {
+    state.authorize(&headers)?;
+    let mut sources = Vec::new();
+    let mut options = AddOptions::default();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "torrents" {
+            let bytes = field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+            sources.push(AddSource::File(bytes.to_vec()));
+            continue;
+        }
+        let text = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+        match name.as_str() {
+            "urls" => sources.extend(
+                text.lines()
+                    .map(str::trim)
+                    .filter(|it| !it.is_empty())
+                    .map(|it| AddSource::Url(it.to_string())),
+            ),
+            "savepath" if !text.is_empty() => options.save_path = Some(PathBuf::from(text)),
+            "category" if !text.is_empty() => options.category = Some(text),
+            "paused" | "stopped" => options.paused = text == "true",
+            _ => {}
+        }
+    }
+    if sources.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let mut added = false;
+    for source in sources {
+        match state.backend.add(source, &options).await {
+            Ok(()) => added = true,
+            Err(e) => log::warn!("Failed to add torrent from the WebUI API: {}", e),
+        }
+    }
+    // qBittorrent answers "Fails." only when none of them could be added
+    Ok(if added { "Ok." } else { "Fails." })
+}
+
+async fn torrents_pause<B: Backend>(
+    State(state): State<Arc<ApiState<B>>>,
+    headers: HeaderMap,
+    Form(params): Form<Params>,
+) -> Result<(), StatusCode> {
+    state.authorize(&headers)?;
+    let hashes = parse_hashes(params.get("hashes").ok_or(StatusCode::BAD_REQUEST)?)?;
+    state.backend.pause(hashes.as_deref()).await;
+    Ok(())
+}
+
+async fn torrents_resume<B: Backend>(
+    State(state): State<Arc<ApiState<B>>>,
+    headers: HeaderMap,
+    Form(params): Form<Params>,
+) -> Result<(), StatusCode> {
+    state.authorize(&headers)?;
+    let hashes = parse_hashes(params.get("hashes").ok_or(StatusCode::BAD_REQUEST)?)?;
+    state.backend.resume(hashes.as_deref()).await;
+    Ok(())
+}
+
+async fn sync_maindata<B: Backend>(
+    State(state): State<Arc<ApiState<B>>>,
+    headers: HeaderMap,
+    Query(params): Query<Params>,
+) -> Result<Json<Value>, StatusCode> {
+    state.authorize(&headers)?;
+    let rid = params
+        .get("rid")
+        .map(|it| it.parse::<u64>().map_err(|_| StatusCode::BAD_REQUEST))
+        .transpose()?
+        .unwrap_or(0);
+    let torrents: Map<String, Value> = state
+        .backend
+        .torrents()
+        .await
+        .into_iter()
+        .map(|it| {
+            let value = serde_json::to_value(&it).unwrap_or_default();
+            (hex(&it.hash), value)
+        })
+        .collect();
+    let server_state = serde_json::to_value(state.backend.server_state().await).unwrap_or_default();
+    Ok(Json(state.maindata.lock().unwrap().update(
+        rid,
+        torrents,
+        server_state,
+    )))
+}
+
+impl MainData {
+    fn update(&mut self, rid: u64, torrents: Map<String, Value>, server_state: Value) -> Value {
+        let previous = self
+            .snapshots
+            .iter()
+            .find(|(it, _)| rid != 0 && *it == rid)
+            .map(|(_, snapshot)| snapshot);
+        let mut response = Map::new();
+        match previous {
+            Some(previous) => {
+                let changed: Map<String, Value> = torrents
+                    .iter()
+                    .filter_map(|(hash, torrent)| {
+                        let diff = match previous.get(hash) {
+                            Some(old) => diff(old, torrent)?,
+                            None => torrent.clone(),
+                        };
+                        Some((hash.clone(), diff))
+                    })
+                    .collect();
+                let removed: Vec<String> = previous
+                    .keys()
+                    .filter(|it| !torrents.contains_key(*it))
+                    .cloned()
+                    .collect();
+                response.insert("full_update".into(), false.into());
+                if !changed.is_empty() {
+                    response.insert("torrents".into(), changed.into());
+                }
+                if !removed.is_empty() {
+                    response.insert("torrents_removed".into(), removed.into());
+                }
+            }
+            None => {
+                response.insert("full_update".into(), true.into());
+                response.insert("torrents".into(), torrents.clone().into());
+            }
+        }
+        response.insert("server_state".into(), server_state);
+
+        self.rid += 1;
+        response.insert("rid".into(), self.rid.into());
+        self.snapshots.push_back((self.rid, torrents));
+        if self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+        Value::Object(response)
+    }
+}
+
+// The fields which changed, None if nothing did.
+fn diff(old: &Value, new: &Value) -> Option<Value> {
+    let (Value::Object(old), Value::Object(new)) = (old, new) else {
+        return (old != new).then(|| new.clone());
+    };
+    let changed: Map<String, Value> = new
+        .iter()
+        .filter(|(key, value)| old.get(*key) != Some(value))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    (!changed.is_empty()).then_some(Value::Object(changed))
+}
+
+fn matches_filter(torrent: &TorrentInfo, filter: &str) -> bool {
+    let state = torrent.state;
+    match filter {
+        "downloading" => !state.is_complete() && state != TorrentState::Error,
+        "seeding" => matches!(state, TorrentState::Uploading | TorrentState::StalledUP),
+        "completed" => state.is_complete(),
+        "paused" | "stopped" => state.is_paused(),
+        "resumed" | "running" => !state.is_paused(),
+        "active" => torrent.dlspeed > 0 || torrent.upspeed > 0,
+        "inactive" => torrent.dlspeed == 0 && torrent.upspeed == 0,
+        "stalled" => matches!(state, TorrentState::StalledUP | TorrentState::StalledDL),
+        "errored" => matches!(state, TorrentState::Error | TorrentState::MissingFiles),
+        _ => true,
+    }
+}
+
+// The hashes separated by |, or "all".
+fn parse_hashes(value: &str) -> Result<Option<Vec<Sha1Hash>>, StatusCode> {
+    if value == "all" {
+        return Ok(None);
+    }
+    value
+        .split('|')
+        .map(|it| {
+            decode_hex(it)
+                .and_then(|it| Sha1Hash::try_from(it).ok())
+                .ok_or(StatusCode::BAD_REQUEST)
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+fn hex(hash: &Sha1Hash) -> String {
+    hash.iter().map(|it| format!("{:02x}", it)).collect()
+}
+
+fn serialize_hash<S: serde::Serializer>(hash: &Sha1Hash, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex(hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Client;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MockBackend {
+        torrents: Mutex<Vec<TorrentInfo>>,
+        added: Mutex<Vec<(AddSource, AddOptions)>>,
+    }
+
+    impl Backend for MockBackend {
+        async fn torrents(&self) -> Vec<TorrentInfo> {
+            self.torrents.lock().unwrap().clone()
+        }
+
+        async fn server_state(&self) -> ServerState {
+            ServerState::default()
+        }
+
+        async fn add(&self, source: AddSource, options: &AddOptions) -> Result<(), AddError> {
+            self.added.lock().unwrap().push((source, options.clone()));
+            Ok(())
+        }
+
+        async fn pause(&self, hashes: Option<&[Sha1Hash]>) {
+            for torrent in self.torrents.lock().unwrap().iter_mut() {
+                if hashes.is_none_or(|it| it.contains(&torrent.hash)) {
+                    torrent.state = TorrentState::PausedDL;
+                }
+            }
+        }
+
+        async fn resume(&self, hashes: Option<&[Sha1Hash]>) {
+            for torrent in self.torrents.lock().unwrap().iter_mut() {
+                if hashes.is_none_or(|it| it.contains(&torrent.hash)) {
+                    torrent.state = TorrentState::Downloading;
+                }
+            }
+        }
+    }
+
+    fn torrent(id: u8, state: TorrentState) -> TorrentInfo {
+        TorrentInfo {
+            hash: [id; 20],
+            name: format!("torrent {}", id),
+            size: 100,
+            progress: 0.5,
+            dlspeed: 0,
+            upspeed: 0,
+            state,
+            category: "tv".to_string(),
+            save_path: "/downloads".to_string(),
+            content_path: format!("/downloads/torrent {}", id),
+            added_on: 0,
+            eta: 8640000,
+            ratio: 0.0,
+            amount_left: 50,
+            downloaded: 50,
+            uploaded: 0,
+        }
+    }
+
+    async fn start(backend: Arc<MockBackend>, credentials: Option<Credentials>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, backend, credentials));
+        format!("http://{}/api/v2", addr)
+    }
+
+    #[tokio::test]
+    async fn test_login_and_info() {
+        let backend = Arc::new(MockBackend::default());
+        backend.torrents.lock().unwrap().extend([
+            torrent(1, TorrentState::Downloading),
+            torrent(2, TorrentState::PausedUP),
+        ]);
+        let credentials = Credentials {
+            username: "admin".to_string(),
+            password: "secret".to_string(),
+        };
+        let api = start(backend, Some(credentials)).await;
+        let client = Client::new();
+
+        let response = client
+            .get(format!("{}/torrents/info", api))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let login = |password: &'static str| {
+            client
+                .post(format!("{}/auth/login", api))
+                .form(&[("username", "admin"), ("password", password)])
+                .send()
+        };
+        assert_eq!(
+            login("wrong").await.unwrap().text().await.unwrap(),
+            "Fails."
+        );
+        let response = login("secret").await.unwrap();
+        let cookie = response.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_string();
+        assert_eq!(response.text().await.unwrap(), "Ok.");
+
+        let info = client
+            .get(format!("{}/torrents/info?filter=paused", api))
+            .header(header::COOKIE, &cookie)
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let info: Vec<Value> = serde_json::from_str(&info).unwrap();
+        assert_eq!(info.len(), 1);
+        assert_eq!(info[0]["hash"], hex(&[2; 20]));
+        assert_eq!(info[0]["state"], "pausedUP");
+    }
+
+    #[test]
+    fn test_session_timeout() {
+        let state = ApiState {
+            backend: Arc::new(MockBackend::default()),
+            credentials: Some(Credentials {
+                username: "admin".to_string(),
+                password: "secret".to_string(),
+            }),
+            sessions: Mutex::new(HashMap::new()),
+            maindata: Mutex::new(MainData::default()),
+        };
+        let now = Instant::now();
+        let Some(idle) = now.checked_sub(SESSION_TIMEOUT + Duration::from_secs(1)) else {
+            return;
+        };
+        state
+            .sessions
+            .lock()
+            .unwrap()
+            .extend([("active".to_string(), now), ("idle".to_string(), idle)]);
+        let cookie = |sid: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::COOKIE, format!("SID={}", sid).parse().unwrap());
+            headers
+        };
+        assert_eq!(state.authorize(&cookie("active")), Ok(()));
+        assert_eq!(state.authorize(&cookie("idle")), Err(StatusCode::FORBIDDEN));
+        assert!(!state.sessions.lock().unwrap().contains_key("idle"));
+
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+    }
+
+    #[tokio::test]
+    async fn test_add_pause_and_maindata() {
+        let backend = Arc::new(MockBackend::default());
+        backend.torrents.lock().unwrap().extend([
+            torrent(1, TorrentState::Downloading),
+            torrent(2, TorrentState::Downloading),
+        ]);
+        let api = start(backend.clone(), None).await;
+        let client = Client::new();
+
+        let body = "--boundary\r\n\
+            Content-Disposition: form-data; name=\"urls\"\r\n\r\n\
+            magnet:?xt=urn:btih:0101010101010101010101010101010101010101\r\n\
+            --boundary\r\n\
+            Content-Disposition: form-data; name=\"category\"\r\n\r\n\
+            tv\r\n\
+            --boundary\r\n\
+            Content-Disposition: form-data; name=\"paused\"\r\n\r\n\
+            true\r\n\
+            --boundary--\r\n";
+        let response = client
+            .post(format!("{}/torrents/add", api))
+            .header(
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=boundary",
+            )
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "Ok.");
+        assert_eq!(
+            *backend.added.lock().unwrap(),
+            vec![(
+                AddSource::Url(
+                    "magnet:?xt=urn:btih:0101010101010101010101010101010101010101".to_string()
+                ),
+                AddOptions {
+                    save_path: None,
+                    category: Some("tv".to_string()),
+                    paused: true,
+                }
+            )]
+        );
+
+        let maindata = |rid: u64| {
+            let client = client.clone();
+            let api = api.clone();
+            async move {
+                let body = client
+                    .get(format!("{}/sync/maindata?rid={}", api, rid))
+                    .send()
+                    .await
+                    .unwrap()
+                    .text()
+                    .await
+                    .unwrap();
+                serde_json::from_str::<Value>(&body).unwrap()
+            }
+        };
+        let full = maindata(0).await;
+        assert_eq!(full["full_update"], true);
+        assert_eq!(full["torrents"].as_object().unwrap().len(), 2);
+        let rid = full["rid"].as_u64().unwrap();
+
+        client
+            .post(format!("{}/torrents/pause", api))
+            .form(&[("hashes", hex(&[1; 20]))])
+            .send()
+            .await
+            .unwrap();
+        backend.torrents.lock().unwrap().remove(1);
+        let partial = maindata(rid).await;
+        assert_eq!(partial["full_update"], false);
+        assert_eq!(
+            partial["torrents"],
+            serde_json::json!({ hex(&[1; 20]): { "state": "pausedDL" } })
+        );
+        assert_eq!(
+            partial["torrents_removed"],
+            serde_json::json!([hex(&[2; 20])])
+        );
+    }
+}
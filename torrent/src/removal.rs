@@ -0,0 +1,198 @@
+use std::{
+    future::Future,
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+
+use crate::types::Sha1Hash;
+
+// Clean up the finished torrents automatically, e.g. remove what reached ratio 2 and seeded
+// for a week. The session evaluates the rules periodically, `preview` shows the user what a
+// rule would remove before it is enabled.
+
+pub const DEFAULT_EVALUATE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// A torrent is removed when it meets every criterion of the rule.
+#[derive(Debug, Clone, Default)]
+pub struct RemovalRule {
+    pub name: String,
+    pub min_ratio: Option<f64>,
+    pub min_seeding_time: Option<Duration>,
+    // Since the torrent was added.
+    pub min_age: Option<Duration>,
+    // Delete the downloaded files too, not only the torrent.
+    pub delete_data: bool,
+}
+
+// What the rules look at of a torrent.
+#[derive(Debug, Clone)]
+pub struct TorrentUsage {
+    pub info_hash: Sha1Hash,
+    // Uploaded / downloaded.
+    pub ratio: f64,
+    pub seeding_time: Duration,
+    pub added_at: SystemTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Removal {
+    pub info_hash: Sha1Hash,
+    // The rule which matched the torrent.
+    pub rule: String,
+    pub delete_data: bool,
+}
+
+impl RemovalRule {
+    pub fn matches(&self, torrent: &TorrentUsage, now: SystemTime) -> bool {
+        // A rule without criteria would remove everything, it is most likely not configured yet
+        if self.min_ratio.is_none() && self.min_seeding_time.is_none() && self.min_age.is_none() {
+            return false;
+        }
+        let age = now.duration_since(torrent.added_at).unwrap_or_default();
+        self.min_ratio.is_none_or(|it| torrent.ratio >= it)
+            && self
+                .min_seeding_time
+                .is_none_or(|it| torrent.seeding_time >= it)
+            && self.min_age.is_none_or(|it| age >= it)
+    }
+}
+
+// The torrents which the rules would remove, each by the first rule it matches.
+// Nothing is removed, so this is also the dry run.
+pub fn preview(rules: &[RemovalRule], torrents: &[TorrentUsage], now: SystemTime) -> Vec<Removal> {
+    torrents
+        .iter()
+        .filter_map(|torrent| {
+            let rule = rules.iter().find(|rule| rule.matches(torrent, now))?;
+            Some(Removal {
+                info_hash: torrent.info_hash,
+                rule: rule.name.clone(),
+                delete_data: rule.delete_data,
+            })
+        })
+        .collect()
+}
+
+// Evaluate the rules every `interval` against the current torrents from `torrents`, and
+// hand what matched to `remove`. Runs until the task is dropped.
+pub async fn run<T, R, Fut>(
+    rules: Vec<RemovalRule>,
+    interval: Duration,
+    mut torrents: T,
+    mut remove: R,
+) where
+    T: FnMut() -> Vec<TorrentUsage>,
+    R: FnMut(Removal) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        for removal in preview(&rules, &torrents(), SystemTime::now()) {
+            log::info!(
+                "Remove torrent {} by rule {:?}",
+                hex(&removal.info_hash),
+                removal.rule
+            );
+            remove(removal).await;
+        }
+    }
+}
+
+fn hex(info_hash: &Sha1Hash) -> String {
+    info_hash.iter().map(|it| format!("{:02x}", it)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn usage(
+        id: u8,
+        ratio: f64,
+        seeding_days: u32,
+        age_days: u32,
+        now: SystemTime,
+    ) -> TorrentUsage {
+        TorrentUsage {
+            info_hash: [id; 20],
+            ratio,
+            seeding_time: DAY * seeding_days,
+            added_at: now - DAY * age_days,
+        }
+    }
+
+    #[test]
+    fn test_preview() {
+        let now = SystemTime::now();
+        let rules = vec![
+            RemovalRule {
+                name: "seeded enough".to_string(),
+                min_ratio: Some(2.0),
+                min_seeding_time: Some(DAY * 7),
+                min_age: Some(DAY * 14),
+                delete_data: false,
+            },
+            RemovalRule {
+                name: "old".to_string(),
+                min_age: Some(DAY * 90),
+                delete_data: true,
+                ..Default::default()
+            },
+            RemovalRule {
+                name: "empty".to_string(),
+                ..Default::default()
+            },
+        ];
+        let torrents = vec![
+            usage(1, 2.5, 10, 20, now),
+            // Ratio not reached
+            usage(2, 1.0, 10, 20, now),
+            // Not seeded long enough
+            usage(3, 3.0, 2, 20, now),
+            usage(4, 0.1, 0, 100, now),
+        ];
+        let removals = preview(&rules, &torrents, now);
+        assert_eq!(
+            removals,
+            vec![
+                Removal {
+                    info_hash: [1; 20],
+                    rule: "seeded enough".to_string(),
+                    delete_data: false,
+                },
+                Removal {
+                    info_hash: [4; 20],
+                    rule: "old".to_string(),
+                    delete_data: true,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let rules = vec![RemovalRule {
+            name: "ratio".to_string(),
+            min_ratio: Some(1.0),
+            ..Default::default()
+        }];
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let handle = tokio::spawn(run(
+            rules,
+            Duration::from_secs(60),
+            || vec![usage(1, 1.5, 0, 0, SystemTime::now())],
+            move |removal| {
+                let tx = tx.clone();
+                async move {
+                    tx.send(removal).unwrap();
+                }
+            },
+        ));
+        assert_eq!(rx.recv().await.unwrap().info_hash, [1; 20]);
+        handle.abort();
+    }
+}
@@ -0,0 +1,169 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::Mutex;
+
+use crate::{
+    disk::Disk,
+    piece::{self, PieceHash},
+    piece_picker::BlockInfo,
+    torrent::{Torrent, TorrentPhase},
+};
+
+// Read back the pieces we seed now and then and check them against their hashes, a long
+// term seed may rot on disk without anyone noticing. A corrupt piece is marked missing
+// so it's downloaded again, instead of being uploaded to the peers which then reject it.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScrubOptions {
+    // Bytes per second read from the disk, kept low so the uploads aren't slowed down.
+    pub rate: u64,
+    // Wait this long before checking again if the torrent isn't seeding.
+    pub idle_delay: Duration,
+}
+
+impl Default for ScrubOptions {
+    fn default() -> Self {
+        Self {
+            rate: 1024 * 1024,
+            idle_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+pub struct Scrubber {
+    torrent: Arc<Mutex<Torrent>>,
+    disk: Arc<Disk>,
+    options: ScrubOptions,
+    // The next piece to check, the pieces are checked round robin.
+    next_piece: u32,
+}
+
+impl Scrubber {
+    pub fn new(torrent: Arc<Mutex<Torrent>>, disk: Arc<Disk>, options: ScrubOptions) -> Self {
+        Self {
+            torrent,
+            disk,
+            options,
+            next_piece: 0,
+        }
+    }
+
+    // Run until the torrent is dropped, spawn it as a background task.
+    pub async fn run(mut self) {
+        loop {
+            let delay = match self.scrub_next().await {
+                Some((_, length)) => {
+                    Duration::from_secs_f64(length as f64 / self.options.rate.max(1) as f64)
+                }
+                None => self.options.idle_delay,
+            };
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    // Check the next piece we have, returns the piece and its length.
+    // None if there is nothing to check, e.g. the torrent isn't seeding.
+    pub async fn scrub_next(&mut self) -> Option<(u32, u32)> {
+        let (metainfo, piece_index, hash) = {
+            let torrent = self.torrent.lock().await;
+            if torrent.phase().await != TorrentPhase::Seeding {
+                return None;
+            }
+            let metainfo = torrent.metainfo()?.clone();
+            let piece_count = metainfo.piece_count() as u32;
+            let mut piece_index = None;
+            for offset in 0..piece_count {
+                let index = (self.next_piece + offset) % piece_count;
+                if torrent.has_piece(index).await {
+                    piece_index = Some(index);
+                    break;
+                }
+            }
+            let piece_index = piece_index?;
+            let hash: PieceHash = piece::piece_hashes(&metainfo)[piece_index as usize];
+            (metainfo, piece_index, hash)
+        };
+        self.next_piece = piece_index + 1;
+
+        let piece_length = metainfo.info.piece_length as usize;
+        let length = piece_length.min(metainfo.total_bytes() - piece_index as usize * piece_length);
+        let block = BlockInfo::new(piece_index, 0, length as u32);
+        let is_valid = match self.disk.read_block(metainfo, block).await {
+            Ok(Ok(data)) => hash.matches(&data),
+            // The file is gone or cut short, that's as bad as corrupt
+            Ok(Err(e)) => {
+                log::warn!("Failed to read piece {} to scrub: {:?}", piece_index, e);
+                false
+            }
+            Err(_) => return None,
+        };
+        if !is_valid {
+            log::warn!(
+                "Piece {} is corrupt on disk, download it again",
+                piece_index
+            );
+            self.torrent
+                .lock()
+                .await
+                .mark_piece_missing(piece_index)
+                .await;
+        }
+        Some((piece_index, length as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        disk::DiskOptions,
+        hash::calculate_sha1_hash,
+        metainfo::{MetaInfo, raw},
+        types::BitField,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_scrub_corrupt_piece() {
+        let data: Vec<u8> = (0..16u8).collect();
+        let mut pieces = calculate_sha1_hash(data[..8].to_vec()).to_vec();
+        pieces.extend_from_slice(&calculate_sha1_hash(data[8..].to_vec()));
+        let metainfo = MetaInfo {
+            announce: None,
+            announce_list: Vec::new(),
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
+            v2: None,
+            info: raw::Info {
+                name: "test_scrub.bin".to_string(),
+                piece_length: 8,
+                length: Some(16),
+                files: None,
+                pieces,
+                extra: std::collections::BTreeMap::new(),
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info_hash: [0u8; 20],
+        };
+        let mut on_disk = data.clone();
+        on_disk[12] ^= 0xff;
+        std::fs::write("test_scrub.bin", &on_disk).unwrap();
+
+        let torrent = Torrent::from_existing_pieces(metainfo, BitField::repeat(true, 2));
+        let torrent = Arc::new(Mutex::new(torrent));
+        let (disk, _events) = Disk::new(DiskOptions::default());
+        let mut scrubber = Scrubber::new(torrent.clone(), Arc::new(disk), ScrubOptions::default());
+
+        assert_eq!(scrubber.scrub_next().await, Some((0, 8)));
+        assert!(torrent.lock().await.has_piece(0).await);
+        assert_eq!(scrubber.scrub_next().await, Some((1, 8)));
+        let torrent = torrent.lock().await;
+        assert!(!torrent.has_piece(1).await);
+        assert_eq!(torrent.phase().await, TorrentPhase::Downloading);
+        assert_eq!(torrent.left_bytes().await, 8);
+
+        let _ = std::fs::remove_file("test_scrub.bin");
+    }
+}
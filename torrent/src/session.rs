@@ -59,6 +59,7 @@ impl Session {
                         piece_index,
                         begin,
                         data: piece,
+                        peer: None,
                     })
                     .await
                 {
@@ -78,6 +79,8 @@ impl Session {
                     !block.is_same_block_as_info(&cancel_block)
                 });
             }
+            Message::Port { .. } => {}
+            Message::Extended { .. } => {}
         }
     }
 }
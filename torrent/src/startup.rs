@@ -0,0 +1,142 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use tokio::{sync::Semaphore, time::timeout};
+
+use crate::torrent::TorrentPriority;
+
+// The startup phase of the session. When the app starts with a large library every torrent
+// wants to announce and dial at once, start them by priority instead, so the content the
+// user wants first starts flowing first.
+
+pub const DEFAULT_MAX_CONCURRENT_STARTS: usize = 8;
+pub const DEFAULT_TIER_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy)]
+pub struct StartupOptions {
+    // How many torrents announce and dial at the same time.
+    pub max_concurrent: usize,
+    // How long the lower priority torrents wait for the higher ones to finish starting,
+    // so a dead tracker doesn't hold back the whole library.
+    pub tier_timeout: Duration,
+}
+
+impl Default for StartupOptions {
+    fn default() -> Self {
+        Self {
+            max_concurrent: DEFAULT_MAX_CONCURRENT_STARTS,
+            tier_timeout: DEFAULT_TIER_TIMEOUT,
+        }
+    }
+}
+
+// Group the torrents by priority, the highest first. The torrents of the same priority
+// keep the order they are given, e.g. the order they were added.
+pub fn startup_tiers<T>(torrents: Vec<(T, TorrentPriority)>) -> Vec<(TorrentPriority, Vec<T>)> {
+    let mut tiers: Vec<(TorrentPriority, Vec<T>)> = Vec::new();
+    let mut torrents = torrents;
+    // Stable, so the order within a priority is kept
+    torrents.sort_by_key(|it| std::cmp::Reverse(it.1));
+    for (torrent, priority) in torrents {
+        match tiers.last_mut() {
+            Some((tier_priority, tier)) if *tier_priority == priority => tier.push(torrent),
+            _ => tiers.push((priority, vec![torrent])),
+        }
+    }
+    tiers
+}
+
+// Run `start` for each torrent, which announces it and dials its first peers.
+// A priority starts once the higher one is done starting, or took longer than the timeout,
+// the slow starts keep running in the background.
+pub async fn start_in_priority_order<T, F, Fut>(
+    torrents: Vec<(T, TorrentPriority)>,
+    options: StartupOptions,
+    mut start: F,
+) where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let slots = Arc::new(Semaphore::new(options.max_concurrent.max(1)));
+    for (priority, tier) in startup_tiers(torrents) {
+        let mut handles = Vec::with_capacity(tier.len());
+        for torrent in tier {
+            // Take the slot before spawning, so the torrents start in order
+            let slot = slots
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("Startup semaphore is never closed");
+            let start = start(torrent);
+            handles.push(tokio::spawn(async move {
+                start.await;
+                drop(slot);
+            }));
+        }
+        let tier_done = futures::future::join_all(handles);
+        if timeout(options.tier_timeout, tier_done).await.is_err() {
+            log::info!(
+                "{:?} priority torrents are slow to start, start the next priority",
+                priority
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn test_startup_tiers() {
+        let tiers = startup_tiers(vec![
+            ("a", TorrentPriority::Normal),
+            ("b", TorrentPriority::Low),
+            ("c", TorrentPriority::High),
+            ("d", TorrentPriority::Normal),
+        ]);
+        assert_eq!(
+            tiers,
+            vec![
+                (TorrentPriority::High, vec!["c"]),
+                (TorrentPriority::Normal, vec!["a", "d"]),
+                (TorrentPriority::Low, vec!["b"]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_start_in_priority_order() {
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let options = StartupOptions {
+            max_concurrent: 2,
+            tier_timeout: Duration::from_millis(100),
+        };
+        start_in_priority_order(
+            vec![
+                ("low", TorrentPriority::Low),
+                ("stuck", TorrentPriority::High),
+                ("normal", TorrentPriority::Normal),
+                ("high", TorrentPriority::High),
+            ],
+            options,
+            |name| {
+                let started = started.clone();
+                async move {
+                    started.lock().unwrap().push(name);
+                    if name == "stuck" {
+                        // e.g. its tracker doesn't respond
+                        tokio::time::sleep(Duration::from_secs(10)).await;
+                    }
+                }
+            },
+        )
+        .await;
+        // The stuck torrent holds a slot, the others still start in order
+        assert_eq!(
+            *started.lock().unwrap(),
+            vec!["stuck", "high", "normal", "low"]
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+// The sessions we wait on for blocks, the peer unchoked us and has our requests, but nothing
+// arrives. A stuck request pipeline, a picker bug and a peer which went silent all look like
+// this from here. A session with nothing requested isn't waiting on the peer, however long
+// it's unchoked. The sessions report to it each tick, the starved ones close themselves so
+// the peer manager dials a fresh connection later.
+
+pub(crate) const DEFAULT_STARVATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Waiting {
+    // Unchoked with requests in flight since, None while it's not.
+    since: Option<Instant>,
+    last_block: Option<Instant>,
+    blocks: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct StarvedPeer {
+    pub addr: SocketAddr,
+    // Waiting without a block for this long.
+    pub waiting: Duration,
+    // Before the wait, None if it never sent one.
+    pub last_block: Option<Duration>,
+    pub blocks: u64,
+}
+
+// Logged once the whole torrent is starved, what to look at to tell which of the causes it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct StarvationReport {
+    pub since_last_block: Duration,
+    pub sessions: usize,
+    // Unchoked with requests in flight, starved or not.
+    pub waiting: usize,
+    pub starved: Vec<StarvedPeer>,
+}
+
+pub(crate) struct StarvationWatchdog {
+    timeout: Duration,
+    sessions: HashMap<SocketAddr, Waiting>,
+    // From any session of the torrent.
+    last_block: Instant,
+    // The report is logged once per timeout, not by each session which sees it.
+    last_report: Option<Instant>,
+}
+
+impl StarvationWatchdog {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            sessions: HashMap::new(),
+            last_block: Instant::now(),
+            last_report: None,
+        }
+    }
+
+    pub(crate) fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    // `unchoked` is whether the peer unchoked us while we are interested.
+    pub(crate) fn update(
+        &mut self,
+        addr: SocketAddr,
+        unchoked: bool,
+        requests: usize,
+        now: Instant,
+    ) {
+        let waiting = unchoked && requests > 0;
+        let session = self.sessions.entry(addr).or_default();
+        match (waiting, session.since) {
+            (true, None) => session.since = Some(now),
+            (false, _) => session.since = None,
+            (true, Some(_)) => {}
+        }
+    }
+
+    // A block arrived, the wait starts over.
+    pub(crate) fn on_block(&mut self, addr: SocketAddr, now: Instant) {
+        let session = self.sessions.entry(addr).or_default();
+        session.last_block = Some(now);
+        session.blocks += 1;
+        if session.since.is_some() {
+            session.since = Some(now);
+        }
+        self.last_block = now;
+    }
+
+    pub(crate) fn remove(&mut self, addr: &SocketAddr) {
+        self.sessions.remove(addr);
+    }
+
+    pub(crate) fn is_starved(&self, addr: &SocketAddr, now: Instant) -> bool {
+        self.sessions
+            .get(addr)
+            .and_then(|it| it.since)
+            .is_some_and(|since| now.saturating_duration_since(since) >= self.timeout)
+    }
+
+    // Some if no session got a block for the timeout while some of them wait for one.
+    pub(crate) fn check(&mut self, now: Instant) -> Option<StarvationReport> {
+        let since_last_block = now.saturating_duration_since(self.last_block);
+        if since_last_block < self.timeout
+            || self
+                .last_report
+                .is_some_and(|it| now.saturating_duration_since(it) < self.timeout)
+        {
+            return None;
+        }
+        let mut starved: Vec<StarvedPeer> = self
+            .sessions
+            .iter()
+            .filter(|(addr, _)| self.is_starved(addr, now))
+            .map(|(addr, session)| {
+                let since = session.since.unwrap_or(now);
+                StarvedPeer {
+                    addr: *addr,
+                    waiting: now.saturating_duration_since(since),
+                    last_block: session
+                        .last_block
+                        .map(|it| since.saturating_duration_since(it)),
+                    blocks: session.blocks,
+                }
+            })
+            .collect();
+        if starved.is_empty() {
+            return None;
+        }
+        starved.sort_by_key(|it| std::cmp::Reverse(it.waiting));
+        self.last_report = Some(now);
+        Some(StarvationReport {
+            since_last_block,
+            sessions: self.sessions.len(),
+            waiting: self
+                .sessions
+                .values()
+                .filter(|it| it.since.is_some())
+                .count(),
+            starved,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_starved_sessions() {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(60);
+        let mut watchdog = StarvationWatchdog::new(timeout);
+        let (stuck, busy, choked) = (
+            SocketAddr::from(([10, 0, 0, 1], 6881)),
+            SocketAddr::from(([10, 0, 0, 2], 6881)),
+            SocketAddr::from(([10, 0, 0, 3], 6881)),
+        );
+        watchdog.update(stuck, true, 4, start);
+        watchdog.update(busy, true, 4, start);
+        watchdog.update(choked, false, 4, start);
+
+        // One session still gets blocks, the torrent isn't starved
+        let later = start + Duration::from_secs(61);
+        watchdog.on_block(busy, start + Duration::from_secs(30));
+        assert!(watchdog.is_starved(&stuck, later));
+        assert!(!watchdog.is_starved(&busy, later));
+        assert!(!watchdog.is_starved(&choked, later));
+        assert!(watchdog.check(later).is_none());
+
+        let later = start + Duration::from_secs(91);
+        let report = watchdog.check(later).unwrap();
+        assert_eq!(report.sessions, 3);
+        assert_eq!(report.waiting, 2);
+        assert_eq!(report.since_last_block, Duration::from_secs(61));
+        let starved: Vec<_> = report.starved.iter().map(|it| it.addr).collect();
+        assert_eq!(starved, vec![stuck, busy]);
+        assert_eq!(report.starved[1].blocks, 1);
+        // Reported once per timeout
+        assert!(watchdog.check(later + Duration::from_secs(1)).is_none());
+
+        // Choked again, it's not waiting on the peer anymore
+        watchdog.update(stuck, false, 4, later);
+        watchdog.remove(&busy);
+        assert!(!watchdog.is_starved(&stuck, later));
+        assert!(watchdog.check(later + timeout).is_none());
+    }
+
+    #[test]
+    fn test_unchoked_without_requests() {
+        let start = Instant::now();
+        let mut watchdog = StarvationWatchdog::new(Duration::from_secs(60));
+        let idle = SocketAddr::from(([10, 0, 0, 1], 6881));
+        // Nothing to request from the peer, e.g. the other peers took all its pieces
+        watchdog.update(idle, true, 0, start);
+        let later = start + Duration::from_secs(120);
+        watchdog.update(idle, true, 0, later);
+        assert!(!watchdog.is_starved(&idle, later));
+        assert!(watchdog.check(later).is_none());
+
+        // The wait starts with the first request
+        watchdog.update(idle, true, 1, later);
+        assert!(!watchdog.is_starved(&idle, later + Duration::from_secs(59)));
+        assert!(watchdog.is_starved(&idle, later + Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{transfer::TransferSnapshot, types::Sha1Hash};
+
+pub(crate) type Result<T> = std::result::Result<T, StatisticsError>;
+
+#[derive(Error, Debug)]
+pub enum StatisticsError {
+    #[error("Failed to access statistics file")]
+    Io(#[from] std::io::Error),
+
+    #[error("Failed to parse statistics file")]
+    Bencode(#[from] serde_bencode::Error),
+}
+
+// Aggregate statistics over the whole lifetime of the client, persisted across restarts.
+pub struct Statistics {
+    lifetime: raw::Statistics,
+    started_at: Instant,
+    // The totals of each torrent at the last record, only what they transferred since is added.
+    torrents: HashMap<Sha1Hash, TransferSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatisticsSnapshot {
+    pub total_downloaded: u64,
+    pub total_uploaded: u64,
+    // Uploaded / downloaded, 0 if nothing downloaded yet.
+    pub ratio: f64,
+    pub time_running: Duration,
+    pub torrents_completed: u64,
+}
+
+impl Statistics {
+    pub fn new() -> Self {
+        Self::from_raw(raw::Statistics::default())
+    }
+
+    // Load the statistics from the file, start from zero if the file is not exists yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(bytes) => Ok(Self::from_raw(serde_bencode::from_bytes(&bytes)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(e) => Err(StatisticsError::Io(e)),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let snapshot = self.snapshot();
+        let raw = raw::Statistics {
+            total_downloaded: snapshot.total_downloaded,
+            total_uploaded: snapshot.total_uploaded,
+            time_running: snapshot.time_running.as_secs(),
+            torrents_completed: snapshot.torrents_completed,
+        };
+        let bytes = serde_bencode::to_bytes(&raw)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Write to a temporary file first, so a crash while saving doesn't lose the totals.
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, bytes)?;
+        std::fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    fn from_raw(lifetime: raw::Statistics) -> Self {
+        Self {
+            lifetime,
+            started_at: Instant::now(),
+            torrents: HashMap::new(),
+        }
+    }
+
+    pub fn record_download(&mut self, bytes: u64) {
+        self.lifetime.total_downloaded += bytes;
+    }
+
+    pub fn record_upload(&mut self, bytes: u64) {
+        self.lifetime.total_uploaded += bytes;
+    }
+
+    pub fn record_completed(&mut self) {
+        self.lifetime.torrents_completed += 1;
+    }
+
+    // Add the transfers of the torrents since the last record. The totals of a torrent count
+    // from zero when it's added, and a torrent is completed when nothing is left of it anymore.
+    pub fn record_torrents(&mut self, snapshots: &[(Sha1Hash, TransferSnapshot)]) {
+        let mut torrents = HashMap::with_capacity(snapshots.len());
+        for (info_hash, snapshot) in snapshots {
+            let last = self.torrents.get(info_hash).copied().unwrap_or_default();
+            self.record_download(snapshot.downloaded.saturating_sub(last.downloaded));
+            self.record_upload(snapshot.uploaded.saturating_sub(last.uploaded));
+            // A torrent complete when it's first seen was completed before, e.g. it's seeded
+            if last.left > 0 && snapshot.left == 0 {
+                self.record_completed();
+            }
+            torrents.insert(*info_hash, *snapshot);
+        }
+        // The removed torrents are forgotten
+        self.torrents = torrents;
+    }
+
+    pub fn snapshot(&self) -> StatisticsSnapshot {
+        let ratio = if self.lifetime.total_downloaded == 0 {
+            0.0
+        } else {
+            self.lifetime.total_uploaded as f64 / self.lifetime.total_downloaded as f64
+        };
+        StatisticsSnapshot {
+            total_downloaded: self.lifetime.total_downloaded,
+            total_uploaded: self.lifetime.total_uploaded,
+            ratio,
+            time_running: Duration::from_secs(self.lifetime.time_running)
+                + self.started_at.elapsed(),
+            torrents_completed: self.lifetime.torrents_completed,
+        }
+    }
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+mod raw {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    pub struct Statistics {
+        #[serde(rename = "total downloaded")]
+        pub total_downloaded: u64,
+        #[serde(rename = "total uploaded")]
+        pub total_uploaded: u64,
+        // In seconds
+        #[serde(rename = "time running")]
+        pub time_running: u64,
+        #[serde(rename = "torrents completed")]
+        pub torrents_completed: u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load_statistics() {
+        let path = Path::new("test_statistics/statistics.dat");
+        let mut statistics = Statistics::new();
+        statistics.record_download(1000);
+        statistics.record_upload(500);
+        statistics.record_completed();
+        statistics.save(path).unwrap();
+
+        let mut statistics = Statistics::load(path).unwrap();
+        statistics.record_download(1000);
+        let snapshot = statistics.snapshot();
+        assert_eq!(snapshot.total_downloaded, 2000);
+        assert_eq!(snapshot.total_uploaded, 500);
+        assert_eq!(snapshot.torrents_completed, 1);
+        assert!((snapshot.ratio - 0.25).abs() < 1e-6);
+
+        let _ = std::fs::remove_dir_all("test_statistics");
+    }
+
+    #[test]
+    fn test_record_torrents() {
+        let mut statistics = Statistics::new();
+        let downloading = |downloaded, uploaded, left| TransferSnapshot {
+            uploaded,
+            downloaded,
+            left,
+        };
+        statistics.record_torrents(&[
+            ([1; 20], downloading(100, 10, 50)),
+            ([2; 20], downloading(0, 5, 0)),
+        ]);
+        statistics.record_torrents(&[
+            ([1; 20], downloading(150, 10, 0)),
+            ([2; 20], downloading(0, 25, 0)),
+        ]);
+        let snapshot = statistics.snapshot();
+        assert_eq!(snapshot.total_downloaded, 150);
+        assert_eq!(snapshot.total_uploaded, 35);
+        // The seeded torrent was complete already
+        assert_eq!(snapshot.torrents_completed, 1);
+
+        // Removed, and added again from zero
+        statistics.record_torrents(&[]);
+        statistics.record_torrents(&[([1; 20], downloading(0, 20, 0))]);
+        let snapshot = statistics.snapshot();
+        assert_eq!(snapshot.total_downloaded, 150);
+        assert_eq!(snapshot.total_uploaded, 55);
+        assert_eq!(snapshot.torrents_completed, 1);
+    }
+
+    #[test]
+    fn test_load_missing_statistics() {
+        let statistics = Statistics::load(Path::new("test_statistics_missing.dat")).unwrap();
+        let snapshot = statistics.snapshot();
+        assert_eq!(snapshot.total_downloaded, 0);
+        assert_eq!(snapshot.ratio, 0.0);
+    }
+}
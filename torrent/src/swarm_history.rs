@@ -0,0 +1,219 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+// Keep the history this small however long the torrent lives, it's saved with the resume data.
+const MAX_SAMPLES: usize = 512;
+// The samples closer than this are merged, doubled every time the history is downsampled.
+const MIN_RESOLUTION: Duration = Duration::from_secs(60);
+
+// What the swarm of a torrent looked like at some point, for the history graph.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct SwarmSample {
+    // Seconds since the unix epoch, the start of the span the sample covers.
+    pub at: u64,
+    pub peers: u32,
+    // From the announces and the scrapes, None if no tracker reported them.
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    // Bytes per second.
+    pub download_rate: u64,
+    pub upload_rate: u64,
+}
+
+// The samples of a torrent since it was added. The older samples are merged to keep the size
+// bounded, so a torrent seeded for years has a coarser history than one added yesterday.
+#[derive(Debug, Clone)]
+pub struct SwarmHistory {
+    samples: Vec<raw::Sample>,
+    resolution: Duration,
+}
+
+impl Default for SwarmHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SwarmHistory {
+    pub fn new() -> Self {
+        Self {
+            samples: Vec::new(),
+            resolution: MIN_RESOLUTION,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_bencode::Error> {
+        let history: raw::History = serde_bencode::from_bytes(bytes)?;
+        Ok(Self {
+            samples: history.samples,
+            resolution: Duration::from_secs(history.resolution).max(MIN_RESOLUTION),
+        })
+    }
+
+    // To save with the resume data.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, serde_bencode::Error> {
+        serde_bencode::to_bytes(&raw::History {
+            samples: self.samples.clone(),
+            resolution: self.resolution.as_secs(),
+        })
+    }
+
+    pub fn record(&mut self, now: SystemTime, sample: SwarmSample) {
+        let at = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let sample = raw::Sample::new(SwarmSample { at, ..sample });
+        match self.samples.last_mut() {
+            Some(last) if at < last.at + self.resolution.as_secs() => last.merge(&sample),
+            _ => self.samples.push(sample),
+        }
+        if self.samples.len() > MAX_SAMPLES {
+            self.downsample();
+        }
+    }
+
+    // Merge the samples two by two, the history covers the same time with half the samples.
+    fn downsample(&mut self) {
+        self.samples = self
+            .samples
+            .chunks(2)
+            .map(|pair| {
+                let mut merged = pair[0].clone();
+                if let Some(next) = pair.get(1) {
+                    merged.merge(next);
+                }
+                merged
+            })
+            .collect();
+        self.resolution *= 2;
+    }
+
+    pub fn samples(&self) -> Vec<SwarmSample> {
+        self.samples.iter().map(raw::Sample::to_sample).collect()
+    }
+}
+
+mod raw {
+    use serde::{Deserialize, Serialize};
+
+    use super::SwarmSample;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct History {
+        pub samples: Vec<Sample>,
+        // In seconds
+        pub resolution: u64,
+    }
+
+    // The values are summed, so the merged samples average correctly.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Sample {
+        pub at: u64,
+        // How many samples are merged into this one.
+        pub count: u64,
+        pub peers: u64,
+        #[serde(default)]
+        pub seeders: Option<u64>,
+        #[serde(default)]
+        pub leechers: Option<u64>,
+        // How many of the merged samples had the seeders and the leechers.
+        #[serde(rename = "swarm count")]
+        pub swarm_count: u64,
+        #[serde(rename = "download rate")]
+        pub download_rate: u64,
+        #[serde(rename = "upload rate")]
+        pub upload_rate: u64,
+    }
+
+    impl Sample {
+        pub fn new(sample: SwarmSample) -> Self {
+            Self {
+                at: sample.at,
+                count: 1,
+                peers: sample.peers as u64,
+                seeders: sample.seeders.map(u64::from),
+                leechers: sample.leechers.map(u64::from),
+                swarm_count: (sample.seeders.is_some() || sample.leechers.is_some()) as u64,
+                download_rate: sample.download_rate,
+                upload_rate: sample.upload_rate,
+            }
+        }
+
+        pub fn merge(&mut self, other: &Sample) {
+            let add = |a: Option<u64>, b: Option<u64>| match (a, b) {
+                (Some(a), Some(b)) => Some(a + b),
+                (a, b) => a.or(b),
+            };
+            self.count += other.count;
+            self.peers += other.peers;
+            self.seeders = add(self.seeders, other.seeders);
+            self.leechers = add(self.leechers, other.leechers);
+            self.swarm_count += other.swarm_count;
+            self.download_rate += other.download_rate;
+            self.upload_rate += other.upload_rate;
+        }
+
+        pub fn to_sample(&self) -> SwarmSample {
+            let count = self.count.max(1);
+            let swarm_count = self.swarm_count.max(1);
+            SwarmSample {
+                at: self.at,
+                peers: (self.peers / count) as u32,
+                seeders: self.seeders.map(|it| (it / swarm_count) as u32),
+                leechers: self.leechers.map(|it| (it / swarm_count) as u32),
+                download_rate: self.download_rate / count,
+                upload_rate: self.upload_rate / count,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(peers: u32, seeders: Option<u32>) -> SwarmSample {
+        SwarmSample {
+            peers,
+            seeders,
+            download_rate: peers as u64 * 1000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_merge_close_samples() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut history = SwarmHistory::new();
+        history.record(start, sample(10, Some(2)));
+        history.record(start + Duration::from_secs(30), sample(20, None));
+        history.record(start + Duration::from_secs(60), sample(5, Some(1)));
+
+        let samples = history.samples();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].at, 1_000_000);
+        assert_eq!(samples[0].peers, 15);
+        // Only the first of the two had the seeders
+        assert_eq!(samples[0].seeders, Some(2));
+        assert_eq!(samples[0].download_rate, 15000);
+        assert_eq!(samples[1].peers, 5);
+    }
+
+    #[test]
+    fn test_downsample_and_restore() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut history = SwarmHistory::new();
+        for i in 0..MAX_SAMPLES as u64 + 1 {
+            history.record(start + MIN_RESOLUTION * i as u32, sample(i as u32, None));
+        }
+        let samples = history.samples();
+        assert_eq!(samples.len(), MAX_SAMPLES / 2 + 1);
+        assert_eq!(samples[0].peers, 0);
+        assert_eq!(samples[1].peers, 2);
+        // Covers all of the time since the torrent was added
+        assert_eq!(samples[0].at, 1_000_000);
+
+        let restored = SwarmHistory::from_bytes(&history.to_bytes().unwrap()).unwrap();
+        assert_eq!(restored.samples(), samples);
+        assert_eq!(restored.resolution, MIN_RESOLUTION * 2);
+    }
+}
@@ -1,44 +1,624 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use bitvec::vec::BitVec;
+use serde::Serialize;
 use thiserror::Error;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, broadcast, mpsc};
+use url::Url;
 
 use crate::{
-    metainfo::MetaInfo,
-    piece::{Block, Piece, PieceError},
-    piece_picker::PiecePicker,
+    announcer::TrackerStatus,
+    choker::{ChokeStrategyKind, DeadWeightPolicy},
+    hash::MERKLE_BLOCK_SIZE,
+    health::{HealthInputs, TorrentHealth},
+    liveness::{DeadTorrentPolicy, SwarmLiveness},
+    magnet::MagnetLink,
+    metadata::MetadataDownloader,
+    metainfo::{MetaInfo, MetaInfoError, MetaInfoLimits},
+    peer_activity::PeerActivityLog,
+    peer_stats::{PeerContribution, PieceAttribution},
+    peer_tls::PeerTls,
+    pick_strategy::{PickStrategy, PickStrategyKind},
+    piece::{self, Block, Piece, PieceError},
+    piece_picker::{BlockInfo, DuplicateStats, PiecePicker},
+    swarm_history::{SwarmHistory, SwarmSample},
+    torrent_settings::{FieldError, TorrentSettings},
+    tracker::TrackerTls,
+    transfer::{TransferSnapshot, TransferTotals},
+    transport::TransportPolicy,
+    types::{BitField, PeerId, Sha1Hash, Sha256Hash},
 };
 
 pub(crate) type Result<T> = std::result::Result<T, TorrentError>;
 
+// A verified piece on its way to the disk, see `Torrent::set_disk_writes`.
+pub(crate) type PieceWrite = (MetaInfo, Piece, Vec<u8>);
+
+// The latest swarm sample older than this doesn't tell whether it's still downloading.
+const RECENT_PROGRESS: Duration = Duration::from_secs(10 * 60);
+
 #[derive(Debug, Error)]
 pub enum TorrentError {
     #[error("invalid piece index")]
     InvalidPieceIndex,
     #[error("piece error")]
     Piece(#[from] PieceError),
+    #[error("invalid metadata")]
+    Metadata(#[from] MetaInfoError),
+    #[error("metadata doesn't match the info hash")]
+    MetadataMismatch,
+    #[error("invalid file index")]
+    InvalidFileIndex,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TorrentPhase {
+    // Started from a magnet link, fetching the info dict from peers before any piece exists.
+    DownloadingMetadata,
+    Downloading,
+    // Downloading, but nobody has shared the torrent for a long time, see `DeadTorrentPolicy`.
+    Dead,
+    // All the wanted files are downloaded.
+    Seeding,
+}
+
+// Which torrents go first when they compete, e.g. announcing and dialing at startup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TorrentPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+// Options which can be changed per torrent, overriding the session defaults.
+#[derive(Debug, Clone, Default)]
+pub struct TorrentOptions {
+    // Local address which the peer connections and tracker announces of this torrent bind to,
+    // use to pin a torrent to a specific network interface (e.g. VPN or LAN).
+    pub bind_addr: Option<IpAddr>,
+    // Which transports to use when dialing the peers.
+    pub transport: TransportPolicy,
+    pub dead_torrent: DeadTorrentPolicy,
+    pub priority: TorrentPriority,
+    // How to choose the next piece to download, e.g. sequential to preview a video.
+    pub pick_strategy: PickStrategyKind,
+    // Which peers get the upload slots.
+    pub choke_strategy: ChokeStrategyKind,
+    // When to drop the peers which transfer nothing while all the connection slots are taken.
+    pub dead_weight: DeadWeightPolicy,
+    // Applied to the info dict fetched from the peers.
+    pub metainfo_limits: MetaInfoLimits,
+    // Send the peers an incomplete bitfield and reveal the rest with have messages, so the
+    // exact bitfield can't be used to fingerprint us.
+    pub lazy_bitfield: bool,
+    // Bytes per second, None means unlimited.
+    pub download_limit: Option<u64>,
+    pub upload_limit: Option<u64>,
+    // None uses the session limit.
+    pub max_peers: Option<usize>,
+    pub super_seed: bool,
+    // Stop seeding once uploaded this many times the size of the torrent.
+    pub ratio_limit: Option<f64>,
+    // The queue starts and stops the torrent, instead of the user.
+    pub auto_managed: bool,
+    // From the profile, see `TrackerTls`.
+    pub tracker_tls: Vec<TrackerTls>,
+    // Announce to all the trackers at once instead of the first which responds in tier order.
+    pub announce_to_all_tiers: bool,
+    // The peers talk over TLS and must hold these certificates, for the SSL torrents.
+    pub peer_tls: Option<PeerTls>,
 }
 
 pub struct Torrent {
+    info_hash: Sha1Hash,
+    // None until the info dict is fetched if the torrent is started from a magnet link.
+    metainfo: Option<MetaInfo>,
+    metadata: Option<MetadataDownloader>,
+    // The bencoded info dict, served to the peers which started from a magnet link.
+    info_bytes: Option<Arc<Vec<u8>>>,
+    // Trackers from the magnet link, used to build the metainfo once the info dict is fetched.
+    trackers: Vec<Url>,
     pieces: Vec<Piece>,
     piece_picker: Arc<Mutex<PiecePicker>>,
+    // Whether each file of the metainfo should be downloaded, all files are wanted by default.
+    file_wanted: Vec<bool>,
+    // The files the magnet link selected, applied once the info dict is fetched.
+    select_only: Option<Vec<usize>>,
+    // The peers the magnet link told us to dial, see `Dialer::push_hints`.
+    peer_hints: Vec<String>,
+    attribution: PieceAttribution,
+    liveness: SwarmLiveness,
+    history: SwarmHistory,
+    // Shared with the peer sessions, which record into it without locking the torrent.
+    peer_activity: Arc<PeerActivityLog>,
+    // Shared with the peer sessions too.
+    transfer: Arc<TransferTotals>,
+    // The index of each piece once it's verified, whoever downloaded it. Once it's on the disk
+    // too if the torrent is served.
+    verified: broadcast::Sender<u32>,
+    // Where the verified pieces are written, None keeps them in memory only.
+    disk_writes: Option<mpsc::UnboundedSender<PieceWrite>>,
+    options: TorrentOptions,
 }
 
 impl Torrent {
     pub fn from_metainfo(metainfo: MetaInfo) -> Self {
-        let piece_length = metainfo.info.piece_length;
-        let total_bytes = metainfo.total_bytes() as u32;
-        let piece_size = total_bytes / piece_length;
-        let piece_picker = PiecePicker::new(
-            // TODO: if already have downloaded piece, read from disk
-            BitVec::repeat(false, piece_size as usize),
-            total_bytes,
-            piece_length,
-        );
+        let have = BitVec::repeat(false, metainfo.piece_count());
+        Self::from_existing_pieces(metainfo, have)
+    }
+
+    // The torrent whose pieces in `have` are already verified on the disk.
+    pub fn from_existing_pieces(metainfo: MetaInfo, have: BitField) -> Self {
+        let piece_picker = Torrent::new_piece_picker(&metainfo, have, PickStrategyKind::default());
+        let pieces = Torrent::new_pieces(&metainfo);
         Self {
-            pieces: Vec::new(),
+            info_hash: metainfo.info_hash,
+            file_wanted: vec![true; metainfo.files().len()],
+            attribution: PieceAttribution::new(),
+            liveness: SwarmLiveness::new(),
+            history: SwarmHistory::new(),
+            peer_activity: Arc::new(PeerActivityLog::new()),
+            transfer: Arc::new(TransferTotals::new()),
+            verified: broadcast::channel(64).0,
+            disk_writes: None,
+            info_bytes: metainfo.info_bytes().ok().map(Arc::new),
+            metainfo: Some(metainfo),
+            metadata: None,
+            trackers: Vec::new(),
+            select_only: None,
+            peer_hints: Vec::new(),
+            pieces,
             piece_picker: Arc::new(Mutex::new(piece_picker)),
+            options: TorrentOptions::default(),
+        }
+    }
+
+    pub fn from_magnet(magnet: MagnetLink) -> Self {
+        Self {
+            info_hash: magnet.info_hash,
+            metainfo: None,
+            metadata: Some(MetadataDownloader::new(magnet.info_hash)),
+            info_bytes: None,
+            trackers: magnet.trackers,
+            select_only: magnet.select_only,
+            peer_hints: magnet.peers,
+            pieces: Vec::new(),
+            piece_picker: Arc::new(Mutex::new(PiecePicker::new(BitVec::new(), 0, 0))),
+            file_wanted: Vec::new(),
+            attribution: PieceAttribution::new(),
+            liveness: SwarmLiveness::new(),
+            history: SwarmHistory::new(),
+            peer_activity: Arc::new(PeerActivityLog::new()),
+            transfer: Arc::new(TransferTotals::new()),
+            verified: broadcast::channel(64).0,
+            disk_writes: None,
+            options: TorrentOptions::default(),
+        }
+    }
+
+    fn new_piece_picker(
+        metainfo: &MetaInfo,
+        have: BitField,
+        pick_strategy: PickStrategyKind,
+    ) -> PiecePicker {
+        let piece_length = metainfo.info.piece_length;
+        let total_bytes = metainfo.total_bytes() as u32;
+        let mut piece_picker = PiecePicker::new(have, total_bytes, piece_length);
+        piece_picker.set_strategy(pick_strategy.build());
+        piece_picker
+    }
+
+    fn new_pieces(metainfo: &MetaInfo) -> Vec<Piece> {
+        let piece_length = metainfo.info.piece_length as u64;
+        let total_bytes = metainfo.total_bytes() as u64;
+        let merkle_hashes = piece::merkle_hashes(metainfo).unwrap_or_default();
+        piece::piece_hashes(metainfo)
+            .into_iter()
+            .enumerate()
+            .map(|(index, hash)| {
+                let length = piece_length.min(total_bytes - index as u64 * piece_length);
+                let piece = Piece::new_unverified(index, hash, length as u32);
+                match merkle_hashes.get(index) {
+                    Some(Some(root)) => piece.with_merkle_root(*root),
+                    _ => piece,
+                }
+            })
+            .collect()
+    }
+
+    pub fn info_hash(&self) -> Sha1Hash {
+        self.info_hash
+    }
+
+    // Not known until the info dict is fetched if started from a magnet link.
+    pub fn is_private(&self) -> bool {
+        self.metainfo.as_ref().is_some_and(MetaInfo::is_private)
+    }
+
+    pub fn peer_hints(&self) -> &[String] {
+        &self.peer_hints
+    }
+
+    pub fn metainfo(&self) -> Option<&MetaInfo> {
+        self.metainfo.as_ref()
+    }
+
+    pub async fn phase(&self) -> TorrentPhase {
+        if self.metainfo.is_none() {
+            TorrentPhase::DownloadingMetadata
+        } else if self.piece_picker.lock().await.is_complete() {
+            TorrentPhase::Seeding
+        } else if self.liveness.is_dead(&self.options.dead_torrent) {
+            TorrentPhase::Dead
+        } else {
+            TorrentPhase::Downloading
+        }
+    }
+
+    // The trackers to announce to, in tiers. The ones of a magnet link are one tier.
+    pub fn tracker_tiers(&self) -> Vec<Vec<Url>> {
+        match &self.metainfo {
+            Some(metainfo) => metainfo.tracker_tiers(),
+            None if self.trackers.is_empty() => Vec::new(),
+            None => vec![self.trackers.clone()],
+        }
+    }
+
+    // Report the swarm we found, the seeders are what the trackers reported.
+    pub fn record_swarm(&mut self, connectable_peers: usize, seeders: Option<u32>) {
+        self.liveness.record(connectable_peers, seeders);
+    }
+
+    // Sampled now and then by the engine, for the history graph of the torrent.
+    pub fn record_swarm_sample(&mut self, sample: SwarmSample) {
+        self.history.record(SystemTime::now(), sample);
+    }
+
+    pub fn swarm_history(&self) -> &SwarmHistory {
+        &self.history
+    }
+
+    // The red/yellow/green indicator of the torrent list, `trackers` is the status from its
+    // announcer.
+    pub async fn health(&self, trackers: &[TrackerStatus]) -> TorrentHealth {
+        let distributed_copies = if self.metainfo.is_none() {
+            // Nothing to download from until a peer sends the metadata
+            Some(0.0)
+        } else {
+            self.piece_picker.lock().await.distributed_copies()
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let download_rate = self
+            .history
+            .samples()
+            .last()
+            .filter(|it| now.saturating_sub(it.at) <= RECENT_PROGRESS.as_secs())
+            .map(|it| it.download_rate);
+        TorrentHealth::compute(&HealthInputs {
+            working_trackers: trackers
+                .iter()
+                .filter(|it| it.last_announce.is_some() && it.last_error.is_none())
+                .count(),
+            trackers: trackers.len(),
+            seeders: trackers.iter().filter_map(|it| it.seeders).max(),
+            distributed_copies,
+            download_rate,
+        })
+    }
+
+    // The history saved with the resume data, to continue it after a restart.
+    pub fn restore_swarm_history(&mut self, history: SwarmHistory) {
+        self.history = history;
+    }
+
+    // The recent activity of the connected peers, for the peer detail pane.
+    pub fn peer_activity(&self) -> Arc<PeerActivityLog> {
+        self.peer_activity.clone()
+    }
+
+    // The sessions tell their peers about the new pieces, and lose interest in the peers which
+    // have nothing else we want.
+    pub fn subscribe_verified(&self) -> broadcast::Receiver<u32> {
+        self.verified.subscribe()
+    }
+
+    // The verified pieces are handed to `writes` from now on, and told to the sessions once
+    // `piece_written` says they are on the disk. Serving the torrent again replaces it.
+    pub(crate) fn set_disk_writes(&mut self, writes: mpsc::UnboundedSender<PieceWrite>) {
+        self.disk_writes = Some(writes);
+    }
+
+    // The peers can be told we have the piece, they may request it right away.
+    pub(crate) fn piece_written(&self, piece_index: u32) {
+        let _ = self.verified.send(piece_index);
+    }
+
+    pub fn transfer_totals(&self) -> Arc<TransferTotals> {
+        self.transfer.clone()
+    }
+
+    // What the announces report to the trackers.
+    pub async fn transfer_snapshot(&self) -> TransferSnapshot {
+        TransferSnapshot {
+            uploaded: self.transfer.uploaded(),
+            downloaded: self.transfer.downloaded(),
+            left: self.left_bytes().await,
+        }
+    }
+
+    // The interval to wait before announcing again, backed off if the torrent is dead.
+    pub fn announce_interval(&self, interval: Duration) -> Duration {
+        self.liveness
+            .announce_interval(&self.options.dead_torrent, interval)
+    }
+
+    // None until the info dict is fetched if started from a magnet link.
+    pub(crate) fn info_bytes(&self) -> Option<Arc<Vec<u8>>> {
+        self.info_bytes.clone()
+    }
+
+    // Only exists in the metadata downloading phase.
+    pub fn metadata_downloader(&mut self) -> Option<&mut MetadataDownloader> {
+        self.metadata.as_mut()
+    }
+
+    // The whole info dict is fetched, leave the metadata phase and start downloading pieces.
+    pub async fn set_info_bytes(&mut self, info_bytes: &[u8]) -> Result<()> {
+        let metainfo = MetaInfo::from_info_bytes_with_limits(
+            info_bytes,
+            self.trackers.clone(),
+            &self.options.metainfo_limits,
+        )?;
+        if metainfo.info_hash != self.info_hash {
+            return Err(TorrentError::MetadataMismatch);
+        }
+        let have = BitVec::repeat(false, metainfo.piece_count());
+        let mut piece_picker =
+            Torrent::new_piece_picker(&metainfo, have, self.options.pick_strategy);
+        self.pieces = Torrent::new_pieces(&metainfo);
+        self.file_wanted = match &self.select_only {
+            Some(indices) => (0..metainfo.files().len())
+                .map(|index| indices.contains(&index))
+                .collect(),
+            None => vec![true; metainfo.files().len()],
+        };
+        // Out of range indices would leave nothing to download, they were likely meant for
+        // another version of the torrent
+        if !self.file_wanted.contains(&true) {
+            log::warn!("The select-only of the magnet matches no file, select all");
+            self.file_wanted.fill(true);
+        }
+        if self.file_wanted.contains(&false) {
+            piece_picker.set_wanted(wanted_pieces(&metainfo, &self.file_wanted));
+        }
+        *self.piece_picker.lock().await = piece_picker;
+        self.info_bytes = Some(Arc::new(info_bytes.to_vec()));
+        self.metainfo = Some(metainfo);
+        self.metadata = None;
+        Ok(())
+    }
+
+    pub fn options(&self) -> &TorrentOptions {
+        &self.options
+    }
+
+    pub async fn set_options(&mut self, options: TorrentOptions) {
+        if options.pick_strategy != self.options.pick_strategy {
+            self.set_pick_strategy(options.pick_strategy.build()).await;
+        }
+        if let Some(metadata) = &mut self.metadata {
+            metadata.set_max_size(options.metainfo_limits.max_info_size);
+        }
+        self.options = options;
+    }
+
+    pub fn settings(&self) -> TorrentSettings {
+        TorrentSettings {
+            download_limit: self.options.download_limit,
+            upload_limit: self.options.upload_limit,
+            max_peers: self.options.max_peers,
+            sequential: self.options.pick_strategy == PickStrategyKind::Sequential,
+            super_seed: self.options.super_seed,
+            ratio_limit: self.options.ratio_limit,
+            auto_managed: self.options.auto_managed,
+        }
+    }
+
+    // Apply all the settings or none of them, returns every invalid field.
+    pub async fn apply_settings(
+        &mut self,
+        settings: TorrentSettings,
+    ) -> std::result::Result<(), Vec<FieldError>> {
+        let errors = settings.validate(self.phase().await == TorrentPhase::Seeding);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let mut options = self.options.clone();
+        options.download_limit = settings.download_limit;
+        options.upload_limit = settings.upload_limit;
+        options.max_peers = settings.max_peers;
+        options.super_seed = settings.super_seed;
+        options.ratio_limit = settings.ratio_limit;
+        options.auto_managed = settings.auto_managed;
+        // Turning off sequential goes back to the default, any other strategy is kept
+        if settings.sequential {
+            options.pick_strategy = PickStrategyKind::Sequential;
+        } else if options.pick_strategy == PickStrategyKind::Sequential {
+            options.pick_strategy = PickStrategyKind::default();
+        }
+        self.set_options(options).await;
+        Ok(())
+    }
+
+    // Swap how the next piece is chosen while downloading, the blocks in flight are kept.
+    // Any strategy can be plugged in, not only the ones of `PickStrategyKind`.
+    pub async fn set_pick_strategy(&self, strategy: Box<dyn PickStrategy>) {
+        self.piece_picker.lock().await.set_strategy(strategy);
+    }
+
+    // The piece is needed by then, picked first by the deadline strategy.
+    pub async fn set_piece_deadline(&self, piece_index: u32, deadline: Instant) {
+        self.piece_picker
+            .lock()
+            .await
+            .set_deadline(piece_index, deadline);
+    }
+
+    // Track which pieces the connected peers have, for the rarest first strategy.
+    pub(crate) async fn add_peer_bitfield(&self, bitfield: &BitField) {
+        self.piece_picker.lock().await.add_peer_bitfield(bitfield);
+    }
+
+    pub(crate) async fn remove_peer_bitfield(&self, bitfield: &BitField) {
+        self.piece_picker
+            .lock()
+            .await
+            .remove_peer_bitfield(bitfield);
+    }
+
+    // The pieces we have, to tell the peers in the bitfield message.
+    pub(crate) async fn own_bitfield(&self) -> BitField {
+        self.piece_picker.lock().await.own_bitfield().clone()
+    }
+
+    pub(crate) async fn add_peer_have(&self, piece_index: u32) {
+        self.piece_picker.lock().await.add_peer_have(piece_index);
+    }
+
+    pub fn file_wanted(&self) -> &[bool] {
+        &self.file_wanted
+    }
+
+    // Skip or want a file again, also after the torrent is completed.
+    // Returns true if the torrent goes back from seeding to downloading,
+    // the trackers should be announced again with the new `left`.
+    pub async fn set_file_wanted(&mut self, file_index: usize, wanted: bool) -> Result<bool> {
+        let (Some(metainfo), Some(file_wanted)) =
+            (&self.metainfo, self.file_wanted.get_mut(file_index))
+        else {
+            return Err(TorrentError::InvalidFileIndex);
+        };
+        *file_wanted = wanted;
+        let wanted_pieces = wanted_pieces(metainfo, &self.file_wanted);
+
+        let mut piece_picker = self.piece_picker.lock().await;
+        let was_seeding = piece_picker.is_complete();
+        piece_picker.set_wanted(wanted_pieces);
+        Ok(was_seeding && !piece_picker.is_complete())
+    }
+
+    // Seeding with some files skipped, we will never have the whole torrent.
+    // https://www.bittorrent.org/beps/bep_0021.html
+    pub async fn is_partial_seed(&self) -> bool {
+        self.file_wanted.contains(&false) && self.phase().await == TorrentPhase::Seeding
+    }
+
+    pub async fn left_bytes(&self) -> u64 {
+        self.piece_picker.lock().await.left_bytes()
+    }
+
+    // Which peers the verified bytes came from.
+    pub fn contributions(&self) -> Vec<PeerContribution> {
+        self.attribution.contributions()
+    }
+
+    pub fn piece_sources(&self, piece_index: u32) -> &[(SocketAddr, u64)] {
+        self.attribution.piece_sources(piece_index)
+    }
+
+    pub async fn has_piece(&self, piece_index: u32) -> bool {
+        self.piece_picker.lock().await.has_piece(piece_index)
+    }
+
+    // We are seeding the piece and no peer has it, we may be its only source. Its blocks are
+    // uploaded before the others, so the swarm has a copy of it if we leave.
+    pub async fn is_rare_piece(&self, piece_index: u32) -> bool {
+        let picker = self.piece_picker.lock().await;
+        picker.is_complete() && picker.has_piece(piece_index) && picker.is_rare(piece_index)
+    }
+
+    // The peer has a wanted piece we don't have.
+    pub(crate) async fn is_interesting(&self, peer_bitfield: &BitField) -> bool {
+        self.piece_picker.lock().await.is_interesting(peer_bitfield)
+    }
+
+    pub(crate) async fn wants_piece(&self, piece_index: u32) -> bool {
+        self.piece_picker.lock().await.wants_piece(piece_index)
+    }
+
+    // Pick the next block to download from a source which has the pieces in `bitfield`,
+    // the block is reserved for the source until it's received or cancelled.
+    pub(crate) async fn request_block(
+        &self,
+        peer: PeerId,
+        bitfield: &BitField,
+    ) -> Option<BlockInfo> {
+        self.piece_picker.lock().await.request_block(peer, bitfield)
+    }
+
+    pub(crate) async fn cancel_request(&self, peer: PeerId, block: &BlockInfo) {
+        self.piece_picker.lock().await.cancel_request(peer, block);
+    }
+
+    // The source is gone, its blocks in flight can be requested from the others.
+    pub(crate) async fn release_requests(&self, peer: PeerId) {
+        self.piece_picker.lock().await.release_requests(peer);
+    }
+
+    // The blocks requested from the sources and not received yet.
+    pub(crate) async fn requests_in_flight(&self) -> usize {
+        self.piece_picker.lock().await.in_flight()
+    }
+
+    // Should stay zero, a block is never requested from two sources at once.
+    pub async fn duplicate_stats(&self) -> DuplicateStats {
+        self.piece_picker.lock().await.duplicates()
+    }
+
+    // The hashes of the 16KiB leaves of a piece of a v2 or hybrid torrent, so only the corrupt
+    // blocks are downloaded again if the piece fails.
+    pub fn set_leaf_hashes(&mut self, piece_index: u32, hashes: Vec<Sha256Hash>) -> Result<()> {
+        let piece = self
+            .pieces
+            .get_mut(piece_index as usize)
+            .ok_or(TorrentError::InvalidPieceIndex)?;
+        Ok(piece.set_leaf_hashes(hashes)?)
+    }
+
+    // The piece we had is corrupt, it's downloaded again.
+    pub async fn mark_piece_missing(&mut self, piece_index: u32) {
+        if let Some(piece) = self.pieces.get_mut(piece_index as usize) {
+            piece.reset();
+        }
+        self.piece_picker.lock().await.mark_missing(piece_index);
+    }
+
+    // Take the pieces a recheck found on the disk, the ones which failed are downloaded again.
+    pub async fn apply_recheck(&mut self, have: &BitField) {
+        for (index, has) in have.iter().by_vals().enumerate() {
+            let piece_index = index as u32;
+            if has == self.has_piece(piece_index).await {
+                continue;
+            }
+            if has {
+                if let Some(piece) = self.pieces.get_mut(index) {
+                    piece.reset();
+                }
+                self.piece_picker.lock().await.mark_have(piece_index);
+            } else {
+                self.mark_piece_missing(piece_index).await;
+            }
         }
     }
 
@@ -50,11 +630,41 @@ impl Torrent {
             match piece.add_block(block) {
                 Ok(_) => {
                     if piece.is_all_blocks_received() {
+                        let sources = piece.sources();
                         match piece.verify() {
-                            Ok(_) => {
-                                // TODO: write to disk and send have message
+                            Ok(data) => {
+                                self.attribution.record_piece(piece.index as u32, sources);
+                                match (&self.disk_writes, &self.metainfo) {
+                                    (Some(writes), Some(metainfo)) => {
+                                        // The data is all the disk needs of the piece
+                                        let written = Piece::new_unverified(
+                                            piece.index,
+                                            piece.hash,
+                                            piece.length,
+                                        );
+                                        let _ = writes.send((metainfo.clone(), written, data));
+                                    }
+                                    _ => {
+                                        let _ = self.verified.send(piece.index as u32);
+                                    }
+                                }
                                 Ok(())
                             }
+                            Err(PieceError::InvalidHash) => {
+                                // Only download again the blocks which don't match their
+                                // leaf hashes, or the whole piece if we can't tell
+                                let begins = piece.corrupt_blocks().unwrap_or_else(|| {
+                                    (0..piece.length).step_by(MERKLE_BLOCK_SIZE).collect()
+                                });
+                                log::warn!(
+                                    "Piece {} failed to verify, {} blocks are downloaded again",
+                                    piece.index,
+                                    begins.len()
+                                );
+                                piece.discard_blocks(&begins);
+                                piece_picker.reset_blocks(piece.index as u32, &begins);
+                                Err(TorrentError::Piece(PieceError::InvalidHash))
+                            }
                             Err(e) => Err(TorrentError::Piece(e)),
                         }
                     } else {
@@ -68,3 +678,153 @@ impl Torrent {
         }
     }
 }
+
+// A piece is wanted if any wanted file has bytes in it.
+fn wanted_pieces(metainfo: &MetaInfo, file_wanted: &[bool]) -> BitField {
+    let piece_length = metainfo.info.piece_length as u64;
+    let mut wanted = BitField::repeat(false, metainfo.piece_count());
+    let mut offset = 0;
+    for (file, is_wanted) in metainfo.files().iter().zip(file_wanted) {
+        if *is_wanted && file.length > 0 {
+            let first_piece = offset / piece_length;
+            let last_piece = (offset + file.length - 1) / piece_length;
+            for piece_index in first_piece..=last_piece {
+                wanted.set(piece_index as usize, true);
+            }
+        }
+        offset += file.length;
+    }
+    wanted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::raw;
+
+    // Files a, b and c over 3 pieces of 10 bytes.
+    fn metainfo() -> MetaInfo {
+        let files = [("a", 10), ("b", 15), ("c", 5)];
+        MetaInfo {
+            announce: None,
+            announce_list: Vec::new(),
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
+            v2: None,
+            info: raw::Info {
+                name: "test".to_string(),
+                piece_length: 10,
+                length: None,
+                files: Some(
+                    files
+                        .into_iter()
+                        .map(|(path, length)| raw::File {
+                            length,
+                            path: vec![path.to_string()],
+                            ..Default::default()
+                        })
+                        .collect(),
+                ),
+                pieces: vec![0; 20 * 3],
+                extra: std::collections::BTreeMap::new(),
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info_hash: [0u8; 20],
+        }
+    }
+
+    #[test]
+    fn test_wanted_pieces() {
+        let metainfo = metainfo();
+        let wanted = wanted_pieces(&metainfo, &[false, true, false]);
+        assert_eq!(
+            wanted.iter().by_vals().collect::<Vec<_>>(),
+            [false, true, true]
+        );
+        let wanted = wanted_pieces(&metainfo, &[true, false, false]);
+        assert_eq!(
+            wanted.iter().by_vals().collect::<Vec<_>>(),
+            [true, false, false]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_partial_seed() {
+        let have = [true, false, false].into_iter().collect();
+        let mut torrent = Torrent::from_existing_pieces(metainfo(), have);
+        assert!(!torrent.is_partial_seed().await);
+
+        torrent.set_file_wanted(1, false).await.unwrap();
+        torrent.set_file_wanted(2, false).await.unwrap();
+        assert_eq!(torrent.phase().await, TorrentPhase::Seeding);
+        assert!(torrent.is_partial_seed().await);
+    }
+
+    #[tokio::test]
+    async fn test_apply_recheck() {
+        let have = [true, true, false].into_iter().collect();
+        let mut torrent = Torrent::from_existing_pieces(metainfo(), have);
+        let found = [false, true, true].into_iter().collect();
+        torrent.apply_recheck(&found).await;
+        assert!(!torrent.has_piece(0).await);
+        assert!(torrent.has_piece(1).await);
+        assert!(torrent.has_piece(2).await);
+        // Only the corrupt piece is left to download
+        assert_eq!(torrent.left_bytes().await, 10);
+        let have_all = BitField::repeat(true, 3);
+        let block = torrent.request_block([0; 20], &have_all).await.unwrap();
+        assert_eq!(block.piece_index, 0);
+    }
+
+    #[tokio::test]
+    async fn test_apply_settings() {
+        let mut torrent = Torrent::from_metainfo(metainfo());
+        let settings = TorrentSettings {
+            upload_limit: Some(1024),
+            sequential: true,
+            super_seed: true,
+            ..Default::default()
+        };
+        // Nothing is applied if any field is invalid
+        let errors = torrent.apply_settings(settings.clone()).await.unwrap_err();
+        assert_eq!(errors[0].field, "super_seed");
+        assert_eq!(torrent.settings(), TorrentSettings::default());
+
+        let settings = TorrentSettings {
+            super_seed: false,
+            ..settings
+        };
+        torrent.apply_settings(settings.clone()).await.unwrap();
+        assert_eq!(torrent.settings(), settings);
+        assert_eq!(
+            torrent.options().pick_strategy,
+            PickStrategyKind::Sequential
+        );
+    }
+
+    #[tokio::test]
+    async fn test_select_only_from_magnet() {
+        let info_bytes = metainfo().info_bytes().unwrap();
+        let magnet = |select_only| MagnetLink {
+            info_hash: crate::hash::calculate_sha1_hash(info_bytes.clone()),
+            display_name: None,
+            trackers: Vec::new(),
+            select_only,
+            peers: Vec::new(),
+        };
+
+        let mut torrent = Torrent::from_magnet(magnet(Some(vec![1, 5])));
+        torrent.set_info_bytes(&info_bytes).await.unwrap();
+        assert_eq!(torrent.file_wanted(), [false, true, false]);
+        let have_all = BitField::repeat(true, 3);
+        let block = torrent.request_block([0; 20], &have_all).await.unwrap();
+        assert_ne!(block.piece_index, 0);
+
+        // None of the files exist, it's ignored
+        let mut torrent = Torrent::from_magnet(magnet(Some(vec![5])));
+        torrent.set_info_bytes(&info_bytes).await.unwrap();
+        assert_eq!(torrent.file_wanted(), [true, true, true]);
+    }
+}
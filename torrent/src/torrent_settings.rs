@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+// More connections than this per torrent only cost memory and sockets.
+pub const MAX_PEERS_LIMIT: usize = 2000;
+
+// The per torrent options the user edits in one go, see `Torrent::apply_settings`.
+// All of them are validated before any is applied, so the torrent never runs with half of them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TorrentSettings {
+    // Bytes per second, None means unlimited.
+    pub download_limit: Option<u64>,
+    pub upload_limit: Option<u64>,
+    // None uses the session limit.
+    pub max_peers: Option<usize>,
+    // Download the pieces in order, e.g. to preview a video.
+    pub sequential: bool,
+    // Hand out each piece to one peer only, for the initial seeder of a torrent.
+    pub super_seed: bool,
+    // Stop seeding once uploaded this many times the size of the torrent.
+    pub ratio_limit: Option<f64>,
+    // The queue starts and stops the torrent, instead of the user.
+    pub auto_managed: bool,
+}
+
+// Why a field of the settings is rejected, the field is named as in `TorrentSettings`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: &'static str,
+}
+
+impl TorrentSettings {
+    // Every invalid field, empty if the settings can be applied.
+    pub fn validate(&self, is_seeding: bool) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        let mut reject = |field, message| errors.push(FieldError { field, message });
+        // 0 would stop the torrent, which is what pausing is for
+        if self.download_limit == Some(0) {
+            reject("download_limit", "must be greater than 0");
+        }
+        if self.upload_limit == Some(0) {
+            reject("upload_limit", "must be greater than 0");
+        }
+        if let Some(max_peers) = self.max_peers
+            && !(1..=MAX_PEERS_LIMIT).contains(&max_peers)
+        {
+            reject("max_peers", "must be between 1 and 2000");
+        }
+        if let Some(ratio) = self.ratio_limit
+            && !(ratio.is_finite() && ratio >= 0.0)
+        {
+            reject("ratio_limit", "must be a positive number");
+        }
+        if self.super_seed && !is_seeding {
+            reject("super_seed", "only a complete torrent can super seed");
+        }
+        if self.super_seed && self.sequential {
+            reject("sequential", "can't download in order while super seeding");
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(TorrentSettings::default().validate(false).is_empty());
+
+        let settings = TorrentSettings {
+            download_limit: Some(0),
+            upload_limit: Some(1024),
+            max_peers: Some(0),
+            ratio_limit: Some(f64::NAN),
+            super_seed: true,
+            ..Default::default()
+        };
+        let fields: Vec<&str> = settings
+            .validate(false)
+            .into_iter()
+            .map(|it| it.field)
+            .collect();
+        assert_eq!(
+            fields,
+            ["download_limit", "max_peers", "ratio_limit", "super_seed"]
+        );
+        assert_eq!(settings.validate(true).len(), 3);
+    }
+}
@@ -1,15 +1,27 @@
-use std::net::{AddrParseError, SocketAddr};
+use std::{
+    collections::HashMap,
+    net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+};
 
 use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_encode};
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use url::Url;
 
-use crate::types::{PeerId, Sha1Hash};
+use crate::{
+    announce_cache::AnnounceCache,
+    announce_throttle::AnnounceThrottle,
+    client_identity::ClientIdentity,
+    compact::{decode_ip, decode_peers},
+    types::{PeerId, Sha1Hash},
+    udp_tracker::UdpTracker,
+};
 
 pub(crate) type Result<T> = std::result::Result<T, TrackerError>;
 
-const URL_ENCODE_RESERVED: &AsciiSet = &NON_ALPHANUMERIC
+pub(crate) const URL_ENCODE_RESERVED: &AsciiSet = &NON_ALPHANUMERIC
     .remove(b'-')
     .remove(b'_')
     .remove(b'~')
@@ -28,12 +40,74 @@ pub enum TrackerError {
 
     #[error("Invalid IP address")]
     InvalidIpAddr(#[from] AddrParseError),
+
+    #[error("Tracker doesn't support scrape")]
+    ScrapeUnsupported,
+
+    #[error("Tracker didn't respond in time")]
+    Timeout,
+
+    #[error("Unsupported proxy {0}, expected http, https, socks5 or socks5h")]
+    InvalidProxy(String),
+
+    #[error("UDP trackers can't be announced to through a proxy")]
+    UdpThroughProxy,
 }
 
 #[derive(Debug)]
 pub struct Response {
     pub interval: u64,
+    // Don't announce more often than this, except for the events.
+    pub min_interval: Option<u64>,
+    // The announce succeeded but the tracker has something to tell the user.
+    pub warning: Option<String>,
     pub peers: Vec<SocketAddr>,
+    // Swarm size reported by the tracker, not all trackers send it.
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    // Sent back by the tracker in the next announces, see `Tracker::tracker_id`.
+    pub tracker_id: Option<String>,
+    // Our address as the tracker sees it, see `ExternalIp`.
+    pub external_ip: Option<IpAddr>,
+}
+
+// Swarm health of a torrent from the tracker scrape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    pub leechers: u32,
+    // How many times the tracker saw the torrent finish downloading.
+    pub completed: u32,
+}
+
+// What one of the trackers of a torrent told of its swarm, see `Engine::scrape`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TrackerScrape {
+    pub url: String,
+    #[serde(flatten)]
+    pub stats: ScrapeStats,
+}
+
+// The TLS settings of the HTTPS trackers of a host, e.g. a private tracker with a
+// self-signed certificate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackerTls {
+    // The settings apply to all the tracker URLs of the host.
+    pub host: String,
+    // PEM encoded, trusted on top of the system roots.
+    pub root_certificates: Vec<String>,
+    // Accept any certificate, only for a tracker the user trusts on the path to it.
+    pub accept_invalid_certs: bool,
+}
+
+impl TrackerTls {
+    // The settings of the tracker, None if its host has none.
+    pub fn find<'a>(settings: &'a [TrackerTls], url: &Url) -> Option<&'a TrackerTls> {
+        let host = url.host_str()?;
+        settings
+            .iter()
+            .find(|it| it.host.eq_ignore_ascii_case(host))
+    }
 }
 
 // Use to request peers from the tracker from the metainfo announce
@@ -42,37 +116,144 @@ pub struct Tracker {
     pub client: Client,
 
     pub url: Url,
+
+    // Shared by all the torrents, to limit the announces to the same tracker host.
+    throttle: Option<Arc<AnnounceThrottle>>,
+    // Shared by all the trackers, remembers the peers of the last successful announce.
+    cache: Option<Arc<AnnounceCache>>,
+    // The last tracker id the tracker gave us, some trackers reject the announces without it.
+    tracker_id: Mutex<Option<String>>,
+    // Set for the udp:// trackers, which are announced to with it instead of the HTTP client.
+    udp: Option<UdpTracker>,
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-enum TrackerEvent {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerEvent {
     Started,
     Stopped,
     Completed,
     Empty,
+    // A partial seed, counted as a seeder by the tracker.
+    // https://www.bittorrent.org/beps/bep_0021.html
+    Paused,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RequestParams {
-    info_hash: Sha1Hash,
-    peer_id: PeerId,
-    ip: Option<String>,
-    port: u16,
-    uploaded: u64,
-    downloaded: u64,
-    left: u64,
-    event: Option<TrackerEvent>,
+    pub(crate) info_hash: Sha1Hash,
+    pub(crate) peer_id: PeerId,
+    pub(crate) ip: Option<String>,
+    pub(crate) port: u16,
+    pub(crate) uploaded: u64,
+    pub(crate) downloaded: u64,
+    pub(crate) left: u64,
+    pub(crate) event: Option<TrackerEvent>,
     // If true, the peers are returned in compact format
     // https://www.bittorrent.org/beps/bep_0023.html
-    compact: bool,
+    pub(crate) compact: bool,
+    // Our addresses of the other IP version, so the tracker hands them to the peers
+    // which can't see them from the announce.
+    // https://www.bittorrent.org/beps/bep_0007.html
+    pub(crate) ipv4: Option<Ipv4Addr>,
+    pub(crate) ipv6: Option<Ipv6Addr>,
+    // How many peers we want, the tracker picks if None.
+    pub(crate) numwant: Option<u32>,
+    // Identifies us to the tracker when our IP address changes, keep it for the whole session.
+    pub(crate) key: Option<u32>,
+    // Overrides the tracker id the tracker gave us, see `Tracker::tracker_id`.
+    pub(crate) tracker_id: Option<String>,
+}
+
+impl RequestParams {
+    // The first announce of a torrent, asking for the peers in compact format.
+    pub fn new(info_hash: Sha1Hash, peer_id: PeerId, port: u16, left: u64) -> Self {
+        Self {
+            info_hash,
+            peer_id,
+            ip: None,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: Some(TrackerEvent::Started),
+            compact: true,
+            ipv4: None,
+            ipv6: None,
+            numwant: None,
+            key: None,
+            tracker_id: None,
+        }
+    }
+
+    // We only want some of the files and have all of them, see `Torrent::is_partial_seed`.
+    pub fn as_partial_seed(mut self) -> Self {
+        self.event = Some(TrackerEvent::Paused);
+        self
+    }
+
+    // The regular announces in between have no event.
+    pub fn with_event(mut self, event: Option<TrackerEvent>) -> Self {
+        self.event = event;
+        self
+    }
+
+    pub fn with_transfer(mut self, uploaded: u64, downloaded: u64) -> Self {
+        self.uploaded = uploaded;
+        self.downloaded = downloaded;
+        self
+    }
+
+    pub fn with_ip_addresses(mut self, ipv4: Option<Ipv4Addr>, ipv6: Option<Ipv6Addr>) -> Self {
+        self.ipv4 = ipv4;
+        self.ipv6 = ipv6;
+        self
+    }
+
+    pub fn with_numwant(mut self, numwant: u32) -> Self {
+        self.numwant = Some(numwant);
+        self
+    }
+
+    pub fn with_key(mut self, key: u32) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn with_tracker_id(mut self, tracker_id: String) -> Self {
+        self.tracker_id = Some(tracker_id);
+        self
+    }
+}
+
+// The public addresses of this host, found by the route to a public address of each IP
+// version, nothing is sent. The private and link local addresses are useless to the peers.
+pub fn local_ip_addresses() -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+    let route = |bind: &str, target: &str| {
+        let socket = UdpSocket::bind(bind).ok()?;
+        socket.connect(target).ok()?;
+        socket.local_addr().ok().map(|it| it.ip())
+    };
+    let ipv4 = match route("0.0.0.0:0", "198.51.100.1:80") {
+        Some(IpAddr::V4(ip)) if !ip.is_private() && !ip.is_loopback() && !ip.is_link_local() => {
+            Some(ip)
+        }
+        _ => None,
+    };
+    let ipv6 = match route("[::]:0", "[2001:db8::1]:80") {
+        Some(IpAddr::V6(ip))
+            if !ip.is_loopback() && !ip.is_unicast_link_local() && !ip.is_unique_local() =>
+        {
+            Some(ip)
+        }
+        _ => None,
+    };
+    (ipv4, ipv6)
 }
 
 mod raw {
     use super::*;
-    use std::net::{IpAddr, Ipv4Addr};
+    use std::net::IpAddr;
 
-    use bytes::Buf;
     use serde::{Deserialize, Serialize};
 
     #[derive(Debug, Serialize, Deserialize)]
@@ -85,7 +266,25 @@ mod raw {
     #[derive(Debug, Serialize, Deserialize)]
     pub struct SuccessResponse {
         pub interval: u64,
-        pub peers: Peer,
+        #[serde(default, rename = "min interval")]
+        pub min_interval: Option<u64>,
+        #[serde(default, rename = "warning message")]
+        pub warning_message: Option<String>,
+        // Left out by the trackers only returning IPv6 peers.
+        #[serde(default)]
+        pub peers: Option<Peer>,
+        // The IPv6 peers in compact format, 18 bytes each.
+        #[serde(default)]
+        pub peers6: Option<serde_bytes::ByteBuf>,
+        #[serde(default)]
+        pub complete: Option<u32>,
+        #[serde(default)]
+        pub incomplete: Option<u32>,
+        #[serde(default, rename = "tracker id")]
+        pub tracker_id: Option<String>,
+        // 4 or 16 bytes of the IPv4 or IPv6 address.
+        #[serde(default, rename = "external ip")]
+        pub external_ip: Option<serde_bytes::ByteBuf>,
     }
 
     #[derive(Debug, Serialize, Deserialize)]
@@ -119,23 +318,33 @@ mod raw {
                 // in compact format, each peer is represented by 6 bytes:
                 // 4 bytes for the IPv4 address and 2 bytes for the port number
                 // https://www.bittorrent.org/beps/bep_0023.html
-                Peer::Compact(bytes) => {
-                    let mut peers = Vec::new();
-
-                    for mut chunk in bytes.chunks(6) {
-                        if chunk.len() == 6 {
-                            let ip = Ipv4Addr::from(chunk.get_u32());
-                            let port = chunk.get_u16();
-                            let addr = SocketAddr::new(IpAddr::V4(ip), port);
-                            peers.push(addr);
-                        }
-                    }
-                    peers
-                }
+                Peer::Compact(bytes) => decode_peers(bytes, 4),
             })
         }
     }
 
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(untagged)]
+    pub enum ScrapeResponse {
+        Success(ScrapeSuccessResponse),
+        Error(ErrorResponse),
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct ScrapeSuccessResponse {
+        // Keyed by the 20 bytes info hash
+        pub files: HashMap<serde_bytes::ByteBuf, ScrapeFile>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct ScrapeFile {
+        pub complete: u32,
+        pub incomplete: u32,
+        // Some trackers leave it out
+        #[serde(default)]
+        pub downloaded: u32,
+    }
+
     #[derive(Debug, Serialize, Deserialize)]
     pub struct ErrorResponse {
         #[serde(rename = "failure reason")]
@@ -145,11 +354,122 @@ mod raw {
 
 impl Tracker {
     pub fn new(url: Url) -> Self {
-        let client = Client::new();
-        Self { client, url }
+        let client = ClientIdentity::default()
+            .http_client(None)
+            .expect("Failed to build HTTP client");
+        Self {
+            client,
+            udp: udp_tracker(&url, None),
+            url,
+            throttle: None,
+            cache: None,
+            tracker_id: Mutex::new(None),
+        }
+    }
+
+    // Create a tracker which sends the announce from the given local address,
+    // so the announce goes through the same interface as the peer connections of the torrent.
+    pub fn with_local_addr(url: Url, local_addr: Option<IpAddr>) -> Result<Self> {
+        Self::with_identity(url, local_addr, &ClientIdentity::default())
+    }
+
+    // Announce as the client of the identity, see `ClientIdentity`.
+    pub fn with_identity(
+        url: Url,
+        local_addr: Option<IpAddr>,
+        identity: &ClientIdentity,
+    ) -> Result<Self> {
+        let client = identity.http_client(local_addr)?;
+        Ok(Self {
+            client,
+            udp: udp_tracker(&url, local_addr),
+            url,
+            throttle: None,
+            cache: None,
+            tracker_id: Mutex::new(None),
+        })
+    }
+
+    // Announce with the TLS settings of the tracker host, see `TrackerTls`.
+    pub fn with_tls(
+        url: Url,
+        local_addr: Option<IpAddr>,
+        identity: &ClientIdentity,
+        tls: &[TrackerTls],
+    ) -> Result<Self> {
+        let client =
+            identity.http_client_with_tls(local_addr, None, TrackerTls::find(tls, &url))?;
+        Ok(Self {
+            client,
+            udp: udp_tracker(&url, local_addr),
+            url,
+            throttle: None,
+            cache: None,
+            tracker_id: Mutex::new(None),
+        })
+    }
+
+    // Announce through the proxy, independently of the peer traffic. The socks5h proxy
+    // resolves the tracker host too, so the DNS lookup doesn't leak either.
+    pub fn with_proxy(
+        url: Url,
+        local_addr: Option<IpAddr>,
+        identity: &ClientIdentity,
+        proxy: &str,
+    ) -> Result<Self> {
+        let proxy = Url::parse(proxy)
+            .ok()
+            .filter(|it| matches!(it.scheme(), "http" | "https" | "socks5" | "socks5h"))
+            .ok_or_else(|| TrackerError::InvalidProxy(proxy.to_string()))?;
+        // Neither kind of proxy relays the UDP packets
+        if url.scheme() == "udp" {
+            return Err(TrackerError::UdpThroughProxy);
+        }
+        let client = identity.http_client_via(local_addr, Some(&proxy))?;
+        Ok(Self {
+            client,
+            udp: None,
+            url,
+            throttle: None,
+            cache: None,
+            tracker_id: Mutex::new(None),
+        })
+    }
+
+    pub fn set_throttle(&mut self, throttle: Arc<AnnounceThrottle>) {
+        self.throttle = Some(throttle);
+    }
+
+    pub fn set_cache(&mut self, cache: Arc<AnnounceCache>) {
+        self.cache = Some(cache);
+    }
+
+    // The tracker id from the last response which had one, it's sent in the next announces.
+    pub fn tracker_id(&self) -> Option<String> {
+        self.tracker_id.lock().unwrap().clone()
     }
 
     pub async fn fetch_peers(&self, params: RequestParams) -> Result<Response> {
+        let info_hash = params.info_hash;
+        let result = self.announce(params).await;
+        if let Some(cache) = &self.cache {
+            match &result {
+                Ok(response) => cache.record_success(info_hash, &self.url, &response.peers),
+                Err(_) => cache.record_failure(info_hash, &self.url),
+            }
+        }
+        result
+    }
+
+    async fn announce(&self, params: RequestParams) -> Result<Response> {
+        let _permit = match &self.throttle {
+            Some(throttle) => Some(throttle.acquire(&self.url).await),
+            None => None,
+        };
+        if let Some(udp) = &self.udp {
+            return udp.announce(params).await;
+        }
+
         let mut query = vec![
             ("port", params.port.to_string()),
             ("uploaded", params.uploaded.to_string()),
@@ -161,6 +481,21 @@ impl Tracker {
         if let Some(ip) = params.ip {
             query.push(("ip", ip));
         }
+        if let Some(ipv4) = params.ipv4 {
+            query.push(("ipv4", ipv4.to_string()));
+        }
+        if let Some(ipv6) = params.ipv6 {
+            query.push(("ipv6", ipv6.to_string()));
+        }
+        if let Some(numwant) = params.numwant {
+            query.push(("numwant", numwant.to_string()));
+        }
+        if let Some(key) = params.key {
+            query.push(("key", format!("{:08x}", key)));
+        }
+        if let Some(tracker_id) = params.tracker_id.or_else(|| self.tracker_id()) {
+            query.push(("trackerid", tracker_id));
+        }
 
         if let Some(event) = params.event {
             let event_str = match event {
@@ -168,6 +503,7 @@ impl Tracker {
                 TrackerEvent::Stopped => "stopped",
                 TrackerEvent::Completed => "completed",
                 TrackerEvent::Empty => "",
+                TrackerEvent::Paused => "paused",
             };
             query.push(("event", event_str.to_string()));
         }
@@ -190,17 +526,105 @@ impl Tracker {
             .error_for_status()?
             .bytes()
             .await?;
+        let response = parse_announce_response(&resp)?;
+        // A response without one keeps the previous one
+        if let Some(tracker_id) = &response.tracker_id {
+            *self.tracker_id.lock().unwrap() = Some(tracker_id.clone());
+        }
+        Ok(response)
+    }
 
-        match serde_bencode::from_bytes::<raw::Response>(&resp) {
-            Ok(resp) => match resp {
-                raw::Response::Success(resp) => Ok(Response {
-                    interval: resp.interval,
-                    peers: resp.peers.to_vec()?,
-                }),
-                raw::Response::Error(e) => Err(TrackerError::QueryPeers(e.failure_reason)),
-            },
-            Err(e) => Err(TrackerError::Bencode(e)),
+    // Ask the tracker for the swarm size of the torrents without announcing to it, the
+    // udp:// trackers as in BEP 15.
+    // https://www.bittorrent.org/beps/bep_0048.html
+    pub async fn scrape(&self, info_hashes: &[Sha1Hash]) -> Result<HashMap<Sha1Hash, ScrapeStats>> {
+        let _permit = match &self.throttle {
+            Some(throttle) => Some(throttle.acquire(&self.url).await),
+            None => None,
+        };
+        if let Some(udp) = &self.udp {
+            return udp.scrape(info_hashes).await;
+        }
+        let url = scrape_url(&self.url).ok_or(TrackerError::ScrapeUnsupported)?;
+
+        // The info hashes are binary, encode them ourselves as in the announce.
+        let mut url = url.to_string();
+        for info_hash in info_hashes {
+            url.push(if url.contains('?') { '&' } else { '?' });
+            url.push_str("info_hash=");
+            url.push_str(&percent_encode(info_hash, URL_ENCODE_RESERVED).to_string());
         }
+
+        let resp = self
+            .client
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        parse_scrape_response(&resp)
+    }
+}
+
+fn udp_tracker(url: &Url, local_addr: Option<IpAddr>) -> Option<UdpTracker> {
+    (url.scheme() == "udp").then(|| UdpTracker::new(url.clone(), local_addr))
+}
+
+fn parse_announce_response(bytes: &[u8]) -> Result<Response> {
+    match serde_bencode::from_bytes::<raw::Response>(bytes)? {
+        raw::Response::Success(resp) => {
+            let mut peers = match resp.peers {
+                Some(peers) => peers.to_vec()?,
+                None => Vec::new(),
+            };
+            if let Some(peers6) = resp.peers6 {
+                peers.extend(decode_peers(&peers6, 16));
+            }
+            Ok(Response {
+                interval: resp.interval,
+                min_interval: resp.min_interval,
+                warning: resp.warning_message,
+                peers,
+                seeders: resp.complete,
+                leechers: resp.incomplete,
+                tracker_id: resp.tracker_id,
+                external_ip: resp.external_ip.and_then(|it| decode_ip(&it)),
+            })
+        }
+        raw::Response::Error(e) => Err(TrackerError::QueryPeers(e.failure_reason)),
+    }
+}
+
+// The scrape URL is the announce URL with the "announce" at the start of the last path segment
+// replaced by "scrape", None if the tracker doesn't follow the convention.
+fn scrape_url(announce: &Url) -> Option<Url> {
+    let path = announce.path();
+    let (directory, last_segment) = path.rsplit_once('/')?;
+    let rest = last_segment.strip_prefix("announce")?;
+    let mut url = announce.clone();
+    url.set_path(&format!("{}/scrape{}", directory, rest));
+    Some(url)
+}
+
+fn parse_scrape_response(bytes: &[u8]) -> Result<HashMap<Sha1Hash, ScrapeStats>> {
+    match serde_bencode::from_bytes::<raw::ScrapeResponse>(bytes)? {
+        raw::ScrapeResponse::Success(resp) => Ok(resp
+            .files
+            .into_iter()
+            .filter_map(|(info_hash, file)| {
+                let info_hash = Sha1Hash::try_from(info_hash.as_slice()).ok()?;
+                Some((
+                    info_hash,
+                    ScrapeStats {
+                        seeders: file.complete,
+                        leechers: file.incomplete,
+                        completed: file.downloaded,
+                    },
+                ))
+            })
+            .collect()),
+        raw::ScrapeResponse::Error(e) => Err(TrackerError::QueryPeers(e.failure_reason)),
     }
 }
 
@@ -210,6 +634,112 @@ mod tests {
 
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[tokio::test]
+    async fn test_announce_through_proxy() {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = format!("http://{}", proxy.local_addr().unwrap());
+        let serve = tokio::spawn(async move {
+            let (mut stream, _) = proxy.accept().await.unwrap();
+            let mut request = vec![0u8; 4096];
+            let n = stream.read(&mut request).await.unwrap();
+            let body = b"d8:intervali1800e5:peers0:e";
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+            String::from_utf8_lossy(&request[..n]).to_string()
+        });
+
+        let url = Url::parse("http://tracker.invalid/announce").unwrap();
+        let identity = ClientIdentity::default();
+        let tracker = Tracker::with_proxy(url, None, &identity, &proxy_url).unwrap();
+        let response = tracker
+            .fetch_peers(RequestParams::new([1; 20], [2; 20], 6881, 0))
+            .await
+            .unwrap();
+        assert_eq!(response.interval, 1800);
+        // The proxy is asked for the whole URL of the tracker
+        let request = serve.await.unwrap();
+        assert!(request.starts_with("GET http://tracker.invalid/announce?"));
+
+        let url = Url::parse("http://tracker.invalid/announce").unwrap();
+        assert!(matches!(
+            Tracker::with_proxy(url, None, &identity, "ftp://proxy:21"),
+            Err(TrackerError::InvalidProxy(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retain_tracker_id() {
+        let mut server = mockito::Server::new_async().await;
+        let first = server
+            .mock("GET", "/announce")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("numwant".into(), "100".into()),
+                mockito::Matcher::UrlEncoded("key".into(), "0000beef".into()),
+            ]))
+            .with_body(b"d8:intervali1800e5:peers0:10:tracker id3:abce")
+            .expect(1)
+            .create_async()
+            .await;
+        let url = Url::parse(&format!("{}/announce", server.url())).unwrap();
+        let tracker = Tracker::new(url);
+        let params = RequestParams::new([1; 20], [2; 20], 6881, 0)
+            .with_numwant(100)
+            .with_key(0xbeef);
+        let response = tracker.fetch_peers(params.clone()).await.unwrap();
+        assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+        assert_eq!(tracker.tracker_id().as_deref(), Some("abc"));
+        first.assert_async().await;
+
+        // Sent back in the next announce, and kept when the response has none
+        let second = server
+            .mock("GET", "/announce")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "trackerid".into(),
+                "abc".into(),
+            ))
+            .with_body(b"d8:intervali1800e5:peers0:e")
+            .expect(2)
+            .create_async()
+            .await;
+        tracker.fetch_peers(params.clone()).await.unwrap();
+        tracker.fetch_peers(params).await.unwrap();
+        assert_eq!(tracker.tracker_id().as_deref(), Some("abc"));
+        second.assert_async().await;
+    }
+
+    #[test]
+    fn test_tracker_tls() {
+        let identity = ClientIdentity::default();
+        let tls = vec![TrackerTls {
+            host: "Private.Example".to_string(),
+            root_certificates: Vec::new(),
+            accept_invalid_certs: true,
+        }];
+        let url = Url::parse("https://private.example/announce").unwrap();
+        assert_eq!(TrackerTls::find(&tls, &url), Some(&tls[0]));
+        let other = Url::parse("https://public.example/announce").unwrap();
+        assert_eq!(TrackerTls::find(&tls, &other), None);
+        assert!(Tracker::with_tls(url.clone(), None, &identity, &tls).is_ok());
+
+        let tls = vec![TrackerTls {
+            root_certificates: vec!["not a certificate".to_string()],
+            ..tls[0].clone()
+        }];
+        assert!(matches!(
+            Tracker::with_tls(url, None, &identity, &tls),
+            Err(TrackerError::Http(_))
+        ));
+    }
+
     #[test]
     fn test_compact_peer_to_vec() {
         // 2 peers: 192.168.1.1:6881 and 10.0.0.2:51413
@@ -233,4 +763,92 @@ mod tests {
             assert_eq!(peer, expected_addr);
         }
     }
+
+    #[test]
+    fn test_parse_peers6() {
+        let mut bytes = b"d8:intervali1800e5:peers6:".to_vec();
+        bytes.extend_from_slice(&[10, 0, 0, 2, 200, 213]);
+        bytes.extend_from_slice(b"6:peers618:");
+        bytes.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        bytes.extend_from_slice(&6881u16.to_be_bytes());
+        bytes.push(b'e');
+        let response = parse_announce_response(&bytes).unwrap();
+        assert_eq!(
+            response.peers,
+            vec![
+                "10.0.0.2:51413".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:6881".parse().unwrap(),
+            ]
+        );
+
+        // An IPv6 only tracker
+        let mut bytes = b"d8:intervali1800e6:peers618:".to_vec();
+        bytes.extend_from_slice(&[0; 18]);
+        bytes.push(b'e');
+        assert_eq!(parse_announce_response(&bytes).unwrap().peers.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_min_interval_and_warning() {
+        let bytes =
+            b"d8:intervali1800e12:min intervali900e5:peers0:15:warning message10:low ratio!e";
+        let response = parse_announce_response(bytes).unwrap();
+        assert_eq!(response.min_interval, Some(900));
+        assert_eq!(response.warning.as_deref(), Some("low ratio!"));
+
+        let response = parse_announce_response(b"d8:intervali1800e5:peers0:e").unwrap();
+        assert_eq!(response.min_interval, None);
+        assert_eq!(response.warning, None);
+    }
+
+    #[test]
+    fn test_parse_external_ip() {
+        let response =
+            parse_announce_response(b"d8:intervali1800e5:peers0:11:external ip4:\x05\x06\x07\x08e")
+                .unwrap();
+        assert_eq!(response.external_ip, Some("5.6.7.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        let cases = [
+            (
+                "http://example.com/announce",
+                Some("http://example.com/scrape"),
+            ),
+            (
+                "http://example.com/x/announce",
+                Some("http://example.com/x/scrape"),
+            ),
+            (
+                "http://example.com/announce.php?passkey=abc",
+                Some("http://example.com/scrape.php?passkey=abc"),
+            ),
+            ("http://example.com/a", None),
+            ("http://example.com/announce/x", None),
+        ];
+        for (announce, expected) in cases {
+            let url = scrape_url(&Url::parse(announce).unwrap());
+            assert_eq!(url.as_ref().map(Url::as_str), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_scrape_response() {
+        let mut bytes = b"d5:filesd20:".to_vec();
+        bytes.extend_from_slice(&[0xaa; 20]);
+        bytes.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let stats = parse_scrape_response(&bytes).unwrap();
+        assert_eq!(
+            stats.get(&[0xaa; 20]),
+            Some(&ScrapeStats {
+                seeders: 5,
+                leechers: 10,
+                completed: 50,
+            })
+        );
+
+        let error = parse_scrape_response(b"d14:failure reason6:deniede").unwrap_err();
+        assert!(matches!(error, TrackerError::QueryPeers(reason) if reason == "denied"));
+    }
 }
@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// The bytes a torrent sent to and received from its peers since it started. The sessions count
+// into it without locking the torrent, the announces report it to the trackers.
+#[derive(Debug, Default)]
+pub struct TransferTotals {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+}
+
+impl TransferTotals {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_upload(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_download(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+}
+
+// The totals at one moment, e.g. taken at shutdown before the sessions close, so the stopped
+// announce reports everything up to the end without waiting on the torrent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferSnapshot {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_count_from_sessions() {
+        let totals = Arc::new(TransferTotals::new());
+        let sessions: Vec<_> = (0..4)
+            .map(|_| {
+                let totals = totals.clone();
+                tokio::spawn(async move {
+                    for _ in 0..100 {
+                        totals.record_upload(16384);
+                        totals.record_download(1000);
+                    }
+                })
+            })
+            .collect();
+        for session in sessions {
+            session.await.unwrap();
+        }
+        assert_eq!(totals.uploaded(), 4 * 100 * 16384);
+        assert_eq!(totals.downloaded(), 4 * 100 * 1000);
+    }
+}
@@ -0,0 +1,377 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::future::{BoxFuture, FutureExt, select_ok};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpSocket, TcpStream},
+};
+use tokio_native_tls::TlsStream;
+
+use crate::{
+    mse::EncryptedStream,
+    utp::{UtpSocket, UtpStream},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Utp,
+}
+
+// How to choose the transport when dialing a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportMode {
+    #[default]
+    TcpOnly,
+    UtpOnly,
+    // Try uTP first, fallback to TCP if it fails.
+    PreferUtp,
+    // Dial both at the same time, keep the first connection which succeeds.
+    Race,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TransportPolicy {
+    pub mode: TransportMode,
+    // uTP backs off when the link is congested but TCP doesn't, so the TCP peers can fill
+    // the router buffer and hurt the latency of everything else on the network.
+    // When enabled, TCP is only dialed after uTP failed, never raced with it.
+    pub limit_tcp_when_utp: bool,
+}
+
+impl TransportPolicy {
+    fn effective_mode(&self) -> TransportMode {
+        match self.mode {
+            TransportMode::Race if self.limit_tcp_when_utp => TransportMode::PreferUtp,
+            mode => mode,
+        }
+    }
+
+    pub async fn connect(
+        &self,
+        addr: SocketAddr,
+        local_addr: Option<IpAddr>,
+    ) -> io::Result<PeerStream> {
+        self.connect_from(addr, local_addr, &[]).await
+    }
+
+    // Same as `connect`, the uTP peers are dialed from the socket in `utp` bound to the local
+    // address, e.g. the one the listener is on. A socket is bound for the dial if none is.
+    pub async fn connect_from(
+        &self,
+        addr: SocketAddr,
+        local_addr: Option<IpAddr>,
+        utp: &[UtpSocket],
+    ) -> io::Result<PeerStream> {
+        match self.effective_mode() {
+            TransportMode::TcpOnly => Ok(PeerStream::Tcp(connect_tcp(addr, local_addr).await?)),
+            TransportMode::UtpOnly => connect_utp(addr, local_addr, utp).await,
+            TransportMode::PreferUtp => match connect_utp(addr, local_addr, utp).await {
+                Ok(stream) => Ok(stream),
+                Err(e) => {
+                    log::debug!(
+                        "Failed to connect {} over uTP, fallback to TCP: {:?}",
+                        addr,
+                        e
+                    );
+                    Ok(PeerStream::Tcp(connect_tcp(addr, local_addr).await?))
+                }
+            },
+            TransportMode::Race => {
+                let attempts: Vec<BoxFuture<io::Result<PeerStream>>> = vec![
+                    connect_utp(addr, local_addr, utp).boxed(),
+                    async move { Ok(PeerStream::Tcp(connect_tcp(addr, local_addr).await?)) }
+                        .boxed(),
+                ];
+                // The slower attempt is dropped, which closes its connection.
+                let (stream, _) = select_ok(attempts).await?;
+                Ok(stream)
+            }
+        }
+    }
+}
+
+// Where the peer connections and the listener bind, for the VPN users whose traffic must not
+// leave the tunnel. The address wins over the interface if both are set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BindSettings {
+    pub address: Option<IpAddr>,
+    // The name, e.g. tun0 or wg0.
+    pub interface: Option<String>,
+}
+
+impl BindSettings {
+    // The local address of the family to bind to, None lets the OS choose. The interface is
+    // looked up each time, its address changes when the VPN reconnects. Fails if the interface
+    // is gone or has no address of the family, rather than going out of another one.
+    pub fn local_addr(&self, ipv6: bool) -> io::Result<Option<IpAddr>> {
+        if let Some(address) = self.address {
+            return Ok(Some(address));
+        }
+        let Some(interface) = &self.interface else {
+            return Ok(None);
+        };
+        let interfaces = local_ip_address::list_afinet_netifas().map_err(io::Error::other)?;
+        interfaces
+            .into_iter()
+            .find(|(name, ip)| name == interface && ip.is_ipv6() == ipv6)
+            .map(|(_, ip)| Some(ip))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("Interface {} has no address to bind to", interface),
+                )
+            })
+    }
+}
+
+// A connection to a peer, regardless of the transport.
+#[derive(Debug)]
+pub enum PeerStream {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+    // After the encryption handshake, over either transport.
+    Encrypted(Box<EncryptedStream>),
+    // The peers of an SSL torrent, see `peer_tls`.
+    Tls(Box<TlsStream<PeerStream>>),
+}
+
+impl PeerStream {
+    pub fn transport(&self) -> Transport {
+        match self {
+            PeerStream::Tcp(_) => Transport::Tcp,
+            PeerStream::Utp(_) => Transport::Utp,
+            PeerStream::Encrypted(stream) => stream.inner().transport(),
+            PeerStream::Tls(stream) => stream.get_ref().get_ref().get_ref().transport(),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            PeerStream::Tcp(stream) => stream.local_addr(),
+            PeerStream::Utp(stream) => stream.local_addr(),
+            PeerStream::Encrypted(stream) => stream.inner().local_addr(),
+            PeerStream::Tls(stream) => stream.get_ref().get_ref().get_ref().local_addr(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            PeerStream::Tcp(stream) => stream.peer_addr(),
+            PeerStream::Utp(stream) => stream.peer_addr(),
+            PeerStream::Encrypted(stream) => stream.inner().peer_addr(),
+            PeerStream::Tls(stream) => stream.get_ref().get_ref().get_ref().peer_addr(),
+        }
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerStream::Encrypted(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            PeerStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+            PeerStream::Encrypted(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            PeerStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_flush(cx),
+            PeerStream::Encrypted(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            PeerStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerStream::Encrypted(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            PeerStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+async fn connect_tcp(addr: SocketAddr, local_addr: Option<IpAddr>) -> io::Result<TcpStream> {
+    let Some(local_addr) = local_addr else {
+        return TcpStream::connect(addr).await;
+    };
+    if local_addr.is_ipv4() != addr.is_ipv4() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Local address and peer address are not the same IP version",
+        ));
+    }
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.bind(SocketAddr::new(local_addr, 0))?;
+    socket.connect(addr).await
+}
+
+async fn connect_utp(
+    addr: SocketAddr,
+    local_addr: Option<IpAddr>,
+    utp: &[UtpSocket],
+) -> io::Result<PeerStream> {
+    let local_addr = match local_addr {
+        Some(local_addr) if local_addr.is_ipv4() != addr.is_ipv4() => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Local address and peer address are not the same IP version",
+            ));
+        }
+        Some(local_addr) => local_addr,
+        None if addr.is_ipv4() => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        None => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    // The peer sees the port we listen on, and may dial it back later
+    let shared = utp
+        .iter()
+        .find(|it| it.local_addr().is_ok_and(|it| it.ip() == local_addr));
+    if let Some(socket) = shared {
+        return Ok(PeerStream::Utp(socket.connect(addr).await?));
+    }
+    // The socket keeps running until the connection is closed.
+    let socket = UtpSocket::bind(SocketAddr::new(local_addr, 0)).await?;
+    Ok(PeerStream::Utp(socket.connect(addr).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_bind_settings() {
+        assert_eq!(BindSettings::default().local_addr(false).unwrap(), None);
+
+        let address = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let settings = BindSettings {
+            address: Some(address),
+            interface: Some("missing0".to_string()),
+        };
+        assert_eq!(settings.local_addr(false).unwrap(), Some(address));
+
+        let settings = BindSettings {
+            address: None,
+            interface: Some("missing0".to_string()),
+        };
+        let error = settings.local_addr(false).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+
+    #[tokio::test]
+    async fn test_connect_tcp_with_local_addr() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let local_addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let stream = connect_tcp(addr, Some(local_addr)).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), local_addr);
+
+        let (_, peer_addr) = listener.accept().await.unwrap();
+        assert_eq!(peer_addr.ip(), local_addr);
+    }
+
+    #[tokio::test]
+    async fn test_connect_tcp_with_mismatched_local_addr() {
+        let addr: SocketAddr = "[::1]:6881".parse().unwrap();
+        let result = connect_tcp(addr, Some(IpAddr::V4(Ipv4Addr::LOCALHOST))).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_policy_fallback_to_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        for mode in [TransportMode::PreferUtp, TransportMode::Race] {
+            let policy = TransportPolicy {
+                mode,
+                limit_tcp_when_utp: false,
+            };
+            let stream = policy.connect(addr, None).await.unwrap();
+            assert_eq!(stream.transport(), Transport::Tcp);
+            listener.accept().await.unwrap();
+        }
+
+        let policy = TransportPolicy {
+            mode: TransportMode::UtpOnly,
+            limit_tcp_when_utp: false,
+        };
+        assert!(policy.connect(addr, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_from_shared_utp_socket() {
+        let peer = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let accept = tokio::spawn(async move { peer.accept().await.unwrap() });
+        let shared = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let policy = TransportPolicy {
+            mode: TransportMode::UtpOnly,
+            limit_tcp_when_utp: false,
+        };
+        let local_addr = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let stream = policy
+            .connect_from(peer_addr, local_addr, std::slice::from_ref(&shared))
+            .await
+            .unwrap();
+        // From the port of the shared socket
+        assert_eq!(stream.local_addr().unwrap(), shared.local_addr().unwrap());
+        assert_eq!(
+            accept.await.unwrap().peer_addr().unwrap(),
+            shared.local_addr().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_limit_tcp_when_utp() {
+        let policy = TransportPolicy {
+            mode: TransportMode::Race,
+            limit_tcp_when_utp: true,
+        };
+        assert_eq!(policy.effective_mode(), TransportMode::PreferUtp);
+
+        let policy = TransportPolicy {
+            mode: TransportMode::TcpOnly,
+            limit_tcp_when_utp: true,
+        };
+        assert_eq!(policy.effective_mode(), TransportMode::TcpOnly);
+    }
+}
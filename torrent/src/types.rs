@@ -2,6 +2,9 @@ use bitvec::{order::Msb0, vec::BitVec};
 
 pub type Sha1Hash = [u8; 20];
 
+// The hashes of BitTorrent v2, https://www.bittorrent.org/beps/bep_0052.html
+pub type Sha256Hash = [u8; 32];
+
 pub type PeerId = [u8; 20];
 
 // Represents which pieces exists for a peer.
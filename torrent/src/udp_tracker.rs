@@ -0,0 +1,478 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    net::{UdpSocket, lookup_host},
+    sync::Mutex,
+    time::timeout_at,
+};
+use url::Url;
+
+use crate::{
+    compact::decode_peers,
+    tracker::{RequestParams, Response, Result, ScrapeStats, TrackerError, TrackerEvent},
+    types::Sha1Hash,
+};
+
+// Announce to and scrape the trackers of the udp:// URLs, one round-trip less than HTTP once
+// connected.
+// https://www.bittorrent.org/beps/bep_0015.html
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+// The tracker accepts the connection id for a minute after it's given out.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+// Wait 15 * 2 ^ n seconds for the n-th retransmission, up to the 8th.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_RETRANSMISSIONS: u32 = 8;
+const MAX_PACKET_SIZE: usize = 2048;
+// The info hashes which fit a scrape request, the tracker answers 12 bytes for each.
+const MAX_SCRAPE_HASHES: usize = 74;
+
+pub struct UdpTracker {
+    url: Url,
+    local_addr: Option<IpAddr>,
+    // Bound on the first announce, the requests take turns so the responses can't mix up.
+    socket: Mutex<Option<UdpSocket>>,
+    connection: std::sync::Mutex<Option<Connection>>,
+    retransmit_timeout: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Connection {
+    id: u64,
+    connected_at: Instant,
+}
+
+impl UdpTracker {
+    pub fn new(url: Url, local_addr: Option<IpAddr>) -> Self {
+        Self {
+            url,
+            local_addr,
+            socket: Mutex::new(None),
+            connection: std::sync::Mutex::new(None),
+            retransmit_timeout: RETRANSMIT_TIMEOUT,
+        }
+    }
+
+    pub async fn announce(&self, params: RequestParams) -> Result<Response> {
+        let (response, is_ipv6) = self
+            .request(|connection_id, transaction_id| {
+                announce_request(connection_id, transaction_id, &params)
+            })
+            .await?;
+        parse_announce_response(&response, is_ipv6)
+    }
+
+    // The swarm size of the torrents, the info hashes beyond what fits a packet are left out.
+    pub async fn scrape(&self, info_hashes: &[Sha1Hash]) -> Result<HashMap<Sha1Hash, ScrapeStats>> {
+        let info_hashes = &info_hashes[..info_hashes.len().min(MAX_SCRAPE_HASHES)];
+        let (response, _) = self
+            .request(|connection_id, transaction_id| {
+                scrape_request(connection_id, transaction_id, info_hashes)
+            })
+            .await?;
+        parse_scrape_response(&response, info_hashes)
+    }
+
+    // Connect if the connection id expired, then send the request built with it. Returns the
+    // response, and whether we talk to the tracker over IPv6.
+    async fn request(&self, build: impl Fn(u64, u32) -> Vec<u8>) -> Result<(Vec<u8>, bool)> {
+        let mut socket = self.socket.lock().await;
+        let socket = match socket.as_mut() {
+            Some(socket) => socket,
+            None => socket.insert(self.bind().await?),
+        };
+        let is_ipv6 = socket.peer_addr().is_ok_and(|it| it.is_ipv6());
+
+        // The retransmissions of the connect and the request count together
+        let mut attempt = 0;
+        loop {
+            let connection_id = match self.connection_id() {
+                Some(id) => id,
+                None => {
+                    let transaction_id = rand::random();
+                    let request = connect_request(transaction_id);
+                    let Some(response) = self
+                        .transact(socket, &request, transaction_id, attempt)
+                        .await?
+                    else {
+                        attempt = self.next_attempt(attempt)?;
+                        continue;
+                    };
+                    let id = parse_connect_response(&response)?;
+                    *self.connection.lock().unwrap() = Some(Connection {
+                        id,
+                        connected_at: Instant::now(),
+                    });
+                    id
+                }
+            };
+            let transaction_id = rand::random();
+            let request = build(connection_id, transaction_id);
+            match self
+                .transact(socket, &request, transaction_id, attempt)
+                .await?
+            {
+                Some(response) => return Ok((response, is_ipv6)),
+                // The connection id may expire in the meantime, it's checked again
+                None => attempt = self.next_attempt(attempt)?,
+            }
+        }
+    }
+
+    // The cached connection id, None if it's expired.
+    fn connection_id(&self) -> Option<u64> {
+        let connection = self.connection.lock().unwrap();
+        connection
+            .filter(|it| it.connected_at.elapsed() < CONNECTION_ID_LIFETIME)
+            .map(|it| it.id)
+    }
+
+    fn next_attempt(&self, attempt: u32) -> Result<u32> {
+        if attempt >= MAX_RETRANSMISSIONS {
+            return Err(TrackerError::Timeout);
+        }
+        Ok(attempt + 1)
+    }
+
+    async fn bind(&self) -> Result<UdpSocket> {
+        let host = self
+            .url
+            .host_str()
+            .ok_or_else(|| TrackerError::QueryPeers(format!("Invalid tracker {}", self.url)))?;
+        let port = self
+            .url
+            .port()
+            .ok_or_else(|| TrackerError::QueryPeers(format!("No port in tracker {}", self.url)))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addr = lookup_host((host, port))
+            .await
+            .map_err(io_error)?
+            // Send from the address the torrent is bound to, which has one IP version
+            .find(|it| {
+                self.local_addr
+                    .is_none_or(|ip| ip.is_ipv4() == it.is_ipv4())
+            })
+            .ok_or_else(|| TrackerError::QueryPeers(format!("Failed to resolve {}", host)))?;
+        let local_ip = self.local_addr.unwrap_or(match addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        });
+        let socket = UdpSocket::bind(SocketAddr::new(local_ip, 0))
+            .await
+            .map_err(io_error)?;
+        socket.connect(addr).await.map_err(io_error)?;
+        Ok(socket)
+    }
+
+    // Send the request and wait for its response, None if it timed out.
+    async fn transact(
+        &self,
+        socket: &UdpSocket,
+        request: &[u8],
+        transaction_id: u32,
+        attempt: u32,
+    ) -> Result<Option<Vec<u8>>> {
+        socket.send(request).await.map_err(io_error)?;
+        let deadline = tokio::time::Instant::now() + self.retransmit_timeout * (1 << attempt);
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        loop {
+            let length = match timeout_at(deadline, socket.recv(&mut buffer)).await {
+                Ok(Ok(length)) => length,
+                // e.g. ICMP port unreachable, retransmit as for a lost packet
+                Ok(Err(e)) => {
+                    log::debug!("Failed to receive from tracker {}: {:?}", self.url, e);
+                    tokio::time::sleep_until(deadline).await;
+                    return Ok(None);
+                }
+                Err(_) => return Ok(None),
+            };
+            let response = &buffer[..length];
+            // A late response to an earlier request
+            if length < 8 || read_u32(response, 4) != transaction_id {
+                continue;
+            }
+            if read_u32(response, 0) == ACTION_ERROR {
+                // e.g. the tracker restarted and forgot our connection id, the next request
+                // connects again instead of failing the same way
+                *self.connection.lock().unwrap() = None;
+                let message = String::from_utf8_lossy(&response[8..]).to_string();
+                return Err(TrackerError::QueryPeers(message));
+            }
+            return Ok(Some(response.to_vec()));
+        }
+    }
+}
+
+fn io_error(e: std::io::Error) -> TrackerError {
+    TrackerError::QueryPeers(e.to_string())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn connect_request(transaction_id: u32) -> Vec<u8> {
+    let mut request = Vec::with_capacity(16);
+    request.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+    request.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+    request.extend_from_slice(&transaction_id.to_be_bytes());
+    request
+}
+
+fn parse_connect_response(response: &[u8]) -> Result<u64> {
+    if response.len() < 16 || read_u32(response, 0) != ACTION_CONNECT {
+        return Err(TrackerError::QueryPeers(
+            "Invalid connect response".to_string(),
+        ));
+    }
+    Ok(u64::from_be_bytes(response[8..16].try_into().unwrap()))
+}
+
+fn announce_request(connection_id: u64, transaction_id: u32, params: &RequestParams) -> Vec<u8> {
+    let event: u32 = match params.event {
+        Some(TrackerEvent::Completed) => 1,
+        Some(TrackerEvent::Started) => 2,
+        Some(TrackerEvent::Stopped) => 3,
+        // BEP 15 has no paused event, the left of 0 tells the tracker we are a seed anyway
+        Some(TrackerEvent::Empty) | Some(TrackerEvent::Paused) | None => 0,
+    };
+    let mut request = Vec::with_capacity(98);
+    request.extend_from_slice(&connection_id.to_be_bytes());
+    request.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+    request.extend_from_slice(&transaction_id.to_be_bytes());
+    request.extend_from_slice(&params.info_hash);
+    request.extend_from_slice(&params.peer_id);
+    request.extend_from_slice(&params.downloaded.to_be_bytes());
+    request.extend_from_slice(&params.left.to_be_bytes());
+    request.extend_from_slice(&params.uploaded.to_be_bytes());
+    request.extend_from_slice(&event.to_be_bytes());
+    // The tracker uses the source address of the packet
+    request.extend_from_slice(&0u32.to_be_bytes());
+    request.extend_from_slice(&params.key.unwrap_or(0).to_be_bytes());
+    let numwant = params
+        .numwant
+        .map_or(-1, |it| it.min(i32::MAX as u32) as i32);
+    request.extend_from_slice(&numwant.to_be_bytes());
+    request.extend_from_slice(&params.port.to_be_bytes());
+    request
+}
+
+// The peers are IPv6 if we talk to the tracker over IPv6.
+fn parse_announce_response(response: &[u8], is_ipv6: bool) -> Result<Response> {
+    if response.len() < 20 || read_u32(response, 0) != ACTION_ANNOUNCE {
+        return Err(TrackerError::QueryPeers(
+            "Invalid announce response".to_string(),
+        ));
+    }
+    Ok(Response {
+        interval: read_u32(response, 8) as u64,
+        min_interval: None,
+        warning: None,
+        peers: decode_peers(&response[20..], if is_ipv6 { 16 } else { 4 }),
+        leechers: Some(read_u32(response, 12)),
+        seeders: Some(read_u32(response, 16)),
+        tracker_id: None,
+        external_ip: None,
+    })
+}
+
+fn scrape_request(connection_id: u64, transaction_id: u32, info_hashes: &[Sha1Hash]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(16 + info_hashes.len() * 20);
+    request.extend_from_slice(&connection_id.to_be_bytes());
+    request.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+    request.extend_from_slice(&transaction_id.to_be_bytes());
+    for info_hash in info_hashes {
+        request.extend_from_slice(info_hash);
+    }
+    request
+}
+
+// The stats come in the order of the info hashes of the request.
+fn parse_scrape_response(
+    response: &[u8],
+    info_hashes: &[Sha1Hash],
+) -> Result<HashMap<Sha1Hash, ScrapeStats>> {
+    if response.len() < 8 || read_u32(response, 0) != ACTION_SCRAPE {
+        return Err(TrackerError::QueryPeers(
+            "Invalid scrape response".to_string(),
+        ));
+    }
+    Ok(info_hashes
+        .iter()
+        .zip(response[8..].chunks_exact(12))
+        .map(|(info_hash, stats)| {
+            let stats = ScrapeStats {
+                seeders: read_u32(stats, 0),
+                completed: read_u32(stats, 4),
+                leechers: read_u32(stats, 8),
+            };
+            (*info_hash, stats)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    };
+
+    use super::*;
+
+    // Answers the connect, the announce and the scrape requests, dropping the first `drop`
+    // packets.
+    async fn fake_tracker(drop: u32) -> (SocketAddr, Arc<AtomicU32>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let connects = Arc::new(AtomicU32::new(0));
+        let counter = connects.clone();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; MAX_PACKET_SIZE];
+            let mut received = 0;
+            let mut next_id = 100u64;
+            loop {
+                let (length, from) = socket.recv_from(&mut buffer).await.unwrap();
+                received += 1;
+                if received <= drop {
+                    continue;
+                }
+                let request = &buffer[..length];
+                let transaction_id = &request[12..16];
+                let mut response = Vec::new();
+                if read_u32(request, 8) == ACTION_CONNECT {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    next_id += 1;
+                    response.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                    response.extend_from_slice(transaction_id);
+                    response.extend_from_slice(&next_id.to_be_bytes());
+                } else {
+                    // Only the connection id we gave out is accepted
+                    let connection_id = u64::from_be_bytes(request[..8].try_into().unwrap());
+                    if connection_id != next_id {
+                        response.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+                        response.extend_from_slice(transaction_id);
+                        response.extend_from_slice(b"bad connection id");
+                    } else if read_u32(request, 8) == ACTION_SCRAPE {
+                        response.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                        response.extend_from_slice(transaction_id);
+                        for _ in request[16..].chunks_exact(20) {
+                            for value in [5u32, 7, 3] {
+                                response.extend_from_slice(&value.to_be_bytes());
+                            }
+                        }
+                    } else {
+                        response.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                        response.extend_from_slice(transaction_id);
+                        for value in [1800u32, 3, 5] {
+                            response.extend_from_slice(&value.to_be_bytes());
+                        }
+                        response.extend_from_slice(&[10, 0, 0, 2, 0x1a, 0xe1]);
+                    }
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+        (addr, connects)
+    }
+
+    fn tracker_at(addr: SocketAddr) -> UdpTracker {
+        let url = Url::parse(&format!("udp://{}", addr)).unwrap();
+        UdpTracker {
+            retransmit_timeout: Duration::from_millis(50),
+            ..UdpTracker::new(url, None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reuse_connection_id() {
+        let (addr, connects) = fake_tracker(0).await;
+        let tracker = tracker_at(addr);
+        let params = RequestParams::new([1; 20], [2; 20], 6881, 0);
+        let response = tracker.announce(params.clone()).await.unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.leechers, Some(3));
+        assert_eq!(response.seeders, Some(5));
+        assert_eq!(response.peers, vec!["10.0.0.2:6881".parse().unwrap()]);
+        tracker.announce(params.clone()).await.unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+        // Connect again once the id expired
+        tracker
+            .connection
+            .lock()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .connected_at -= CONNECTION_ID_LIFETIME;
+        tracker.announce(params).await.unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_connect_again_after_error() {
+        let (addr, connects) = fake_tracker(0).await;
+        let tracker = tracker_at(addr);
+        let params = RequestParams::new([1; 20], [2; 20], 6881, 0);
+        tracker.announce(params.clone()).await.unwrap();
+
+        // The tracker restarted and doesn't know our connection id anymore
+        tracker.connection.lock().unwrap().as_mut().unwrap().id = 1;
+        assert!(matches!(
+            tracker.announce(params.clone()).await,
+            Err(TrackerError::QueryPeers(message)) if message == "bad connection id"
+        ));
+        assert!(tracker.connection.lock().unwrap().is_none());
+        tracker.announce(params).await.unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_scrape() {
+        let (addr, connects) = fake_tracker(0).await;
+        let tracker = tracker_at(addr);
+        let stats = tracker.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+        let expected = ScrapeStats {
+            seeders: 5,
+            leechers: 3,
+            completed: 7,
+        };
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[&[1; 20]], expected);
+        assert_eq!(stats[&[2; 20]], expected);
+
+        // On the connection of the scrape
+        let params = RequestParams::new([1; 20], [2; 20], 6881, 0);
+        tracker.announce(params).await.unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retransmit_lost_packets() {
+        // The first connect is lost
+        let (addr, _) = fake_tracker(1).await;
+        let tracker = tracker_at(addr);
+        let params = RequestParams::new([1; 20], [2; 20], 6881, 0);
+        assert!(tracker.announce(params).await.is_ok());
+
+        // Nobody answers
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let unanswered = UdpTracker {
+            retransmit_timeout: Duration::from_millis(1),
+            ..tracker_at(silent.local_addr().unwrap())
+        };
+        let params = RequestParams::new([1; 20], [2; 20], 6881, 0);
+        assert!(matches!(
+            unanswered.announce(params).await,
+            Err(TrackerError::Timeout)
+        ));
+    }
+}
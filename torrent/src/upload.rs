@@ -0,0 +1,234 @@
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    pin::Pin,
+    sync::Mutex,
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::oneshot;
+
+use crate::{message::Message, piece_picker::BlockInfo};
+
+type ReadResult = std::io::Result<Vec<u8>>;
+
+// The piece messages are written this much at a time under the upload limit, see `UploadPacer`.
+pub const PACING_CHUNK_SIZE: usize = 4 * 1024;
+
+// Tracks the blocks requested by a peer on the way from disk to the socket:
+// first waiting for the disk read, then waiting to be written to the socket.
+// The blocks of the rare pieces skip ahead of the others, see `Torrent::is_rare_piece`.
+#[derive(Default)]
+pub struct UploadQueue {
+    pending_reads: Vec<(BlockInfo, bool, oneshot::Receiver<ReadResult>)>,
+    outbound: VecDeque<(BlockInfo, bool, Message)>,
+}
+
+impl UploadQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_read(
+        &mut self,
+        block: BlockInfo,
+        is_rare: bool,
+        read: oneshot::Receiver<ReadResult>,
+    ) {
+        self.pending_reads.push((block, is_rare, read));
+    }
+
+    // Remove the block from both pending reads and the outbound queue,
+    // dropping the read receiver so the disk skips the read if not started yet.
+    // Returns true if the block was found.
+    pub fn cancel(&mut self, block: &BlockInfo) -> bool {
+        let pending = self.pending_reads.len() + self.outbound.len();
+        self.pending_reads
+            .retain(|(it, _, _)| !it.is_same_block_as_info(block));
+        self.outbound
+            .retain(|(it, _, _)| !it.is_same_block_as_info(block));
+        pending != self.pending_reads.len() + self.outbound.len()
+    }
+
+    // Drop everything, e.g. when we choke the peer.
+    pub fn clear(&mut self) {
+        self.pending_reads.clear();
+        self.outbound.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending_reads.is_empty() && self.outbound.is_empty()
+    }
+
+    pub fn has_outbound(&self) -> bool {
+        !self.outbound.is_empty()
+    }
+
+    // Take the next piece message which is ready to write to the socket.
+    pub fn pop_outbound(&mut self) -> Option<Message> {
+        self.outbound.pop_front().map(|(_, _, message)| message)
+    }
+
+    // Wait until one of the pending reads is finished and move it to the outbound queue.
+    // Never resolves if there is no pending read.
+    pub async fn wait_read(&mut self) -> std::io::Result<()> {
+        let (index, result) = poll_fn(|cx| {
+            for (index, (_, _, read)) in self.pending_reads.iter_mut().enumerate() {
+                if let Poll::Ready(result) = Pin::new(read).poll(cx) {
+                    return Poll::Ready((index, result));
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        let (block, is_rare, _) = self.pending_reads.remove(index);
+        match result {
+            Ok(Ok(data)) => {
+                let message = Message::Piece {
+                    piece_index: block.piece_index,
+                    begin: block.begin,
+                    piece: data,
+                };
+                // Behind the other rare blocks, the requests are still served in order
+                let position = if is_rare {
+                    self.outbound
+                        .iter()
+                        .position(|(_, rare, _)| !rare)
+                        .unwrap_or(self.outbound.len())
+                } else {
+                    self.outbound.len()
+                };
+                self.outbound.insert(position, (block, is_rare, message));
+                Ok(())
+            }
+            Ok(Err(e)) => Err(e),
+            // The disk dropped the read, nothing to upload.
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+// Spreads the uploads of a torrent over time under its upload limit, a chunk of a piece message
+// at a time instead of the whole 16 KiB block at once, so the uplink isn't flooded in bursts
+// which delay the interactive traffic sharing it. Shared by the sessions of the torrent.
+pub struct UploadPacer {
+    // When the next chunk may be written.
+    next_write: Mutex<Instant>,
+}
+
+impl Default for UploadPacer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UploadPacer {
+    pub fn new() -> Self {
+        Self {
+            next_write: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Take the turn to write `bytes`, returns how long to wait before writing them.
+    // Bytes per second, None means unlimited and never waits.
+    pub fn reserve(&self, bytes: usize, limit: Option<u64>) -> Duration {
+        let Some(limit) = limit.filter(|it| *it > 0) else {
+            return Duration::ZERO;
+        };
+        let now = Instant::now();
+        let mut next_write = self.next_write.lock().unwrap();
+        let start = (*next_write).max(now);
+        *next_write = start + Duration::from_secs_f64(bytes as f64 / limit as f64);
+        start - now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancel_pending_read() {
+        let mut queue = UploadQueue::new();
+        let (tx, rx) = oneshot::channel();
+        queue.push_read(BlockInfo::new(0, 0, 4), false, rx);
+
+        assert!(queue.cancel(&BlockInfo::new(0, 0, 4)));
+        assert!(queue.is_empty());
+        // The disk can tell the read is aborted.
+        assert!(tx.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_outbound_block() {
+        let mut queue = UploadQueue::new();
+        let (tx1, rx1) = oneshot::channel();
+        let (tx2, rx2) = oneshot::channel();
+        queue.push_read(BlockInfo::new(0, 0, 4), false, rx1);
+        queue.push_read(BlockInfo::new(0, 4, 4), false, rx2);
+        tx1.send(Ok(vec![1, 2, 3, 4])).unwrap();
+        tx2.send(Ok(vec![5, 6, 7, 8])).unwrap();
+        queue.wait_read().await.unwrap();
+        queue.wait_read().await.unwrap();
+
+        assert!(queue.cancel(&BlockInfo::new(0, 0, 4)));
+        assert!(!queue.cancel(&BlockInfo::new(1, 0, 4)));
+        match queue.pop_outbound() {
+            Some(Message::Piece { begin, piece, .. }) => {
+                assert_eq!(begin, 4);
+                assert_eq!(piece, vec![5, 6, 7, 8]);
+            }
+            _ => panic!("Expected piece message"),
+        }
+        assert!(queue.pop_outbound().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_clear_on_choke() {
+        let mut queue = UploadQueue::new();
+        let (tx1, rx1) = oneshot::channel();
+        let (tx2, rx2) = oneshot::channel();
+        queue.push_read(BlockInfo::new(0, 0, 4), false, rx1);
+        queue.push_read(BlockInfo::new(0, 4, 4), false, rx2);
+        tx1.send(Ok(vec![1, 2, 3, 4])).unwrap();
+        queue.wait_read().await.unwrap();
+
+        queue.clear();
+        assert!(queue.is_empty());
+        assert!(queue.pop_outbound().is_none());
+        assert!(tx2.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_rare_blocks_first() {
+        let mut queue = UploadQueue::new();
+        for (piece_index, is_rare) in [(0, false), (1, true), (2, false), (3, true)] {
+            let (tx, rx) = oneshot::channel();
+            queue.push_read(BlockInfo::new(piece_index, 0, 4), is_rare, rx);
+            tx.send(Ok(vec![0; 4])).unwrap();
+            queue.wait_read().await.unwrap();
+        }
+        let mut order = Vec::new();
+        while let Some(Message::Piece { piece_index, .. }) = queue.pop_outbound() {
+            order.push(piece_index);
+        }
+        assert_eq!(order, vec![1, 3, 0, 2]);
+    }
+
+    #[test]
+    fn test_pace_uploads() {
+        let pacer = UploadPacer::new();
+        assert_eq!(pacer.reserve(16 * 1024, None), Duration::ZERO);
+
+        // 4 chunks of a block at 16 KiB/s, a quarter of a second apart
+        let waits: Vec<_> = (0..4)
+            .map(|_| pacer.reserve(PACING_CHUNK_SIZE, Some(16 * 1024)))
+            .collect();
+        assert_eq!(waits[0], Duration::ZERO);
+        for (i, wait) in waits.iter().enumerate().skip(1) {
+            let expected = Duration::from_millis(250) * i as u32;
+            assert!(*wait <= expected && *wait > expected - Duration::from_millis(50));
+        }
+    }
+}
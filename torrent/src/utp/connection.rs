@@ -0,0 +1,436 @@
+use std::{
+    cmp,
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, Bytes};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream, WriteHalf},
+    sync::mpsc,
+    time::MissedTickBehavior,
+};
+
+use super::{
+    Shared,
+    ledbat::Ledbat,
+    now_micros,
+    packet::{HEADER_SIZE, Packet, PacketType},
+};
+
+// Keep the packets below the MTU of most links, uTP doesn't fragment.
+const PACKET_SIZE: usize = 1400;
+const PAYLOAD_SIZE: usize = PACKET_SIZE - HEADER_SIZE;
+// The bytes we take from the other side before the stream reads them.
+const RECEIVE_BUFFER_SIZE: usize = 1024 * 1024;
+const MAX_WINDOW: usize = 1024 * 1024;
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+// Give up on the connection after so many timeouts in a row.
+const MAX_TIMEOUTS: usize = 6;
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(20);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// An incoming connection must send more than its SYN within this, see `Shared::half_open`.
+const HALF_OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+const DUPLICATE_ACKS: usize = 3;
+
+struct InFlight {
+    packet: Packet,
+    sent_at: Instant,
+    retransmitted: bool,
+}
+
+// The state of one uTP connection, the task moves the bytes between the packets
+// and the stream handed to the user.
+pub(super) struct Connection {
+    shared: Arc<Shared>,
+    remote: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    // The sequence number of the next data packet we send.
+    seq_nr: u16,
+    // The last sequence number we received in order.
+    ack_nr: u16,
+    in_flight: VecDeque<InFlight>,
+    in_flight_bytes: usize,
+    // The packets which arrived ahead of a missing one.
+    out_of_order: HashMap<u16, Packet>,
+    out_of_order_bytes: usize,
+    // The received bytes the stream hasn't read yet.
+    inbound: VecDeque<Bytes>,
+    inbound_bytes: usize,
+    ledbat: Ledbat,
+    peer_window: usize,
+    // The delay of the last packet received, echoed back so the other side can measure it.
+    reply_micro: u32,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    timeout: Duration,
+    timeouts: usize,
+    duplicate_acks: usize,
+    last_received: Instant,
+    last_sent: Instant,
+    fin_sent: bool,
+    fin_acked: bool,
+    peer_fin: bool,
+    closed: bool,
+    // An incoming connection which only sent its SYN so far, counted in `Shared::half_open`.
+    half_open: bool,
+}
+
+impl Connection {
+    pub(super) fn new(
+        shared: Arc<Shared>,
+        remote: SocketAddr,
+        recv_id: u16,
+        send_id: u16,
+        seq_nr: u16,
+        ack_nr: u16,
+    ) -> Self {
+        Self {
+            shared,
+            remote,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr,
+            in_flight: VecDeque::new(),
+            in_flight_bytes: 0,
+            out_of_order: HashMap::new(),
+            out_of_order_bytes: 0,
+            inbound: VecDeque::new(),
+            inbound_bytes: 0,
+            ledbat: Ledbat::new(PACKET_SIZE, MAX_WINDOW),
+            peer_window: PAYLOAD_SIZE,
+            reply_micro: 0,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            timeout: MIN_TIMEOUT,
+            timeouts: 0,
+            duplicate_acks: 0,
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+            fin_sent: false,
+            fin_acked: false,
+            peer_fin: false,
+            closed: false,
+            half_open: false,
+        }
+    }
+
+    // Accepted from a SYN, the caller counted it in `Shared::half_open` already.
+    pub(super) fn half_open(mut self) -> Self {
+        self.half_open = true;
+        self
+    }
+
+    fn leave_half_open(&mut self) {
+        if std::mem::take(&mut self.half_open) {
+            self.shared.half_open.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(super) fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    pub(super) async fn run(
+        mut self,
+        mut packets: mpsc::UnboundedReceiver<Packet>,
+        app: DuplexStream,
+    ) {
+        let (mut reader, mut writer) = tokio::io::split(app);
+        let mut read_buffer = vec![0u8; PAYLOAD_SIZE];
+        let mut ticker = tokio::time::interval(TICK_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut app_eof_sent = false;
+        // The stream is dropped, the received bytes go nowhere.
+        let mut app_gone = false;
+
+        while !self.closed {
+            if self.peer_fin && self.inbound.is_empty() && !app_eof_sent {
+                app_eof_sent = true;
+                let _ = writer.shutdown().await;
+            }
+            if self.fin_acked && self.peer_fin && (app_eof_sent || app_gone) {
+                break;
+            }
+
+            let front = self.inbound.front().cloned();
+            tokio::select! {
+                result = reader.read(&mut read_buffer), if self.can_send() => {
+                    match result {
+                        Ok(0) | Err(_) => self.send_fin().await,
+                        Ok(n) => self.send_data(read_buffer[..n].to_vec()).await,
+                    }
+                }
+                result = write_front(&mut writer, front.clone()), if front.is_some() && !app_gone => {
+                    match result {
+                        Ok(n) => self.consume_inbound(n),
+                        Err(_) => {
+                            app_gone = true;
+                            self.inbound.clear();
+                            self.inbound_bytes = 0;
+                        }
+                    }
+                }
+                packet = packets.recv() => {
+                    match packet {
+                        Some(packet) => self.on_packet(packet).await,
+                        None => break,
+                    }
+                }
+                _ = ticker.tick() => self.on_tick().await,
+            }
+        }
+
+        self.leave_half_open();
+        self.shared
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&(self.remote, self.recv_id));
+    }
+
+    // Whether a full packet fits in the window. One packet is always allowed,
+    // so a zero window is probed instead of waiting forever.
+    fn can_send(&self) -> bool {
+        if self.fin_sent {
+            return false;
+        }
+        let window = cmp::min(self.ledbat.window(), self.peer_window);
+        self.in_flight.is_empty() || self.in_flight_bytes + PAYLOAD_SIZE <= window
+    }
+
+    fn receive_window(&self) -> u32 {
+        RECEIVE_BUFFER_SIZE.saturating_sub(self.inbound_bytes + self.out_of_order_bytes) as u32
+    }
+
+    fn consume_inbound(&mut self, mut n: usize) {
+        self.inbound_bytes -= n;
+        while n > 0 {
+            let front = self.inbound.front_mut().unwrap();
+            let taken = cmp::min(n, front.len());
+            front.advance(taken);
+            n -= taken;
+            if front.is_empty() {
+                self.inbound.pop_front();
+            }
+        }
+    }
+
+    async fn on_packet(&mut self, packet: Packet) {
+        self.last_received = Instant::now();
+        self.reply_micro = now_micros().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.window as usize;
+
+        match packet.packet_type {
+            // The SYN, or its retransmission when our ack is lost
+            PacketType::Syn => {
+                self.send_state().await;
+                return;
+            }
+            PacketType::Reset => {
+                log::debug!("uTP connection reset by {}", self.remote);
+                self.closed = true;
+                return;
+            }
+            _ => self.leave_half_open(),
+        }
+
+        self.on_ack(&packet);
+
+        if packet.packet_type == PacketType::Data || packet.packet_type == PacketType::Fin {
+            let seq_nr = packet.seq_nr;
+            if seq_nr == self.ack_nr.wrapping_add(1) {
+                self.receive_in_order(packet);
+                while let Some(next) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+                    self.out_of_order_bytes -= next.payload.len();
+                    self.receive_in_order(next);
+                }
+            } else if !seq_le(seq_nr, self.ack_nr)
+                && self.out_of_order_bytes + packet.payload.len() <= RECEIVE_BUFFER_SIZE
+            {
+                self.out_of_order_bytes += packet.payload.len();
+                if let Some(old) = self.out_of_order.insert(seq_nr, packet) {
+                    self.out_of_order_bytes -= old.payload.len();
+                }
+            }
+            // Ack every data packet, the duplicates too, our previous ack may be lost.
+            self.send_state().await;
+        }
+    }
+
+    fn on_ack(&mut self, packet: &Packet) {
+        let mut acked_bytes = 0;
+        while let Some(front) = self.in_flight.front() {
+            if !seq_le(front.packet.seq_nr, packet.ack_nr) {
+                break;
+            }
+            let acked = self.in_flight.pop_front().unwrap();
+            acked_bytes += acked.packet.payload.len();
+            if acked.packet.packet_type == PacketType::Fin {
+                self.fin_acked = true;
+            }
+            // Only the packets sent once tell the round trip time.
+            if !acked.retransmitted {
+                self.update_rtt(acked.sent_at.elapsed());
+            }
+        }
+        self.in_flight_bytes -= acked_bytes;
+
+        if acked_bytes > 0 || self.fin_acked && self.in_flight.is_empty() {
+            self.timeouts = 0;
+            self.duplicate_acks = 0;
+            if acked_bytes > 0 {
+                self.ledbat.on_ack(acked_bytes, packet.timestamp_diff);
+            }
+        } else if packet.packet_type == PacketType::State && !self.in_flight.is_empty() {
+            self.duplicate_acks += 1;
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let delta = rtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+        let rtt = self.rtt.unwrap_or_default();
+        self.timeout = (rtt + self.rtt_var * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    fn receive_in_order(&mut self, packet: Packet) {
+        self.ack_nr = packet.seq_nr;
+        if packet.packet_type == PacketType::Fin {
+            self.peer_fin = true;
+            return;
+        }
+        if !packet.payload.is_empty() {
+            self.inbound_bytes += packet.payload.len();
+            self.inbound.push_back(Bytes::from(packet.payload));
+        }
+    }
+
+    async fn on_tick(&mut self) {
+        if self.last_received.elapsed() > IDLE_TIMEOUT {
+            log::debug!("uTP connection to {} is idle, close it", self.remote);
+            self.closed = true;
+            return;
+        }
+        if self.half_open && self.last_received.elapsed() > HALF_OPEN_TIMEOUT {
+            log::debug!("uTP connection from {} never opened, close it", self.remote);
+            self.closed = true;
+            return;
+        }
+
+        let timed_out = self
+            .in_flight
+            .front()
+            .is_some_and(|it| it.sent_at.elapsed() > self.timeout);
+        if timed_out {
+            self.timeouts += 1;
+            if self.timeouts > MAX_TIMEOUTS {
+                log::debug!("uTP connection to {} timed out", self.remote);
+                self.closed = true;
+                return;
+            }
+            self.timeout = cmp::min(self.timeout * 2, MAX_TIMEOUT);
+            self.ledbat.on_timeout();
+            self.retransmit_front().await;
+        } else if self.duplicate_acks >= DUPLICATE_ACKS {
+            // The packet after the acked one is likely lost, don't wait for the timeout.
+            self.duplicate_acks = 0;
+            self.retransmit_front().await;
+        }
+
+        if self.last_sent.elapsed() > KEEP_ALIVE_INTERVAL {
+            self.send_state().await;
+        }
+    }
+
+    async fn retransmit_front(&mut self) {
+        let Some(front) = self.in_flight.front_mut() else {
+            return;
+        };
+        front.sent_at = Instant::now();
+        front.retransmitted = true;
+        let mut packet = front.packet.clone();
+        packet.ack_nr = self.ack_nr;
+        self.send(packet).await;
+    }
+
+    async fn send_data(&mut self, payload: Vec<u8>) {
+        self.send_in_sequence(PacketType::Data, payload).await;
+    }
+
+    async fn send_fin(&mut self) {
+        self.fin_sent = true;
+        self.send_in_sequence(PacketType::Fin, Vec::new()).await;
+    }
+
+    async fn send_in_sequence(&mut self, packet_type: PacketType, payload: Vec<u8>) {
+        let packet = self.packet(packet_type, payload);
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.in_flight_bytes += packet.payload.len();
+        self.in_flight.push_back(InFlight {
+            packet: packet.clone(),
+            sent_at: Instant::now(),
+            retransmitted: false,
+        });
+        self.send(packet).await;
+    }
+
+    // Ack only, it doesn't take a sequence number.
+    async fn send_state(&mut self) {
+        let packet = self.packet(PacketType::State, Vec::new());
+        self.send(packet).await;
+    }
+
+    fn packet(&self, packet_type: PacketType, payload: Vec<u8>) -> Packet {
+        Packet {
+            packet_type,
+            connection_id: self.send_id,
+            timestamp: 0,
+            timestamp_diff: self.reply_micro,
+            window: self.receive_window(),
+            seq_nr: self.seq_nr,
+            ack_nr: self.ack_nr,
+            payload,
+        }
+    }
+
+    async fn send(&mut self, mut packet: Packet) {
+        packet.timestamp = now_micros();
+        self.last_sent = Instant::now();
+        if let Err(e) = self
+            .shared
+            .socket
+            .send_to(&packet.to_bytes(), self.remote)
+            .await
+        {
+            log::debug!("Failed to send uTP packet to {}: {:?}", self.remote, e);
+        }
+    }
+}
+
+async fn write_front(
+    writer: &mut WriteHalf<DuplexStream>,
+    front: Option<Bytes>,
+) -> std::io::Result<usize> {
+    writer.write(&front.unwrap_or_default()).await
+}
+
+// Whether the sequence number `a` is at or before `b`, they wrap around.
+fn seq_le(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a) < 0x8000
+}
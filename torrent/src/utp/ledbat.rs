@@ -0,0 +1,108 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+// LEDBAT congestion control, grow the window while the one-way delay is below the target
+// and shrink it once the delay rises, so uTP gives way to the other traffic on the link.
+// https://www.bittorrent.org/beps/bep_0029.html#congestion-control
+
+// The queuing delay we aim for, in microseconds.
+const TARGET_DELAY: f64 = 100_000.0;
+// The window grows by at most this many packets per round trip.
+const GAIN: f64 = 1.0;
+// The lowest delay is kept per minute for this long, so the base delay follows route changes.
+const BASE_DELAY_HISTORY: usize = 2;
+const BASE_DELAY_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Ledbat {
+    // Bytes allowed in flight.
+    window: f64,
+    packet_size: usize,
+    max_window: usize,
+    // The lowest delay of each minute, the oldest first.
+    base_delays: VecDeque<(Instant, u32)>,
+}
+
+impl Ledbat {
+    pub fn new(packet_size: usize, max_window: usize) -> Self {
+        Self {
+            window: (2 * packet_size) as f64,
+            packet_size,
+            max_window,
+            base_delays: VecDeque::new(),
+        }
+    }
+
+    pub fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    // Some bytes are acked, `delay` is the one-way delay the other side measured for them,
+    // which includes the clock difference of both sides, only the change matters.
+    pub fn on_ack(&mut self, bytes_acked: usize, delay: u32) {
+        self.record_delay(delay);
+        let queuing_delay = delay.saturating_sub(self.base_delay()) as f64;
+        let off_target = (TARGET_DELAY - queuing_delay) / TARGET_DELAY;
+        let gain = GAIN * off_target * bytes_acked as f64 * self.packet_size as f64 / self.window;
+        self.window = (self.window + gain).clamp(self.min_window(), self.max_window as f64);
+    }
+
+    // A packet is lost, start over from the minimum window.
+    pub fn on_timeout(&mut self) {
+        self.window = self.min_window();
+    }
+
+    fn min_window(&self) -> f64 {
+        (2 * self.packet_size) as f64
+    }
+
+    fn base_delay(&self) -> u32 {
+        self.base_delays
+            .iter()
+            .map(|(_, delay)| *delay)
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn record_delay(&mut self, delay: u32) {
+        match self.base_delays.back_mut() {
+            Some((started_at, lowest)) if started_at.elapsed() < BASE_DELAY_INTERVAL => {
+                *lowest = (*lowest).min(delay);
+            }
+            _ => {
+                self.base_delays.push_back((Instant::now(), delay));
+                if self.base_delays.len() > BASE_DELAY_HISTORY {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_follows_delay() {
+        let mut ledbat = Ledbat::new(1000, 100_000);
+        assert_eq!(ledbat.window(), 2000);
+
+        // No queuing, the window grows
+        for _ in 0..20 {
+            ledbat.on_ack(1000, 50_000);
+        }
+        let grown = ledbat.window();
+        assert!(grown > 2000);
+
+        // The delay is way over the target, the window shrinks
+        for _ in 0..5 {
+            ledbat.on_ack(1000, 50_000 + 300_000);
+        }
+        assert!(ledbat.window() < grown);
+
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window(), 2000);
+    }
+}
@@ -0,0 +1,419 @@
+mod connection;
+mod ledbat;
+mod packet;
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf},
+    net::UdpSocket,
+    sync::mpsc,
+    task::JoinHandle,
+    time::timeout,
+};
+
+use self::{
+    connection::Connection,
+    packet::{Packet, PacketType},
+};
+
+// uTP, the reliable stream over UDP with delay based congestion control,
+// which gets out of the way of the interactive traffic on the same link.
+// https://www.bittorrent.org/beps/bep_0029.html
+
+const SYN_TIMEOUT: Duration = Duration::from_secs(1);
+const SYN_ATTEMPTS: usize = 3;
+const MAX_PACKET_SIZE: usize = 1500;
+// The bytes buffered between the connection and the stream, each direction.
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+// The SYNs past this are dropped, so a flood of them, e.g. from spoofed addresses, can't
+// make us run a connection for each.
+const MAX_HALF_OPEN: usize = 64;
+
+// A UDP socket carrying the uTP connections, both the dialed and the accepted ones.
+// The clones share the socket, e.g. the peers are dialed from the port the listener is on.
+#[derive(Clone)]
+pub struct UtpSocket {
+    shared: Arc<Shared>,
+    receiver: Arc<Receiver>,
+    incoming: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<UtpStream>>>,
+}
+
+struct Shared {
+    socket: UdpSocket,
+    // The packets of each connection, by the remote address and our connection id.
+    connections: Mutex<HashMap<(SocketAddr, u16), mpsc::UnboundedSender<Packet>>>,
+    // The incoming connections which only sent their SYN so far.
+    half_open: AtomicUsize,
+}
+
+// The task reading the UDP socket, it stops once the socket and all its connections are dropped.
+struct Receiver(Mutex<Option<JoinHandle<()>>>);
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        if let Some(handle) = self.0.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self::from_socket(UdpSocket::bind(addr).await?))
+    }
+
+    // A socket bound already, e.g. set to v6 only first.
+    pub fn from_socket(socket: UdpSocket) -> Self {
+        let shared = Arc::new(Shared {
+            socket,
+            connections: Mutex::new(HashMap::new()),
+            half_open: AtomicUsize::new(0),
+        });
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let receiver = Arc::new(Receiver(Mutex::new(None)));
+        let handle = tokio::spawn(
+            shared
+                .clone()
+                .receive(Arc::downgrade(&receiver), incoming_tx),
+        );
+        *receiver.0.lock().unwrap() = Some(handle);
+        Self {
+            shared,
+            receiver,
+            incoming: Arc::new(tokio::sync::Mutex::new(incoming)),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let (recv_id, mut packets) = loop {
+            let recv_id = rand::random::<u16>();
+            let (tx, rx) = mpsc::unbounded_channel();
+            let mut connections = self.shared.connections.lock().unwrap();
+            if let std::collections::hash_map::Entry::Vacant(entry) =
+                connections.entry((addr, recv_id))
+            {
+                entry.insert(tx);
+                break (recv_id, rx);
+            }
+        };
+        let send_id = recv_id.wrapping_add(1);
+        let syn = Packet {
+            packet_type: PacketType::Syn,
+            connection_id: recv_id,
+            timestamp: now_micros(),
+            timestamp_diff: 0,
+            window: STREAM_BUFFER_SIZE as u32,
+            seq_nr: 1,
+            ack_nr: 0,
+            payload: Vec::new(),
+        };
+        for _ in 0..SYN_ATTEMPTS {
+            self.shared.socket.send_to(&syn.to_bytes(), addr).await?;
+            match timeout(SYN_TIMEOUT, packets.recv()).await {
+                Ok(Some(packet)) if packet.packet_type == PacketType::State => {
+                    // The first data of the other side takes the sequence number of its ack.
+                    let connection = Connection::new(
+                        self.shared.clone(),
+                        addr,
+                        recv_id,
+                        send_id,
+                        2,
+                        packet.seq_nr.wrapping_sub(1),
+                    );
+                    return Ok(self.spawn(connection, packets));
+                }
+                Ok(Some(packet)) if packet.packet_type == PacketType::Reset => break,
+                Ok(_) => break,
+                Err(_) => continue,
+            }
+        }
+        self.shared
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&(addr, recv_id));
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "uTP connection timed out",
+        ))
+    }
+
+    pub async fn accept(&self) -> io::Result<UtpStream> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "uTP socket is closed"))
+    }
+
+    fn spawn(&self, connection: Connection, packets: mpsc::UnboundedReceiver<Packet>) -> UtpStream {
+        spawn_connection(
+            connection,
+            packets,
+            self.receiver.clone(),
+            self.local_addr().ok(),
+        )
+    }
+}
+
+impl Shared {
+    async fn receive(
+        self: Arc<Self>,
+        receiver: Weak<Receiver>,
+        incoming: mpsc::UnboundedSender<UtpStream>,
+    ) {
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let local_addr = self.socket.local_addr().ok();
+        loop {
+            let (n, addr) = match self.socket.recv_from(&mut buffer).await {
+                Ok(result) => result,
+                Err(e) => {
+                    log::debug!("Failed to receive uTP packet: {:?}", e);
+                    continue;
+                }
+            };
+            let Some(packet) = Packet::from_bytes(&buffer[..n]) else {
+                continue;
+            };
+            // The SYN carries the id the other side receives on, we receive on the next one.
+            let recv_id = if packet.packet_type == PacketType::Syn {
+                packet.connection_id.wrapping_add(1)
+            } else {
+                packet.connection_id
+            };
+            let known = self
+                .connections
+                .lock()
+                .unwrap()
+                .get(&(addr, recv_id))
+                .map(|it| it.send(packet.clone()).is_ok());
+            if known.is_some() || packet.packet_type != PacketType::Syn {
+                continue;
+            }
+            let Some(receiver) = receiver.upgrade() else {
+                return;
+            };
+            if self.half_open.load(Ordering::Relaxed) >= MAX_HALF_OPEN {
+                log::debug!("Drop uTP SYN from {}, too many half-open connections", addr);
+                continue;
+            }
+            self.half_open.fetch_add(1, Ordering::Relaxed);
+            let (tx, packets) = mpsc::unbounded_channel();
+            self.connections
+                .lock()
+                .unwrap()
+                .insert((addr, recv_id), tx.clone());
+            let seq_nr = rand::random::<u16>();
+            let connection = Connection::new(
+                self.clone(),
+                addr,
+                recv_id,
+                packet.connection_id,
+                seq_nr,
+                packet.seq_nr,
+            )
+            .half_open();
+            // The connection answers the SYN with its first ack.
+            let _ = tx.send(packet);
+            let stream = spawn_connection(connection, packets, receiver, local_addr);
+            if incoming.send(stream).is_err() {
+                log::debug!("Drop uTP connection from {}, nobody is accepting", addr);
+            }
+        }
+    }
+}
+
+fn spawn_connection(
+    connection: Connection,
+    packets: mpsc::UnboundedReceiver<Packet>,
+    receiver: Arc<Receiver>,
+    local_addr: Option<SocketAddr>,
+) -> UtpStream {
+    let (stream, app) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+    let peer_addr = connection.remote();
+    tokio::spawn(async move {
+        connection.run(packets, app).await;
+        // Keep the socket reading until the connection is done.
+        drop(receiver);
+    });
+    UtpStream {
+        stream,
+        local_addr,
+        peer_addr,
+    }
+}
+
+// Microseconds by the local clock, wrapping around.
+fn now_micros() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_micros() as u32)
+        .unwrap_or(0)
+}
+
+// A uTP connection, read and write it like a TCP stream.
+#[derive(Debug)]
+pub struct UtpStream {
+    stream: DuplexStream,
+    local_addr: Option<SocketAddr>,
+    peer_addr: SocketAddr,
+}
+
+impl UtpStream {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.local_addr
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "uTP socket is closed"))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_transfer_both_ways() {
+        let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let data: Vec<u8> = (0..300_000u32).map(|it| (it % 251) as u8).collect();
+        let server_data = data.clone();
+        let server_task = tokio::spawn(async move {
+            let stream = server.accept().await.unwrap();
+            let (mut reader, mut writer) = tokio::io::split(stream);
+            // Write while reading, neither side may wait for the other to drain first
+            let write = async {
+                writer.write_all(&server_data).await.unwrap();
+                writer.shutdown().await.unwrap();
+            };
+            let read = async {
+                let mut received = Vec::new();
+                reader.read_to_end(&mut received).await.unwrap();
+                received
+            };
+            let (_, received) = tokio::join!(write, read);
+            received
+        });
+
+        let stream = client.connect(server_addr).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), server_addr);
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let write = async {
+            writer.write_all(&data).await.unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let read = async {
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let (_, received) = timeout(Duration::from_secs(20), async { tokio::join!(write, read) })
+            .await
+            .unwrap();
+        assert_eq!(received, data);
+        assert_eq!(server_task.await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_half_open_limit() {
+        let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let flood = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for connection_id in 0..MAX_HALF_OPEN as u16 + 10 {
+            let syn = Packet {
+                packet_type: PacketType::Syn,
+                connection_id: connection_id * 2,
+                timestamp: now_micros(),
+                timestamp_diff: 0,
+                window: STREAM_BUFFER_SIZE as u32,
+                seq_nr: 1,
+                ack_nr: 0,
+                payload: Vec::new(),
+            };
+            flood.send_to(&syn.to_bytes(), server_addr).await.unwrap();
+        }
+        let all_received = async {
+            while server.shared.connections.lock().unwrap().len() < MAX_HALF_OPEN {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(5), all_received).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            server.shared.connections.lock().unwrap().len(),
+            MAX_HALF_OPEN
+        );
+        assert_eq!(
+            server.shared.half_open.load(Ordering::Relaxed),
+            MAX_HALF_OPEN
+        );
+
+        // The dialed connections from the same socket aren't limited
+        let client = server.clone();
+        let other = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let other_addr = other.local_addr().unwrap();
+        let accept = tokio::spawn(async move { other.accept().await.unwrap() });
+        let stream = client.connect(other_addr).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap(), server_addr);
+        accept.await.unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+use bytes::{Buf, BufMut};
+
+// The uTP packet, a 20 bytes header followed by the payload.
+// https://www.bittorrent.org/beps/bep_0029.html#header-format
+
+pub const HEADER_SIZE: usize = 20;
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    // Ack only, doesn't take a sequence number.
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(PacketType::Data),
+            1 => Ok(PacketType::Fin),
+            2 => Ok(PacketType::State),
+            3 => Ok(PacketType::Reset),
+            4 => Ok(PacketType::Syn),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    // Microseconds when the packet is sent, by the clock of the sender.
+    pub timestamp: u32,
+    // The difference between the timestamp of the last packet received and when it arrived,
+    // tells the other side the one-way delay of its packets.
+    pub timestamp_diff: u32,
+    // Bytes the sender can still receive.
+    pub window: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        bytes.put_u8((self.packet_type as u8) << 4 | VERSION);
+        // No extension
+        bytes.put_u8(0);
+        bytes.put_u16(self.connection_id);
+        bytes.put_u32(self.timestamp);
+        bytes.put_u32(self.timestamp_diff);
+        bytes.put_u32(self.window);
+        bytes.put_u16(self.seq_nr);
+        bytes.put_u16(self.ack_nr);
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE {
+            return None;
+        }
+        let type_version = bytes.get_u8();
+        if type_version & 0x0f != VERSION {
+            return None;
+        }
+        let packet_type = PacketType::try_from(type_version >> 4).ok()?;
+        let mut extension = bytes.get_u8();
+        let connection_id = bytes.get_u16();
+        let timestamp = bytes.get_u32();
+        let timestamp_diff = bytes.get_u32();
+        let window = bytes.get_u32();
+        let seq_nr = bytes.get_u16();
+        let ack_nr = bytes.get_u16();
+        // Skip the extensions (e.g. selective ack), each is the next extension type, length and data.
+        while extension != 0 {
+            if bytes.len() < 2 {
+                return None;
+            }
+            extension = bytes.get_u8();
+            let length = bytes.get_u8() as usize;
+            if bytes.len() < length {
+                return None;
+            }
+            bytes.advance(length);
+        }
+        Some(Self {
+            packet_type,
+            connection_id,
+            timestamp,
+            timestamp_diff,
+            window,
+            seq_nr,
+            ack_nr,
+            payload: bytes.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_roundtrip() {
+        let packet = Packet {
+            packet_type: PacketType::Data,
+            connection_id: 1234,
+            timestamp: 1,
+            timestamp_diff: 2,
+            window: 65536,
+            seq_nr: 10,
+            ack_nr: 9,
+            payload: b"hello".to_vec(),
+        };
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(bytes.len(), HEADER_SIZE + 5);
+        assert_eq!(Packet::from_bytes(&bytes), Some(packet));
+    }
+
+    #[test]
+    fn test_skip_extensions() {
+        let mut bytes = Packet {
+            packet_type: PacketType::State,
+            connection_id: 1,
+            timestamp: 0,
+            timestamp_diff: 0,
+            window: 0,
+            seq_nr: 1,
+            ack_nr: 1,
+            payload: Vec::new(),
+        }
+        .to_bytes();
+        // Selective ack extension with a 4 bytes bitmask
+        bytes[1] = 1;
+        bytes.extend_from_slice(&[0, 4, 0xff, 0, 0, 0]);
+        let packet = Packet::from_bytes(&bytes).unwrap();
+        assert_eq!(packet.packet_type, PacketType::State);
+        assert!(packet.payload.is_empty());
+
+        // Truncated extension
+        assert_eq!(Packet::from_bytes(&bytes[..HEADER_SIZE + 3]), None);
+    }
+}
@@ -0,0 +1,244 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::{client_identity::ClientIdentity, hash::hmac_sha256, types::Sha1Hash};
+
+// POST the engine events to the user's URLs, so the media managers etc. can react to a
+// finished download without polling us.
+
+pub const SIGNATURE_HEADER: &str = "X-BitDrift-Signature";
+pub const DEFAULT_RETRIES: usize = 3;
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum WebhookError {
+    #[error("Failed to send the webhook: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("The webhook responded with {0}")]
+    Status(StatusCode),
+}
+
+pub(crate) type Result<T> = std::result::Result<T, WebhookError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    TorrentAdded,
+    TorrentFinished,
+    TorrentError,
+}
+
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub url: Url,
+    // Only these events are sent to the URL.
+    pub events: Vec<WebhookEvent>,
+    // When set the body is signed with HMAC-SHA256, the receiver can check the request came
+    // from us by the signature header.
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Payload {
+    pub event: WebhookEvent,
+    pub info_hash: String,
+    pub name: String,
+    // What went wrong, for `TorrentError`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    // Seconds since the epoch.
+    pub timestamp: u64,
+}
+
+impl Payload {
+    pub fn new(event: WebhookEvent, info_hash: &Sha1Hash, name: &str) -> Self {
+        Self {
+            event,
+            info_hash: info_hash.iter().map(|it| format!("{:02x}", it)).collect(),
+            name: name.to_string(),
+            message: None,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+
+    pub fn with_message(mut self, message: &str) -> Self {
+        self.message = Some(message.to_string());
+        self
+    }
+}
+
+pub struct Webhooks {
+    hooks: Vec<Webhook>,
+    client: Client,
+    retries: usize,
+    retry_delay: Duration,
+}
+
+impl Webhooks {
+    pub fn new(hooks: Vec<Webhook>, identity: &ClientIdentity) -> Result<Self> {
+        let client = Client::builder()
+            .user_agent(identity.user_agent())
+            .timeout(TIMEOUT)
+            .build()?;
+        Ok(Self {
+            hooks,
+            client,
+            retries: DEFAULT_RETRIES,
+            retry_delay: INITIAL_RETRY_DELAY,
+        })
+    }
+
+    pub fn with_retries(mut self, retries: usize, retry_delay: Duration) -> Self {
+        self.retries = retries;
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    // Send the payload to every hook subscribed to the event, in the background so a slow
+    // receiver doesn't hold up the engine.
+    pub fn notify(&self, payload: &Payload) -> Vec<JoinHandle<Result<()>>> {
+        let body = match serde_json::to_vec(payload) {
+            Ok(body) => body,
+            Err(err) => {
+                log::error!("Failed to serialize the webhook payload: {}", err);
+                return vec![];
+            }
+        };
+        self.hooks
+            .iter()
+            .filter(|hook| hook.events.contains(&payload.event))
+            .map(|hook| {
+                let client = self.client.clone();
+                let hook = hook.clone();
+                let body = body.clone();
+                let (retries, retry_delay) = (self.retries, self.retry_delay);
+                tokio::spawn(async move {
+                    let result = deliver(&client, &hook, body, retries, retry_delay).await;
+                    if let Err(err) = &result {
+                        log::warn!("Webhook {} failed: {}", hook.url, err);
+                    }
+                    result
+                })
+            })
+            .collect()
+    }
+}
+
+async fn deliver(
+    client: &Client,
+    hook: &Webhook,
+    body: Vec<u8>,
+    retries: usize,
+    mut retry_delay: Duration,
+) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+        let mut request = client
+            .post(hook.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        if let Some(secret) = &hook.secret {
+            request = request.header(SIGNATURE_HEADER, signature(secret, &body));
+        }
+        let result = match request.send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => Err(WebhookError::Status(response.status())),
+            Err(err) => Err(err.into()),
+        };
+        // The client errors other than rate limiting won't get better by retrying
+        let retryable = match &result {
+            Err(WebhookError::Status(status)) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => true,
+        };
+        if !retryable || attempt >= retries {
+            return result;
+        }
+        attempt += 1;
+        tokio::time::sleep(retry_delay).await;
+        retry_delay *= 2;
+    }
+}
+
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mac = hmac_sha256(secret.as_bytes(), body);
+    let hex: String = mac.iter().map(|it| format!("{:02x}", it)).collect();
+    format!("sha256={}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_notify() {
+        let mut server = mockito::Server::new_async().await;
+        let payload = Payload::new(WebhookEvent::TorrentFinished, &[0xab; 20], "ubuntu.iso");
+        let body = serde_json::to_string(&payload).unwrap();
+        assert!(body.contains("\"event\":\"torrent_finished\""));
+        assert!(!body.contains("message"));
+
+        let failing = server
+            .mock("POST", "/finished")
+            .with_status(503)
+            .expect(1)
+            .create_async()
+            .await;
+        let finished = server
+            .mock("POST", "/finished")
+            .match_header(
+                SIGNATURE_HEADER,
+                signature("secret", body.as_bytes()).as_str(),
+            )
+            .match_body(body.as_str())
+            .expect(1)
+            .create_async()
+            .await;
+        let added = server.mock("POST", "/added").expect(0).create_async().await;
+        let url = |path: &str| Url::parse(&format!("{}{}", server.url(), path)).unwrap();
+        let hooks = vec![
+            Webhook {
+                url: url("/finished"),
+                events: vec![WebhookEvent::TorrentFinished],
+                secret: Some("secret".to_string()),
+            },
+            Webhook {
+                url: url("/added"),
+                events: vec![WebhookEvent::TorrentAdded],
+                secret: None,
+            },
+        ];
+        let webhooks = Webhooks::new(hooks, &ClientIdentity::default())
+            .unwrap()
+            .with_retries(1, Duration::from_millis(10));
+        // The first attempt fails and the retry is answered by the later mock, mockito
+        // matches the mocks in order and skips the exhausted ones
+        let handles = webhooks.notify(&payload);
+        assert_eq!(handles.len(), 1);
+        for handle in handles {
+            handle.await.unwrap().unwrap();
+        }
+        failing.assert_async().await;
+        finished.assert_async().await;
+        added.assert_async().await;
+    }
+}
@@ -0,0 +1,679 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{StreamExt, stream::FuturesUnordered};
+use percent_encoding::percent_encode;
+use reqwest::{Client, StatusCode, header::RANGE};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use url::Url;
+
+use crate::{
+    client_identity::ClientIdentity,
+    disk::Disk,
+    hash::calculate_sha1_hash,
+    metainfo::MetaInfo,
+    piece::Block,
+    piece_picker::BlockInfo,
+    torrent::Torrent,
+    tracker::URL_ENCODE_RESERVED,
+    types::{BitField, PeerId},
+};
+
+// Download the pieces from an HTTP server hosting the files of the torrent,
+// the blocks go through the same piece picker and verification as the ones from peers.
+// https://www.bittorrent.org/beps/bep_0019.html
+// The older HTTP seeds are scripts which serve the pieces by index instead.
+// https://www.bittorrent.org/beps/bep_0017.html
+
+pub(crate) type Result<T> = std::result::Result<T, WebSeedError>;
+
+// Give up the web seed after this many requests in a row failed.
+const MAX_FAILURES: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+// An HTTP seed may ask to come back later, but not for too long.
+const MAX_BUSY_DELAY: Duration = Duration::from_secs(10 * 60);
+// The backoff of a failing seed in a pool doubles up to this.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+// A seed in a pool gets another request in flight for each this many bytes per second.
+const SLOT_THROUGHPUT: f64 = 256.0 * 1024.0;
+const MAX_IN_FLIGHT_PER_SEED: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum WebSeedError {
+    #[error("Http request failed")]
+    Http(#[from] reqwest::Error),
+    #[error("Web seed returned {0}")]
+    UnexpectedStatus(StatusCode),
+    #[error("Web seed returned fewer bytes than requested")]
+    ShortResponse,
+    #[error("Invalid web seed URL")]
+    InvalidUrl,
+    #[error("HTTP seed is busy, retry in {0:?}")]
+    Busy(Duration),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSeedKind {
+    // The `url-list`, a plain HTTP server hosting the files.
+    Url,
+    // The `httpseeds`, a script serving the pieces.
+    HttpSeed,
+}
+
+pub struct WebSeed {
+    client: Client,
+    url: Url,
+    kind: WebSeedKind,
+}
+
+impl WebSeed {
+    pub fn new(url: Url) -> Self {
+        Self {
+            client: ClientIdentity::default()
+                .http_client(None)
+                .expect("Failed to build HTTP client"),
+            url,
+            kind: WebSeedKind::Url,
+        }
+    }
+
+    // Request as the client of the identity, see `ClientIdentity`.
+    pub fn with_identity(mut self, identity: &ClientIdentity) -> Self {
+        if let Ok(client) = identity.http_client(None) {
+            self.client = client;
+        }
+        self
+    }
+
+    pub fn http_seed(url: Url) -> Self {
+        Self {
+            kind: WebSeedKind::HttpSeed,
+            ..Self::new(url)
+        }
+    }
+
+    // Both kinds of the web seeds of the torrent.
+    pub fn from_metainfo(metainfo: &MetaInfo) -> Vec<Self> {
+        let web_seeds = metainfo.web_seeds.iter().cloned().map(WebSeed::new);
+        let http_seeds = metainfo.http_seeds.iter().cloned().map(WebSeed::http_seed);
+        web_seeds.chain(http_seeds).collect()
+    }
+
+    pub fn kind(&self) -> WebSeedKind {
+        self.kind
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    // Web seeds have no peer id, the hash of the URL stands in for it to reserve the blocks.
+    pub(crate) fn requester_id(&self) -> PeerId {
+        calculate_sha1_hash(self.url.as_str().as_bytes().to_vec())
+    }
+
+    // Download the blocks the torrent is missing until nothing is left to request,
+    // or the server keeps failing.
+    pub async fn run(self, torrent: Arc<Mutex<Torrent>>) {
+        let mut failures = 0;
+        loop {
+            let (metainfo, block) = {
+                let torrent = torrent.lock().await;
+                let Some(metainfo) = torrent.metainfo().cloned() else {
+                    return;
+                };
+                // The server has every piece
+                let have_all = BitField::repeat(true, metainfo.piece_count());
+                let Some(block) = torrent.request_block(self.requester_id(), &have_all).await
+                else {
+                    return;
+                };
+                (metainfo, block)
+            };
+            match self.fetch_block(&metainfo, &block).await {
+                Ok(data) => {
+                    failures = 0;
+                    let mut torrent = torrent.lock().await;
+                    // Counted in the totals the trackers are told, same as from the peers
+                    torrent
+                        .transfer_totals()
+                        .record_download(data.data.len() as u64);
+                    if let Err(e) = torrent.add_block(data).await {
+                        log::warn!(
+                            "Failed to add block of piece {} from {}: {:?}",
+                            block.piece_index,
+                            self.url,
+                            e
+                        );
+                    }
+                }
+                Err(WebSeedError::Busy(delay)) => {
+                    log::debug!("HTTP seed {} is busy, retry in {:?}", self.url, delay);
+                    torrent
+                        .lock()
+                        .await
+                        .cancel_request(self.requester_id(), &block)
+                        .await;
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => {
+                    log::warn!("Failed to download from web seed {}: {:?}", self.url, e);
+                    torrent
+                        .lock()
+                        .await
+                        .cancel_request(self.requester_id(), &block)
+                        .await;
+                    failures += 1;
+                    if failures >= MAX_FAILURES {
+                        log::warn!("Give up web seed {}", self.url);
+                        return;
+                    }
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    pub(crate) async fn fetch_block(
+        &self,
+        metainfo: &MetaInfo,
+        block: &BlockInfo,
+    ) -> Result<Block> {
+        let data = match self.kind {
+            WebSeedKind::Url => self.fetch_file_ranges(metainfo, block).await?,
+            WebSeedKind::HttpSeed => self.fetch_piece_range(metainfo, block).await?,
+        };
+        if data.len() != block.length as usize {
+            return Err(WebSeedError::ShortResponse);
+        }
+        Ok(Block {
+            piece_index: block.piece_index,
+            begin: block.begin,
+            data,
+            peer: None,
+        })
+    }
+
+    // Fetch the block with a range request to each file it covers.
+    async fn fetch_file_ranges(&self, metainfo: &MetaInfo, block: &BlockInfo) -> Result<Vec<u8>> {
+        let offset =
+            block.piece_index as u64 * metainfo.info.piece_length as u64 + block.begin as u64;
+        let mut data = Vec::with_capacity(block.length as usize);
+        for (file, file_offset, length) in Disk::file_spans(metainfo, offset, block.length as usize)
+        {
+            // The servers don't have the padding files
+            if file.attributes().padding {
+                data.resize(data.len() + length, 0);
+                continue;
+            }
+            let url = self.file_url(metainfo, &file.path)?;
+            let bytes = self.fetch_range(url, file_offset, length).await?;
+            data.extend_from_slice(&bytes);
+        }
+        Ok(data)
+    }
+
+    // Ask the script for the range of the piece, the end of the range is inclusive.
+    // A busy script answers 503 with the seconds to wait in the body.
+    async fn fetch_piece_range(&self, metainfo: &MetaInfo, block: &BlockInfo) -> Result<Vec<u8>> {
+        let mut url = self.url.clone();
+        let query = format!(
+            "info_hash={}&piece={}&ranges={}-{}",
+            percent_encode(&metainfo.info_hash, URL_ENCODE_RESERVED),
+            block.piece_index,
+            block.begin,
+            block.begin + block.length - 1
+        );
+        match url.query() {
+            Some(existing) if !existing.is_empty() => {
+                let query = format!("{}&{}", existing, query);
+                url.set_query(Some(&query));
+            }
+            _ => url.set_query(Some(&query)),
+        }
+        let response = self.client.get(url).send().await?;
+        let status = response.status();
+        let bytes = response.bytes().await?;
+        match status {
+            StatusCode::OK => Ok(bytes.to_vec()),
+            StatusCode::SERVICE_UNAVAILABLE => {
+                let delay = std::str::from_utf8(&bytes)
+                    .ok()
+                    .and_then(|it| it.trim().parse().ok())
+                    .map(Duration::from_secs)
+                    .unwrap_or(RETRY_DELAY);
+                Err(WebSeedError::Busy(delay.min(MAX_BUSY_DELAY)))
+            }
+            status => Err(WebSeedError::UnexpectedStatus(status)),
+        }
+    }
+
+    async fn fetch_range(&self, url: Url, offset: u64, length: usize) -> Result<Vec<u8>> {
+        let end = offset + length as u64 - 1;
+        let response = self
+            .client
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", offset, end))
+            .send()
+            .await?;
+        let status = response.status();
+        let bytes = response.bytes().await?;
+        let range = match status {
+            StatusCode::PARTIAL_CONTENT => 0..length,
+            // The server ignored the range and sent the whole file
+            StatusCode::OK => offset as usize..offset as usize + length,
+            status => return Err(WebSeedError::UnexpectedStatus(status)),
+        };
+        bytes
+            .get(range)
+            .map(|it| it.to_vec())
+            .ok_or(WebSeedError::ShortResponse)
+    }
+
+    // The URL of a file, the torrent name is appended to the URL ending in a slash,
+    // and the multi-file torrents always have the name and the file path appended.
+    fn file_url(&self, metainfo: &MetaInfo, path: &[String]) -> Result<Url> {
+        let is_multi_file = metainfo.info.files.is_some();
+        if !is_multi_file && !self.url.path().ends_with('/') {
+            return Ok(self.url.clone());
+        }
+        let mut url = self.url.clone();
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| WebSeedError::InvalidUrl)?;
+            segments.pop_if_empty();
+            if is_multi_file {
+                segments.push(&metainfo.info.name);
+            }
+            segments.extend(path);
+        }
+        Ok(url)
+    }
+}
+
+// Download from all the web seeds of the torrent at once, the blocks are striped across them
+// by their measured throughput, and a failing mirror backs off for longer each time.
+pub struct WebSeedPool {
+    seeds: Vec<SeedState>,
+}
+
+struct SeedState {
+    seed: Arc<WebSeed>,
+    // Bytes per second, smoothed over the recent blocks. None until the first block.
+    throughput: Option<f64>,
+    requests: u32,
+    errors: u32,
+    // Failures in a row, the seed is given up at MAX_FAILURES.
+    failures: u32,
+    in_flight: usize,
+    backoff_until: Option<Instant>,
+}
+
+impl WebSeedPool {
+    pub fn new(seeds: Vec<WebSeed>) -> Self {
+        Self {
+            seeds: seeds.into_iter().map(SeedState::new).collect(),
+        }
+    }
+
+    pub fn from_metainfo(metainfo: &MetaInfo) -> Self {
+        Self::new(WebSeed::from_metainfo(metainfo))
+    }
+
+    pub fn len(&self) -> usize {
+        self.seeds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seeds.is_empty()
+    }
+
+    // Download the blocks the torrent is missing until nothing is left to request,
+    // or every seed has been given up.
+    pub async fn run(mut self, torrent: Arc<Mutex<Torrent>>) {
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            let mut exhausted = false;
+            while let Some(index) = self.pick(Instant::now()) {
+                let (metainfo, block) = {
+                    let torrent = torrent.lock().await;
+                    let Some(metainfo) = torrent.metainfo().cloned() else {
+                        return;
+                    };
+                    let have_all = BitField::repeat(true, metainfo.piece_count());
+                    let requester = self.seeds[index].seed.requester_id();
+                    let Some(block) = torrent.request_block(requester, &have_all).await else {
+                        exhausted = true;
+                        break;
+                    };
+                    (metainfo, block)
+                };
+                let state = &mut self.seeds[index];
+                state.in_flight += 1;
+                state.requests += 1;
+                let seed = state.seed.clone();
+                in_flight.push(async move {
+                    let started_at = Instant::now();
+                    let result = seed.fetch_block(&metainfo, &block).await;
+                    (index, block, result, started_at.elapsed())
+                });
+            }
+
+            let Some((index, block, result, elapsed)) = in_flight.next().await else {
+                if exhausted || self.seeds.iter().all(|it| it.failures >= MAX_FAILURES) {
+                    return;
+                }
+                // Every seed is backing off
+                let wake_at = self.seeds.iter().filter_map(|it| it.backoff_until).min();
+                if let Some(wake_at) = wake_at {
+                    tokio::time::sleep_until(wake_at.into()).await;
+                }
+                continue;
+            };
+            let state = &mut self.seeds[index];
+            state.in_flight -= 1;
+            match result {
+                Ok(data) => {
+                    state.on_success(data.data.len(), elapsed);
+                    if let Err(e) = torrent.lock().await.add_block(data).await {
+                        log::warn!(
+                            "Failed to add block of piece {} from {}: {:?}",
+                            block.piece_index,
+                            state.seed.url,
+                            e
+                        );
+                    }
+                }
+                Err(e) => {
+                    log::debug!(
+                        "Failed to download from web seed {}: {:?}",
+                        state.seed.url,
+                        e
+                    );
+                    state.on_failure(e, Instant::now());
+                    if state.failures >= MAX_FAILURES {
+                        log::warn!("Give up web seed {}", state.seed.url);
+                    }
+                    let requester = state.seed.requester_id();
+                    torrent.lock().await.cancel_request(requester, &block).await;
+                }
+            }
+        }
+    }
+
+    // The best seed with a free slot.
+    fn pick(&self, now: Instant) -> Option<usize> {
+        self.seeds
+            .iter()
+            .enumerate()
+            .filter(|(_, it)| it.is_available(now) && it.in_flight < it.slots())
+            .max_by(|(_, a), (_, b)| {
+                a.score()
+                    .total_cmp(&b.score())
+                    .then(b.in_flight.cmp(&a.in_flight))
+            })
+            .map(|(index, _)| index)
+    }
+}
+
+impl SeedState {
+    fn new(seed: WebSeed) -> Self {
+        Self {
+            seed: Arc::new(seed),
+            throughput: None,
+            requests: 0,
+            errors: 0,
+            failures: 0,
+            in_flight: 0,
+            backoff_until: None,
+        }
+    }
+
+    fn is_available(&self, now: Instant) -> bool {
+        self.failures < MAX_FAILURES && self.backoff_until.is_none_or(|it| now >= it)
+    }
+
+    fn slots(&self) -> usize {
+        match self.throughput {
+            // Probe it with a single request first
+            None => 1,
+            Some(throughput) => {
+                (1 + (throughput / SLOT_THROUGHPUT) as usize).min(MAX_IN_FLIGHT_PER_SEED)
+            }
+        }
+    }
+
+    // The throughput discounted by how often the seed fails, the unmeasured seeds go
+    // first so each one gets measured.
+    fn score(&self) -> f64 {
+        let error_rate = match self.requests {
+            0 => 0.0,
+            requests => self.errors as f64 / requests as f64,
+        };
+        self.throughput.unwrap_or(f64::INFINITY) * (1.0 - error_rate)
+    }
+
+    fn on_success(&mut self, bytes: usize, elapsed: Duration) {
+        self.failures = 0;
+        self.backoff_until = None;
+        let sample = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        self.throughput = Some(match self.throughput {
+            Some(throughput) => throughput * 0.7 + sample * 0.3,
+            None => sample,
+        });
+    }
+
+    fn on_failure(&mut self, error: WebSeedError, now: Instant) {
+        // Busy isn't the seed's fault, it just tells when to come back
+        let delay = match error {
+            WebSeedError::Busy(delay) => delay,
+            _ => {
+                self.errors += 1;
+                self.failures += 1;
+                (RETRY_DELAY * 2u32.pow(self.failures - 1)).min(MAX_RETRY_DELAY)
+            }
+        };
+        self.backoff_until = Some(now + delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::TestMetaInfo;
+
+    #[test]
+    fn test_file_url() {
+        let single = TestMetaInfo::new("my torrent", 4).single(b"abcd").build();
+        let multi = TestMetaInfo::new("my torrent", 4)
+            .file("dir/a b.txt", b"abcd")
+            .build();
+        let path = vec!["dir".to_string(), "a b.txt".to_string()];
+
+        let seed = WebSeed::new(Url::parse("http://a.com/file.iso").unwrap());
+        assert_eq!(
+            seed.file_url(&single, &["my torrent".to_string()])
+                .unwrap()
+                .as_str(),
+            "http://a.com/file.iso"
+        );
+        assert_eq!(
+            seed.file_url(&multi, &path).unwrap().as_str(),
+            "http://a.com/file.iso/my%20torrent/dir/a%20b.txt"
+        );
+
+        let seed = WebSeed::new(Url::parse("http://a.com/files/").unwrap());
+        assert_eq!(
+            seed.file_url(&single, &["my torrent".to_string()])
+                .unwrap()
+                .as_str(),
+            "http://a.com/files/my%20torrent"
+        );
+        assert_eq!(
+            seed.file_url(&multi, &path).unwrap().as_str(),
+            "http://a.com/files/my%20torrent/dir/a%20b.txt"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_from_web_seed() {
+        let mut server = mockito::Server::new_async().await;
+        // The second piece crosses the two files
+        let metainfo = TestMetaInfo::new("my torrent", 4)
+            .file("a", b"abcdef")
+            .file("b", b"ghij")
+            .build();
+        let ranges = [
+            ("/my%20torrent/a", "bytes=0-3", "abcd"),
+            ("/my%20torrent/a", "bytes=4-5", "ef"),
+            ("/my%20torrent/b", "bytes=0-1", "gh"),
+            ("/my%20torrent/b", "bytes=2-3", "ij"),
+        ];
+        let mut mocks = Vec::new();
+        for (path, range, body) in ranges {
+            let mock = server
+                .mock("GET", path)
+                .match_header("range", range)
+                .with_status(206)
+                .with_body(body)
+                .create_async()
+                .await;
+            mocks.push(mock);
+        }
+
+        let torrent = Arc::new(Mutex::new(Torrent::from_metainfo(metainfo)));
+        let seed = WebSeed::new(Url::parse(&format!("{}/", server.url())).unwrap());
+        seed.run(torrent.clone()).await;
+
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+        let torrent = torrent.lock().await;
+        for piece_index in 0..3 {
+            assert!(torrent.has_piece(piece_index).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_from_http_seed() {
+        let mut server = mockito::Server::new_async().await;
+        let mut metainfo = TestMetaInfo::new("my torrent", 4)
+            .single(b"abcdefgh")
+            .build();
+        metainfo.info_hash = [b'a'; 20];
+        let info_hash = "a".repeat(20);
+        let busy = server
+            .mock("GET", "/seed.php")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("info_hash".into(), info_hash.clone()),
+                mockito::Matcher::UrlEncoded("piece".into(), "0".into()),
+            ]))
+            .with_status(503)
+            .with_body("0")
+            .expect(1)
+            .create_async()
+            .await;
+        let seed = WebSeed::http_seed(Url::parse(&format!("{}/seed.php", server.url())).unwrap());
+        let block = BlockInfo::new(0, 1, 3);
+        assert!(matches!(
+            seed.fetch_block(&metainfo, &block).await,
+            Err(WebSeedError::Busy(delay)) if delay.is_zero()
+        ));
+        busy.assert_async().await;
+
+        let mut mocks = Vec::new();
+        for (piece, body) in [("0", "abcd"), ("1", "efgh")] {
+            let mock = server
+                .mock("GET", "/seed.php")
+                .match_query(mockito::Matcher::AllOf(vec![
+                    mockito::Matcher::UrlEncoded("info_hash".into(), info_hash.clone()),
+                    mockito::Matcher::UrlEncoded("piece".into(), piece.into()),
+                    mockito::Matcher::UrlEncoded("ranges".into(), "0-3".into()),
+                ]))
+                .with_body(body)
+                .create_async()
+                .await;
+            mocks.push(mock);
+        }
+        let torrent = Arc::new(Mutex::new(Torrent::from_metainfo(metainfo)));
+        seed.run(torrent.clone()).await;
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+        let torrent = torrent.lock().await;
+        assert!(torrent.has_piece(0).await);
+        assert!(torrent.has_piece(1).await);
+    }
+
+    #[test]
+    fn test_pool_pick() {
+        let seed = |url: &str| WebSeed::new(Url::parse(url).unwrap());
+        let mut pool = WebSeedPool::new(vec![seed("http://a.com/"), seed("http://b.com/")]);
+        let now = Instant::now();
+
+        pool.seeds[0].on_success(1024 * 1024, Duration::from_secs(1));
+        pool.seeds[1].on_success(100 * 1024, Duration::from_secs(1));
+        assert_eq!(pool.seeds[0].slots(), 5);
+        assert_eq!(pool.seeds[1].slots(), 1);
+        assert_eq!(pool.pick(now), Some(0));
+        // The faster seed is full
+        pool.seeds[0].in_flight = 5;
+        assert_eq!(pool.pick(now), Some(1));
+        pool.seeds[1].in_flight = 1;
+        assert_eq!(pool.pick(now), None);
+
+        pool.seeds[0].in_flight = 0;
+        pool.seeds[0].on_failure(WebSeedError::ShortResponse, now);
+        assert_eq!(pool.seeds[0].backoff_until, Some(now + RETRY_DELAY));
+        pool.seeds[0].on_failure(WebSeedError::ShortResponse, now);
+        assert_eq!(pool.seeds[0].backoff_until, Some(now + RETRY_DELAY * 2));
+        assert_eq!(pool.pick(now), None);
+        assert_eq!(pool.pick(now + RETRY_DELAY * 2), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_download_from_pool() {
+        let mut good = mockito::Server::new_async().await;
+        let mut broken = mockito::Server::new_async().await;
+        let metainfo = TestMetaInfo::new("my torrent", 4)
+            .single(b"abcdefgh")
+            .build();
+        let mut mocks = Vec::new();
+        for (range, body) in [("bytes=0-3", "abcd"), ("bytes=4-7", "efgh")] {
+            let mock = good
+                .mock("GET", "/file.iso")
+                .match_header("range", range)
+                .with_status(206)
+                .with_body(body)
+                .create_async()
+                .await;
+            mocks.push(mock);
+        }
+        let failing = broken
+            .mock("GET", "/file.iso")
+            .with_status(500)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let seed = |server: &mockito::Server| {
+            WebSeed::new(Url::parse(&format!("{}/file.iso", server.url())).unwrap())
+        };
+        let pool = WebSeedPool::new(vec![seed(&broken), seed(&good)]);
+        let torrent = Arc::new(Mutex::new(Torrent::from_metainfo(metainfo)));
+        pool.run(torrent.clone()).await;
+
+        // The broken seed backs off after its first failure, the other seed takes its block
+        failing.assert_async().await;
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+        let torrent = torrent.lock().await;
+        assert!(torrent.has_piece(0).await);
+        assert!(torrent.has_piece(1).await);
+    }
+}
@@ -0,0 +1,423 @@
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite};
+use url::Url;
+
+use crate::types::{PeerId, Sha1Hash};
+
+// The WebTorrent trackers, which browsers announce to over a WebSocket.
+// https://github.com/webtorrent/bittorrent-tracker
+//
+// Browsers can't open sockets, they connect to each other with WebRTC. The tracker only
+// relays the WebRTC offers and answers between the peers, this is the signalling half of it.
+// The data channel itself isn't bridged to the peer sessions yet: the sessions are known by
+// the socket address of the peer, which WebRTC hides from us.
+
+pub(crate) type Result<T> = std::result::Result<T, WebTorrentError>;
+
+#[derive(Debug, Error)]
+pub enum WebTorrentError {
+    // Boxed, the WebSocket error is large
+    #[error("Failed to talk to WebSocket tracker")]
+    WebSocket(#[from] Box<tungstenite::Error>),
+    #[error("Invalid WebSocket tracker message")]
+    Json(#[from] serde_json::Error),
+    #[error("Tracker failed: {0}")]
+    Failure(String),
+    #[error("Tracker closed the connection")]
+    Closed,
+}
+
+// The WebRTC session description, created and consumed by the WebRTC stack.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionDescription {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub sdp: String,
+}
+
+// An offer to whoever the tracker hands it to, the answer refers it by the id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Offer {
+    pub offer_id: [u8; 20],
+    pub offer: SessionDescription,
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnounceParams {
+    pub info_hash: Sha1Hash,
+    pub peer_id: PeerId,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    // started, completed or stopped, None for the regular announces.
+    pub event: Option<&'static str>,
+    pub offers: Vec<Offer>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WssEvent {
+    Announced {
+        interval: u64,
+        seeders: Option<u32>,
+        leechers: Option<u32>,
+    },
+    // A peer wants to connect, answer it with `send_answer`.
+    Offer {
+        info_hash: Sha1Hash,
+        peer_id: PeerId,
+        offer_id: [u8; 20],
+        offer: SessionDescription,
+    },
+    // A peer accepted one of our offers.
+    Answer {
+        info_hash: Sha1Hash,
+        peer_id: PeerId,
+        offer_id: [u8; 20],
+        answer: SessionDescription,
+    },
+}
+
+mod raw {
+    use super::*;
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    pub struct Message {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub action: Option<String>,
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            with = "binary_string"
+        )]
+        pub info_hash: Option<Vec<u8>>,
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            with = "binary_string"
+        )]
+        pub peer_id: Option<Vec<u8>>,
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            with = "binary_string"
+        )]
+        pub to_peer_id: Option<Vec<u8>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub uploaded: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub downloaded: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub left: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub event: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub numwant: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub offers: Option<Vec<Offer>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub offer: Option<SessionDescription>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub answer: Option<SessionDescription>,
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            with = "binary_string"
+        )]
+        pub offer_id: Option<Vec<u8>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub interval: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub complete: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub incomplete: Option<u32>,
+        #[serde(
+            rename = "failure reason",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub failure_reason: Option<String>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Offer {
+        pub offer: SessionDescription,
+        #[serde(with = "binary_string")]
+        pub offer_id: Option<Vec<u8>>,
+    }
+
+    // The hashes and ids are sent as JSON strings, one char per byte.
+    mod binary_string {
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            bytes: &Option<Vec<u8>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match bytes {
+                Some(bytes) => serializer
+                    .serialize_str(&bytes.iter().map(|it| *it as char).collect::<String>()),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Vec<u8>>, D::Error> {
+            let Some(string) = Option::<String>::deserialize(deserializer)? else {
+                return Ok(None);
+            };
+            string
+                .chars()
+                .map(|it| u8::try_from(it).map_err(serde::de::Error::custom))
+                .collect::<Result<Vec<u8>, _>>()
+                .map(Some)
+        }
+    }
+}
+
+pub struct WssTracker {
+    url: Url,
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl WssTracker {
+    pub async fn connect(url: Url) -> Result<Self> {
+        let (socket, _) = tokio_tungstenite::connect_async(url.as_str())
+            .await
+            .map_err(Box::new)?;
+        Ok(Self { url, socket })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub async fn announce(&mut self, params: AnnounceParams) -> Result<()> {
+        let offers = params
+            .offers
+            .into_iter()
+            .map(|it| raw::Offer {
+                offer: it.offer,
+                offer_id: Some(it.offer_id.to_vec()),
+            })
+            .collect::<Vec<_>>();
+        let message = raw::Message {
+            action: Some("announce".to_string()),
+            info_hash: Some(params.info_hash.to_vec()),
+            peer_id: Some(params.peer_id.to_vec()),
+            uploaded: Some(params.uploaded),
+            downloaded: Some(params.downloaded),
+            left: Some(params.left),
+            event: params.event.map(str::to_string),
+            // Each peer the tracker hands us takes one of the offers
+            numwant: Some(offers.len()),
+            offers: Some(offers),
+            ..Default::default()
+        };
+        self.send(&message).await
+    }
+
+    // Answer an offer received from `next_event`, the tracker relays it to the peer.
+    pub async fn send_answer(
+        &mut self,
+        info_hash: Sha1Hash,
+        peer_id: PeerId,
+        to_peer_id: PeerId,
+        offer_id: [u8; 20],
+        answer: SessionDescription,
+    ) -> Result<()> {
+        let message = raw::Message {
+            action: Some("announce".to_string()),
+            info_hash: Some(info_hash.to_vec()),
+            peer_id: Some(peer_id.to_vec()),
+            to_peer_id: Some(to_peer_id.to_vec()),
+            offer_id: Some(offer_id.to_vec()),
+            answer: Some(answer),
+            ..Default::default()
+        };
+        self.send(&message).await
+    }
+
+    // Wait for the next message from the tracker, the messages we don't understand are skipped.
+    pub async fn next_event(&mut self) -> Result<WssEvent> {
+        loop {
+            let message = match self.socket.next().await {
+                Some(message) => message.map_err(Box::new)?,
+                None => return Err(WebTorrentError::Closed),
+            };
+            let text = match message {
+                tungstenite::Message::Text(text) => text,
+                tungstenite::Message::Close(_) => return Err(WebTorrentError::Closed),
+                _ => continue,
+            };
+            let message: raw::Message = serde_json::from_str(&text)?;
+            if let Some(event) = parse_event(message)? {
+                return Ok(event);
+            }
+        }
+    }
+
+    async fn send(&mut self, message: &raw::Message) -> Result<()> {
+        let text = serde_json::to_string(message)?;
+        self.socket
+            .send(tungstenite::Message::Text(text.into()))
+            .await
+            .map_err(Box::new)?;
+        Ok(())
+    }
+}
+
+fn parse_event(message: raw::Message) -> Result<Option<WssEvent>> {
+    if let Some(reason) = message.failure_reason {
+        return Err(WebTorrentError::Failure(reason));
+    }
+    let info_hash = message
+        .info_hash
+        .as_deref()
+        .and_then(|it| it.try_into().ok());
+    let peer_id = message.peer_id.as_deref().and_then(|it| it.try_into().ok());
+    let offer_id = message
+        .offer_id
+        .as_deref()
+        .and_then(|it| it.try_into().ok());
+    let event = match (message.offer, message.answer) {
+        (Some(offer), _) => {
+            info_hash
+                .zip(peer_id)
+                .zip(offer_id)
+                .map(|((info_hash, peer_id), offer_id)| WssEvent::Offer {
+                    info_hash,
+                    peer_id,
+                    offer_id,
+                    offer,
+                })
+        }
+        (_, Some(answer)) => {
+            info_hash
+                .zip(peer_id)
+                .zip(offer_id)
+                .map(|((info_hash, peer_id), offer_id)| WssEvent::Answer {
+                    info_hash,
+                    peer_id,
+                    offer_id,
+                    answer,
+                })
+        }
+        _ => message.interval.map(|interval| WssEvent::Announced {
+            interval,
+            seeders: message.complete,
+            leechers: message.incomplete,
+        }),
+    };
+    Ok(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn description(kind: &str) -> SessionDescription {
+        SessionDescription {
+            kind: kind.to_string(),
+            sdp: "v=0".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_binary_string() {
+        let message = raw::Message {
+            info_hash: Some(vec![0x00, 0x7f, 0x80, 0xff]),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&message).unwrap();
+        // One char per byte, not UTF-8 of the bytes
+        assert!(json.ends_with("\u{80}ÿ\"}"));
+        let message: raw::Message = serde_json::from_str(&json).unwrap();
+        assert_eq!(message.info_hash, Some(vec![0x00, 0x7f, 0x80, 0xff]));
+
+        assert!(serde_json::from_str::<raw::Message>(r#"{"info_hash":"Ā"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_event() {
+        let message: raw::Message =
+            serde_json::from_str(r#"{"action":"announce","interval":120,"complete":3}"#).unwrap();
+        assert_eq!(
+            parse_event(message).unwrap(),
+            Some(WssEvent::Announced {
+                interval: 120,
+                seeders: Some(3),
+                leechers: None,
+            })
+        );
+
+        let message: raw::Message =
+            serde_json::from_str(r#"{"failure reason":"unregistered torrent"}"#).unwrap();
+        assert!(matches!(
+            parse_event(message),
+            Err(WebTorrentError::Failure(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_receive_answer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let announce = socket.next().await.unwrap().unwrap();
+            let announce: raw::Message = serde_json::from_str(announce.to_text().unwrap()).unwrap();
+            assert_eq!(announce.numwant, Some(1));
+            let offer = &announce.offers.as_ref().unwrap()[0];
+
+            // Another peer answers our offer
+            let answer = raw::Message {
+                action: Some("announce".to_string()),
+                info_hash: announce.info_hash.clone(),
+                peer_id: Some(vec![b'b'; 20]),
+                offer_id: offer.offer_id.clone(),
+                answer: Some(description("answer")),
+                ..Default::default()
+            };
+            let text = serde_json::to_string(&answer).unwrap();
+            socket
+                .send(tungstenite::Message::Text(text.into()))
+                .await
+                .unwrap();
+        });
+
+        let url = Url::parse(&format!("ws://{}/announce", addr)).unwrap();
+        let mut tracker = WssTracker::connect(url).await.unwrap();
+        tracker
+            .announce(AnnounceParams {
+                info_hash: [0xff; 20],
+                peer_id: [b'a'; 20],
+                uploaded: 0,
+                downloaded: 0,
+                left: 100,
+                event: Some("started"),
+                offers: vec![Offer {
+                    offer_id: [7; 20],
+                    offer: description("offer"),
+                }],
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            tracker.next_event().await.unwrap(),
+            WssEvent::Answer {
+                info_hash: [0xff; 20],
+                peer_id: [b'b'; 20],
+                offer_id: [7; 20],
+                answer: description("answer"),
+            }
+        );
+        server.await.unwrap();
+    }
+}
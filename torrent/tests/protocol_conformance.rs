@@ -0,0 +1,810 @@
+// Drive the engine's peer listener over raw TCP like a remote peer would,
+// and check it answers the malformed and hostile input as documented.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+use torrent::{
+    dht::Dht,
+    dialer::{DialCandidate, PeerSource},
+    ip_filter::{IpFilter, SharedIpFilter},
+    listener::{DialEvent, PeerListener},
+    metainfo::MetaInfo,
+    mse::EncryptionPolicy,
+    peer_tls::PeerTls,
+    torrent::{Torrent, TorrentOptions},
+    transport::{Transport, TransportMode, TransportPolicy},
+};
+
+const ENGINE_PEER_ID: [u8; 20] = *b"-BD0001-conformance0";
+// A single piece torrent, the engine has none of it.
+const TORRENT: &[u8] = b"d8:announce27:http://example.com/announce4:infod6:lengthi262144e4:name4:test12:piece lengthi262144e6:pieces20:12345678901234567890ee";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(300);
+
+async fn start_engine() -> (SocketAddr, [u8; 20]) {
+    let metainfo = MetaInfo::from_bytes(TORRENT).unwrap();
+    let info_hash = metainfo.info_hash;
+    let listener = PeerListener::bind("127.0.0.1:0", ENGINE_PEER_ID)
+        .await
+        .unwrap()
+        .with_handshake_timeout(HANDSHAKE_TIMEOUT);
+    listener.add_torrent(Torrent::from_metainfo(metainfo));
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(listener.run());
+    (addr, info_hash)
+}
+
+fn handshake(info_hash: [u8; 20]) -> Vec<u8> {
+    let mut bytes = vec![19u8];
+    bytes.extend_from_slice(b"BitTorrent protocol");
+    // No extension bits, so the engine has nothing to send after its handshake
+    bytes.extend_from_slice(&[0; 8]);
+    bytes.extend_from_slice(&info_hash);
+    bytes.extend_from_slice(b"-XX0001-remotepeer01");
+    bytes
+}
+
+fn message(id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = ((payload.len() + 1) as u32).to_be_bytes().to_vec();
+    bytes.push(id);
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+fn request(piece_index: u32, begin: u32, length: u32) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&piece_index.to_be_bytes());
+    payload.extend_from_slice(&begin.to_be_bytes());
+    payload.extend_from_slice(&length.to_be_bytes());
+    message(6, &payload)
+}
+
+// Connect and complete the handshake, returns the stream ready for the messages.
+async fn connect(addr: SocketAddr, info_hash: [u8; 20]) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&handshake(info_hash)).await.unwrap();
+    let mut reply = [0u8; 68];
+    timeout(Duration::from_secs(2), stream.read_exact(&mut reply))
+        .await
+        .expect("Engine didn't reply to the handshake")
+        .unwrap();
+    assert_eq!(&reply[1..20], b"BitTorrent protocol");
+    assert_eq!(&reply[28..48], &info_hash);
+    assert_eq!(&reply[48..68], &ENGINE_PEER_ID);
+    stream
+}
+
+// The engine closed the connection without sending anything else.
+async fn assert_closed(stream: &mut TcpStream) {
+    let mut buffer = [0u8; 64];
+    let read = timeout(Duration::from_secs(2), stream.read(&mut buffer))
+        .await
+        .expect("Engine kept the connection open");
+    match read {
+        Ok(n) => assert_eq!(n, 0, "Engine replied instead of closing"),
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+    }
+}
+
+// The remote has a piece the engine wants.
+async fn assert_interested(stream: &mut TcpStream) {
+    let mut interested = [0u8; 5];
+    timeout(Duration::from_secs(2), stream.read_exact(&mut interested))
+        .await
+        .expect("Engine didn't tell it's interested")
+        .unwrap();
+    assert_eq!(interested, [0, 0, 0, 1, 2]);
+}
+
+async fn assert_open(stream: &mut TcpStream) {
+    let mut buffer = [0u8; 64];
+    assert!(
+        timeout(Duration::from_millis(200), stream.read(&mut buffer))
+            .await
+            .is_err(),
+        "Engine closed or wrote to the connection"
+    );
+}
+
+#[tokio::test]
+async fn test_handshake_is_answered() {
+    let (addr, info_hash) = start_engine().await;
+    let mut stream = connect(addr, info_hash).await;
+    // Keep-alive keeps the connection open
+    stream.write_all(&[0, 0, 0, 0]).await.unwrap();
+    assert_open(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_handshake_over_utp() {
+    let (addr, info_hash) = start_engine().await;
+    let policy = TransportPolicy {
+        mode: TransportMode::UtpOnly,
+        limit_tcp_when_utp: false,
+    };
+    let mut stream = policy.connect(addr, None).await.unwrap();
+    assert_eq!(stream.transport(), Transport::Utp);
+    stream.write_all(&handshake(info_hash)).await.unwrap();
+    let mut reply = [0u8; 68];
+    timeout(Duration::from_secs(2), stream.read_exact(&mut reply))
+        .await
+        .expect("Engine didn't reply to the handshake")
+        .unwrap();
+    assert_eq!(&reply[28..48], &info_hash);
+    assert_eq!(&reply[48..68], &ENGINE_PEER_ID);
+}
+
+#[tokio::test]
+async fn test_handshake_over_v4_and_v6() {
+    let metainfo = MetaInfo::from_bytes(TORRENT).unwrap();
+    let info_hash = metainfo.info_hash;
+    let listener = PeerListener::bind_dual_stack(0, ENGINE_PEER_ID)
+        .await
+        .unwrap();
+    listener.add_torrent(Torrent::from_metainfo(metainfo));
+    let addrs = listener.local_addrs().unwrap();
+    let port = listener.listen_port().get();
+    assert_eq!(addrs.len(), 2);
+    assert!(addrs.iter().all(|it| it.port() == port));
+    tokio::spawn(listener.run());
+
+    for ip in [
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(Ipv6Addr::LOCALHOST),
+    ] {
+        let mut stream = connect(SocketAddr::new(ip, port), info_hash).await;
+        assert_eq!(stream.local_addr().unwrap().ip(), ip);
+        assert_open(&mut stream).await;
+    }
+}
+
+#[tokio::test]
+async fn test_bad_protocol_string_is_dropped() {
+    let (addr, info_hash) = start_engine().await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut bytes = handshake(info_hash);
+    bytes[1..20].copy_from_slice(b"BitTorrent protocoX");
+    stream.write_all(&bytes).await.unwrap();
+    assert_closed(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_unknown_info_hash_is_dropped() {
+    let (addr, _) = start_engine().await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&handshake([0xab; 20])).await.unwrap();
+    assert_closed(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_torrents_added_and_removed_while_running() {
+    let listener = PeerListener::bind("127.0.0.1:0", ENGINE_PEER_ID)
+        .await
+        .unwrap();
+    let registry = listener.registry();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(listener.run());
+
+    let metainfo = MetaInfo::from_bytes(TORRENT).unwrap();
+    let info_hash = metainfo.info_hash;
+    registry.add_torrent(Torrent::from_metainfo(metainfo));
+    let mut stream = connect(addr, info_hash).await;
+    assert_open(&mut stream).await;
+
+    registry.remove_torrent(&info_hash);
+    assert!(!registry.contains(&info_hash));
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&handshake(info_hash)).await.unwrap();
+    assert_closed(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_blocked_peer_is_dropped() {
+    let ip_filter = SharedIpFilter::default();
+    let listener = PeerListener::bind("127.0.0.1:0", ENGINE_PEER_ID)
+        .await
+        .unwrap()
+        .with_ip_filter(ip_filter.clone());
+    let metainfo = MetaInfo::from_bytes(TORRENT).unwrap();
+    let info_hash = metainfo.info_hash;
+    listener.add_torrent(Torrent::from_metainfo(metainfo));
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(listener.run());
+
+    let mut stream = connect(addr, info_hash).await;
+    assert_open(&mut stream).await;
+
+    // The new blocklist applies to the next peers
+    ip_filter.replace(IpFilter::parse("localhost:127.0.0.0/8"), &[]);
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&handshake(info_hash)).await.unwrap();
+    assert_closed(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_blocked_peer_is_not_dialed() {
+    let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listener = PeerListener::bind("127.0.0.1:0", ENGINE_PEER_ID)
+        .await
+        .unwrap()
+        .with_ip_filter(SharedIpFilter::new(IpFilter::parse(
+            "localhost:127.0.0.0/8",
+        )));
+    let metainfo = MetaInfo::from_bytes(TORRENT).unwrap();
+    let info_hash = metainfo.info_hash;
+    listener.add_torrent(Torrent::from_metainfo(metainfo));
+
+    let (events, mut received) = mpsc::unbounded_channel();
+    let candidate = DialCandidate::new(peer.local_addr().unwrap(), PeerSource::Manual, None);
+    assert!(listener.registry().dial(&info_hash, candidate, events));
+    assert!(matches!(
+        received.recv().await,
+        Some(DialEvent::Failed { .. })
+    ));
+    assert!(
+        timeout(Duration::from_millis(200), peer.accept())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_hybrid_torrent_answers_both_swarms() {
+    let mut info = b"d9:file treed4:testd0:d6:lengthi5e11:pieces root32:".to_vec();
+    info.extend_from_slice(&[1; 32]);
+    info.extend_from_slice(b"eee6:lengthi5e12:meta versioni2e4:name4:test");
+    info.extend_from_slice(b"12:piece lengthi16384e6:pieces20:12345678901234567890e");
+    let mut bytes = b"d4:info".to_vec();
+    bytes.extend_from_slice(&info);
+    bytes.extend_from_slice(b"e");
+    let metainfo = MetaInfo::from_bytes(&bytes).unwrap();
+    let info_hashes = metainfo.info_hashes();
+    assert_eq!(info_hashes.len(), 2);
+
+    let listener = PeerListener::bind("127.0.0.1:0", ENGINE_PEER_ID)
+        .await
+        .unwrap();
+    listener.add_torrent(Torrent::from_metainfo(metainfo));
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(listener.run());
+
+    // The v1 hash and the truncated v2 hash
+    for info_hash in info_hashes {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&handshake(info_hash)).await.unwrap();
+        let mut reply = [0u8; 68];
+        timeout(Duration::from_secs(2), stream.read_exact(&mut reply))
+            .await
+            .expect("Engine didn't reply to the handshake")
+            .unwrap();
+        assert_eq!(&reply[28..48], &info_hash);
+        // The v2 bit of the reserved bytes
+        assert_eq!(reply[27] & 0x10, 0x10);
+    }
+}
+
+#[tokio::test]
+async fn test_slow_handshake_times_out() {
+    let (addr, info_hash) = start_engine().await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let bytes = handshake(info_hash);
+    // Trickle the handshake slower than the timeout allows
+    for byte in &bytes[..10] {
+        if stream.write_all(&[*byte]).await.is_err() {
+            // Already dropped by the engine
+            return;
+        }
+        sleep(HANDSHAKE_TIMEOUT / 5).await;
+    }
+    assert_closed(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_silent_peer_times_out() {
+    let listener = PeerListener::bind("127.0.0.1:0", ENGINE_PEER_ID)
+        .await
+        .unwrap()
+        .with_peer_timeout(Duration::from_millis(500));
+    let metainfo = MetaInfo::from_bytes(TORRENT).unwrap();
+    let info_hash = metainfo.info_hash;
+    listener.add_torrent(Torrent::from_metainfo(metainfo));
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(listener.run());
+
+    let mut stream = connect(addr, info_hash).await;
+    // A keep-alive counts as hearing from the peer
+    sleep(Duration::from_millis(300)).await;
+    stream.write_all(&[0, 0, 0, 0]).await.unwrap();
+    assert_open(&mut stream).await;
+    assert_closed(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_bitfield_after_have_is_dropped() {
+    let (addr, info_hash) = start_engine().await;
+    let mut stream = connect(addr, info_hash).await;
+    stream
+        .write_all(&message(4, &0u32.to_be_bytes()))
+        .await
+        .unwrap();
+    assert_interested(&mut stream).await;
+    stream.write_all(&message(5, &[0xff])).await.unwrap();
+    assert_closed(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_second_bitfield_is_ignored() {
+    let (addr, info_hash) = start_engine().await;
+    let mut stream = connect(addr, info_hash).await;
+    stream.write_all(&message(5, &[0xff])).await.unwrap();
+    stream.write_all(&message(5, &[0x00])).await.unwrap();
+    assert_interested(&mut stream).await;
+    assert_open(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_oversized_request_is_dropped() {
+    let (addr, info_hash) = start_engine().await;
+    let mut stream = connect(addr, info_hash).await;
+    stream.write_all(&request(0, 0, 1024 * 1024)).await.unwrap();
+    assert_closed(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_request_while_choked_is_ignored() {
+    let (addr, info_hash) = start_engine().await;
+    let mut stream = connect(addr, info_hash).await;
+    stream.write_all(&request(0, 0, 16 * 1024)).await.unwrap();
+    assert_open(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_interested_peer_is_unchoked() {
+    let (addr, info_hash) = start_engine().await;
+    let mut stream = connect(addr, info_hash).await;
+    // A free upload slot is given out on the next tick, not the next round
+    stream.write_all(&message(2, &[])).await.unwrap();
+    let mut unchoke = [0u8; 5];
+    timeout(Duration::from_secs(3), stream.read_exact(&mut unchoke))
+        .await
+        .expect("Engine didn't unchoke the interested peer")
+        .unwrap();
+    assert_eq!(unchoke, [0, 0, 0, 1, 1]);
+}
+
+#[tokio::test]
+async fn test_oversized_message_is_dropped() {
+    let (addr, info_hash) = start_engine().await;
+    let mut stream = connect(addr, info_hash).await;
+    // Only the length prefix of a 2 GiB message, the engine must not wait for the rest
+    stream
+        .write_all(&0x7fff_ffffu32.to_be_bytes())
+        .await
+        .unwrap();
+    stream.write_all(&[7]).await.unwrap();
+    assert_closed(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_port_message_with_wrong_length_is_dropped() {
+    let (addr, info_hash) = start_engine().await;
+    let mut stream = connect(addr, info_hash).await;
+    // A port with a trailing byte, followed by what would be misread as a new message
+    stream
+        .write_all(&message(9, &[0x1a, 0xe1, 0]))
+        .await
+        .unwrap();
+    stream.write_all(&message(2, &[])).await.unwrap();
+    assert_closed(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_short_extended_message_is_dropped() {
+    let (addr, info_hash) = start_engine().await;
+    let mut stream = connect(addr, info_hash).await;
+    // An extended message without the extended message id
+    stream.write_all(&message(20, &[])).await.unwrap();
+    assert_closed(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_metadata_is_served() {
+    let (addr, info_hash) = start_engine().await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut bytes = handshake(info_hash);
+    // The extension protocol bit
+    bytes[25] |= 0x10;
+    stream.write_all(&bytes).await.unwrap();
+    let mut reply = [0u8; 68];
+    stream.read_exact(&mut reply).await.unwrap();
+
+    stream
+        .write_all(&message(20, b"\x00d1:md11:ut_metadatai3eee"))
+        .await
+        .unwrap();
+    stream
+        .write_all(&message(20, b"\x01d8:msg_typei0e5:piecei0ee"))
+        .await
+        .unwrap();
+
+    let info =
+        b"d6:lengthi262144e4:name4:test12:piece lengthi262144e6:pieces20:12345678901234567890e";
+    let mut expected =
+        format!("d8:msg_typei1e5:piecei0e10:total_sizei{}ee", info.len()).into_bytes();
+    expected.extend_from_slice(info);
+    // Skip the engine's extended handshake and whatever else it sends first
+    loop {
+        let mut length = [0u8; 4];
+        timeout(Duration::from_secs(2), stream.read_exact(&mut length))
+            .await
+            .expect("Engine didn't serve the metadata")
+            .unwrap();
+        let mut payload = vec![0u8; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut payload).await.unwrap();
+        if payload.starts_with(&[20, 3]) {
+            assert_eq!(&payload[2..], &expected[..]);
+            break;
+        }
+    }
+}
+
+// Dial the peer from another engine serving the torrent, returns whether the handshake was done.
+async fn dial(addr: SocketAddr, encryption: EncryptionPolicy) -> bool {
+    let torrent = Torrent::from_metainfo(MetaInfo::from_bytes(TORRENT).unwrap());
+    dial_torrent(addr, torrent, encryption).await
+}
+
+async fn dial_torrent(addr: SocketAddr, torrent: Torrent, encryption: EncryptionPolicy) -> bool {
+    let info_hash = torrent.info_hash();
+    let listener = PeerListener::bind("127.0.0.1:0", *b"-BD0001-dialingpeer0")
+        .await
+        .unwrap()
+        .with_encryption(encryption);
+    listener.add_torrent(torrent);
+    let (events, mut received) = mpsc::unbounded_channel();
+    let candidate = DialCandidate::new(addr, PeerSource::Manual, None);
+    assert!(listener.registry().dial(&info_hash, candidate, events));
+    let event = timeout(Duration::from_secs(5), received.recv())
+        .await
+        .expect("The dial never finished")
+        .unwrap();
+    matches!(event, DialEvent::Connected { .. })
+}
+
+// A peer which only speaks plaintext, it hangs up on anything but the BitTorrent handshake.
+async fn start_plaintext_peer(info_hash: [u8; 20]) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut received = [0u8; 68];
+                if stream.read_exact(&mut received[..20]).await.is_err()
+                    || &received[1..20] != b"BitTorrent protocol"
+                {
+                    return;
+                }
+                stream.read_exact(&mut received[20..]).await.unwrap();
+                stream.write_all(&handshake(info_hash)).await.unwrap();
+                // Keep the connection until the engine closes it
+                let _ = stream.read(&mut [0u8; 64]).await;
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_dial_with_encryption() {
+    let (addr, _) = start_engine().await;
+    assert!(dial(addr, EncryptionPolicy::Require).await);
+    assert!(dial(addr, EncryptionPolicy::Allow).await);
+}
+
+#[tokio::test]
+async fn test_dial_falls_back_to_plaintext() {
+    let info_hash = MetaInfo::from_bytes(TORRENT).unwrap().info_hash;
+    let addr = start_plaintext_peer(info_hash).await;
+    assert!(dial(addr, EncryptionPolicy::Prefer).await);
+    assert!(!dial(addr, EncryptionPolicy::Require).await);
+}
+
+// A peer of an SSL torrent, with a certificate issued by the CA of the swarm.
+async fn start_tls_peer(info_hash: [u8; 20]) -> SocketAddr {
+    let identity = native_tls::Identity::from_pkcs8(
+        include_bytes!("certs/peer_cert.pem"),
+        include_bytes!("certs/peer_key.pem"),
+    )
+    .unwrap();
+    let acceptor =
+        tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    return;
+                };
+                let mut received = [0u8; 68];
+                if stream.read_exact(&mut received).await.is_ok() {
+                    stream.write_all(&handshake(info_hash)).await.unwrap();
+                    let _ = stream.read(&mut [0u8; 64]).await;
+                }
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_dial_ssl_torrent() {
+    let metainfo = MetaInfo::from_bytes(TORRENT).unwrap();
+    let addr = start_tls_peer(metainfo.info_hash).await;
+    let ssl_torrent = |peer_tls| async {
+        let mut torrent = Torrent::from_metainfo(metainfo.clone());
+        let options = TorrentOptions {
+            peer_tls: Some(peer_tls),
+            ..Default::default()
+        };
+        torrent.set_options(options).await;
+        torrent
+    };
+
+    let torrent = ssl_torrent(PeerTls {
+        ca_certificate: Some(include_str!("certs/ca.pem").to_string()),
+        pinned_certificates: Vec::new(),
+    })
+    .await;
+    assert!(dial_torrent(addr, torrent, EncryptionPolicy::Allow).await);
+
+    // The user pinned another certificate, the peer shows up in the failures
+    let torrent = ssl_torrent(PeerTls {
+        ca_certificate: None,
+        pinned_certificates: vec![[0xab; 32]],
+    })
+    .await;
+    let activity = torrent.peer_activity();
+    assert!(!dial_torrent(addr, torrent, EncryptionPolicy::Allow).await);
+    let failures = activity.failures();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].addr, addr);
+    assert!(failures[0].reason.contains("not pinned"));
+}
+
+#[tokio::test]
+async fn test_failed_pex_peer_is_holepunched_through_relay() {
+    let metainfo = MetaInfo::from_bytes(TORRENT).unwrap();
+    let info_hash = metainfo.info_hash;
+    let listener = PeerListener::bind("127.0.0.1:0", ENGINE_PEER_ID)
+        .await
+        .unwrap();
+    listener.add_torrent(Torrent::from_metainfo(metainfo));
+    let registry = listener.registry();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(listener.run());
+    // Nobody listens there, the dial fails
+    let target = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    // The relay supports holepunch and tells the engine of the target through PEX
+    let mut relay = TcpStream::connect(addr).await.unwrap();
+    let mut bytes = handshake(info_hash);
+    bytes[25] |= 0x10;
+    relay.write_all(&bytes).await.unwrap();
+    relay.read_exact(&mut [0u8; 68]).await.unwrap();
+    relay
+        .write_all(&message(20, b"\x00d1:md12:ut_holepunchi4e6:ut_pexi5eee"))
+        .await
+        .unwrap();
+    let mut pex = b"\x02d5:added6:".to_vec();
+    pex.extend_from_slice(&[127, 0, 0, 1]);
+    pex.extend_from_slice(&target.port().to_be_bytes());
+    pex.push(b'e');
+    relay.write_all(&message(20, &pex)).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let (events, mut received) = mpsc::unbounded_channel();
+    let candidate = DialCandidate::new(target, PeerSource::Pex, None);
+    assert!(registry.dial(&info_hash, candidate, events));
+    let event = timeout(Duration::from_secs(5), received.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(event, DialEvent::Failed { .. }));
+
+    let mut rendezvous = vec![20, 4, 0, 0, 127, 0, 0, 1];
+    rendezvous.extend_from_slice(&target.port().to_be_bytes());
+    rendezvous.extend_from_slice(&[0; 4]);
+    loop {
+        let mut length = [0u8; 4];
+        timeout(Duration::from_secs(2), relay.read_exact(&mut length))
+            .await
+            .expect("Engine didn't ask the relay to connect it with the peer")
+            .unwrap();
+        let mut payload = vec![0u8; u32::from_be_bytes(length) as usize];
+        relay.read_exact(&mut payload).await.unwrap();
+        if payload.starts_with(&[20, 4]) {
+            assert_eq!(payload, rendezvous);
+            break;
+        }
+    }
+}
+
+// Connect with the extension protocol as a partial seed, then send the bitfield.
+async fn connect_upload_only(addr: SocketAddr, info_hash: [u8; 20], bitfield: u8) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut bytes = handshake(info_hash);
+    bytes[25] |= 0x10;
+    stream.write_all(&bytes).await.unwrap();
+    stream.read_exact(&mut [0u8; 68]).await.unwrap();
+    stream
+        .write_all(&message(20, b"\x00d1:mde11:upload_onlyi1ee"))
+        .await
+        .unwrap();
+    stream.write_all(&message(5, &[bitfield])).await.unwrap();
+    stream
+}
+
+// The ids of the messages the engine sent until it closed the connection or `duration` passed,
+// None once it closed.
+async fn read_message_ids(stream: &mut TcpStream, duration: Duration) -> Option<Vec<u8>> {
+    let mut ids = Vec::new();
+    let read = async {
+        loop {
+            let mut length = [0u8; 4];
+            stream.read_exact(&mut length).await?;
+            let mut payload = vec![0u8; u32::from_be_bytes(length) as usize];
+            stream.read_exact(&mut payload).await?;
+            ids.extend(payload.first());
+        }
+    };
+    match timeout(duration, read).await {
+        Ok(Err::<(), io::Error>(_)) => None,
+        _ => Some(ids),
+    }
+}
+
+#[tokio::test]
+async fn test_upload_only_peer() {
+    let (addr, info_hash) = start_engine().await;
+    // It has nothing the engine wants and won't download, there is nothing to exchange
+    let mut stream = connect_upload_only(addr, info_hash, 0x00).await;
+    assert_eq!(
+        read_message_ids(&mut stream, Duration::from_secs(2)).await,
+        None
+    );
+
+    // It has the piece, the engine is interested but doesn't give it an upload slot
+    let mut stream = connect_upload_only(addr, info_hash, 0x80).await;
+    stream.write_all(&message(2, &[])).await.unwrap();
+    let ids = read_message_ids(&mut stream, Duration::from_millis(1500))
+        .await
+        .expect("Engine dropped the peer it's interested in");
+    assert!(ids.contains(&2));
+    assert!(!ids.contains(&1));
+}
+
+// The piece and begin of the requests the engine sent within `duration`.
+async fn read_requests(stream: &mut TcpStream, duration: Duration) -> Vec<(u32, u32)> {
+    let mut requests = Vec::new();
+    let read = async {
+        loop {
+            let mut length = [0u8; 4];
+            stream.read_exact(&mut length).await?;
+            let mut payload = vec![0u8; u32::from_be_bytes(length) as usize];
+            stream.read_exact(&mut payload).await?;
+            if payload.first() == Some(&6) {
+                requests.push((
+                    u32::from_be_bytes(payload[1..5].try_into().unwrap()),
+                    u32::from_be_bytes(payload[5..9].try_into().unwrap()),
+                ));
+            }
+        }
+    };
+    let _: Result<io::Result<()>, _> = timeout(duration, read).await;
+    requests
+}
+
+#[tokio::test]
+async fn test_unchoked_peer_is_requested_from() {
+    let (addr, info_hash) = start_engine().await;
+    let mut stream = connect(addr, info_hash).await;
+    // A peer with no pieces may skip the bitfield and send have messages
+    stream
+        .write_all(&message(4, &0u32.to_be_bytes()))
+        .await
+        .unwrap();
+    assert_interested(&mut stream).await;
+    // Nothing is requested while choked
+    assert!(
+        read_requests(&mut stream, Duration::from_millis(300))
+            .await
+            .is_empty()
+    );
+
+    stream.write_all(&message(1, &[])).await.unwrap();
+    let requests = read_requests(&mut stream, Duration::from_millis(500)).await;
+    assert_eq!(requests.len(), 4);
+    assert!(requests.iter().all(|(piece_index, _)| *piece_index == 0));
+
+    // Choked, the requests are dropped and the same blocks are requested again once unchoked
+    stream.write_all(&message(0, &[])).await.unwrap();
+    stream.write_all(&message(1, &[])).await.unwrap();
+    let mut again = read_requests(&mut stream, Duration::from_millis(500)).await;
+    again.sort();
+    let mut requests = requests;
+    requests.sort();
+    assert_eq!(again, requests);
+}
+
+#[tokio::test]
+async fn test_dht_port_is_exchanged() {
+    let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let dht = Arc::new(Dht::bind(localhost).await.unwrap());
+    let metainfo = MetaInfo::from_bytes(TORRENT).unwrap();
+    let info_hash = metainfo.info_hash;
+    let listener = PeerListener::bind("127.0.0.1:0", ENGINE_PEER_ID)
+        .await
+        .unwrap()
+        .with_dht(dht.clone());
+    listener.add_torrent(Torrent::from_metainfo(metainfo));
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(listener.run());
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut bytes = handshake(info_hash);
+    // The DHT bit of the reserved bytes
+    bytes[27] |= 0x01;
+    stream.write_all(&bytes).await.unwrap();
+    let mut reply = [0u8; 68];
+    timeout(Duration::from_secs(2), stream.read_exact(&mut reply))
+        .await
+        .expect("Engine didn't reply to the handshake")
+        .unwrap();
+    assert_eq!(reply[27] & 0x01, 0x01);
+    let read_port = async {
+        loop {
+            let mut length = [0u8; 4];
+            stream.read_exact(&mut length).await.unwrap();
+            let mut payload = vec![0u8; u32::from_be_bytes(length) as usize];
+            stream.read_exact(&mut payload).await.unwrap();
+            if payload.first() == Some(&9) {
+                return u16::from_be_bytes(payload[1..3].try_into().unwrap());
+            }
+        }
+    };
+    let port = timeout(Duration::from_secs(2), read_port)
+        .await
+        .expect("Engine didn't send its DHT port");
+    assert_eq!(port, dht.local_addr().unwrap().port());
+
+    // The node of the peer is added once it answers the ping
+    let node = Dht::bind(localhost).await.unwrap();
+    let node_port = node.local_addr().unwrap().port();
+    stream
+        .write_all(&message(9, &node_port.to_be_bytes()))
+        .await
+        .unwrap();
+    for _ in 0..20 {
+        if dht.node_count() == 1 {
+            return;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    panic!("The node of the peer wasn't added to the DHT");
+}